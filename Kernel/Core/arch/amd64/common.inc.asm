@@ -1,5 +1,6 @@
 
-%define MAX_CPUS	1
+%define MAX_CPUS	16
+%define AP_TRAMPOLINE_ADDR	0x8000	; NOTE: Must match smp.rs
 %define KSTACK_BASE	0xFFFFA00000000000
 %define INITIAL_KSTACK_SIZE	16
 %define KERNEL_BASE	0xFFFFFFFF80000000
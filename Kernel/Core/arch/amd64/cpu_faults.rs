@@ -63,10 +63,18 @@ pub extern "C" fn error_handler(regs: &InterruptRegs)
 	13 => { puts("GPF ("); puth(regs.errorcode); puts(")\n"); },
 	14 => {
 		let cr2 = get_cr2();
-		puts("PF ("); puth(regs.errorcode); puts(") at "); puth(cr2 as u64); puts(" by "); puth(regs.rip); puts(" SP="); puth(regs.rsp); puts("\n");
-		if ::arch::imp::memory::virt::handle_page_fault(cr2 as usize, regs.errorcode as u32) {
+		// Resolving the fault can sleep (e.g. reading file data for a demand-paged page), so re-enable interrupts
+		// if they were enabled when the fault happened.
+		let irqs_enabled = regs.rflags & (1 << 9) != 0;
+		// SAFE: Restores the state from before the fault, and is undone before returning
+		if irqs_enabled { unsafe { super::sync::start_interrupts(); } }
+		let handled = ::arch::imp::memory::virt::handle_page_fault(cr2 as usize, regs.errorcode as u32);
+		// SAFE: See above
+		if irqs_enabled { unsafe { super::sync::stop_interrupts(); } }
+		if handled {
 			return ;
 		}
+		puts("PF ("); puth(regs.errorcode); puts(") at "); puth(cr2 as u64); puts(" by "); puth(regs.rip); puts(" SP="); puth(regs.rsp); puts("\n");
 		},
 	_ => { puts("ERROR "); puth(regs.intnum); puts(" (code "); puth(regs.errorcode); puts(")\n"); },
 	}
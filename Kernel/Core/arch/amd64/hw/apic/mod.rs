@@ -20,6 +20,12 @@ pub struct IRQHandle
 	isr_handle: ::arch::imp::interrupts::ISRHandle,
 }
 
+/// Handle to a bound message-signalled interrupt (ISR is released on drop)
+pub struct MsiHandle
+{
+	_isr_handle: ::arch::imp::interrupts::ISRHandle,
+}
+
 #[derive(Debug,Copy,Clone)]
 pub enum IrqError
 {
@@ -33,6 +39,9 @@ pub enum IrqError
 static s_lapic: ::lib::LazyStatic<raw::LAPIC> = lazystatic_init!();
 #[allow(non_upper_case_globals)]
 static s_ioapics: ::lib::LazyStatic<Vec<raw::IOAPIC>> = lazystatic_init!();
+/// LAPIC IDs of all enabled processors (including the BSP)
+#[allow(non_upper_case_globals)]
+static s_processors: ::lib::LazyStatic<Vec<u32>> = lazystatic_init!();
 
 fn init()
 {
@@ -75,6 +84,15 @@ fn init()
 				}
 			).collect();
 	
+	// Enumerate processors (for SMP startup)
+	let processors: Vec<_> = madt.data().records(madt.data_len()).filter_map(
+			|r| match r {
+				init::MADTDevRecord::DevLAPIC(a) if a.flags & 1 != 0 => Some(a.apic_id as u32),
+				_ => None
+				}
+			).collect();
+	log_debug!("{} processors: {:?}", processors.len(), processors);
+	
 	// Create APIC and IOAPIC instances
 	// SAFE: Called in a single-threaded context
 	unsafe {
@@ -82,6 +100,7 @@ fn init()
 		s_lapic.ls_unsafe_mut().global_init();
 
 		s_ioapics.prep(|| ioapics);
+		s_processors.prep(|| processors);
 		};
 	s_lapic.init();
 	
@@ -92,6 +111,37 @@ fn init()
 	unsafe { asm!("sti"); }
 }
 
+/// Returns the LAPIC IDs of all enabled processors
+pub fn processors() -> &'static [u32]
+{
+	if s_processors.ls_is_valid() {
+		&s_processors[..]
+	}
+	else {
+		&[]
+	}
+}
+/// Returns the LAPIC ID of the current processor
+pub fn local_apic_id() -> u32
+{
+	get_lapic().get_id()
+}
+/// Initialise the LAPIC on an application processor (enables interrupts)
+pub fn init_ap()
+{
+	get_lapic().init();
+}
+/// Send an INIT IPI to the specified processor
+pub fn send_init(apic_id: u32)
+{
+	get_lapic().send_ipi(apic_id, raw::IpiMode::Init, 0);
+}
+/// Send a startup IPI to the specified processor, starting it in real mode at `vector * 0x1000`
+pub fn send_startup(apic_id: u32, vector: u8)
+{
+	get_lapic().send_ipi(apic_id, raw::IpiMode::Startup, vector);
+}
+
 fn get_ioapic(interrupt: usize) -> Option<(&'static raw::IOAPIC, usize)>
 {
 	match s_ioapics.iter().find( |a| a.contains(interrupt) )
@@ -108,16 +158,35 @@ fn get_lapic() -> &'static raw::LAPIC
 	&*s_lapic
 }
 
-///// Registers a message-signalled interrupt handler.
-//pub fn register_msi(callback: fn (*const()), info: *const ()) -> Result<(uint,::arch::interrupts::ISRHandle),()>
-//{
-//	// 1. Find a spare ISR slot on a processor
-//	let lapic_id = 0;
-//	let isrnum = 33u;
-//	// 2. Bind
-//	let h = try!(::arch::interrupts::bind_isr(lapic_id, isrnum as u8, callback, info, cleanup_nop));
-//	Ok( (isrnum, h) )
-//}
+/// Registers a message-signalled interrupt handler.
+///
+/// Returns the handle, and the MSI address/data pair that targets the allocated vector
+pub fn register_msi(callback: IRQHandler, info: *const ()) -> Result<(MsiHandle, u64, u32),IrqError>
+{
+	// TODO: Pick a suitable processor (same as register_irq)
+	let lapic_id = 0u32;
+	// NOTE: The callback is passed through the ISR's index value, and recovered in `lapic_msi_handler`
+	let isr_handle = match ::arch::imp::interrupts::bind_free_isr(lapic_msi_handler, info, callback as usize)
+		{
+		Ok(v) => v,
+		Err(e) => return Err(IrqError::BindFail(e)),
+		};
+	// Fixed delivery, edge triggered, physical destination
+	let addr = 0xFEE0_0000 | ((lapic_id as u64) << 12);
+	let data = isr_handle.idx() as u32;
+	log_debug!("register_msi: ISR {} addr={:#x} data={:#x}", isr_handle.idx(), addr, data);
+	Ok( (MsiHandle { _isr_handle: isr_handle }, addr, data) )
+}
+
+/// Message-signalled interrupt handler (no IOAPIC involved, just the LAPIC)
+#[req_safe(irq)]
+extern "C" fn lapic_msi_handler(isr: usize, info: *const(), callback: usize)
+{
+	// SAFE: `callback` was created from an IRQHandler in `register_msi`
+	let cb: IRQHandler = unsafe { ::core::mem::transmute(callback) };
+	cb(info);
+	get_lapic().eoi(isr);
+}
 
 /// Local + IO APIC interrupt handler
 #[req_safe(irq)]
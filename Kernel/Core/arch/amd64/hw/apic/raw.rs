@@ -27,6 +27,16 @@ struct IOAPICRegs
 	mapping: ::memory::virt::AllocHandle,
 }
 
+/// Delivery mode for an inter-processor interrupt
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum IpiMode
+{
+	Fixed,
+	Init,
+	Startup,
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub enum TriggerMode
@@ -59,6 +69,7 @@ enum ApicReg
 	ErrStatus = 0x28,	// Error Status
 	LVTCMCI   = 0x2F,	// LVT CMCI Registers (?)
 	ICR       = 0x30,	// Interrupt Command Register (1/2)
+	ICR2      = 0x31,	// Interrupt Command Register (2/2)
 	LVTTimer  = 0x32,
 	LVTThermalSensor = 0x33,
 	LVTPermCounters  = 0x34,
@@ -130,9 +141,11 @@ impl LAPIC
 		
 		//self.write_reg(ApicReg::SIR as usize, self.read_reg(ApicReg_SIR as usize) | (1 << 8));
 		self.write_reg(ApicReg::SIR, 0x7F | (1 << 8));	// Enable LAPIC (and set Spurious to 127)
-		self.write_reg(ApicReg::InitCount, 0x100000);
+		// Periodic timer, wakes this CPU from idle to check for runnable threads
+		// - Uncalibrated, but it's only used as a wakeup tick (timekeeping uses the HPET)
 		self.write_reg(ApicReg::TmrDivide, 3);	// Timer Divide = 16
-		self.write_reg(ApicReg::LVTTimer, TIMER_VEC as u32);	// Enable Timer
+		self.write_reg(ApicReg::LVTTimer, TIMER_VEC as u32 | (1 << 17));	// Enable Timer (Periodic)
+		self.write_reg(ApicReg::InitCount, 0x100000);
 		self.write_reg(ApicReg::LVTThermalSensor, 0);	// "Disable" Thermal Sensor
 		self.write_reg(ApicReg::LVTPermCounters, 0);	// "Disable" ? Counters
 		self.write_reg(ApicReg::LVT_LINT0, 0);	// "Disable" LINT0
@@ -159,6 +172,28 @@ impl LAPIC
 		self.write_reg(ApicReg::EOI, num as u32);
 	}
 	
+	/// Returns the ID of the current CPU's LAPIC
+	pub fn get_id(&self) -> u32
+	{
+		self.read_reg(ApicReg::LAPIC_ID) >> 24
+	}
+	/// Send an inter-processor interrupt to the specified LAPIC
+	pub fn send_ipi(&self, apic_id: u32, mode: IpiMode, vector: u8)
+	{
+		let mode_bits = match mode
+			{
+			IpiMode::Fixed   => (0 << 8),
+			IpiMode::Init    => (5 << 8) | (1 << 14),	// INIT, Level Assert
+			IpiMode::Startup => (6 << 8),
+			};
+		// Wait for any previous IPI to be accepted
+		while self.read_reg(ApicReg::ICR) & (1 << 12) != 0 {
+		}
+		self.write_reg(ApicReg::ICR2, apic_id << 24);
+		// NOTE: Writing the low word sends the IPI
+		self.write_reg(ApicReg::ICR, mode_bits | vector as u32);
+	}
+	
 	fn read_reg(&self, reg: ApicReg) -> u32
 	{
 		// SAFE: Aligned memory accesses to hardware are atomic on x86
@@ -196,7 +231,7 @@ impl LAPIC
 		assert!( !sp.is_null() );
 		// SAFE: 'sp' is the bound pointer, and should be valid
 		let s: &LAPIC = unsafe { &*(sp as *const LAPIC) };
-		log_trace!("LAPIC Timer");
+		// Nothing else to do, the interrupt is only to wake idle CPUs
 		s.eoi(isr);
 	}
 }
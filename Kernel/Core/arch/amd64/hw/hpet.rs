@@ -39,6 +39,12 @@ enum HPETReg
 
 static S_INSTANCE: ::lib::LazyStatic<HPET> = lazystatic_init!();
 
+/// Returns true if a HPET was found (and the timestamp is valid)
+pub fn is_present() -> bool
+{
+	S_INSTANCE.ls_is_valid()
+}
+
 /// Reutrns the current system timestamp, in miliseconds since an arbitary point (usually power-on)
 pub fn get_timestamp() -> u64
 {
@@ -50,6 +56,21 @@ pub fn get_timestamp() -> u64
 	}
 }
 
+/// Request that the timer interrupt fires no later than the specified timestamp (in ms)
+pub fn request_tick(target_ms: u64)
+{
+	if S_INSTANCE.ls_is_valid() {
+		let s = &*S_INSTANCE;
+		let _irq = ::sync::hold_interrupts();
+		let now = s.current();
+		// Clamp to a small distance in the future, so the comparator isn't set to a time that's already passed
+		let target = ::core::cmp::max(target_ms.saturating_mul(s.ticks_per_ms()), now + s.ticks_per_ms() / 10);
+		if target < s.comparitor_value(0) || s.comparitor_value(0) <= now {
+			s.oneshot(0, target);
+		}
+	}
+}
+
 fn init()
 {
 	log_trace!("init()");
@@ -121,6 +142,8 @@ impl HPET
 		s.write_reg(HPETReg::ISR as usize, s.read_reg(HPETReg::ISR as usize));
 		
 		s.oneshot(0, s.current() + 100*1000 );
+		// Let the timer wheel process expired timers (it will request an earlier tick if needed)
+		::time::time_tick();
 	}
 	
 	fn read_reg(&self, reg: usize) -> u64 {
@@ -146,6 +169,9 @@ impl HPET
 	fn current(&self) -> u64 {
 		self.read_reg(HPETReg::MainCtr as usize)
 	}
+	fn comparitor_value(&self, comparitor: usize) -> u64 {
+		self.read_reg(HPETReg::Timer0 as usize + comparitor*2 + 1)
+	}
 	fn oneshot(&self, comparitor: usize, value: u64) {
 		assert!(comparitor < self.num_comparitors());
 		let comp_reg = HPETReg::Timer0 as usize + comparitor*2;
@@ -100,6 +100,9 @@ ErrorCommon:
 	jz .inkernel2
 	cmp rax, 0x2B
 	jnz .bugcheck
+	; Returning to usermode, the thread might need to exit
+	[extern int_return_to_user]
+	call int_return_to_user
 	; Reset the GS/FS base
 	swapgs
 .inkernel2:
@@ -169,8 +172,19 @@ BLANKINT i
 [extern irq_handler]
 IRQCommon:
 	API_SAVE
+	; Restore the kernel's GS base if interrupted in usermode (CS is above the saved RBX and RIP)
+	cmp QWORD [rsp+API_SAVE_SIZE+2*8], 0x08
+	jz .inkernel
+	swapgs
+.inkernel:
 	mov rdi, rbx
 	call irq_handler
+	cmp QWORD [rsp+API_SAVE_SIZE+2*8], 0x08
+	jz .inkernel2
+	; Returning to usermode, the thread might need to exit
+	call int_return_to_user
+	swapgs
+.inkernel2:
 	API_RESTORE
 	pop rbx
 	iretq
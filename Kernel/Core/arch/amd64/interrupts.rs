@@ -45,6 +45,19 @@ pub extern "C" fn irq_handler(index: usize)
 	}
 }
 
+#[no_mangle]
+#[doc(hidden)]
+/// Called by assembly when an IRQ or CPU fault is about to return to usermode
+pub extern "C" fn int_return_to_user()
+{
+	// A thread that never makes a syscall only notices an exit request here
+	if ::threads::is_exit_requested() {
+		// SAFE: Interrupted context was usermode, so nothing on this stack relies on interrupts being disabled
+		unsafe { ::arch::sync::start_interrupts(); }
+		::threads::terminate_thread();
+	}
+}
+
 #[derive(Debug,Copy,Clone)]
 /// Error code for bind_isr
 pub enum BindISRError
@@ -55,6 +68,8 @@ pub enum BindISRError
 pub use super::hw::apic::IRQHandle;
 pub use super::hw::apic::IrqError as BindError;
 pub use super::hw::apic::register_irq as bind_gsi;
+pub use super::hw::apic::MsiHandle;
+pub use super::hw::apic::register_msi as bind_msi;
 
 /// Bind a callback (and params) to an allocatable ISR
 pub fn bind_isr(isr: u8, callback: ISRHandler, info: *const(), idx: usize) -> Result<ISRHandle,BindISRError>
@@ -451,23 +451,37 @@ pub fn handle_page_fault(accessed_address: usize, error_code: u32) -> bool
 		// Poke the main VMM layer
 		//::memory::virt::cow_write(accessed_address);
 
+		let mut rv = false;
 		// 1. Lock (relevant) address space
 		// SAFE: Changes to address space are transparent
 		::memory::virt::with_lock(accessed_address, || unsafe {
 			let frame = pte.addr();
 			let pgaddr = (accessed_address as usize) & !PAGE_MASK;
 			// 2. Get the PMM to provide us with a unique copy of that frame (can return the same addr)
-			let newframe = ::memory::phys::make_unique( frame, &*(pgaddr as *const [u8; 4096]) );
-			// 3. Remap to this page as UserRW (because COW is user-only atm)
-			pte.set(newframe, ProtectionMode::UserRW);
-			invlpg( (accessed_address & !0xFFF) as *mut () );
+			match ::memory::phys::make_unique( frame, &*(pgaddr as *const [u8; 4096]) )
+			{
+			Ok(newframe) => {
+				// 3. Remap to this page as UserRW (because COW is user-only atm)
+				pte.set(newframe, ProtectionMode::UserRW);
+				invlpg( (accessed_address & !0xFFF) as *mut () );
+				rv = true;
+				},
+			Err(_) => log_warning!("Out of memory handling COW fault at {:#x}", accessed_address),
+			}
 			});
-		return true;
+		return rv;
 	}
 	//  > Paged-out pages
 	if error_code & FAULT_LOCKED == 0 && pte.is_reserved() {
 		todo!("Paged - {:#x} pte = {:?}", accessed_address, pte);
 	}
+	//  > Demand-paged user memory (not yet populated, or the first write to a shared mapping)
+	//  - Only from userland, as populating can sleep. Buffers used by the kernel are prefaulted (see `memory::demand::prefault`)
+	if error_code & FAULT_USER != 0 && (error_code & FAULT_LOCKED == 0 || error_code & FAULT_WRITE != 0) {
+		if ::memory::demand::handle_fault(accessed_address, error_code & FAULT_WRITE != 0) {
+			return true;
+		}
+	}
 	
 	
 	// Check if the user is buggy
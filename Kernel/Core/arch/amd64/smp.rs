@@ -0,0 +1,157 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/arch/amd64/smp.rs
+//! Symmetric multi-processing (application processor startup)
+use core::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+
+module_define!{SMP, [APIC, HPET, TSS], init}
+
+/// Physical address of the AP startup trampoline (must be page aligned and below 1MiB)
+// NOTE: MUST match the value in common.inc.asm (reserved in the boot memory map)
+pub const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+/// Set by an AP once it has reached rust code (and no longer needs the boot state)
+static S_AP_STARTED: AtomicBool = AtomicBool::new(false);
+/// Set by the BSP once all APs are started (and the identity mapping is removed)
+static S_AP_RELEASE: AtomicBool = AtomicBool::new(false);
+/// Number of running CPUs
+static S_CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+extern "C" {
+	static mut InitialPML4: [u64; 512];
+	static InitialPDP: [u64; 512];
+	static mut s_ap_boot_tls: u64;
+	static ap_trampoline: [u8; 0];
+	static ap_trampoline_end: [u8; 0];
+}
+
+/// Returns the number of CPUs that have been started
+pub fn cpu_count() -> usize
+{
+	S_CPU_COUNT.load(Ordering::Relaxed)
+}
+
+fn init()
+{
+	let bsp_id = super::hw::apic::local_apic_id();
+	let processors = super::hw::apic::processors();
+	if processors.len() <= 1 {
+		log_notice!("Single processor system");
+		return ;
+	}
+
+	if !super::hw::hpet::is_present() {
+		log_error!("No HPET, can't time processor startup");
+		return ;
+	}
+
+	// 1. Copy the trampoline into low memory
+	// SAFE: Just taking the addresses
+	let tramp_len = unsafe { ap_trampoline_end.as_ptr() as usize - ap_trampoline.as_ptr() as usize };
+	assert!(tramp_len <= ::PAGE_SIZE);
+	// SAFE: Trampoline page is reserved in the boot memory map (and only used here), and the source is static
+	let _tramp_handle = unsafe {
+		let h = ::memory::virt::map_hw_rw(TRAMPOLINE_ADDR, 1, "SMP").expect("Unable to map SMP trampoline");
+		h.as_int_mut_slice::<u8>(0, tramp_len).copy_from_slice( ::core::slice::from_raw_parts(ap_trampoline.as_ptr(), tramp_len) );
+		h
+		};
+
+	// 2. Temporarily restore the identity mapping of low memory (the APs enable paging while executing from it)
+	// SAFE: Only the kernel is running in this address space, and nothing else uses the low half of it yet
+	unsafe {
+		InitialPML4[0] = ::memory::virt::get_phys(&InitialPDP) | 3;
+	}
+
+	// 3. Start each AP in sequence (they share the boot state)
+	let mut index = 1;
+	for &apic_id in processors.iter().filter(|&&id| id != bsp_id)
+	{
+		if index >= super::tss::MAX_CPUS {
+			log_warning!("Too many processors, only using {}", index);
+			break;
+		}
+
+		log_debug!("Starting CPU{} (APIC ID {})", index, apic_id);
+		let tlsbase = super::threads::init_ap_cpu(index, apic_id);
+		// SAFE: Only read by the AP being started, which isn't started until after this write
+		unsafe {
+			::core::ptr::write_volatile(&mut s_ap_boot_tls, tlsbase);
+		}
+		S_AP_STARTED.store(false, Ordering::SeqCst);
+
+		// INIT-SIPI-SIPI sequence
+		super::hw::apic::send_init(apic_id);
+		::time::sleep_ms(10);
+		let vector = (TRAMPOLINE_ADDR / ::PAGE_SIZE as u64) as u8;
+		super::hw::apic::send_startup(apic_id, vector);
+		if !wait_started(2) {
+			super::hw::apic::send_startup(apic_id, vector);
+			if !wait_started(100) {
+				// Put the AP back into wait-for-SIPI, so a slow AP can't still be running the trampoline when the
+				// boot state is reused for the next AP (or the identity mapping is removed).
+				super::hw::apic::send_init(apic_id);
+				::time::sleep_ms(10);
+				// NOTE: The CPU slot is not reused (its per-CPU data was already handed out)
+				log_error!("CPU{} (APIC ID {}) failed to start", index, apic_id);
+				index += 1;
+				continue ;
+			}
+		}
+		S_CPU_COUNT.fetch_add(1, Ordering::Relaxed);
+		index += 1;
+	}
+
+	// 4. Remove the identity mapping and release the APs (they flush their TLBs before scheduling)
+	// SAFE: No CPU is using the identity mapping anymore
+	unsafe {
+		InitialPML4[0] = 0;
+		flush_tlb_global();
+	}
+	S_AP_RELEASE.store(true, Ordering::SeqCst);
+	log_notice!("{} CPUs online", cpu_count());
+}
+
+/// Wait (up to `ms` milliseconds) for the AP currently being started to report in
+fn wait_started(ms: u64) -> bool
+{
+	::time::wait_for(ms, 1, || S_AP_STARTED.load(Ordering::SeqCst))
+}
+fn pause()
+{
+	// SAFE: Spin-loop hint
+	unsafe { asm!("pause" : : : : "volatile"); }
+}
+
+/// Flush all TLB entries (including global ones) on this CPU, by toggling CR4.PGE
+unsafe fn flush_tlb_global()
+{
+	asm!("mov %cr4, %rax; btc $$7, %rax; mov %rax, %cr4; btc $$7, %rax; mov %rax, %cr4" : : : "rax" : "volatile");
+}
+
+/// Rust entrypoint for application processors (called by `ap_start64`, on the CPU's idle thread)
+#[no_mangle]
+#[doc(hidden)]
+pub extern "C" fn ap_entry() -> !
+{
+	let cpu = super::threads::cpu_num();
+	super::tss::init_ap(cpu);
+	S_AP_STARTED.store(true, Ordering::SeqCst);
+
+	// Wait until the BSP has finished starting CPUs (and has removed the identity mapping)
+	while !S_AP_RELEASE.load(Ordering::SeqCst)
+	{
+		pause();
+	}
+	// SAFE: Nothing depends on stale TLB entries
+	unsafe { flush_tlb_global(); }
+
+	log_notice!("CPU{} online (APIC ID {})", cpu, super::threads::cpu_apic_id());
+	super::hw::apic::init_ap();
+
+	// Start scheduling
+	::threads::idle_thread();
+	unreachable!();
+}
+
+// vim: ft=rust
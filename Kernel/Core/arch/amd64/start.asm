@@ -240,7 +240,20 @@ start64_higher:
 	mov al, 10
 	out dx, al
 	
-	; Bind the 'SYSCALL' handler (and set flags for it)
+	call syscall_init
+	
+	mov rax, InitialPML4
+	mov QWORD [rax], 0
+	; 7. Call rust kmain
+	call kmain
+.dead_loop:
+	cli
+	hlt
+	jmp .dead_loop
+
+; Bind the 'SYSCALL' handler (and set flags for it)
+; - These MSRs are per-CPU, so this is also called by each AP
+syscall_init:
 	; LSTAR = 0xC000_0082
 	mov rax, syscall_handler
 	mov rdx, rax
@@ -257,39 +270,154 @@ start64_higher:
 	mov edx, 0
 	mov ecx, 0xC0000084
 	wrmsr
+	ret
+
+; -------------------------------------------------
+; Application Processor startup
+; -------------------------------------------------
+; Entered from the trampoline (see below) with paging enabled, running in 64-bit mode
+; - s_ap_boot_tls is the TLS base (and stack top) of this CPU's idle thread
+[extern ap_entry]
+ap_start64:
+	; Load the real GDT and IDT (shared with the BSP)
+	lgdt [rel GDTPtr2]
+	lidt [rel IDTPtr]
+	mov ax, 0x10
+	mov ds, ax
+	mov ss, ax
+	mov es, ax
+	mov fs, ax
+	mov gs, ax
 	
-	mov rax, InitialPML4
-	mov QWORD [rax], 0
-	; 7. Call rust kmain
-	call kmain
+	; Switch to the idle thread's stack, and set GS to its TLS block
+	mov rax, [rel s_ap_boot_tls]
+	mov rsp, rax
+	mov rdx, rax
+	shr rdx, 32
+	mov ecx, 0xC0000101	; GS Base
+	wrmsr
+	xor eax, eax
+	xor edx, edx
+	mov ecx, 0xC0000100	; FS Base
+	wrmsr
+	
+	call syscall_init
+	
+	; Clear RBP (terminates backtraces) and enter rust
+	xor rbp, rbp
+	call ap_entry
 .dead_loop:
 	cli
 	hlt
 	jmp .dead_loop
 
+; AP startup trampoline
+; - Copied to AP_TRAMPOLINE_ADDR by the BSP (see smp.rs), and entered in real mode via a SIPI
+; - All addresses within the trampoline must be relative to AP_TRAMPOLINE_ADDR
+%define TRAMPOLINE_REL(lbl)	(AP_TRAMPOLINE_ADDR + (lbl) - ap_trampoline)
+[section .rodata]
+[BITS 16]
+EXPORT ap_trampoline
+	cli
+	cld
+	xor ax, ax
+	mov ds, ax
+	lgdt [TRAMPOLINE_REL(ap_trampoline.gdtptr)]
+	; Enter protected mode
+	mov eax, cr0
+	or al, 1
+	mov cr0, eax
+	jmp 0x18:TRAMPOLINE_REL(.pmode)
+[BITS 32]
+.pmode:
+	mov ax, 0x10
+	mov ds, ax
+	mov es, ax
+	mov ss, ax
+	; Same CR4/EFER/CR0 setup as the BSP (see `start`)
+	mov eax, cr4
+	or eax, 0x80|0x20|0x10
+	or ax, (1 << 9)|(1 << 10)
+	mov cr4, eax
+	mov eax, low_InitialPML4
+	mov cr3, eax
+	mov ecx, 0xC0000080
+	rdmsr
+	or eax, (1 << 11)|(1 << 8)|(1 << 0)	; NXE, LME, SCE
+	wrmsr
+	mov eax, cr0
+	or eax, 0x80010000|(1 << 3)|(1 << 1)	; PG & WP
+	and ax, ~(1 << 2)
+	mov cr0, eax
+	jmp 0x08:TRAMPOLINE_REL(.lmode)
+[BITS 64]
+.lmode:
+	; Still executing from the identity mapping, jump to the kernel proper
+	mov rax, ap_start64
+	jmp rax
+ALIGN 8
+.gdt:
+	; NOTE: 0x08 and 0x10 match the real GDT, so CS/SS are valid once it's loaded
+	dd 0, 0
+	dd 0x00000000, 0x00209A00	; 0x08: 64-bit Code
+	dd 0x0000FFFF, 0x00CF9200	; 0x10: Data (flat)
+	dd 0x0000FFFF, 0x00CF9A00	; 0x18: 32-bit Code (flat)
+.gdtptr:
+	dw	$-.gdt-1
+	dd	TRAMPOLINE_REL(.gdt)
+EXPORT ap_trampoline_end
+
 %include "Core/arch/amd64/interrupts.inc.asm"
 
 ; RDI: Save location for RSP
 ; RSI: New RSP (pointer)
-; RDX: New FSBASE
+; RDX: New TLS base (GS)
 ; RCX: New CR3
+; R8: Outgoing thread's "running" flag
+; R9: Incoming thread's "running" flag
 [section .text.asm.task_switch]
 EXPORT task_switch
+	pushf
+	cli
 	push rbp
 	mov rbp, rsp
 	SAVE rbx, r12, r13, r14, r15
 	
-	; Perfom context save/restore
+	; Save RSP and switch address space
 	mov [rdi], rsp	; Save RSP
-	mov rsp, [rsi]	; New RSP
 	mov cr3, rcx	; New CR3
+	; Get this CPU's data pointer (see TLSData) while the old TLS block is still ours
+	mov r10, [gs:0x20]
+	; Release the outgoing thread to other CPUs
+	; - The old stack/TLS must not be touched after this point (interrupts are off, and the below doesn't use the stack)
+	mov BYTE [r8], 0
+	; Claim the incoming thread, waiting until the CPU that last ran it has switched away
+.claim:
+	mov al, 1
+	xchg al, [r9]
+	test al, al
+	jz .claimed
+	pause
+	jmp .claim
+.claimed:
+	mov rsp, [rsi]	; New RSP
 	invlpg [rsp]
 	
-	; Update stack top (RSP0) and TLS base (GS)
+	; Pass this CPU's data pointer to the new thread
+	mov [rdx+0x20], r10
+	; Update stack top (RSP0) in this CPU's TSS
 	; TLS base and stack top are the same address.
-	; TODO: This assumes uniprocessor
-	mov [rel TSSes+tss.rsp0], rdx
-	mov rax, rdx
+	mov rax, [r10]	; CpuLocal.tss_rsp0
+	mov [rax], rdx
+	; Load the new thread's user TLS base (FS) and kernel TLS base (GS)
+	mov rsi, rdx
+	mov rax, [rsi+0x18]
+	mov rdx, rax
+	shr rdx, 32	; EDX = High
+	mov ecx, 0xC0000100	; FS Base
+	wrmsr
+	mov rax, rsi
+	mov rdx, rsi
 	shr rdx, 32	; EDX = High
 	mov ecx, 0xC0000101	; GS Base
 	wrmsr
@@ -300,6 +428,7 @@ EXPORT task_switch
 	;mov rbp, rsp
 	;int3
 	pop rbp
+	popf
 	ret
 
 [section .text]
@@ -309,21 +438,29 @@ EXPORT thread_trampoline
 	jmp rax	; 3. Jump to the thread root method, which should never return
 
 ; RDI: IP
-; RDI: SP
+; RSI: SP
 ; RDX: Arg
 EXPORT drop_to_user
-	mov rcx, rdi	; Set IP for SYSRET
 	pushf
 	cli
 	pop r11	; Set RFLAGS for SYSRET
+	mov r8, [gs:0x18]	; User TLS base (see TLSData)
 	swapgs
 	mov ax, 0x23
 	mov ds, ax
 	mov es, ax
 	mov fs, ax
 	mov gs, ax
+	; Loading FS clears its base, so set the user's TLS base afterwards
+	mov r9, rdx
+	mov eax, r8d
+	shr r8, 32
+	mov edx, r8d
+	mov ecx, 0xC0000100	; FS Base
+	wrmsr
+	mov rcx, rdi	; Set IP for SYSRET
 	mov rsp, rsi	; User's stack
-	mov rax, rdx	; Argument passed in RAX
+	mov rax, r9	; Argument passed in RAX
 	db 0x48
 	sysret
 
@@ -548,6 +685,8 @@ IDTPtr:
 	dq	IDT
 EXPORT s_tid0_tls_base
 	dq	0
+EXPORT s_ap_boot_tls
+	dq	0
 
 [section .bss]
 EXPORT TSSes
@@ -3,7 +3,7 @@
 //
 // arch/amd64/sync.rs
 //! Low-level synchronisaion primitives
-use core::sync::atomic::{AtomicBool,Ordering};
+use core::sync::atomic::{AtomicUsize,Ordering};
 
 const TRACE_IF: bool = false;
 //const TRACE_IF: bool = true;
@@ -12,7 +12,8 @@ const TRACE_IF: bool = false;
 pub struct Spinlock<T>
 {
 	#[doc(hidden)]
-	pub lock: AtomicBool,
+	/// Holding CPU number plus one (zero when unlocked)
+	pub lock: AtomicUsize,
 	#[doc(hidden)]
 	pub value: ::core::cell::UnsafeCell<T>,
 }
@@ -39,7 +40,7 @@ impl<T> Spinlock<T>
 	/// Create a new spinning lock
 	pub const fn new(val: T) -> Spinlock<T> {
 		Spinlock {
-			lock: ::core::sync::atomic::ATOMIC_BOOL_INIT, //AtomicBool::new(false),
+			lock: AtomicUsize::new(0),
 			value: ::core::cell::UnsafeCell::new(val),
 		}
 	}
@@ -63,31 +64,42 @@ impl<T> Spinlock<T>
 		HeldSpinlock { lock: self }
 	}
 	/// Attempt to acquire the lock, returning None if it is already held by this CPU
+	///
+	/// If another CPU holds the lock, this spins until it is released.
 	#[is_safe(irq)]
 	pub fn try_lock_cpu(&self) -> Option<HeldSpinlock<T>>
 	{
-		//if self.lock.compare_and_swap(0, cpu_num()+1, Ordering::Acquire) == 0
-		if self.lock.compare_and_swap(false, true, Ordering::Acquire) == false
+		let cpu = super::threads::cpu_num() + 1;
+		if self.lock.load(Ordering::Relaxed) == cpu
 		{
-			Some( HeldSpinlock { lock: self } )
+			None
 		}
 		else
 		{
-			None
+			self.inner_lock_as(cpu);
+			Some( HeldSpinlock { lock: self } )
 		}
 	}
 	
 	fn inner_lock(&self) {
-		//while self.lock.compare_and_swap(0, cpu_num()+1, Ordering::Acquire) != 0
-		while self.lock.compare_and_swap(false, true, Ordering::Acquire) == true
+		self.inner_lock_as( super::threads::cpu_num() + 1 );
+	}
+	fn inner_lock_as(&self, cpu: usize) {
+		while self.lock.compare_and_swap(0, cpu, Ordering::Acquire) != 0
 		{
+			// Wait for the lock to be released before attempting again (avoids hammering the cache line)
+			while self.lock.load(Ordering::Relaxed) != 0
+			{
+				// SAFE: Spin-loop hint
+				unsafe { asm!("pause" : : : : "volatile"); }
+			}
 		}
 		::core::sync::atomic::fence(Ordering::Acquire);
 	}
 	fn inner_release(&self) {
 		//::arch::puts("Spinlock::release()\n");
 		::core::sync::atomic::fence(Ordering::Release);
-		self.lock.store(false, Ordering::Release);
+		self.lock.store(0, Ordering::Release);
 	}
 }
 // Some special functions on non-wrapping spinlocks
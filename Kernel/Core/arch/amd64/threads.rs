@@ -4,6 +4,7 @@
 // Core/arch/amd64/threads.rs
 //! Architecture-level thread handling (helpers for ::threads).
 use prelude::*;
+use core::sync::atomic::{AtomicBool,Ordering};
 
 #[derive(Default)]//,Copy,Clone)]
 /// Low-level thread state
@@ -12,6 +13,8 @@ pub struct State
 	cr3: u64,
 	rsp: u64,
 	tlsbase: u64,
+	/// Set while a CPU is executing on this thread's stack (cleared/set by `task_switch`)
+	running: AtomicBool,
 	// Not strictly part of the CPU state, but it prevents this thread's stack from disappearing
 	stack_handle: Option< ::memory::virt::ArrayHandle<u8> >,
 	// TODO: SSE state 
@@ -27,11 +30,30 @@ impl Default for SSERegisters {
 extern "C" {
 	static InitialPML4: [u64; 512];
 	static s_tid0_tls_base: u64;
-	fn task_switch(oldrsp: &mut u64, newrsp: &u64, tlsbase: u64, cr3: u64);
+	fn task_switch(oldrsp: &mut u64, newrsp: &u64, tlsbase: u64, cr3: u64, old_running: &AtomicBool, new_running: &AtomicBool);
 }
 
 pub static S_IRQS_ENABLED: ::core::sync::atomic::AtomicBool = ::core::sync::atomic::ATOMIC_BOOL_INIT;
-static mut S_IDLE_THREAD: *mut ::threads::Thread = 0 as *mut _;
+
+#[repr(C)]
+#[derive(Copy,Clone)]
+/// Per-CPU data, referenced by the TLS block of the thread currently running on that CPU
+pub struct CpuLocal {
+	// MUST be first (used by task_switch)
+	tss_rsp0: *mut u64,
+	
+	index: usize,
+	apic_id: u32,
+	idle_thread: *mut ::threads::Thread,
+}
+const CPU_LOCAL_INIT: CpuLocal = CpuLocal {
+	tss_rsp0: 0 as *mut _,
+	index: 0,
+	apic_id: 0,
+	idle_thread: 0 as *mut _,
+	};
+// NOTE: Each entry is only written before the owning CPU is started
+static mut S_CPUS: [CpuLocal; super::tss::MAX_CPUS] = [CPU_LOCAL_INIT; super::tss::MAX_CPUS];
 
 #[repr(C)]
 /// Thread-local-storage block
@@ -44,6 +66,10 @@ struct TLSData {
 	stack_top: *const (),
 	// MUST be third (same as above)
 	user_stack: u64,
+	// MUST be fourth (loaded into FS base by drop_to_user and task_switch)
+	user_tls: u64,
+	// MUST be fifth (passed to the incoming thread by task_switch)
+	cpu: *const CpuLocal,
 	
 	// Free to reorder these
 	thread_ptr: *mut ::threads::Thread,
@@ -56,9 +82,11 @@ struct TLSData {
 /// Returns the thread state for TID0 (aka the kernel's core thread)
 pub fn init_tid0_state() -> State
 {
-	// SAFE: Called in single-threaded context... hopefully (TODO)
+	// SAFE: Called in single-threaded context (before the APs are started)
 	unsafe {
-		S_IDLE_THREAD = ::core::mem::transmute( ::threads::new_idle_thread(0) );
+		S_CPUS[0].tss_rsp0 = super::tss::rsp0_ptr(0);
+		(*get_tls_ptr()).cpu = &S_CPUS[0];
+		S_CPUS[0].idle_thread = ::core::mem::transmute( ::threads::new_idle_thread(0) );
 	}
 	// SAFE: Just taking the address
 	let cr3 = unsafe { &InitialPML4 as *const _ as u64 - super::memory::addresses::IDENT_START as u64 };
@@ -68,10 +96,71 @@ pub fn init_tid0_state() -> State
 		rsp: 0,
 		// SAFE: Doesn't change outside rust control
 		tlsbase: unsafe { s_tid0_tls_base },
+		running: AtomicBool::new(true),
 		stack_handle: None,
 		}
 }
 
+/// Prepare the per-CPU data and idle thread for an application processor
+///
+/// Returns the TLS base (and initial stack top) for the AP to start with (see `ap_start64`)
+pub fn init_ap_cpu(index: usize, apic_id: u32) -> u64
+{
+	assert!(index != 0 && index < super::tss::MAX_CPUS);
+	let idle_thread = ::threads::new_idle_thread(index);
+	// The AP starts executing on the idle thread's stack directly (without a task switch)
+	idle_thread.cpu_state.running.store(true, Ordering::Relaxed);
+	let tlsbase = idle_thread.cpu_state.tlsbase;
+	// SAFE: The target CPU isn't yet running, so nothing else is accessing its entry
+	unsafe {
+		S_CPUS[index] = CpuLocal {
+			tss_rsp0: super::tss::rsp0_ptr(index),
+			index: index,
+			apic_id: apic_id,
+			idle_thread: ::core::mem::transmute(idle_thread),
+			};
+		(*(tlsbase as *mut TLSData)).cpu = &S_CPUS[index];
+		::core::ptr::write_unaligned(S_CPUS[index].tss_rsp0, tlsbase);
+	}
+	tlsbase
+}
+
+/// Returns the index of the current CPU
+#[is_safe(irq)]
+pub fn cpu_num() -> usize
+{
+	// SAFE: Read-only access to the TLS block (the CPU pointer is only changed by this CPU)
+	unsafe {
+		let cpu = (*get_tls_ptr()).cpu;
+		if cpu.is_null() {
+			// Before `init_tid0_state`, only the BSP is running
+			0
+		}
+		else {
+			(*cpu).index
+		}
+	}
+}
+/// Returns the LAPIC ID of the current CPU
+pub fn cpu_apic_id() -> u32
+{
+	// SAFE: Read-only access to the TLS block
+	unsafe {
+		let cpu = (*get_tls_ptr()).cpu;
+		assert!( !cpu.is_null() );
+		(*cpu).apic_id
+	}
+}
+fn cur_cpu() -> &'static CpuLocal
+{
+	// SAFE: Pointer is valid once set (points into S_CPUS), and the entry isn't modified once in use
+	unsafe {
+		let cpu = (*get_tls_ptr()).cpu;
+		assert!( !cpu.is_null() );
+		&*cpu
+	}
+}
+
 impl State
 {
 	/// Construct a new empty CPU state using the provided address space
@@ -112,7 +201,9 @@ pub unsafe extern "C" fn prep_tls(top: usize, _bottom: usize, thread_ptr: *mut :
 		self_ptr: data_ptr,
 		stack_top: tlsblock as *const (),
 		user_stack: 0,
+		user_tls: 0,
 		
+		cpu: 0 as *const _,
 		thread_ptr: thread_ptr,
 		thread_ptr_lent: false,
 		sse_registers: None,
@@ -154,6 +245,8 @@ pub fn start_thread<F: FnOnce()+Send>(thread: &mut ::threads::Thread, code: F)
 		stack_top -= 8; ::core::ptr::write(stack_top as *mut u64, thread_root::<F> as usize as u64);
 		// Trampoline that sets RDI to the address of 'code'
 		stack_top -= 8; ::core::ptr::write(stack_top as *mut u64, thread_trampoline as usize as u64);
+		// RFLAGS restored by task_switch (interrupts enabled)
+		stack_top -= 8; ::core::ptr::write(stack_top as *mut u64, 0x202);
 		// Six callee-save GPRs saved by task_switch
 		stack_top -= 8; ::core::ptr::write(stack_top as *mut u64, 0xB4);
 		stack_top -= 8; ::core::ptr::write(stack_top as *mut u64, 0xBB);
@@ -189,10 +282,23 @@ pub fn start_thread<F: FnOnce()+Send>(thread: &mut ::threads::Thread, code: F)
 pub fn get_idle_thread() -> ::threads::ThreadPtr
 {
 	// TODO: Shared mutability shouldn't be an issue (this thread pointer should not be created twice)
-	// SAFE: Passes a static pointer. `static mut` should be initialised
+	// SAFE: Passes a static pointer. Each CPU's idle thread is only ever run by that CPU
 	unsafe {
-		assert!(S_IDLE_THREAD != 0 as *mut _);
-		::threads::ThreadPtr::new_static( &mut *S_IDLE_THREAD )
+		let idle = cur_cpu().idle_thread;
+		assert!(idle != 0 as *mut _);
+		::threads::ThreadPtr::new_static( &mut *idle )
+	}
+}
+
+/// Wait until the passed thread state is no longer in use by any CPU
+///
+/// Called before a terminated thread is released, as the CPU it was running on may still be switching away.
+pub fn wait_stopped(state: &State)
+{
+	while state.running.load(Ordering::Acquire)
+	{
+		// SAFE: Spin-loop hint
+		unsafe { asm!("pause" : : : : "volatile"); }
 	}
 }
 
@@ -205,6 +311,16 @@ pub fn switch_to(newthread: ::threads::ThreadPtr)
 		// was interrupted by an IRQ. If said thread attempts to sleep, it's an error
 		assert!( newthread.is_runnable() );
 	}
+	else if &*newthread as *const _ == borrow_thread()
+	{
+		// Switching to self (thread was woken before it switched away), nothing to do
+		// SAFE: Valid pointer access
+		unsafe
+		{
+			(*get_tls_ptr()).thread_ptr_lent = false;
+			::core::mem::forget(newthread);
+		}
+	}
 	else
 	{
 		if true && S_IRQS_ENABLED.load(::core::sync::atomic::Ordering::Relaxed) {
@@ -228,7 +344,9 @@ pub fn switch_to(newthread: ::threads::ThreadPtr)
 			
 			assert!( *(outstate.tlsbase as *const usize) != 0, "outstate TLS Base clobbered before switch" );
 			assert!( *(state.tlsbase as *const usize) != 0, "TLS Base clobbered before switch" );
-			task_switch(&mut outstate.rsp, &state.rsp, state.tlsbase, state.cr3);
+			// NOTE: The new thread may still be running on another CPU, task_switch waits for it to be released
+			// - It also loads the new thread's user TLS base (FS) and hands over this CPU's data pointer
+			task_switch(&mut outstate.rsp, &state.rsp, state.tlsbase, state.cr3, &outstate.running, &state.running);
 		}
 		
 		// SAFE: Valid pointer access
@@ -240,6 +358,20 @@ pub fn switch_to(newthread: ::threads::ThreadPtr)
 	}
 }
 
+/// Set the user-mode thread pointer (FS base) for the current thread
+pub fn set_user_tls(base: usize)
+{
+	// SAFE: Thread-local data, and FS isn't used by the kernel
+	unsafe {
+		(*get_tls_ptr()).user_tls = base as u64;
+		set_fs_base(base as u64);
+	}
+}
+unsafe fn set_fs_base(base: u64)
+{
+	asm!("wrmsr" : : "c" (0xC0000100u32), "a" (base as u32), "d" ((base >> 32) as u32) : : "volatile");
+}
+
 fn get_tls_ptr() -> *mut TLSData {
 	let ret;
 	// SAFE: Just obtains the pointer from %gs
@@ -8,7 +8,7 @@
 module_define!(TSS, [], init);
 
 // NOTE: MUST match the value in common.inc.asm
-const MAX_CPUS: usize = 1;
+pub const MAX_CPUS: usize = 16;
 
 #[repr(C,packed)]
 struct TSS
@@ -28,7 +28,7 @@ struct GDTEnt(u32,u32);
 
 extern "C" {
 	static mut GDT: [GDTEnt; 7+MAX_CPUS*2];
-	static mut TSSes: [TSS; 4];
+	static mut TSSes: [TSS; MAX_CPUS];
 	
 	static s_tid0_tls_base: u64;
 }
@@ -45,9 +45,31 @@ fn init()
 		TSSes[0].rsp0 = s_tid0_tls_base as u64;
 	}
 	
-	// SAFE: Just setting the task register
+	load_tr(0);
+}
+
+/// Load the task register for an application processor
+pub fn init_ap(cpu: usize)
+{
+	assert!(cpu < MAX_CPUS);
+	load_tr(cpu);
+}
+
+/// Obtain a pointer to the RSP0 field of the specified CPU's TSS (updated by `task_switch`)
+pub fn rsp0_ptr(cpu: usize) -> *mut u64
+{
+	assert!(cpu < MAX_CPUS);
+	// SAFE: Just taking the address (field is unaligned, so can't borrow it)
+	unsafe {
+		(&TSSes[cpu] as *const TSS as usize + 4) as *mut u64
+	}
+}
+
+fn load_tr(cpu: usize)
+{
+	// SAFE: Just setting the task register (descriptors are populated by `init`)
 	unsafe {
-		asm!("ltr %cx" : : "{ecx}" (7*8) );
+		asm!("ltr %cx" : : "{ecx}" ((7+cpu*2)*8) );
 	}
 }
 
@@ -81,3 +81,8 @@ pub fn bind_gsi(gsi: usize, handler: fn(*const()), info: *const ()) -> Result<IR
 	}
 }
 
+pub struct MsiHandle;
+pub fn bind_msi(_handler: fn(*const()), _info: *const ()) -> Result<(MsiHandle, u64, u32),()> {
+	// TODO: MSI support on the GIC (v2m frame)
+	Err( () )
+}
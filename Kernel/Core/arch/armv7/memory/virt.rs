@@ -698,6 +698,17 @@ pub struct AbortRegs
 #[no_mangle]
 pub fn data_abort_handler(pc: u32, reg_state: &AbortRegs, dfar: u32, dfsr: u32) {
 
+	// Translation faults (or permission faults on write) can be demand-paged user memory
+	// - Only from usermode (populating can sleep), buffers used by the kernel are prefaulted (see `memory::demand::prefault`)
+	let is_write = dfsr & (1 << 11) != 0;
+	let from_user = reg_state.spsr & 0x1F == 0x10;
+	match dfsr & 0x40F
+	{
+	0x005 | 0x007 if from_user => if ::memory::demand::handle_fault(dfar as usize, is_write) { return ; },
+	0x00D | 0x00F if from_user && is_write => if ::memory::demand::handle_fault(dfar as usize, is_write) { return ; },
+	_ => {},
+	}
+
 	log_warning!("Data abort by {:#x} address {:#x} status {:#x} ({})", pc, dfar, dfsr, fsr_name(dfsr));
 	dump_tables();
 	//log_debug!("Registers:");
@@ -707,16 +718,25 @@ pub fn data_abort_handler(pc: u32, reg_state: &AbortRegs, dfar: u32, dfsr: u32)
 	if ent.mode() == ProtectionMode::UserCOW {
 		// 1. Lock (relevant) address space
 		// SAFE: Changes to address space are transparent
+		let mut handled = false;
 		::memory::virt::with_lock(dfar as usize, || unsafe {
 			let frame = ent.phys_addr();
 			// 2. Get the PMM to provide us with a unique copy of that frame (can return the same addr)
-			let newframe = ::memory::phys::make_unique( frame, &*(((dfar as usize) & !PAGE_MASK) as *const [u8; PAGE_SIZE]) );
-			// 3. Remap to this page as UserRW (because COW is user-only atm)
-			ent.set(newframe, ProtectionMode::UserRW);
-			log_debug!("- COW frame copied");
+			match ::memory::phys::make_unique( frame, &*(((dfar as usize) & !PAGE_MASK) as *const [u8; PAGE_SIZE]) )
+			{
+			Ok(newframe) => {
+				// 3. Remap to this page as UserRW (because COW is user-only atm)
+				ent.set(newframe, ProtectionMode::UserRW);
+				log_debug!("- COW frame copied");
+				handled = true;
+				},
+			Err(_) => log_warning!("- Out of memory copying COW frame"),
+			}
 			});
 
-		return ;
+		if handled {
+			return ;
+		}
 	}
 	
 	if pc < 0x8000_0000 {
@@ -109,6 +109,10 @@ pub fn puth(v: u64) {
 pub fn cur_timestamp() -> u64 {
 	0
 }
+/// No-op: There is no timer support on this architecture (`cur_timestamp` is always zero), so kernel timers are
+/// amd64-only for now.
+pub fn request_tick(_target_ms: u64) {
+}
 
 pub fn print_backtrace() {
 	let rs = aeabi_unwind::UnwindState::new_cur();
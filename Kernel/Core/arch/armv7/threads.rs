@@ -4,6 +4,8 @@
 pub struct State {
 	sp: usize,
 	ttbr0: u32,
+	/// User-mode thread pointer (TPIDRURO)
+	user_tls: usize,
 	stack_handle: Option< ::memory::virt::ArrayHandle<u8> >,
 }
 
@@ -13,6 +15,7 @@ impl State
 		State {
 			sp: 0,
 			ttbr0: address_space.get_ttbr0(),
+			user_tls: 0,
 			stack_handle: None,
 		}
 	}
@@ -82,9 +85,21 @@ pub fn switch_to(thread: ::threads::ThreadPtr) {
 		let new_sp = thread.cpu_state.sp;
 		let new_ttbr0 = thread.cpu_state.ttbr0;
 		log_trace!("Switching to SP={:#x},TTBR0={:#x}", new_sp, new_ttbr0);
+		asm!("mcr p15,0, $0, c13,c0,3" : : "r" (thread.cpu_state.user_tls));
 		task_switch(&mut outstate.sp, new_sp, new_ttbr0, thread.into_usize());
 	}
 }
+/// Wait until the passed thread is no longer running (uniprocessor only, so nothing to wait for)
+pub fn wait_stopped(_state: &State) {
+}
+/// Set the user-mode thread pointer for the current thread
+pub fn set_user_tls(base: usize) {
+	// SAFE: Current thread's state, and TPIDRURO isn't used by the kernel
+	unsafe {
+		(*borrow_thread_mut()).cpu_state.user_tls = base;
+		asm!("mcr p15,0, $0, c13,c0,3" : : "r" (base));
+	}
+}
 pub fn idle() {
 	log_trace!("idle");
 	// SAFE: Calls 'wait for interrupt'
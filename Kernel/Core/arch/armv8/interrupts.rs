@@ -8,3 +8,9 @@ pub fn bind_gsi(idx: usize, handler: fn(*const ()), info: *const ()) -> Result<I
 	Ok(IRQHandle)
 }
 
+pub struct MsiHandle;
+pub fn bind_msi(_handler: fn(*const ()), _info: *const ()) -> Result<(MsiHandle, u64, u32),BindError> {
+	// TODO: GICv2m/ITS support
+	Err(BindError)
+}
+
@@ -48,6 +48,10 @@ pub fn print_backtrace() {
 pub fn cur_timestamp() -> u64 {
 	0
 }
+/// No-op: There is no timer support on this architecture (`cur_timestamp` is always zero), so kernel timers are
+/// amd64-only for now.
+pub fn request_tick(_target_ms: u64) {
+}
 
 extern "C" {
 	pub fn drop_to_user(entry: usize, stack: usize, args_len: usize) -> !;
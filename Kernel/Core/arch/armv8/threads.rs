@@ -83,6 +83,16 @@ pub fn switch_to(thread: ::threads::ThreadPtr) {
 		task_switch(&mut outstate.sp, new_sp, new_ttbr0, thread.into_usize());
 	}
 }
+/// Wait until the passed thread is no longer running (uniprocessor only, so nothing to wait for)
+pub fn wait_stopped(_state: &State) {
+}
+/// Set the user-mode thread pointer for the current thread (saved/restored by task_switch)
+pub fn set_user_tls(base: usize) {
+	// SAFE: TPIDR_EL0 isn't used by the kernel
+	unsafe {
+		asm!("msr TPIDR_EL0, $0" : : "r"(base));
+	}
+}
 pub fn idle() {
 	log_trace!("idle");
 	// SAFE: Calls 'wait for interrupt'
@@ -145,6 +145,10 @@ pub mod interrupts {
 	pub fn bind_gsi(_gsi: usize, _handler: fn(*const()), _info: *const ()) -> Result<IRQHandle, BindError> {
 		todo!("bind_gsi")
 	}
+	pub struct MsiHandle;
+	pub fn bind_msi(_handler: fn(*const()), _info: *const ()) -> Result<(MsiHandle, u64, u32), BindError> {
+		todo!("bind_msi")
+	}
 }
 pub mod boot {
 	pub fn get_boot_string() -> &'static str {
@@ -192,6 +196,8 @@ pub mod threads {
 	}
 	pub fn switch_to(_t: ::threads::ThreadPtr) {
 	}
+	pub fn wait_stopped(_s: &State) {
+	}
 
 	pub fn start_thread<F: FnOnce()+Send+'static>(_thread: &mut ::threads::Thread, _code: F) {
 	}
@@ -215,6 +221,8 @@ pub fn puth(_v: u64) {
 pub fn cur_timestamp() -> u64 {
 	0
 }
+pub fn request_tick(_target_ms: u64) {
+}
 pub fn print_backtrace() {
 }
 
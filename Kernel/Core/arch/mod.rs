@@ -215,12 +215,20 @@ pub mod interrupts {
 
 	pub type BindError = imp::BindError;
 	pub type IRQHandle = imp::IRQHandle;
+	pub type MsiHandle = imp::MsiHandle;
 
 	
 	#[inline]
 	pub fn bind_gsi(gsi: usize, handler: fn(*const()), info: *const ()) -> Result<IRQHandle, BindError> {
 		imp::bind_gsi(gsi, handler, info)
 	}
+	/// Allocate and bind a message-signalled interrupt
+	///
+	/// Returns the handle, along with the address and data value the device should write to raise the interrupt
+	#[inline]
+	pub fn bind_msi(handler: fn(*const()), info: *const ()) -> Result<(MsiHandle, u64, u32), BindError> {
+		imp::bind_msi(handler, info)
+	}
 }
 pub mod boot {
 	use super::imp::boot as imp;
@@ -285,6 +293,10 @@ pub mod threads {
 	pub fn switch_to(t: ::threads::ThreadPtr) {
 		imp::switch_to(t)
 	}
+	#[inline]
+	pub fn wait_stopped(s: &State) {
+		imp::wait_stopped(s)
+	}
 
 	#[inline]
 	pub fn start_thread<F: FnOnce()+Send+'static>(thread: &mut ::threads::Thread, code: F) {
@@ -324,6 +336,13 @@ pub fn puth(v: u64) {
 pub fn cur_timestamp() -> u64 {
 	imp::cur_timestamp()
 }
+/// Request that the timer interrupt fires at (or before) the specified timestamp
+///
+/// NOTE: Only amd64 has timer hardware support, on other architectures timers (and `time::sleep_ms`) never fire.
+#[inline]
+pub fn request_tick(target_ms: u64) {
+	imp::request_tick(target_ms)
+}
 #[inline]
 pub fn print_backtrace() {
 	imp::print_backtrace()
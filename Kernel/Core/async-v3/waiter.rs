@@ -200,6 +200,31 @@ impl<'h, 'a: 'h> Waiter<'h, 'a>
 			self.sleeper.wait();
 		}
 	}
+	/// Waits for an operation to complete, giving up once the tick count `deadline` has passed
+	///
+	/// Returns `Err(())` on timeout (the operations are still in progress), or `Ok(None)` if all operations are complete.
+	pub fn wait_one_until(&self, deadline: ::time::TickCount) -> Result<Option<WaitResult>, ()>
+	{
+		if self.handles.iter().all(|h| h.stack.lock().is_empty())
+		{
+			return Ok(None);
+		}
+
+		let _reg = HandleSleepReg::new(&self.sleeper, self.handles);
+		let _timer = ::time::Timer::new(deadline, &self.sleeper);
+		loop
+		{
+			if let Some(rv) = self.check_one()
+			{
+				return Ok(Some(rv));
+			}
+			if ::time::ticks() >= deadline
+			{
+				return Err( () );
+			}
+			self.sleeper.wait();
+		}
+	}
 }
 
 // -----------
@@ -181,6 +181,7 @@ pub enum WaitError
 
 /// Wait on the provided list of Waiter trait objects
 ///
+/// `timeout` is a duration in milliseconds, if it elapses before any waiter fires `Some(0)` is returned.
 pub fn wait_on_list(waiters: &mut [&mut Waiter], timeout: Option<u64>) -> Option<usize>
 {
 	log_trace!("wait_on_list(waiters = {:?}, timeout = {:?})", waiters, timeout);
@@ -189,10 +190,6 @@ pub fn wait_on_list(waiters: &mut [&mut Waiter], timeout: Option<u64>) -> Option
 		panic!("wait_on_list - Nothing to wait on");
 	}
 	
-	if timeout.is_some() {
-		todo!("Support timeouts in wait_on_list");
-	}
-	
 	// Wait on primitives from the waiters, returning the indexes of those that need a state advance
 	
 	// - If there are no incomplete waiters, return None
@@ -202,6 +199,7 @@ pub fn wait_on_list(waiters: &mut [&mut Waiter], timeout: Option<u64>) -> Option
 	
 	// - Create an object for them to signal
 	let mut obj = ::threads::SleepObject::new("wait_on_list");
+	let deadline = timeout.map(|t| ::time::ticks() + t);
 	let force_poll = waiters.iter_mut()
 		.filter( |x| !x.is_complete() )
 		.fold(false, |v,x| v | !x.get_waiter().bind_signal( &mut obj) )
@@ -226,6 +224,10 @@ pub fn wait_on_list(waiters: &mut [&mut Waiter], timeout: Option<u64>) -> Option
 				}
 			}
 			n_passes += 1;
+			if deadline.map(|d| ::time::ticks() >= d).unwrap_or(false) {
+				log_trace!("- Timed out");
+				break 'outer;
+			}
 			// TODO: Take a short nap
 		}
 		log_trace!("- Fire ({} passes)", n_passes);
@@ -234,6 +236,8 @@ pub fn wait_on_list(waiters: &mut [&mut Waiter], timeout: Option<u64>) -> Option
 	{
 		// - Wait the current thread on that object
 		log_trace!(" Sleeping");
+		// - The timer handle must be dropped before `obj`
+		let _timer = deadline.map(|d| ::time::Timer::new(d, &obj));
 		obj.wait();
 	}
 	
@@ -78,6 +78,22 @@ pub trait BusDevice:
 	fn bind_io(&mut self, block_id: usize) -> IOBinding;
 	/// Obtain the specified interrupt vector
 	fn get_irq(&mut self, idx: usize) -> u32;
+	
+	/// Read a 32-bit word from the device's configuration space (`ofs` is in bytes)
+	///
+	/// Only meaningful on busses with a configuration space (e.g. PCI), others return zero.
+	fn config_read(&self, _ofs: usize) -> u32 {
+		0
+	}
+	/// Write a 32-bit word to the device's configuration space (`ofs` is in bytes)
+	fn config_write(&mut self, ofs: usize, _value: u32) {
+		log_warning!("config_write({:#x}) on bus without a configuration space", ofs);
+	}
+
+	/// Obtain the bus-specific device object (for drivers that need more than the generic interface, e.g. USB class drivers)
+	fn bus_specific(&self) -> Option<&::core::any::Any> {
+		None
+	}
 }
 
 /// Abstract driver for a device (creates instances when passed a device)
@@ -127,10 +143,14 @@ struct Device
 /// Internal representation of a bus
 struct Bus
 {
+	id: usize,
 	manager: &'static BusManager,
 	devices: Vec<Device>,
 }
 
+/// Handle to a registered bus, used to add and remove devices after registration (e.g. hot-plug)
+pub struct BusHandle(usize);
+
 /// List of registered busses on the system
 #[allow(non_upper_case_globals)]
 static s_root_busses: Mutex<Queue<Bus>> = mutex_init!(queue_init!());
@@ -147,9 +167,13 @@ fn init()
 /// Register a bus with the device manager
 ///
 /// Creates a new internal representation of the bus, containg the passed set of devices.
-pub fn register_bus(manager: &'static BusManager, devices: Vec<Box<BusDevice>>) //-> BusHandle
+pub fn register_bus(manager: &'static BusManager, devices: Vec<Box<BusDevice>>) -> BusHandle
 {
+	use core::sync::atomic::{AtomicUsize,Ordering};
+	static S_NEXT_BUS_ID: AtomicUsize = ::core::sync::atomic::ATOMIC_USIZE_INIT;
+	let id = S_NEXT_BUS_ID.fetch_add(1, Ordering::Relaxed);
 	let bus = Bus {
+		id: id,
 		manager: manager,
 		// For each device, locate a driver
 		devices: devices.into_iter().map(|mut d| Device {
@@ -160,8 +184,50 @@ pub fn register_bus(manager: &'static BusManager, devices: Vec<Box<BusDevice>>)
 		};
 	let mut bus_list_lh = s_root_busses.lock();
 	bus_list_lh.push(bus);
-	//let ptr: *const _ = bus_list_lh.last().unwrap();
-	//BusHandle(ptr)
+	BusHandle(id)
+}
+
+impl BusHandle
+{
+	/// Add a device to the bus, binding the best avaliable driver
+	pub fn add_device(&self, mut bus_dev: Box<BusDevice>)
+	{
+		let manager = self.with_bus(|bus| bus.manager);
+		// NOTE: The driver is bound without the bus list locked, as binding may add/remove other devices
+		let driver = find_driver(manager, &mut *bus_dev);
+		self.with_bus(|bus| bus.devices.push(Device {
+			driver: driver,
+			bus_dev: bus_dev,
+			}));
+	}
+	/// Remove the device with the specified address (dropping its driver instance)
+	pub fn remove_device(&self, addr: u32)
+	{
+		let dev = self.with_bus(|bus| match bus.devices.iter().position(|d| d.bus_dev.addr() == addr)
+			{
+			Some(i) => Some(bus.devices.remove(i)),
+			None => None,
+			});
+		match dev
+		{
+		Some(dev) => {
+			log_debug!("Removing device {:x}", addr);
+			// Driver instance is dropped before the bus device
+			drop(dev.driver);
+			},
+		None => log_warning!("remove_device: No device with address {:x}", addr),
+		}
+	}
+
+	fn with_bus<R, F: FnOnce(&mut Bus)->R>(&self, f: F) -> R
+	{
+		let mut lh = s_root_busses.lock();
+		match lh.iter_mut().find(|b| b.id == self.0)
+		{
+		Some(bus) => f(bus),
+		None => panic!("BusHandle refers to an unknown bus ({})", self.0),
+		}
+	}
 }
 
 /// Registers a driver with the device manger
@@ -175,6 +175,19 @@ impl ::device_manager::BusDevice for PCIDev
 			todo!("PCI get_irq {} > 0", idx);
 		}
 	}
+	fn config_read(&self, ofs: usize) -> u32
+	{
+		assert!(ofs % 4 == 0 && ofs < 256, "PCI config_read - Bad offset {:#x}", ofs);
+		read_word(self.addr, (ofs / 4) as u8)
+	}
+	fn config_write(&mut self, ofs: usize, value: u32)
+	{
+		assert!(ofs % 4 == 0 && ofs < 256, "PCI config_write - Bad offset {:#x}", ofs);
+		if ofs / 4 < self.config.len() {
+			self.config[ofs / 4] = value;
+		}
+		write_word(self.addr, (ofs / 4) as u8, value)
+	}
 }
 
 fn scan_bus(bus_id: u8) -> Vec<Box<BusDevice+'static>>
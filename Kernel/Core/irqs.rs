@@ -17,6 +17,13 @@ pub struct EventHandle
 	event: Arc<::async::event::Source>,
 }
 pub struct ObjectHandle( BindingHandle );
+/// A handle for a message-signalled interrupt binding, see `bind_msi_object`
+pub struct MsiHandle
+{
+	_binding: BindingHandle,
+	addr: u64,
+	data: u32,
+}
 
 struct BindingHandle(u32, u32);
 
@@ -24,6 +31,7 @@ struct BindingHandle(u32, u32);
 struct IRQBinding
 {
 	arch_handle: interrupts::IRQHandle,
+	msi_handle: Option<interrupts::MsiHandle>,
 	has_fired: AtomicBool,	// Set to true if the IRQ fires while the lock is held by this CPU
 	//handlers: Spinlock<Queue<Handler>>,
 	handlers: Spinlock<Vec<Box<FnMut()->bool + Send + 'static>>>,
@@ -33,8 +41,12 @@ struct Bindings
 {
 	mapping: VecMap<u32, Box<IRQBinding>>,
 	next_index: usize,
+	next_msi: u32,
 }
 
+/// Base of the binding numbers used for MSIs (above any valid GSI)
+const MSI_NUM_BASE: u32 = 0x1_0000;
+
 // Notes:
 // - Store a map of interrupt IDs against 
 // - Hand out 'Handle' structures containing a pointer to the handler on that queue?
@@ -57,7 +69,7 @@ fn bind(num: u32, obj: Box<FnMut()->bool + Send>) -> BindingHandle
 {	
 	log_trace!("bind(num={}, obj={:?})", num, "TODO"/*obj*/);
 	// 1. (if not already) bind a handler on the architecture's handlers
-	let mut map_lh = S_IRQ_BINDINGS.lock_init(|| Bindings { mapping: VecMap::new(), next_index: 0 });
+	let mut map_lh = S_IRQ_BINDINGS.lock_init(|| Bindings { mapping: VecMap::new(), next_index: 0, next_msi: 0 });
 	let index = map_lh.next_index;
 	map_lh.next_index += 1;
 	let binding = match map_lh.mapping.entry(num)
@@ -114,6 +126,39 @@ pub fn bind_object(num: u32, obj: Box<FnMut()->bool + Send + 'static>) -> Object
 	ObjectHandle( bind(num, obj) )
 }
 
+/// Allocate a message-signalled interrupt and bind a handler to it
+///
+/// Returns None if the platform does not support MSIs (or has run out of vectors)
+pub fn bind_msi_object(obj: Box<FnMut()->bool + Send + 'static>) -> Option<MsiHandle>
+{
+	let mut map_lh = S_IRQ_BINDINGS.lock_init(|| Bindings { mapping: VecMap::new(), next_index: 0, next_msi: 0 });
+	let mut binding = Box::new( IRQBinding::default() );
+	let context = &*binding as *const IRQBinding as *const ();
+	let (handle, addr, data) = match interrupts::bind_msi(IRQBinding::handler_raw, context)
+		{
+		Ok(v) => v,
+		Err(e) => {
+			log_notice!("Unable to allocate MSI: {:?}", e);
+			return None;
+			},
+		};
+	binding.msi_handle = Some(handle);
+	binding.handlers.lock().push( obj );
+	
+	let num = MSI_NUM_BASE + map_lh.next_msi;
+	map_lh.next_msi += 1;
+	let index = map_lh.next_index;
+	map_lh.next_index += 1;
+	map_lh.mapping.insert(num, binding);
+	log_trace!("bind_msi_object: #{} addr={:#x} data={:#x}", num, addr, data);
+	
+	Some(MsiHandle {
+		_binding: BindingHandle(num, index as u32),
+		addr: addr,
+		data: data,
+		})
+}
+
 impl IRQBinding
 {
 	fn new_boxed(num: u32) -> Box<IRQBinding>
@@ -151,6 +196,18 @@ impl IRQBinding
 	}
 }
 
+impl MsiHandle
+{
+	/// Address the device must write to in order to raise this interrupt
+	pub fn addr(&self) -> u64 {
+		self.addr
+	}
+	/// Value the device must write to `addr`
+	pub fn data(&self) -> u32 {
+		self.data
+	}
+}
+
 impl EventHandle
 {
 	pub fn get_event(&self) -> &::async::event::Source
@@ -17,6 +17,7 @@ pub struct Arc<T: ?Sized>
 unsafe impl<T: ?Sized + Send+Sync> Send for Arc<T> {}
 // Sync if internals are Send+Sync (Sync allows &Arc which allows cloning)
 unsafe impl<T: ?Sized + Send+Sync> Sync for Arc<T> {}
+impl<T: ?Sized, U: ?Sized> ops::CoerceUnsized<Arc<U>> for Arc<T> where T: ::core::marker::Unsize<U> {}
 
 impl<T> Arc<T>
 {
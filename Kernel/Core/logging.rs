@@ -10,10 +10,12 @@
 // > Cache/buffer sink
 // > Display sink
 // > Serial sink
+// > External sinks (registered by drivers, e.g. a VirtIO console)
 #[allow(unused_imports)]
 use prelude::*;
 use core::fmt;
 use arch::sync::Spinlock;
+use lib::mem::aref::ArefBorrow;
 
 /// Log level, ranging from a kernel panic down to tracing
 #[repr(u16)]
@@ -54,10 +56,14 @@ pub enum Colour
 pub struct LoggingFormatter<'a>
 {
 	lock_handle: ::arch::sync::HeldSpinlock<'a,Sinks>,
+	// NOTE: Must be after the lock handle, so external sinks are flushed once the lock is released
+	flush: PendingFlush,
 	
-	// NOTE: Must be second, forcing interrupts to be reenabled after the lock is released
+	// NOTE: Must be last, forcing interrupts to be reenabled after the lock is released
 	_irq_handle: ::arch::sync::HeldInterrupts,
 }
+/// External sinks to flush once the logging lock has been released
+struct PendingFlush([Option<ArefBorrow<ExternalSink>>; 4]);
 
 /// Wrapper around a &-ptr that prints a hexdump of the passed data.
 pub struct HexDump<'a,T: ?Sized + 'a>(pub &'a T);
@@ -65,7 +71,29 @@ pub struct HexDump<'a,T: ?Sized + 'a>(pub &'a T);
 /// Wrapper around a `&[u8]` to print it as an escaped byte string
 pub struct RawString<'a>(pub &'a [u8]);
 
-static S_LOGGING_LOCK: Spinlock<Sinks> = Spinlock::new( Sinks { serial: serial::Sink, memory: None, video: None } );
+static S_LOGGING_LOCK: Spinlock<Sinks> = Spinlock::new( Sinks {
+	serial: serial::Sink, memory: None, video: None,
+	external: [None, None, None, None],
+	} );
+
+/// Log sink provided by a driver
+///
+/// NOTE: `start`/`write`/`end` are called with the logging lock held and interrupts disabled, so must not block (or log).
+/// Drivers should buffer the data there, and hand it to the hardware from `flush` (or elsewhere).
+pub trait ExternalSink: Send + Sync
+{
+	/// Start a new log entry
+	fn start(&self, timestamp: ::time::TickCount, level: Level, source: &'static str);
+	/// Append data to the current log entry
+	fn write(&self, data: &str);
+	/// End a log entry
+	fn end(&self);
+	/// Called after an entry is complete and the logging lock has been released (interrupts are still disabled)
+	fn flush(&self) {
+	}
+}
+/// Handle to a registered external sink, removes the sink when dropped
+pub struct ExternalSinkHandle(usize);
 
 trait Sink
 {
@@ -81,6 +109,7 @@ struct Sinks
 	serial: serial::Sink,
 	memory: Option<memory::Sink>,
 	video: Option<video::Sink>,
+	external: [Option<ArefBorrow<ExternalSink>>; 4],
 }
 
 mod serial
@@ -268,6 +297,22 @@ impl Sinks
 		f(&mut self.serial);
 		self.memory.as_mut().map(|x| f(x));
 		self.video .as_mut().map(|x| f(x));
+		for x in self.external.iter_mut() {
+			x.as_mut().map(|x| f(x));
+		}
+	}
+}
+
+impl Sink for ArefBorrow<ExternalSink>
+{
+	fn start(&mut self, timestamp: ::time::TickCount, level: Level, source: &'static str) {
+		(**self).start(timestamp, level, source);
+	}
+	fn write(&mut self, s: &str) {
+		(**self).write(s);
+	}
+	fn end(&mut self) {
+		(**self).end();
 	}
 }
 
@@ -280,7 +325,8 @@ impl<'a> LoggingFormatter<'a>
 		// TODO: if S_LOGGING_LOCK is held by the current CPU, error.
 		let mut rv = LoggingFormatter {
 				_irq_handle: ::arch::sync::hold_interrupts(),
-				lock_handle: S_LOGGING_LOCK.lock()
+				lock_handle: S_LOGGING_LOCK.lock(),
+				flush: PendingFlush([None, None, None, None]),
 			};
 		let ts = ::time::ticks();
 		rv.lock_handle.foreach_mut(|x| x.start(ts, level, modname));
@@ -301,6 +347,21 @@ impl<'a> ::core::ops::Drop for LoggingFormatter<'a>
 	fn drop(&mut self)
 	{
 		self.lock_handle.foreach_mut(|x| x.end());
+		// Take a borrow of each external sink, they're flushed once the lock is released (by the drop of `flush`)
+		for (dst, src) in Iterator::zip( self.flush.0.iter_mut(), self.lock_handle.external.iter() ) {
+			*dst = src.as_ref().map(|x| x.reborrow());
+		}
+	}
+}
+impl ::core::ops::Drop for PendingFlush
+{
+	fn drop(&mut self)
+	{
+		for s in self.0.iter() {
+			if let Some(ref s) = *s {
+				s.flush();
+			}
+		}
 	}
 }
 
@@ -443,6 +504,29 @@ pub fn start_memory_sink() {
 	}
 }
 
+/// Register an external log sink (returns `None` if all slots are in use)
+pub fn register_external_sink(sink: ArefBorrow<ExternalSink>) -> Option<ExternalSinkHandle> {
+	let _irq = ::arch::sync::hold_interrupts();
+	let mut lh = S_LOGGING_LOCK.lock();
+	for (i, slot) in lh.external.iter_mut().enumerate()
+	{
+		if slot.is_none() {
+			*slot = Some(sink);
+			return Some( ExternalSinkHandle(i) );
+		}
+	}
+	None
+}
+impl ::core::ops::Drop for ExternalSinkHandle
+{
+	fn drop(&mut self) {
+		let _irq = ::arch::sync::hold_interrupts();
+		let mut lh = S_LOGGING_LOCK.lock();
+		assert!(lh.external[self.0].is_some());
+		lh.external[self.0] = None;
+	}
+}
+
 #[doc(hidden)]
 /// Returns true if the passed combination of module and level is enabled
 pub fn enabled(level: Level, modname: &str) -> bool
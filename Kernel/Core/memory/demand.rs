@@ -0,0 +1,439 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/memory/demand.rs
+//! Demand-paged user memory
+//!
+//! Regions registered here aren't backed by memory until they're accessed, the page fault handler calls
+//! `handle_fault` to populate each page on first use.
+//! - Only faults from userland are handled this way (populating a page can sleep and do IO), user buffers accessed by
+//!   the kernel are populated beforehand using `prefault` (see `memory::freeze`).
+//! - Anonymous regions are filled with zeroes.
+//! - File-backed regions map frames from the backing's page cache. Private mappings are mapped copy-on-write,
+//!   shared writable mappings track the pages written to and write them back when unmapped.
+//!
+//! Regions are per-process, and are released (with any writeback) when the process terminates.
+#[allow(unused_imports)]
+use prelude::*;
+use lib::mem::Arc;
+use memory::phys::FrameHandle;
+use memory::virt::{MapError,ProtectionMode};
+use PAGE_SIZE;
+
+/// Source of page data for a file-backed region
+pub trait Backing: Send + Sync
+{
+	/// Obtain the cached frame holding the page at (page-aligned) offset `ofs`
+	///
+	/// Bytes past the end of the file read as zero.
+	fn get_page(&self, ofs: u64) -> Result<FrameHandle, ()>;
+	/// Write a modified page (previously returned by `get_page`) back to the file
+	fn write_page(&self, ofs: u64, frame: &FrameHandle) -> Result<(), ()>;
+	/// Called once the page at `ofs` is no longer mapped by this region, allowing the cached copy to be dropped
+	fn release_page(&self, ofs: u64);
+}
+
+/// Contents of a demand-paged region
+#[derive(Clone)]
+pub enum Source
+{
+	/// Anonymous memory, zero-filled on first access
+	Zero,
+	/// File data from `ofs` (page-aligned), `len` bytes long. The rest of the region is zero-filled
+	File {
+		backing: Arc<Backing>,
+		ofs: u64,
+		len: u64,
+	},
+}
+
+/// Access allowed to a demand-paged region
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Mode
+{
+	/// Read-only
+	ReadOnly,
+	/// Read-execute
+	Execute,
+	/// Private read-write (file pages are copied on first write)
+	Private,
+	/// Shared read-write, writes are visible through the file and are written back
+	Shared,
+}
+impl Mode
+{
+	fn is_writable(&self) -> bool {
+		match *self
+		{
+		Mode::ReadOnly | Mode::Execute => false,
+		Mode::Private | Mode::Shared => true,
+		}
+	}
+	/// Protection used for pages that don't need special handling
+	fn prot(&self) -> ProtectionMode {
+		match *self
+		{
+		Mode::ReadOnly => ProtectionMode::UserRO,
+		Mode::Execute  => ProtectionMode::UserRX,
+		Mode::Private | Mode::Shared => ProtectionMode::UserRW,
+		}
+	}
+}
+
+struct Region
+{
+	/// Address of the first page
+	base: usize,
+	page_count: usize,
+	source: Source,
+	mode: Mode,
+	/// Pages of a `Shared` region that have been written to (by address), written back when unmapped
+	dirty: Vec<(usize, FrameHandle)>,
+}
+
+#[derive(Default)]
+struct ProcessRegions(::sync::Mutex<Vec<Region>>);
+
+/// Register a demand-paged region in the current address space
+///
+/// NOTE: The caller (`memory::virt::map_user_demand`) must ensure that the range is unused
+pub fn add_region(base: usize, page_count: usize, source: Source, mode: Mode)
+{
+	assert!(base % PAGE_SIZE == 0);
+	if let Source::File { ofs, .. } = source {
+		assert!(ofs % PAGE_SIZE as u64 == 0);
+	}
+	log_trace!("add_region({:#x}+{}pg, {:?})", base, page_count, mode);
+	::threads::get_process_local::<ProcessRegions>().0.lock().push(Region {
+		base: base,
+		page_count: page_count,
+		source: source,
+		mode: mode,
+		dirty: Vec::new(),
+		});
+}
+
+/// Returns true if the page containing `addr` is within a demand-paged region of the current process
+pub fn is_covered(addr: usize) -> bool
+{
+	::threads::get_process_local::<ProcessRegions>().0.lock().iter().any(|r| r.contains(addr))
+}
+
+/// Resolve a page fault on a demand-paged page
+///
+/// Returns false if the address isn't in a demand-paged region, or if the access isn't allowed.
+pub fn handle_fault(addr: usize, is_write: bool) -> bool
+{
+	if ::arch::memory::addresses::is_global(addr) {
+		return false;
+	}
+	let page = addr & !(PAGE_SIZE - 1);
+
+	let regions = ::threads::get_process_local::<ProcessRegions>();
+	let mut lh = regions.0.lock();
+	let r = match lh.iter_mut().find(|r| r.contains(page))
+		{
+		Some(r) => r,
+		None => return false,
+		};
+	if is_write && !r.mode.is_writable() {
+		return false;
+	}
+
+	match ::arch::memory::virt::get_info(page as *const ())
+	{
+	// Not yet populated
+	None =>
+		match r.populate(page, is_write)
+		{
+		Ok( () ) => true,
+		Err(e) => {
+			log_warning!("Unable to populate demand-paged page {:#x}: {:?}", page, e);
+			false
+			},
+		},
+	// First write to a page of a shared mapping, record it for writeback
+	Some( (paddr, ProtectionMode::UserRO) ) if is_write && r.mode == Mode::Shared => {
+		// SAFE: The frame is mapped (thus valid), the handle takes a new reference
+		r.dirty.push( (page, unsafe { FrameHandle::from_addr(paddr) }) );
+		// SAFE: Page is owned by this region, and only the access is changing
+		unsafe {
+			::arch::memory::virt::reprotect(page as *mut (), ProtectionMode::UserRW);
+		}
+		true
+		},
+	// Populated by another thread since the fault, retry the access (unless it's still not allowed)
+	Some( (_, prot) ) => !is_write || prot == ProtectionMode::UserRW || prot == ProtectionMode::UserCOW,
+	}
+}
+
+/// Populate any not-yet-present demand-paged pages in the passed range
+///
+/// Must be called before the kernel accesses a user buffer, as faults from kernel code aren't resolved by
+/// `handle_fault`. For writes, this also records the first write to pages of shared mappings.
+///
+/// Returns false if a page couldn't be populated (or doesn't allow the access).
+pub fn prefault(addr: usize, len: usize, is_write: bool) -> bool
+{
+	if len == 0 || ::arch::memory::addresses::is_global(addr) {
+		return true;
+	}
+	let mut page = addr & !(PAGE_SIZE - 1);
+	while page < addr + len && !::arch::memory::addresses::is_global(page)
+	{
+		if ! ::arch::memory::virt::is_reserved(page as *const ()) || (is_write && is_covered(page)) {
+			if ! handle_fault(page, is_write) {
+				return false;
+			}
+		}
+		page += PAGE_SIZE;
+	}
+	true
+}
+
+/// Remove a page from demand paging (populating it if needed), so its mapping can be altered as normal memory
+///
+/// Returns `Err` for pages of shared writable regions, which must stay tracked for writeback.
+pub fn detach(addr: usize) -> Result<(), ()>
+{
+	if ::arch::memory::addresses::is_global(addr) {
+		return Ok( () );
+	}
+	let page = addr & !(PAGE_SIZE - 1);
+	let regions = ::threads::get_process_local::<ProcessRegions>();
+	let mut lh = regions.0.lock();
+	let idx = match lh.iter().position(|r| r.contains(page))
+		{
+		Some(i) => i,
+		None => return Ok( () ),
+		};
+	if lh[idx].mode == Mode::Shared {
+		return Err( () );
+	}
+	if ! ::arch::memory::virt::is_reserved(page as *const ()) {
+		if let Err(e) = lh[idx].populate(page, false) {
+			log_notice!("detach({:#x}) - Unable to populate: {:?}", page, e);
+			return Err( () );
+		}
+	}
+	remove_range(&mut lh, page, page + PAGE_SIZE);
+	Ok( () )
+}
+
+/// Unmap demand-paged pages in the passed range, writing back any modified shared pages
+///
+/// Returns true if any of the range was demand-paged.
+pub fn unmap(addr: usize, page_count: usize) -> bool
+{
+	if ::arch::memory::addresses::is_global(addr) {
+		return false;
+	}
+	let start = addr & !(PAGE_SIZE - 1);
+	let end = start + page_count * PAGE_SIZE;
+	let regions = ::threads::get_process_local::<ProcessRegions>();
+	let mut lh = regions.0.lock();
+
+	let mut covered = false;
+	for r in lh.iter_mut().filter(|r| r.base < end && start < r.end())
+	{
+		covered = true;
+		let (s, e) = (::core::cmp::max(start, r.base), ::core::cmp::min(end, r.end()));
+		r.write_back(|a| s <= a && a < e);
+		let mut page = s;
+		while page < e
+		{
+			// SAFE: Page is owned by this region, which is being removed
+			unsafe {
+				if let Some(paddr) = ::arch::memory::virt::unmap(page as *mut ()) {
+					::memory::phys::deref_frame(paddr);
+				}
+			}
+			if let Source::File { ref backing, ofs, len } = r.source {
+				let rel = (page - r.base) as u64;
+				if rel < len {
+					backing.release_page(ofs + rel);
+				}
+			}
+			page += PAGE_SIZE;
+		}
+	}
+	if covered {
+		remove_range(&mut lh, start, end);
+	}
+	covered
+}
+
+/// Remove the passed range from the region list, splitting regions as needed
+fn remove_range(regions: &mut Vec<Region>, start: usize, end: usize)
+{
+	let mut i = 0;
+	while i < regions.len()
+	{
+		let (base, rend) = (regions[i].base, regions[i].end());
+		if end <= base || rend <= start {
+			i += 1;
+		}
+		else if start <= base && rend <= end {
+			regions.remove(i);
+		}
+		else if start <= base {
+			// Trim the start
+			regions[i].advance(end - base);
+			i += 1;
+		}
+		else if rend <= end {
+			// Trim the end
+			regions[i].page_count = (start - base) / PAGE_SIZE;
+			i += 1;
+		}
+		else {
+			// Split around the hole
+			let mut tail = Region {
+				base: base,
+				page_count: regions[i].page_count,
+				source: regions[i].source.clone(),
+				mode: regions[i].mode,
+				dirty: Vec::new(),
+				};
+			tail.advance(end - base);
+			{
+				let r = &mut regions[i];
+				let mut j = 0;
+				while j < r.dirty.len()
+				{
+					if r.dirty[j].0 >= end {
+						tail.dirty.push( r.dirty.swap_remove(j) );
+					}
+					else {
+						j += 1;
+					}
+				}
+				r.page_count = (start - base) / PAGE_SIZE;
+			}
+			regions.insert(i + 1, tail);
+			i += 2;
+		}
+	}
+}
+
+impl Region
+{
+	fn end(&self) -> usize {
+		self.base + self.page_count * PAGE_SIZE
+	}
+	fn contains(&self, addr: usize) -> bool {
+		self.base <= addr && addr < self.end()
+	}
+	/// Move the start of the region forwards by `bytes` (a multiple of the page size)
+	fn advance(&mut self, bytes: usize) {
+		self.base += bytes;
+		self.page_count -= bytes / PAGE_SIZE;
+		if let Source::File { ref mut ofs, ref mut len, .. } = self.source {
+			*ofs += bytes as u64;
+			*len = len.saturating_sub(bytes as u64);
+		}
+	}
+
+	/// Map a frame for the passed (not present) page
+	fn populate(&mut self, page: usize, is_write: bool) -> Result<(), MapError>
+	{
+		let rel = (page - self.base) as u64;
+		let (frame, prot) = match self.source
+			{
+			Source::Zero => (try!(alloc_zeroed()), self.mode.prot()),
+			Source::File { ref backing, ofs, len } =>
+				if rel >= len {
+					(try!(alloc_zeroed()), self.mode.prot())
+				}
+				else {
+					let cached = try!(backing.get_page(ofs + rel).map_err(|_| MapError::OutOfMemory));
+					if rel + PAGE_SIZE as u64 > len && self.mode != Mode::Shared {
+						// Partial page, the rest of the page isn't part of the mapping so must be cleared
+						(try!(alloc_copy(&cached, (len - rel) as usize)), self.mode.prot())
+					}
+					else {
+						match self.mode
+						{
+						Mode::ReadOnly | Mode::Execute => (cached, self.mode.prot()),
+						Mode::Private =>
+							if is_write {
+								(try!(alloc_copy(&cached, PAGE_SIZE)), ProtectionMode::UserRW)
+							}
+							else {
+								(cached, ProtectionMode::UserCOW)
+							},
+						Mode::Shared =>
+							if is_write {
+								self.dirty.push( (page, cached.clone()) );
+								(cached, ProtectionMode::UserRW)
+							}
+							else {
+								(cached, ProtectionMode::UserRO)
+							},
+						}
+					}
+				},
+			};
+		// SAFE: Page is owned by this region and not present, the mapping takes the frame's reference
+		unsafe {
+			::arch::memory::virt::map(page as *mut (), frame.into_addr(), prot);
+		}
+		Ok( () )
+	}
+
+	/// Write back (and forget) dirty pages selected by `filter`
+	fn write_back<F: Fn(usize)->bool>(&mut self, filter: F)
+	{
+		if let Source::File { ref backing, ofs, .. } = self.source
+		{
+			let mut i = 0;
+			while i < self.dirty.len()
+			{
+				if filter(self.dirty[i].0) {
+					let (page, frame) = self.dirty.swap_remove(i);
+					let file_ofs = ofs + (page - self.base) as u64;
+					if backing.write_page(file_ofs, &frame).is_err() {
+						log_warning!("Writeback of page {:#x} (file offset {:#x}) failed", page, file_ofs);
+					}
+				}
+				else {
+					i += 1;
+				}
+			}
+		}
+	}
+}
+impl ::core::ops::Drop for Region
+{
+	fn drop(&mut self)
+	{
+		self.write_back(|_| true);
+	}
+}
+
+/// Allocate a zero-filled frame
+fn alloc_zeroed() -> Result<FrameHandle, MapError>
+{
+	let mut page: ::arch::memory::virt::TempHandle<u64> = try!( ::memory::phys::allocate_bare() ).into();
+	// SAFE: Frame was just allocated, the handle takes ownership of the allocation's reference
+	let frame = unsafe { FrameHandle::from_addr_noref(page.phys_addr()) };
+	for v in page.iter_mut() {
+		*v = 0;
+	}
+	Ok( frame )
+}
+/// Allocate a frame containing the first `len` bytes of `src` (the remainder is zeroed)
+fn alloc_copy(src: &FrameHandle, len: usize) -> Result<FrameHandle, MapError>
+{
+	let mut page = try!( ::memory::phys::allocate_bare() );
+	// SAFE: Frame was just allocated, the handle takes ownership of the allocation's reference
+	let frame = unsafe { FrameHandle::from_addr_noref(page.phys_addr()) };
+	// SAFE: Source frame is valid (held by the handle), and is only read
+	unsafe {
+		::memory::virt::with_temp(src.phys_addr(), |data| page[..len].copy_from_slice(&data[..len]));
+	}
+	for b in page[len..].iter_mut() {
+		*b = 0;
+	}
+	Ok( frame )
+}
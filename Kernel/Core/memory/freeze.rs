@@ -34,6 +34,10 @@ pub struct FreezeMut<T:?Sized>(*mut T);
 impl<T: ?Sized> Freeze<T> {
 	// UNSAFE: Requires the passed pointer to never alias pointers not protected via the API
 	pub unsafe fn new(ptr: *const T) -> Result<Freeze<T>,FreezeError> {
+		// Populate demand-paged memory now, the kernel can't handle a fault on it
+		if ! ::memory::demand::prefault(ptr as *const u8 as usize, ::core::mem::size_of_val(&*ptr), false) {
+			return Err( FreezeError::Unmapped );
+		}
 		// TODO: Freeze page as immutable (using a per-process freeze list to handle overlaps)
 		Ok( Freeze(ptr) )
 	}
@@ -54,6 +58,10 @@ impl<T: ?Sized> ::core::ops::Deref for Freeze<T> {
 impl<T: ?Sized> FreezeMut<T> {
 	// UNSAFE: Requires the passed pointer to never alias pointers not protected via the API
 	pub unsafe fn new(ptr: *mut T) -> Result<FreezeMut<T>,FreezeError> {
+		// Populate demand-paged memory now, the kernel can't handle a fault on it
+		if ! ::memory::demand::prefault(ptr as *const u8 as usize, ::core::mem::size_of_val(&*ptr), true) {
+			return Err( FreezeError::Inaccessible );
+		}
 		// TODO: Freeze page as mutable (using a per-process freeze list to handle overlaps)
 		Ok( FreezeMut(ptr) )
 	}
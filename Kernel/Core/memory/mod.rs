@@ -23,6 +23,8 @@ pub mod memorymap;
 pub mod bump_region;
 pub mod page_cache;
 pub mod page_array;
+pub mod shared;
+pub mod demand;
 
 pub use arch::memory::PAddr;
 /*
@@ -55,7 +57,7 @@ impl ::core::ops::Sub<PAddr> for PAddr {
 pub unsafe fn c_string_as_byte_slice<'a>(c_str: *const i8) -> Option<&'a [u8]>
 {
 	// 1. Check first page
-	if ! ::arch::memory::virt::is_reserved(c_str) {
+	if ! ::memory::virt::is_reserved(c_str) || ! ::memory::demand::prefault(c_str as usize, 1, false) {
 		return None;
 	}
 	
@@ -65,7 +67,7 @@ pub unsafe fn c_string_as_byte_slice<'a>(c_str: *const i8) -> Option<&'a [u8]>
 		ptr = ptr.offset(1);
 		if ptr as usize % ::PAGE_SIZE == 0
 		{
-			if ! ::arch::memory::virt::is_reserved(ptr) {
+			if ! ::memory::virt::is_reserved(ptr) || ! ::memory::demand::prefault(ptr as usize, 1, false) {
 				return None;
 			}
 		}
@@ -90,6 +92,10 @@ pub unsafe fn buf_to_slice<'a, T>(ptr: *const T, size: usize) -> Option<&'a [T]>
 	else if ! buf_valid(ptr as *const (), size) {
 		None
 	}
+	// Demand-paged memory is only populated on faults from userland, so populate it before the kernel uses it
+	else if ! ::memory::demand::prefault(ptr as usize, size * ::core::mem::size_of::<T>(), false) {
+		None
+	}
 	else {
 		Some( ::core::slice::from_raw_parts(ptr, size) )
 	}
@@ -102,6 +108,9 @@ pub unsafe fn buf_to_slice_mut<'a, T>(ptr: *mut T, size: usize) -> Option<&'a mu
 	else if ! buf_valid(ptr as *const (), size) {
 		None
 	}
+	else if ! ::memory::demand::prefault(ptr as usize, size * ::core::mem::size_of::<T>(), true) {
+		None
+	}
 	else {
 		Some( ::core::slice::from_raw_parts_mut(ptr, size) )
 	}
@@ -121,7 +130,7 @@ pub fn buf_valid(ptr: *const (), mut size: usize) -> bool
 			return true;
 		}
 	}
-	else if ! ::arch::memory::virt::is_reserved(ptr) {
+	else if ! ::memory::virt::is_reserved(ptr) {
 		return false;
 	}
 	let rem_ofs = ::PAGE_SIZE - addr % ::PAGE_SIZE;
@@ -132,7 +141,7 @@ pub fn buf_valid(ptr: *const (), mut size: usize) -> bool
 		size -= rem_ofs;
 		while size != 0
 		{
-			if ! ::arch::memory::virt::is_reserved(addr as *const ()) {
+			if ! ::memory::virt::is_reserved(addr as *const ()) {
 				return false;
 			}
 			if size > ::PAGE_SIZE {
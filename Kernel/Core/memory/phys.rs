@@ -66,6 +66,13 @@ impl FrameHandle
 	pub unsafe fn from_addr_noref(addr: PAddr) -> FrameHandle {
 		FrameHandle(addr)
 	}
+	pub fn phys_addr(&self) -> PAddr {
+		self.0
+	}
+	/// Returns true if this handle holds the only reference to the frame
+	pub fn is_unique(&self) -> bool {
+		::arch::memory::phys::get_multiref_count(self.0 as u64 / ::PAGE_SIZE as u64) == 0
+	}
 	pub fn into_addr(self) -> PAddr {
 		let rv = self.0;
 		::core::mem::forget(self);
@@ -109,20 +116,25 @@ fn is_ram(phys: PAddr) -> bool
 	false
 }
 
-pub fn make_unique(page: PAddr, virt_addr: &[u8; ::PAGE_SIZE]) -> PAddr
+/// Obtain a frame that is only referenced by the caller's mapping, copying `page` if it is shared
+///
+/// If a copy is made, the caller's reference to the original frame is released.
+pub fn make_unique(page: PAddr, virt_addr: &[u8; ::PAGE_SIZE]) -> Result<PAddr, Error>
 {
 	if !is_ram(page) {
 		panic!("Calling 'make_unique' on non-RAM page");
 	}
 	else if ::arch::memory::phys::get_multiref_count(page as u64 / ::PAGE_SIZE as u64) == 0 {
-		page
+		Ok( page )
 	}
 	else {
 		// 1. Allocate a new frame in temp region
-		let mut new_frame = ::memory::virt::alloc_free().expect("TODO: handle OOM in make_unique");
+		let mut new_frame = try!( ::memory::virt::alloc_free().map_err(|_| Error) );
 		// 2. Copy in content of old frame
 		new_frame.clone_from_slice( virt_addr );
-		new_frame.into_frame().into_addr()
+		// 3. Release the reference held by the caller's mapping
+		deref_frame(page);
+		Ok( new_frame.into_frame().into_addr() )
 	}
 }
 
@@ -0,0 +1,56 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/memory/shared.rs
+//! Shared (anonymous) memory regions
+//!
+//! A region owns a reference to each of its frames, and every mapping of the region takes another. Frames are
+//! only released once the region and all of its mappings have gone.
+#[allow(unused_imports)]
+use prelude::*;
+use memory::phys::FrameHandle;
+use memory::virt::{MapError,ProtectionMode};
+
+/// A set of frames that can be mapped into multiple address spaces
+pub struct SharedRegion
+{
+	frames: Vec<FrameHandle>,
+}
+impl_fmt! {
+	Debug(self,f) for SharedRegion {
+		write!(f, "SharedRegion({} pages)", self.frames.len())
+	}
+}
+
+impl SharedRegion
+{
+	/// Allocate a zeroed region of `page_count` pages
+	pub fn new(page_count: usize) -> Result<SharedRegion, MapError>
+	{
+		let mut frames = Vec::with_capacity(page_count);
+		for _ in 0 .. page_count
+		{
+			let mut page: ::arch::memory::virt::TempHandle<u64> = try!( ::memory::phys::allocate_bare() ).into();
+			for v in page.iter_mut() {
+				*v = 0;
+			}
+			// SAFE: Frame was just allocated, the handle takes ownership of the allocation's reference
+			frames.push( unsafe { FrameHandle::from_addr_noref(page.phys_addr()) } );
+		}
+		Ok(SharedRegion {
+			frames: frames,
+			})
+	}
+
+	/// Size of the region in pages
+	pub fn page_count(&self) -> usize {
+		self.frames.len()
+	}
+
+	/// Map the region into the current address space at `addr` (must be user memory)
+	pub fn map_user(&self, addr: *mut (), prot: ProtectionMode) -> Result<(), MapError>
+	{
+		let frames: Vec<_> = self.frames.iter().map(|f| f.phys_addr()).collect();
+		::memory::virt::map_user_frames(addr, &frames, prot)
+	}
+}
@@ -10,6 +10,9 @@ pub fn read<T: ::lib::POD>(addr: usize) -> Result<T, ()> {
 	else if addr % ::core::mem::align_of::<T>() != 0 {
 		Err( () )
 	}
+	else if ! ::memory::demand::prefault(addr, ::core::mem::size_of::<T>(), false) {
+		Err( () )
+	}
 	else {
 		// TODO: XXX Handle potential for user to alter the AS during this
 		// SAFE: (Assuming single-thread) Alignment and validity checked
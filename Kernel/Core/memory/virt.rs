@@ -102,10 +102,15 @@ impl ::core::iter::Iterator for Pages {
 }
 
 // Alias the arch's get_phys method into this namespace
-pub use arch::memory::virt::is_reserved;
 pub use arch::memory::virt::get_phys;
 pub use arch::memory::virt::get_info;
 
+/// Returns true if the passed address is valid (mapped, or a demand-paged user page)
+pub fn is_reserved<T>(addr: *const T) -> bool
+{
+	::arch::memory::virt::is_reserved(addr) || (!addresses::is_global(addr as usize) && ::memory::demand::is_covered(addr as usize))
+}
+
 /// Temporarily map a frame into memory and run the provided closure
 pub unsafe fn with_temp<F, R>(phys: PAddr, f: F) -> R
 where
@@ -129,14 +134,16 @@ where
 
 /// Ensure that the provded pages are valid (i.e. backed by memory)
 pub fn allocate(addr: *mut (), page_count: usize) -> Result<(), MapError> {
-	allocate_int(addr, page_count, false)
+	allocate_int(addr, page_count)
 }
 /// Allocate memory for user access
+///
+/// Frames are only allocated (and zeroed) when each page is first accessed
 pub fn allocate_user(addr: *mut (), page_count: usize) -> Result<(), MapError> {
-	allocate_int(addr, page_count, true)
+	map_user_demand(addr, page_count, ::memory::demand::Source::Zero, ::memory::demand::Mode::Private)
 }
 
-fn allocate_int(addr: *mut (), page_count: usize, is_user: bool) -> Result<(), MapError>
+fn allocate_int(addr: *mut (), page_count: usize) -> Result<(), MapError>
 {
 	use arch::memory::addresses::is_global;
 
@@ -145,7 +152,7 @@ fn allocate_int(addr: *mut (), page_count: usize, is_user: bool) -> Result<(), M
 	// 2. Ensure range is free
 	for pgptr in Pages(addr, page_count)
 	{
-		if ::arch::memory::virt::is_reserved( pgptr ) {
+		if is_reserved( pgptr ) {
 			// nope.avi
 			log_warning!("Allocated memory ({:p}) in allocate({:p},{})", pgptr, addr, page_count);
 			return Err(MapError::RangeInUse);
@@ -168,74 +175,41 @@ fn allocate_int(addr: *mut (), page_count: usize, is_user: bool) -> Result<(), M
 			return Err( MapError::OutOfMemory );
 		}
 	}
-	if is_user {
-		for pgptr in Pages(addr, page_count) {
-			// SAFE: This region has just been allocated, and is KernelRW, upgrading to allow user access
-			unsafe {
-				::arch::memory::virt::reprotect(pgptr, ProtectionMode::UserRW);
-			}
-		}
-	}
 
 	Ok( () )
 }
 
-/// Atomically reserves a region of address space
-pub fn reserve(addr: *mut (), page_count: usize) -> Result<Reservation, ()>
+/// Register a demand-paged region of user memory (pages are populated by the page fault handler)
+pub fn map_user_demand(addr: *mut (), page_count: usize, source: ::memory::demand::Source, mode: ::memory::demand::Mode) -> Result<(), MapError>
 {
 	use arch::memory::addresses::is_global;
-	
-	if is_global(addr as usize) != is_global(addr as usize + page_count * ::PAGE_SIZE - 1) {
-		todo!("Error out when straddling user-supervisor region {:p}+{:#x}", addr, page_count*::PAGE_SIZE)
-	}
-	
+
 	assert_eq!(addr as usize % ::PAGE_SIZE, 0);
-	
+	if page_count == 0 {
+		return Ok( () );
+	}
+	let last = match page_count.checked_mul(::PAGE_SIZE).and_then(|s| (addr as usize).checked_add(s - 1))
+		{
+		Some(v) => v,
+		None => return Err(MapError::RangeInUse),
+		};
+	if is_global(addr as usize) || is_global(last) {
+		return Err(MapError::RangeInUse);
+	}
+
 	// 1. Lock
-	let _lh = if is_global(addr as usize) { s_kernelspace_lock.lock() } else { s_userspace_lock.lock() };
+	let _lh = s_userspace_lock.lock();
 	// 2. Ensure range is free
 	for pgptr in Pages(addr, page_count)
 	{
-		if ::arch::memory::virt::is_reserved( pgptr ) {
+		if is_reserved( pgptr ) {
 			log_trace!("Address {:?} in range {:p}+{}pg reserved", pgptr, addr, page_count);
-			return Err( () );
-		}
-	}
-	// 3. do `page_count` single arbitary allocations
-	for pgptr in Pages(addr, page_count)
-	{
-		// TODO: Instead map in COW zero pages
-		::memory::phys::allocate( pgptr );
-	}
-	
-	Ok( Reservation(addr, page_count) )
-}
-/// Handle to a reserved region of address space
-pub struct Reservation(*mut (), usize);
-impl Reservation
-{
-	pub fn get_mut_page(&mut self, idx: usize) -> &mut [u8] {
-		assert!(idx < self.1);
-		// SAFE: Unique, and owned
-		unsafe { ::core::slice::from_raw_parts_mut( (self.0 as usize + idx * ::PAGE_SIZE) as *mut u8, ::PAGE_SIZE) }
-	}
-	//pub fn map_at(&mut self, idx: usize, frame: FrameHandle) -> Result<(),FrameHandle> {
-	//	assert!(idx < self.1);
-	//	let addr = (self.0 as usize + idx * ::PAGE_SIZE) as *mut ();
-	//	::arch::memory::virt::unmap(addr);
-	//	::arch::memory::virt::map(addr, frame.into_addr(), ProtectionMode::KernelRW);
-	//	Ok( () )
-	//}
-	pub fn finalise(self, final_mode: ProtectionMode) -> Result<(),()> {
-		log_trace!("Reservation::finalise(final_mode={:?})", final_mode);
-		for addr in Pages(self.0, self.1) {
-			// SAFE: Just changing flags, and 'self' owns this region of memory.
-			unsafe {
-				::arch::memory::virt::reprotect(addr, final_mode);
-			}
+			return Err(MapError::RangeInUse);
 		}
-		Ok( () )
 	}
+	// 3. Register the region
+	::memory::demand::add_region(addr as usize, page_count, source, mode);
+	Ok( () )
 }
 
 /// Map the given physical address to the given virtual address
@@ -256,6 +230,50 @@ pub unsafe fn map(addr: *mut (), phys: PAddr, prot: ProtectionMode)
 	}
 }
 
+/// Map a set of frames into user memory at `addr`, each mapping takes a new reference to its frame
+///
+/// Used for shared memory, where the frames are also mapped elsewhere
+pub fn map_user_frames(addr: *mut (), frames: &[PAddr], prot: ProtectionMode) -> Result<(), MapError>
+{
+	use arch::memory::addresses::is_global;
+
+	match prot
+	{
+	ProtectionMode::UserRO => {},
+	ProtectionMode::UserRW => {},
+	ProtectionMode::UserRX => {},
+	_ => panic!("Invalid protection mode passed to map_user_frames - {:?}", prot),
+	}
+	assert_eq!(addr as usize % ::PAGE_SIZE, 0);
+	if frames.len() == 0 {
+		return Ok( () );
+	}
+	if is_global(addr as usize) || is_global(addr as usize + frames.len() * ::PAGE_SIZE - 1) {
+		return Err(MapError::RangeInUse);
+	}
+
+	// 1. Lock
+	let _lh = s_userspace_lock.lock();
+	// 2. Ensure range is free
+	for pgptr in Pages(addr, frames.len())
+	{
+		if is_reserved( pgptr ) {
+			log_trace!("Address {:?} in range {:p}+{}pg reserved", pgptr, addr, frames.len());
+			return Err(MapError::RangeInUse);
+		}
+	}
+	// 3. Map (the reference is released when unmapped, or when the address space is destroyed)
+	for (pgptr, &paddr) in Pages(addr, frames.len()).zip(frames.iter())
+	{
+		::memory::phys::ref_frame(paddr);
+		// SAFE: Range is free user memory, and the frame is referenced for this mapping
+		unsafe {
+			::arch::memory::virt::map(pgptr, paddr, prot);
+		}
+	}
+	Ok( () )
+}
+
 /// Alter the protection flags on a mapping (only allows changing to a user-accessible mode)
 /// UNSAFE: (Very) Can change the protection mode of a page to anything
 pub unsafe fn reprotect_user(addr: *mut (), prot: ProtectionMode) -> Result<(),()>
@@ -268,9 +286,19 @@ pub unsafe fn reprotect_user(addr: *mut (), prot: ProtectionMode) -> Result<(),(
 	_ => panic!("Invalid protection mode passed to reprotect_user - {:?}", prot),
 	}
 	if ::arch::memory::addresses::is_global(addr as usize) {
-		Err( () )
+		return Err( () );
+	}
+	// Demand-paged pages are either released, or populated and then handled as normal pages
+	if prot == ProtectionMode::Unmapped {
+		if ::memory::demand::unmap(addr as usize, 1) {
+			return Ok( () );
+		}
 	}
-	else if ! ::arch::memory::virt::is_reserved(addr) {
+	else {
+		try!(::memory::demand::detach(addr as usize));
+	}
+
+	if ! ::arch::memory::virt::is_reserved(addr) {
 		Err( () )
 	}
 	else {
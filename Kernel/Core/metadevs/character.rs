@@ -0,0 +1,98 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/metadevs/character.rs
+//! Character (byte stream) devices
+//!
+//! Devices registered here are exposed to userland as files in `/system/devices`
+use prelude::*;
+use sync::Mutex;
+use lib::mem::aref::ArefBorrow;
+
+/// Byte stream device (e.g. a serial console)
+pub trait CharDevice: Send + Sync
+{
+	/// Read waiting data (non-blocking, returns 0 if nothing is avaliable)
+	fn read(&self, dst: &mut [u8]) -> usize;
+	/// Write data, returns the number of bytes accepted
+	fn write(&self, src: &[u8]) -> usize;
+}
+
+struct DeviceInfo
+{
+	name: String,
+	dev: ArefBorrow<CharDevice>,
+}
+
+/// Handle held by a driver, removes the device when dropped
+pub struct Registration
+{
+	idx: usize,
+}
+
+static S_DEVICES: Mutex<Vec<Option<DeviceInfo>>> = Mutex::new(Vec::new_const());
+
+/// Register a character device, named using `prefix` and an index (e.g. `console0`)
+pub fn register(prefix: &str, dev: ArefBorrow<CharDevice>) -> Registration
+{
+	let mut lh = S_DEVICES.lock();
+	let mut num = 0;
+	let name = loop {
+		let name = format!("{}{}", prefix, num);
+		if ! lh.iter().any(|e| match *e { Some(ref e) => e.name == *name, None => false }) {
+			break name;
+		}
+		num += 1;
+		};
+	let info = DeviceInfo {
+		name: name,
+		dev: dev,
+		};
+	log_log!("Registered character device '{}'", info.name);
+
+	let idx = match lh.iter().position(|e| e.is_none())
+		{
+		Some(i) => { lh[i] = Some(info); i },
+		None => { lh.push(Some(info)); lh.len() - 1 },
+		};
+	Registration { idx: idx }
+}
+impl ::core::ops::Drop for Registration
+{
+	fn drop(&mut self) {
+		let mut lh = S_DEVICES.lock();
+		assert!( lh[self.idx].is_some() );
+		lh[self.idx] = None;
+	}
+}
+
+/// Obtain a device given its index
+pub fn get(idx: usize) -> Option<ArefBorrow<CharDevice>>
+{
+	match S_DEVICES.lock().get(idx)
+	{
+	Some(&Some(ref e)) => Some(e.dev.reborrow()),
+	_ => None,
+	}
+}
+/// Locate a device by name, returning its index
+pub fn find(name: &[u8]) -> Option<usize>
+{
+	S_DEVICES.lock().iter().position(|e| match *e { Some(ref e) => e.name.as_bytes() == name, None => false })
+}
+/// Enumerate devices starting at `start`, returning the index to continue from
+///
+/// The callback returns `false` to stop enumeration
+pub fn enumerate(start: usize, cb: &mut FnMut(usize, &str)->bool) -> usize
+{
+	let lh = S_DEVICES.lock();
+	for idx in start .. lh.len()
+	{
+		if let Some(ref e) = lh[idx] {
+			if ! cb(idx, &e.name) {
+				return idx;
+			}
+		}
+	}
+	lh.len()
+}
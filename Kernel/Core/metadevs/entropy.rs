@@ -0,0 +1,164 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/metadevs/entropy.rs
+//! Kernel entropy pool
+//!
+//! Hardware sources (e.g. virtio-rng) add data with `add_entropy`, consumers obtain random bytes from a ChaCha20
+//! generator that is reseeded from the pool once enough entropy has been collected.
+use sync::Spinlock;
+use sync::EventChannel;
+
+/// Number of bits of estimated entropy required before the generator is reseeded
+const RESEED_BITS: usize = 256;
+/// Number of bytes output before more entropy is requested from sources
+const RESEED_INTERVAL: usize = 1024*1024;
+
+struct Pool
+{
+	/// Generator key
+	key: [u32; 8],
+	/// Generator block counter
+	counter: u64,
+	/// Input accumulator (mixed into the key on reseed)
+	accum: [u32; 16],
+	accum_pos: usize,
+	/// Estimated entropy in the accumulator (bits)
+	accum_bits: usize,
+	/// Number of times the generator has been seeded
+	seed_count: usize,
+	/// Bytes generated since the last reseed
+	output_count: usize,
+}
+
+static S_POOL: Spinlock<Pool> = Spinlock::new(Pool {
+	key: [0; 8],
+	counter: 0,
+	accum: [0; 16],
+	accum_pos: 0,
+	accum_bits: 0,
+	seed_count: 0,
+	output_count: 0,
+	});
+/// Signalled when the pool would like more entropy
+static S_DEMAND: EventChannel = EventChannel::new();
+
+/// Add data from an entropy source, with an estimate of the number of random bits it contains
+pub fn add_entropy(data: &[u8], est_bits: usize)
+{
+	let mut lh = S_POOL.lock();
+	for &b in data
+	{
+		let pos = lh.accum_pos;
+		lh.accum[pos % 16] = lh.accum[pos % 16].rotate_left(8) ^ b as u32;
+		lh.accum_pos = pos.wrapping_add(1);
+	}
+	lh.accum_bits += est_bits;
+	if lh.accum_bits >= RESEED_BITS {
+		lh.reseed();
+	}
+}
+
+/// Fill a buffer with random data
+///
+/// NOTE: If no entropy source has provided data yet, the output is predictable.
+pub fn get_bytes(dst: &mut [u8])
+{
+	let mut lh = S_POOL.lock();
+	if lh.seed_count == 0 {
+		// Hand what's been collected so far to the generator, and request more
+		if lh.accum_bits > 0 {
+			lh.reseed();
+		}
+		S_DEMAND.post();
+	}
+	else if lh.output_count > RESEED_INTERVAL {
+		S_DEMAND.post();
+	}
+	lh.output_count += dst.len();
+	for chunk in dst.chunks_mut(64)
+	{
+		let block = lh.next_block();
+		for (d, s) in chunk.iter_mut().zip( block.iter().flat_map(|w| (0 .. 4).map(move |i| (w >> (8*i)) as u8)) ) {
+			*d = s;
+		}
+	}
+	// Fast key erasure - previous outputs can't be recovered from the current state
+	let block = lh.next_block();
+	lh.key.copy_from_slice(&block[..8]);
+}
+
+/// Returns true if the generator has been seeded from a hardware source
+pub fn is_seeded() -> bool
+{
+	S_POOL.lock().seed_count > 0
+}
+
+/// Block until the pool requests more entropy (for use by entropy source drivers)
+pub fn wait_for_demand()
+{
+	S_DEMAND.sleep();
+}
+
+impl Pool
+{
+	fn reseed(&mut self) {
+		let mut input = [0u32; 16];
+		for i in 0 .. 8 {
+			input[4+i] = self.key[i] ^ self.accum[i];
+		}
+		input[12] = self.accum[8] ^ self.accum[12];
+		input[13] = self.accum[9] ^ self.accum[13];
+		input[14] = self.accum[10] ^ self.accum[14];
+		input[15] = self.accum[11] ^ self.accum[15];
+		let out = chacha20_block(&input);
+		self.key.copy_from_slice(&out[..8]);
+		self.accum = [0; 16];
+		self.accum_bits = 0;
+		self.counter = 0;
+		self.seed_count += 1;
+		self.output_count = 0;
+		log_debug!("Entropy pool reseeded (#{})", self.seed_count);
+	}
+	fn next_block(&mut self) -> [u32; 16] {
+		let mut input = [0u32; 16];
+		input[4..12].copy_from_slice(&self.key);
+		input[12] = self.counter as u32;
+		input[13] = (self.counter >> 32) as u32;
+		self.counter += 1;
+		chacha20_block(&input)
+	}
+}
+
+/// ChaCha20 block function (constants are filled in, `input[4..]` is the key, counter, and nonce)
+fn chacha20_block(input: &[u32; 16]) -> [u32; 16]
+{
+	fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+		s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(16);
+		s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(12);
+		s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(8);
+		s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(7);
+	}
+	let mut init = *input;
+	// "expand 32-byte k"
+	init[0] = 0x61707865;
+	init[1] = 0x3320646e;
+	init[2] = 0x79622d32;
+	init[3] = 0x6b206574;
+	let mut s = init;
+	for _ in 0 .. 10
+	{
+		quarter_round(&mut s, 0, 4,  8, 12);
+		quarter_round(&mut s, 1, 5,  9, 13);
+		quarter_round(&mut s, 2, 6, 10, 14);
+		quarter_round(&mut s, 3, 7, 11, 15);
+		quarter_round(&mut s, 0, 5, 10, 15);
+		quarter_round(&mut s, 1, 6, 11, 12);
+		quarter_round(&mut s, 2, 7,  8, 13);
+		quarter_round(&mut s, 3, 4,  9, 14);
+	}
+	for i in 0 .. 16 {
+		s[i] = s[i].wrapping_add(init[i]);
+	}
+	s
+}
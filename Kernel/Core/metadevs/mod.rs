@@ -1,2 +1,4 @@
 pub mod video;
 pub mod storage;
+pub mod character;
+pub mod entropy;
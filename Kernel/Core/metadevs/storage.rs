@@ -22,8 +22,8 @@ pub struct VolumeHandle
 
 /// Physical volume registration (PV will be deregistered when this handle is dropped)
 /// 
-// TODO: What is the behavior when this PV still has LVs (open LVs too?). Just waiting will not
-// be the correct behavior.
+/// Dropping this handle removes the PV and any LVs that use it. Open LVs stay valid, but any
+/// further IO to the removed PV will fail with `IoError::Removed`.
 pub struct PhysicalVolumeReg
 {
 	idx: usize,
@@ -42,6 +42,8 @@ pub enum IoError
 	BadBlock,
 	ReadOnly,
 	NoMedium,
+	/// The physical volume has been removed (hot-unplugged)
+	Removed,
 	Unknown(&'static str),
 }
 
@@ -83,7 +85,7 @@ impl<'a> ::core::fmt::Debug for DataPtr<'a> {
 /// Physical volume instance provided by driver
 ///
 /// Provides the low-level methods to manipulate the underlying storage
-pub trait PhysicalVolume: Send + 'static
+pub trait PhysicalVolume: Send + Sync + 'static
 {
 	/// Returns the volume name (must be unique to the system)
 	fn name(&self) -> &str;	// Local lifetime string
@@ -126,10 +128,13 @@ pub trait Mapper: Send + Sync
 }
 
 
+/// Shared handle to a PV driver instance, kept alive by in-flight requests
+type PhysicalVolumeRef = Arc<Box<PhysicalVolume>>;
+
 /// A single physical volume
 struct PhysicalVolumeInfo
 {
-	dev: Box<PhysicalVolume>,
+	dev: PhysicalVolumeRef,
 	mapper: Option<(usize,&'static Mapper)>,
 }
 /// A single logical volume, composed of 1 or more physical blocks
@@ -216,7 +221,7 @@ pub fn register_pv(dev: Box<PhysicalVolume>) -> PhysicalVolumeReg
 	
 	// Wait until after checking for a handler before we add the PV to the list
 	S_PHYSICAL_VOLUMES.lock().insert(pv_id, PhysicalVolumeInfo {
-		dev: dev,
+		dev: Arc::new(dev),
 		mapper: None,
 		});
 	
@@ -247,7 +252,7 @@ pub fn register_mapper(mapper: &'static Mapper)
 			// No media, skip
 			continue ;
 		}
-		match mapper.handles_pv(&*pv.dev)
+		match mapper.handles_pv(&**pv.dev)
 		{
 		Err(e) => log_error!("Error checking PV{}: {:?}", pv.dev.name(), e),
 		Ok(0) => {},	// Ignore
@@ -314,7 +319,7 @@ fn apply_mapper_to_pv(mapper: &'static Mapper, level: usize, pv_id: usize, pvi:
 	pvi.mapper = Some( (level, mapper) );
 	// - Enumerate volumes
 	//  TODO: Support more complex volume types
-	match mapper.enum_volumes(&*pvi.dev, &mut |name, base, len| {
+	match mapper.enum_volumes(&**pvi.dev, &mut |name, base, len| {
 		new_simple_lv(name, pv_id, pvi.dev.blocksize(), base, len);
 		})
 	{
@@ -419,17 +424,57 @@ impl VolumeHandle
 		&self.handle.name
 	}
 	
+	/// Start an asynchronous read of a series of blocks into the provided buffer
+	///
+	/// The buffer must be a multiple of the logical block size. The returned request is split
+	/// into one request per physical region, issued in sequence.
+	pub fn read<'a>(&'a self, prio: u8, idx: u64, dst: &'a mut [u8]) -> AsyncIoResult<'a,()>
+	{
+		log_trace!("VolumeHandle::read(prio={}, idx={}, dst={{len={}}})", prio, idx, dst.len());
+		if dst.len() % self.block_size() != 0 {
+			log_warning!("Read size {} not a multiple of {} bytes", dst.len(), self.block_size());
+			return Box::new(::async::NullResultWaiter::new( || Err(IoError::InvalidParameter) ));
+		}
+		Box::new( VolumeIo::new(&*self.handle, prio, idx, DataPtr::Recv(dst)) )
+	}
+	/// Start an asynchronous write of a series of blocks from the provided buffer
+	///
+	/// The buffer must be a multiple of the logical block size
+	pub fn write<'a>(&'a self, prio: u8, idx: u64, src: &'a [u8]) -> AsyncIoResult<'a,()>
+	{
+		log_trace!("VolumeHandle::write(prio={}, idx={}, src={{len={}}})", prio, idx, src.len());
+		if src.len() % self.block_size() != 0 {
+			log_warning!("Write size {} not a multiple of {} bytes", src.len(), self.block_size());
+			return Box::new(::async::NullResultWaiter::new( || Err(IoError::InvalidParameter) ));
+		}
+		Box::new( VolumeIo::new(&*self.handle, prio, idx, DataPtr::Send(src)) )
+	}
+	
+	/// Read a series of blocks from the volume into the provided buffer.
+	/// 
+	/// The buffer must be a multiple of the logical block size
+	pub fn read_blocks(&self, idx: u64, dst: &mut [u8]) -> Result<(),IoError> {
+		self.read(0, idx, dst).wait()
+	}
+
+	pub fn write_blocks(&self, idx: u64, dst: &[u8]) -> Result<(),IoError> {
+		self.write(0, idx, dst).wait()
+	}
+}
+
+impl LogicalVolume
+{
 	// TODO: Return a more complex type that can be incremented
 	// Returns: VolIdx, Block, Count
 	fn get_phys_block(&self, idx: u64, count: usize) -> Option<(usize,u64,usize)> {
-		if let Some(size) = self.handle.chunk_size
+		if let Some(size) = self.chunk_size
 		{
 			todo!("Non JBOD logocal volumes ({} block stripe)", size);
 		}
 		else
 		{
 			let mut idx_rem = idx;
-			for v in self.handle.regions.iter()
+			for v in self.regions.iter()
 			{
 				if idx_rem < v.block_count as u64 {
 					let ret_count = ::core::cmp::min(
@@ -445,144 +490,253 @@ impl VolumeHandle
 		}
 		None
 	}
-	
-	/// Read a series of blocks from the volume into the provided buffer.
-	/// 
-	/// The buffer must be a multiple of the logical block size
-	pub fn read_blocks(&self, idx: u64, dst: &mut [u8]) -> Result<(),IoError> {
-		log_trace!("VolumeHandle::read_blocks(idx={}, dst={{len={}}})", idx, dst.len());
-		if dst.len() % self.block_size() != 0 {
-			log_warning!("Read size {} not a multiple of {} bytes", dst.len(), self.block_size());
-			return Err( IoError::InvalidParameter );
-		}
-		
-		let mut rem = dst.len() / self.block_size();
-		let mut blk = 0;
-		while rem > 0
+}
+
+/// Composite IO request on a logical volume
+///
+/// Issues one request per physical region (in sequence), advancing through the buffer as each
+/// PV reports the number of blocks it serviced.
+struct VolumeIo<'a>
+{
+	lv: &'a LogicalVolume,
+	prio: u8,
+	first_block: u64,
+	buffer: DataPtr<'a>,
+	/// Number of logical blocks completed so far
+	blocks_done: usize,
+	state: VolumeIoState<'a>,
+}
+enum VolumeIoState<'a>
+{
+	Active(PvRequest<'a>),
+	Done(Result<(),IoError>),
+}
+/// Outstanding request on a single physical volume
+struct PvRequest<'a>
+{
+	// NOTE: `req` borrows from `_dev`, so must be declared (and hence dropped) first
+	req: AsyncIoResult<'a,usize>,
+	_dev: PhysicalVolumeRef,
+	pv: usize,
+	count: usize,
+}
+
+impl<'a> VolumeIo<'a>
+{
+	fn new(lv: &'a LogicalVolume, prio: u8, first_block: u64, buffer: DataPtr<'a>) -> VolumeIo<'a>
+	{
+		let mut rv = VolumeIo {
+			lv: lv,
+			prio: prio,
+			first_block: first_block,
+			buffer: buffer,
+			blocks_done: 0,
+			state: VolumeIoState::Done(Ok( () )),
+			};
+		rv.advance();
+		rv
+	}
+
+	fn total_blocks(&self) -> usize {
+		self.buffer.len() / self.lv.block_size
+	}
+
+	/// Issue PV requests until one is outstanding, or the entire request has completed/failed
+	fn advance(&mut self)
+	{
+		loop
 		{
-			let (pv, ofs, count) = match self.get_phys_block(idx + blk as u64, rem) {
-				Some(v) => v,
-				None => {
-					log_warning!("VolumeHandle::read_blocks - Block id {} is invalid", idx + blk as u64);
-					return Err( IoError::BadAddr )
-					},
+			if self.blocks_done == self.total_blocks() {
+				self.state = VolumeIoState::Done(Ok( () ));
+				return ;
+			}
+			self.state = match self.issue()
+				{
+				Ok(req) => VolumeIoState::Active(req),
+				Err(e) => VolumeIoState::Done(Err(e)),
 				};
-			log_trace!("- PV{} {} + {}", pv, ofs, count);
-			assert!(count <= rem);
-			let bofs = blk as usize * self.block_size();
-			let dst = &mut dst[bofs .. bofs + count * self.block_size()];
-			try!( S_PHYSICAL_VOLUMES.lock().get(&pv).expect("Volume missing").read(ofs, dst) );
-			blk += count;
-			rem -= count;
+			// Synchronous drivers return already-complete requests, keep going until one blocks
+			if !self.harvest() {
+				return ;
+			}
 		}
-		Ok( () )
 	}
 
-	pub fn write_blocks(&self, idx: u64, dst: &[u8]) -> Result<(),IoError> {
-		log_trace!("VolumeHandle::write_blocks(idx={}, dst={{len={}}})", idx, dst.len());
-		if dst.len() % self.block_size() != 0 {
-			log_warning!("Write size {} not a multiple of {} bytes", dst.len(), self.block_size());
-			return Err( IoError::InvalidParameter );
-		}
+	/// Start a request for the next run of blocks on the relevant PV
+	fn issue(&self) -> Result<PvRequest<'a>,IoError>
+	{
+		let block_size = self.lv.block_size;
+		let blk = self.first_block + self.blocks_done as u64;
+		let rem = self.total_blocks() - self.blocks_done;
+		let (pv, ofs, count) = match self.lv.get_phys_block(blk, rem)
+			{
+			Some(v) => v,
+			None => {
+				log_warning!("VolumeIo - Block id {} is invalid", blk);
+				return Err( IoError::BadAddr );
+				},
+			};
+		log_trace!("- PV{} {} + {}", pv, ofs, count);
+		assert!(count <= rem);
 		
-		let mut rem = dst.len() / self.block_size();
-		let mut blk = 0;
-		while rem > 0
-		{
-			let (pv, ofs, count) = match self.get_phys_block(idx + blk as u64, rem) {
-				Some(v) => v,
-				None => {
-					log_warning!("VolumeHandle::write_blocks - Block id {} is invalid", idx + blk as u64);
-					return Err( IoError::BadAddr )
+		let dev = match S_PHYSICAL_VOLUMES.lock().get(&pv)
+			{
+			Some(pvi) => pvi.dev.clone(),
+			None => {
+				log_warning!("VolumeIo - PV{} has been removed", pv);
+				return Err( IoError::Removed );
+				},
+			};
+		assert_eq!(dev.blocksize(), block_size);
+
+		// SAFE: The Arc'd device is stored alongside (and outlives) the request that borrows it
+		let dev_ref: &'a PhysicalVolume = unsafe { &*(&**dev as *const PhysicalVolume) };
+		let byte_ofs = self.blocks_done * block_size;
+		let len = count * block_size;
+		let req = match self.buffer
+			{
+			DataPtr::Send(p) => dev_ref.write(self.prio, ofs, count, &p[byte_ofs ..][.. len]),
+			DataPtr::Recv(ref p) => {
+				assert!(byte_ofs + len <= p.len());
+				// SAFE: Only one PV request is active at a time, so this is the only live borrow of the region
+				let dst = unsafe { ::core::slice::from_raw_parts_mut(p.as_ptr().offset(byte_ofs as isize) as *mut u8, len) };
+				dev_ref.read(self.prio, ofs, count, dst)
+				},
+			};
+		Ok(PvRequest {
+			req: req,
+			_dev: dev,
+			pv: pv,
+			count: count,
+			})
+	}
+
+	/// Collect the result of the active PV request (if it has completed)
+	///
+	/// Returns true if the next PV request should be issued
+	fn harvest(&mut self) -> bool
+	{
+		let res = match self.state
+			{
+			VolumeIoState::Active(ref mut r) => {
+				if !r.req.is_complete() {
+					return false;
+				}
+				match r.req.get_result()
+				{
+				Some(Ok(0)) => {
+					log_warning!("VolumeIo - PV{} serviced zero blocks", r.pv);
+					Err( IoError::Unknown("PV made no progress") )
 					},
-				};
-			log_trace!("- PV{} {} + {}", pv, ofs, count);
-			assert!(count <= rem);
-			let bofs = blk as usize * self.block_size();
-			let dst = &dst[bofs .. bofs + count * self.block_size()];
-			try!( S_PHYSICAL_VOLUMES.lock().get(&pv).unwrap().write(ofs, dst) );
-			blk += count;
-			rem -= count;
+				Some(Ok(n)) => if n > r.count {
+						log_error!("VolumeIo - PV{} serviced {} blocks, but only {} requested", r.pv, n, r.count);
+						Err( IoError::Unknown("PV overran request") )
+					}
+					else {
+						Ok(n)
+					},
+				Some(Err(e)) => {
+					log_warning!("VolumeIo - PV{} request failed: {:?}", r.pv, e);
+					Err(e)
+					},
+				None => Err( IoError::Unknown("PV request completed without a result") ),
+				}
+				},
+			VolumeIoState::Done(..) => return false,
+			};
+		match res
+		{
+		Ok(n) => {
+			self.blocks_done += n;
+			true
+			},
+		Err(e) => {
+			self.state = VolumeIoState::Done(Err(e));
+			false
+			},
 		}
-		Ok( () )
 	}
 }
-
-impl PhysicalVolumeInfo
+impl<'a> ::core::fmt::Debug for VolumeIo<'a> {
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		try!( write!(f, "VolumeIo(LV{} {}+{}/{} {:?})", self.lv.index, self.first_block, self.blocks_done, self.total_blocks(), self.buffer) );
+		match self.state
+		{
+		VolumeIoState::Active(ref r) => write!(f, "(Active PV{} {})", r.pv, r.count),
+		VolumeIoState::Done(ref r) => write!(f, "(Done {:?})", r),
+		}
+	}
+}
+impl<'a> ::async::Waiter for VolumeIo<'a>
 {
-	fn max_blocks_per_read(&self) -> usize {
-		// 32 blocks per read op, = 0x4000 (16KB) for 512 byte sectors
-		// TODO: Remove this?
-		32
+	fn is_complete(&self) -> bool {
+		if let VolumeIoState::Done(..) = self.state { true } else { false }
 	}
-	
-	/// Read blocks from the device
-	pub fn read(&self, first: u64, dst: &mut [u8]) -> Result<usize,IoError>
-	{
-		log_trace!("PhysicalVolumeInfo::read(first={},{} bytes)", first, dst.len());
-		let block_size = self.dev.blocksize();
-		let total_blocks = dst.len() / block_size;
-		// Read up to 'block_step' blocks in each read call
-		// - TODO: Request a read of as much as possible, and be told by the device how many were serviced
+	fn get_waiter(&mut self) -> &mut ::async::PrimitiveWaiter {
+		match self.state
 		{
-			let mut buf = dst;
-			let mut blk_id = first;
-			while buf.len() > 0
-			{
-				assert!(buf.len() % block_size == 0);
-				let prio = 0;
-				let blocks = buf.len() / block_size;
-				
-				// TODO: Async! (maybe return a composite read handle?)
-				let real_count = match self.dev.read(prio, blk_id, blocks, buf).wait()
-					{
-					Ok(v) => v,
-					Err(e) => todo!("Error when PV fails to read: {:?}", e),
-					};
-				assert!(real_count <= blocks);
-				blk_id += real_count as u64;
-
-				// SAFE: Evil stuff to advance the buffer
-				buf = unsafe { &mut *(&mut buf[real_count * block_size..] as *mut _) };
-//				split_at_mut_inplace(&mut buf, real_count * block_size);
-			}
+		VolumeIoState::Active(ref mut r) => r.req.get_waiter(),
+		VolumeIoState::Done(..) => unreachable!(),
 		}
-
-		Ok(total_blocks)
 	}
-	
-	/// Write blocks from the device
-	pub fn write(&self, first: u64, dst: &[u8]) -> Result<usize,IoError>
-	{
-		log_trace!("PhysicalVolumeInfo::write(first={},{} bytes)", first, dst.len());
-		let block_step = self.max_blocks_per_read();
-		let block_size = self.dev.blocksize();
-		// Read up to 'block_step' blocks in each read call
+	fn complete(&mut self) -> bool {
+		if let VolumeIoState::Active(ref mut r) = self.state {
+			r.req.complete();
+		}
+		if self.harvest() {
+			self.advance();
+		}
+		self.is_complete()
+	}
+}
+impl<'a> ::async::ResultWaiter for VolumeIo<'a>
+{
+	type Result = Result<(),IoError>;
+	fn get_result(&mut self) -> Option<Self::Result> {
+		match self.state
 		{
-			let iter_ids  = (first .. ).step_by(block_step);
-			let iter_bufs = dst.chunks( block_step * block_size );
-			for (blk_id,buf) in iter_ids.zip( iter_bufs )
-			{
-				let prio = 0;
-				let blocks = buf.len() / block_size;
-				
-				// TODO: Async! (maybe return a composite read handle?)
-				match self.dev.write(prio, blk_id, blocks, buf).wait()
-				{
-				Ok(real_count) => { assert!(real_count == blocks, "TODO: Handle incomplete writes"); },
-				Err(e) => todo!("Error when PV fails to write: {:?}", e),
-				}
-			}
+		VolumeIoState::Done(r) => Some(r),
+		_ => None,
 		}
-		Ok(dst.len()/block_size)
 	}
+	fn as_waiter(&mut self) -> &mut ::async::Waiter { self }
 }
 
 impl ::core::ops::Drop for PhysicalVolumeReg
 {
 	fn drop(&mut self)
 	{
-		todo!("PhysicalVolumeReg::drop idx={}", self.idx);
+		let pvi = match S_PHYSICAL_VOLUMES.lock().remove(&self.idx)
+			{
+			Some(v) => v,
+			None => {
+				log_error!("PhysicalVolumeReg::drop - PV{} not registered", self.idx);
+				return ;
+				},
+			};
+		log_notice!("Removing PV{} '{}'", self.idx, pvi.dev.name());
+		
+		// Remove all LVs that use this PV (open handles keep their LV, but IO will fail)
+		{
+			let mut lh = S_LOGICAL_VOLUMES.lock();
+			let keys: Vec<usize> = lh.iter()
+				.filter( |&(_,lv)| lv.regions.iter().any(|r| r.volume == self.idx) )
+				.map(|(&i,_)| i)
+				.collect();
+			for k in keys
+			{
+				let lv = lh.remove(&k).expect("LV vanished while locked");
+				if Arc::strong_count(&lv) > 1 {
+					log_warning!("LV '{}' is still open, further IO will fail", lv.name);
+				}
+			}
+		}
+		
+		// In-flight requests hold their own reference, the device is dropped when they complete
+		let n_active = Arc::strong_count(&pvi.dev) - 1;
+		if n_active > 0 {
+			log_notice!("{} requests still in flight on PV{}", n_active, self.idx);
+		}
 	}
 }
 
@@ -15,6 +15,7 @@ mod sleep_object;
 
 pub use self::thread::{Thread,ThreadPtr};
 pub use self::thread::{ThreadHandle,ProcessHandle};
+pub use self::thread::Credentials;
 pub use self::thread::new_idle_thread;
 
 pub use self::worker_thread::WorkerThread;
@@ -57,6 +58,8 @@ fn reap_threads() -> bool
 	while let Some(thread) = S_TO_REAP_THREADS.lock().pop() {
 		log_log!("Reaping thread {:?}", thread);
 		assert!(&*thread as *const Thread != ::arch::threads::borrow_thread() as *const _, "Reaping thread from itself");
+		// The CPU that was running it might still be switching away
+		::arch::threads::wait_stopped(&thread.cpu_state);
 		match thread.into_boxed()
 		{
 		Ok(thread) => drop(thread),
@@ -100,14 +103,20 @@ pub fn yield_time()
 	reap_threads();
 
 	// Add current thread to active queue, then reschedule
-	s_runnable_threads.lock().push( get_cur_thread() );
+	{
+		let _irq_lock = ::arch::sync::hold_interrupts();
+		s_runnable_threads.lock().push( get_cur_thread() );
+	}
 	reschedule();
 }
 
 pub fn yield_to(thread: ThreadPtr)
 {
 	log_debug!("Yielding CPU to {:?}", thread);
-	s_runnable_threads.lock().push( get_cur_thread() );
+	{
+		let _irq_lock = ::arch::sync::hold_interrupts();
+		s_runnable_threads.lock().push( get_cur_thread() );
+	}
 	::arch::threads::switch_to( thread );
 }
 
@@ -121,6 +130,13 @@ pub fn terminate_thread() -> !
 	// NOTE: Can this just obtain a handle to the current thread then drop it?
 	// - No... kinda needs to be properly reaped. (so that no outstanding pointers exist)
 	//
+	// Wake anything waiting for this thread, and exit the process if this was its last thread
+	let was_last = with_cur_thread(|cur| cur.mark_exit());
+	if was_last && get_process_id() != 0 {
+		// Releases process objects, and wakes anything waiting on the process (address space is released on reap)
+		with_cur_thread(|cur| cur.get_process_info().mark_exit());
+	}
+
 	// Set state to "Dead"
 	let mut this_thread = get_cur_thread();
 	this_thread.set_state( thread::RunState::Dead(0) );
@@ -132,23 +148,42 @@ pub fn terminate_thread() -> !
 }
 
 pub fn exit_process(status: u32) -> ! {
-	// Requirements:
-	// - Save exit status somewhere
-	match with_cur_thread( |cur| cur.get_process_info().mark_exit(status) )
-	{
-	Ok(_) => {},
-	Err(_) => todo!("Two threads raced to exit"),
+	// Save the exit status and request all other threads terminate
+	// - They're woken if sleeping in a syscall, and exit when they next reach a syscall boundary (see `check_exit_request`)
+	// - If another thread/process got in first, its status is kept
+	if ! with_cur_thread( |cur| cur.get_process_info().request_exit(status) ) {
+		log_debug!("Process already terminating");
 	}
 	log_notice!("Terminating process with status={:#x}", status);
 
-	// - Request all other threads terminate
-	// TODO: How would this be done cleanly? Need to wake all and terminate on syscall boundary?
-	
 	// - Terminate this thread
-	//  > Process reaping is handled by the PCB dropping when refcount reaches zero
+	//  > The last thread to terminate publishes the status, the address space is released once all are reaped
 	terminate_thread();
 }
 
+/// Returns true if the current process has been asked to exit
+pub fn is_exit_requested() -> bool {
+	with_cur_thread(|cur| cur.get_process_info().is_exit_requested())
+}
+/// Terminate the current thread if the process has been asked to exit
+///
+/// Called on every return to userland (syscall boundaries, and IRQs that interrupted userland)
+pub fn check_exit_request() {
+	if is_exit_requested() {
+		log_debug!("Exit requested, terminating thread");
+		terminate_thread();
+	}
+}
+
+/// Sleep on `obj`, waking early if the current process is asked to exit
+///
+/// Used by syscalls that sleep for a user-controlled length of time (the thread then exits on syscall return)
+pub fn wait_interruptible(obj: &mut SleepObject) {
+	with_cur_thread(|cur| cur.get_process_info().bind_wait_exit_request(obj));
+	obj.wait();
+	with_cur_thread(|cur| cur.get_process_info().clear_wait_exit_request(obj));
+}
+
 pub fn get_thread_id() -> thread::ThreadID
 {
 	let p = ::arch::threads::borrow_thread();
@@ -171,6 +206,10 @@ pub fn get_process_id() -> thread::ProcessID {
 		};
 	p.get_process_info().get_pid()
 }
+/// Obtain the user/group identity of the current process
+pub fn get_credentials() -> thread::Credentials {
+	with_cur_thread(|cur| cur.get_process_info().get_credentials())
+}
 
 fn with_cur_thread<T, F: FnOnce(&thread::Thread)->T>(fcn: F) -> T
 {
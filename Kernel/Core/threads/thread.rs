@@ -18,6 +18,24 @@ use lib::mem::Arc;
 pub type ThreadID = u32;
 pub type ProcessID = u32;
 
+/// User/group identity of a process (used for file ownership checks)
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct Credentials
+{
+	pub uid: u32,
+	pub gid: u32,
+}
+impl Credentials
+{
+	/// Credentials of the superuser (PID0 and kernel threads)
+	pub fn root() -> Credentials {
+		Credentials { uid: 0, gid: 0 }
+	}
+	pub fn is_root(&self) -> bool {
+		self.uid == 0
+	}
+}
+
 //#[deriving(PartialEq)]
 /// Thread run state
 pub enum RunState
@@ -39,11 +57,32 @@ pub struct Process
 {
 	name: String,
 	pid: ProcessID,
-	address_space: ::memory::virt::AddressSpace,
-	// TODO: use of a tuple here looks a little crufty
-	exit_status: ::sync::Mutex< (Option<u32>, Option<::threads::sleep_object::SleepObjectRef>) >,
+	/// Address space, released once all threads have been reaped
+	address_space: ::sync::Mutex< Option<::memory::virt::AddressSpace> >,
+	exit_status: ::sync::Mutex<ExitState>,
+	/// Set when the process has been asked to terminate (checked at syscall boundaries)
+	exit_requested: ::core::sync::atomic::AtomicBool,
+	/// Number of threads that haven't yet terminated
+	thread_count: ::core::sync::atomic::AtomicUsize,
+	/// Number of threads that haven't yet been reaped
+	unreaped_count: ::core::sync::atomic::AtomicUsize,
+	/// User/group identity, inherited from the creating process
+	credentials: ::sync::Mutex<Credentials>,
 	pub proc_local_data: ::sync::RwLock<Vec< ::lib::mem::aref::Aref<::core::any::Any+Sync+Send> >>,
 }
+#[derive(Default)]
+struct ExitState
+{
+	/// Final exit status, set once the last thread has terminated
+	status: Option<u32>,
+	/// Status requested by `exit_process` or a kill, applied when the last thread terminates
+	requested: Option<u32>,
+	/// Objects to signal once the process has terminated
+	waiters: Vec<::threads::sleep_object::SleepObjectRef>,
+	/// Objects that this process's threads are sleeping on, signalled when termination is requested
+	sleepers: Vec<::threads::sleep_object::SleepObjectRef>,
+}
+
 /// Handle to a process, used for spawning and communicating
 pub struct ProcessHandle(Arc<Process>);
 impl_fmt! {
@@ -57,6 +96,8 @@ struct SharedBlock
 	name: String,
 	tid: ThreadID,
 	process: Arc<Process>,
+	/// Termination flag, and objects to signal when it's set
+	exit_status: ::sync::Mutex< (bool, Vec<::threads::sleep_object::SleepObjectRef>) >,
 }
 
 /// An owning thread handle
@@ -127,7 +168,11 @@ impl Process
 			name: String::from("PID0"),
 			pid: 0,
 			exit_status: Default::default(),
-			address_space: ::memory::virt::AddressSpace::pid0(),
+			exit_requested: ::core::sync::atomic::AtomicBool::new(false),
+			thread_count: ::core::sync::atomic::AtomicUsize::new(0),
+			unreaped_count: ::core::sync::atomic::AtomicUsize::new(0),
+			address_space: ::sync::Mutex::new( Some(::memory::virt::AddressSpace::pid0()) ),
+			credentials: ::sync::Mutex::new( Credentials::root() ),
 			proc_local_data: ::sync::RwLock::new( Vec::new() ),
 		})
 	}
@@ -137,30 +182,81 @@ impl Process
 			pid: allocate_pid(),
 			name: name.into(),
 			exit_status: Default::default(),
-			address_space: addr_space,
+			exit_requested: ::core::sync::atomic::AtomicBool::new(false),
+			thread_count: ::core::sync::atomic::AtomicUsize::new(0),
+			unreaped_count: ::core::sync::atomic::AtomicUsize::new(0),
+			address_space: ::sync::Mutex::new( Some(addr_space) ),
+			credentials: ::sync::Mutex::new( super::get_credentials() ),
 			proc_local_data: ::sync::RwLock::new( Vec::new() ),
 		})
 	}
 	
 	fn empty_cpu_state(&self) -> ::arch::threads::State {
-		::arch::threads::State::new( &self.address_space )
+		let lh = self.address_space.lock();
+		::arch::threads::State::new( lh.as_ref().expect("Creating a thread in a process with no address space") )
 	}
 
 	pub fn get_pid(&self) -> ProcessID { self.pid }
+	pub fn get_credentials(&self) -> Credentials {
+		*self.credentials.lock()
+	}
+	pub fn set_credentials(&self, creds: Credentials) {
+		log_notice!("{} credentials set to {:?}", self, creds);
+		*self.credentials.lock() = creds;
+	}
 
-	pub fn mark_exit(&self, status: u32) -> Result<(),()> {
+	/// Request that all threads in this process terminate (at their next syscall boundary)
+	///
+	/// Returns false if termination was already requested (the original status is kept)
+	pub fn request_exit(&self, status: u32) -> bool {
 		let mut lh = self.exit_status.lock();
-		if lh.0.is_some() {
-			Err( () )
+		if lh.requested.is_some() || lh.status.is_some() {
+			false
 		}
 		else {
-			
-			if let Some(ref sleep_ref) = lh.1 {
-				sleep_ref.signal();
+			lh.requested = Some(status);
+			self.exit_requested.store(true, ::core::sync::atomic::Ordering::SeqCst);
+			// Wake all threads sleeping in syscalls, so they notice the request
+			for s in lh.sleepers.iter() {
+				s.signal();
 			}
+			true
+		}
+	}
+	/// Returns true if termination has been requested
+	pub fn is_exit_requested(&self) -> bool {
+		self.exit_requested.load(::core::sync::atomic::Ordering::SeqCst)
+	}
+
+	/// Register an object to be signalled if termination is requested
+	pub fn bind_wait_exit_request(&self, obj: &mut ::threads::SleepObject) {
+		let mut lh = self.exit_status.lock();
+		if lh.requested.is_some() {
+			obj.signal();
+		}
+		lh.sleepers.push( obj.get_ref() );
+	}
+	pub fn clear_wait_exit_request(&self, obj: &mut ::threads::SleepObject) {
+		let mut lh = self.exit_status.lock();
+		if let Some(pos) = lh.sleepers.iter().position(|v| v.is_from(obj)) {
+			lh.sleepers.swap_remove(pos);
+		}
+	}
+
+	/// Called when the last thread terminates, releases per-process objects and wakes waiters
+	pub fn mark_exit(&self) {
+		// Drop process-local data (e.g. the syscall layer's object handles)
+		// - Done outside the lock, as the destructors can take an arbitary amount of time
+		let pld = ::core::mem::replace(&mut *self.proc_local_data.write(), Vec::new());
+		drop(pld);
 
-			lh.0 = Some(status);
-			Ok( () )
+		let mut lh = self.exit_status.lock();
+		assert!(lh.status.is_none(), "Process {} exited twice", self);
+		let status = lh.requested.unwrap_or(0);
+		log_notice!("{} terminated with status={:#x}", self, status);
+		lh.status = Some(status);
+		for s in lh.waiters.iter() {
+			s.signal();
 		}
 	}
 }
@@ -171,6 +267,11 @@ impl ProcessHandle
 		ProcessHandle( Process::new(name, ::memory::virt::AddressSpace::new(clone_start, clone_end).expect("ProcessHandle::new - OOM")) )
 	}
 	
+	/// Set the user/group identity of the process
+	pub fn set_credentials(&self, creds: Credentials) {
+		self.0.set_credentials(creds);
+	}
+	
 	pub fn start_root_thread(&mut self, ip: usize, sp: usize) {
 		log_trace!("start_thread(self={:?}, ip={:#x}, sp={:#x})", self, ip, sp);
 		assert!( Arc::get_mut(&mut self.0).is_some() );
@@ -236,33 +337,38 @@ impl ProcessHandle
 	pub fn bind_wait_terminate(&self, obj: &mut ::threads::SleepObject) {
 		log_trace!("bind_wait_terminate({:p}, obj={:p})", self, obj);
 		let mut lh = self.0.exit_status.lock();
-		if let Some(_status) = lh.0 {
+		if let Some(_status) = lh.status {
 			obj.signal();
 		}
-		else if let Some(_) = lh.1 {
-			todo!("Multiple threads sleeping on this process");
-		}
 		else {
-			lh.1 = Some( obj.get_ref() );
+			lh.waiters.push( obj.get_ref() );
 		}
 	}
 	pub fn clear_wait_terminate(&self, obj: &mut ::threads::SleepObject) -> bool {
 		log_trace!("clear_wait_terminate({:p}, obj={:p})", self, obj);
 		let mut lh = self.0.exit_status.lock();
 
-		if let Some(ref v) = lh.1 {
-			assert!(v.is_from(obj), "clear_wait_terminate from different object");
+		if let Some(pos) = lh.waiters.iter().position(|v| v.is_from(obj)) {
+			lh.waiters.swap_remove(pos);
 		}
 		else {
 			log_trace!("- Wasn't registered");
 		}
-		lh.1 = None;
 		
-		lh.0.is_some()
+		lh.status.is_some()
+	}
+
+	/// Request that the process terminate with the provided status
+	///
+	/// Threads exit at their next syscall boundary, the status is available once the last has terminated.
+	pub fn kill(&self, status: u32) {
+		if ! self.0.request_exit(status) {
+			log_debug!("{:?} already terminating", self);
+		}
 	}
 
 	pub fn get_exit_status(&self) -> Option<u32> {
-		self.0.exit_status.lock().0
+		self.0.exit_status.lock().status
 	}
 }
 impl ::core::ops::Drop for ProcessHandle {
@@ -286,6 +392,61 @@ impl ThreadHandle
 		
 		handle
 	}
+
+	/// Start a new user-mode thread in the current process
+	///
+	/// `tls_base` is the user's thread pointer (e.g. FS base on amd64)
+	pub fn new_user(ip: usize, sp: usize, tls_base: usize) -> ThreadHandle
+	{
+		let process = super::with_cur_thread(|cur| cur.block.process.clone());
+		let tid = allocate_tid();
+		let name = format!("{}#{}", process.name, tid);
+		log_trace!("new_user(ip={:#x}, sp={:#x}, tls_base={:#x}) - {}", ip, sp, tls_base, name);
+		let mut thread = Thread::new_boxed(tid, name, process);
+		let handle = ThreadHandle {
+			block: thread.block.clone(),
+			};
+		::arch::threads::start_thread( &mut thread,
+			// SAFE: Addresses are user-provided, so any faults are the user's problem
+			move || unsafe {
+					::arch::threads::set_user_tls(tls_base);
+					::arch::drop_to_user(ip, sp, 0)
+				}
+			);
+		super::yield_to(thread);
+
+		handle
+	}
+
+	pub fn get_tid(&self) -> ThreadID {
+		self.block.tid
+	}
+
+	pub fn bind_wait_terminate(&self, obj: &mut ::threads::SleepObject) {
+		let mut lh = self.block.exit_status.lock();
+		if lh.0 {
+			obj.signal();
+		}
+		else {
+			lh.1.push( obj.get_ref() );
+		}
+	}
+	pub fn clear_wait_terminate(&self, obj: &mut ::threads::SleepObject) -> bool {
+		let mut lh = self.block.exit_status.lock();
+		if let Some(pos) = lh.1.iter().position(|v| v.is_from(obj)) {
+			lh.1.swap_remove(pos);
+		}
+		else {
+			log_trace!("- Wasn't registered");
+		}
+
+		lh.0
+	}
+
+	/// Returns true if the thread has terminated
+	pub fn is_terminated(&self) -> bool {
+		self.block.exit_status.lock().0
+	}
 }
 impl ::core::fmt::Debug for ThreadHandle
 {
@@ -297,7 +458,10 @@ impl ::core::fmt::Debug for ThreadHandle
 impl ::core::ops::Drop for ThreadHandle
 {
 	fn drop(&mut self) {
-		panic!("TODO: Wait for thread to terminate when handle is dropped");
+		// The thread owns its own reference to the shared block, so it's just detached
+		if !self.is_terminated() {
+			log_debug!("Detaching {:?}", self);
+		}
 	}
 }
 
@@ -371,9 +535,11 @@ impl Thread
 	/// Create a new thread
 	pub fn new_boxed<S: Into<String>>(tid: ThreadID, name: S, process: Arc<Process>) -> ThreadPtr
 	{
+		process.thread_count.fetch_add(1, ::core::sync::atomic::Ordering::Relaxed);
+		process.unreaped_count.fetch_add(1, ::core::sync::atomic::Ordering::Relaxed);
 		let rv = box Thread {
 			cpu_state: process.empty_cpu_state(),
-			block: Arc::new( SharedBlock { tid: tid, name: name.into(), process: process } ),
+			block: Arc::new( SharedBlock { tid: tid, name: name.into(), process: process, exit_status: Default::default() } ),
 			run_state: RunState::Runnable,
 			next: None,
 			};
@@ -403,6 +569,19 @@ impl Thread
 	pub fn get_process_info(&self) -> &Process {
 		&*self.block.process
 	}
+
+	/// Mark this thread as terminated (waking anything waiting on it)
+	///
+	/// Returns true if this was the last thread in the process
+	pub fn mark_exit(&self) -> bool {
+		let mut lh = self.block.exit_status.lock();
+		assert!( !lh.0, "Thread {:?} terminated twice", self );
+		lh.0 = true;
+		for sleep_ref in lh.1.iter() {
+			sleep_ref.signal();
+		}
+		self.block.process.thread_count.fetch_sub(1, ::core::sync::atomic::Ordering::Relaxed) == 1
+	}
 }
 
 pub fn new_idle_thread(cpu: usize) -> ThreadPtr {
@@ -439,6 +618,14 @@ impl ::core::ops::Drop for Thread
 	{
 		// TODO: Remove self from the global thread map
 		log_debug!("Destroying thread {:?} - {} handles to block, {} to process", self, Arc::strong_count(&self.block), Arc::strong_count(&self.block.process));
+		// Once every thread in a process has been reaped, release the user address space
+		// - Can't be done by the last thread, as it's still using it
+		let process = &self.block.process;
+		if process.unreaped_count.fetch_sub(1, ::core::sync::atomic::Ordering::Relaxed) == 1 && process.pid != 0 {
+			log_debug!("Releasing address space for {}", process);
+			let aspace = process.address_space.lock().take();
+			drop(aspace);
+		}
 	}
 }
 
@@ -3,6 +3,10 @@
 //
 // Core/time.rs
 //! Kernel timing and timers
+use prelude::*;
+use core::sync::atomic::{AtomicUsize,Ordering};
+use sync::Spinlock;
+use threads::{SleepObject,SleepObjectRef};
 
 /// Timer ticks (ms)
 pub type TickCount = u64;
@@ -52,5 +56,240 @@ impl CacheTimer
 	}
 }
 
+// --------------------------------------------------------------------
+// Timer wheel
+// --------------------------------------------------------------------
+// Hierarchical timer wheel with millisecond granularity. Level N covers deadlines up to 64^(N+1) ms away, entries
+// are cascaded down a level when the lower level wraps around. Deadlines beyond the top level are kept in an overflow
+// list that is re-sorted each time the top level wraps (every ~4.6 hours).
+//
+// The wheel is only manipulated in thread context (by the "Timer" worker), the architecture's timer interrupt just
+// pokes the worker via `time_tick`.
+
+const WHEEL_BITS: usize = 6;
+const WHEEL_SLOTS: usize = 1 << WHEEL_BITS;
+const WHEEL_LEVELS: usize = 4;
+
+static S_TIMER_WHEEL: ::lib::LazyStatic<Spinlock<TimerWheel>> = lazystatic_init!();
+static S_TIMER_SIGNAL: ::lib::LazyStatic<SleepObject<'static>> = lazystatic_init!();
+static S_TIMER_WORKER: ::lib::LazyStatic<::threads::WorkerThread> = lazystatic_init!();
+static S_NEXT_TIMER_ID: AtomicUsize = AtomicUsize::new(1);
+
+struct TimerEntry
+{
+	deadline: TickCount,
+	id: usize,
+	target: SleepObjectRef,
+}
+
+struct TimerWheel
+{
+	/// Last tick processed by the wheel
+	now: TickCount,
+	/// `WHEEL_LEVELS` levels of `WHEEL_SLOTS` slots
+	slots: Vec<Vec<TimerEntry>>,
+	overflow: Vec<TimerEntry>,
+}
+
+/// A pending timer, signals the passed sleep object once the deadline has passed
+///
+/// Dropping the handle cancels the timer. NOTE: The handle must be dropped before the sleep object.
+pub struct Timer
+{
+	id: usize,
+	deadline: TickCount,
+}
+
+pub fn init()
+{
+	// SAFE: Called in a single-threaded context
+	unsafe {
+		S_TIMER_WHEEL.prep(|| Spinlock::new(TimerWheel::new(ticks())));
+		S_TIMER_SIGNAL.prep(|| SleepObject::new("Timer Worker"));
+		S_TIMER_WORKER.prep(|| ::threads::WorkerThread::new("Timer Worker", timer_worker));
+	}
+}
+
+/// Called by the architecture code when the timer interrupt fires (IRQ context)
+pub fn time_tick()
+{
+	if S_TIMER_SIGNAL.ls_is_valid() {
+		S_TIMER_SIGNAL.signal();
+	}
+}
+
+fn timer_worker()
+{
+	loop
+	{
+		S_TIMER_SIGNAL.wait();
+		let next = S_TIMER_WHEEL.lock().advance(ticks());
+		::arch::request_tick(next);
+	}
+}
+
+impl Timer
+{
+	/// Create a new timer that signals `obj` at (or after) the tick count `deadline`
+	pub fn new(deadline: TickCount, obj: &SleepObject) -> Timer
+	{
+		let id = S_NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed);
+		S_TIMER_WHEEL.lock().insert(TimerEntry {
+			deadline: deadline,
+			id: id,
+			target: obj.get_ref(),
+			});
+		// Ensure that the timer interrupt fires no later than this deadline
+		::arch::request_tick(deadline);
+		Timer {
+			id: id,
+			deadline: deadline,
+		}
+	}
+	/// Create a timer that fires after `duration_ms` milliseconds
+	pub fn new_relative(duration_ms: TickCount, obj: &SleepObject) -> Timer
+	{
+		Timer::new(ticks() + duration_ms, obj)
+	}
+
+	pub fn deadline(&self) -> TickCount {
+		self.deadline
+	}
+	pub fn has_expired(&self) -> bool {
+		ticks() >= self.deadline
+	}
+}
+impl ::core::ops::Drop for Timer
+{
+	fn drop(&mut self)
+	{
+		S_TIMER_WHEEL.lock().remove(self.id, self.deadline);
+	}
+}
+impl_fmt! {
+	Debug(self,f) for Timer {
+		write!(f, "Timer(#{} @{})", self.id, self.deadline)
+	}
+}
+
+/// Sleep the current thread for (at least) `ms` milliseconds
+pub fn sleep_ms(ms: TickCount)
+{
+	let obj = SleepObject::new("sleep_ms");
+	let timer = Timer::new_relative(ms, &obj);
+	while !timer.has_expired()
+	{
+		obj.wait();
+	}
+}
+
+/// Poll `cond` (sleeping `poll_ms` between checks) until it returns true, or `timeout_ms` milliseconds have passed
+///
+/// Returns `false` if the timeout was reached without `cond` returning true.
+pub fn wait_for<F: FnMut()->bool>(timeout_ms: TickCount, poll_ms: TickCount, mut cond: F) -> bool
+{
+	let end = ticks() + timeout_ms;
+	while !cond()
+	{
+		if ticks() >= end {
+			return cond();
+		}
+		sleep_ms(::core::cmp::min(poll_ms, end.saturating_sub(ticks())));
+	}
+	true
+}
+
+impl TimerWheel
+{
+	fn new(now: TickCount) -> TimerWheel
+	{
+		TimerWheel {
+			now: now,
+			slots: (0 .. WHEEL_LEVELS * WHEEL_SLOTS).map(|_| Vec::new()).collect(),
+			overflow: Vec::new(),
+		}
+	}
+
+	fn slot_idx(level: usize, deadline: TickCount) -> usize {
+		level * WHEEL_SLOTS + ((deadline >> (WHEEL_BITS * level)) as usize) % WHEEL_SLOTS
+	}
+
+	fn insert(&mut self, ent: TimerEntry)
+	{
+		if ent.deadline <= self.now {
+			// Already passed, fire immediately
+			ent.target.signal();
+			return ;
+		}
+		let delta = ent.deadline - self.now;
+		for level in 0 .. WHEEL_LEVELS
+		{
+			if delta < 1 << (WHEEL_BITS * (level+1)) {
+				self.slots[Self::slot_idx(level, ent.deadline)].push(ent);
+				return ;
+			}
+		}
+		self.overflow.push(ent);
+	}
+
+	fn remove(&mut self, id: usize, deadline: TickCount)
+	{
+		// The entry can only be in its deadline's slot at one of the levels (or in the overflow list)
+		for level in 0 .. WHEEL_LEVELS
+		{
+			let slot = &mut self.slots[Self::slot_idx(level, deadline)];
+			if let Some(pos) = slot.iter().position(|e| e.id == id) {
+				slot.swap_remove(pos);
+				return ;
+			}
+		}
+		if let Some(pos) = self.overflow.iter().position(|e| e.id == id) {
+			self.overflow.swap_remove(pos);
+		}
+		// - Not found, has already fired
+	}
+
+	fn cascade(&mut self, ents: Vec<TimerEntry>)
+	{
+		for ent in ents {
+			self.insert(ent);
+		}
+	}
+
+	/// Process all ticks up to `to`, returns the next tick at which the wheel needs attention
+	fn advance(&mut self, to: TickCount) -> TickCount
+	{
+		while self.now < to
+		{
+			self.now += 1;
+			let now = self.now;
+			// Cascade higher levels first, so entries can drop through several levels at once
+			if now % (1 << (WHEEL_BITS * WHEEL_LEVELS)) == 0 {
+				let ents = ::core::mem::replace(&mut self.overflow, Vec::new());
+				self.cascade(ents);
+			}
+			for level in (1 .. WHEEL_LEVELS).rev()
+			{
+				if now % (1 << (WHEEL_BITS * level)) == 0 {
+					let ents = ::core::mem::replace(&mut self.slots[Self::slot_idx(level, now)], Vec::new());
+					self.cascade(ents);
+				}
+			}
+			// Fire everything in the current level-0 slot (insert signals entries that have passed)
+			let ents = ::core::mem::replace(&mut self.slots[Self::slot_idx(0, now)], Vec::new());
+			self.cascade(ents);
+		}
+
+		// Next non-empty level-0 slot, or the next cascade point
+		for i in 1 .. WHEEL_SLOTS as u64
+		{
+			if ! self.slots[Self::slot_idx(0, self.now + i)].is_empty() {
+				return self.now + i;
+			}
+		}
+		(self.now | (WHEEL_SLOTS as u64 - 1)) + 1
+	}
+}
+
 // vim: ft=rust
 
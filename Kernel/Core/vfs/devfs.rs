@@ -0,0 +1,125 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/vfs/devfs.rs
+//! Device filesystem (exposes character devices as files)
+use prelude::*;
+use vfs;
+use super::{mount, node};
+use metadevs::storage::VolumeHandle;
+use metadevs::character;
+use lib::byte_str::ByteStr;
+
+pub struct Driver;
+pub static S_DRIVER: Driver = Driver;
+
+struct DevFS
+{
+	_vh: VolumeHandle,
+}
+
+/// Root directory (lists all registered devices)
+struct RootDir;
+/// Character device, refers to an index in the character device list
+struct DevFile(usize);
+
+pub fn init()
+{
+	let h = mount::DriverRegistration::new("devfs", &S_DRIVER);
+	::core::mem::forget(h);
+}
+
+impl mount::Driver for Driver
+{
+	fn detect(&self, _vol: &VolumeHandle) -> super::Result<usize> {
+		// Never binds to a real volume
+		Ok(0)
+	}
+	fn mount(&self, vol: VolumeHandle, _: mount::SelfHandle) -> super::Result<Box<mount::Filesystem>> {
+		Ok(Box::new(DevFS { _vh: vol }))
+	}
+}
+
+impl mount::Filesystem for DevFS
+{
+	fn root_inode(&self) -> node::InodeId {
+		0
+	}
+	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
+		log_trace!("DevFS::get_node_by_inode({})", id);
+		if id == 0 {
+			Some(node::Node::Dir(Box::new(RootDir)))
+		}
+		else {
+			let idx = (id - 1) as usize;
+			match character::get(idx)
+			{
+			Some(_) => Some(node::Node::File(Box::new(DevFile(idx)))),
+			None => None,
+			}
+		}
+	}
+}
+
+impl node::NodeBase for RootDir {
+	fn get_id(&self) -> node::InodeId {
+		0
+	}
+	fn get_any(&self) -> &::core::any::Any {
+		self
+	}
+}
+impl node::Dir for RootDir {
+	fn lookup(&self, name: &ByteStr) -> vfs::Result<node::InodeId> {
+		match character::find(name.as_bytes())
+		{
+		Some(idx) => Ok(idx as node::InodeId + 1),
+		None => Err(vfs::Error::NotFound),
+		}
+	}
+	fn read(&self, start_ofs: usize, callback: &mut node::ReadDirCallback) -> node::Result<usize> {
+		Ok( character::enumerate(start_ofs, &mut |idx, name| callback(idx as node::InodeId + 1, &mut name.bytes())) )
+	}
+	fn create(&self, _name: &ByteStr, _nodetype: node::NodeType) -> vfs::Result<node::InodeId> {
+		Err(vfs::Error::ReadOnlyFilesystem)
+	}
+	fn link(&self, _name: &ByteStr, _node: &node::NodeBase) -> vfs::Result<()> {
+		Err(vfs::Error::ReadOnlyFilesystem)
+	}
+	fn unlink(&self, _name: &ByteStr) -> vfs::Result<()> {
+		Err(vfs::Error::ReadOnlyFilesystem)
+	}
+}
+
+impl DevFile {
+	fn dev(&self) -> vfs::Result<::lib::mem::aref::ArefBorrow<character::CharDevice>> {
+		// NOTE: The device can be removed while the node is cached
+		character::get(self.0).ok_or(vfs::Error::NotFound)
+	}
+}
+impl node::NodeBase for DevFile {
+	fn get_id(&self) -> node::InodeId {
+		self.0 as node::InodeId + 1
+	}
+	fn get_any(&self) -> &::core::any::Any {
+		self
+	}
+}
+impl node::File for DevFile {
+	fn size(&self) -> u64 {
+		0
+	}
+	fn truncate(&self, _newsize: u64) -> vfs::Result<u64> {
+		Err(vfs::Error::InvalidParameter)
+	}
+	fn clear(&self, _ofs: u64, _size: u64) -> vfs::Result<()> {
+		Err(vfs::Error::InvalidParameter)
+	}
+	// NOTE: Offsets are ignored, devices are streams
+	fn read(&self, _ofs: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+		Ok( self.dev()?.read(buf) )
+	}
+	fn write(&self, _ofs: u64, buf: &[u8]) -> vfs::Result<usize> {
+		Ok( self.dev()?.write(buf) )
+	}
+}
@@ -139,15 +139,24 @@ impl File
 		if !node.is_file() {
 			return Err(super::Error::TypeMismatch);
 		}
+		// Check the file's ownership against the current process's credentials
+		// NOTE: Capability-based checks are done by the caller (e.g. the syscall layer)
+		try!(node.check_access( match mode
+			{
+			FileOpenMode::SharedRO => super::node::ACCESS_READ,
+			FileOpenMode::Execute => super::node::ACCESS_EXEC,
+			_ => super::node::ACCESS_READ | super::node::ACCESS_WRITE,
+			} ));
 		match mode
 		{
 		// TODO: Mark file as shared
-		// TODO: Check permissions (must be readable in current context)
 		FileOpenMode::SharedRO => {},
 		// TODO: Mark file as shared
-		// TODO: Check permissions (must be executable in current context)
 		FileOpenMode::Execute => {},
-		_ => todo!("Acquire lock depending on mode({:?})", mode),
+		// TODO: Fail if any other open type is active
+		FileOpenMode::Unsynch => {},
+		// TODO: Acquire lock depending on mode
+		_ => return Err(super::Error::Unknown("File open mode not yet supported")),
 		}
 		Ok(File { node: node, mode: mode })
 	}
@@ -162,16 +171,33 @@ impl File
 	/// slice).
 	pub fn read(&self, ofs: u64, dst: &mut [u8]) -> super::Result<usize> {
 		assert!(self.node.is_file());
+		// Populate demand-paged buffers first, filesystems can't handle page faults that need IO
+		if ! ::memory::demand::prefault(dst.as_ptr() as usize, dst.len(), true) {
+			return Err(super::Error::InvalidParameter);
+		}
 		self.node.read(ofs, dst)
 	}
 	pub fn write(&self, ofs: u64, src: &[u8]) -> super::Result<usize> {
 		assert!(self.node.is_file());
-		todo!("Handle::write({:#x}, {:p}+{}", ofs, src.as_ptr(), src.len());
+		if ! ::memory::demand::prefault(src.as_ptr() as usize, src.len(), false) {
+			return Err(super::Error::InvalidParameter);
+		}
+		match self.mode
+		{
+		FileOpenMode::SharedRO | FileOpenMode::Execute => Err(super::Error::PermissionDenied),
+		FileOpenMode::Unsynch => self.node.write(ofs, src),
+		// TODO: ExclRW/UniqueRW/Append (needs the locking from `from_node`)
+		_ => Err(super::Error::Unknown("File::write - Unsupported open mode")),
+		}
 	}
 
 	
-	/// Map a file into the address space
+	/// Map a file into the (user) address space
+	///
+	/// Pages are populated through the file's page cache when first accessed. `size` doesn't need to be a
+	/// multiple of the page size, the rest of the last page is zero-filled (except for `WriteBack` mappings).
 	pub fn memory_map(&self, address: usize, ofs: u64, size: usize, mode: MemoryMapMode) -> super::Result<MemoryMapHandle> {
+		use memory::demand;
 		log_debug!("memory_map(self={{mode:{:?}}}, address={:#x}, ofs={:#x}, size={:#x}, mode={:?})",
 			self.mode, address, ofs, size, mode);
 		// - Check that this file is opened in a sufficent mode to allow this form of mapping
@@ -205,63 +231,52 @@ impl File
 			{
 			//FileOpenMode::ExclRW => /* NOTE: Needs extra checks to ensure that aliasing does not occur */
 			//FileOpenMode::UniqueRW => /* NOTE: Needs extra checks to ensure that aliasing does not occur */
+			// Unsynchronised - The user has accepted that there's no synchronisation
+			FileOpenMode::Unsynch => {},
 			_ => return Err(super::Error::PermissionDenied),
 			},
 		}
 		
-		// TODO: Handle unaligned mappings somehow
-		// - Depends on several qirks:
-		//  > Unaligned address could write to an existing page (converting it to a private) - But how would that interact with existing mappings?
-		//  > Unaligned sizes would usually cause a new anon mapping, but if its unaligned becuase of EOF, it should just be COW as usual
-		assert!(address % ::PAGE_SIZE == 0, "TODO: Unaligned memory_map (address={})", address);
-		assert!(size % ::PAGE_SIZE == 0, "TODO: Unaligned memory_map (size={})", size);
+		// TODO: Handle unaligned addresses somehow
+		// - An unaligned address could write to an existing page (converting it to a private) - But how would that interact with existing mappings?
+		if address % ::PAGE_SIZE != 0 {
+			return Err( super::Error::InvalidParameter );
+		}
 		if address % ::PAGE_SIZE != (ofs % ::PAGE_SIZE as u64) as usize {
 			return Err( super::Error::Unknown("memory_map alignment mismatch") );
 		}
 		// - Limit checking (ofs + size must be within size of the file)
-		// TODO: Limit checking
-		// - Reserve the region to be mapped (reserve sticks a zero page in)
-		let page_count = size / ::PAGE_SIZE;
-		let mut resv = match ::memory::virt::reserve(address as *mut (), page_count)
+		if ofs.checked_add(size as u64).map(|end| end > self.size()).unwrap_or(true) {
+			return Err( super::Error::InvalidParameter );
+		}
+		// - Register the region, pages are read (or shared from the page cache) when accessed
+		let page_count = (size + ::PAGE_SIZE - 1) / ::PAGE_SIZE;
+		let source = demand::Source::File {
+			backing: ::lib::mem::Arc::new(self.node.clone()),
+			ofs: ofs,
+			len: size as u64,
+			};
+		let demand_mode = match mode
 			{
-			Ok(v) => v,
-			Err(e) => {
-				log_notice!("mmap reserve error {:?}", e);
-				return Err( super::Error::Locked );
-				},
+			MemoryMapMode::ReadOnly  => demand::Mode::ReadOnly,
+			MemoryMapMode::Execute   => demand::Mode::Execute,
+			MemoryMapMode::COW       => demand::Mode::Private,
+			MemoryMapMode::WriteBack => demand::Mode::Shared,
 			};
-		// - Obtain handles to each cached page, and map into the reservation
-		for i in 0 .. page_count {
-			let page = ofs / ::PAGE_SIZE as u64 + i as u64;
-			// 1. Search the node for this particular page
-			//let lh = self.page_cache.read();
-			//  - If found, map over region
-			// 2. Drop lock, read data from file, and try again
-			//drop(lh)
-			try!( self.node.read(page * ::PAGE_SIZE as u64, resv.get_mut_page(i)) );
-			// 3. Acquire write on lock, and attempt to insert a handle to this page
-			//let lh = self.page_cache.write();
-			//match lh.try_insert(pag, self.get_page_handle(i))
-			//{
-			//Ok(_) => {},	// Inserted correctly
-			//Err(h) => {	// Another handle made a page for this first
-			//	resv.map_at(i, h);	// - Map over our original attempt
-			//	},
-			//}
+		match ::memory::virt::map_user_demand(address as *mut (), page_count, source, demand_mode)
+		{
+		Ok( () ) => {},
+		Err(::memory::virt::MapError::OutOfMemory) => return Err( super::Error::OutOfMemory ),
+		Err(e) => {
+			log_notice!("memory_map error {:?}", e);
+			return Err( super::Error::Locked );
+			},
 		}
-		resv.finalise( match mode
-			{
-			MemoryMapMode::ReadOnly  => ::memory::virt::ProtectionMode::UserRO,
-			MemoryMapMode::Execute   => ::memory::virt::ProtectionMode::UserRX,
-			MemoryMapMode::COW       => ::memory::virt::ProtectionMode::UserCOW,
-			MemoryMapMode::WriteBack => ::memory::virt::ProtectionMode::UserRW,
-			})
-			.unwrap();
-		log_debug!("- Mapped at {:p} + {:#x}", address as *mut (), page_count * ::PAGE_SIZE);
+		log_debug!("- Mapped at {:p} + {:#x}", address as *mut (), size);
 		Ok(MemoryMapHandle {
 			handle: self,
 			base: address as *mut (),
-			len: page_count * ::PAGE_SIZE,
+			len: size,
 			})
 	}
 }
@@ -272,7 +287,9 @@ impl ::core::ops::Drop for File
 		{
 		FileOpenMode::SharedRO => {},
 		FileOpenMode::Execute => {},
-		_ => todo!("File::drop() - mode={:?}", self.mode),
+		FileOpenMode::Unsynch => {},
+		// Other modes are rejected by `from_node`
+		_ => {},
 		}
 		// TODO: For files, we need to release the lock
 	}
@@ -282,13 +299,9 @@ impl<'a> Drop for MemoryMapHandle<'a>
 {
 	fn drop(&mut self)
 	{
-		assert_eq!(self.len % ::PAGE_SIZE, 0, "TODO: Handle unaligned lengths in MemoryMapHandle::drop");
-		assert_eq!(self.base as usize % ::PAGE_SIZE, 0, "TODO: Handle unaligned addresses in MemoryMapHandle::drop");
-		let npages = self.len / ::PAGE_SIZE;
-		// SAFE: This is a uniquely owned handle
-		unsafe {
-			::memory::virt::unmap(self.base, npages);
-		}
+		// Releases the pages (writing back any modified pages of a `WriteBack` mapping)
+		let npages = (self.len + ::PAGE_SIZE - 1) / ::PAGE_SIZE;
+		::memory::demand::unmap(self.base as usize, npages);
 	}
 }
 
@@ -314,12 +327,14 @@ impl Dir
 	
 	/// Create a new directory
 	pub fn mkdir(&self, name: &str) -> super::Result<Dir> {
+		try!(self.node.check_access(super::node::ACCESS_WRITE));
 		let node = try!(self.node.create(name.as_ref(), NodeType::Dir));
 		assert!(node.is_dir());
 		Ok( Dir { node: node } )
 	}
 	/// Create a new symbolic link
 	pub fn symlink(&self, name: &str, target: &Path) -> super::Result<()> {
+		try!(self.node.check_access(super::node::ACCESS_WRITE));
 		try!(self.node.create(name.as_ref(), NodeType::Symlink(target)));
 		Ok( () )
 	}
@@ -338,6 +353,7 @@ impl Dir
 
 	/// RETURN: next position
 	pub fn read_ents(&self, pos: usize, ents: &mut super::node::ReadDirCallback) -> super::Result<usize> {
+		try!(self.node.check_access(super::node::ACCESS_READ));
 		self.node.read_dir(pos, ents)
 	}
 }
@@ -22,6 +22,8 @@ pub enum Error
 	Locked,
 	/// The item already exists
 	AlreadyExists,
+	/// Directory is not empty (e.g. when removing it)
+	NotEmpty,
 
 	/// Path was malformed (too long, not absolute, not normalised, ... depends)
 	MalformedPath,
@@ -75,6 +77,7 @@ pub mod mount;
 pub mod handle;
 mod path;
 mod ramfs;
+mod devfs;
 
 fn init()
 {
@@ -82,6 +85,7 @@ fn init()
 	mount::init();
 	node::init();
 	ramfs::init();
+	devfs::init();
 	// 2. Start the root/builtin filesystems
 	mount::mount("/".as_ref(), VolumeHandle::new_ramdisk(0), "ramfs", &[]).expect("Unable to mount /");
 	// 3. Initialise root filesystem layout
@@ -90,8 +94,11 @@ fn init()
 		Ok(v) => v,
 		Err(e) => panic!("BUG - Opening '/' failed: {:?}", e),
 		};
-	root.mkdir("system").unwrap();
+	let system = root.mkdir("system").unwrap();
 	root.mkdir("volumes").unwrap();
 	root.mkdir("temp").unwrap();
+	// 4. Device filesystem
+	system.mkdir("devices").unwrap();
+	mount::mount("/system/devices".as_ref(), VolumeHandle::new_ramdisk(0), "devfs", &[]).expect("Unable to mount /system/devices");
 }
 
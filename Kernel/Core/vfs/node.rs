@@ -8,6 +8,8 @@ use super::Path;
 use sync::mutex::LazyMutex;
 use lib::byte_str::{ByteStr,ByteString};
 use core::sync::atomic::{self,AtomicUsize};
+use memory::phys::FrameHandle;
+use PAGE_SIZE;
 
 pub type InodeId = u64;
 pub type Result<T> = ::core::result::Result<T,super::Error>;
@@ -27,12 +29,52 @@ pub enum NodeClass {
 	Special,
 }
 
+/// Access bit for `Ownership::permits`: Read
+pub const ACCESS_READ: u16 = 4;
+/// Access bit for `Ownership::permits`: Write
+pub const ACCESS_WRITE: u16 = 2;
+/// Access bit for `Ownership::permits`: Execute (or search, for directories)
+pub const ACCESS_EXEC: u16 = 1;
+
+/// Owner and UNIX-style permission bits of a node
+#[derive(Debug,Copy,Clone)]
+pub struct Ownership
+{
+	pub uid: u32,
+	pub gid: u32,
+	/// Permission bits, three `ACCESS_*` masks for owner (`0o700`), group (`0o070`) and others (`0o007`)
+	pub mode: u16,
+}
+impl Ownership
+{
+	/// Returns true if the passed credentials are allowed the requested access (bitmask of `ACCESS_*`)
+	pub fn permits(&self, creds: &::threads::Credentials, access: u16) -> bool {
+		if creds.is_root() {
+			return true;
+		}
+		let bits = if creds.uid == self.uid {
+				self.mode >> 6
+			}
+			else if creds.gid == self.gid {
+				self.mode >> 3
+			}
+			else {
+				self.mode
+			};
+		bits & access == access
+	}
+}
+
 /// Base trait for a VFS node, defines common operation on nodes
 pub trait NodeBase: Send {
 	/// Return the volume's inode number
 	fn get_id(&self) -> InodeId;
 	/// Return an &Any associated with this node (not nessesarily same as `self`, up to the driver)
 	fn get_any(&self) -> &Any;
+	/// Return the owner and permissions of this node (`None` if the filesystem doesn't track ownership)
+	fn get_ownership(&self) -> Option<Ownership> {
+		None
+	}
 }
 /// Trait for "File" nodes
 pub trait File: NodeBase {
@@ -101,10 +143,9 @@ pub enum Node
 enum CacheNodeInt
 {
 	File {
-		fsnode: Box<File>
-		
-		// File memory map data
-		//mapped_pages: HashMap<u64,FrameHandle>,
+		fsnode: Box<File>,
+		/// Page cache used for memory-mapped pages (by page-aligned file offset)
+		mapped_pages: ::sync::Mutex<::lib::VecMap<u64,FrameHandle>>,
 		},
 	Dir {
 		mountpoint: AtomicUsize,	// 0 is invalid (that's root), so means "no mount"
@@ -122,7 +163,7 @@ impl_from!{
 	From<Node>(v) for CacheNodeInt {
 		match v
 		{
-		Node::File(f) => CacheNodeInt::File { fsnode: f },
+		Node::File(f) => CacheNodeInt::File { fsnode: f, mapped_pages: Default::default() },
 		Node::Dir(f) => CacheNodeInt::Dir { fsnode: f, mountpoint: AtomicUsize::new(0) },
 		Node::Symlink(f) => CacheNodeInt::Symlink { target: f.read(), fsnode: f },
 		Node::Special(f) => CacheNodeInt::Special { fsnode: f },
@@ -333,6 +374,24 @@ impl CacheHandle
 		&CacheNodeInt::Symlink { ref fsnode, .. } => fsnode.get_any(),
 		}
 	}
+
+	pub fn get_ownership(&self) -> Option<Ownership> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Dir { ref fsnode, .. } => fsnode.get_ownership(),
+		&CacheNodeInt::File { ref fsnode, .. } => fsnode.get_ownership(),
+		&CacheNodeInt::Special { ref fsnode, .. } => fsnode.get_ownership(),
+		&CacheNodeInt::Symlink { ref fsnode, .. } => fsnode.get_ownership(),
+		}
+	}
+	/// Check that the current process is allowed the requested access (bitmask of `ACCESS_*`)
+	pub fn check_access(&self, access: u16) -> super::Result<()> {
+		match self.get_ownership()
+		{
+		Some(o) if !o.permits(&::threads::get_credentials(), access) => Err( super::Error::PermissionDenied ),
+		_ => Ok( () ),
+		}
+	}
 }
 /// Directory methods
 impl CacheHandle
@@ -402,10 +461,129 @@ impl CacheHandle
 	pub fn read(&self, ofs: u64, dst: &mut [u8]) -> super::Result<usize> {
 		match self.as_ref()
 		{
-		&CacheNodeInt::File { ref fsnode, .. } => Ok( try!(fsnode.read(ofs, dst)) ),
+		&CacheNodeInt::File { ref fsnode, ref mapped_pages } => {
+			let len = try!(fsnode.read(ofs, dst));
+			// Memory-mapped pages may have been modified (and not yet written back)
+			for_each_cached(mapped_pages, ofs, len, |data, pos| dst[pos ..][..data.len()].copy_from_slice(data));
+			Ok( len )
+			},
 		_ => Err( super::Error::Unknown("Calling read on non-file") ),
 		}
 	}
+	pub fn write(&self, ofs: u64, src: &[u8]) -> super::Result<usize> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref fsnode, ref mapped_pages } => {
+			let len = try!(fsnode.write(ofs, src));
+			// Keep memory-mapped pages consistent with the file
+			for_each_cached(mapped_pages, ofs, len, |data, pos| data.copy_from_slice(&src[pos ..][..data.len()]));
+			Ok( len )
+			},
+		_ => Err( super::Error::Unknown("Calling write on non-file") ),
+		}
+	}
+
+	/// Obtain the page cache frame for the page at `ofs` (page-aligned), reading it from the file if needed
+	pub fn get_cached_page(&self, ofs: u64) -> super::Result<FrameHandle> {
+		assert!(ofs % PAGE_SIZE as u64 == 0, "get_cached_page({:#x}) - Unaligned offset", ofs);
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref fsnode, ref mapped_pages } => {
+			let mut lh = mapped_pages.lock();
+			if let Some(frame) = lh.get(&ofs) {
+				return Ok( frame.clone() );
+			}
+			// Drop pages left behind by mappings that have since gone away (e.g. with their process)
+			evict_unused(&mut lh);
+			let mut page = try!( ::memory::phys::allocate_bare().map_err(|_| super::Error::OutOfMemory) );
+			// SAFE: Frame was just allocated, the handle takes ownership of the allocation's reference
+			let frame = unsafe { FrameHandle::from_addr_noref(page.phys_addr()) };
+			let len = if ofs < fsnode.size() { try!(fsnode.read(ofs, &mut page)) } else { 0 };
+			for b in page[len..].iter_mut() {
+				*b = 0;
+			}
+			lh.insert(ofs, frame.clone());
+			Ok( frame )
+			},
+		_ => Err( super::Error::Unknown("Calling get_cached_page on non-file") ),
+		}
+	}
+	/// Drop the page cache frame for the page at `ofs` if it's no longer mapped anywhere
+	pub fn release_cached_page(&self, ofs: u64) {
+		if let &CacheNodeInt::File { ref mapped_pages, .. } = self.as_ref()
+		{
+			let mut lh = mapped_pages.lock();
+			if lh.get(&ofs).map(|f| f.is_unique()).unwrap_or(false) {
+				lh.remove(&ofs);
+			}
+		}
+	}
+	/// Write the contents of a cached page back to the file (not extending the file)
+	pub fn write_cached_page(&self, ofs: u64, frame: &FrameHandle) -> super::Result<()> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref fsnode, .. } => {
+			let size = fsnode.size();
+			if ofs < size {
+				let len = ::core::cmp::min(PAGE_SIZE as u64, size - ofs) as usize;
+				// SAFE: Frame is valid (held by the handle), and only read
+				try!(unsafe { ::memory::virt::with_temp(frame.phys_addr(), |data| fsnode.write(ofs, &data[..len])) });
+			}
+			Ok( () )
+			},
+		_ => Err( super::Error::Unknown("Calling write_cached_page on non-file") ),
+		}
+	}
+}
+
+/// Drop all cached pages that are only referenced by the cache
+///
+/// NOTE: Cached pages are always clean once unmapped, as writes go to both the file and the cache, and shared
+/// mappings write back when they're unmapped.
+fn evict_unused(mapped_pages: &mut ::lib::VecMap<u64,FrameHandle>)
+{
+	let unused: Vec<u64> = mapped_pages.iter()
+		.filter(|&(_, f)| f.is_unique())
+		.map(|(&p, _)| p)
+		.collect();
+	for p in unused {
+		mapped_pages.remove(&p);
+	}
+}
+
+/// Call `cb` with the section of each cached page overlapping `ofs`+`len`, along with its offset from `ofs`
+fn for_each_cached<F>(mapped_pages: &::sync::Mutex<::lib::VecMap<u64,FrameHandle>>, ofs: u64, len: usize, mut cb: F)
+where
+	F: FnMut(&mut [u8], usize)
+{
+	let end = ofs + len as u64;
+	// Take copies of the handles, so the lock isn't held while accessing the caller's buffer (which may fault)
+	let pages: Vec<(u64,FrameHandle)> = mapped_pages.lock().iter()
+		.filter(|&(&p, _)| p < end && ofs < p + PAGE_SIZE as u64)
+		.map(|(&p, f)| (p, f.clone()))
+		.collect();
+	for (page_ofs, frame) in pages
+	{
+		let start = ::core::cmp::max(page_ofs, ofs);
+		let stop = ::core::cmp::min(page_ofs + PAGE_SIZE as u64, end);
+		// SAFE: Frame is valid (held by the handle). NOTE: It can be concurrently modified by userland mappings
+		unsafe {
+			::memory::virt::with_temp(frame.phys_addr(), |data| cb( &mut data[(start - page_ofs) as usize .. (stop - page_ofs) as usize], (start - ofs) as usize ));
+		}
+	}
+}
+
+impl ::memory::demand::Backing for CacheHandle
+{
+	fn get_page(&self, ofs: u64) -> ::core::result::Result<FrameHandle, ()> {
+		self.get_cached_page(ofs).map_err(|e| log_notice!("Unable to read page {:#x} of {:?} for mapping: {:?}", ofs, self, e))
+	}
+	fn write_page(&self, ofs: u64, frame: &FrameHandle) -> ::core::result::Result<(), ()> {
+		self.write_cached_page(ofs, frame).map_err(|e| log_notice!("Unable to write back page {:#x} of {:?}: {:?}", ofs, self, e))
+	}
+	fn release_page(&self, ofs: u64) {
+		self.release_cached_page(ofs)
+	}
 }
 
 
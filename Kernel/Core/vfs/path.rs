@@ -112,6 +112,11 @@ impl AsRef<ByteStr> for Path {
 		&self.0
 	}
 }
+impl AsRef<Path> for Path {
+	fn as_ref(&self) -> &Path {
+		self
+	}
+}
 impl AsRef<Path> for str {
 	fn as_ref(&self) -> &Path {
 		Path::new(self)
@@ -114,6 +114,9 @@ impl vfs::node::NodeBase for Dir
 	fn get_any(&self) -> &::core::any::Any {
 		self
 	}
+	fn get_ownership(&self) -> Option<vfs::node::Ownership> {
+		Some( self.inode.ownership() )
+	}
 }
 impl vfs::node::Dir for Dir
 {
@@ -33,6 +33,9 @@ impl vfs::node::NodeBase for File
 	fn get_any(&self) -> &::core::any::Any {
 		self
 	}
+	fn get_ownership(&self) -> Option<vfs::node::Ownership> {
+		Some( self.inode.ownership() )
+	}
 }
 impl vfs::node::File for File
 {
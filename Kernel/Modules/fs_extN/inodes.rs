@@ -66,6 +66,15 @@ impl Inode
 	pub fn i_size(&self) -> u64 {
 		self.ondisk.i_size as u64
 	}
+	/// Owner and permission bits (including the Linux-specific high 16 bits of the uid/gid)
+	pub fn ownership(&self) -> vfs::node::Ownership {
+		let ids_high = self.ondisk._osd2[1];
+		vfs::node::Ownership {
+			uid: self.ondisk.i_uid as u32 | (ids_high & 0xFFFF) << 16,
+			gid: self.ondisk.i_gid as u32 | (ids_high >> 16) << 16,
+			mode: self.ondisk.i_mode & 0o777,
+		}
+	}
 }
 
 impl Inode
@@ -178,6 +178,26 @@ impl InputChannel
 		self.double_click_info.lock().clear();
 		super::windows::handle_input(/*self, */Event::MouseMove(x, y, dx, dy));
 	}
+	/// Absolute pointer position (e.g. tablets), `x` and `y` are fractions of the primary display (0 - 0xFFFF)
+	pub fn handle_mouse_abs(&self, x: u16, y: u16)
+	{
+		let dims = match ::kernel::metadevs::video::get_display_for_pos(::kernel::metadevs::video::Pos::new(0,0))
+			{
+			Some(r) => r.dims(),
+			None => return,
+			};
+		let (old_x, old_y) = self.cursor.pos();
+		let new_x = (x as u64 * dims.w as u64 / 0x10000) as u32;
+		let new_y = (y as u64 * dims.h as u64 / 0x10000) as u32;
+		let dx = new_x as i32 - old_x as i32;
+		let dy = new_y as i32 - old_y as i32;
+		if dx != 0 || dy != 0 {
+			self.cursor.move_pos(dx, dy);
+			let (x,y) = self.cursor.pos();
+			self.double_click_info.lock().clear();
+			super::windows::handle_input(/*self, */Event::MouseMove(x, y, dx as i16, dy as i16));
+		}
+	}
 	pub fn handle_mouse_btn(&self, btn: u8, release: bool)
 	{
 		let (x,y) = self.cursor.pos();
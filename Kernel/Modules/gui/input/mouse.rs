@@ -16,6 +16,10 @@ impl Instance
 	pub fn move_cursor(&self, dx: i16, dy: i16) {
 		super::get_channel_by_index(0).handle_mouse_move(dx, dy);
 	}
+	/// Set the cursor position, as a fraction of the display (0 - 0xFFFF on each axis)
+	pub fn set_cursor_abs(&self, x: u16, y: u16) {
+		super::get_channel_by_index(0).handle_mouse_abs(x, y);
+	}
 	pub fn press_button(&self, btn: u8) {
 		super::get_channel_by_index(0).handle_mouse_btn(btn, false);
 	}
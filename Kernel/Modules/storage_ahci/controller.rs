@@ -5,6 +5,8 @@
 use kernel::prelude::*;
 use kernel::device_manager;
 use kernel::lib::mem::aref::ArefInner;
+use kernel::sync::Semaphore;
+use core::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
 use hw;
 
 use port::{Port, PortRegs};
@@ -15,12 +17,21 @@ pub struct Controller
 	inner: ArefInner<ControllerInner>,
 	ports: Vec<Port>,
 	irq_handle: Option<::kernel::irqs::ObjectHandle>,
+	/// Handles port connection changes (probing a new device requires waiting for commands)
+	hotplug_worker: Option<::kernel::threads::WorkerThread>,
 }
 pub struct ControllerInner
 {
 	pub io_base: device_manager::IOBinding,
 	pub max_commands: u8,
 	pub supports_64bit: bool,
+	pub supports_ncq: bool,
+
+	/// Bitmask of ports with a pending connection change
+	hotplug_ports: AtomicUsize,
+	hotplug_sem: Semaphore,
+	hotplug_shutdown: AtomicBool,
+	hotplug_done: AtomicBool,
 }
 
 impl Controller
@@ -31,7 +42,7 @@ impl Controller
 		// Enumerate implemented ports
 		let ports_implemented;
 		// SAFE: Enumerate access to hardware
-		let (n_ports, max_commands, supports_64bit) = unsafe {
+		let (n_ports, max_commands, supports_64bit, supports_ncq) = unsafe {
 			io.write_32(hw::REG_GHC, hw::GHC_AE);
 			ports_implemented = io.read_32(hw::REG_PI);
 			
@@ -55,9 +66,10 @@ impl Controller
 
 			let capabilities = io.read_32(hw::REG_CAP);
 			let supports_64bit = capabilities & hw::CAP_S64A != 0;
+			let supports_ncq = capabilities & hw::CAP_SNCQ != 0;
 			let max_commands = ((capabilities & hw::CAP_NCS) >> hw::CAP_NCS_ofs) + 1;
 			
-			(n_ports, max_commands, supports_64bit,)
+			(n_ports, max_commands, supports_64bit, supports_ncq)
 			};
 		
 		// Construct controller structure
@@ -66,10 +78,17 @@ impl Controller
 			inner: unsafe {ArefInner::new(ControllerInner {
 				io_base: io,
 				supports_64bit: supports_64bit,
+				supports_ncq: supports_ncq,
 				max_commands: max_commands as u8,
+				hotplug_ports: AtomicUsize::new(0),
+				// - One per port, plus shutdown
+				hotplug_sem: Semaphore::new(0, 33),
+				hotplug_shutdown: AtomicBool::new(false),
+				hotplug_done: AtomicBool::new(false),
 				}) },
 			ports: Vec::with_capacity(n_ports),
 			irq_handle: None,
+			hotplug_worker: None,
 			});
 		
 		// Allocate port information
@@ -119,9 +138,38 @@ impl Controller
 			port.update_connection();
 		}
 
+		// Start the hotplug worker (changes before this point were handled above)
+		{
+			struct RawSend<T: Send>(*const T);
+			unsafe impl<T: Send> Send for RawSend<T> {}
+			let ret_raw = RawSend(&*ret);
+			// SAFE: Drop waits for the worker to exit before the controller is invalidated
+			ret.hotplug_worker = Some(::kernel::threads::WorkerThread::new("AHCI Hotplug", move || unsafe { (*ret_raw.0).run_hotplug() }));
+		}
+
 		Ok( ret )
 	}
 
+	fn run_hotplug(&self)
+	{
+		loop
+		{
+			self.inner.hotplug_sem.acquire();
+			if self.inner.hotplug_shutdown.load(Ordering::Acquire) {
+				break ;
+			}
+			let pending = self.inner.hotplug_ports.swap(0, Ordering::Acquire);
+			for port in &self.ports
+			{
+				if pending & (1 << port.index) != 0
+				{
+					port.update_connection();
+				}
+			}
+		}
+		self.inner.hotplug_done.store(true, Ordering::Release);
+	}
+
 
 	fn handle_irq(&self) -> bool
 	{
@@ -140,6 +188,17 @@ impl Controller
 		rv
 	}
 }
+impl ControllerInner
+{
+	/// Schedule a connection re-check on a port (called from the interrupt handler)
+	pub fn port_changed(&self, index: usize)
+	{
+		// Only signal if not already pending, so the semaphore can't overflow
+		if self.hotplug_ports.fetch_or(1 << index, Ordering::Release) & (1 << index) == 0 {
+			self.hotplug_sem.release();
+		}
+	}
+}
 impl_fmt! {
 	Display(self, f) for ControllerInner {
 		write!(f, "AHCI ?")
@@ -149,3 +208,19 @@ impl device_manager::DriverInstance for Controller
 {
 
 }
+impl ::core::ops::Drop for Controller
+{
+	fn drop(&mut self)
+	{
+		// Stop the hotplug worker (it references the ports)
+		if self.hotplug_worker.is_some()
+		{
+			self.inner.hotplug_shutdown.store(true, Ordering::Release);
+			self.inner.hotplug_sem.release();
+			while !self.inner.hotplug_done.load(Ordering::Acquire)
+			{
+				::kernel::threads::yield_time();
+			}
+		}
+	}
+}
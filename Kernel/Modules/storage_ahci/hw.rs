@@ -94,6 +94,11 @@ pub const PxSSTS_SPD_ofs: usize = 4;
 pub const PxSSTS_DET: u32 = (15 << 0);	// Device Detection (0: None, 1: Present but no PHY yet, 3: Present and PHY, 4: offline)
 pub const PxSSTS_DET_ofs: usize = 0;
 
+pub const PxSCTL_DET: u32 = (15 << 0);	// Device Detection Initialization (1 = COMRESET)
+
+pub const PxSERR_DIAG_X: u32 = (1 << 26);	// Exchanged (device presence change)
+pub const PxSERR_DIAG_N: u32 = (1 << 16);	// PhyRdy Change
+
 #[repr(C)]
 pub struct CmdHeader
 {
@@ -3,20 +3,33 @@
 //
 //! 
 use kernel::prelude::*;
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicBool,AtomicPtr,AtomicUsize,Ordering};
 use kernel::sync::atomic::AtomicU32;
-use kernel::sync::Mutex;
+use kernel::sync::{Mutex,Spinlock,RwLock};
+use kernel::sync::rwlock;
 use kernel::metadevs::storage::{self, DataPtr};
 use kernel::memory::virt::AllocHandle;
 use kernel::lib::mem::aref::ArefBorrow;
 use kernel::device_manager;
 use hw;
 
+const ATA_READ_DMA: u8 = 0xC8;
+const ATA_WRITE_DMA: u8 = 0xCA;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_READ_FPDMA_QUEUED: u8 = 0x60;
+const ATA_WRITE_FPDMA_QUEUED: u8 = 0x61;
+
+/// IDENTIFY word 76 - Native Command Queuing supported
+const SATA_CAP_NCQ: u16 = 1 << 8;
+
 enum Error
 {
 	Ata { err: u8, sts: u8 },
 	Atapi { sense_key: ::storage_scsi::proto::SenseKey, eom: bool, ili: bool },
 	Bus,
+	/// Device was removed (or the port is not running)
+	Removed,
 }
 impl_fmt! {
 	Debug(self,f) for Error {
@@ -27,25 +40,67 @@ impl_fmt! {
 			),
 		&Error::Atapi { sense_key, eom, ili } => write!(f, "Atapi(sense_key={:?},eom={},ili={})", sense_key, eom, ili),
 		&Error::Bus => write!(f, "Bus"),
+		&Error::Removed => write!(f, "Removed"),
 		}
 	}
 }
 
-pub struct Port
+/// State of a command slot
+#[derive(Copy,Clone)]
+enum SlotState
 {
-	name: String,
-	pub index: usize,
-	ctrlr: ArefBorrow<::controller::ControllerInner>,
+	Idle,
+	/// Issued to the hardware
+	Active,
+	Complete,
+	/// Device reported an error (value is PxTFD)
+	TaskFileError(u32),
+	/// Interface/bus error, or aborted by error recovery
+	BusError,
+	/// Device removed while the command was outstanding
+	Removed,
+}
 
-	volume: Mutex<Option<storage::PhysicalVolumeReg>>,
-	
-	// Hardware allocations:
+/// Command engine state, shared between the issuing code and the interrupt handler
+struct EngineState
+{
+	/// PxCMD.ST is set (commands can be issued)
+	running: bool,
+	slots: [SlotState; 32],
+}
+
+/// Hardware allocations for a port (only made once a device is detected)
+struct PortMemory
+{
 	// - 1KB (<32*32 bytes) for the command list
 	// - 256 bytes of Received FIS
 	// - <16KB (32*256 bytes) of command tables
 	// Contains the "Command List" (a 1KB aligned block of memory containing commands)
 	command_list_alloc: AllocHandle,
 	command_tables: [AllocHandle; 4],
+}
+
+pub struct Port
+{
+	name: String,
+	pub index: usize,
+	ctrlr: ArefBorrow<::controller::ControllerInner>,
+
+	volume: Mutex<Option<storage::PhysicalVolumeReg>>,
+
+	/// Owned `PortMemory` (null until a device is first detected, never freed until the port is dropped)
+	memory: AtomicPtr<PortMemory>,
+
+	/// Serialises starting/stopping the command engine (connection changes and error recovery)
+	state_lock: Mutex<()>,
+	engine: Spinlock<EngineState>,
+	/// Set by the interrupt handler when an error has halted the command engine
+	needs_recovery: AtomicBool,
+
+	/// Usable NCQ depth (zero if the device doesn't support NCQ)
+	ncq_depth: AtomicUsize,
+	/// Queued (NCQ) commands hold this for read, non-queued commands for write (the two can't be mixed)
+	queue_mode: RwLock<()>,
 
 	command_events: Vec<::kernel::sync::EventChannel>,
 
@@ -83,6 +138,8 @@ impl<'a> PortRegs<'a>
 const MAX_COMMANDS_FOR_SHARE: usize = (::kernel::PAGE_SIZE - 256) / (256 + 32);
 const CMDS_PER_PAGE: usize = ::kernel::PAGE_SIZE / 0x100;
 
+/// Maximum length of a single PRDT entry
+const MAX_SEG_LEN: usize = (1 << 22);
 
 impl ::core::fmt::Display for Port
 {
@@ -98,34 +155,22 @@ impl Port
 	/// - The stored instance does not move once any other methods are called.
 	pub unsafe fn new(controller: ArefBorrow<::controller::ControllerInner>, idx: usize, max_commands: usize) -> Result<Port, device_manager::DriverBindError>
 	{
-		use core::mem::size_of;
 		log_trace!("Port::new(, idx={}, max_commands={})", idx, max_commands);
 
 		assert!(idx < 32);
 		assert!(max_commands <= 32);
-		
-		let (cl_page, cmdtab_pages) = try!( Self::allocate_memory(&controller) );
 
-		// Populate register values.
+		// NOTE: Memory is allocated (and the command engine started) once a device is detected, see `update_connection`
 		{
 			let regs = PortRegs::new(&controller.io_base, idx);
 
-			let addr = ::kernel::memory::virt::get_phys( cl_page.as_ref::<()>(0) ) as u64;
-			regs.write(hw::REG_PxCLB , (addr >>  0) as u32);
-			regs.write(hw::REG_PxCLBU, (addr >> 32) as u32);
-			let addr = ::kernel::memory::virt::get_phys( cl_page.as_ref::<hw::RcvdFis>( ::kernel::PAGE_SIZE - size_of::<hw::RcvdFis>() ) ) as u64;
-			regs.write(hw::REG_PxFB , (addr >>  0) as u32);
-			regs.write(hw::REG_PxFBU, (addr >> 32) as u32);
-
-			// Clear PxACT (TODO: not really used here)
 			regs.write(hw::REG_PxSACT, 0);
 			// Interrupts on
 			regs.write(hw::REG_PxSERR, 0x3FF783);
 			regs.write(hw::REG_PxIS, !0);
-			regs.write(hw::REG_PxIE, hw::PxIS_CPDS|hw::PxIS_DSS|hw::PxIS_PSS|hw::PxIS_DHRS|hw::PxIS_TFES|hw::PxIS_IFS);
-			// Start command engine (Start, FIS Rx Enable)
-			let cmd = regs.read(hw::REG_PxCMD);
-			regs.write(hw::REG_PxCMD, cmd|hw::PxCMD_ST|hw::PxCMD_FRE);
+			regs.write(hw::REG_PxIE, hw::PxIS_CPDS|hw::PxIS_PRCS|hw::PxIS_PCS
+				|hw::PxIS_TFES|hw::PxIS_HBFS|hw::PxIS_HBDS|hw::PxIS_IFS
+				|hw::PxIS_SDBS|hw::PxIS_DSS|hw::PxIS_PSS|hw::PxIS_DHRS);
 		}
 
 
@@ -136,32 +181,41 @@ impl Port
 
 			volume: Mutex::new(None),
 
-			command_list_alloc: cl_page,
-			command_tables: cmdtab_pages,
+			memory: AtomicPtr::new(::core::ptr::null_mut()),
+
+			state_lock: Mutex::new( () ),
+			engine: Spinlock::new(EngineState {
+				running: false,
+				slots: [SlotState::Idle; 32],
+				}),
+			needs_recovery: AtomicBool::new(false),
+
+			ncq_depth: AtomicUsize::new(0),
+			queue_mode: RwLock::new( () ),
 
 			command_events: (0 .. max_commands).map(|_| ::kernel::sync::EventChannel::new()).collect(),
 			used_commands_sem: ::kernel::sync::Semaphore::new(max_commands as isize, max_commands as isize),
 			used_commands: AtomicU32::new(0),
 			})
 	}
-	
-	fn allocate_memory(controller: &::controller::ControllerInner) -> Result< (AllocHandle, [AllocHandle; 4]), device_manager::DriverBindError >
+
+	fn allocate_memory(controller: &::controller::ControllerInner) -> Result< (AllocHandle, [AllocHandle; 4]), ::kernel::memory::virt::MapError >
 	{
 		use core::mem::size_of;
 		let max_commands = controller.max_commands as usize;
 		let cl_size = max_commands * size_of::<hw::CmdHeader>();
+		let bits = if controller.supports_64bit { 64 } else { 32 };
 
 		// Command list
 		// - Command list first (32 * max_commands)
 		// - Up to MAX_COMMANDS_FOR_SHARE in 1024 -- 4096-256
 		// - RcvdFis last
-		let cl_page = try!( ::kernel::memory::virt::alloc_dma(64, 1, "AHCI") );
+		let cl_page = try!( ::kernel::memory::virt::alloc_dma(bits, 1, "AHCI") );
 
 		// Allocate pages for the command table
-		// TODO: Delay allocating memory until a device is detected on this port
 		let cmdtab_pages = if max_commands <= MAX_COMMANDS_FOR_SHARE {
 				// All fits in the CL page!
-				
+
 				// - Return empty allocations
 				Default::default()
 			}
@@ -172,7 +226,7 @@ impl Port
 				assert!(n_pages < 4);
 				for i in 0 .. n_pages
 				{
-					tab_pages[i] = try!( ::kernel::memory::virt::alloc_dma(64, 1, "AHCI") );
+					tab_pages[i] = try!( ::kernel::memory::virt::alloc_dma(bits, 1, "AHCI") );
 				}
 				tab_pages
 			};
@@ -192,6 +246,55 @@ impl Port
 		Ok( (cl_page, cmdtab_pages) )
 	}
 
+	/// Allocate the port's memory and hand it to the hardware (if not already done)
+	///
+	/// Must be called with `state_lock` held, and the FIS receive engine stopped
+	fn init_memory(&self) -> bool
+	{
+		use core::mem::size_of;
+		if !self.memory.load(Ordering::Acquire).is_null() {
+			return true;
+		}
+
+		let (cl_page, cmdtab_pages) = match Self::allocate_memory(&self.ctrlr)
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("{} - Unable to allocate memory: {:?}", self, e);
+				return false;
+				},
+			};
+
+		let regs = self.regs();
+		// SAFE: Engine is stopped, and the memory is owned by this port until it's dropped
+		unsafe {
+			let addr = ::kernel::memory::virt::get_phys( cl_page.as_ref::<()>(0) ) as u64;
+			regs.write(hw::REG_PxCLB , (addr >>  0) as u32);
+			regs.write(hw::REG_PxCLBU, (addr >> 32) as u32);
+			let addr = ::kernel::memory::virt::get_phys( cl_page.as_ref::<hw::RcvdFis>( ::kernel::PAGE_SIZE - size_of::<hw::RcvdFis>() ) ) as u64;
+			regs.write(hw::REG_PxFB , (addr >>  0) as u32);
+			regs.write(hw::REG_PxFBU, (addr >> 32) as u32);
+		}
+
+		let mem = Box::new(PortMemory {
+			command_list_alloc: cl_page,
+			command_tables: cmdtab_pages,
+			});
+		self.memory.store(Box::into_raw(mem), Ordering::Release);
+		true
+	}
+	fn memory(&self) -> Option<&PortMemory>
+	{
+		let p = self.memory.load(Ordering::Acquire);
+		if p.is_null() {
+			None
+		}
+		else {
+			// SAFE: Once set, the pointer is valid until the port is dropped
+			Some(unsafe { &*p })
+		}
+	}
+
 
 	pub fn handle_irq(&self)
 	{
@@ -201,77 +304,82 @@ impl Port
 		let tfd = regs.read(hw::REG_PxTFD);
 		//log_trace!("{} - int_status={:#x}", self, int_status);
 
-		// Cold Port Detection Status
-		if int_status & hw::PxIS_CPDS != 0
+		// Cold Port Detection Status, PhyRdy change, or Port Connect change
+		if int_status & (hw::PxIS_CPDS|hw::PxIS_PRCS|hw::PxIS_PCS) != 0
 		{
-			log_notice!("{} - Presence change", self);
-		}
-
-
-		// "Task File Error Status"
-		if int_status & hw::PxIS_TFES != 0
-		{
-			log_warning!("{} - Device pushed error: TFD={:#x}", self, tfd);
-			// TODO: This should terminate all outstanding transactions with an error.
+			log_notice!("{} - Presence change (SSTS={:#x})", self, regs.read(hw::REG_PxSSTS));
+			// PRCS and PCS are cleared by clearing the matching PxSERR.DIAG bits
+			// SAFE: Write-1-to-clear register
+			unsafe {
+				let serr = regs.read(hw::REG_PxSERR);
+				regs.write(hw::REG_PxSERR, serr & (hw::PxSERR_DIAG_N|hw::PxSERR_DIAG_X));
+			}
+			// Probing needs to wait for commands, so is done by the controller's worker
+			self.ctrlr.port_changed(self.index);
 		}
 
-		// Device->Host Register Update
-		if int_status & hw::PxIS_DHRS != 0
+		// "Task File Error Status", or a fatal interface/bus error
+		let is_error = int_status & (hw::PxIS_TFES|hw::PxIS_HBFS|hw::PxIS_HBDS|hw::PxIS_IFS) != 0;
+		if is_error
 		{
-			log_trace!("{} - Device register update, RFIS={:?}", self, self.get_rcvd_fis().RFIS);
-		}
-		// PIO Setup FIS Update
-		if int_status & hw::PxIS_PSS != 0
-		{
-			log_trace!("{} - PIO setup status update, PSFIS={:?}", self, self.get_rcvd_fis().PSFIS);
+			log_warning!("{} - Error: IS={:#x} TFD={:#x} SERR={:#x}", self, int_status, tfd, regs.read(hw::REG_PxSERR));
+			// The engine stops on an error, recovery is done by the first waiter to see the error
+			self.needs_recovery.store(true, Ordering::Release);
 		}
 
-		if int_status & hw::PxIS_IFS != 0
+		if let Some(mem) = self.memory()
 		{
+			// Device->Host Register Update
+			if int_status & hw::PxIS_DHRS != 0
+			{
+				log_trace!("{} - Device register update, RFIS={:?}", self, mem.rcvd_fis().RFIS);
+			}
+			// PIO Setup FIS Update
+			if int_status & hw::PxIS_PSS != 0
+			{
+				log_trace!("{} - PIO setup status update, PSFIS={:?}", self, mem.rcvd_fis().PSFIS);
+			}
 		}
 
 		// Check commands
-		//if int_status & hw::PxIS_DPS != 0
-		//{
-		let issued_commands = regs.read(hw::REG_PxCI);
-		let active_commands = regs.read(hw::REG_PxSACT);
-		let used_commands = self.used_commands.load(Ordering::Relaxed);
-		//log_trace!("{} - used_commands = {:#x}, issued_commands={:#x}, active_commands={:#x}",
-		//	self, used_commands, issued_commands, active_commands);
-		for cmd in 0 .. self.ctrlr.max_commands as usize
 		{
-			let mask = 1 << cmd;
-			if used_commands & mask != 0
+			let mut lh = self.engine.lock();
+			let issued_commands = regs.read(hw::REG_PxCI);
+			let active_commands = regs.read(hw::REG_PxSACT);
+			for cmd in 0 .. self.ctrlr.max_commands as usize
 			{
-				if tfd & 0x01 != 0 {
-					self.command_events[cmd].post();
-				}
-				else if issued_commands & mask == 0 || active_commands & mask == 0 {
-					self.command_events[cmd].post();
-				}
-				else {
-					// Not yet complete
+				let mask = 1 << cmd;
+				match lh.slots[cmd]
+				{
+				SlotState::Active => {
+					// An error fails all outstanding commands (the failing NCQ command can't be identified)
+					if is_error {
+						lh.slots[cmd] = if int_status & hw::PxIS_TFES != 0 { SlotState::TaskFileError(tfd) } else { SlotState::BusError };
+						self.command_events[cmd].post();
+					}
+					// Non-queued commands complete when PxCI is cleared, queued when PxSACT is
+					else if (issued_commands | active_commands) & mask == 0 {
+						lh.slots[cmd] = SlotState::Complete;
+						self.command_events[cmd].post();
+					}
+					else {
+						// Not yet complete
+					}
+					},
+				SlotState::Idle if (issued_commands | active_commands) & mask != 0 => {
+					log_warning!("{} - Command {} active, but not used", self, cmd);
+					},
+				_ => {},
 				}
 			}
-			else if active_commands & mask != 0	{
-				log_warning!("{} - Command {} active, but not used", self, cmd);
-			}
-			else {
-			}
 		}
-		//}
-	
+
 		// SAFE: Exclusive range, only written here
 		unsafe {
 			regs.write(hw::REG_PxIS, int_status);
 		}
 	}
 
-	fn get_rcvd_fis(&self) -> &hw::RcvdFis
-	{
-		self.command_list_alloc.as_ref::<hw::RcvdFis>( ::kernel::PAGE_SIZE - ::core::mem::size_of::<hw::RcvdFis>() )
-	}
-
 	fn cmdidx_to_ref<'a>(cl_page: &'a AllocHandle, cl_size: usize, cmdtab_pages: &'a [AllocHandle], i: usize) -> &'a hw::CmdTable {
 		//let cl_size = max_commands * size_of::<hw::CmdHeader>();
 		let n_shared = (::kernel::PAGE_SIZE - cl_size) / 0x100 - 1;
@@ -284,12 +392,6 @@ impl Port
 			&cmdtab_pages[pg].as_slice(0, CMDS_PER_PAGE)[ofs]
 		}
 	}
-	fn get_cmdtab_ptr(&self, idx: usize) -> *mut hw::CmdTable
-	{
-		// TODO: Does the fact that this returns &-ptr break anything?
-		let r = Self::cmdidx_to_ref(&self.command_list_alloc, self.ctrlr.max_commands as usize * ::core::mem::size_of::<hw::CmdHeader>(), &self.command_tables,  idx);
-		r as *const _ as *mut _
-	}
 
 	fn regs(&self) -> PortRegs {
 		PortRegs {
@@ -298,22 +400,135 @@ impl Port
 			}
 	}
 
+	/// Set PxCMD.ST (with `state_lock` held)
+	fn start_engine(&self)
+	{
+		let regs = self.regs();
+		let mut lh = self.engine.lock();
+		// SAFE: Memory has been allocated, and all slots are idle
+		unsafe {
+			regs.write(hw::REG_PxIS, !0);
+			let cmd = regs.read(hw::REG_PxCMD);
+			regs.write(hw::REG_PxCMD, cmd|hw::PxCMD_ST);
+		}
+		lh.running = true;
+	}
+	/// Clear PxCMD.ST (with `state_lock` held), failing all outstanding commands
+	fn stop_engine(&self, reason: SlotState)
+	{
+		let regs = self.regs();
+		{
+			let mut lh = self.engine.lock();
+			lh.running = false;
+			// SAFE: Stopping the engine can't cause memory unsafety
+			unsafe {
+				let cmd = regs.read(hw::REG_PxCMD);
+				regs.write(hw::REG_PxCMD, cmd & !hw::PxCMD_ST);
+			}
+		}
+		// The HBA clears PxCI and PxSACT once the engine has stopped, after which command memory is no longer accessed
+		if !::kernel::time::wait_for(500, 1, || regs.read(hw::REG_PxCMD) & hw::PxCMD_CR == 0) {
+			log_error!("{} - Command engine failed to stop", self);
+		}
+
+		let mut lh = self.engine.lock();
+		for (cmd, slot) in lh.slots[.. self.ctrlr.max_commands as usize].iter_mut().enumerate()
+		{
+			if let SlotState::Active = *slot {
+				*slot = reason;
+				self.command_events[cmd].post();
+			}
+		}
+	}
+	/// Reset the link (COMRESET), returning true if a device responded and is ready
+	fn comreset(&self) -> bool
+	{
+		let regs = self.regs();
+		// SAFE: Engine is stopped, resetting the link has no memory effects
+		unsafe {
+			// DET=1 sends COMRESET, which must be held for at least 1ms
+			let sctl = regs.read(hw::REG_PxSCTL);
+			regs.write(hw::REG_PxSCTL, (sctl & !hw::PxSCTL_DET) | 1);
+			::kernel::time::sleep_ms(2);
+			regs.write(hw::REG_PxSCTL, sctl & !hw::PxSCTL_DET);
+		}
+		let linked = ::kernel::time::wait_for(1000, 1, || (regs.read(hw::REG_PxSSTS) & hw::PxSSTS_DET) >> hw::PxSSTS_DET_ofs == 3);
+		// SAFE: Write-1-to-clear
+		unsafe {
+			regs.write(hw::REG_PxSERR, !0);
+		}
+		if !linked {
+			return false;
+		}
+		// Wait for the device to finish its power-on/reset (signature FIS received)
+		::kernel::time::wait_for(10_000, 1, || regs.read(hw::REG_PxTFD) & (hw::PxTFD_STS_BSY|hw::PxTFD_STS_DRQ) == 0)
+	}
+
+	/// Recover from an error that halted the command engine (no-op if recovery isn't needed)
+	fn recover(&self)
+	{
+		let _lh = self.state_lock.lock();
+		if !self.needs_recovery.swap(false, Ordering::Acquire) {
+			return ;
+		}
+		log_notice!("{} - Resetting port after error", self);
+
+		self.stop_engine(SlotState::BusError);
+		if self.comreset() {
+			self.start_engine();
+		}
+		else {
+			// Device has gone away, the presence change will clean up
+			log_warning!("{} - Device did not respond after reset", self);
+		}
+	}
+
 	// Re-check the port for a new device
 	pub fn update_connection(&self)
 	{
 		let io = self.regs();
 
-		// SAFE: Status only registers
-		let (tfd, ssts) = (io.read(hw::REG_PxTFD), io.read(hw::REG_PxSSTS));
-
-		if tfd & (hw::PxTFD_STS_BSY|hw::PxTFD_STS_DRQ) != 0 {
+		// SATA Status: Detected. 3 = Connected and PHY up
+		if (io.read(hw::REG_PxSSTS) & hw::PxSSTS_DET) >> hw::PxSSTS_DET_ofs != 3
+		{
+			{
+				let _lh = self.state_lock.lock();
+				// - Nothing to recover
+				self.needs_recovery.store(false, Ordering::Relaxed);
+				self.stop_engine(SlotState::Removed);
+				self.ncq_depth.store(0, Ordering::Relaxed);
+			}
+			// Any commands issued while the volume is dropped will fail with `Removed`
+			let old_volume = self.volume.lock().take();
+			if old_volume.is_some() {
+				log_notice!("{} - Device removed", self);
+			}
 			return ;
 		}
-		// SATA Status: Detected. 3 = Connected and PHY up
-		if (ssts & hw::PxSSTS_DET) >> hw::PxSSTS_DET_ofs != 3 {
+
+		if self.volume.lock().is_some() {
+			// Already registered (e.g. a link that briefly dropped)
 			return ;
 		}
-		
+
+		// Start the port: Allocate memory, enable FIS receive, and reset the device to get a fresh signature
+		{
+			let _lh = self.state_lock.lock();
+			if !self.init_memory() {
+				return ;
+			}
+			// SAFE: Memory is allocated
+			unsafe {
+				let cmd = io.read(hw::REG_PxCMD);
+				io.write(hw::REG_PxCMD, cmd|hw::PxCMD_FRE);
+			}
+			if !self.comreset() {
+				log_notice!("{} - Device not ready (TFD={:#x})", self, io.read(hw::REG_PxTFD));
+				return ;
+			}
+			self.needs_recovery.store(false, Ordering::Relaxed);
+			self.start_engine();
+		}
 
 		// Obtain the physical volume registration handle
 		let pvh = match io.read(hw::REG_PxSIG)
@@ -322,13 +537,27 @@ impl Port
 			0x00000101 => {
 				// Request ATA Identify from the disk
 				const ATA_IDENTIFY: u8 = 0xEC;
-				let ident = self.request_identify(ATA_IDENTIFY).expect("Failure requesting ATA identify");
+				let ident = match self.request_identify(ATA_IDENTIFY)
+					{
+					Ok(v) => v,
+					Err(e) => {
+						log_error!("{}: Failure requesting ATA identify - {:?}", self, e);
+						return ;
+						},
+					};
 
 				log_debug!("ATA `IDENTIFY` response data = {:?}", ident);
-				
+
 				let sectors = if ident.sector_count_48 == 0 { ident.sector_count_28 as u64 } else { ident.sector_count_48 };
 				log_log!("{}: Hard Disk, {} sectors, {}", self, sectors, storage::SizePrinter(sectors * 512));
 
+				// Native Command Queuing (needs both controller and device support)
+				if self.ctrlr.supports_ncq && ident.sata_capabilities & SATA_CAP_NCQ != 0 {
+					let depth = ::core::cmp::min( (ident.queue_depth & 0x1F) as usize + 1, self.ctrlr.max_commands as usize );
+					log_log!("{}: NCQ enabled, depth {}", self, depth);
+					self.ncq_depth.store(depth, Ordering::Relaxed);
+				}
+
 				//*
 				match ::storage_ata::volume::AtaVolume::new_boxed( self.get_interface() )
 				{
@@ -431,6 +660,49 @@ impl Port
 			};
 		self.do_fis(cmd_data.as_ref(), &[], data)
 	}
+	/// READ/WRITE FPDMA QUEUED (the tag is filled by `do_fis_inner`)
+	fn request_ncq(&self, n_sectors: u16, lba: u64, data: DataPtr) -> Result<usize, Error>
+	{
+		log_trace!("request_ncq(n_sectors={}, lba={}, data={:?})", n_sectors, lba, data);
+		assert!(lba < (1<<48));
+		let cmd_data = hw::sata::FisHost2DevReg {
+			ty: hw::sata::FisType::H2DRegister as u8,
+			flags: 0x80,
+			command: if data.is_send() { ATA_WRITE_FPDMA_QUEUED } else { ATA_READ_FPDMA_QUEUED },
+			// Sector count is in the features register
+			features: n_sectors as u8,
+			features_exp: (n_sectors >> 8) as u8,
+			sector_num: lba as u8,
+			cyl_low: (lba >> 8) as u8,
+			cyl_high: (lba >> 16) as u8,
+			dev_head: 0x40,
+			sector_num_exp: (lba >> 24) as u8,
+			cyl_low_exp: (lba >> 32) as u8,
+			cyl_high_exp: (lba >> 40) as u8,
+			..Default::default()
+			};
+		self.do_fis_inner(true, cmd_data.as_ref(), &[], data)
+	}
+	/// DMA read/write, using NCQ if the device supports it
+	fn request_dma_lba48(&self, cmd: u8, n_sectors: u16, lba: u64, mut data: DataPtr) -> Result<usize, Error>
+	{
+		if self.ncq_depth.load(Ordering::Relaxed) > 0 && (cmd == ATA_READ_DMA_EXT || cmd == ATA_WRITE_DMA_EXT)
+		{
+			let reborrow = match data
+				{
+				DataPtr::Send(p) => DataPtr::Send(p),
+				DataPtr::Recv(ref mut p) => DataPtr::Recv(&mut **p),
+				};
+			match self.request_ncq(n_sectors, lba, reborrow)
+			{
+			Ok(v) => return Ok(v),
+			// An error aborts all queued commands, so retry as a non-queued command to get an error for just this one
+			Err(Error::Removed) => return Err(Error::Removed),
+			Err(e) => log_notice!("{} - Queued command failed ({:?}), retrying without NCQ", self, e),
+			}
+		}
+		self.request_ata_lba48(0, cmd, n_sectors, lba, data)
+	}
 	fn request_atapi(&self, disk: u8, cmd: &[u8], data: DataPtr) -> Result<(), Error>
 	{
 		let fis = hw::sata::FisHost2DevReg {
@@ -449,85 +721,162 @@ impl Port
 		}
 	}
 
-	/// Create and dispatch a FIS, returns the number of bytes
+	/// Create and dispatch a (non-queued) FIS, returns the number of bytes
 	fn do_fis(&self, cmd: &[u8], pkt: &[u8], data: DataPtr) -> Result<usize, Error>
 	{
-		use kernel::memory::virt::get_phys;
-
+		self.do_fis_inner(false, cmd, pkt, data)
+	}
+	fn do_fis_inner(&self, queued: bool, cmd: &[u8], pkt: &[u8], mut data: DataPtr) -> Result<usize, Error>
+	{
 		//log_trace!("do_fis(self={}, cmd={:p}+{}, pkt={:p}+{}, data={:?})",
 		//	self, cmd.as_ptr(), cmd.len(), pkt.as_ptr(), pkt.len(), data);
 
-		let slot = self.get_command_slot();
+		let slot = self.get_command_slot(queued);
 
 		slot.data.cmd_fis[..cmd.len()].clone_from_slice(cmd);
 		slot.data.atapi_cmd[..pkt.len()].clone_from_slice(pkt);
+		if queued {
+			// NCQ tag (the slot index) goes in the sector count register
+			slot.data.cmd_fis[12] = slot.idx << 3;
+		}
 
-		// Generate the scatter-gather list
-		let mut va = data.as_slice().as_ptr() as usize;
-		let mut len = data.as_slice().len();
+		// Generate the scatter-gather list, using a bounce buffer if the caller's buffer is unsuitable
+		let bounce = match Self::fill_prdt(&mut slot.data.prdt, data.as_slice(), self.ctrlr.supports_64bit)
+			{
+			Some(n) => {
+				slot.hdr.prdtl = n as u16;
+				None
+				},
+			None => {
+				let len = data.len();
+				let bits = if self.ctrlr.supports_64bit { 64 } else { 32 };
+				let mut h = match ::kernel::memory::virt::alloc_dma(bits, (len + ::kernel::PAGE_SIZE - 1) / ::kernel::PAGE_SIZE, "AHCI")
+					{
+					Ok(v) => v,
+					Err(e) => {
+						log_error!("{} - Unable to allocate {} byte bounce buffer: {:?}", self, len, e);
+						return Err(Error::Bus);
+						},
+					};
+				if let DataPtr::Send(p) = data {
+					h.as_mut_slice::<u8>(0, len).copy_from_slice(p);
+				}
+				// NOTE: Bounce buffers are physically contiguous and page aligned, so this shouldn't fail
+				let n = match Self::fill_prdt(&mut slot.data.prdt, h.as_slice::<u8>(0, len), self.ctrlr.supports_64bit)
+					{
+					Some(n) => n,
+					None => {
+						log_error!("{} - {} byte bounce buffer unsuitable for PRDT", self, len);
+						return Err(Error::Bus);
+						},
+					};
+				slot.hdr.prdtl = n as u16;
+				Some(h)
+				},
+			};
+		slot.hdr.prdbc = 0;
+		slot.hdr.flags = (cmd.len() / 4) as u16
+			//| (multiplier_port << 12)
+			| (if data.is_send() { 1 << 6 } else { 0 })	// Write
+			| (if pkt.len() > 0 { 1 << 5 } else { 0 })	// ATAPI
+			;
+
+		slot.event.clear();
+		// SAFE: Wait ensures that memory stays valid (the engine is stopped before a failed command returns)
+		let rv = unsafe {
+			try!(slot.start());
+			slot.wait()
+			};
+		// PRDBC isn't updated for queued commands (they either transfer everything, or fail)
+		let rv = if queued { rv.map(|_| data.len()) } else { rv };
+
+		if let Some(h) = bounce {
+			if let DataPtr::Recv(ref mut p) = data {
+				let len = p.len();
+				p.copy_from_slice( h.as_slice::<u8>(0, len) );
+			}
+		}
+		rv
+	}
+
+	/// Populate the PRDT for a buffer, returning the number of entries used
+	///
+	/// Returns `None` if the buffer can't be used for DMA directly (misaligned, not addressable, or too fragmented)
+	fn fill_prdt(prdt: &mut [hw::CmdEnt], buf: &[u8], supports_64bit: bool) -> Option<usize>
+	{
+		use kernel::memory::virt::get_phys;
+
+		let mut va = buf.as_ptr() as usize;
+		let mut len = buf.len();
 		let mut n_prdt_ents = 0;
 		while len > 0
 		{
 			let base_phys = get_phys(va as *const u8);
 			let mut seglen = ::kernel::PAGE_SIZE - base_phys as usize % ::kernel::PAGE_SIZE;
-			const MAX_SEG_LEN: usize = (1 << 22);
 			// Each entry must be contigious, and not >4MB
-			while seglen < len && seglen <= MAX_SEG_LEN && get_phys( (va + seglen-1) as *const u8 ) == base_phys + (seglen-1) as ::kernel::memory::PAddr
+			while seglen < len && seglen <= MAX_SEG_LEN && get_phys( (va + seglen) as *const u8 ) == base_phys + seglen as ::kernel::memory::PAddr
 			{
 				seglen += ::kernel::PAGE_SIZE;
 			}
 			let seglen = ::core::cmp::min(len, seglen);
 			let seglen = ::core::cmp::min(MAX_SEG_LEN, seglen);
 			if base_phys % 4 != 0 || seglen % 2 != 0 {
-				todo!("AHCI Port::do_fis - Use a bounce buffer if alignment requirements are not met");
+				return None;
+			}
+			if !supports_64bit && (base_phys as u64 + seglen as u64) > (1 << 32) {
+				return None;
+			}
+			if n_prdt_ents == prdt.len() {
+				return None;
 			}
-			assert!( n_prdt_ents < slot.data.prdt.len() );
-			slot.data.prdt[n_prdt_ents].dba = base_phys as u64;
-			slot.data.prdt[n_prdt_ents].dbc = (seglen - 1) as u32;
+			prdt[n_prdt_ents].dba = base_phys as u64;
+			prdt[n_prdt_ents].dbc = (seglen - 1) as u32;
 
 			va += seglen;
 			len -= seglen;
 
 			n_prdt_ents += 1;
 		}
-		slot.data.prdt[n_prdt_ents-1].dbc |= 1 << 31;	// set IOC
-		slot.hdr.prdtl = n_prdt_ents as u16;
-		slot.hdr.prdbc = 0;
-		slot.hdr.flags = (cmd.len() / 4) as u16
-			//| (multiplier_port << 12)
-			| (if data.is_send() { 1 << 6 } else { 0 })	// Write
-			| (if pkt.len() > 0 { 1 << 5 } else { 0 })	// ATAPI
-			;
-
-		slot.event.clear();
-		// SAFE: Wait ensures that memory stays valid
-		unsafe {
-			slot.start();
-			slot.wait()
+		if n_prdt_ents > 0 {
+			prdt[n_prdt_ents-1].dbc |= 1 << 31;	// set IOC
 		}
+		Some(n_prdt_ents)
 	}
 
-	fn get_command_slot(&self) -> CommandSlot
+	fn get_command_slot(&self, queued: bool) -> CommandSlot
 	{
 		let max_commands = self.ctrlr.max_commands as usize;
+		let mem = self.memory().expect("Command issued before memory allocated");
+
+		// Queued and non-queued commands can't be mixed, and non-queued commands are issued one at a time
+		let queue_lock = if queued {
+				QueueLock::Queued(self.queue_mode.read())
+			}
+			else {
+				QueueLock::Exclusive(self.queue_mode.write())
+			};
+		// NCQ tags must be below the device's queue depth
+		let limit = if queued { self.ncq_depth.load(Ordering::Relaxed) } else { max_commands };
+		assert!(limit > 0);
 
 		// 0. Request slot from semaphore
 		self.used_commands_sem.acquire();
-		
+
 		// 1. Load
 		let mut cur_used_commands = self.used_commands.load(Ordering::Relaxed);
 		loop
 		{
 			// 2. Search
-			let mut avail = self.ctrlr.max_commands as usize;
-			for i in 0 .. self.ctrlr.max_commands as usize
-			{
-				if cur_used_commands & 1 << i == 0 {
-					avail = i;
-					break ;
-				}
-			}
-			assert!(avail < self.ctrlr.max_commands as usize);
+			let avail = match (0 .. limit).position(|i| cur_used_commands & 1 << i == 0)
+				{
+				Some(v) => v,
+				None => {
+					// - Only possible if the queue depth is below the number of slots
+					::kernel::threads::yield_time();
+					cur_used_commands = self.used_commands.load(Ordering::Relaxed);
+					continue ;
+					},
+				};
 
 			// 3. Try and commit
 			let try_new_val = cur_used_commands | (1 << avail);
@@ -539,16 +888,18 @@ impl Port
 				// SAFE: Exclusive access
 				let (tab, hdr) = unsafe {
 					(
-						&mut *self.get_cmdtab_ptr(avail),
-						&mut self.command_list_alloc.as_int_mut_slice(0, max_commands)[avail],
+						&mut *mem.get_cmdtab_ptr(avail, max_commands),
+						&mut mem.command_list_alloc.as_int_mut_slice(0, max_commands)[avail],
 						)
 					};
 				return CommandSlot {
 					idx: avail as u8,
+					queued: queued,
 					port: self,
 					data: tab,
 					hdr: hdr,
 					event: &self.command_events[avail],
+					_queue_lock: queue_lock,
 					};
 			}
 
@@ -562,64 +913,119 @@ impl ::core::ops::Drop for Port
 	{
 		*self.volume.lock() = None;
 		//assert!( self.interface_active == false );
+
+		// Stop the port before releasing its memory
+		let p = self.memory.swap(::core::ptr::null_mut(), Ordering::Acquire);
+		if !p.is_null()
+		{
+			let regs = self.regs();
+			// SAFE: Stopping the engine has no memory effects
+			unsafe {
+				let cmd = regs.read(hw::REG_PxCMD);
+				regs.write(hw::REG_PxCMD, cmd & !(hw::PxCMD_ST|hw::PxCMD_FRE));
+			}
+			if !::kernel::time::wait_for(500, 1, || regs.read(hw::REG_PxCMD) & (hw::PxCMD_CR|hw::PxCMD_FR) == 0) {
+				log_error!("{} - Port failed to stop, leaking memory", self);
+				return ;
+			}
+			// SAFE: Pointer was created by `Box::into_raw`, and the hardware is no longer using it
+			unsafe {
+				drop(Box::from_raw(p));
+			}
+		}
+	}
+}
+
+impl PortMemory
+{
+	fn rcvd_fis(&self) -> &hw::RcvdFis
+	{
+		self.command_list_alloc.as_ref::<hw::RcvdFis>( ::kernel::PAGE_SIZE - ::core::mem::size_of::<hw::RcvdFis>() )
+	}
+	fn get_cmdtab_ptr(&self, idx: usize, max_commands: usize) -> *mut hw::CmdTable
+	{
+		// TODO: Does the fact that this returns &-ptr break anything?
+		let r = Port::cmdidx_to_ref(&self.command_list_alloc, max_commands * ::core::mem::size_of::<hw::CmdHeader>(), &self.command_tables,  idx);
+		r as *const _ as *mut _
 	}
 }
 
+enum QueueLock<'a>
+{
+	Queued(rwlock::Read<'a, ()>),
+	Exclusive(rwlock::Write<'a, ()>),
+}
+
 struct CommandSlot<'a> {
 	idx: u8,
+	queued: bool,
 	port: &'a Port,
 	pub data: &'a mut hw::CmdTable,
 	pub hdr: &'a mut hw::CmdHeader,
 	pub event: &'a ::kernel::sync::EventChannel,
+	_queue_lock: QueueLock<'a>,
 }
 impl<'a> CommandSlot<'a>
 {
 	// UNSAFE: Caller must ensure that memory pointed to by the `data` table stays valid until the command is complete
-	pub unsafe fn start(&self)
+	pub unsafe fn start(&self) -> Result<(), Error>
 	{
 		//log_trace!("{} - start(idx={})", self.port, self.idx);
 		let mask = 1 << self.idx as usize;
-		self.port.regs().write(hw::REG_PxSACT, mask);
-		self.port.regs().write(hw::REG_PxCI, mask);
+		let regs = self.port.regs();
+		// Wait for any in-progress error recovery, then issue with the engine lock held (so the interrupt handler sees
+		// a consistent state)
+		let _state_lh = self.port.state_lock.lock();
+		let mut lh = self.port.engine.lock();
+		if !lh.running {
+			return Err(Error::Removed);
+		}
+		lh.slots[self.idx as usize] = SlotState::Active;
+		if self.queued {
+			regs.write(hw::REG_PxSACT, mask);
+		}
+		regs.write(hw::REG_PxCI, mask);
+		Ok( () )
 	}
 
 	/// Wait for a command to complete and returns the number of bytes transferred
 	pub fn wait(&self) -> Result<usize, Error>
 	{
-		self.event.sleep();
-
-		let regs = self.port.regs();
-		let active = regs.read(hw::REG_PxCI);
-		let tfd = regs.read(hw::REG_PxTFD);
+		loop
+		{
+			self.event.sleep();
 
-		let mask = 1 << self.idx;
-		if regs.read(hw::REG_PxSERR) != 0 {
-			Err( Error::Bus )
-		}
-		else if tfd & 0x01 != 0 {
-			// Errored (ATA)
-			if self.hdr.flags & (1 << 5) == 0 {
-				Err( Error::Ata {
-					sts: tfd as u8,
-					err: (tfd >> 8) as u8,
-					} )
-			}
-			// ATAPI error
-			else {
-				let err = (tfd >> 8) as u8;
-				Err( Error::Atapi {
-					sense_key: ::storage_scsi::proto::SenseKey::from(err >> 4),
-					eom: err & 2 != 0,
-					ili: err & 1 != 0,
-					})
-			}
-		}
-		else if active & mask == 0 {
-			// All good
-			Ok( self.hdr.prdbc as usize )
-		}
-		else {
-			panic!("{} - Command {} woken while still active", self.port, self.idx);
+			let state = self.port.engine.lock().slots[self.idx as usize];
+			return match state
+				{
+				SlotState::Idle => {
+					log_error!("{} - Command {} woken while idle", self.port, self.idx);
+					Err(Error::Bus)
+					},
+				// Spurious wakeup
+				SlotState::Active => continue,
+				SlotState::Complete => Ok( self.hdr.prdbc as usize ),
+				SlotState::TaskFileError(tfd) => {
+					// Errored (ATA)
+					if self.hdr.flags & (1 << 5) == 0 {
+						Err( Error::Ata {
+							sts: tfd as u8,
+							err: (tfd >> 8) as u8,
+							} )
+					}
+					// ATAPI error
+					else {
+						let err = (tfd >> 8) as u8;
+						Err( Error::Atapi {
+							sense_key: ::storage_scsi::proto::SenseKey::from(err >> 4),
+							eom: err & 2 != 0,
+							ili: err & 1 != 0,
+							})
+					}
+					},
+				SlotState::BusError => Err( Error::Bus ),
+				SlotState::Removed => Err( Error::Removed ),
+				};
 		}
 	}
 }
@@ -629,13 +1035,33 @@ impl<'a> ::core::ops::Drop for CommandSlot<'a>
 	fn drop(&mut self)
 	{
 		let mask = 1 << self.idx;
-		let regs = self.port.regs();
-		// SAFE: Reading has no effect
-		let cur_active = regs.read(hw::REG_PxCI) /* | regs.read(hw::REG_PxSACT) */;
-		if cur_active & mask != 0 {
-			todo!("CommandSlot::drop - Port {} cmd {} - Still active", self.port.index, self.idx);
+
+		// Restart the port if an error halted it (which also stops any failed commands)
+		self.port.recover();
+
+		let still_active = {
+			let regs = self.port.regs();
+			let mut lh = self.port.engine.lock();
+			let issued = (regs.read(hw::REG_PxCI) | regs.read(hw::REG_PxSACT)) & mask != 0;
+			match lh.slots[self.idx as usize]
+			{
+			SlotState::Active => true,
+			_ if issued => true,
+			_ => {
+				lh.slots[self.idx as usize] = SlotState::Idle;
+				false
+				},
+			}
+			};
+		if still_active
+		{
+			// Dropped without waiting for completion (e.g. a panic), stop the engine so the hardware stops using the memory
+			log_error!("{} - Command {} dropped while still active", self.port, self.idx);
+			self.port.needs_recovery.store(true, Ordering::Release);
+			self.port.recover();
+			self.port.engine.lock().slots[self.idx as usize] = SlotState::Idle;
 		}
-		
+
 		// Release into the pool
 		loop
 		{
@@ -677,6 +1103,15 @@ impl ::storage_ata::volume::Interface for Interface
 		}
 	}
 	fn dma_lba_28(&self, cmd: u8, count: u8 , addr: u32, data: DataPtr) -> Result<usize,::storage_ata::volume::Error> {
+		// Use the 48-bit path for reads/writes, so they can be queued
+		if self.port().ncq_depth.load(Ordering::Relaxed) > 0 && count != 0 {
+			match cmd
+			{
+			ATA_READ_DMA => return self.dma_lba_48(ATA_READ_DMA_EXT, count as u16, addr as u64, data),
+			ATA_WRITE_DMA => return self.dma_lba_48(ATA_WRITE_DMA_EXT, count as u16, addr as u64, data),
+			_ => {},
+			}
+		}
 		match self.port().request_ata_lba28(0, cmd, count, addr, data)
 		{
 		Ok(bc) => Ok( bc / 512 ),
@@ -685,7 +1120,7 @@ impl ::storage_ata::volume::Interface for Interface
 		}
 	}
 	fn dma_lba_48(&self, cmd: u8, count: u16, addr: u64, data: DataPtr) -> Result<usize,::storage_ata::volume::Error> {
-		match self.port().request_ata_lba48(0, cmd, count, addr, data)
+		match self.port().request_dma_lba48(cmd, count, addr, data)
 		{
 		Ok(bc) => Ok( bc / 512 ),
 		Err(Error::Ata{err, ..}) => Err(From::from(err)),
@@ -707,6 +1142,7 @@ impl ::storage_scsi::ScsiInterface for Interface
 		{
 		Ok(_) => Box::new( NullResultWaiter::new(|| Ok( () )) ),
 		Err(Error::Atapi { sense_key: SenseKey::NotReady, .. }) => Box::new(NullResultWaiter::new(|| Err(storage::IoError::NoMedium))),
+		Err(Error::Removed) => Box::new(NullResultWaiter::new(|| Err(storage::IoError::Removed))),
 		Err(_) => Box::new(NullResultWaiter::new(|| Err(storage::IoError::Unknown(""))))
 		}
 	}
@@ -718,6 +1154,7 @@ impl ::storage_scsi::ScsiInterface for Interface
 		{
 		Ok(_) => Box::new( NullResultWaiter::new(|| Ok( () )) ),
 		Err(Error::Atapi { sense_key: SenseKey::NotReady, .. }) => Box::new(NullResultWaiter::new(|| Err(storage::IoError::NoMedium))),
+		Err(Error::Removed) => Box::new(NullResultWaiter::new(|| Err(storage::IoError::Removed))),
 		Err(_) => Box::new(NullResultWaiter::new(|| Err(storage::IoError::Unknown(""))))
 		}
 	}
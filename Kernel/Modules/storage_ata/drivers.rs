@@ -8,6 +8,8 @@ use kernel::device_manager;
 
 struct PciLegacyDriver;	// PCI Legacy ATA (BMDMA, all ports/IRQs legacy)
 struct PciNativeDriver;	// PCI Native Mode ATA (all configured via PCI)
+/// Returned when a controller fails to initialise
+struct NullInstance;
 
 #[allow(non_upper_case_globals)]
 static s_pci_legacy_driver: PciLegacyDriver = PciLegacyDriver;
@@ -44,7 +46,7 @@ impl device_manager::Driver for PciLegacyDriver
 	{
 		let bm_io = bus_dev.bind_io(4);
 		bus_dev.set_attr("bus_master", device_manager::AttrValue::U32(1));
-		Box::new( ::ControllerRoot::new(0x1F0, 0x3F6, 14,  0x170, 0x376, 15,  bm_io) )
+		make_instance( ::ControllerRoot::new(0x1F0, 0x3F6, 14,  0x170, 0x376, 15,  bm_io) )
 	}
 }
 
@@ -76,7 +78,21 @@ impl device_manager::Driver for PciNativeDriver
 		let io_sec = bus_dev.bind_io(2).io_base();
 		let st_sec = bus_dev.bind_io(3).io_base() + 2;
 		let bm_io = bus_dev.bind_io(4);
-		Box::new( ::ControllerRoot::new(io_pri, st_pri, irq,  io_sec, st_sec, irq,  bm_io) )
+		make_instance( ::ControllerRoot::new(io_pri, st_pri, irq,  io_sec, st_sec, irq,  bm_io) )
 	}
 }
 
+fn make_instance(r: Result<::ControllerRoot, ::kernel::memory::virt::MapError>) -> Box<device_manager::DriverInstance+'static>
+{
+	match r
+	{
+	Ok(v) => Box::new(v),
+	Err(e) => {
+		log_error!("ATA controller initialisation failed: {:?}", e);
+		Box::new(NullInstance)
+		},
+	}
+}
+impl device_manager::DriverInstance for NullInstance
+{
+}
@@ -5,13 +5,16 @@
 use kernel::prelude::*;
 use kernel::memory::helpers::{DMABuffer};
 use kernel::async;
+use kernel::async::PrimitiveWaiter;
 use kernel::metadevs::storage;
 use kernel::device_manager::IOBinding;
+use core::cell::Cell;
 
 pub const SECTOR_SIZE: usize = 512;
-//const MAX_DMA_SECTORS: usize = 0x2_0000 / SECTOR_SIZE;	// Limited by sector count (and PRDT entries)
-const MAX_DMA_SECTORS: usize = 0x1F_F000 / SECTOR_SIZE;	// Limited by sector count (and PRDT entries)
-// 512 PDRT entries, assume maximum fragmentation = 512 * 4K max = 2^21 = 2MB per transfer
+/// Largest LBA28 transfer (a count of zero means 256 sectors)
+pub const MAX_SECTORS_28: usize = 256;
+/// Largest LBA48 transfer (the PRDT may limit this further)
+pub const MAX_SECTORS_48: usize = 0xFFFF;
 
 //const HDD_PIO_W28: u8 = 0x30,
 //const HDD_PIO_R28: u8 = 0x20;
@@ -23,6 +26,21 @@ const HDD_DMA_R28: u8 = 0xC8;
 const HDD_DMA_W28: u8 = 0xCA;
 const HDD_DMA_R48: u8 = 0x25;
 const HDD_DMA_W48: u8 = 0x35;
+const HDD_FLUSH28: u8 = 0xE7;
+const HDD_FLUSH48: u8 = 0xEA;
+/// DATA SET MANAGEMENT (always LBA48 DMA, data is a list of ranges)
+const HDD_DSM: u8 = 0x06;
+/// DATA SET MANAGEMENT feature value for TRIM
+const DSM_TRIM: u8 = 0x01;
+
+/// Time allowed for a disk command to complete
+const ATA_TIMEOUT_MS: u64 = 10_000;
+/// Time allowed for an ATAPI command (larger, as the medium may need to spin up)
+const ATAPI_TIMEOUT_MS: u64 = 20_000;
+/// Time allowed for IDENTIFY to return
+const IDENTIFY_TIMEOUT_MS: u64 = 2_000;
+/// Time allowed for a device to leave BSY after a software reset
+const RESET_TIMEOUT_MS: u64 = 5_000;
 
 pub struct DmaController
 {
@@ -55,6 +73,17 @@ struct AtaInterrupt
 	handle: ::kernel::irqs::EventHandle,
 }
 
+/// Register-level description of a command
+#[derive(Copy,Clone)]
+struct AtaCommand
+{
+	command: u8,
+	features: u8,
+	lba: u64,
+	lba48: bool,
+	is_write: bool,
+}
+
 #[repr(C)]
 struct PRDTEnt
 {
@@ -70,6 +99,15 @@ impl_fmt!{
 
 impl DmaController
 {
+	pub fn new(name: String, ata_controllers: [AtaController; 2], dma_base: IOBinding) -> DmaController
+	{
+		DmaController {
+			name: name,
+			ata_controllers: ata_controllers,
+			dma_base: dma_base,
+			}
+	}
+
 	fn borrow_regs(&self, is_secondary: bool) -> DmaRegBorrow {
 		DmaRegBorrow {
 			dma_base: &self.dma_base,
@@ -78,22 +116,77 @@ impl DmaController
 	}
 
 	/// Read ATA DMA
-	pub fn do_dma_rd<'a>(&'a self, blockidx: u64, count: usize, dst: &'a mut [u8], disk: u8) -> storage::AsyncIoResult<'a,usize> {
+	///
+	/// Large (or fragmented) requests are truncated, the result is the number of sectors read
+	pub fn do_dma_rd<'a>(&'a self, blockidx: u64, count: usize, dst: &'a mut [u8], disk: u8, lba48: bool) -> storage::AsyncIoResult<'a,usize> {
 		assert_eq!(dst.len(), count * SECTOR_SIZE);
-		let dst = if count > MAX_DMA_SECTORS { &mut dst[.. MAX_DMA_SECTORS * SECTOR_SIZE] } else { dst };
-		self.do_dma(blockidx, DMABuffer::new_mut(dst, 32), disk, false)
+		let max = if lba48 { MAX_SECTORS_48 } else { MAX_SECTORS_28 };
+		let dst = if count > max { &mut dst[.. max * SECTOR_SIZE] } else { dst };
+		self.do_dma(blockidx, DMABuffer::new_mut(dst, 32), disk, lba48, false)
 	}
 	/// Write ATA DMA
-	pub fn do_dma_wr<'a>(&'a self, blockidx: u64, count: usize, dst: &'a [u8], disk: u8) -> storage::AsyncIoResult<'a,usize> {
+	pub fn do_dma_wr<'a>(&'a self, blockidx: u64, count: usize, dst: &'a [u8], disk: u8, lba48: bool) -> storage::AsyncIoResult<'a,usize> {
 		assert_eq!(dst.len(), count * SECTOR_SIZE);
-		let dst = if count > MAX_DMA_SECTORS { &dst[.. MAX_DMA_SECTORS * SECTOR_SIZE] } else { dst };
-		self.do_dma(blockidx, DMABuffer::new(dst, 32), disk, true)
+		let max = if lba48 { MAX_SECTORS_48 } else { MAX_SECTORS_28 };
+		let dst = if count > max { &dst[.. max * SECTOR_SIZE] } else { dst };
+		self.do_dma(blockidx, DMABuffer::new(dst, 32), disk, lba48, true)
 	}
-	fn do_dma<'a>(&'a self, blockidx: u64, dst: DMABuffer<'a>, disk: u8, is_write: bool) -> storage::AsyncIoResult<'a,usize>
+	fn do_dma<'a>(&'a self, blockidx: u64, dst: DMABuffer<'a>, disk: u8, lba48: bool, is_write: bool) -> storage::AsyncIoResult<'a,usize>
 	{
 		log_trace!("do_dma(blockidx={}, dst={:?}, disk={})", blockidx, dst, disk);
+		let count = dst.len() / SECTOR_SIZE;
+		
+		// - Only use LBA48 if needed
+		let use_48 = blockidx + count as u64 > (1 << 28) || count > MAX_SECTORS_28;
+		if use_48 && !lba48 {
+			log_warning!("do_dma: {}+{} out of range for LBA28 disk {}", blockidx, count, disk);
+			return Box::new(async::NullResultWaiter::new( || Err(storage::IoError::BadAddr) ));
+		}
+		let cmd = AtaCommand {
+			command: match (use_48, is_write)
+				{
+				(false, false) => HDD_DMA_R28,
+				(false, true ) => HDD_DMA_W28,
+				(true , false) => HDD_DMA_R48,
+				(true , true ) => HDD_DMA_W48,
+				},
+			features: 0,
+			lba: blockidx,
+			lba48: use_48,
+			is_write: is_write,
+			};
+		self.do_ata(disk, cmd, Some(dst))
+	}
+
+	/// Flush the disk's write cache
+	pub fn do_flush<'a>(&'a self, disk: u8, lba48: bool) -> storage::AsyncIoResult<'a,usize>
+	{
+		let cmd = AtaCommand {
+			command: if lba48 { HDD_FLUSH48 } else { HDD_FLUSH28 },
+			features: 0,
+			lba: 0,
+			lba48: lba48,
+			is_write: false,
+			};
+		self.do_ata(disk, cmd, None)
+	}
+	/// Send a DATA SET MANAGEMENT TRIM request, `ranges` is a whole number of sectors of range entries
+	pub fn do_trim<'a>(&'a self, disk: u8, ranges: &'a [u8]) -> storage::AsyncIoResult<'a,usize>
+	{
+		assert!(ranges.len() > 0 && ranges.len() % SECTOR_SIZE == 0);
+		let cmd = AtaCommand {
+			command: HDD_DSM,
+			features: DSM_TRIM,
+			lba: 0,
+			lba48: true,
+			is_write: true,
+			};
+		self.do_ata(disk, cmd, Some(DMABuffer::new(ranges, 32)))
+	}
+
+	fn do_ata<'a>(&'a self, disk: u8, cmd: AtaCommand, dst: Option<DMABuffer<'a>>) -> storage::AsyncIoResult<'a,usize>
+	{
 		assert!(disk < 4);
-		assert!(dst.len() <= MAX_DMA_SECTORS * SECTOR_SIZE);
 		
 		let bus = (disk >> 1) & 1;
 		let disk = disk & 1;
@@ -102,7 +195,7 @@ impl DmaController
 		let ctrlr = &self.ata_controllers[bus as usize];
 		let bm_regs = self.borrow_regs(bus == 1);
 		
-		let ub = ctrlr.do_dma(blockidx, dst, disk, is_write, bm_regs);
+		let ub = ctrlr.do_dma(cmd, dst, disk, bm_regs);
 		Box::new(ub)
 	}
 	
@@ -150,12 +243,12 @@ impl<'a> DmaRegBorrow<'a>
 
 impl AtaRegs
 {
-	fn new(ata_base: u16, sts_port: u16) -> AtaRegs
+	fn new(ata_base: u16, sts_port: u16) -> Result<AtaRegs, ::kernel::memory::virt::MapError>
 	{
-		AtaRegs {
+		Ok(AtaRegs {
 			ata_base: ata_base, sts_base: sts_port,
-			prdts: ::kernel::memory::virt::alloc_dma(32, 1, module_path!()).unwrap().into_array(),
-		}
+			prdts: try!(::kernel::memory::virt::alloc_dma(32, 1, module_path!())).into_array(),
+		})
 	}
 	
 	#[allow(dead_code)]
@@ -240,29 +333,73 @@ impl AtaRegs
 		}
 	}
 	
-	fn fill_prdt(&mut self, dma_buffer: &DMABuffer)// -> Result<(), usize>
+	/// Perform a software reset of the channel (used to recover after a command times out)
+	fn soft_reset(&mut self)
+	{
+		// SAFE: Unique access, device control register only affects this channel
+		unsafe {
+			::kernel::arch::x86_io::outb(self.sts_base, 0x04);	// SRST
+			::kernel::time::sleep_ms(1);
+			::kernel::arch::x86_io::outb(self.sts_base, 0x00);
+		}
+		::kernel::time::sleep_ms(2);
+		if !::kernel::time::wait_for(RESET_TIMEOUT_MS, 1, || self.in_sts() & AtaStatusVal::BSY == 0) {
+			log_error!("ATA {:#x}: Channel still busy after reset", self.ata_base);
+		}
+	}
+	
+	/// Fill the PRDT from a buffer, returning the number of bytes covered
+	///
+	/// If the buffer is too fragmented to fit in the table, the transfer is truncated to a whole
+	/// number of sectors (the caller reports the shorter length).
+	fn fill_prdt(&mut self, dma_buffer: &DMABuffer) -> usize
 	{
-		// TODO: Handle running out of entries.
-		// - Should ensure that transfer is aligned to 512 bytes (or to sector size)
+		// Entries can't cross a 64KiB boundary (and a length of zero means 64KiB)
+		fn ent_len(paddr: ::kernel::memory::PAddr, bytes: usize) -> usize {
+			::core::cmp::min(bytes, 0x1_0000 - (paddr & 0xFFFF) as usize)
+		}
+
+		// - Determine how much of the buffer fits
+		let mut limit = 0;
+		{
+			let mut n_ents = 0;
+			'count: for (paddr, bytes) in dma_buffer.phys_ranges()
+			{
+				let mut ofs = 0;
+				while ofs < bytes
+				{
+					if n_ents == self.prdts.len() {
+						break 'count;
+					}
+					let len = ent_len(paddr + ofs as ::kernel::memory::PAddr, bytes - ofs);
+					n_ents += 1;
+					ofs += len;
+					limit += len;
+				}
+			}
+		}
+		if limit < dma_buffer.len() {
+			limit -= limit % SECTOR_SIZE;
+			log_debug!("fill_prdt: Ran out of PRDT entries, truncating to {}/{} bytes", limit, dma_buffer.len());
+		}
+		assert!(limit > 0);
 
 		// Fill PRDT
 		let mut count = 0;
 		{
+			let mut remaining = limit;
 			let mut prdt_ents = self.prdts.iter_mut();
 			for region in dma_buffer.phys_ranges()
 			{
 				let mut paddr = region.0;
-				let mut bytes = region.1;
+				let mut bytes = ::core::cmp::min(region.1, remaining);
+				remaining -= bytes;
 				while bytes > 0
 				{
-					let prd_ent = match prdt_ents.next()
-						{
-						Some(v) => v,
-						None => todo!("Ran out of PRDT ents, return early with truncated read"),
-						};
-					let ent_bytes = if bytes >= 0x1_0000 { 0xFFFF } else { bytes };
+					let prd_ent = prdt_ents.next().expect("PRDT entries exhausted after sizing");
+					let ent_bytes = ent_len(paddr, bytes);
 
-					assert!(paddr <= 0xFFFF_FFFF);
+					assert!(paddr + ent_bytes as ::kernel::memory::PAddr <= 0x1_0000_0000);
 					prd_ent.bytes = ent_bytes as u16;
 					prd_ent.addr = paddr as u32;
 					prd_ent.flags = 0;
@@ -271,60 +408,71 @@ impl AtaRegs
 					paddr += ent_bytes as ::kernel::memory::PAddr;
 					bytes -= ent_bytes;
 				}
+				if remaining == 0 {
+					break;
+				}
 			}
 		}
 		assert!(count > 0);
 		self.prdts[count-1].flags = 0x8000;
-		//Ok( () )
+		limit
 	}
 	
-	fn start_dma(&mut self, disk: u8, blockidx: u64, dma_buffer: &DMABuffer, is_write: bool, bm: &DmaRegBorrow)
+	/// Start a command, returning the number of bytes that will be transferred
+	fn start_cmd(&mut self, disk: u8, cmd: &AtaCommand, dma_buffer: Option<&DMABuffer>, bm: &DmaRegBorrow) -> usize
 	{
-		log_debug!("start_dma(disk={},blockidx={},is_write={},dma_buffer={{len={}}})",
-			disk, blockidx, is_write, dma_buffer.len());
-		let count = dma_buffer.len() / SECTOR_SIZE;
-		
-		self.fill_prdt(dma_buffer);
+		log_debug!("start_cmd(disk={},cmd={:#x},lba={},is_write={},dma_buffer={{len={:?}}})",
+			disk, cmd.command, cmd.lba, cmd.is_write, dma_buffer.map(|b| b.len()));
+		let bytes = match dma_buffer
+			{
+			Some(b) => self.fill_prdt(b),
+			None => 0,
+			};
+		let count = bytes / SECTOR_SIZE;
+		let lba = cmd.lba;
 		
 		// Commence the IO and return a wait handle for the operation
 		// SAFE: Unique access and valid IO accesses
 		unsafe
 		{
-			// - Only use LBA48 if needed
-			if blockidx >= (1 << 28) || count >= 256
+			if cmd.lba48
 			{
 				self.out_8(6, 0x40 | (disk << 4));
+				self.out_8(1, 0);
 				self.out_8(2, (count >> 8) as u8);
-				self.out_8(3, (blockidx >> 24) as u8);
-				self.out_8(4, (blockidx >> 32) as u8);
-				self.out_8(5, (blockidx >> 40) as u8);
+				self.out_8(3, (lba >> 24) as u8);
+				self.out_8(4, (lba >> 32) as u8);
+				self.out_8(5, (lba >> 40) as u8);
 			}
 			else
 			{
-				self.out_8(6, 0xE0 | (disk << 4) | ((blockidx >> 24) & 0x0F) as u8);
+				self.out_8(6, 0xE0 | (disk << 4) | ((lba >> 24) & 0x0F) as u8);
 			}
+			self.out_8(1, cmd.features);
 			self.out_8(2, count as u8);
-			self.out_8(3, (blockidx >>  0) as u8);
-			self.out_8(4, (blockidx >>  8) as u8);
-			self.out_8(5, (blockidx >> 16) as u8);
+			self.out_8(3, (lba >>  0) as u8);
+			self.out_8(4, (lba >>  8) as u8);
+			self.out_8(5, (lba >> 16) as u8);
 			
-			// - Set PRDT
-			bm.out_32(4, ::kernel::memory::virt::get_phys(&self.prdts[0]) as u32);
-			bm.out_8(0, 0x04);	// Reset IRQ
+			if bytes > 0
+			{
+				// - Set PRDT
+				bm.out_32(4, ::kernel::memory::virt::get_phys(&self.prdts[0]) as u32);
+				bm.out_8(0, 0x04);	// Reset IRQ
+			}
 			
-			self.out_8(7,
-				if blockidx >= (1 << 48) {
-					if is_write { HDD_DMA_W48 } else { HDD_DMA_R48 }	// LBA 48
-				} else {
-					if is_write { HDD_DMA_W28 } else { HDD_DMA_R28 }	// LBA 28
-				});
+			self.out_8(7, cmd.command);
 			
 			// Start IO
-			bm.out_8(0, if is_write { 0 } else { 8 } | 1);
+			if bytes > 0
+			{
+				bm.out_8(0, if cmd.is_write { 0 } else { 8 } | 1);
+			}
 		}
+		bytes
 	}
 	
-	fn start_atapi(&mut self, bm: &DmaRegBorrow, disk: u8, is_write: bool, cmd: &[u16], dma_buffer: &DMABuffer)
+	fn start_atapi(&mut self, bm: &DmaRegBorrow, disk: u8, is_write: bool, cmd: &[u16], dma_buffer: &DMABuffer) -> Result<(),storage::IoError>
 	{
 		log_debug!("start_atapi(...,disk={},is_write={},cmd={{len={}}},dma_buffer={{len={}}})",
 			disk, is_write, cmd.len()*2, dma_buffer.len());
@@ -337,7 +485,11 @@ impl AtaRegs
 		//	cmd[5] & 0xFF, cmd[5] >> 8
 		//	);
 		
-		self.fill_prdt(dma_buffer);
+		// The command encodes the transfer length, so it can't be truncated
+		if self.fill_prdt(dma_buffer) != dma_buffer.len() {
+			log_warning!("start_atapi: Buffer too fragmented for PRDT ({} bytes)", dma_buffer.len());
+			return Err(storage::IoError::InvalidParameter);
+		}
 		
 		// Commence the IO and return a wait handle for the operation
 		// SAFE: Locked (unique self) and checked access
@@ -358,45 +510,51 @@ impl AtaRegs
 			self.out_8(5, (dma_buffer.len() >> 8) as u8);
 			// ATAPI PACKET
 			self.out_8(7, 0xA0);
+		}
 
-			// - Send command once IRQ is fired?
-			// TODO: Find a way of avoiding this poll (extra wait state)
-
-			while self.in_sts() & AtaStatusVal::BSY != 0 { }
-			self.atapi_send_cmd(cmd);
+		// - Send command once IRQ is fired?
+		// TODO: Find a way of avoiding this poll (extra wait state)
+		if !::kernel::time::wait_for(ATAPI_TIMEOUT_MS, 1, || self.in_sts() & AtaStatusVal::BSY == 0) {
+			log_warning!("start_atapi: Device stuck busy after PACKET");
+			return Err(storage::IoError::Timeout);
 		}
+		self.atapi_send_cmd(cmd)
 	}
 
-	fn atapi_send_cmd(&mut self, cmd: &[u16])
+	fn atapi_send_cmd(&mut self, cmd: &[u16]) -> Result<(),storage::IoError>
 	{
 		// Command must be 6 words long
 		assert!(cmd.len() == 6);
+		if self.in_sts() & AtaStatusVal::DRQ == 0 {
+			log_warning!("atapi_send_cmd: Device not ready for packet, status={:?}", AtaStatusVal(self.in_sts()));
+			return self.last_result(true).and(Err(storage::IoError::Unknown("ATAPI packet refused")));
+		}
 		// SAFE: Unique self
 		unsafe {
-			assert!(self.in_sts() & AtaStatusVal::DRQ != 0);
-			
 			// Send command
 			for &word in cmd {
 				self.out_16(0, word);
 			}
 		}
+		Ok( () )
 	}
 }
 
 enum WaitState<'dev>
 {
 	Acquire(async::mutex::Waiter<'dev,AtaRegs>),
-	IoActive(async::mutex::HeldMutex<'dev,AtaRegs>, async::event::Waiter<'dev>),
+	IoActive(async::mutex::HeldMutex<'dev,AtaRegs>, CommandWaiter<'dev>),
 	Done(Result<(),storage::IoError>),
 }
 struct AtaWaiter<'dev,'buf>
 {
 	dev: &'dev AtaController,
 	disk: u8,
-	blockidx: u64,
-	is_write: bool,
+	cmd: AtaCommand,
 	dma_regs: DmaRegBorrow<'dev>,
-	dma_buffer: DMABuffer<'buf>,
+	dma_buffer: Option<DMABuffer<'buf>>,
+	/// Number of bytes actually transferred (the PRDT can truncate the request)
+	bytes: usize,
 	state: WaitState<'dev>,
 }
 impl<'a,'b> async::ResultWaiter for AtaWaiter<'a,'b>
@@ -406,8 +564,7 @@ impl<'a,'b> async::ResultWaiter for AtaWaiter<'a,'b>
 	fn get_result(&mut self) -> Option<Self::Result> {
 		match self.state
 		{
-		WaitState::Done(r) => Some(r.map( |()| self.dma_buffer.len() / 512 )),
-		//WaitState::Done(r) => Some(r),
+		WaitState::Done(r) => Some(r.map( |()| self.bytes / SECTOR_SIZE )),
 		_ => None,
 		}
 	}
@@ -442,21 +599,33 @@ impl<'a,'b> async::Waiter for AtaWaiter<'a,'b>
 			// If the Acquire wait completed, switch to IoActive state
 			WaitState::Acquire(ref mut waiter) => {
 				let mut lh = waiter.take_lock();
-				lh.start_dma( self.disk, self.blockidx, &self.dma_buffer, self.is_write, &self.dma_regs );
-				WaitState::IoActive(lh, self.dev.interrupt.handle.get_event().wait())
+				self.bytes = lh.start_cmd( self.disk, &self.cmd, self.dma_buffer.as_ref(), &self.dma_regs );
+				WaitState::IoActive(lh, CommandWaiter::new(self.dev.interrupt.handle.get_event().wait(), ATA_TIMEOUT_MS))
 				},
 			// And if IoActive completes, we're complete
-			WaitState::IoActive(ref mut lh, ref _waiter) => WaitState::Done(
+			WaitState::IoActive(ref mut lh, ref waiter) => {
+				let timed_out = waiter.timed_out();
 				// SAFE: Holding the register lock
 				unsafe {
 					log_trace!("Complete");
 					self.dma_regs.out_8(0, 0);	// Stop transfer
-					let ata_status = AtaStatusVal(lh.in_8(7));
-					let dma_status = DmaStatusVal(self.dma_regs.in_8(2));
-					log_trace!("BM Status = {:?}, ATA Status = {:?}", dma_status, ata_status);
-					lh.last_result(false)	// not ATAPI
 				}
-				),
+				if timed_out {
+					log_warning!("ATA {:#x}: Command {:#x} timed out", lh.ata_base, self.cmd.command);
+					lh.soft_reset();
+					self.dev.clear_irq();
+					WaitState::Done( Err(storage::IoError::Timeout) )
+				}
+				else {
+					// SAFE: Holding the register lock
+					unsafe {
+						let ata_status = AtaStatusVal(lh.in_8(7));
+						let dma_status = DmaStatusVal(self.dma_regs.in_8(2));
+						log_trace!("BM Status = {:?}, ATA Status = {:?}", dma_status, ata_status);
+					}
+					WaitState::Done( lh.last_result(false) )	// not ATAPI
+				}
+				},
 			//
 			WaitState::Done(..) => unreachable!(),
 			};
@@ -527,26 +696,53 @@ impl<'a,'b> async::Waiter for AtapiWaiter<'a,'b>
 			// If the Acquire wait completed, switch to IoActive state
 			WaitState::Acquire(ref mut waiter) => {
 				let mut lh = waiter.take_lock();
-				lh.start_atapi( &self.dma_regs, self.disk, self.is_write, &self.cmd_buffer, &self.dma_buffer );
-				WaitState::IoActive(lh, self.dev.interrupt.handle.get_event().wait())
+				match lh.start_atapi( &self.dma_regs, self.disk, self.is_write, &self.cmd_buffer, &self.dma_buffer )
+				{
+				Ok(_) => {
+					WaitState::IoActive(lh, CommandWaiter::new(self.dev.interrupt.handle.get_event().wait(), ATAPI_TIMEOUT_MS))
+					},
+				Err(e) => {
+					// SAFE: Holding the register lock
+					unsafe { self.dma_regs.out_8(0, 0); }	// Stop transfer
+					if let storage::IoError::Timeout = e {
+						lh.soft_reset();
+						self.dev.clear_irq();
+					}
+					WaitState::Done( Err(e) )
+					},
+				}
 				},
 			// And if IoActive completes, we're complete
-			WaitState::IoActive(ref mut lh, ref _waiter) => {
+			WaitState::IoActive(ref mut lh, ref mut waiter) => {
+				if waiter.timed_out()
+				{
+					log_warning!("ATA {:#x}: ATAPI command timed out", lh.ata_base);
+					// SAFE: Holding the register lock
+					unsafe { self.dma_regs.out_8(0, 0); }	// Stop transfer
+					lh.soft_reset();
+					self.dev.clear_irq();
+					WaitState::Done( Err(storage::IoError::Timeout) )
+				}
 				// If the controller is still busy, keep going
-				if lh.in_sts() & AtaStatusVal::BSY != 0 {
+				else if lh.in_sts() & AtaStatusVal::BSY != 0
+				{
 					log_warning!("Controller still busy when waiter woken");
+					waiter.rearm(self.dev.interrupt.handle.get_event().wait());
 					return false;
 				}
-				// SAFE: Holding the register lock
-				let completion_res = unsafe {
-						//log_trace!("Complete");
-						self.dma_regs.out_8(0, 0);	// Stop transfer
-						let ata_status = AtaStatusVal( lh.in_8(7) );
-						let dma_status = DmaStatusVal(self.dma_regs.in_8(2));
-						log_trace!("BM Status = {:?}, ATA Status = {:?}", dma_status, ata_status);
-						lh.last_result(true)
-					};
-				WaitState::Done( completion_res )
+				else
+				{
+					// SAFE: Holding the register lock
+					let completion_res = unsafe {
+							//log_trace!("Complete");
+							self.dma_regs.out_8(0, 0);	// Stop transfer
+							let ata_status = AtaStatusVal( lh.in_8(7) );
+							let dma_status = DmaStatusVal(self.dma_regs.in_8(2));
+							log_trace!("BM Status = {:?}, ATA Status = {:?}", dma_status, ata_status);
+							lh.last_result(true)
+						};
+					WaitState::Done( completion_res )
+				}
 				},
 			//
 			WaitState::Done(..) => unreachable!(),
@@ -567,27 +763,103 @@ impl<'a,'b> ::core::fmt::Debug for AtapiWaiter<'a,'b> {
 	}
 }
 
+/// Waits for a channel's IRQ, giving up once the command's deadline passes
+struct CommandWaiter<'dev>
+{
+	event: async::event::Waiter<'dev>,
+	deadline: u64,
+	/// Fires at the deadline (only present while bound to a sleep object)
+	timer: Option<::kernel::time::Timer>,
+	/// Set by `poll` once the IRQ has fired
+	irq_seen: Cell<bool>,
+}
+impl<'dev> CommandWaiter<'dev>
+{
+	fn new(event: async::event::Waiter<'dev>, timeout_ms: u64) -> CommandWaiter<'dev>
+	{
+		CommandWaiter {
+			event: event,
+			deadline: ::kernel::time::ticks() + timeout_ms,
+			timer: None,
+			irq_seen: Cell::new(false),
+		}
+	}
+	/// Wait for another IRQ (keeping the original deadline)
+	fn rearm(&mut self, event: async::event::Waiter<'dev>)
+	{
+		self.event = event;
+		self.irq_seen.set(false);
+	}
+	/// Returns true if the wait completed because the deadline passed
+	fn timed_out(&self) -> bool
+	{
+		!self.irq_seen.get()
+	}
+}
+impl<'dev> async::PrimitiveWaiter for CommandWaiter<'dev>
+{
+	fn is_complete(&self) -> bool {
+		self.event.is_complete()
+	}
+	fn poll(&self) -> bool {
+		if self.event.poll() {
+			self.irq_seen.set(true);
+			true
+		}
+		else {
+			::kernel::time::ticks() >= self.deadline
+		}
+	}
+	fn run_completion(&mut self) {
+		self.event.run_completion();
+	}
+	fn bind_signal(&mut self, sleeper: &mut ::kernel::threads::SleepObject) -> bool {
+		if ::kernel::time::ticks() >= self.deadline {
+			false
+		}
+		else {
+			self.timer = Some( ::kernel::time::Timer::new(self.deadline, sleeper) );
+			self.event.bind_signal(sleeper)
+		}
+	}
+	fn unbind_signal(&mut self) {
+		self.timer = None;
+		self.event.unbind_signal();
+	}
+}
+impl<'dev> ::core::fmt::Debug for CommandWaiter<'dev> {
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		write!(f, "CommandWaiter({:?}, deadline={})", self.event, self.deadline)
+	}
+}
+
 impl AtaController
 {
-	pub fn new(ata_base: u16, sts_port: u16, irq: u32) -> AtaController
+	pub fn new(ata_base: u16, sts_port: u16, irq: u32) -> Result<AtaController, ::kernel::memory::virt::MapError>
 	{
-		AtaController {
-			regs: async::Mutex::new( AtaRegs::new(ata_base, sts_port) ),
+		Ok(AtaController {
+			regs: async::Mutex::new( try!(AtaRegs::new(ata_base, sts_port)) ),
 			interrupt: AtaInterrupt {
 				handle: ::kernel::irqs::bind_event(irq),
 				},
-			}
+			})
+	}
+
+	/// Discard any interrupt raised by a failed command
+	fn clear_irq(&self)
+	{
+		self.interrupt.handle.get_event().wait().poll();
 	}
 	
-	fn do_dma<'a,'b>(&'a self, blockidx: u64, dst: DMABuffer<'b>, disk: u8, is_write: bool, dma_regs: DmaRegBorrow<'a>) -> AtaWaiter<'a,'b>
+	fn do_dma<'a,'b>(&'a self, cmd: AtaCommand, dst: Option<DMABuffer<'b>>, disk: u8, dma_regs: DmaRegBorrow<'a>) -> AtaWaiter<'a,'b>
 	{
 		AtaWaiter {
 			dev: self,
 			disk: disk,
-			blockidx: blockidx,
-			is_write: is_write,
+			cmd: cmd,
 			dma_regs: dma_regs,
 			dma_buffer: dst,
+			bytes: 0,
 			state: WaitState::Acquire( self.regs.async_lock() ),
 		}
 	}
@@ -622,7 +894,9 @@ impl AtaController
 	}
 	
 	/// Request an ATA IDENTIFY packet from the device
-	pub fn ata_identify<'a>(&'a self, disk: u8, data: &'a mut ::AtaIdentifyData, class: &'a mut ::AtaClass) -> async::poll::Waiter<'a>
+	///
+	/// Fails if the channel is already in use (IDENTIFY is only sent during probing)
+	pub fn ata_identify<'a>(&'a self, disk: u8, data: &'a mut ::AtaIdentifyData, class: &'a mut ::AtaClass) -> Result<async::poll::Waiter<'a>, storage::IoError>
 	{
 		// - Cast 'data' to a u16 slice
 		// SAFE: AtaIdentifyData should be POD
@@ -649,20 +923,35 @@ impl AtaController
 				*class = ::AtaClass::None;
 				// SAFE: Plain old data
 				*data = unsafe { ::core::mem::zeroed() };
-				async::poll::Waiter::null()
+				Ok( async::poll::Waiter::null() )
+			}
+			else if !::kernel::time::wait_for(IDENTIFY_TIMEOUT_MS, 1, || buslock.in_sts() & AtaStatusVal::BSY == 0)
+			{
+				log_warning!("Disk {} on {:#x} stuck busy, resetting", disk, buslock.ata_base);
+				buslock.soft_reset();
+				*class = ::AtaClass::Invalid;
+				// SAFE: Plain old data
+				*data = unsafe { ::core::mem::zeroed() };
+				Ok( async::poll::Waiter::null() )
 			}
 			else
 			{
-				// Block until BSY clears
-				// TODO: Timeout?
-				while buslock.in_sts() & AtaStatusVal::BSY != 0 { }
+				let deadline = ::kernel::time::ticks() + IDENTIFY_TIMEOUT_MS;
 				
 				// Return a poller
-				async::poll::Waiter::new(move |e| match e
+				Ok(async::poll::Waiter::new(move |e| match e
 					{
 					// Being called as a completion function
 					Some(_event_ptr) => {
-						if buslock.in_sts() & 1 == 1 {
+						if buslock.in_sts() & 9 == 0 {
+							// - Timed out waiting for data
+							log_warning!("ata_identify: Disk {:#x}/{} timed out", buslock.ata_base, disk);
+							buslock.soft_reset();
+							// SAFE: Plain old data
+							*data = unsafe { ::core::mem::zeroed() };
+							*class = ::AtaClass::Invalid;
+						}
+						else if buslock.in_sts() & 1 == 1 {
 							// - Error, clear and return
 							// SAFE: Called holding the lock
 							let (f4, f5) = unsafe { (buslock.in_8(4), buslock.in_8(5)) };
@@ -691,14 +980,15 @@ impl AtaController
 							// Done.
 							true
 						} else {
-							false
+							::kernel::time::ticks() >= deadline
 						}
-					} )
+					} ))
 			}
 		}
 		else
 		{
-			panic!("Sending ATA identify while controller is in use");
+			log_error!("ata_identify: Disk {} - Channel is in use", disk);
+			Err(storage::IoError::Unknown("ATA channel busy"))
 		}
 	}
 }
@@ -30,6 +30,7 @@ struct AtaVolume
 	controller: Arc<io::DmaController>,
 	
 	size: u64,
+	features: AtaFeatures,
 }
 
 struct AtapiVolume
@@ -77,16 +78,36 @@ pub struct AtaIdentifyData
 	pub size_of_rw_multiple: u16,
 	/// LBA 28 sector count (if zero, use 48)
 	pub sector_count_28: u32,
-	_unused6: [u16; 100-62],
+	_unused6: [u16; 63-62],
+	/// [0:2] Multiword DMA modes supported, [8:10] selected
+	pub multiword_dma: u16,
+	_unused6a: [u16; 75-64],
+	/// [0:4] Maximum queue depth - 1 (for NCQ)
+	pub queue_depth: u16,
+	/// Serial ATA capabilities ([8] = Native Command Queuing supported)
+	pub sata_capabilities: u16,
+	_unused6b: [u16; 82-77],
+	/// Command sets supported (see `AtaFeatures`)
+	pub command_sets: [u16; 3],
+	/// Command sets enabled
+	pub command_sets_enabled: [u16; 3],
+	/// [0:6] Ultra DMA modes supported, [8:14] selected (valid if `valid_ext_data` bit 2 is set)
+	pub ultra_dma: u16,
+	_unused6c: [u16; 100-89],
 	/// LBA 48 sector count
 	pub sector_count_48: u64,
-	_unused7: [u16; 2],
+	_unused7: u16,
+	/// Maximum number of 512 byte blocks of DATA SET MANAGEMENT ranges (0 = unspecified)
+	pub dsm_max_blocks: u16,
 	/// [0:3] Physical sector size (in logical sectors
 	pub physical_sector_size: u16,
 	_unused8: [u16; 9],
 	/// Number of words per logical sector
 	pub words_per_logical_sector: u32,
-	_unusedz: [u16; 256-119],
+	_unused9: [u16; 169-118],
+	/// [0] DATA SET MANAGEMENT/TRIM supported
+	pub data_set_management: u16,
+	_unusedz: [u16; 256-170],
 }
 impl Default for AtaIdentifyData {
 	fn default() -> AtaIdentifyData {
@@ -107,13 +128,85 @@ impl ::core::fmt::Debug for AtaIdentifyData {
 		try!(write!(f, " valid_ext_data: {}", self.valid_ext_data));
 		try!(write!(f, " size_of_rw_multiple: {}", self.size_of_rw_multiple));
 		try!(write!(f, " sector_count_28: {:#x}", self.sector_count_28));
+		try!(write!(f, " queue_depth: {}", (self.queue_depth & 0x1F) + 1));
+		try!(write!(f, " sata_capabilities: {:#x}", self.sata_capabilities));
+		try!(write!(f, " command_sets: [{:#x},{:#x},{:#x}]", self.command_sets[0], self.command_sets[1], self.command_sets[2]));
+		try!(write!(f, " command_sets_enabled: [{:#x},{:#x},{:#x}]", self.command_sets_enabled[0], self.command_sets_enabled[1], self.command_sets_enabled[2]));
+		try!(write!(f, " multiword_dma: {:#x}", self.multiword_dma));
+		try!(write!(f, " ultra_dma: {:#x}", self.ultra_dma));
 		try!(write!(f, " sector_count_48: {:#x}", self.sector_count_48));
 		try!(write!(f, " words_per_logical_sector: {}", self.words_per_logical_sector));
+		try!(write!(f, " data_set_management: {:#x}", self.data_set_management));
 		try!(write!(f, "}}"));
 		Ok( () )
 	}
 }
 
+/// Optional features reported by IDENTIFY
+#[derive(Copy,Clone,Default)]
+pub struct AtaFeatures
+{
+	/// 48-bit addressing (and 16-bit sector counts)
+	pub lba48: bool,
+	/// Highest multiword DMA mode supported
+	pub multiword_dma: Option<u8>,
+	/// Highest Ultra DMA mode supported
+	pub ultra_dma: Option<u8>,
+	/// Volatile write cache present
+	pub write_cache: bool,
+	/// FLUSH CACHE (and FLUSH CACHE EXT if `lba48` is set) available
+	pub flush_cache: bool,
+	/// DATA SET MANAGEMENT with the TRIM bit
+	pub trim: bool,
+	/// Maximum number of 512 byte blocks of TRIM ranges in one command
+	pub trim_max_blocks: u16,
+	/// Power management feature set (STANDBY/IDLE timers)
+	pub power_management: bool,
+	/// Advanced power management
+	pub apm: bool,
+}
+impl AtaIdentifyData
+{
+	/// Decode the optional feature words
+	pub fn features(&self) -> AtaFeatures
+	{
+		// Highest set bit in a mode mask
+		fn top_mode(mask: u16) -> Option<u8> {
+			(0 .. 8).rev().find(|&i| mask & (1 << i) != 0).map(|i| i as u8)
+		}
+		// Words 82-84 are only valid if not all-zeros or all-ones, and 83 has [15:14] = 01
+		let w82 = self.command_sets[0];
+		let w83 = self.command_sets[1];
+		let sets_valid = w82 != 0 && w82 != 0xFFFF && w83 & 0xC000 == 0x4000;
+		let sets = |w: u16, bit: u16| sets_valid && w & (1 << bit) != 0;
+
+		AtaFeatures {
+			lba48: sets(w83, 10),
+			multiword_dma: if self.capabilities[0] & (1 << 8) != 0 { top_mode(self.multiword_dma & 0x7) } else { None },
+			ultra_dma: if self.valid_ext_data & (1 << 2) != 0 { top_mode(self.ultra_dma & 0x7F) } else { None },
+			write_cache: sets(w82, 5),
+			flush_cache: sets(w83, 12),
+			trim: self.data_set_management & 1 != 0,
+			trim_max_blocks: self.dsm_max_blocks,
+			power_management: sets(w82, 3),
+			apm: sets(w83, 3),
+		}
+	}
+}
+impl_fmt! {
+	Display(self, f) for AtaFeatures {{
+		try!(write!(f, "{}", if self.lba48 { "LBA48" } else { "LBA28" }));
+		if let Some(m) = self.ultra_dma { try!(write!(f, " UDMA{}", m)); }
+		else if let Some(m) = self.multiword_dma { try!(write!(f, " MWDMA{}", m)); }
+		if self.write_cache { try!(write!(f, " WCache")); }
+		if self.flush_cache { try!(write!(f, " Flush")); }
+		if self.trim { try!(write!(f, " TRIM")); }
+		if self.power_management { try!(write!(f, " PM")); }
+		if self.apm { try!(write!(f, " APM")); }
+		Ok( () )
+	}}
+}
+
 fn init()
 {
 	drivers::register();
@@ -121,15 +214,79 @@ fn init()
 
 impl AtaVolume
 {
-	fn new_boxed(dma_controller: Arc<io::DmaController>, disk: u8, sectors: u64) -> Box<AtaVolume>
+	fn new_boxed(dma_controller: Arc<io::DmaController>, disk: u8, sectors: u64, features: AtaFeatures) -> Box<AtaVolume>
 	{
 		Box::new( AtaVolume {
 			name: format!("{}-{}", dma_controller.name, disk),
 			disk: disk,
 			controller: dma_controller,
 			size: sectors,
+			features: features,
 			} )
 	}
+
+	/// Flush the disk's write cache
+	fn flush(&self) -> Result<(), storage::IoError>
+	{
+		if !(self.features.write_cache && self.features.flush_cache) {
+			return Ok( () );
+		}
+		self.controller.do_flush(self.disk, self.features.lba48).wait().map(|_| ())
+	}
+
+	/// Issue DATA SET MANAGEMENT/TRIM for a range of sectors
+	fn trim(&self, blockidx: u64, count: usize) -> Result<(), storage::IoError>
+	{
+		// Each range entry is a 48-bit LBA and a 16-bit count, 64 entries per 512 byte block
+		const RANGE_MAX: u64 = 0xFFFF;
+		const RANGES_PER_BLOCK: usize = io::SECTOR_SIZE / 8;
+		let mut buf = match ::kernel::memory::virt::alloc_dma(32, 1, "storage_ata")
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_warning!("{}: Unable to allocate TRIM buffer - {:?}", self.name, e);
+				return Err(storage::IoError::Unknown("Out of memory"));
+				},
+			};
+		// A single page of ranges, further limited by what the disk accepts
+		let max_blocks = ::core::cmp::max(1, ::core::cmp::min(::kernel::PAGE_SIZE / io::SECTOR_SIZE, self.features.trim_max_blocks as usize));
+
+		let mut lba = blockidx;
+		let mut remaining = count as u64;
+		while remaining > 0
+		{
+			let n_blocks;
+			{
+				let ents = buf.as_mut_slice::<u64>(0, max_blocks * RANGES_PER_BLOCK);
+				let mut n_ents = 0;
+				while remaining > 0 && n_ents < ents.len()
+				{
+					let n = ::core::cmp::min(remaining, RANGE_MAX);
+					ents[n_ents] = lba | (n << 48);
+					n_ents += 1;
+					lba += n;
+					remaining -= n;
+				}
+				n_blocks = (n_ents + RANGES_PER_BLOCK - 1) / RANGES_PER_BLOCK;
+				// Unused entries in the final block must be zero
+				for e in &mut ents[n_ents .. n_blocks * RANGES_PER_BLOCK] {
+					*e = 0;
+				}
+			}
+			try!( self.controller.do_trim(self.disk, buf.as_slice(0, n_blocks * io::SECTOR_SIZE)).wait() );
+		}
+		Ok( () )
+	}
+}
+impl ::core::ops::Drop for AtaVolume
+{
+	fn drop(&mut self)
+	{
+		// Ensure written data reaches the medium before the volume goes away
+		if let Err(e) = self.flush() {
+			log_notice!("{}: Cache flush failed - {:?}", self.name, e);
+		}
+	}
 }
 
 impl ::kernel::metadevs::storage::PhysicalVolume for AtaVolume
@@ -141,19 +298,26 @@ impl ::kernel::metadevs::storage::PhysicalVolume for AtaVolume
 	fn read<'a>(&'a self, _prio: u8, idx: u64, num: usize, dst: &'a mut [u8]) -> storage::AsyncIoResult<'a,usize>
 	{
 		assert_eq!( dst.len(), num * io::SECTOR_SIZE );
-		self.controller.do_dma_rd(idx, num, dst, self.disk)
+		self.controller.do_dma_rd(idx, num, dst, self.disk, self.features.lba48)
 	}
 	fn write<'a>(&'a self, _prio: u8, idx: u64, num: usize, src: &'a [u8]) -> storage::AsyncIoResult<'a,usize>
 	{
 		assert_eq!( src.len(), num * io::SECTOR_SIZE );
 		let ctrlr = &self.controller;
-		ctrlr.do_dma_wr(idx, num, src, self.disk)
+		ctrlr.do_dma_wr(idx, num, src, self.disk, self.features.lba48)
 	}
 	
-	fn wipe<'a>(&'a self, _blockidx: u64, _count: usize) -> storage::AsyncIoResult<'a,()>
+	fn wipe<'a>(&'a self, blockidx: u64, count: usize) -> storage::AsyncIoResult<'a,()>
 	{
-		// Do nothing, no support for TRIM
-		Box::new(async::NullResultWaiter::new( || Ok( () ) ))
+		if blockidx >= self.size || count as u64 > self.size - blockidx {
+			return Box::new(async::NullResultWaiter::new( || Err(storage::IoError::BadAddr) ));
+		}
+		// Wiping is advisory, so disks without TRIM just keep the data
+		if !self.features.trim || count == 0 {
+			return Box::new(async::NullResultWaiter::new( || Ok( () ) ));
+		}
+		let rv = self.trim(blockidx, count);
+		Box::new(async::NullResultWaiter::new( move || rv ))
 	}
 	
 }
@@ -193,25 +357,25 @@ impl storage_scsi::ScsiInterface for AtapiVolume
 
 impl ControllerRoot
 {
-	fn new(ata_pri: u16, sts_pri: u16, irq_pri: u32,  ata_sec: u16, sts_sec: u16, irq_sec: u32,  bm: device_manager::IOBinding) -> ControllerRoot
+	fn new(ata_pri: u16, sts_pri: u16, irq_pri: u32,  ata_sec: u16, sts_sec: u16, irq_sec: u32,  bm: device_manager::IOBinding) -> Result<ControllerRoot, ::kernel::memory::virt::MapError>
 	{
 		log_debug!("ControllerRoot::new( {:#x}, {:#x}, {},  {:#x}, {:#x}, {},  {:?}",
 			ata_pri, sts_pri, irq_pri,
 			ata_sec, sts_sec, irq_sec,
 			bm
 			);
-		let dma_controller = Arc::new(io::DmaController {
-			name: if ata_pri == 0x1F0 {
-					String::from("ATA")
-				} else {
-					format!("ATA{:x}", ata_pri)
-				},
-			ata_controllers: [
-				io::AtaController::new(ata_pri, sts_pri, irq_pri),
-				io::AtaController::new(ata_sec, sts_sec, irq_sec),
-				],
-			dma_base: bm,
-			});
+		let dma_controller = Arc::new(io::DmaController::new(
+			if ata_pri == 0x1F0 {
+				String::from("ATA")
+			} else {
+				format!("ATA{:x}", ata_pri)
+			},
+			[
+				try!(io::AtaController::new(ata_pri, sts_pri, irq_pri)),
+				try!(io::AtaController::new(ata_sec, sts_sec, irq_sec)),
+			],
+			bm
+			));
 		let mut volumes = Vec::new();
 		
 		// Send IDENTIFY to all disks
@@ -225,18 +389,18 @@ impl ControllerRoot
 			let (mut identify_sec, mut type_sec) = Default::default();
 			
 			// Perform IDENTIFY requests, both controllers in pararllel
-			// TODO: Include a timeout to prevent a misbehaving controller from halting the system.
+			// - Each request times out internally (returning AtaClass::Invalid)
 			{
 				use kernel::async::Waiter;
 				
-				let mut wh_pri = ctrlr_pri.ata_identify(i, &mut identify_pri, &mut type_pri);
-				let mut wh_sec = ctrlr_sec.ata_identify(i, &mut identify_sec, &mut type_sec);
-				//let mut wh_timer = ::kernel::async::timer::Waiter::new(2*1000);
+				// - On failure the class stays as `Invalid`
+				let mut wh_pri = ctrlr_pri.ata_identify(i, &mut identify_pri, &mut type_pri).unwrap_or_else(|e| identify_failed(i, e));
+				let mut wh_sec = ctrlr_sec.ata_identify(i, &mut identify_sec, &mut type_sec).unwrap_or_else(|e| identify_failed(2+i, e));
 				
-				// Loop until timer fires, or both disks have read
-				while /* !wh_timer.is_complete() &&*/ !(wh_pri.is_complete() && wh_sec.is_complete())
+				// Loop until both disks have read
+				while !(wh_pri.is_complete() && wh_sec.is_complete())
 				{
-					::kernel::async::wait_on_list(&mut [&mut wh_pri, &mut wh_sec/*, &mut wh_timer*/], None);
+					::kernel::async::wait_on_list(&mut [&mut wh_pri, &mut wh_sec], None);
 				}
 			}
 			
@@ -256,9 +420,10 @@ impl ControllerRoot
 					log_log!("ATA{}: No disk", disk);
 					},
 				AtaClass::Native => {
-					let sectors = if ident.sector_count_48 == 0 { ident.sector_count_28 as u64 } else { ident.sector_count_48 };
-					log_log!("ATA{}: Hard Disk, {} sectors, {}", disk, sectors, storage::SizePrinter(sectors * io::SECTOR_SIZE as u64));
-					volumes.push( storage::register_pv( AtaVolume::new_boxed(dma_controller.clone(), disk, sectors) ) );
+					let features = ident.features();
+					let sectors = if ident.sector_count_48 == 0 || !features.lba48 { ident.sector_count_28 as u64 } else { ident.sector_count_48 };
+					log_log!("ATA{}: Hard Disk, {} sectors, {} ({})", disk, sectors, storage::SizePrinter(sectors * io::SECTOR_SIZE as u64), features);
+					volumes.push( storage::register_pv( AtaVolume::new_boxed(dma_controller.clone(), disk, sectors, features) ) );
 					},
 				AtaClass::ATAPI => {
 					log_log!("ATA{}: ATAPI", disk);
@@ -276,9 +441,15 @@ impl ControllerRoot
 		}
 		
 		// Return a controller handle, holding on to all handles
-		ControllerRoot { _controller: dma_controller, _volumes: volumes, }
+		Ok(ControllerRoot { _controller: dma_controller, _volumes: volumes, })
 	}
 }
+/// Log a failed IDENTIFY request, returning a waiter that completes immediately
+fn identify_failed<'a>(disk: u8, e: storage::IoError) -> async::poll::Waiter<'a>
+{
+	log_error!("ATA{}: IDENTIFY failed - {:?}", disk, e);
+	async::poll::Waiter::null()
+}
 
 impl device_manager::DriverInstance for ControllerRoot
 {
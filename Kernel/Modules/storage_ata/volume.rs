@@ -28,7 +28,7 @@ const ATA_WRITE_DMA: u8 = 0xCA;
 const ATA_READ_DMA_EXT: u8 = 0x25;
 const ATA_WRITE_DMA_EXT: u8 = 0x35;
 
-pub trait Interface: 'static + Send
+pub trait Interface: 'static + Send + Sync
 {
 	fn name(&self) -> &str;
 
@@ -51,9 +51,10 @@ impl<I: Interface> AtaVolume<I>
 		let ident_data = try!(int.ata_identify());
 
 		let block_size = if ident_data.words_per_logical_sector == 0 { 512 } else { ident_data.words_per_logical_sector as u32 * 2 };
-		let block_count = if ident_data.sector_count_28 == 0 { ident_data.sector_count_48 } else { ident_data.sector_count_28 as u64 };
+		let features = ident_data.features();
+		let block_count = if ident_data.sector_count_48 == 0 || !features.lba48 { ident_data.sector_count_28 as u64 } else { ident_data.sector_count_48 };
 		
-		log_log!("{}: Hard Disk, {} sectors of {}b each, {}", int.name(), block_count, block_size, storage::SizePrinter(block_count * block_size as u64));
+		log_log!("{}: Hard Disk, {} sectors of {}b each, {} ({})", int.name(), block_count, block_size, storage::SizePrinter(block_count * block_size as u64), features);
 				
 		Ok(Box::new(AtaVolume {
 			int: int,
@@ -74,15 +75,15 @@ impl<I: Interface + Send + 'static> storage::PhysicalVolume for AtaVolume<I>
 	{
 		assert_eq!( dst.len(), num * self.block_size as usize );
 		let ret = if idx < (1 << 28) && num < 256 {
-				self.int.dma_lba_28(ATA_READ_DMA, num as u8, idx as u32, DataPtr::Recv(dst))
+				self.int.dma_lba_28(ATA_READ_DMA, num as u8, idx as u32, DataPtr::Recv(dst)).map_err(|e| e.into())
 			}
 			else if idx < (1 << 48) && num < (1 << 16) {
-				self.int.dma_lba_48(ATA_READ_DMA_EXT, num as u16, idx, DataPtr::Recv(dst))
+				self.int.dma_lba_48(ATA_READ_DMA_EXT, num as u16, idx, DataPtr::Recv(dst)).map_err(|e| e.into())
 			}
 			else {
-				panic!("Count/address out of range for ATA");
+				log_warning!("{}: Request {}+{} out of range for ATA", self.int.name(), idx, num);
+				Err(storage::IoError::BadAddr)
 			};
-		let ret = ret.map_err(|e| e.into());
 
 		Box::new( ::kernel::async::NullResultWaiter::new( move || ret ) )
 	}
@@ -90,15 +91,15 @@ impl<I: Interface + Send + 'static> storage::PhysicalVolume for AtaVolume<I>
 	{
 		assert_eq!( src.len(), num * self.block_size as usize );
 		let ret = if idx < (1 << 28) && num < 256 {
-				self.int.dma_lba_28(ATA_WRITE_DMA, num as u8, idx as u32, DataPtr::Send(src))
+				self.int.dma_lba_28(ATA_WRITE_DMA, num as u8, idx as u32, DataPtr::Send(src)).map_err(|e| e.into())
 			}
 			else if idx < (1 << 48) && num < (1 << 16) {
-				self.int.dma_lba_48(ATA_WRITE_DMA_EXT, num as u16, idx, DataPtr::Send(src))
+				self.int.dma_lba_48(ATA_WRITE_DMA_EXT, num as u16, idx, DataPtr::Send(src)).map_err(|e| e.into())
 			}
 			else {
-				panic!("Count/address out of range for ATA");
+				log_warning!("{}: Request {}+{} out of range for ATA", self.int.name(), idx, num);
+				Err(storage::IoError::BadAddr)
 			};
-		let ret = ret.map_err(|e| e.into());
 
 		Box::new( ::kernel::async::NullResultWaiter::new( move || ret ) )
 	}
@@ -0,0 +1,53 @@
+// "Tifflin" Kernel - NVMe Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_nvme/bus_bindings.rs
+//! Bus drivers (e.g. PCI)
+use kernel::prelude::*;
+use kernel::device_manager;
+
+pub static S_PCI_DRIVER: PciDriver = PciDriver;
+
+/// Standard PCI bus binding (Class 1, Subclass 8, IF 2)
+pub struct PciDriver;
+
+impl device_manager::Driver for PciDriver
+{
+	fn name(&self) -> &str {
+		"nvme-pci"
+	}
+	fn bus_type(&self) -> &str {
+		"pci"
+	}
+	fn handles(&self, bus_dev: &::kernel::device_manager::BusDevice) -> u32
+	{
+		let classcode = bus_dev.get_attr("class").unwrap_u32();
+		// [class] [subclass] [IF] [ver]
+		if classcode & 0xFFFFFF00 == 0x01080200 {
+			1	// Handle as weakly as possible (vendor-provided drivers bind higher)
+		}
+		else {
+			0
+		}
+	}
+	fn bind(&self, bus_dev: &mut ::kernel::device_manager::BusDevice) -> Box<::kernel::device_manager::DriverInstance+'static>
+	{
+		bus_dev.set_attr("bus_master", device_manager::AttrValue::U32(1));
+		let irq = bus_dev.get_irq(0);
+		let base = bus_dev.bind_io(0);
+
+		match ::controller::Controller::new(irq, base)
+		{
+		Ok(v) => v,
+		Err(e) => {
+			log_error!("Failed to initialise NVMe controller: {}", e);
+			Box::new(NullInstance)
+			},
+		}
+	}
+}
+
+/// Placeholder instance for a controller that failed to initialise
+struct NullInstance;
+impl device_manager::DriverInstance for NullInstance {
+}
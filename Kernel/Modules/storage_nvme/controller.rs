@@ -0,0 +1,496 @@
+// "Tifflin" Kernel - NVMe Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_nvme/controller.rs
+//! Controller initialisation and command handling
+use kernel::prelude::*;
+use kernel::lib::mem::Arc;
+use kernel::device_manager;
+use kernel::metadevs::storage;
+use kernel::memory::virt::AllocHandle;
+use kernel::async;
+use core::sync::atomic::{AtomicUsize,Ordering};
+use hw;
+use queue::{Queue,CommandSlot,Status};
+
+/// Number of entries in the admin queues
+const ADMIN_QUEUE_SIZE: u16 = 16;
+/// Maximum number of entries in the I/O queues
+const IO_QUEUE_SIZE: u16 = 64;
+/// Memory page size used by the driver (CC.MPS = 0)
+const PAGE_SIZE: usize = 0x1000;
+/// Limit on the number of namespaces probed (NN can be very large)
+const MAX_NAMESPACES: u32 = 64;
+/// Time allowed for an admin command during initialisation
+const ADMIN_TIMEOUT_MS: u64 = 5_000;
+
+static S_NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Controller
+{
+	inner: Arc<ControllerInner>,
+	volumes: Vec<storage::PhysicalVolumeReg>,
+	_irq_handle: ::kernel::irqs::ObjectHandle,
+}
+pub struct ControllerInner
+{
+	pub name: String,
+	io: device_manager::IOBinding,
+	/// Time to wait for the controller to change state (CAP.TO)
+	ready_timeout: u64,
+	admin: Queue,
+	io_queue: Queue,
+
+	/// Maximum size of a single transfer (bytes)
+	pub max_transfer: usize,
+	/// DATA SET MANAGEMENT (deallocate) is supported
+	pub has_dsm: bool,
+	/// A volatile write cache is present (and needs flushing)
+	pub has_write_cache: bool,
+}
+
+/// Information about an active namespace
+struct NamespaceInfo
+{
+	nsid: u32,
+	block_size: usize,
+	block_count: u64,
+}
+
+/// Asynchronous I/O command
+pub struct IoWaiter<'a>
+{
+	slot: CommandSlot<'a>,
+	event: async::event::Waiter<'a>,
+	/// Bounce buffer (used when the caller's buffer isn't suitably aligned)
+	bounce: Option<AllocHandle>,
+	/// Destination for bounced reads
+	read_dst: Option<&'a mut [u8]>,
+	count: usize,
+	result: Option<Result<usize, storage::IoError>>,
+}
+
+impl Controller
+{
+	pub fn new(irq: u32, io: device_manager::IOBinding) -> Result<Box<Controller>, &'static str>
+	{
+		// SAFE: Reading registers has no side-effects
+		let (cap, version) = unsafe { (io.read_32(hw::REG_CAP) as u64 | (io.read_32(hw::REG_CAP + 4) as u64) << 32, io.read_32(hw::REG_VS)) };
+		let max_entries = ((cap & hw::CAP_MQES_MASK) + 1) as usize;
+		let doorbell_stride = 4 << ((cap >> hw::CAP_DSTRD_OFS) & 0xF);
+		let ready_timeout = ::core::cmp::max(1, (cap >> hw::CAP_TO_OFS) & 0xFF) * 500;
+		log_debug!("NVMe v{}.{}: CAP={:#x}", version >> 16, (version >> 8) & 0xFF, cap);
+		if (cap >> hw::CAP_MPSMIN_OFS) & 0xF != 0 {
+			return Err("Controller doesn't support 4KiB pages");
+		}
+		if cap & hw::CAP_CSS_NVM == 0 {
+			return Err("Controller doesn't support the NVM command set");
+		}
+
+		// - Disable the controller before reconfiguring it
+		// SAFE: Exclusive access to the controller
+		unsafe {
+			let cc = io.read_32(hw::REG_CC);
+			if cc & hw::CC_EN != 0 {
+				io.write_32(hw::REG_CC, cc & !hw::CC_EN);
+			}
+		}
+		// SAFE: Read-only register
+		if !::kernel::time::wait_for(ready_timeout, 1, || unsafe { io.read_32(hw::REG_CSTS) } & hw::CSTS_RDY == 0) {
+			return Err("Timeout waiting for controller to disable");
+		}
+
+		let admin = try!(Queue::new(0, ADMIN_QUEUE_SIZE, doorbell_stride, false).map_err(|_| "Unable to allocate admin queue"));
+		let io_queue_size = ::core::cmp::min(IO_QUEUE_SIZE as usize, max_entries) as u16;
+		let io_queue = try!(Queue::new(1, io_queue_size, doorbell_stride, true).map_err(|_| "Unable to allocate I/O queue"));
+
+		// - Configure the admin queues and enable
+		// SAFE: Exclusive access, queue memory is owned by this driver
+		unsafe {
+			io.write_32(hw::REG_AQA, ((ADMIN_QUEUE_SIZE as u32 - 1) << 16) | (ADMIN_QUEUE_SIZE as u32 - 1));
+			io.write_32(hw::REG_ASQ + 0, admin.sq_phys() as u32);
+			io.write_32(hw::REG_ASQ + 4, (admin.sq_phys() >> 32) as u32);
+			io.write_32(hw::REG_ACQ + 0, admin.cq_phys() as u32);
+			io.write_32(hw::REG_ACQ + 4, (admin.cq_phys() >> 32) as u32);
+			// Interrupts stay masked until the handler is bound (admin commands are polled until then)
+			io.write_32(hw::REG_INTMS, 1);
+			io.write_32(hw::REG_CC, hw::CC_EN | hw::CC_CSS_NVM | hw::CC_MPS_4K | hw::CC_IOSQES | hw::CC_IOCQES);
+		}
+		// SAFE: Read-only register
+		if !::kernel::time::wait_for(ready_timeout, 1, || unsafe { io.read_32(hw::REG_CSTS) } & (hw::CSTS_RDY|hw::CSTS_CFS) != 0) {
+			return Err("Timeout waiting for controller to enable");
+		}
+		// SAFE: Read-only register
+		if unsafe { io.read_32(hw::REG_CSTS) } & hw::CSTS_CFS != 0 {
+			return Err("Controller fatal status set after enable");
+		}
+
+		// - Identify the controller
+		let mut ident = try!(::kernel::memory::virt::alloc_dma(64, 1, "storage_nvme").map_err(|_| "Unable to allocate identify buffer"));
+		{
+			let mut cmd = hw::Command::new(hw::ADMIN_IDENTIFY, 0);
+			cmd.prp1 = ::kernel::memory::virt::get_phys(ident.as_ref::<u8>(0)) as u64;
+			cmd.cdw10 = hw::IDENTIFY_CNS_CONTROLLER;
+			try!(admin_polled(&io, &admin, cmd));
+		}
+		let (n_namespaces, max_transfer, has_dsm, has_write_cache) = {
+			let b = ident.as_slice::<u8>(0, PAGE_SIZE);
+			log_log!("NVMe Controller: Model {:?}, Serial {:?}, Firmware {:?}",
+				::kernel::lib::RawString(&b[hw::IDC_MN..][..40]),
+				::kernel::lib::RawString(&b[hw::IDC_SN..][..20]),
+				::kernel::lib::RawString(&b[hw::IDC_FR..][..8])
+				);
+			let mdts = b[hw::IDC_MDTS];
+			// - The PRP list is a single page, so limit transfers to what it can describe
+			let max_prp = (PAGE_SIZE / 8) * PAGE_SIZE;
+			let max_transfer = if mdts == 0 || mdts >= 20 { max_prp } else { ::core::cmp::min(max_prp, PAGE_SIZE << mdts) };
+			let nn = b[hw::IDC_NN] as u32 | (b[hw::IDC_NN+1] as u32) << 8 | (b[hw::IDC_NN+2] as u32) << 16 | (b[hw::IDC_NN+3] as u32) << 24;
+			let oncs = b[hw::IDC_ONCS] as u16 | (b[hw::IDC_ONCS+1] as u16) << 8;
+			(nn, max_transfer, oncs & hw::ONCS_DSM != 0, b[hw::IDC_VWC] & hw::VWC_PRESENT != 0)
+			};
+
+		// - Create the I/O queue pair
+		{
+			let mut cmd = hw::Command::new(hw::ADMIN_SET_FEATURES, 0);
+			cmd.cdw10 = hw::FEATURE_NUM_QUEUES;
+			cmd.cdw11 = 0;	// One submission and one completion queue (0-based)
+			try!(admin_polled(&io, &admin, cmd));
+
+			let mut cmd = hw::Command::new(hw::ADMIN_CREATE_CQ, 0);
+			cmd.prp1 = io_queue.cq_phys();
+			cmd.cdw10 = ((io_queue_size as u32 - 1) << 16) | 1;
+			cmd.cdw11 = hw::CQ_IRQ_ENABLE | hw::QUEUE_PHYS_CONTIG;	// Vector 0
+			try!(admin_polled(&io, &admin, cmd));
+
+			let mut cmd = hw::Command::new(hw::ADMIN_CREATE_SQ, 0);
+			cmd.prp1 = io_queue.sq_phys();
+			cmd.cdw10 = ((io_queue_size as u32 - 1) << 16) | 1;
+			cmd.cdw11 = (1 << 16) | hw::QUEUE_PHYS_CONTIG;	// Completions to CQ 1
+			try!(admin_polled(&io, &admin, cmd));
+		}
+
+		// - Enumerate namespaces
+		let mut namespaces = Vec::new();
+		if n_namespaces > MAX_NAMESPACES {
+			log_notice!("NVMe: Controller reports {} namespaces, only probing {}", n_namespaces, MAX_NAMESPACES);
+		}
+		for nsid in 1 .. ::core::cmp::min(n_namespaces, MAX_NAMESPACES) + 1
+		{
+			let mut cmd = hw::Command::new(hw::ADMIN_IDENTIFY, nsid);
+			cmd.prp1 = ::kernel::memory::virt::get_phys(ident.as_ref::<u8>(0)) as u64;
+			cmd.cdw10 = hw::IDENTIFY_CNS_NAMESPACE;
+			for v in ident.as_mut_slice::<u8>(0, PAGE_SIZE).iter_mut() { *v = 0; }
+			if let Err(e) = admin_polled(&io, &admin, cmd) {
+				log_notice!("NVMe: Identify namespace {} failed - {}", nsid, e);
+				continue ;
+			}
+			let b = ident.as_slice::<u8>(0, PAGE_SIZE);
+			let size = (0 .. 8).fold(0u64, |v, i| v | (b[hw::IDN_NSZE + i] as u64) << (8 * i));
+			if size == 0 {
+				// Inactive namespace
+				continue ;
+			}
+			let fmt_idx = (b[hw::IDN_FLBAS] & 0xF) as usize;
+			let lbads = b[hw::IDN_LBAF + fmt_idx * 4 + 2];
+			if lbads < 9 || lbads > 16 {
+				log_warning!("NVMe: Namespace {} has unsupported block size 2^{}", nsid, lbads);
+				continue ;
+			}
+			namespaces.push(NamespaceInfo {
+				nsid: nsid,
+				block_size: 1 << lbads,
+				block_count: size,
+				});
+		}
+
+		// - Construct the shared state and enable interrupts
+		let inner = Arc::new(ControllerInner {
+			name: format!("nvme{}", S_NEXT_INDEX.fetch_add(1, Ordering::Relaxed)),
+			io: io,
+			ready_timeout: ready_timeout,
+			admin: admin,
+			io_queue: io_queue,
+			max_transfer: max_transfer,
+			has_dsm: has_dsm,
+			has_write_cache: has_write_cache,
+			});
+		let irq_handle = {
+			let inner = inner.clone();
+			::kernel::irqs::bind_object(irq, Box::new(move || inner.handle_irq()))
+			};
+		// SAFE: Unmasking the (only) interrupt vector
+		unsafe { inner.io.write_32(hw::REG_INTMC, 1); }
+
+		// - Register volumes
+		let mut volumes = Vec::new();
+		for ns in namespaces
+		{
+			let vol = ::volume::Volume::new_boxed(inner.clone(), ns.nsid, ns.block_size, ns.block_count);
+			volumes.push( storage::register_pv(vol) );
+		}
+		log_log!("{}: {} namespace(s), max transfer {}, dsm={}, write cache={}",
+			inner.name, volumes.len(), storage::SizePrinter(max_transfer as u64), has_dsm, has_write_cache);
+
+		Ok(Box::new(Controller {
+			inner: inner,
+			volumes: volumes,
+			_irq_handle: irq_handle,
+			}))
+	}
+}
+impl device_manager::DriverInstance for Controller
+{
+}
+impl ::core::ops::Drop for Controller
+{
+	fn drop(&mut self)
+	{
+		// Volumes flush their caches as they go
+		self.volumes.clear();
+
+		// Request a normal shutdown
+		let io = &self.inner.io;
+		// SAFE: Exclusive access to CC
+		unsafe {
+			let cc = io.read_32(hw::REG_CC);
+			io.write_32(hw::REG_CC, (cc & !hw::CC_SHN_MASK) | hw::CC_SHN_NORMAL);
+		}
+		// SAFE: Read-only register
+		if !::kernel::time::wait_for(self.inner.ready_timeout, 1, || unsafe { io.read_32(hw::REG_CSTS) } & hw::CSTS_SHST_MASK == hw::CSTS_SHST_DONE) {
+			log_warning!("{}: Timeout waiting for shutdown", self.inner.name);
+		}
+	}
+}
+
+/// Send an admin command, polling for completion (used before the interrupt is bound)
+fn admin_polled(io: &device_manager::IOBinding, admin: &Queue, cmd: hw::Command) -> Result<u32, &'static str>
+{
+	let opcode = cmd.cdw0 as u8;
+	let mut slot = admin.alloc_slot();
+	slot.submit(io, cmd);
+	match slot.poll_wait(io, ADMIN_TIMEOUT_MS)
+	{
+	Some(Ok(v)) => Ok(v),
+	Some(Err(s)) => {
+		log_warning!("NVMe: Admin command {:#x} failed - {:?}", opcode, s);
+		Err("Admin command failed")
+		},
+	None => {
+		log_warning!("NVMe: Admin command {:#x} timed out", opcode);
+		Err("Admin command timed out")
+		},
+	}
+}
+
+/// Convert a command status into a storage error
+fn status_to_error(s: Status) -> storage::IoError
+{
+	match (s.sct(), s.sc())
+	{
+	// Generic command status
+	(0, 0x02) => storage::IoError::InvalidParameter,	// Invalid Field in Command
+	(0, 0x0B) => storage::IoError::BadAddr,	// Invalid Namespace or Format
+	(0, 0x20) => storage::IoError::ReadOnly,	// Namespace is Write Protected
+	(0, 0x80) => storage::IoError::BadAddr,	// LBA Out of Range
+	(0, 0x81) => storage::IoError::BadAddr,	// Capacity Exceeded
+	(0, 0x82) => storage::IoError::NoMedium,	// Namespace Not Ready
+	// Media and data integrity errors
+	(2, 0x80) => storage::IoError::BadBlock,	// Write Fault
+	(2, 0x81) => storage::IoError::BadBlock,	// Unrecovered Read Error
+	(2, 0x86) => storage::IoError::ReadOnly,	// Access Denied
+	_ => storage::IoError::Unknown("NVMe error"),
+	}
+}
+
+impl ControllerInner
+{
+	fn handle_irq(&self) -> bool
+	{
+		// Both queues use vector 0 (using `|` so both are always checked)
+		self.admin.handle_irq(&self.io) | self.io_queue.handle_irq(&self.io)
+	}
+
+	/// Start a read or write, returning the number of blocks that will be transferred
+	///
+	/// Requests larger than the maximum transfer size are truncated.
+	pub fn start_rw<'a>(&'a self, nsid: u32, lba: u64, block_size: usize, data: storage::DataPtr<'a>) -> Result<IoWaiter<'a>, storage::IoError>
+	{
+		let count = ::core::cmp::min( ::core::cmp::min(data.len(), self.max_transfer) / block_size, 0x1_0000 );
+		assert!(count > 0);
+		let len = count * block_size;
+		let mut cmd = hw::Command::new(if data.is_send() { hw::NVM_WRITE } else { hw::NVM_READ }, nsid);
+		cmd.cdw10 = lba as u32;
+		cmd.cdw11 = (lba >> 32) as u32;
+		cmd.cdw12 = (count - 1) as u32;	// 0-based
+
+		// PRP entries must be dword aligned
+		let (bounce, read_dst, ptr) = if data.as_slice().as_ptr() as usize % 4 != 0 {
+				let pages = (len + PAGE_SIZE - 1) / PAGE_SIZE;
+				let mut h = match ::kernel::memory::virt::alloc_dma(64, pages, "storage_nvme")
+					{
+					Ok(v) => v,
+					Err(e) => {
+						log_warning!("{}: Unable to allocate {} byte bounce buffer - {:?}", self.name, len, e);
+						return Err(storage::IoError::Unknown("Out of memory"));
+						},
+					};
+				let read_dst = match data
+					{
+					storage::DataPtr::Send(src) => { h.as_mut_slice::<u8>(0, len).copy_from_slice(&src[..len]); None },
+					storage::DataPtr::Recv(dst) => Some(&mut dst[..len]),
+					};
+				let ptr = h.as_ref::<u8>(0) as *const u8;
+				(Some(h), read_dst, ptr)
+			}
+			else {
+				let ptr = data.as_slice().as_ptr();
+				(None, None, ptr)
+			};
+
+		let mut slot = self.io_queue.alloc_slot();
+		fill_prps(&mut slot, &mut cmd, ptr, len);
+		slot.submit(&self.io, cmd);
+		Ok(IoWaiter {
+			event: slot.get_event(),
+			slot: slot,
+			bounce: bounce,
+			read_dst: read_dst,
+			count: count,
+			result: None,
+			})
+	}
+
+	/// Deallocate (TRIM) a range of blocks
+	pub fn deallocate(&self, nsid: u32, lba: u64, count: usize) -> Result<(), storage::IoError>
+	{
+		const MAX_RANGE: u64 = 0xFFFF_FFFF;
+		let mut buf = match ::kernel::memory::virt::alloc_dma(64, 1, "storage_nvme")
+			{
+			Ok(v) => v,
+			Err(_) => return Err(storage::IoError::Unknown("Out of memory")),
+			};
+		let mut lba = lba;
+		let mut remaining = count as u64;
+		while remaining > 0
+		{
+			let mut n_ranges = 0;
+			{
+				let ranges = buf.as_mut_slice::<hw::DsmRange>(0, hw::DSM_MAX_RANGES);
+				while remaining > 0 && n_ranges < ranges.len()
+				{
+					let n = ::core::cmp::min(remaining, MAX_RANGE);
+					ranges[n_ranges] = hw::DsmRange { attributes: 0, block_count: n as u32, start_lba: lba };
+					n_ranges += 1;
+					lba += n;
+					remaining -= n;
+				}
+			}
+			let mut cmd = hw::Command::new(hw::NVM_DSM, nsid);
+			cmd.prp1 = ::kernel::memory::virt::get_phys(buf.as_ref::<u8>(0)) as u64;
+			cmd.cdw10 = (n_ranges - 1) as u32;	// 0-based
+			cmd.cdw11 = hw::DSM_AD;
+			try!(self.io_cmd(cmd));
+		}
+		Ok( () )
+	}
+
+	/// Flush the volatile write cache for a namespace
+	pub fn flush(&self, nsid: u32) -> Result<(), storage::IoError>
+	{
+		if !self.has_write_cache {
+			return Ok( () );
+		}
+		self.io_cmd(hw::Command::new(hw::NVM_FLUSH, nsid))
+	}
+
+	/// Issue a data-less (or pre-filled) command on the I/O queue and wait for it
+	fn io_cmd(&self, cmd: hw::Command) -> Result<(), storage::IoError>
+	{
+		let opcode = cmd.cdw0 as u8;
+		let mut slot = self.io_queue.alloc_slot();
+		slot.submit(&self.io, cmd);
+		match slot.wait()
+		{
+		Ok(_) => Ok( () ),
+		Err(s) => {
+			log_warning!("{}: Command {:#x} failed - {:?}", self.name, opcode, s);
+			Err(status_to_error(s))
+			},
+		}
+	}
+}
+
+/// Fill the PRP entries for a (dword aligned) buffer
+fn fill_prps(slot: &mut CommandSlot, cmd: &mut hw::Command, ptr: *const u8, len: usize)
+{
+	let page_phys = |ofs: usize| ::kernel::memory::virt::get_phys( (ptr as usize + ofs) as *const u8 ) as u64;
+	let first = page_phys(0);
+	let first_len = PAGE_SIZE - (first as usize % PAGE_SIZE);
+	cmd.prp1 = first;
+	if len <= first_len {
+		cmd.prp2 = 0;
+	}
+	else if len <= first_len + PAGE_SIZE {
+		cmd.prp2 = page_phys(first_len);
+	}
+	else {
+		{
+			let list = slot.prp_list();
+			let mut ofs = first_len;
+			let mut i = 0;
+			while ofs < len
+			{
+				list[i] = page_phys(ofs);
+				ofs += PAGE_SIZE;
+				i += 1;
+			}
+		}
+		cmd.prp2 = slot.prp_list_phys();
+	}
+}
+
+impl<'a> async::Waiter for IoWaiter<'a>
+{
+	fn is_complete(&self) -> bool {
+		self.result.is_some()
+	}
+	fn get_waiter(&mut self) -> &mut async::PrimitiveWaiter {
+		&mut self.event
+	}
+	fn complete(&mut self) -> bool
+	{
+		self.result = match self.slot.result()
+			{
+			// Woken by a stale event, wait again
+			None => {
+				self.event = self.slot.get_event();
+				return false;
+				},
+			Some(Ok(_)) => {
+				if let Some(ref mut dst) = self.read_dst {
+					let h = self.bounce.as_ref().expect("Bounced read without buffer");
+					dst.copy_from_slice( h.as_slice::<u8>(0, dst.len()) );
+				}
+				Some(Ok(self.count))
+				},
+			Some(Err(s)) => {
+				log_warning!("NVMe: I/O failed - {:?}", s);
+				Some(Err(status_to_error(s)))
+				},
+			};
+		true
+	}
+}
+impl<'a> async::ResultWaiter for IoWaiter<'a>
+{
+	type Result = Result<usize, storage::IoError>;
+	fn get_result(&mut self) -> Option<Self::Result> {
+		self.result
+	}
+	fn as_waiter(&mut self) -> &mut async::Waiter { self }
+}
+impl<'a> ::core::fmt::Debug for IoWaiter<'a> {
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		write!(f, "IoWaiter({} blocks, {})", self.count, if self.result.is_some() { "Done" } else { "Active" })
+	}
+}
@@ -0,0 +1,141 @@
+// "Tifflin" Kernel - NVMe Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_nvme/hw.rs
+//! Hardware definitions (registers, commands and data structures)
+#![allow(dead_code)]
+
+pub const REG_CAP  : usize = 0x00;	// Controller Capabilities (64-bit)
+pub const REG_VS   : usize = 0x08;	// Version
+pub const REG_INTMS: usize = 0x0C;	// Interrupt Mask Set
+pub const REG_INTMC: usize = 0x10;	// Interrupt Mask Clear
+pub const REG_CC   : usize = 0x14;	// Controller Configuration
+pub const REG_CSTS : usize = 0x1C;	// Controller Status
+pub const REG_AQA  : usize = 0x24;	// Admin Queue Attributes
+pub const REG_ASQ  : usize = 0x28;	// Admin Submission Queue base (64-bit)
+pub const REG_ACQ  : usize = 0x30;	// Admin Completion Queue base (64-bit)
+pub const REG_DOORBELL_BASE: usize = 0x1000;
+
+// CAP fields
+pub const CAP_MQES_MASK: u64 = 0xFFFF;	// Maximum queue entries supported (0-based)
+pub const CAP_CQR: u64 = (1 << 16);	// Contiguous queues required
+pub const CAP_TO_OFS: usize = 24;	// Ready timeout (500ms units)
+pub const CAP_DSTRD_OFS: usize = 32;	// Doorbell stride (4 << DSTRD bytes)
+pub const CAP_CSS_NVM: u64 = (1 << 37);	// NVM command set supported
+pub const CAP_MPSMIN_OFS: usize = 48;	// Minimum memory page size (4096 << MPSMIN)
+
+// CC fields
+pub const CC_EN: u32 = (1 << 0);
+pub const CC_CSS_NVM: u32 = (0 << 4);
+pub const CC_MPS_4K: u32 = (0 << 7);
+pub const CC_SHN_NORMAL: u32 = (1 << 14);
+pub const CC_SHN_MASK: u32 = (3 << 14);
+pub const CC_IOSQES: u32 = (6 << 16);	// 64 byte submission entries
+pub const CC_IOCQES: u32 = (4 << 20);	// 16 byte completion entries
+
+// CSTS fields
+pub const CSTS_RDY: u32 = (1 << 0);
+pub const CSTS_CFS: u32 = (1 << 1);	// Controller Fatal Status
+pub const CSTS_SHST_MASK: u32 = (3 << 2);
+pub const CSTS_SHST_DONE: u32 = (2 << 2);
+
+// Admin command set
+pub const ADMIN_DELETE_SQ: u8 = 0x00;
+pub const ADMIN_CREATE_SQ: u8 = 0x01;
+pub const ADMIN_DELETE_CQ: u8 = 0x04;
+pub const ADMIN_CREATE_CQ: u8 = 0x05;
+pub const ADMIN_IDENTIFY: u8 = 0x06;
+pub const ADMIN_SET_FEATURES: u8 = 0x09;
+
+pub const IDENTIFY_CNS_NAMESPACE: u32 = 0x00;
+pub const IDENTIFY_CNS_CONTROLLER: u32 = 0x01;
+
+pub const FEATURE_NUM_QUEUES: u32 = 0x07;
+
+// Queue creation flags (CDW11)
+pub const QUEUE_PHYS_CONTIG: u32 = (1 << 0);
+pub const CQ_IRQ_ENABLE: u32 = (1 << 1);
+
+// NVM command set
+pub const NVM_FLUSH: u8 = 0x00;
+pub const NVM_WRITE: u8 = 0x01;
+pub const NVM_READ: u8 = 0x02;
+pub const NVM_DSM: u8 = 0x09;
+
+/// DATA SET MANAGEMENT "Attribute - Deallocate"
+pub const DSM_AD: u32 = (1 << 2);
+/// Maximum number of ranges in one DSM command
+pub const DSM_MAX_RANGES: usize = 256;
+
+// Identify Controller fields (byte offsets)
+pub const IDC_SN: usize = 4;
+pub const IDC_MN: usize = 24;
+pub const IDC_FR: usize = 64;
+pub const IDC_MDTS: usize = 77;	// Maximum data transfer size (2^n minimum pages, 0 = unlimited)
+pub const IDC_NN: usize = 516;	// Number of namespaces
+pub const IDC_ONCS: usize = 520;	// Optional NVM command support
+pub const IDC_VWC: usize = 525;	// Volatile write cache
+
+pub const ONCS_DSM: u16 = (1 << 2);
+pub const VWC_PRESENT: u8 = (1 << 0);
+
+// Identify Namespace fields (byte offsets)
+pub const IDN_NSZE: usize = 0;	// Namespace size (blocks)
+pub const IDN_NLBAF: usize = 25;	// Number of LBA formats (0-based)
+pub const IDN_FLBAS: usize = 26;	// Formatted LBA size ([3:0] = format index)
+pub const IDN_LBAF: usize = 128;	// LBA format table (32-bit entries, [23:16] = log2 block size)
+
+/// Submission queue entry
+#[repr(C)]
+#[derive(Default)]
+pub struct Command
+{
+	/// [7:0] Opcode, [31:16] Command ID
+	pub cdw0: u32,
+	pub nsid: u32,
+	_rsvd: u64,
+	pub mptr: u64,
+	pub prp1: u64,
+	pub prp2: u64,
+	pub cdw10: u32,
+	pub cdw11: u32,
+	pub cdw12: u32,
+	pub cdw13: u32,
+	pub cdw14: u32,
+	pub cdw15: u32,
+}
+impl Command
+{
+	pub fn new(opcode: u8, nsid: u32) -> Command
+	{
+		Command {
+			cdw0: opcode as u32,
+			nsid: nsid,
+			.. Default::default()
+		}
+	}
+}
+
+/// Completion queue entry
+#[repr(C)]
+pub struct Completion
+{
+	/// Command-specific result
+	pub dw0: u32,
+	_dw1: u32,
+	pub sq_head: u16,
+	pub sq_id: u16,
+	pub cid: u16,
+	/// [0] Phase tag, [8:1] Status code, [11:9] Status code type, [15] Do not retry
+	pub status: u16,
+}
+
+/// DATA SET MANAGEMENT range entry
+#[repr(C)]
+pub struct DsmRange
+{
+	pub attributes: u32,
+	/// Number of blocks (not 0-based)
+	pub block_count: u32,
+	pub start_lba: u64,
+}
@@ -0,0 +1,24 @@
+// "Tifflin" Kernel - NVMe Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_nvme/lib.rs
+//! NVM Express (PCIe SSD) Driver
+#![feature(linkage)]
+#![no_std]
+
+#[macro_use]
+extern crate kernel;
+
+module_define!{NVMe, [DeviceManager, Storage], init}
+
+mod bus_bindings;
+mod hw;
+
+mod controller;
+mod queue;
+mod volume;
+
+fn init()
+{
+	::kernel::device_manager::register_driver(&bus_bindings::S_PCI_DRIVER);
+}
@@ -0,0 +1,284 @@
+// "Tifflin" Kernel - NVMe Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_nvme/queue.rs
+//! Submission/completion queue pairs
+use kernel::prelude::*;
+use kernel::async;
+use kernel::sync::{Spinlock,Semaphore};
+use kernel::memory::virt::AllocHandle;
+use kernel::device_manager::IOBinding;
+use core::sync::atomic::{AtomicUsize,Ordering};
+use hw;
+
+/// Maximum number of outstanding commands on one queue (size of the free slot bitmask)
+const MAX_SLOTS: usize = 64;
+/// Value of `Slot::status` while the command is outstanding
+const STATUS_PENDING: usize = !0;
+
+/// A submission queue and its (dedicated) completion queue
+pub struct Queue
+{
+	qid: u16,
+	size: u16,
+	sq_doorbell: usize,
+	cq_doorbell: usize,
+	sq: AllocHandle,
+	cq: AllocHandle,
+	state: Spinlock<QueueState>,
+
+	slots: Vec<Slot>,
+	/// Bitmask of free entries in `slots`
+	free_slots: Spinlock<u64>,
+	free_sem: Semaphore,
+}
+struct QueueState
+{
+	sq_tail: u16,
+	cq_head: u16,
+	/// Expected phase tag of the next new completion entry
+	phase: bool,
+}
+struct Slot
+{
+	event: async::event::Source,
+	/// Completion status (`STATUS_PENDING` until complete)
+	status: AtomicUsize,
+	/// Command-specific result (completion DW0)
+	result: AtomicUsize,
+	/// Page used for PRP lists (I/O queues only)
+	prp_list: Option<AllocHandle>,
+}
+
+/// An allocated command slot
+///
+/// Freed on drop, after waiting for the command to complete if it was submitted.
+pub struct CommandSlot<'a>
+{
+	queue: &'a Queue,
+	idx: usize,
+	submitted: bool,
+}
+
+/// Error status from a completed command
+#[derive(Copy,Clone)]
+pub struct Status(pub u16);
+impl Status
+{
+	/// Status Code Type
+	pub fn sct(&self) -> u8 { ((self.0 >> 8) & 7) as u8 }
+	/// Status Code
+	pub fn sc(&self) -> u8 { self.0 as u8 }
+}
+impl_fmt! {
+	Debug(self, f) for Status {
+		write!(f, "Status(SCT={},SC={:#x})", self.sct(), self.sc())
+	}
+}
+
+impl Queue
+{
+	/// Allocate memory for a queue pair (the controller is told about it separately)
+	pub fn new(qid: u16, size: u16, doorbell_stride: usize, with_prp_lists: bool) -> Result<Queue, ::kernel::memory::virt::MapError>
+	{
+		assert!(size >= 2);
+		let n_slots = ::core::cmp::min(MAX_SLOTS, size as usize - 1);
+		let pages = |bytes: usize| (bytes + ::kernel::PAGE_SIZE - 1) / ::kernel::PAGE_SIZE;
+
+		let sq = try!(::kernel::memory::virt::alloc_dma(64, pages(size as usize * 64), "storage_nvme"));
+		let cq = try!(::kernel::memory::virt::alloc_dma(64, pages(size as usize * 16), "storage_nvme"));
+		let mut slots = Vec::with_capacity(n_slots);
+		for _ in 0 .. n_slots
+		{
+			slots.push(Slot {
+				event: async::event::Source::new(),
+				status: AtomicUsize::new(0),
+				result: AtomicUsize::new(0),
+				prp_list: if with_prp_lists { Some(try!(::kernel::memory::virt::alloc_dma(64, 1, "storage_nvme"))) } else { None },
+				});
+		}
+		Ok(Queue {
+			qid: qid,
+			size: size,
+			sq_doorbell: hw::REG_DOORBELL_BASE + (2 * qid as usize + 0) * doorbell_stride,
+			cq_doorbell: hw::REG_DOORBELL_BASE + (2 * qid as usize + 1) * doorbell_stride,
+			sq: sq,
+			cq: cq,
+			state: Spinlock::new(QueueState { sq_tail: 0, cq_head: 0, phase: true }),
+			slots: slots,
+			free_slots: Spinlock::new( if n_slots == 64 { !0 } else { (1 << n_slots) - 1 } ),
+			free_sem: Semaphore::new(n_slots as isize, n_slots as isize),
+			})
+	}
+
+	pub fn size(&self) -> u16 {
+		self.size
+	}
+	pub fn sq_phys(&self) -> u64 {
+		::kernel::memory::virt::get_phys(self.sq.as_ref::<u8>(0)) as u64
+	}
+	pub fn cq_phys(&self) -> u64 {
+		::kernel::memory::virt::get_phys(self.cq.as_ref::<u8>(0)) as u64
+	}
+
+	/// Allocate a command slot (blocking if all are in use)
+	pub fn alloc_slot(&self) -> CommandSlot
+	{
+		self.free_sem.acquire();
+		let idx = {
+			let mut lh = self.free_slots.lock();
+			let idx = (0 .. self.slots.len()).find(|&i| *lh & (1 << i) != 0).expect("NVMe: Free slot semaphore out of sync");
+			*lh &= !(1 << idx);
+			idx
+			};
+		CommandSlot {
+			queue: self,
+			idx: idx,
+			submitted: false,
+		}
+	}
+
+	/// Collect new completion entries, returning true if there were any
+	pub fn handle_irq(&self, io: &IOBinding) -> bool
+	{
+		let mut completed = 0u64;
+		{
+			let mut lh = self.state.lock();
+			loop
+			{
+				// SAFE: Completion entries are only written by the controller, and read via volatile
+				let ent: hw::Completion = unsafe { ::core::ptr::read_volatile(self.cq.as_ref::<hw::Completion>(lh.cq_head as usize * 16)) };
+				if (ent.status & 1 != 0) != lh.phase {
+					break;
+				}
+				match self.slots.get(ent.cid as usize)
+				{
+				Some(slot) => {
+					slot.result.store(ent.dw0 as usize, Ordering::Relaxed);
+					slot.status.store(((ent.status >> 1) & 0x7FF) as usize, Ordering::Release);
+					completed |= 1 << ent.cid;
+					},
+				None => log_error!("NVMe Q{}: Completion for invalid command ID {}", self.qid, ent.cid),
+				}
+
+				lh.cq_head += 1;
+				if lh.cq_head == self.size {
+					lh.cq_head = 0;
+					lh.phase = !lh.phase;
+				}
+			}
+			if completed != 0
+			{
+				// SAFE: Doorbell is owned by this queue
+				unsafe { io.write_32(self.cq_doorbell, lh.cq_head as u32); }
+			}
+		}
+
+		// Wake waiters (outside the lock)
+		for (i, slot) in self.slots.iter().enumerate()
+		{
+			if completed & (1 << i) != 0 {
+				slot.event.trigger();
+			}
+		}
+		completed != 0
+	}
+}
+
+impl<'a> CommandSlot<'a>
+{
+	/// Page for a PRP list (owned by this slot)
+	pub fn prp_list(&mut self) -> &mut [u64]
+	{
+		let h = self.queue.slots[self.idx].prp_list.as_ref().expect("NVMe: PRP list requested on admin queue");
+		// SAFE: The slot (and hence its PRP list) is exclusively owned by this handle
+		unsafe { h.as_int_mut_slice::<u64>(0, ::kernel::PAGE_SIZE / 8) }
+	}
+	pub fn prp_list_phys(&self) -> u64
+	{
+		let h = self.queue.slots[self.idx].prp_list.as_ref().expect("NVMe: PRP list requested on admin queue");
+		::kernel::memory::virt::get_phys(h.as_ref::<u8>(0)) as u64
+	}
+
+	/// Add the command to the submission queue
+	pub fn submit(&mut self, io: &IOBinding, mut cmd: hw::Command)
+	{
+		assert!(!self.submitted);
+		cmd.cdw0 = (cmd.cdw0 & 0xFFFF) | (self.idx as u32) << 16;
+		self.queue.slots[self.idx].status.store(STATUS_PENDING, Ordering::Release);
+		self.submitted = true;
+
+		let mut lh = self.queue.state.lock();
+		// SAFE: The tail entry isn't owned by the controller (there are fewer slots than entries)
+		unsafe {
+			*self.queue.sq.as_int_mut::<hw::Command>(lh.sq_tail as usize * 64) = cmd;
+		}
+		lh.sq_tail += 1;
+		if lh.sq_tail == self.queue.size {
+			lh.sq_tail = 0;
+		}
+		// Ensure the entry is visible before the doorbell write
+		::core::sync::atomic::fence(Ordering::Release);
+		// SAFE: Doorbell is owned by this queue
+		unsafe { io.write_32(self.queue.sq_doorbell, lh.sq_tail as u32); }
+	}
+
+	/// Obtain a handle to wait for the completion interrupt
+	pub fn get_event(&self) -> async::event::Waiter<'a>
+	{
+		self.queue.slots[self.idx].event.wait()
+	}
+
+	/// Result of the command, if it has completed
+	pub fn result(&self) -> Option<Result<u32, Status>>
+	{
+		assert!(self.submitted);
+		let slot = &self.queue.slots[self.idx];
+		match slot.status.load(Ordering::Acquire)
+		{
+		STATUS_PENDING => None,
+		0 => Some(Ok(slot.result.load(Ordering::Relaxed) as u32)),
+		s => Some(Err(Status(s as u16))),
+		}
+	}
+
+	/// Wait for completion by polling the completion queue (used before the interrupt is bound)
+	///
+	/// Returns `None` on timeout, leaking the slot as the controller may still own the command.
+	pub fn poll_wait(self, io: &IOBinding, timeout_ms: u64) -> Option<Result<u32, Status>>
+	{
+		if ::kernel::time::wait_for(timeout_ms, 1, || { self.queue.handle_irq(io); self.result().is_some() }) {
+			self.result()
+		}
+		else {
+			::core::mem::forget(self);
+			None
+		}
+	}
+
+	/// Block until the command completes
+	pub fn wait(&self) -> Result<u32, Status>
+	{
+		loop
+		{
+			if let Some(r) = self.result() {
+				return r;
+			}
+			let mut w = self.get_event();
+			(&mut w as &mut async::Waiter).wait();
+		}
+	}
+}
+impl<'a> ::core::ops::Drop for CommandSlot<'a>
+{
+	fn drop(&mut self)
+	{
+		// The controller may still be accessing the buffers, so wait for it to finish
+		if self.submitted && self.result().is_none() {
+			log_notice!("NVMe Q{}: Command {} abandoned, waiting for completion", self.queue.qid, self.idx);
+			let _ = self.wait();
+		}
+		*self.queue.free_slots.lock() |= 1 << self.idx;
+		self.queue.free_sem.release();
+	}
+}
@@ -0,0 +1,88 @@
+// "Tifflin" Kernel - NVMe Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_nvme/volume.rs
+//! Namespace volumes
+use kernel::prelude::*;
+use kernel::lib::mem::Arc;
+use kernel::metadevs::storage;
+use kernel::async;
+use controller::ControllerInner;
+
+/// A single NVMe namespace
+pub struct Volume
+{
+	name: String,
+	controller: Arc<ControllerInner>,
+	nsid: u32,
+	block_size: usize,
+	block_count: u64,
+}
+
+impl Volume
+{
+	pub fn new_boxed(controller: Arc<ControllerInner>, nsid: u32, block_size: usize, block_count: u64) -> Box<Volume>
+	{
+		log_log!("{}n{}: {} blocks of {} bytes ({})", controller.name, nsid,
+			block_count, block_size, storage::SizePrinter(block_count * block_size as u64));
+		Box::new(Volume {
+			name: format!("{}n{}", controller.name, nsid),
+			controller: controller,
+			nsid: nsid,
+			block_size: block_size,
+			block_count: block_count,
+			})
+	}
+
+	fn start_rw<'a>(&'a self, idx: u64, num: usize, data: storage::DataPtr<'a>) -> storage::AsyncIoResult<'a,usize>
+	{
+		assert_eq!( data.len(), num * self.block_size );
+		if idx >= self.block_count || num as u64 > self.block_count - idx {
+			return Box::new(async::NullResultWaiter::new( || Err(storage::IoError::BadAddr) ));
+		}
+		match self.controller.start_rw(self.nsid, idx, self.block_size, data)
+		{
+		Ok(w) => Box::new(w),
+		Err(e) => Box::new(async::NullResultWaiter::new( move || Err(e) )),
+		}
+	}
+}
+impl ::core::ops::Drop for Volume
+{
+	fn drop(&mut self)
+	{
+		// Ensure written data reaches the medium before the volume goes away
+		if let Err(e) = self.controller.flush(self.nsid) {
+			log_notice!("{}: Cache flush failed - {:?}", self.name, e);
+		}
+	}
+}
+
+impl storage::PhysicalVolume for Volume
+{
+	fn name(&self) -> &str { &*self.name }
+	fn blocksize(&self) -> usize { self.block_size }
+	fn capacity(&self) -> Option<u64> { Some(self.block_count) }
+
+	fn read<'a>(&'a self, _prio: u8, idx: u64, num: usize, dst: &'a mut [u8]) -> storage::AsyncIoResult<'a,usize>
+	{
+		self.start_rw(idx, num, storage::DataPtr::Recv(dst))
+	}
+	fn write<'a>(&'a self, _prio: u8, idx: u64, num: usize, src: &'a [u8]) -> storage::AsyncIoResult<'a,usize>
+	{
+		self.start_rw(idx, num, storage::DataPtr::Send(src))
+	}
+
+	fn wipe<'a>(&'a self, blockidx: u64, count: usize) -> storage::AsyncIoResult<'a,()>
+	{
+		if blockidx >= self.block_count || count as u64 > self.block_count - blockidx {
+			return Box::new(async::NullResultWaiter::new( || Err(storage::IoError::BadAddr) ));
+		}
+		// Wiping is advisory, so controllers without DSM just keep the data
+		if !self.controller.has_dsm || count == 0 {
+			return Box::new(async::NullResultWaiter::new( || Ok( () ) ));
+		}
+		let rv = self.controller.deallocate(self.nsid, blockidx, count);
+		Box::new(async::NullResultWaiter::new( move || rv ))
+	}
+}
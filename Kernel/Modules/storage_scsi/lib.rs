@@ -10,6 +10,7 @@ use kernel::prelude::*;
 
 use kernel::async;
 use kernel::metadevs::storage;
+use core::sync::atomic::{AtomicBool,Ordering};
 
 pub mod proto;
 
@@ -35,11 +36,52 @@ pub struct Volume<I: ScsiInterface>
 	class: VolumeClass,
 	// block size, number of blocks
 	size: Option< (usize, u64) >,
+	/// Device accepts UNMAP (cleared if the device rejects it)
+	unmap_supported: AtomicBool,
+}
+
+/// Number of times a command is retried after a UNIT ATTENTION (medium change or reset)
+const UNIT_ATTENTION_RETRIES: usize = 3;
+/// Error message for a UNIT ATTENTION sense key
+const UNIT_ATTENTION_MSG: &'static str = "Unit attention (medium changed or device reset)";
+/// Maximum number of blocks in a single UNMAP descriptor
+const UNMAP_MAX_BLOCKS: u64 = 0xFFFF_FFFF;
+
+/// Convert SCSI sense data into an IO error (`None` if the sense data doesn't indicate an error)
+pub fn error_from_sense(key: proto::SenseKey, asc: u8, ascq: u8) -> Option<storage::IoError>
+{
+	use proto::SenseKey;
+	Some(match key
+		{
+		SenseKey::NoSense | SenseKey::RecoveredError => return None,
+		// 3A = Medium not present
+		SenseKey::NotReady if asc == 0x3A => storage::IoError::NoMedium,
+		SenseKey::NotReady => storage::IoError::Unknown("Device not ready"),
+		SenseKey::MediumError => storage::IoError::BadBlock,
+		SenseKey::DataProtect => storage::IoError::ReadOnly,
+		// 21/00 = LBA out of range
+		SenseKey::IllegalRequest if asc == 0x21 && ascq == 0x00 => storage::IoError::BadAddr,
+		SenseKey::IllegalRequest => storage::IoError::InvalidParameter,
+		SenseKey::UnitAttention => storage::IoError::Unknown(UNIT_ATTENTION_MSG),
+		SenseKey::HardwareError => storage::IoError::Unknown("Hardware error"),
+		SenseKey::AbortedCommand => storage::IoError::Unknown("Command aborted"),
+		SenseKey::BlankCheck => storage::IoError::Unknown("Blank medium"),
+		SenseKey::VolumeOverflow => storage::IoError::BadAddr,
+		_ => storage::IoError::Unknown("SCSI check condition"),
+		})
+}
+
+/// A failed command, as decoded using REQUEST SENSE
+enum Failure
+{
+	/// UNIT ATTENTION (medium change or reset), the command can be retried
+	UnitAttention,
+	Error(storage::IoError),
 }
 
 impl<I: ScsiInterface> Volume<I>
 {
-	fn recv_cmd<'a>(int: &I, cmd: &[u8], data: &'a mut [u8]) -> Result<(), storage::IoError> {
+	fn check_cmd(cmd: &[u8]) {
 		log_debug!("- cmd=[{:?}]", cmd);
 		match cmd[0] & 0xE0
 		{
@@ -50,19 +92,84 @@ impl<I: ScsiInterface> Volume<I>
 		0x80 => assert_eq!(cmd.len(), 16),
 		_ => {},
 		}
-
-		let _size = {
-			let mut v = int.recv(cmd, data);
-			while !v.is_complete() {
-				::kernel::async::wait_on_list(&mut [v.as_waiter()], None);
+	}
+	fn wait<'a>(mut v: storage::AsyncIoResult<'a,()>) -> Result<(), storage::IoError> {
+		while !v.is_complete() {
+			::kernel::async::wait_on_list(&mut [v.as_waiter()], None);
+		}
+		v.get_result().unwrap()
+	}
+	/// Synchronously run a command that reads data, decoding any failure using REQUEST SENSE
+	fn recv_cmd<'a>(int: &I, cmd: &[u8], data: &'a mut [u8]) -> Result<(), storage::IoError> {
+		Self::check_cmd(cmd);
+		for _ in 0 .. UNIT_ATTENTION_RETRIES
+		{
+			match Self::wait(int.recv(cmd, &mut *data))
+			{
+			Ok(_) => return Ok( () ),
+			Err(e) => match Self::decode_failure(int, e)
+				{
+				Failure::UnitAttention => continue,
+				Failure::Error(e) => return Err(e),
+				},
 			}
-			try!(v.get_result().unwrap())
-			};
-		Ok( () )
+		}
+		Err( storage::IoError::Unknown(UNIT_ATTENTION_MSG) )
+	}
+	/// Synchronously run a command that sends data (or has no data stage)
+	fn send_cmd(int: &I, cmd: &[u8], data: &[u8]) -> Result<(), storage::IoError> {
+		Self::check_cmd(cmd);
+		for _ in 0 .. UNIT_ATTENTION_RETRIES
+		{
+			match Self::wait(int.send(cmd, data))
+			{
+			Ok(_) => return Ok( () ),
+			Err(e) => match Self::decode_failure(int, e)
+				{
+				Failure::UnitAttention => continue,
+				Failure::Error(e) => return Err(e),
+				},
+			}
+		}
+		Err( storage::IoError::Unknown(UNIT_ATTENTION_MSG) )
+	}
+	/// Obtain a more specific error for a failed command using REQUEST SENSE
+	fn refine_error(int: &I, e: storage::IoError) -> storage::IoError {
+		match Self::decode_failure(int, e)
+		{
+		Failure::UnitAttention => storage::IoError::Unknown(UNIT_ATTENTION_MSG),
+		Failure::Error(e) => e,
+		}
+	}
+	/// Decode a failed command using REQUEST SENSE
+	///
+	/// Interfaces that can't decode a failure themselves return `IoError::Unknown`, other errors are passed through.
+	fn decode_failure(int: &I, e: storage::IoError) -> Failure {
+		match e
+		{
+		storage::IoError::Unknown(_) => {},
+		_ => return Failure::Error(e),
+		}
+		let mut rsp = proto::RequestSenseRsp::new();
+		match Self::wait( int.recv(proto::RequestSense::new(rsp.len() as u8).as_ref(), rsp.as_mut()) )
+		{
+		Ok(_) => {},
+		Err(se) => {
+			log_notice!("{}: REQUEST SENSE failed - {:?}", int.name(), se);
+			return Failure::Error(e);
+			},
+		}
+		log_debug!("{}: Sense {:?} ASC={:#x} ASCQ={:#x}", int.name(), rsp.key(), rsp.asc(), rsp.ascq());
+		match rsp.key()
+		{
+		proto::SenseKey::UnitAttention => Failure::UnitAttention,
+		key => Failure::Error( error_from_sense(key, rsp.asc(), rsp.ascq()).unwrap_or(e) ),
+		}
 	}
+
 	pub fn new_boxed(int: I) -> Result<Box<Self>,storage::IoError> {
 		// 1. Request device type (INQUIRY)
-		let (class, removable) = {
+		let (class, removable, version) = {
 			let mut inq_data = proto::InquiryRsp::new();
 			try!( Self::recv_cmd(&int, proto::Inquiry::new(inq_data.len() as u16).as_ref(), inq_data.as_mut()) );
 			log_debug!("Type: {:#x}", inq_data.prehipheral_type());
@@ -79,10 +186,11 @@ impl<I: ScsiInterface> Volume<I>
 				};
 			let removable = inq_data.removable();
 			
-			(class, removable)
+			(class, removable, inq_data.version())
 			};
 		
 		// 2. Check the size (and check for a disk too)
+		let mut unmap_supported = false;
 		let size = {
 			let mut data = proto::ReadCapacity10Rsp::new();
 			match Self::recv_cmd(&int, proto::ReadCapacity10::new().as_ref(), data.as_mut())
@@ -91,7 +199,32 @@ impl<I: ScsiInterface> Volume<I>
 				::kernel::logging::hex_dump("SCSI Volume size", data.as_ref());
 				let blksz = data.block_length();
 				let max = data.maxlba();
-				Some( (blksz as usize, (max as u64 + 1)) )
+				// READ CAPACITY(16) is needed for disks over 2^32 blocks, and reports thin provisioning support
+				// - Only sent to SPC-3 (or later) disks, older devices (e.g. some USB sticks) can fail badly on it
+				if let VolumeClass::DirectAccessBlock = class
+				{
+					if max == 0xFFFF_FFFF || version >= 5
+					{
+						let mut data16 = proto::ReadCapacity16Rsp::new();
+						match Self::recv_cmd(&int, proto::ReadCapacity16::new(data16.len() as u32).as_ref(), data16.as_mut())
+						{
+						Ok(_) => {
+							unmap_supported = data16.lbpme();
+							Some( (data16.block_length() as usize, data16.maxlba() + 1) )
+							},
+						Err(e) => {
+							log_debug!("READ CAPACITY(16) failed: {:?}", e);
+							Some( (blksz as usize, (max as u64 + 1)) )
+							},
+						}
+					}
+					else {
+						Some( (blksz as usize, (max as u64 + 1)) )
+					}
+				}
+				else {
+					Some( (blksz as usize, (max as u64 + 1)) )
+				}
 				},
 			Err(storage::IoError::NoMedium) if removable => {
 				log_debug!("No medium");
@@ -100,14 +233,116 @@ impl<I: ScsiInterface> Volume<I>
 			Err(e) => return Err(From::from(e)),
 			}
 			};
-		log_log!("SCSI Volume {} - class={:?} size={:?}", int.name(), class, size);
+		log_log!("SCSI Volume {} - class={:?} size={:?} unmap={}", int.name(), class, size, unmap_supported);
 		
 		Ok(Box::new( Volume {
 			int: int,
 			class: class,
 			size: size,
+			unmap_supported: AtomicBool::new(unmap_supported),
 			} ))
 	}
+
+	/// Flush the device's write cache (SYNCHRONIZE CACHE)
+	pub fn flush(&self) -> Result<(), storage::IoError> {
+		Self::send_cmd(&self.int, proto::SynchronizeCache10::new().as_ref(), &[])
+	}
+
+	/// Check that a block range is within the volume
+	fn check_range(&self, idx: u64, num: usize) -> Result<(), storage::IoError> {
+		match self.size
+		{
+		None => Err(storage::IoError::NoMedium),
+		Some((_, count)) if idx >= count || num as u64 > count - idx => Err(storage::IoError::BadAddr),
+		Some(_) => Ok( () ),
+		}
+	}
+}
+
+impl<I: ScsiInterface> ::core::ops::Drop for Volume<I>
+{
+	fn drop(&mut self) {
+		// Ensure written data reaches the medium before the volume goes away
+		if let VolumeClass::DirectAccessBlock = self.class
+		{
+			if self.size.is_some()
+			{
+				match self.flush()
+				{
+				Ok(_) => {},
+				// - Device already gone, nothing to flush to
+				Err(storage::IoError::Removed) => {},
+				Err(e) => log_notice!("{}: Cache flush failed - {:?}", self.int.name(), e),
+				}
+			}
+		}
+	}
+}
+
+/// Wrapper around an interface's result that converts to a block count (and decodes errors using REQUEST SENSE)
+struct IoWrapper<'a, I: 'a + ScsiInterface>
+{
+	vol: &'a Volume<I>,
+	inner: storage::AsyncIoResult<'a,()>,
+	count: usize,
+}
+impl<'a, I: 'a + ScsiInterface> ::core::fmt::Debug for IoWrapper<'a, I> {
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		write!(f, "IoWrapper({:?}, {})", self.inner, self.count)
+	}
+}
+impl<'a, I: 'a + ScsiInterface> ::kernel::async::Waiter for IoWrapper<'a, I> {
+	fn is_complete(&self) -> bool { self.inner.is_complete() }
+	fn get_waiter(&mut self) -> &mut ::kernel::async::PrimitiveWaiter { self.inner.get_waiter() }
+	fn complete(&mut self) -> bool { self.inner.complete() }
+}
+impl<'a, I: 'a + ScsiInterface> ::kernel::async::ResultWaiter for IoWrapper<'a, I> {
+	type Result = Result<usize, ::kernel::metadevs::storage::IoError>;
+	fn get_result(&mut self) -> Option<Self::Result> {
+		let count = self.count;
+		let vol = self.vol;
+		self.inner.get_result().map(|v| match v
+			{
+			Ok(_) => Ok(count),
+			Err(e) => Err(Volume::<I>::refine_error(&vol.int, e)),
+			})
+	}
+	fn as_waiter(&mut self) -> &mut ::kernel::async::Waiter { self }
+}
+
+/// Asynchronous UNMAP, owning the parameter list (a rejected UNMAP disables further wipes)
+struct UnmapWaiter<'a, I: 'a + ScsiInterface>
+{
+	vol: &'a Volume<I>,
+	// NOTE: Borrows `_params`, so must be dropped first
+	inner: storage::AsyncIoResult<'a,()>,
+	_params: Vec<u8>,
+}
+impl<'a, I: 'a + ScsiInterface> ::core::fmt::Debug for UnmapWaiter<'a, I> {
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		write!(f, "UnmapWaiter({:?})", self.inner)
+	}
+}
+impl<'a, I: 'a + ScsiInterface> ::kernel::async::Waiter for UnmapWaiter<'a, I> {
+	fn is_complete(&self) -> bool { self.inner.is_complete() }
+	fn get_waiter(&mut self) -> &mut ::kernel::async::PrimitiveWaiter { self.inner.get_waiter() }
+	fn complete(&mut self) -> bool { self.inner.complete() }
+}
+impl<'a, I: 'a + ScsiInterface> ::kernel::async::ResultWaiter for UnmapWaiter<'a, I> {
+	type Result = Result<(), ::kernel::metadevs::storage::IoError>;
+	fn get_result(&mut self) -> Option<Self::Result> {
+		let vol = self.vol;
+		self.inner.get_result().map(|v| match v.map_err(|e| Volume::<I>::refine_error(&vol.int, e))
+			{
+			Err(storage::IoError::InvalidParameter) => {
+				log_notice!("{}: UNMAP rejected, disabling", vol.int.name());
+				vol.unmap_supported.store(false, Ordering::Relaxed);
+				Ok( () )
+				},
+			v => v,
+			})
+	}
+	fn as_waiter(&mut self) -> &mut ::kernel::async::Waiter { self }
 }
 
 fn fits_in_bits(v: usize, bits: usize) -> bool {
@@ -127,6 +362,9 @@ impl<I: ScsiInterface> storage::PhysicalVolume for Volume<I>
 	
 	fn read<'a>(&'a self, _prio: u8, idx: u64, num: usize, dst: &'a mut [u8]) -> storage::AsyncIoResult<'a,usize>
 	{
+		if let Err(e) = self.check_range(idx, num) {
+			return Box::new(async::NullResultWaiter::new( move || Err(e) ));
+		}
 		// NOTE: Read6 commented out, as qemu's CD code doesn't support it
 		let rv = /*if idx < (1<<24) && num < (1 << 8) {
 				log_trace!("SCSI Read6");
@@ -141,40 +379,69 @@ impl<I: ScsiInterface> storage::PhysicalVolume for Volume<I>
 				self.int.recv(proto::Read16::new(idx, num as u32).as_ref(), dst)
 			}
 			else {
-				todo!("SCSI read out of range");
+				return Box::new(async::NullResultWaiter::new( || Err(storage::IoError::InvalidParameter) ));
 			};
 		
-		#[derive(Debug)]
-		struct Wrapper<'a>(storage::AsyncIoResult<'a,()>, usize);
-		impl<'a> ::kernel::async::Waiter for Wrapper<'a> {
-			fn is_complete(&self) -> bool { self.0.is_complete() }
-			fn get_waiter(&mut self) -> &mut ::kernel::async::PrimitiveWaiter { self.0.get_waiter() }
-			fn complete(&mut self) -> bool { self.0.complete() }
-		}
-		impl<'a> ::kernel::async::ResultWaiter for Wrapper<'a> {
-			type Result = Result<usize, ::kernel::metadevs::storage::IoError>;
-			fn get_result(&mut self) -> Option<Self::Result> { self.0.get_result().map(|v| v.map(|_| self.1)) }
-			fn as_waiter(&mut self) -> &mut ::kernel::async::Waiter { self.0.as_waiter() }
-		}
-		Box::new( Wrapper(rv, num) )
-
-		//rv
+		Box::new( IoWrapper { vol: self, inner: rv, count: num } )
 	}
 	fn write<'s>(&'s self, _prio: u8, idx: u64, num: usize, src: &'s [u8]) -> storage::AsyncIoResult<'s,usize> {
 		match self.class
 		{
 		VolumeClass::CdDvd => Box::new(async::NullResultWaiter::new( || Err(storage::IoError::ReadOnly) )),
 		VolumeClass::DirectAccessBlock => {
-			todo!("Volume::write(idx={},num={},len={})", idx, num, src.len());
+			if let Err(e) = self.check_range(idx, num) {
+				return Box::new(async::NullResultWaiter::new( move || Err(e) ));
+			}
+			let rv = if idx < (1<<32) && num < (1 << 16) {
+					log_trace!("SCSI Write10");
+					self.int.send(proto::Write10::new(idx as u32, num as u16).as_ref(), src)
+				}
+				else if fits_in_bits(num, 32) {
+					log_trace!("SCSI Write16");
+					self.int.send(proto::Write16::new(idx, num as u32).as_ref(), src)
+				}
+				else {
+					return Box::new(async::NullResultWaiter::new( || Err(storage::IoError::InvalidParameter) ));
+				};
+			Box::new( IoWrapper { vol: self, inner: rv, count: num } )
 			},
-		_ => Box::new(async::NullResultWaiter::new( || Err(storage::IoError::Unknown("TODO: Write support")) )),
+		_ => Box::new(async::NullResultWaiter::new( || Err(storage::IoError::Unknown("Writes not supported for this device class")) )),
 		}
 	}
 	
-	fn wipe<'a>(&'a self, _blockidx: u64, _count: usize) -> storage::AsyncIoResult<'a,()>
+	fn wipe<'a>(&'a self, blockidx: u64, count: usize) -> storage::AsyncIoResult<'a,()>
 	{
-		todo!("Volume::wipe");
+		if let Err(e) = self.check_range(blockidx, count) {
+			return Box::new(async::NullResultWaiter::new( move || Err(e) ));
+		}
+		// Wiping is advisory, so devices without UNMAP support just keep the data
+		if !self.unmap_supported.load(Ordering::Relaxed) {
+			return Box::new(async::NullResultWaiter::new( || Ok( () ) ));
+		}
+
+		// Build the parameter list (one descriptor per 2^32-1 blocks)
+		let n_desc = ((count as u64 + UNMAP_MAX_BLOCKS - 1) / UNMAP_MAX_BLOCKS) as usize;
+		let mut params = vec![0u8; proto::UNMAP_HEADER_LEN + n_desc * proto::UNMAP_DESCRIPTOR_LEN];
+		proto::unmap_header(&mut params, n_desc);
+		let mut lba = blockidx;
+		let mut remaining = count as u64;
+		for d in params[proto::UNMAP_HEADER_LEN..].chunks_mut(proto::UNMAP_DESCRIPTOR_LEN)
+		{
+			let n = ::core::cmp::min(remaining, UNMAP_MAX_BLOCKS);
+			proto::unmap_descriptor(d, lba, n as u32);
+			lba += n;
+			remaining -= n;
+		}
+
+		let cmd = proto::Unmap::new(params.len() as u16);
+		Self::check_cmd(cmd.as_ref());
+		// SAFE: The parameter list's heap allocation is owned by the waiter, and outlives `inner` (see field order)
+		let params_ref: &'a [u8] = unsafe { &*(&params[..] as *const [u8]) };
+		Box::new( UnmapWaiter {
+			vol: self,
+			inner: self.int.send(cmd.as_ref(), params_ref),
+			_params: params,
+			})
 	}
 	
 }
-
@@ -145,6 +145,110 @@ impl Read16
 	}
 }
 
+def_cmd!{ Write10[10] 0x2A,
+	(lba: u32, count: u16) => [
+		0,	// 1: flags
+		((lba >> 24) & 0xFF) as u8,
+		((lba >> 16) & 0xFF) as u8,
+		((lba >>  8) & 0xFF) as u8,
+		((lba >>  0) & 0xFF) as u8,
+		0,	// 6: group number
+		((count >> 8) & 0xFF) as u8,
+		((count >> 0) & 0xFF) as u8,
+		0	// 9: control
+	] }
+
+def_cmd!{ Write16[16] 0x8A,
+	(lba: u64, count: u32) => [
+		0,	// 1: flags
+		((lba >> 56) & 0xFF) as u8,
+		((lba >> 48) & 0xFF) as u8,
+		((lba >> 40) & 0xFF) as u8,
+		((lba >> 32) & 0xFF) as u8,
+		((lba >> 24) & 0xFF) as u8,
+		((lba >> 16) & 0xFF) as u8,
+		((lba >>  8) & 0xFF) as u8,
+		((lba >>  0) & 0xFF) as u8,
+		((count >> 24) & 0xFF) as u8,
+		((count >> 16) & 0xFF) as u8,
+		((count >>  8) & 0xFF) as u8,
+		((count >>  0) & 0xFF) as u8,
+		0,	// 14: group number
+		0	// 15: control
+	] }
+
+// SYNCHRONIZE CACHE(10) - Flush the device's write cache (LBA=0,count=0 covers the whole device)
+def_cmd!{ SynchronizeCache10[10] 0x35,
+	() => [
+		0,	// 1: flags
+		0,0,0,0,	// LBA
+		0,	// 6: group number
+		0,0,	// block count
+		0	// 9: control
+	] }
+
+// UNMAP - Deallocate blocks (parameter list is a header followed by block descriptors, see `unmap_header`)
+def_cmd!{ Unmap[10] 0x42,
+	(param_len: u16) => [
+		0,	// 1: anchor
+		0,0,0,0,	// reserved
+		0,	// 6: group number
+		((param_len >> 8) & 0xFF) as u8,
+		((param_len >> 0) & 0xFF) as u8,
+		0	// 9: control
+	] }
+/// Size of the UNMAP parameter list header
+pub const UNMAP_HEADER_LEN: usize = 8;
+/// Size of an UNMAP block descriptor
+pub const UNMAP_DESCRIPTOR_LEN: usize = 16;
+/// Write the UNMAP parameter list header for `count` descriptors
+pub fn unmap_header(dst: &mut [u8], count: usize) {
+	let desc_len = count * UNMAP_DESCRIPTOR_LEN;
+	BigEndian::write_u16(&mut dst[0..2], (UNMAP_HEADER_LEN - 2 + desc_len) as u16);
+	BigEndian::write_u16(&mut dst[2..4], desc_len as u16);
+	for b in &mut dst[4..8] {
+		*b = 0;
+	}
+}
+/// Write an UNMAP block descriptor
+pub fn unmap_descriptor(dst: &mut [u8], lba: u64, count: u32) {
+	BigEndian::write_u64(&mut dst[0..8], lba);
+	BigEndian::write_u32(&mut dst[8..12], count);
+	for b in &mut dst[12..16] {
+		*b = 0;
+	}
+}
+
+def_cmd!{ RequestSense[6] 0x03,
+	(alloc: u8) => [
+		0,	// 1: DESC (clear = fixed format)
+		0,0,	// reserved
+		alloc,
+		0	// 5: control
+	] }
+def_rsp!{ RequestSenseRsp[18] }
+impl RequestSenseRsp
+{
+	/// Response code (0x70/0x71 = fixed format, 0x72/0x73 = descriptor format)
+	pub fn response_code(&self) -> u8 {
+		self.0[0] & 0x7F
+	}
+	fn is_descriptor(&self) -> bool {
+		self.response_code() >= 0x72
+	}
+	pub fn key(&self) -> SenseKey {
+		SenseKey::from(if self.is_descriptor() { self.0[1] } else { self.0[2] } & 0xF)
+	}
+	/// Additional sense code
+	pub fn asc(&self) -> u8 {
+		if self.is_descriptor() { self.0[2] } else { self.0[12] }
+	}
+	/// Additional sense code qualifier
+	pub fn ascq(&self) -> u8 {
+		if self.is_descriptor() { self.0[3] } else { self.0[13] }
+	}
+}
+
 def_cmd!{ Inquiry[6] 0x12,
 	(alloc: u16) => [
 		0,	// 1: EPVD
@@ -170,6 +274,10 @@ impl InquiryRsp
 	pub fn removable(&self) -> bool {
 		self.0[1] & 0x80 != 0
 	}
+	/// Supported standard version (5 = SPC-3, 6 = SPC-4)
+	pub fn version(&self) -> u8 {
+		self.0[2]
+	}
 }
 
 
@@ -193,6 +301,34 @@ impl ReadCapacity10Rsp
 	}
 }
 
+// READ CAPACITY(16) (SERVICE ACTION IN(16), service action 0x10)
+def_cmd!{ ReadCapacity16[16] 0x9E,
+	(alloc: u32) => [
+		0x10,	// 1: service action
+		0,0,0,0,0,0,0,0,	// LBA
+		((alloc >> 24) & 0xFF) as u8,
+		((alloc >> 16) & 0xFF) as u8,
+		((alloc >>  8) & 0xFF) as u8,
+		((alloc >>  0) & 0xFF) as u8,
+		0,	// 14: PMI
+		0	// 15: control
+	] }
+
+def_rsp!{ ReadCapacity16Rsp[32] }
+impl ReadCapacity16Rsp
+{
+	pub fn maxlba(&self) -> u64 {
+		BigEndian::read_u64(&self.0[0..8])
+	}
+	pub fn block_length(&self) -> u32 {
+		BigEndian::read_u32(&self.0[8..12])
+	}
+	/// Logical block provisioning enabled (the device supports UNMAP)
+	pub fn lbpme(&self) -> bool {
+		self.0[14] & 0x80 != 0
+	}
+}
+
 def_cmd!{ GetConfiguration[10] 0x46,
 	(alloc: u16) => [
 		0,	// mode (bottom two bits)
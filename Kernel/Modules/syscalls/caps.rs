@@ -0,0 +1,162 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Modules/syscalls/caps.rs
+//! Per-process capability sets
+//!
+//! Each process holds a set of capability flags (`values::CAP_*`), a range of network ports it may use,
+//! and a list of filesystem subtrees with the rights (`values::FS_RIGHT_*`) granted within them.
+//! A new process inherits its parent's set, which can only be restricted (via the `ProtoProcess` handle)
+//! before the process is started.
+use kernel::prelude::*;
+use kernel::lib::byte_str::ByteString;
+use kernel::vfs::Path;
+use kernel::sync::RwLock;
+use values;
+
+/// Capability set for a single process
+#[derive(Clone)]
+pub struct Capabilities
+{
+	/// Bitmask of `values::CAP_*` flags
+	flags: u32,
+	/// Inclusive range of network ports that can be used (empty if `.0 > .1`)
+	ports: (u16, u16),
+	/// Filesystem subtrees (absolute normalised paths) and the rights granted within them
+	/// - The most specific matching entry applies
+	subtrees: Vec<(ByteString, u8)>,
+}
+
+impl Default for Capabilities {
+	fn default() -> Capabilities {
+		Capabilities::none()
+	}
+}
+
+impl Capabilities
+{
+	/// A capability set that grants nothing
+	pub fn none() -> Capabilities {
+		Capabilities {
+			flags: 0,
+			ports: (1, 0),
+			subtrees: Vec::new(),
+		}
+	}
+	/// A capability set that grants everything (used for PID0)
+	pub fn full() -> Capabilities {
+		let mut subtrees = Vec::new();
+		subtrees.push( (ByteString::from(&b"/"[..]), values::FS_RIGHT_READ | values::FS_RIGHT_WRITE) );
+		Capabilities {
+			flags: values::CAP_ALL,
+			ports: (0, 0xFFFF),
+			subtrees: subtrees,
+		}
+	}
+
+	pub fn has(&self, flags: u32) -> bool {
+		self.flags & flags == flags
+	}
+	pub fn port_allowed(&self, port: u16) -> bool {
+		self.ports.0 <= port && port <= self.ports.1
+	}
+	/// Rights granted at the specified (absolute, normalised) path
+	pub fn path_rights(&self, path: &Path) -> u8 {
+		let mut best: Option<(usize, u8)> = None;
+		for &(ref subtree, rights) in self.subtrees.iter()
+		{
+			if path.starts_with( Path::new(subtree) ).is_some() {
+				match best
+				{
+				Some( (len, _) ) if len >= subtree.len() => {},
+				_ => best = Some( (subtree.len(), rights) ),
+				}
+			}
+		}
+		best.map(|(_,r)| r).unwrap_or(0)
+	}
+}
+
+/// Per-process storage for the capability set
+#[derive(Default)]
+struct ProcessCaps(RwLock<Capabilities>);
+
+/// Obtain a copy of the current process's capabilities
+pub fn current() -> Capabilities {
+	::kernel::threads::get_process_local::<ProcessCaps>().0.read().clone()
+}
+/// Returns true if the current process holds all of the passed `CAP_*` flags
+pub fn has(flags: u32) -> bool {
+	::kernel::threads::get_process_local::<ProcessCaps>().0.read().has(flags)
+}
+/// Returns true if the current process is allowed to use the specified network port
+pub fn port_allowed(port: u16) -> bool {
+	::kernel::threads::get_process_local::<ProcessCaps>().0.read().port_allowed(port)
+}
+/// Returns the `FS_RIGHT_*` flags the current process holds at the specified path
+pub fn path_rights(path: &Path) -> u8 {
+	::kernel::threads::get_process_local::<ProcessCaps>().0.read().path_rights(path)
+}
+
+/// Grant the current process (PID0) a full capability set
+pub fn init_pid0() {
+	assert!(::kernel::threads::get_process_id() == 0);
+	*::kernel::threads::get_process_local::<ProcessCaps>().0.write() = Capabilities::full();
+}
+
+
+/// Capability set being prepared for a new process
+pub struct Builder
+{
+	/// Creating process's capabilities (the upper limit)
+	parent: Capabilities,
+	caps: Capabilities,
+	/// Set once a subtree restriction has been applied (replacing the inherited list)
+	subtrees_limited: bool,
+}
+impl Builder
+{
+	/// Start with a copy of the current process's capabilities
+	pub fn inherit() -> Builder {
+		let parent = current();
+		Builder {
+			caps: parent.clone(),
+			parent: parent,
+			subtrees_limited: false,
+		}
+	}
+
+	/// Remove the specified `CAP_*` flags
+	pub fn drop_flags(&mut self, flags: u32) {
+		self.caps.flags &= !flags;
+	}
+	/// Limit the usable network ports to `first` ..= `last`
+	pub fn limit_ports(&mut self, first: u16, last: u16) {
+		let (cur_first, cur_last) = self.caps.ports;
+		self.caps.ports = (::core::cmp::max(cur_first, first), ::core::cmp::min(cur_last, last));
+	}
+	/// Limit filesystem access to the passed subtree (with at most `rights`)
+	///
+	/// The first call replaces the inherited subtree list, subsequent calls add further subtrees.
+	/// The rights granted never exceed those of the creating process.
+	pub fn limit_subtree(&mut self, path: &Path, rights: u8) {
+		if !self.subtrees_limited {
+			self.caps.subtrees = Vec::new();
+			self.subtrees_limited = true;
+		}
+		self.caps.subtrees.push( (ByteString::from(AsRef::<[u8]>::as_ref(path)), rights & self.parent.path_rights(path)) );
+		// - Keep any more specific restrictions the parent has within this subtree
+		for &(ref subtree, parent_rights) in self.parent.subtrees.iter()
+		{
+			let sub_path = Path::new(subtree);
+			if subtree.len() > AsRef::<[u8]>::as_ref(path).len() && sub_path.starts_with(path).is_some() {
+				self.caps.subtrees.push( (subtree.clone(), rights & parent_rights) );
+			}
+		}
+	}
+
+	/// Set the capabilities of the (not yet started) process
+	pub fn install(&self, process: &::kernel::threads::ProcessHandle) {
+		*process.get_process_local_alloc::<ProcessCaps>().0.write() = self.caps.clone();
+	}
+}
@@ -36,13 +36,13 @@ impl ::core::convert::Into<values::GuiEvent> for ::gui::input::Event {
 
 #[inline(never)]
 pub fn newgroup(name: &str) -> Result<ObjectHandle,u32> {
-	// Only init can create new sessions
-	// TODO: Use a capability system instead of hardcoding to only PID0
-	if ::kernel::threads::get_process_id() == 0 {
+	// Only processes with the session capability (e.g. init) can create new sessions
+	if ::caps::has(::values::CAP_GUI_SESSION) {
 		Ok(objects::new_object(Group(::gui::WindowGroupHandle::alloc(name))))
 	}
 	else {
-		todo!("syscall_gui_newgroup(name={}) - PID != 0", name);
+		log_notice!("syscall_gui_newgroup(name={}) - Permission denied", name);
+		Err(0)
 	}
 }
 
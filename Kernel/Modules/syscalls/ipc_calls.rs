@@ -3,10 +3,15 @@
 //
 // Core/syscalls/ipc_calls.rs
 //! Userland interface to IPC channels
+use kernel::prelude::*;
 use args::Args;
 use kernel::memory::freeze::{Freeze,FreezeMut};
 use core::sync::atomic::{AtomicU8,Ordering};
-use values::RpcMessage;
+use values::{RpcMessage,RpcError};
+use objects::ObjectAlloc;
+
+/// Maximum number of messages waiting on one side of a channel (sends fail with `QueueFull` past this)
+const MAX_QUEUED_MESSAGES: usize = 16;
 
 struct SyncChannel {
 	// TODO: NonZero?
@@ -31,19 +36,11 @@ impl ::objects::Object for SyncChannel
 		::values::IPC_RPC_SEND => {
 			let data: Freeze<::values::RpcMessage> = try!(args.get());
 			let obj: u32 = try!(args.get());
-			todo!("IPC_RPC_SEND({:p}, {})", &*data, obj);
+			Ok( to_result(try!(self.send(*data, obj))) )
 			},
 		::values::IPC_RPC_RECV => {
-			let _data: FreezeMut<::values::RpcMessage> = try!(args.get());
-
-			if let Some(msg) = self.take_message()
-			{
-				todo!("IPC_RPC_RECV - Message present - {:?}", msg);
-			}
-			else
-			{
-				Ok( 0x1000 )
-			}
+			let mut data: FreezeMut<::values::RpcMessage> = try!(args.get());
+			Ok( to_result(self.receive(&mut data)) )
 			},
 		_ => ::objects::object_has_no_such_method_ref("ipc_calls::SyncChannel", call),
 		}
@@ -55,24 +52,37 @@ impl ::objects::Object for SyncChannel
 	}
 	fn bind_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
 		let mut ret = 0;
-		if flags & ::values::EV_IPC_RPC_RECV != 0 {
+		if flags & (::values::EV_IPC_RPC_RECV | ::values::EV_IPC_RPC_CLOSED) != 0 {
 			self.wait_upon(obj);
-			ret |= ::values::EV_IPC_RPC_RECV;
+			// If the event has already happened, wake immediately
+			if self.has_message() || self.is_peer_closed() {
+				obj.signal();
+			}
+			ret += 1;
 		}
 		ret
 	}
 	fn clear_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
 		let mut ret = 0;
-		if flags & ::values::EV_IPC_RPC_RECV != 0 {
+		if flags & (::values::EV_IPC_RPC_RECV | ::values::EV_IPC_RPC_CLOSED) != 0 {
 			self.clear_wait(obj);
-			if self.has_message() {
-				ret += 1;
+			let closed = self.is_peer_closed();
+			// NOTE: Closure also fires RECV, so a receiver sees `ConnectionClosed`
+			if flags & ::values::EV_IPC_RPC_RECV != 0 && (self.has_message() || closed) {
+				ret |= ::values::EV_IPC_RPC_RECV;
+			}
+			if flags & ::values::EV_IPC_RPC_CLOSED != 0 && closed {
+				ret |= ::values::EV_IPC_RPC_CLOSED;
 			}
 		}
 		ret
 	}
 }
 
+fn to_result(r: Result<u32, RpcError>) -> u64 {
+	::from_result(r.map_err(|e| { let v: u8 = e.into(); v as u32 }))
+}
+
 pub fn new_pair() -> Result< (u32,u32), () >
 {
 	let (a_obj, b_obj) = SyncChannel::new_pair();
@@ -94,14 +104,18 @@ pub fn new_pair() -> Result< (u32,u32), () >
 #[derive(Default)]
 struct SyncChannelBack
 {
+	/// Bitmask of sides that have started dropping (i.e. closed)
 	dying_refs: AtomicU8,
+	/// Bitmask of sides that have finished dropping (the last to finish frees the allocation)
 	dead_refs: AtomicU8,
 	sides: [ SyncChannelSide; 2 ],
 }
 #[derive(Default)]
 struct SyncChannelSide
 {
-	message: ::kernel::sync::Spinlock<Option<RpcMessage>>,
+	/// Messages waiting to be received by this side, along with any attached object
+	messages: ::kernel::sync::Mutex<Vec<(RpcMessage, Option<ObjectAlloc>)>>,
+	/// Threads waiting for a message (or for the other side to be closed)
 	queue: ::kernel::async::queue::Source,
 }
 
@@ -114,12 +128,18 @@ impl SyncChannel
 		(SyncChannel { ptr: ptr, side_idx: 0 }, SyncChannel { ptr: ptr, side_idx: 1 })
 	}
 
-	fn get_side(&self) -> &SyncChannelSide {
+	fn back(&self) -> &SyncChannelBack {
 		// SAFE: Destructor ensures that pointer is valid until both are dead
 		unsafe {
-			&(*self.ptr).sides[self.side_idx as usize]
+			&*self.ptr
 		}
 	}
+	fn get_side(&self) -> &SyncChannelSide {
+		&self.back().sides[self.side_idx as usize]
+	}
+	fn get_other_side(&self) -> &SyncChannelSide {
+		&self.back().sides[1 - self.side_idx as usize]
+	}
 
 	pub fn wait_upon(&self, waiter: &mut ::kernel::threads::SleepObject) {
 		self.get_side().queue.wait_upon(waiter);
@@ -129,28 +149,83 @@ impl SyncChannel
 	}
 
 	pub fn has_message(&self) -> bool {
-		self.get_side().message.lock().is_some()
+		! self.get_side().messages.lock().is_empty()
+	}
+	/// Returns true if the other end of the channel has been dropped
+	pub fn is_peer_closed(&self) -> bool {
+		self.back().dying_refs.load(Ordering::SeqCst) & (1 << (1 - self.side_idx)) != 0
+	}
+
+	/// Queue a message (and optionally move an object) to the other side
+	///
+	/// Outer error is for an invalid object handle (argument error)
+	fn send(&self, msg: RpcMessage, obj_handle: u32) -> Result<Result<u32, RpcError>, ::Error> {
+		let other = self.get_other_side();
+		{
+			let mut lh = other.messages.lock();
+			// Checked with the queue locked, so the object isn't moved into a queue that's about to be discarded
+			if self.is_peer_closed() {
+				return Ok( Err(RpcError::ConnectionClosed) );
+			}
+			if lh.len() >= MAX_QUEUED_MESSAGES {
+				return Ok( Err(RpcError::QueueFull) );
+			}
+			let obj = if obj_handle != 0 {
+					Some( try!(::objects::take_object_raw(obj_handle)) )
+				}
+				else {
+					None
+				};
+			lh.push( (msg, obj) );
+		}
+		other.queue.wake_one();
+		Ok( Ok(0) )
 	}
-	pub fn take_message(&self) -> Option<RpcMessage> {
-		self.get_side().message.lock().take()
+	/// Pop a message from this side's queue, returning the handle of the attached object (or 0)
+	fn receive(&self, dst: &mut RpcMessage) -> Result<u32, RpcError> {
+		let mut lh = self.get_side().messages.lock();
+		if lh.is_empty() {
+			return Err( if self.is_peer_closed() { RpcError::ConnectionClosed } else { RpcError::NoMessage } );
+		}
+		let (msg, obj) = lh.remove(0);
+		let handle = match obj
+			{
+			None => 0,
+			Some(obj) =>
+				match ::objects::insert_object_raw(obj)
+				{
+				Ok(h) => h,
+				Err(obj) => {
+					// No space for the object, leave the message at the head of the queue
+					lh.insert(0, (msg, Some(obj)));
+					return Err( RpcError::TooManyObjects );
+					},
+				},
+			};
+		*dst = msg;
+		Ok( handle )
 	}
 }
 
 impl ::core::ops::Drop for SyncChannel {
 	fn drop(&mut self) {
-		// SAFE: Pointer is valid
-		let should_free = unsafe {
-			if (*self.ptr).dying_refs.fetch_or(1 << self.side_idx, Ordering::SeqCst) != 0 {
-				// Other side is in shutdown or dead.
-			}
-			else {
-			}
+		// Mark this side as closing, then wake the other side's waiters so they see the closure
+		// - The allocation stays valid until both sides have marked themselves dead
+		{
+			// Set with this side's queue locked, so a concurrent `send` either sees the closure or finishes queueing first
+			let _lh = self.get_side().messages.lock();
+			self.back().dying_refs.fetch_or(1 << self.side_idx, Ordering::SeqCst);
+		}
+		while self.get_other_side().queue.wake_one() {
+		}
 
-			(*self.ptr).dead_refs.fetch_or(1 << self.side_idx, Ordering::SeqCst) != 0
-			};
+		let should_free = self.back().dead_refs.fetch_or(1 << self.side_idx, Ordering::SeqCst) != 0;
 		if should_free {
-			todo!("Deallocate SyncChannel");
+			// SAFE: Both sides are dead, so nothing else references the allocation. Queued objects are dropped.
+			unsafe {
+				::core::ptr::drop_in_place(self.ptr as *mut SyncChannelBack);
+				::kernel::memory::heap::dealloc(self.ptr as *mut SyncChannelBack);
+			}
 		}
 	}
 }
-
@@ -25,6 +25,8 @@ mod threads;
 mod gui_calls;
 mod vfs;
 mod ipc_calls;
+mod mem_calls;
+mod caps;
 
 pub type ObjectHandle = u32;
 
@@ -64,8 +66,9 @@ impl ::core::fmt::Display for Error {
 	}
 }
 
-/// Initialise PID0's handles
+/// Initialise PID0's handles and capabilities
 pub fn init(loader_handle: ::kernel::vfs::handle::File, init_handle: ::kernel::vfs::handle::File) {
+	caps::init_pid0();
 	vfs::init_handles(loader_handle, init_handle);
 }
 
@@ -74,7 +77,11 @@ pub fn init(loader_handle: ::kernel::vfs::handle::File, init_handle: ::kernel::v
 pub unsafe extern "C" fn syscalls_handler(id: u32, first_arg: *const usize, count: u32) -> u64
 {
 	//log_debug!("syscalls_handler({}, {:p}+{})", id, first_arg, count);
-	invoke(id, ::core::slice::from_raw_parts(first_arg, count as usize))
+	// Syscall boundaries are where threads notice that their process is being terminated
+	::kernel::threads::check_exit_request();
+	let rv = invoke(id, ::core::slice::from_raw_parts(first_arg, count as usize));
+	::kernel::threads::check_exit_request();
+	rv
 }
 
 fn invoke(call_id: u32, args: &[usize]) -> u64 {
@@ -167,7 +174,13 @@ fn invoke_int(call_id: u32, args: &mut Args) -> Result<u64,Error>
 		CORE_STARTTHREAD => {
 			let ip: usize = try!(args.get());
 			let sp: usize = try!(args.get());
-			threads::newthread(sp, ip) as u64
+			let tls_base: usize = try!(args.get());
+			// NOTE: SP and the TLS base are only used in user-space, but the IP has to be valid for the return to user
+			if ip >= ::kernel::arch::memory::addresses::USER_END {
+				log_log!("CORE_STARTTHREAD - IP {:#x} invalid", ip);
+				return Err( Error::BadValue );
+			}
+			threads::newthread(sp, ip, tls_base) as u64
 			},
 		// - 0/5: Wait for event
 		CORE_WAIT => {
@@ -176,10 +189,18 @@ fn invoke_int(call_id: u32, args: &mut Args) -> Result<u64,Error>
 			try!(threads::wait(&mut events, timeout)) as u64
 			},
 		CORE_FUTEX_SLEEP => {
-			todo!("FUTEX_SLEEP");
+			let addr: usize = try!(args.get());
+			let sleep_if_val: usize = try!(args.get());
+			try!(threads::futex_sleep(addr, sleep_if_val))
 			},
 		CORE_FUTEX_WAKE => {
-			todo!("FUTEX_SLEEP");
+			let addr: usize = try!(args.get());
+			let num_to_wake: usize = try!(args.get());
+			try!(threads::futex_wake(addr, num_to_wake))
+			},
+		CORE_GETCREDENTIALS => {
+			let creds = ::kernel::threads::get_credentials();
+			creds.uid as u64 | (creds.gid as u64) << 32
 			},
 		// === 1: Window Manager / GUI
 		// - 1/0: New group (requires permission, has other restrictions)
@@ -245,6 +266,11 @@ fn invoke_int(call_id: u32, args: &mut Args) -> Result<u64,Error>
 			Err( () ) => error_code(0) as u64,
 			}
 			},
+		MEM_SHM_CREATE => {
+			let size: usize = try!(args.get());
+			log_debug!("MEM_SHM_CREATE({:#x})", size);
+			try!(mem_calls::new_shared(size))
+			},
 		// === 3: IPC
 		IPC_NEWPAIR => {
 			match ipc_calls::new_pair()
@@ -0,0 +1,93 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Modules/syscalls/mem_calls.rs
+//! Userland interface to shared memory objects
+use kernel::prelude::*;
+use kernel::lib::mem::Arc;
+use kernel::memory::shared::SharedRegion;
+use kernel::memory::virt::{MapError,ProtectionMode};
+use args::Args;
+use values;
+use Error;
+
+/// Maximum size of a single shared memory object (64MiB)
+const MAX_SHARED_PAGES: usize = (64 << 20) / ::kernel::PAGE_SIZE;
+
+/// Handle to a shared memory region, clones refer to the same memory
+struct SharedMemory(Arc<SharedRegion>);
+
+/// Create a new shared memory object of at least `size` bytes
+pub fn new_shared(size: usize) -> Result<u64, Error>
+{
+	let page_count = size / ::kernel::PAGE_SIZE + if size % ::kernel::PAGE_SIZE != 0 { 1 } else { 0 };
+	if size == 0 || page_count > MAX_SHARED_PAGES {
+		log_log!("MEM_SHM_CREATE - Bad size {:#x}", size);
+		// Reported the same as an allocation failure (an invalid handle)
+		return Ok( !0 );
+	}
+	match SharedRegion::new(page_count)
+	{
+	Ok(r) => Ok( ::objects::new_object(SharedMemory(Arc::new(r))) as u64 ),
+	Err(e) => {
+		log_notice!("MEM_SHM_CREATE({:#x}) - Allocation failed: {:?}", size, e);
+		Ok( !0 )
+		},
+	}
+}
+
+fn to_result(r: Result<(), MapError>) -> u64 {
+	::from_result(r
+		.map(|_| 0u32)
+		.map_err(|e| {
+			let v: u8 = match e
+				{
+				MapError::RangeInUse => values::MemoryError::RangeInUse,
+				MapError::OutOfMemory => values::MemoryError::OutOfMemory,
+				}.into();
+			v as u32
+			})
+		)
+}
+
+impl ::objects::Object for SharedMemory
+{
+	fn class(&self) -> u16 { values::CLASS_MEM_SHARED }
+	fn as_any(&self) -> &Any { self }
+	fn try_clone(&self) -> Option<u32> {
+		Some( ::objects::new_object(SharedMemory(self.0.clone())) )
+	}
+	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,Error>
+	{
+		match call
+		{
+		values::MEM_SHM_GETSIZE => {
+			Ok( (self.0.page_count() * ::kernel::PAGE_SIZE) as u64 )
+			},
+		values::MEM_SHM_MAP => {
+			let addr: usize = try!(args.get());
+			let mode = match try!(args.get::<u8>())
+				{
+				0 => ProtectionMode::UserRO,
+				1 => ProtectionMode::UserRW,
+				2 => ProtectionMode::UserRX,
+				3 => ProtectionMode::UserRWX,	// NOTE: Allowed, as MEM_REPROTECT allows it
+				v @ _ => {
+					log_log!("MEM_SHM_MAP - Bad protection mode {}", v);
+					return Err( Error::BadValue );
+					},
+				};
+			log_debug!("MEM_SHM_MAP({:#x}, {:?}) - {:?}", addr, mode, self.0);
+			let size = self.0.page_count() * ::kernel::PAGE_SIZE;
+			if addr % ::kernel::PAGE_SIZE != 0 || addr.checked_add(size).map(|e| e > ::kernel::arch::memory::addresses::USER_END).unwrap_or(true) {
+				log_log!("MEM_SHM_MAP - Bad address {:#x}+{:#x}", addr, size);
+				return Err( Error::BadValue );
+			}
+			Ok( to_result(self.0.map_user(addr as *mut (), mode)) )
+			},
+		_ => ::objects::object_has_no_such_method_ref("mem_calls::SharedMemory", call),
+		}
+	}
+	fn bind_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 { 0 }
+	fn clear_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 { 0 }
+}
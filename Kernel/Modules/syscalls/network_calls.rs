@@ -12,10 +12,27 @@ pub fn new_free_socket(local_address: ::values::SocketAddress, remote_mask: ::va
 	if local_address.addr_ty != remote_mask.addr.addr_ty {
 		return Err(::values::SocketError::InvalidValue);
 	}
-	// TODO: Check that the current process is allowed to use the specified combination of port/type
+	try!(check_address_allowed(&local_address));
 	todo!("new_free_socket");
 }
 
+/// Check that the current process is allowed to use the specified combination of port/type
+fn check_address_allowed(addr: &::values::SocketAddress) -> Result<(), ::values::SocketError>
+{
+	let is_raw = addr.port_ty == ::values::SocketPortType::Raw as u8 || addr.addr_ty == ::values::SocketAddressType::Mac as u8;
+	if is_raw {
+		if !::caps::has(::values::CAP_NET_RAW) {
+			log_notice!("Process not permitted raw network access");
+			return Err(::values::SocketError::PermissionDenied);
+		}
+	}
+	else if !::caps::port_allowed(addr.port) {
+		log_notice!("Process not permitted to use port {}", addr.port);
+		return Err(::values::SocketError::PermissionDenied);
+	}
+	Ok( () )
+}
+
 struct FreeSocket
 {
 }
@@ -201,6 +201,22 @@ impl ProcessObjects {
 		Err(super::Error::TooManyObjects)
 	}
 
+	fn insert(&self, obj: ObjectAlloc) -> Result<u32, ObjectAlloc> {
+		for (i,ent) in self.iter().enumerate()
+		{
+			if ent.read().is_none() {
+				let mut wh = ent.write();
+				if wh.is_none() {
+					log_debug!("Object inserted #{}: {}", i, obj.type_name());
+					*wh = Some(UserObject { data: obj });
+					return Ok(i as u32);
+				}
+			}
+		}
+		log_debug!("No space");
+		Err(obj)
+	}
+
 	fn push_given(&self, handle: u32, tag: &str)
 	{
 		let mut lh = self.given.lock();
@@ -356,6 +372,21 @@ pub fn take_object<T: Object+'static>(handle: u32) -> Result<T,super::Error> {
 	}
 }
 
+/// Remove an object from the current process (e.g. to send it over IPC), see `insert_object_raw`
+pub fn take_object_raw(handle: u32) -> Result<ObjectAlloc,super::Error> {
+	if handle == 0 {
+		// "this process" can't be moved
+		Err( super::Error::NoSuchObject(handle) )
+	}
+	else {
+		get_process_local::<ProcessObjects>().take_object(handle)
+	}
+}
+/// Insert an object obtained from `take_object_raw` into the current process (returning it if there's no space)
+pub fn insert_object_raw(obj: ObjectAlloc) -> Result<u32,ObjectAlloc> {
+	get_process_local::<ProcessObjects>().insert(obj)
+}
+
 #[inline(never)]
 pub fn drop_object(handle: u32)
 {
@@ -5,6 +5,10 @@
 //! Thread management calls
 
 use kernel::prelude::*;
+use kernel::lib::VecMap;
+use kernel::memory::freeze::Freeze;
+use kernel::threads::{SleepObject,SleepObjectRef};
+use core::sync::atomic::{AtomicUsize,Ordering};
 
 use ObjectHandle;
 use Error;
@@ -50,18 +54,104 @@ pub fn exit(status: u32) {
 }
 #[inline(never)]
 pub fn terminate() {
-	todo!("terminate()");
+	::kernel::threads::terminate_thread();
 }
 #[inline(never)]
-pub fn newthread(sp: usize, ip: usize) -> ObjectHandle {
-	todo!("newthread(sp={:#x},ip={:#x})", sp, ip);
+pub fn newthread(sp: usize, ip: usize, tls_base: usize) -> ObjectHandle {
+	let handle = ::kernel::threads::ThreadHandle::new_user(ip, sp, tls_base);
+	::objects::new_object( Thread(handle) )
+}
+
+/// Per-process list of threads sleeping on futexes, keyed by user address
+struct FutexTable
+{
+	/// Sleep object references, with a flag set once the sleeper has been woken
+	waiters: ::kernel::sync::Mutex< VecMap<usize, Vec<(SleepObjectRef, bool)>> >,
+}
+impl Default for FutexTable {
+	fn default() -> FutexTable {
+		FutexTable {
+			waiters: ::kernel::sync::Mutex::new(VecMap::new()),
+		}
+	}
+}
+
+fn get_futex(addr: usize) -> Result<&'static AtomicUsize, Error>
+{
+	if addr >= ::kernel::arch::memory::addresses::USER_END {
+		return Err( Error::InvalidBuffer(addr as *const (), ::core::mem::size_of::<usize>()) );
+	}
+	// SAFE: Checks that the pointer is valid and aligned, and atomics are valid for any bit pattern
+	match unsafe { ::kernel::memory::buf_to_slice(addr as *const AtomicUsize, 1) }
+	{
+	Some(v) => Ok(&v[0]),
+	None => Err( Error::InvalidBuffer(addr as *const (), ::core::mem::size_of::<usize>()) ),
+	}
+}
+
+/// Sleep on the futex at `addr` if it holds `sleep_if_val`, returns 1 if the thread slept
+#[inline(never)]
+pub fn futex_sleep(addr: usize, sleep_if_val: usize) -> Result<u64,Error>
+{
+	let futex = try!(get_futex(addr));
+	let table = ::kernel::threads::get_process_local::<FutexTable>();
+	let mut waiter = SleepObject::new("futex");
+	{
+		let mut lh = table.waiters.lock();
+		// Checked with the table locked, so a wake can't be lost between the check and the sleep
+		if futex.load(Ordering::SeqCst) != sleep_if_val {
+			return Ok(0);
+		}
+		let list = match lh.entry(addr)
+			{
+			::kernel::lib::vec_map::Entry::Occupied(e) => e.into_mut(),
+			::kernel::lib::vec_map::Entry::Vacant(e) => e.insert( Vec::new() ),
+			};
+		list.push( (waiter.get_ref(), false) );
+	}
+	// Also wake if the process is asked to terminate
+	::kernel::threads::wait_interruptible(&mut waiter);
+
+	// The sleeper removes its own entry (so the reference is never released after the object is gone)
+	let mut lh = table.waiters.lock();
+	let now_empty = match lh.get_mut(&addr)
+		{
+		Some(list) => {
+			list.retain(|&(ref r, _)| !r.is_from(&waiter));
+			list.is_empty()
+			},
+		None => false,
+		};
+	if now_empty {
+		lh.remove(&addr);
+	}
+	Ok(1)
+}
+/// Wake up to `num_to_wake` threads sleeping on the futex at `addr`, returns the number woken
+#[inline(never)]
+pub fn futex_wake(addr: usize, num_to_wake: usize) -> Result<u64,Error>
+{
+	let table = ::kernel::threads::get_process_local::<FutexTable>();
+	let mut lh = table.waiters.lock();
+	let mut count = 0;
+	if let Some(list) = lh.get_mut(&addr)
+	{
+		// Oldest sleepers first
+		for &mut (ref r, ref mut woken) in list.iter_mut().filter(|e| !e.1).take(num_to_wake)
+		{
+			*woken = true;
+			r.signal();
+			count += 1;
+		}
+	}
+	Ok(count)
 }
 #[inline(never)]
 pub fn newprocess(name: &str,  clone_start: usize, clone_end: usize) -> ObjectHandle {
 	// 1. Create a new process image (virtual address space)
 	let process = ::kernel::threads::ProcessHandle::new(name, clone_start, clone_end);
 	
-	::objects::new_object( ProtoProcess(process) )
+	::objects::new_object( ProtoProcess(process, ::kernel::sync::Mutex::new(::caps::Builder::inherit())) )
 }
 
 // ret: number of events triggered
@@ -73,30 +163,61 @@ pub fn wait(events: &mut [values::WaitItem], wake_time_mono: u64) -> Result<u32,
 	for ev in events.iter() {
 		num_bound += try!(::objects::wait_on_object(ev.object, ev.flags, &mut waiter));
 	}
-
-	if num_bound == 0 && wake_time_mono == !0 {
-		// Attempting to sleep on no events with an infinite timeout! Would sleep forever
-		log_error!("TODO: What to do when a thread tries to sleep forever");
-		waiter.wait();
-	}
-
 	// A wake time of 0 means to not sleep at all, just check the status of the events
 	// TODO: There should be a more efficient way of doing this, than binding only to unbind again
-	if wake_time_mono != 0 {
-		// !0 indicates an unbounded wait (no need to set a wakeup time)
-		if wake_time_mono != !0 {
-			todo!("Set a wakeup timer at {}", wake_time_mono);
-			//waiter.wait_until(wake_time_mono);
-		}
-		else {
-			waiter.wait();
+	if wake_time_mono == 0 {
+	}
+	// !0 indicates an unbounded wait (no need to set a wakeup time)
+	else if wake_time_mono == !0 {
+		if num_bound == 0 {
+			// Attempting to sleep on no events with an infinite timeout, the thread will never wake
+			log_notice!("Thread sleeping forever (no events and no timeout)");
 		}
+		::kernel::threads::wait_interruptible(&mut waiter);
+	}
+	else {
+		// NOTE: Timer is dropped before `waiter` (it holds a reference to it)
+		let _timer = ::kernel::time::Timer::new(wake_time_mono, &waiter);
+		::kernel::threads::wait_interruptible(&mut waiter);
 	}
 
 	Ok( events.iter_mut().fold(0, |total,ev| total + ::objects::clear_wait(ev.object, ev.flags, &mut waiter).unwrap()) )
 }
 
-pub struct ProtoProcess(::kernel::threads::ProcessHandle);
+/// Handle to a thread within the current process
+pub struct Thread(::kernel::threads::ThreadHandle);
+impl ::objects::Object for Thread
+{
+	fn class(&self) -> u16 { values::CLASS_CORE_THREAD }
+	fn as_any(&self) -> &Any { self }
+	fn try_clone(&self) -> Option<u32> {
+		None
+	}
+	fn handle_syscall_ref(&self, call: u16, _args: &mut Args) -> Result<u64,Error>
+	{
+		::objects::object_has_no_such_method_ref("threads::Thread", call)
+	}
+	fn bind_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & values::EV_THREAD_TERMINATED != 0 {
+			self.0.bind_wait_terminate(obj);
+			ret += 1;
+		}
+		ret
+	}
+	fn clear_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & values::EV_THREAD_TERMINATED != 0 {
+			if self.0.clear_wait_terminate(obj) {
+				ret |= values::EV_THREAD_TERMINATED;
+			}
+		}
+		ret
+	}
+}
+
+/// Process that has not yet been started, along with its (inherited) capability set
+pub struct ProtoProcess(::kernel::threads::ProcessHandle, ::kernel::sync::Mutex<::caps::Builder>);
 impl ::objects::Object for ProtoProcess
 {
 	fn class(&self) -> u16 { values::CLASS_CORE_PROTOPROCESS }
@@ -114,6 +235,53 @@ impl ::objects::Object for ProtoProcess
 			let handle: u32 = try!(args.get());
 			::objects::give_object(&self.0, &tag, handle).map(|_| 0)
 			}
+		values::CORE_PROTOPROCESS_DROPCAPS => {
+			let flags: u32 = try!(args.get());
+			log_debug!("CORE_PROTOPROCESS_DROPCAPS({:#x})", flags);
+			self.1.lock().drop_flags(flags);
+			Ok(0)
+			},
+		values::CORE_PROTOPROCESS_LIMITPORTS => {
+			let first: u16 = try!(args.get());
+			let last: u16 = try!(args.get());
+			log_debug!("CORE_PROTOPROCESS_LIMITPORTS({}-{})", first, last);
+			self.1.lock().limit_ports(first, last);
+			Ok(0)
+			},
+		values::CORE_PROTOPROCESS_LIMITPATH => {
+			let path: Freeze<[u8]> = try!(args.get());
+			let rights: u8 = try!(args.get());
+			let path = ::kernel::vfs::Path::new(&*path);
+			log_debug!("CORE_PROTOPROCESS_LIMITPATH({:?}, {:#x})", path, rights);
+			if !path.is_absolute() || !path.is_normalised() {
+				return Err( Error::BadValue );
+			}
+			// Rights are checked against the link-free path (see `vfs::open_tracked`)
+			// - A path that doesn't exist (yet) is used as-is
+			match ::vfs::resolve_path(path)
+			{
+			Ok(resolved) => self.1.lock().limit_subtree(::kernel::vfs::Path::new(&resolved), rights),
+			Err(e) => {
+				log_debug!("- Unable to resolve {:?}: {:?}", path, e);
+				self.1.lock().limit_subtree(path, rights);
+				},
+			}
+			Ok(0)
+			},
+		values::CORE_PROTOPROCESS_SETCREDS => {
+			let uid: u32 = try!(args.get());
+			let gid: u32 = try!(args.get());
+			log_debug!("CORE_PROTOPROCESS_SETCREDS(uid={}, gid={})", uid, gid);
+			let rv = if ::caps::has(values::CAP_SET_CREDENTIALS) {
+					self.0.set_credentials( ::kernel::threads::Credentials { uid: uid, gid: gid } );
+					Ok(0u32)
+				}
+				else {
+					log_notice!("CORE_PROTOPROCESS_SETCREDS - Permission denied");
+					Err(::values::ProcessError::PermissionDenied)
+				};
+			Ok( super::from_result(rv.map_err(|e| { let v: u8 = e.into(); v as u32 })) )
+			},
 		_ => ::objects::object_has_no_such_method_ref("threads::ProtoProcess", call),
 		}
 	}
@@ -127,6 +295,7 @@ impl ::objects::Object for ProtoProcess
 			let sp: usize = try!(args.get());
 			
 			let mut inner = this.0;
+			this.1.lock().install(&inner);
 
 			// NOTE: Don't need to validate these values, as they're used only in user-space
 			inner.start_root_thread(ip, sp);
@@ -156,7 +325,18 @@ impl ::objects::Object for Process
 		match call
 		{
 		// Request termination of child process
-		values::CORE_PROCESS_KILL => todo!("CORE_PROCESS_KILL"),
+		values::CORE_PROCESS_KILL => {
+			log_debug!("CORE_PROCESS_KILL - {:?}", self.0);
+			self.0.kill(values::EXIT_STATUS_KILLED);
+			Ok(0)
+			},
+		values::CORE_PROCESS_GETEXIT => {
+			Ok( match self.0.get_exit_status()
+				{
+				Some(v) => v as u64,
+				None => !0,
+				})
+			},
 		_ => ::objects::object_has_no_such_method_ref("threads::Process", call),
 		}
 	}
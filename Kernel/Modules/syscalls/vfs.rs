@@ -12,6 +12,7 @@ use super::Error;
 use args::Args;
 use kernel::vfs::{handle,node};
 use kernel::vfs::Path;
+use kernel::lib::byte_str::{ByteStr,ByteString};
 
 
 macro_rules! map_enums {
@@ -37,8 +38,24 @@ impl_from! {
 		Error::PermissionDenied => VFSError::PermissionDenied,
 		Error::Locked => VFSError::FileLocked,
 		Error::MalformedPath => VFSError::MalformedPath,
-		Error::Unknown(reason) => todo!("VFS Error Unknown - '{}'", reason),
-		_ => todo!("VFS Error - {:?}", v),
+		Error::AlreadyExists => VFSError::AlreadyExists,
+		Error::NotEmpty => VFSError::NotEmpty,
+		Error::InvalidParameter => VFSError::InvalidParameter,
+		Error::NonDirComponent => VFSError::NotADirectory,
+		Error::RecursionDepthExceeded => VFSError::RecursionLimit,
+		Error::BlockIoError(e) => {
+			log_notice!("VFS IO Error - {:?}", e);
+			VFSError::IoError
+			},
+		Error::ReadOnlyFilesystem => VFSError::ReadOnly,
+		Error::InconsistentFilesystem => VFSError::Inconsistent,
+		Error::OutOfSpace => VFSError::OutOfSpace,
+		Error::OutOfMemory => VFSError::OutOfMemory,
+		Error::TransientError => VFSError::Transient,
+		Error::Unknown(reason) => {
+			log_notice!("VFS Error Unknown - '{}'", reason);
+			VFSError::Unknown
+			},
 		}
 	}}
 	From<node::NodeClass>(v) for ::values::VFSNodeType {
@@ -73,6 +90,94 @@ fn to_result<T>(r: Result<T, ::kernel::vfs::Error>) -> Result<T, u32> {
 	r.map_err( |e| Into::into( <::values::VFSError as From<_>>::from(e) ) )
 }
 
+/// Append the components of `rel` to the absolute path `base` (used to track the path of opened nodes)
+fn join_path(base: &ByteStr, rel: &Path) -> Result<ByteString, ::kernel::vfs::Error> {
+	// `.` and `..` would allow escaping a subtree without it being visible in the tracked path
+	if !rel.is_normalised() {
+		return Err( ::kernel::vfs::Error::MalformedPath );
+	}
+	let mut rv = Vec::from(base.as_bytes());
+	for comp in rel.iter().filter(|c| c.len() > 0)
+	{
+		if rv.last() != Some(&b'/') {
+			rv.push(b'/');
+		}
+		rv.extend_from_slice(comp.as_bytes());
+	}
+	Ok( ByteString::from(rv) )
+}
+/// Maximum number of symbolic links expanded by a single `open_tracked` call
+const MAX_SYMLINK_DEPTH: usize = 8;
+/// Open `rel` below `dir` (which was opened at `base`), returning the node and the path it was reached by
+///
+/// Symbolic links in the intermediate components are expanded here (instead of by the VFS), so the returned
+/// path is where the node actually lives and subtree checks can't be bypassed by a link out of the subtree.
+fn open_tracked(dir: &handle::Dir, base: &ByteStr, rel: &Path) -> Result<(handle::Any, ByteString), ::kernel::vfs::Error> {
+	use kernel::vfs::Error as VfsError;
+	if !rel.is_normalised() {
+		return Err( VfsError::MalformedPath );
+	}
+	// Stack of components still to be opened (next component last)
+	let mut remaining: Vec<ByteString> = rel.iter().filter(|c| c.len() > 0).map(|c| ByteString::from(c)).collect();
+	remaining.reverse();
+
+	let mut cur_dir = dir.clone();
+	let mut cur_path = ByteString::from(base);
+	let mut depth = 0;
+	while remaining.len() > 1
+	{
+		let comp = remaining.pop().unwrap();
+		let child = try!(cur_dir.open_child(&comp));
+		match child.get_class()
+		{
+		node::NodeClass::Dir => {
+			cur_path = try!(join_path(&cur_path, Path::new(&comp)));
+			cur_dir = try!(child.to_dir());
+			},
+		node::NodeClass::Symlink => {
+			depth += 1;
+			if depth > MAX_SYMLINK_DEPTH {
+				return Err( VfsError::RecursionDepthExceeded );
+			}
+			let target = try!(try!(child.to_symlink()).get_target());
+			let target = Path::new(&target);
+			// NOTE: Relative links aren't supported by the VFS either
+			if !target.is_absolute() || !target.is_normalised() {
+				return Err( VfsError::MalformedPath );
+			}
+			let target_comps: Vec<ByteString> = target.iter().filter(|c| c.len() > 0).map(|c| ByteString::from(c)).collect();
+			remaining.extend( target_comps.into_iter().rev() );
+			// Restart the walk from the root
+			cur_path = ByteString::from(&b"/"[..]);
+			cur_dir = try!(handle::Dir::open(Path::new("/")));
+			},
+		_ => return Err( VfsError::NonDirComponent ),
+		}
+	}
+
+	match remaining.pop()
+	{
+	// NOTE: The final component isn't followed (the caller gets the link itself)
+	Some(last) => Ok( (try!(cur_dir.open_child(&last)), try!(join_path(&cur_path, Path::new(&last)))) ),
+	None => Ok( (try!(dir.open_child_path(rel)), ByteString::from(base)) ),
+	}
+}
+/// Expand symbolic links in an absolute path (as opening it would), so restrictions apply to where nodes live
+pub fn resolve_path(path: &Path) -> Result<ByteString, ::kernel::vfs::Error> {
+	let root = try!(handle::Dir::open(Path::new("/")));
+	open_tracked(&root, ByteStr::new("/"), path).map(|(_, p)| p)
+}
+/// Check that the current process holds the passed `FS_RIGHT_*` flags at `path`
+fn check_rights(path: &ByteStr, rights: u8) -> Result<(), ::kernel::vfs::Error> {
+	if ::caps::path_rights(Path::new(path)) & rights == rights {
+		Ok( () )
+	}
+	else {
+		log_notice!("Access to {:?} denied (requires rights {:#x})", path, rights);
+		Err( ::kernel::vfs::Error::PermissionDenied )
+	}
+}
+
 pub fn init_handles(loader_handle: ::kernel::vfs::handle::File, init_handle: ::kernel::vfs::handle::File) {
 	use kernel::vfs::handle;
 	// - Forget the loader (no need)
@@ -81,7 +186,7 @@ pub fn init_handles(loader_handle: ::kernel::vfs::handle::File, init_handle: ::k
 	// #1: Initial file handle
 	::objects::new_object( File(init_handle) );
 	// #2: Read-only root
-	::objects::new_object(Dir::new( {
+	::objects::new_object(Dir::new( ByteString::from(&b"/"[..]), {
 		let root = handle::Dir::open(Path::new("/")).unwrap();
 		//root.set_permissions( handle::Perms::readonly() );
 		root
@@ -89,7 +194,7 @@ pub fn init_handles(loader_handle: ::kernel::vfs::handle::File, init_handle: ::k
 
 	// - Read-write handle to /
 	//::objects::push_as_unclaimed( ::objects::new_object( Dir::new( handle::Dir::open(Path::new("/")).unwrap() ) ) );
-	::objects::push_as_unclaimed("RwRoot", ::objects::new_object( Dir::new( handle::Dir::open(Path::new("/")).unwrap() ) ) );
+	::objects::push_as_unclaimed("RwRoot", ::objects::new_object( Dir::new( ByteString::from(&b"/"[..]), handle::Dir::open(Path::new("/")).unwrap() ) ) );
 }
 
 
@@ -97,13 +202,14 @@ pub fn init_handles(loader_handle: ::kernel::vfs::handle::File, init_handle: ::k
 //
 // --------------------------------------------------------------------
 
-struct Node( handle::Any );
+/// Opened node, along with the path used to open it (for capability checks)
+struct Node( handle::Any, ByteString );
 impl objects::Object for Node
 {
 	fn class(&self) -> u16 { values::CLASS_VFS_NODE }
 	fn as_any(&self) -> &Any { self }
 	fn try_clone(&self) -> Option<u32> {
-		Some( ::objects::new_object( Node(self.0.clone()) ) )
+		Some( ::objects::new_object( Node(self.0.clone(), self.1.clone()) ) )
 	}
 	fn handle_syscall_ref(&self, call: u16, _args: &mut Args) -> Result<u64,Error> {
 		match call
@@ -120,6 +226,7 @@ impl objects::Object for Node
 		// SAFE: Raw pointer coerced from &mut, caller forgets us
 		let this = unsafe { ::core::ptr::read(self) };
 		let inner = this.0;
+		let path = this.1;
 		match call
 		{
 		values::VFS_NODE_TOFILE => {
@@ -132,17 +239,22 @@ impl objects::Object for Node
 				};
 			log_debug!("VFS_NODE_TOFILE({:?})", mode);
 
-			let objres = to_result(inner.to_file(mode.into()))
+			let rights = match mode
+				{
+				::values::VFSFileOpenMode::ReadOnly | ::values::VFSFileOpenMode::Execute => values::FS_RIGHT_READ,
+				_ => values::FS_RIGHT_READ | values::FS_RIGHT_WRITE,
+				};
+			let objres = to_result( check_rights(&path, rights).and_then(|_| inner.to_file(mode.into())) )
 				.map( |h| objects::new_object(File(h)) );
 			Ok( super::from_result(objres) )
 			},
 		values::VFS_NODE_TODIR => {
 			let objres = to_result(inner.to_dir())
-				.map( |h| objects::new_object(Dir::new(h)) );
+				.map( |h| objects::new_object(Dir::new(path, h)) );
 			Ok( super::from_result(objres) )
 			},
 		values::VFS_NODE_TOLINK => {
-			let objres = to_result(inner.to_symlink())
+			let objres = to_result( check_rights(&path, values::FS_RIGHT_READ).and_then(|_| inner.to_symlink()) )
 				.map( |h| objects::new_object(Link(h)) );
 			Ok(super::from_result( objres ))
 			},
@@ -179,7 +291,10 @@ impl objects::Object for File
 			match self.0.read(ofs, &mut dest)
 			{
 			Ok(count) => Ok(count as u64),
-			Err(e) => todo!("File::handle_syscall READAT Error {:?}", e),
+			Err(e) => {
+				log_debug!("File::handle_syscall READAT Error {:?}", e);
+				Ok( super::from_result::<u32,u32>(to_result(Err(e))) )
+				},
 			}
 			},
 		values::VFS_FILE_WRITEAT => {
@@ -189,7 +304,10 @@ impl objects::Object for File
 			match self.0.write(ofs, &src)
 			{
 			Ok(count) => Ok(count as u64),
-			Err(e) => todo!("File::handle_syscall WRITEAT Error {:?}", e),
+			Err(e) => {
+				log_debug!("File::handle_syscall WRITEAT Error {:?}", e);
+				Ok( super::from_result::<u32,u32>(to_result(Err(e))) )
+				},
 			}
 			},
 		values::VFS_FILE_MEMMAP => {
@@ -219,7 +337,10 @@ impl objects::Object for File
 				::core::mem::forget(h);
 				Ok(0)
 				},
-			Err(e) => todo!("File::handle_syscall MEMMAP Error {:?}", e),
+			Err(e) => {
+				log_debug!("File::handle_syscall MEMMAP Error {:?}", e);
+				Ok( super::from_result::<u32,u32>(to_result(Err(e))) )
+				},
 			}
 			},
 		_ => ::objects::object_has_no_such_method_ref("vfs::File", call),
@@ -236,11 +357,14 @@ impl objects::Object for File
 
 struct Dir {
 	handle: ::kernel::vfs::handle::Dir,
+	/// Path used to open this directory (for capability checks)
+	path: ByteString,
 }
 impl Dir {
-	fn new(handle: ::kernel::vfs::handle::Dir) -> Dir {
+	fn new(path: ByteString, handle: ::kernel::vfs::handle::Dir) -> Dir {
 		Dir {
 			handle: handle,
+			path: path,
 		}
 	}
 }
@@ -250,7 +374,7 @@ impl objects::Object for Dir
 	fn class(&self) -> u16 { values::CLASS_VFS_DIR }
 	fn as_any(&self) -> &Any { self }
 	fn try_clone(&self) -> Option<u32> {
-		Some( ::objects::new_object( Dir { handle: self.handle.clone() } ) )
+		Some( ::objects::new_object( Dir::new(self.path.clone(), self.handle.clone()) ) )
 	}
 	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,Error> {
 		Ok(match call
@@ -261,9 +385,14 @@ impl objects::Object for Dir
 			let name = ::kernel::lib::byte_str::ByteStr::new(&*name);
 			log_debug!("VFS_DIR_OPENCHILD({:?})", name);
 
+			// NOTE: Looking up a child doesn't require rights (so a process can traverse down to its subtrees)
+			// - A symbolic link child isn't followed, so the tracked path is always where the node lives
+			if name.as_bytes().contains(&b'/') {
+				return Ok( super::from_result::<u32,u32>(to_result(Err( ::kernel::vfs::Error::MalformedPath ))) );
+			}
 			super::from_result(
-				to_result( self.handle.open_child(name) )
-					.map( |h| objects::new_object(Node(h)) )
+				to_result( join_path(&self.path, Path::new(name)).and_then(|p| Ok( (try!(self.handle.open_child(name)), p) )) )
+					.map( |(h,p)| objects::new_object(Node(h, p)) )
 				)
 			},
 		values::VFS_DIR_OPENPATH => {
@@ -272,12 +401,15 @@ impl objects::Object for Dir
 			let path = Path::new(&path);
 			log_debug!("VFS_DIR_OPENPATH({:?})", path);
 			super::from_result(
-				to_result( self.handle.open_child_path(path) )
-					.map( |h| objects::new_object(Node(h)) )
+				to_result( open_tracked(&self.handle, &self.path, path) )
+					.map( |(h,p)| objects::new_object(Node(h, p)) )
 				)
 			},
 		values::VFS_DIR_ENUMERATE => {
-			objects::new_object( DirIter::new( self.handle.clone() ) ) as u64
+			super::from_result(
+				to_result( check_rights(&self.path, values::FS_RIGHT_READ) )
+					.map( |_| objects::new_object( DirIter::new( self.handle.clone() ) ) )
+				)
 			},
 		_ => return ::objects::object_has_no_such_method_ref("vfs::Dir", call),
 		})
@@ -0,0 +1,192 @@
+// "Tifflin" Kernel - USB Core
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_core/descriptors.rs
+//! Standard USB descriptors
+
+pub const DEVICE       	: u8 = 1;
+pub const CONFIGURATION	: u8 = 2;
+pub const STRING       	: u8 = 3;
+pub const INTERFACE    	: u8 = 4;
+pub const ENDPOINT     	: u8 = 5;
+/// HID class descriptor
+pub const HID          	: u8 = 0x21;
+/// HID report descriptor
+pub const HID_REPORT   	: u8 = 0x22;
+/// Hub class descriptor
+pub const HUB          	: u8 = 0x29;
+
+fn u16_at(d: &[u8], ofs: usize) -> u16 {
+	d[ofs] as u16 | (d[ofs+1] as u16) << 8
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct DeviceDescriptor
+{
+	pub usb_version: u16,
+	pub class: u8,
+	pub subclass: u8,
+	pub protocol: u8,
+	pub max_packet_size0: u8,
+	pub vendor: u16,
+	pub product: u16,
+	pub device_version: u16,
+	pub manufacturer_str: u8,
+	pub product_str: u8,
+	pub serial_str: u8,
+	pub num_configurations: u8,
+}
+impl DeviceDescriptor
+{
+	pub fn from_bytes(d: &[u8]) -> Option<DeviceDescriptor> {
+		if d.len() < 18 || d[1] != DEVICE {
+			return None;
+		}
+		Some(DeviceDescriptor {
+			usb_version: u16_at(d, 2),
+			class: d[4],
+			subclass: d[5],
+			protocol: d[6],
+			max_packet_size0: d[7],
+			vendor: u16_at(d, 8),
+			product: u16_at(d, 10),
+			device_version: u16_at(d, 12),
+			manufacturer_str: d[14],
+			product_str: d[15],
+			serial_str: d[16],
+			num_configurations: d[17],
+			})
+	}
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct ConfigDescriptor
+{
+	pub total_length: u16,
+	pub num_interfaces: u8,
+	pub config_value: u8,
+	pub attributes: u8,
+	/// Maximum power draw (units of 2mA)
+	pub max_power: u8,
+}
+impl ConfigDescriptor
+{
+	pub fn from_bytes(d: &[u8]) -> Option<ConfigDescriptor> {
+		if d.len() < 9 || d[1] != CONFIGURATION {
+			return None;
+		}
+		Some(ConfigDescriptor {
+			total_length: u16_at(d, 2),
+			num_interfaces: d[4],
+			config_value: d[5],
+			attributes: d[7],
+			max_power: d[8],
+			})
+	}
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct InterfaceDescriptor
+{
+	pub number: u8,
+	pub alt_setting: u8,
+	pub num_endpoints: u8,
+	pub class: u8,
+	pub subclass: u8,
+	pub protocol: u8,
+}
+impl InterfaceDescriptor
+{
+	pub fn from_bytes(d: &[u8]) -> Option<InterfaceDescriptor> {
+		if d.len() < 9 || d[1] != INTERFACE {
+			return None;
+		}
+		Some(InterfaceDescriptor {
+			number: d[2],
+			alt_setting: d[3],
+			num_endpoints: d[4],
+			class: d[5],
+			subclass: d[6],
+			protocol: d[7],
+			})
+	}
+}
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum EndpointType
+{
+	Control,
+	Isochronous,
+	Bulk,
+	Interrupt,
+}
+#[derive(Debug,Clone,Default)]
+pub struct EndpointDescriptor
+{
+	/// Endpoint address (bit 7 set for IN endpoints)
+	pub address: u8,
+	pub attributes: u8,
+	pub max_packet_size: u16,
+	/// Polling interval (frames for low/full speed, 2^(n-1) microframes for high speed)
+	pub interval: u8,
+}
+impl EndpointDescriptor
+{
+	pub fn from_bytes(d: &[u8]) -> Option<EndpointDescriptor> {
+		if d.len() < 7 || d[1] != ENDPOINT {
+			return None;
+		}
+		Some(EndpointDescriptor {
+			address: d[2],
+			attributes: d[3],
+			max_packet_size: u16_at(d, 4),
+			interval: d[6],
+			})
+	}
+	pub fn number(&self) -> u8 {
+		self.address & 0xF
+	}
+	pub fn is_in(&self) -> bool {
+		self.address & 0x80 != 0
+	}
+	pub fn transfer_type(&self) -> EndpointType {
+		match self.attributes & 3
+		{
+		0 => EndpointType::Control,
+		1 => EndpointType::Isochronous,
+		2 => EndpointType::Bulk,
+		_ => EndpointType::Interrupt,
+		}
+	}
+	/// Maximum packet size (excluding high-bandwidth transaction bits)
+	pub fn packet_size(&self) -> usize {
+		(self.max_packet_size & 0x7FF) as usize
+	}
+}
+
+/// Iterator over a list of descriptors (e.g. a configuration descriptor), yielding (type, bytes)
+pub struct DescriptorIter<'a>(&'a [u8]);
+impl<'a> DescriptorIter<'a>
+{
+	pub fn new(data: &'a [u8]) -> DescriptorIter<'a> {
+		DescriptorIter(data)
+	}
+}
+impl<'a> Iterator for DescriptorIter<'a>
+{
+	type Item = (u8, &'a [u8]);
+	fn next(&mut self) -> Option<(u8, &'a [u8])> {
+		if self.0.len() < 2 {
+			return None;
+		}
+		let len = self.0[0] as usize;
+		if len < 2 || len > self.0.len() {
+			log_warning!("Malformed descriptor list (length {} with {} bytes remaining)", len, self.0.len());
+			self.0 = &[];
+			return None;
+		}
+		let (rv, tail) = self.0.split_at(len);
+		self.0 = tail;
+		Some( (rv[1], rv) )
+	}
+}
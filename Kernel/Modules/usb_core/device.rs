@@ -0,0 +1,417 @@
+// "Tifflin" Kernel - USB Core
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_core/device.rs
+//! Device enumeration and registry
+//!
+//! Each interface of a configured device is exposed as a device on the "usb" bus, so class drivers bind using
+//! `device_manager` (and then use `get_interface` to access the device).
+use kernel::prelude::*;
+use kernel::lib::mem::aref::{Aref,ArefBorrow};
+use kernel::sync::Mutex;
+use kernel::device_manager::{self,AttrValue};
+use host::{self,Handle,EndpointAddr,DevicePath,Speed,SetupPacket,Error,PortFeature};
+use descriptors::{self,DescriptorIter,DeviceDescriptor,ConfigDescriptor,InterfaceDescriptor,EndpointDescriptor};
+use {Host,PortId,PortControl};
+
+/// Delay between a port connection being detected and the reset (debounce, USB 2.0 7.1.7.3)
+const DEBOUNCE_MS: u64 = 100;
+/// Time to wait after a port reset completes
+const RESET_RECOVERY_MS: u64 = 10;
+/// Time to wait after SET_ADDRESS before using the new address
+const SET_ADDRESS_RECOVERY_MS: u64 = 2;
+
+/// Registry entry for an enumerated device (owned by the host)
+pub struct DeviceEntry
+{
+	port: PortId,
+	/// Addresses of the interfaces on the host's bus
+	interfaces: Vec<u32>,
+	dev: Aref<Device>,
+}
+
+/// A configured USB device
+pub struct Device
+{
+	host: ArefBorrow<Host>,
+	addr: u8,
+	path: DevicePath,
+	desc: DeviceDescriptor,
+	/// Full descriptor set of the active configuration
+	config: Vec<u8>,
+	ep0: Mutex<Handle<host::ControlEndpoint>>,
+}
+
+/// An interface of a configured device (the object bound by class drivers)
+pub struct Interface
+{
+	dev: ArefBorrow<Device>,
+	desc: InterfaceDescriptor,
+	/// Byte range of this interface's descriptors within the configuration
+	range: (usize, usize),
+}
+
+/// Obtain the USB interface for a device on the "usb" bus
+pub fn get_interface(bus_dev: &device_manager::BusDevice) -> Option<&Interface>
+{
+	match bus_dev.bus_specific()
+	{
+	Some(v) => v.downcast_ref::<Interface>(),
+	None => None,
+	}
+}
+
+pub struct UsbBusManager;
+pub static USB_BUS_MANAGER: UsbBusManager = UsbBusManager;
+impl device_manager::BusManager for UsbBusManager
+{
+	fn bus_type(&self) -> &str { "usb" }
+	fn get_attr_names(&self) -> &[&str]
+	{
+		static S_ATTR_NAMES: [&'static str; 6] = ["vendor", "product", "class", "subclass", "protocol", "interface"];
+		&S_ATTR_NAMES
+	}
+}
+
+// --------------------------------------------------------------------
+// Port state handling
+// --------------------------------------------------------------------
+
+/// Handle a status change on a port (root hub or hub device)
+///
+/// `make_path` creates the topology information for a device on this port
+pub fn handle_port_change<P: ?Sized + PortControl, F: Fn(Speed)->DevicePath>(host: &ArefBorrow<Host>, ports: &P, port: usize, id: PortId, make_path: F)
+{
+	if ports.get_port_feature(port, PortFeature::CConnection)
+	{
+		ports.clear_port_feature(port, PortFeature::CConnection);
+		if ports.get_port_feature(port, PortFeature::Connection)
+		{
+			log_debug!("{:?}: Connected", id);
+			// Any device previously on this port is gone
+			detach(host, id);
+			if !ports.get_port_feature(port, PortFeature::Power)
+			{
+				ports.set_port_feature(port, PortFeature::Power);
+			}
+			::kernel::time::sleep_ms(DEBOUNCE_MS);
+			// Enumeration continues once the reset completes
+			ports.set_port_feature(port, PortFeature::Reset);
+		}
+		else
+		{
+			log_debug!("{:?}: Disconnected", id);
+			detach(host, id);
+		}
+	}
+	if ports.get_port_feature(port, PortFeature::CReset)
+	{
+		ports.clear_port_feature(port, PortFeature::CReset);
+		if ports.get_port_feature(port, PortFeature::Reset)
+		{
+			// Still resetting
+		}
+		else if ports.get_port_feature(port, PortFeature::Enable)
+		{
+			::kernel::time::sleep_ms(RESET_RECOVERY_MS);
+			let path = make_path(ports.get_port_speed(port));
+			if let Err(e) = attach(host, id, path)
+			{
+				log_error!("{:?}: Enumeration failed - {:?}", id, e);
+				// Disable the port, so the (unconfigured) device is inaccessible
+				ports.clear_port_feature(port, PortFeature::Enable);
+			}
+		}
+		else
+		{
+			log_notice!("{:?}: Reset complete, but port not enabled", id);
+		}
+	}
+	if ports.get_port_feature(port, PortFeature::CEnable)
+	{
+		ports.clear_port_feature(port, PortFeature::CEnable);
+		// The enable bit only changes on error (e.g. babble), so treat as a disconnect
+		if !ports.get_port_feature(port, PortFeature::Enable)
+		{
+			log_notice!("{:?}: Port disabled by hardware", id);
+			detach(host, id);
+		}
+	}
+	if ports.get_port_feature(port, PortFeature::COverCurrent)
+	{
+		ports.clear_port_feature(port, PortFeature::COverCurrent);
+		log_warning!("{:?}: Over-current condition", id);
+	}
+	if ports.get_port_feature(port, PortFeature::CSuspend)
+	{
+		ports.clear_port_feature(port, PortFeature::CSuspend);
+	}
+}
+
+/// Enumerate a newly reset device on the given port
+fn attach(host: &ArefBorrow<Host>, port: PortId, path: DevicePath) -> Result<(), Error>
+{
+	let addr = match host.allocate_address()
+		{
+		Some(a) => a,
+		None => return Err(Error::NoAddress),
+		};
+	match enumerate(host, port, path, addr)
+	{
+	Ok(()) => Ok( () ),
+	Err(e) => {
+		host.driver.release_address(addr);
+		host.free_address(addr);
+		Err(e)
+		},
+	}
+}
+fn enumerate(host: &ArefBorrow<Host>, port: PortId, path: DevicePath, addr: u8) -> Result<(), Error>
+{
+	log_debug!("{:?}: Enumerating {:?} device as address {}", port, path.speed, addr);
+	host.driver.set_address(&path, addr)?;
+	::kernel::time::sleep_ms(SET_ADDRESS_RECOVERY_MS);
+
+	// Get the endpoint zero packet size (the first eight bytes of the device descriptor are always readable)
+	let mps0 = path.speed.default_mps0();
+	let mut ep0 = host.driver.init_control(EndpointAddr::new(addr, 0), mps0);
+	let mut buf = [0u8; 18];
+	host::control_read(&*ep0, SetupPacket::get_descriptor(descriptors::DEVICE, 0, 8), &mut buf[..8])?;
+	if path.speed != Speed::Super && buf[7] as usize != mps0 {
+		ep0 = host.driver.init_control(EndpointAddr::new(addr, 0), buf[7] as usize);
+	}
+
+	// Full device descriptor
+	let len = host::control_read(&*ep0, SetupPacket::get_descriptor(descriptors::DEVICE, 0, 18), &mut buf)?;
+	let desc = match DeviceDescriptor::from_bytes(&buf[..len])
+		{
+		Some(v) => v,
+		None => {
+			log_error!("{:?}: Malformed device descriptor {:?}", port, ::kernel::logging::HexDump(&buf[..len]));
+			return Err(Error::Data);
+			},
+		};
+
+	// Configuration (always the first) - the header contains the total length
+	let mut hdr = [0u8; 9];
+	let len = host::control_read(&*ep0, SetupPacket::get_descriptor(descriptors::CONFIGURATION, 0, 9), &mut hdr)?;
+	let config_hdr = match ConfigDescriptor::from_bytes(&hdr[..len])
+		{
+		Some(v) => v,
+		None => {
+			log_error!("{:?}: Malformed configuration descriptor {:?}", port, ::kernel::logging::HexDump(&hdr[..len]));
+			return Err(Error::Data);
+			},
+		};
+	let mut config = vec![0u8; config_hdr.total_length as usize];
+	let len = host::control_read(&*ep0, SetupPacket::get_descriptor(descriptors::CONFIGURATION, 0, config.len()), &mut config)?;
+	config.truncate(len);
+	host::control_write(&*ep0, SetupPacket::set_configuration(config_hdr.config_value), &[])?;
+
+	log_notice!("USB device {:04x}:{:04x} on {:?} - address {}, {:?} speed, class {:02x}/{:02x}, {} interfaces",
+		desc.vendor, desc.product, port, addr, path.speed, desc.class, desc.subclass, config_hdr.num_interfaces);
+
+	let dev = Aref::new(Device {
+		host: host.reborrow(),
+		addr: addr,
+		path: path,
+		desc: desc,
+		config: config,
+		ep0: Mutex::new(ep0),
+		});
+
+	// Expose each interface (default alternate setting only) as a bus device
+	let mut interfaces: Vec<Box<Interface>> = Vec::new();
+	{
+		let mut ofs = 0;
+		for (ty, d) in DescriptorIter::new(&dev.config)
+		{
+			if ty == descriptors::INTERFACE {
+				// The previous interface ends at the next interface descriptor
+				if let Some(last) = interfaces.last_mut() {
+					if last.range.1 == dev.config.len() {
+						last.range.1 = ofs;
+					}
+				}
+				if let Some(v) = InterfaceDescriptor::from_bytes(d) {
+					if v.alt_setting == 0 {
+						interfaces.push(Box::new(Interface {
+							dev: dev.borrow(),
+							desc: v,
+							range: (ofs + d.len(), dev.config.len()),
+							}));
+					}
+				}
+			}
+			ofs += d.len();
+		}
+	}
+
+	// Register the device before binding drivers, so a disconnect during binding finds it
+	let bus_addrs = interfaces.iter().map(|i| device_manager::BusDevice::addr(&**i)).collect();
+	host.devices.lock().push(DeviceEntry {
+		port: port,
+		interfaces: bus_addrs,
+		dev: dev,
+		});
+	for i in interfaces
+	{
+		log_debug!("Interface {}: class {:02x}/{:02x}/{:02x}", i.desc.number, i.desc.class, i.desc.subclass, i.desc.protocol);
+		host.bus.add_device(i);
+	}
+	Ok( () )
+}
+
+/// Remove the device on a port, and everything downstream of it
+pub fn detach(host: &ArefBorrow<Host>, port: PortId)
+{
+	let ent = {
+		let mut lh = host.devices.lock();
+		match lh.iter().position(|e| e.port == port)
+		{
+		Some(i) => lh.swap_remove(i),
+		None => return,
+		}
+		};
+	let addr = ent.dev.addr;
+	log_notice!("USB device {:04x}:{:04x} (address {}) removed from {:?}", ent.dev.desc.vendor, ent.dev.desc.product, addr, port);
+
+	// Devices behind this one (if it's a hub) are removed first
+	loop
+	{
+		let child = host.devices.lock().iter()
+			.filter_map(|e| match e.port { PortId::Hub(a, _) if a == addr => Some(e.port), _ => None })
+			.next();
+		match child
+		{
+		Some(p) => detach(host, p),
+		None => break,
+		}
+	}
+
+	// Remove the interfaces (drops the class drivers, releasing their borrows of the device)
+	for &a in &ent.interfaces
+	{
+		host.bus.remove_device(a);
+	}
+	host.driver.release_address(addr);
+	host.free_address(addr);
+	drop(ent);
+}
+
+// --------------------------------------------------------------------
+// Device
+// --------------------------------------------------------------------
+impl Device
+{
+	/// Controller this device is attached to
+	pub fn host(&self) -> &ArefBorrow<Host> {
+		&self.host
+	}
+	pub fn addr(&self) -> u8 {
+		self.addr
+	}
+	pub fn speed(&self) -> Speed {
+		self.path.speed
+	}
+	/// Location in the bus topology
+	pub fn path(&self) -> &DevicePath {
+		&self.path
+	}
+	pub fn descriptor(&self) -> &DeviceDescriptor {
+		&self.desc
+	}
+	/// All descriptors in the active configuration
+	pub fn config_descriptors(&self) -> DescriptorIter {
+		DescriptorIter::new(&self.config)
+	}
+
+	/// Control transfer reading data from the device
+	pub fn control_read(&self, setup: SetupPacket, buf: &mut [u8]) -> Result<usize, Error> {
+		let ep0 = self.ep0.lock();
+		host::control_read(&**ep0, setup, buf)
+	}
+	/// Control transfer sending data to the device (or with no data stage)
+	pub fn control_write(&self, setup: SetupPacket, data: &[u8]) -> Result<usize, Error> {
+		let ep0 = self.ep0.lock();
+		host::control_write(&**ep0, setup, data)
+	}
+
+	/// Start polling an interrupt IN endpoint, completions push `(waiter_idx, length)` to `waiter`
+	pub fn init_interrupt(&self, ep: &EndpointDescriptor, waiter: &'static ::kernel::sync::Queue<(usize,usize)>, waiter_idx: usize) -> Handle<host::InterruptEndpoint> {
+		let period_ms = match self.path.speed
+			{
+			Speed::Low | Speed::Full => ep.interval as usize,
+			// High-speed intervals are 2^(n-1) 125us microframes
+			Speed::High | Speed::Super => ::core::cmp::max(1, (1 << (ep.interval.saturating_sub(1) as usize)) / 8),
+			};
+		self.host.driver.init_interrupt(EndpointAddr::new(self.addr, ep.number()), ::core::cmp::max(1, period_ms), ep.packet_size(), waiter, waiter_idx)
+	}
+	pub fn init_bulk_in(&self, ep: &EndpointDescriptor) -> Handle<host::BulkEndpointIn> {
+		assert!(ep.is_in());
+		self.host.driver.init_bulk_in(EndpointAddr::new(self.addr, ep.number()), ep.packet_size())
+	}
+	pub fn init_bulk_out(&self, ep: &EndpointDescriptor) -> Handle<host::BulkEndpointOut> {
+		assert!(!ep.is_in());
+		self.host.driver.init_bulk_out(EndpointAddr::new(self.addr, ep.number()), ep.packet_size())
+	}
+}
+
+// --------------------------------------------------------------------
+// Interface
+// --------------------------------------------------------------------
+impl Interface
+{
+	pub fn device(&self) -> &Device {
+		&self.dev
+	}
+	/// Obtain a borrow of the device (for class driver instances)
+	pub fn device_ref(&self) -> ArefBorrow<Device> {
+		self.dev.reborrow()
+	}
+	pub fn descriptor(&self) -> &InterfaceDescriptor {
+		&self.desc
+	}
+	pub fn number(&self) -> u8 {
+		self.desc.number
+	}
+	/// Class-specific and endpoint descriptors for this interface
+	pub fn descriptors(&self) -> DescriptorIter {
+		DescriptorIter::new(&self.dev.config[self.range.0 .. self.range.1])
+	}
+	pub fn endpoints(&self) -> Vec<EndpointDescriptor> {
+		self.descriptors().filter_map(|(ty, d)| if ty == descriptors::ENDPOINT { EndpointDescriptor::from_bytes(d) } else { None }).collect()
+	}
+}
+impl device_manager::BusDevice for Interface
+{
+	fn addr(&self) -> u32 {
+		(self.dev.addr as u32) << 8 | self.desc.number as u32
+	}
+	fn get_attr(&self, name: &str) -> AttrValue {
+		match name
+		{
+		"vendor"   => AttrValue::U32(self.dev.desc.vendor as u32),
+		"product"  => AttrValue::U32(self.dev.desc.product as u32),
+		"class"    => AttrValue::U32(self.desc.class as u32),
+		"subclass" => AttrValue::U32(self.desc.subclass as u32),
+		"protocol" => AttrValue::U32(self.desc.protocol as u32),
+		"interface"=> AttrValue::U32(self.desc.number as u32),
+		_ => AttrValue::None,
+		}
+	}
+	fn set_attr(&mut self, _name: &str, _value: AttrValue) {
+	}
+	fn set_power(&mut self, _state: bool) {
+		// TODO: Suspend
+	}
+	fn bind_io(&mut self, _block_id: usize) -> device_manager::IOBinding {
+		panic!("USB interfaces have no IO blocks");
+	}
+	fn get_irq(&mut self, _idx: usize) -> u32 {
+		panic!("USB interfaces have no IRQs");
+	}
+	fn bus_specific(&self) -> Option<&::core::any::Any> {
+		Some(self)
+	}
+}
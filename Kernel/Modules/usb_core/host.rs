@@ -25,15 +25,169 @@ impl EndpointAddr
 	}
 }
 
+/// Device bus speed
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Speed
+{
+	Low,
+	Full,
+	High,
+	Super,
+}
+impl Speed
+{
+	/// Default maximum packet size for endpoint zero (used until the device descriptor is read)
+	pub fn default_mps0(&self) -> usize {
+		match *self
+		{
+		Speed::Low | Speed::Full => 8,
+		Speed::High => 64,
+		Speed::Super => 512,
+		}
+	}
+}
+
+/// Location of a device in the bus topology (passed to the controller when the device is addressed)
+#[derive(Clone,Debug)]
+pub struct DevicePath
+{
+	/// Root hub port number (zero-based)
+	pub root_port: u8,
+	/// Route string (a nibble per hub tier, not including the root port)
+	pub route: u32,
+	/// Device speed
+	pub speed: Speed,
+	/// Parent hub address and (one-based) port, `None` for devices on the root hub
+	pub parent: Option<(u8, u8)>,
+	/// Transaction translator (high-speed hub address and port) for low/full-speed devices behind a high-speed hub
+	pub tt: Option<(u8, u8)>,
+}
+
+/// Transfer error
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Error
+{
+	/// Endpoint returned STALL
+	Stall,
+	/// Device didn't respond
+	NoResponse,
+	/// Data error (CRC, bit stuffing, babble)
+	Data,
+	/// Controller error (out of resources, internal error)
+	Controller,
+	/// No free addresses on this bus
+	NoAddress,
+	/// Transfer didn't complete in time (and was cancelled)
+	Timeout,
+}
+/// Async results at or above this value encode an `Error`
+const RESULT_ERROR_BASE: usize = !0 - 15;
+impl Error
+{
+	/// Encode as an async result value
+	pub fn to_result(self) -> usize {
+		RESULT_ERROR_BASE + self as usize
+	}
+	/// Decode an async result value (byte count or error)
+	pub fn from_result(v: usize) -> Result<usize, Error> {
+		if v < RESULT_ERROR_BASE {
+			Ok(v)
+		}
+		else {
+			Err(match v - RESULT_ERROR_BASE
+				{
+				0 => Error::Stall,
+				1 => Error::NoResponse,
+				2 => Error::Data,
+				4 => Error::NoAddress,
+				5 => Error::Timeout,
+				_ => Error::Controller,
+				})
+		}
+	}
+}
+
+/// Control transfer setup packet
+#[derive(Copy,Clone,Debug)]
+pub struct SetupPacket
+{
+	pub req_type: u8,
+	pub request: u8,
+	pub value: u16,
+	pub index: u16,
+	pub length: u16,
+}
+pub mod request_type {
+	pub const DIR_IN     	: u8 = 0x80;
+	pub const TYPE_STANDARD	: u8 = 0x00;
+	pub const TYPE_CLASS	: u8 = 0x20;
+	pub const RECIP_DEVICE	: u8 = 0x00;
+	pub const RECIP_INTERFACE	: u8 = 0x01;
+	pub const RECIP_ENDPOINT	: u8 = 0x02;
+	pub const RECIP_OTHER	: u8 = 0x03;
+}
+pub mod request {
+	pub const GET_STATUS	: u8 = 0;
+	pub const CLEAR_FEATURE	: u8 = 1;
+	pub const SET_FEATURE	: u8 = 3;
+	pub const SET_ADDRESS	: u8 = 5;
+	pub const GET_DESCRIPTOR	: u8 = 6;
+	pub const SET_CONFIGURATION	: u8 = 9;
+	pub const SET_INTERFACE	: u8 = 11;
+}
+impl SetupPacket
+{
+	pub fn to_bytes(&self) -> [u8; 8] {
+		[
+			self.req_type, self.request,
+			self.value as u8, (self.value >> 8) as u8,
+			self.index as u8, (self.index >> 8) as u8,
+			self.length as u8, (self.length >> 8) as u8,
+			]
+	}
+	pub fn get_descriptor(ty: u8, idx: u8, len: usize) -> SetupPacket {
+		SetupPacket {
+			req_type: request_type::DIR_IN | request_type::TYPE_STANDARD | request_type::RECIP_DEVICE,
+			request: request::GET_DESCRIPTOR,
+			value: (ty as u16) << 8 | idx as u16,
+			index: 0,
+			length: len as u16,
+			}
+	}
+	pub fn set_address(addr: u8) -> SetupPacket {
+		SetupPacket {
+			req_type: request_type::TYPE_STANDARD | request_type::RECIP_DEVICE,
+			request: request::SET_ADDRESS,
+			value: addr as u16,
+			index: 0,
+			length: 0,
+			}
+	}
+	pub fn set_configuration(value: u8) -> SetupPacket {
+		SetupPacket {
+			req_type: request_type::TYPE_STANDARD | request_type::RECIP_DEVICE,
+			request: request::SET_CONFIGURATION,
+			value: value as u16,
+			index: 0,
+			length: 0,
+			}
+	}
+}
+
 pub trait InterruptEndpoint: Send + Sync
 {
 	fn get_data(&self) -> Handle<::handle::RemoteBuffer>;
 }
 //	fn tx_async<'a, 's>(&'s self, async: async::ObjectHandle, stack: async::StackPush<'a, 's>, pkt: SparsePacket) -> Result<(), Error>;
-pub trait ControlEndpoint
+/// Control endpoint
+///
+/// Operations complete with the number of bytes transferred, or an encoded `Error` (see `Error::to_result`)
+pub trait ControlEndpoint: Send + Sync
 {
 	fn out_only<'a, 's>(&'s self, async: async::ObjectHandle, stack: async::StackPush<'a, 's>, setup_data: async::WriteBufferHandle<'a, '_>, out_data: async::WriteBufferHandle<'a, '_>);
 	fn in_only<'a, 's>(&'s self, async: async::ObjectHandle, stack: async::StackPush<'a, 's>, setup_data: async::WriteBufferHandle<'a, '_>, in_buf: &'a mut [u8]);
+	/// Abort all in-progress transfers (completing them with `Error::Timeout`)
+	fn cancel(&self);
 	// The following are more interesting, `out/in` works, but `in/out` has ordering problems...
 	// - Thankfully, these patterns aren't needed?
 	//fn out_in(&self, waiter: async::WaiterHandle, out_data: async::WriteBufferHandle, in_buf: async::ReadBufferHandle);
@@ -48,11 +202,21 @@ pub trait IsochEndpoint: Send + Sync
 	/// Prepare a receive to complete in the specified frame.
 	fn recv_at<'a, 's>(&'s self, async: async::ObjectHandle, stack: async::StackPush<'a, 's>, buffer: &'a mut [u8], abs_frame: u32);
 }
-pub trait BulkEndpoint: Send + Sync
+/// Bulk OUT endpoint (completes with the number of bytes sent, or an encoded `Error`)
+pub trait BulkEndpointOut: Send + Sync
 {
-	// Start a send operation of the passed buffers
-	fn send<'a, 's>(&self, async: async::ObjectHandle, stack: async::StackPush<'a, 's>, buffer: async::WriteBufferHandle<'a, '_>);
-	fn recv<'a, 's>(&self, async: async::ObjectHandle, stack: async::StackPush<'a, 's>, buffer: &'a mut [u8]);
+	/// Start a send operation of the passed buffer
+	fn send<'a, 's>(&'s self, async: async::ObjectHandle, stack: async::StackPush<'a, 's>, buffer: async::WriteBufferHandle<'a, '_>);
+	/// Abort all in-progress transfers (completing them with `Error::Timeout`)
+	fn cancel(&self);
+}
+/// Bulk IN endpoint (completes with the number of bytes received, or an encoded `Error`)
+pub trait BulkEndpointIn: Send + Sync
+{
+	/// Start a receive into the passed buffer
+	fn recv<'a, 's>(&'s self, async: async::ObjectHandle, stack: async::StackPush<'a, 's>, buffer: &'a mut [u8]);
+	/// Abort all in-progress transfers (completing them with `Error::Timeout`)
+	fn cancel(&self);
 }
 
 pub trait HostController: Send + Sync
@@ -60,13 +224,25 @@ pub trait HostController: Send + Sync
 	///// Obtain a handle to endpoint zero
 	//fn get_control_zero(&self) -> Handle<ControlEndpoint>;
 	/// Begin polling an endpoint at the given rate (buffer used is allocated by the driver to be the interrupt endpoint's size)
-	fn init_interrupt(&self, endpoint: EndpointAddr, period_ms: usize, waiter: async::ObjectHandle) -> Handle<InterruptEndpoint>;
+	///
+	/// Each completed transfer pushes `(waiter_idx, length)` to `waiter`
+	fn init_interrupt(&self, endpoint: EndpointAddr, period_ms: usize, max_packet_size: usize, waiter: &'static ::kernel::sync::Queue<(usize,usize)>, waiter_idx: usize) -> Handle<InterruptEndpoint>;
 	/// Initialise an ichronous endpoint
 	fn init_isoch(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<IsochEndpoint>;
 	/// Initialise a control endpoint
 	fn init_control(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<ControlEndpoint>;
-	/// Initialise a bulk endpoint
-	fn init_bulk(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<BulkEndpoint>;
+	/// Initialise a bulk OUT endpoint
+	fn init_bulk_out(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<BulkEndpointOut>;
+	/// Initialise a bulk IN endpoint
+	fn init_bulk_in(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<BulkEndpointIn>;
+
+	/// Move a newly reset device (responding to address zero) to address `addr`
+	///
+	/// The controller records the device's location/speed for later endpoint initialisation. Controllers without
+	/// hardware address assignment can use `send_set_address`.
+	fn set_address(&self, path: &DevicePath, addr: u8) -> Result<(), Error>;
+	/// Release any controller state for a removed device
+	fn release_address(&self, addr: u8);
 
 
 	// Root hub maintainence
@@ -74,8 +250,88 @@ pub trait HostController: Send + Sync
 	fn set_port_feature(&self, port: usize, feature: PortFeature);
 	fn clear_port_feature(&self, port: usize, feature: PortFeature);
 	fn get_port_feature(&self, port: usize, feature: PortFeature) -> bool;
+	/// Speed of the device attached to a port (valid after reset)
+	fn get_port_speed(&self, port: usize) -> Speed {
+		if self.get_port_feature(port, PortFeature::LowSpeed) {
+			Speed::Low
+		}
+		else if self.get_port_feature(port, PortFeature::HighSpeed) {
+			Speed::High
+		}
+		else {
+			Speed::Full
+		}
+	}
 
 	/// Register a queue of (my_idx,port_num) pairs for changes to the root hub
 	fn set_root_waiter(&mut self, waiter: &'static ::kernel::sync::Queue<(usize,usize)>, my_idx: usize);
 }
 
+/// Time allowed for a control transfer (USB 2.0 section 9.2.6.4 allows up to 5s for requests with a data stage)
+const CONTROL_TIMEOUT_MS: u64 = 5000;
+/// Time allowed for a bulk transfer
+const BULK_TIMEOUT_MS: u64 = 10000;
+
+/// Run an async operation to completion on the current thread, cancelling it (using `cancel`) if it takes longer
+/// than `timeout_ms`
+///
+/// NOTE: The operation must push at least one layer to the async stack
+fn wait_sync<F, C>(timeout_ms: u64, f: F, cancel: C) -> Result<usize, Error>
+where
+	F: FnOnce(async::ObjectHandle, async::StackPush),
+	C: FnOnce()
+{
+	let mut obj = async::Object::default();
+	let handle = obj.get_handle();
+	f(handle, obj.get_stack());
+	let handles = [&obj];
+	let waiter = async::Waiter::new(&handles);
+	let res = match waiter.wait_one_until(::kernel::time::ticks() + timeout_ms)
+		{
+		Ok(res) => res,
+		Err(()) => {
+			log_notice!("USB transfer timed out after {}ms, cancelling", timeout_ms);
+			// The controller still holds the buffers, wait for the (now cancelled) transfer to complete
+			cancel();
+			waiter.wait_one()
+			},
+		};
+	match res
+	{
+	Some(res) => Error::from_result(res.result),
+	None => Err(Error::Controller),
+	}
+}
+/// Perform a control transfer with a data stage from the device, returning the number of bytes read
+pub fn control_read(ep: &ControlEndpoint, setup: SetupPacket, buf: &mut [u8]) -> Result<usize, Error>
+{
+	let mut setup_bytes = setup.to_bytes();
+	wait_sync(CONTROL_TIMEOUT_MS, |h, stack| ep.in_only(h, stack, async::WriteBufferHandle::Long(&mut setup_bytes), buf), || ep.cancel())
+}
+/// Perform a control transfer with an optional data stage to the device
+pub fn control_write(ep: &ControlEndpoint, setup: SetupPacket, data: &[u8]) -> Result<usize, Error>
+{
+	let mut setup_bytes = setup.to_bytes();
+	let mut data: Vec<u8> = data.to_owned();
+	wait_sync(CONTROL_TIMEOUT_MS, |h, stack| ep.out_only(h, stack, async::WriteBufferHandle::Long(&mut setup_bytes), async::WriteBufferHandle::Long(&mut data)), || ep.cancel())
+}
+/// Perform a bulk OUT transfer
+pub fn bulk_send(ep: &BulkEndpointOut, data: &[u8]) -> Result<usize, Error>
+{
+	let mut data: Vec<u8> = data.to_owned();
+	wait_sync(BULK_TIMEOUT_MS, |h, stack| ep.send(h, stack, async::WriteBufferHandle::Long(&mut data)), || ep.cancel())
+}
+/// Perform a bulk IN transfer
+pub fn bulk_recv(ep: &BulkEndpointIn, buf: &mut [u8]) -> Result<usize, Error>
+{
+	wait_sync(BULK_TIMEOUT_MS, |h, stack| ep.recv(h, stack, buf), || ep.cancel())
+}
+
+/// Helper for `HostController::set_address`, sends SET_ADDRESS using the controller's address zero endpoint
+pub fn send_set_address(host: &HostController, addr: u8) -> Result<(), Error>
+{
+	let ep0 = host.init_control(EndpointAddr::new(0, 0), 8);
+	control_write(&*ep0, SetupPacket::set_address(addr), &[])?;
+	Ok( () )
+}
+
@@ -1,6 +1,33 @@
+// "Tifflin" Kernel - USB Core
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_core/hub.rs
+//! USB hub devices
+//!
+//! Hubs are bound as a class driver on the "usb" bus, and their status change endpoint is fed through the
+//! core's event queue (so port handling happens on the same worker as the root hubs).
+use kernel::prelude::*;
+use kernel::lib::mem::aref::{Aref,ArefBorrow};
+use kernel::device_manager;
+use host::{self,Handle,SetupPacket,Speed,request,request_type};
+use descriptors::{self,EndpointType};
+use device::{self,Device};
+use {PortId,PortControl,Meta};
+
+/// Interface class for hubs
+const CLASS_HUB: u32 = 9;
+/// Maximum number of hub tiers (USB 2.0 4.1.1)
+const MAX_TIERS: usize = 5;
+
+/// Hub feature selectors (for the hub itself, as opposed to its ports)
+const C_HUB_LOCAL_POWER: u16 = 0;
+const C_HUB_OVER_CURRENT: u16 = 1;
+
+pub struct HubDriver;
+pub static HUB_DRIVER: HubDriver = HubDriver;
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug,Copy,Clone)]
 pub enum PortFeature
 {
 	Connection,
@@ -10,6 +37,8 @@ pub enum PortFeature
 	Reset,
 	Power,
 	LowSpeed,
+	/// High-speed device attached (status only)
+	HighSpeed,
 	CConnection = 16,
 	CEnable,
 	CSuspend,
@@ -18,4 +47,309 @@ pub enum PortFeature
 	Test,
 	Indicator,
 }
+impl PortFeature
+{
+	/// Hub class feature selector (for SET_FEATURE/CLEAR_FEATURE)
+	fn selector(&self) -> Option<u16> {
+		Some(match *self
+			{
+			PortFeature::Connection  => 0,
+			PortFeature::Enable      => 1,
+			PortFeature::Suspend     => 2,
+			PortFeature::OverCurrent => 3,
+			PortFeature::Reset       => 4,
+			PortFeature::Power       => 8,
+			PortFeature::LowSpeed    => 9,
+			PortFeature::HighSpeed   => return None,
+			PortFeature::CConnection => 16,
+			PortFeature::CEnable     => 17,
+			PortFeature::CSuspend    => 18,
+			PortFeature::COverCurrent=> 19,
+			PortFeature::CReset      => 20,
+			PortFeature::Test        => 21,
+			PortFeature::Indicator   => 22,
+			})
+	}
+	/// Bit in the port status (status in the low 16 bits, changes in the high 16)
+	fn status_mask(&self) -> u32 {
+		match *self
+		{
+		PortFeature::Connection  => 1 << 0,
+		PortFeature::Enable      => 1 << 1,
+		PortFeature::Suspend     => 1 << 2,
+		PortFeature::OverCurrent => 1 << 3,
+		PortFeature::Reset       => 1 << 4,
+		PortFeature::Power       => 1 << 8,
+		PortFeature::LowSpeed    => 1 << 9,
+		PortFeature::HighSpeed   => 1 << 10,
+		PortFeature::Test        => 1 << 11,
+		PortFeature::Indicator   => 1 << 12,
+		PortFeature::CConnection => 1 << 16,
+		PortFeature::CEnable     => 1 << 17,
+		PortFeature::CSuspend    => 1 << 18,
+		PortFeature::COverCurrent=> 1 << 19,
+		PortFeature::CReset      => 1 << 20,
+		}
+	}
+}
+
+/// A hub device (downstream of a root hub)
+pub struct HubDevice
+{
+	dev: ArefBorrow<Device>,
+	int_ep: Handle<host::InterruptEndpoint>,
+	nports: u8,
+}
+/// Driver instance for a hub, owns the hub and its entry in the event source list
+struct HubInstance
+{
+	hub: Aref<HubDevice>,
+	watch_idx: usize,
+}
+
+impl device_manager::Driver for HubDriver
+{
+	fn name(&self) -> &str {
+		"usb-hub"
+	}
+	fn bus_type(&self) -> &str {
+		"usb"
+	}
+	fn handles(&self, bus_dev: &device_manager::BusDevice) -> u32
+	{
+		if bus_dev.get_attr("class").unwrap_u32() == CLASS_HUB {
+			1
+		}
+		else {
+			0
+		}
+	}
+	fn bind(&self, bus_dev: &mut device_manager::BusDevice) -> Box<device_manager::DriverInstance+'static>
+	{
+		let iface = ::get_interface(bus_dev).expect("Hub driver bound to non-USB device");
+		let dev = iface.device_ref();
+		if depth(dev.path()) >= MAX_TIERS - 1 {
+			log_error!("Hub {}: Too deep in the topology, ignoring", dev.addr());
+			return Box::new(NullInstance);
+		}
+
+		// Hub descriptor - port count and power-on time
+		let mut hub_desc = [0u8; 9];
+		let (nports, power_on_ms) = match dev.control_read(SetupPacket {
+				req_type: request_type::DIR_IN | request_type::TYPE_CLASS | request_type::RECIP_DEVICE,
+				request: request::GET_DESCRIPTOR,
+				value: (descriptors::HUB as u16) << 8,
+				index: 0,
+				length: hub_desc.len() as u16,
+				}, &mut hub_desc)
+			{
+			Ok(len) if len >= 7 && hub_desc[1] == descriptors::HUB => (hub_desc[2], hub_desc[5] as u64 * 2),
+			Ok(len) => {
+				log_error!("Hub {}: Malformed hub descriptor {:?}", dev.addr(), ::kernel::logging::HexDump(&hub_desc[..len]));
+				return Box::new(NullInstance);
+				},
+			Err(e) => {
+				log_error!("Hub {}: Unable to read hub descriptor - {:?}", dev.addr(), e);
+				return Box::new(NullInstance);
+				},
+			};
+		log_notice!("Hub {}: {} ports, {}ms power-on delay", dev.addr(), nports, power_on_ms);
+
+		let ep = match iface.endpoints().into_iter().find(|e| e.is_in() && e.transfer_type() == EndpointType::Interrupt)
+			{
+			Some(v) => v,
+			None => {
+				log_error!("Hub {}: No status change endpoint", dev.addr());
+				return Box::new(NullInstance);
+				},
+			};
+
+		// Reserve the event source slot, so the endpoint can be created before the hub object
+		let watch_idx = {
+			let mut lh = ::WATCH_LIST.lock();
+			lh.push(None);
+			lh.len() - 1
+			};
+		let hub = Aref::new(HubDevice {
+			int_ep: dev.init_interrupt(&ep, &::EVENT_QUEUE, watch_idx),
+			dev: dev,
+			nports: nports,
+			});
+		::WATCH_LIST.lock()[watch_idx] = Some(Meta::Hub(hub.borrow()));
+
+		// Power all ports, the hub reports connections (as status changes) once power is stable
+		for port in 1 ..= nports as usize
+		{
+			hub.set_port_feature(port, PortFeature::Power);
+		}
+		::kernel::time::sleep_ms(power_on_ms);
+
+		Box::new(HubInstance {
+			hub: hub,
+			watch_idx: watch_idx,
+			})
+	}
+}
 
+/// Driver instance used when a hub can't be initialised
+struct NullInstance;
+impl device_manager::DriverInstance for NullInstance
+{
+}
+
+impl device_manager::DriverInstance for HubInstance
+{
+}
+impl Drop for HubInstance
+{
+	fn drop(&mut self)
+	{
+		log_debug!("Hub {}: Removed", self.hub.dev.addr());
+		// Remove the event source (releasing the borrow), any queued events for it are ignored.
+		// NOTE: Slots aren't reused, so stale events can't be misdirected to a new source.
+		::WATCH_LIST.lock()[self.watch_idx] = None;
+		// Devices behind this hub have already been detached (see `device::detach`), and dropping the hub object
+		// stops the status change polling.
+	}
+}
+
+impl HubDevice
+{
+	/// Handle data from the status change endpoint (a bitmap of changed ports, bit 0 is the hub itself)
+	pub fn handle_int(&self, _size: usize)
+	{
+		let data_handle = self.int_ep.get_data();
+		let data = data_handle.get();
+		log_trace!("Hub {}: Status change {:?}", self.dev.addr(), ::kernel::logging::HexDump(data));
+		if data.len() > 0 && data[0] & 1 != 0
+		{
+			self.handle_hub_change();
+		}
+		for port in 1 ..= self.nports as usize
+		{
+			if port / 8 < data.len() && data[port / 8] & (1 << (port % 8)) != 0
+			{
+				let hub_addr = self.dev.addr();
+				let hub_path = self.dev.path();
+				device::handle_port_change(self.dev.host(), self, port, PortId::Hub(hub_addr, port as u8), |speed| host::DevicePath {
+					root_port: hub_path.root_port,
+					route: hub_path.route | ((::core::cmp::min(port, 15) as u32) << (4 * depth(hub_path))),
+					speed: speed,
+					parent: Some( (hub_addr, port as u8) ),
+					// Low/full-speed devices behind a high-speed hub use its transaction translator
+					tt: if hub_path.speed == Speed::High && speed != Speed::High { Some( (hub_addr, port as u8) ) } else { hub_path.tt },
+					});
+			}
+		}
+	}
+
+	/// Handle a change in the hub's own status (local power or over-current)
+	fn handle_hub_change(&self)
+	{
+		let mut buf = [0u8; 4];
+		let status = match self.dev.control_read(SetupPacket {
+				req_type: request_type::DIR_IN | request_type::TYPE_CLASS | request_type::RECIP_DEVICE,
+				request: request::GET_STATUS,
+				value: 0,
+				index: 0,
+				length: 4,
+				}, &mut buf)
+			{
+			Ok(4) => buf[0] as u32 | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24,
+			_ => return,
+			};
+		if status & (1 << 16) != 0 {
+			log_notice!("Hub {}: Local power {}", self.dev.addr(), if status & 1 != 0 { "lost" } else { "good" });
+			self.hub_clear_feature(C_HUB_LOCAL_POWER);
+		}
+		if status & (1 << 17) != 0 {
+			if status & 2 != 0 {
+				log_warning!("Hub {}: Over-current condition", self.dev.addr());
+			}
+			self.hub_clear_feature(C_HUB_OVER_CURRENT);
+		}
+	}
+	fn hub_clear_feature(&self, feature: u16)
+	{
+		let rv = self.dev.control_write(SetupPacket {
+				req_type: request_type::TYPE_CLASS | request_type::RECIP_DEVICE,
+				request: request::CLEAR_FEATURE,
+				value: feature,
+				index: 0,
+				length: 0,
+				}, &[]);
+		if let Err(e) = rv {
+			log_warning!("Hub {}: Clearing hub feature {} failed - {:?}", self.dev.addr(), feature, e);
+		}
+	}
+
+	/// Read the port status (status in the low 16 bits, changes in the high 16)
+	fn get_port_status(&self, port: usize) -> u32
+	{
+		let mut buf = [0u8; 4];
+		match self.dev.control_read(SetupPacket {
+				req_type: request_type::DIR_IN | request_type::TYPE_CLASS | request_type::RECIP_OTHER,
+				request: request::GET_STATUS,
+				value: 0,
+				index: port as u16,
+				length: 4,
+				}, &mut buf)
+		{
+		Ok(4) => buf[0] as u32 | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24,
+		Ok(n) => { log_warning!("Hub {}: Short port status ({} bytes)", self.dev.addr(), n); 0 },
+		Err(e) => { log_warning!("Hub {}: Error reading port {} status - {:?}", self.dev.addr(), port, e); 0 },
+		}
+	}
+	fn port_request(&self, req: u8, port: usize, feature: PortFeature)
+	{
+		let sel = match feature.selector()
+			{
+			Some(v) => v,
+			None => return,
+			};
+		let rv = self.dev.control_write(SetupPacket {
+				req_type: request_type::TYPE_CLASS | request_type::RECIP_OTHER,
+				request: req,
+				value: sel,
+				index: port as u16,
+				length: 0,
+				}, &[]);
+		if let Err(e) = rv {
+			log_warning!("Hub {}: Request {} on port {} ({:?}) failed - {:?}", self.dev.addr(), req, port, feature, e);
+		}
+	}
+}
+impl PortControl for HubDevice
+{
+	fn set_port_feature(&self, port: usize, feature: PortFeature) {
+		self.port_request(request::SET_FEATURE, port, feature)
+	}
+	fn clear_port_feature(&self, port: usize, feature: PortFeature) {
+		self.port_request(request::CLEAR_FEATURE, port, feature)
+	}
+	fn get_port_feature(&self, port: usize, feature: PortFeature) -> bool {
+		self.get_port_status(port) & feature.status_mask() != 0
+	}
+	fn get_port_speed(&self, port: usize) -> Speed {
+		let s = self.get_port_status(port);
+		if s & PortFeature::LowSpeed.status_mask() != 0 {
+			Speed::Low
+		}
+		else if s & PortFeature::HighSpeed.status_mask() != 0 {
+			Speed::High
+		}
+		else {
+			Speed::Full
+		}
+	}
+}
+
+/// Number of hub tiers between the root hub and this device
+fn depth(path: &host::DevicePath) -> usize
+{
+	let mut d = 0;
+	while d < MAX_TIERS && path.route >> (4 * d) != 0 {
+		d += 1;
+	}
+	d
+}
@@ -1,68 +1,89 @@
-// 
+//
 //! USB Core
 #![no_std]
 #![feature(linkage)]	// for module_define!
 use kernel::prelude::*;
 use kernel::lib::mem::aref::{Aref,ArefBorrow};
+use kernel::sync::Mutex;
 
 #[macro_use]
 extern crate kernel;
 extern crate stack_dst;
 
-module_define!{usb_core, [], init}
+module_define!{usb_core, [DeviceManager], init}
 
 fn init()
 {
 	// Start the worker, then forget the handle
 	::core::mem::forget( ::kernel::threads::WorkerThread::new("USB Hubs", worker_thread) );
+	::kernel::device_manager::register_driver(&hub::HUB_DRIVER);
 }
 
 mod hub;
 pub mod host;
 pub mod handle;
+pub mod descriptors;
+mod device;
+
+pub use device::{Device, Interface, get_interface};
 
 enum Meta
 {
-	RootHub(Aref<Host>),
-	Hub(HubDevice),
+	RootHub(ArefBorrow<Host>),
+	Hub(ArefBorrow<hub::HubDevice>),
 }
 
-struct Host
+/// A registered host controller (and its root hub)
+pub struct Host
 {
 	driver: Box<host::HostController>,
-	next_id: u8,
-	used_ids: [u8; 128/8],
+	/// Handle to this controller's instance of the "usb" bus
+	bus: ::kernel::device_manager::BusHandle,
+	used_ids: Mutex<[u8; 128/8]>,
+	/// Enumerated devices on this controller
+	devices: Mutex<Vec<device::DeviceEntry>>,
+}
 
-	//// If true, EP0 is currently being enumerated
-	//endpoint_zero_state: bool,
+/// Identifies the port a device is attached to
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum PortId
+{
+	/// Root hub port (zero-based)
+	Root(u8),
+	/// Port on a hub device (hub address, one-based port)
+	Hub(u8, u8),
 }
-struct HubDevice
+
+/// Port state access, common to root hubs and hub devices
+pub trait PortControl
 {
-	host: ArefBorrow<Host>,
-	int_ep: host::Handle<host::InterruptEndpoint>,
+	fn set_port_feature(&self, port: usize, feature: host::PortFeature);
+	fn clear_port_feature(&self, port: usize, feature: host::PortFeature);
+	fn get_port_feature(&self, port: usize, feature: host::PortFeature) -> bool;
+	fn get_port_speed(&self, port: usize) -> host::Speed;
 }
-///// An endpoint currently being enumerated
-//struct EnumeratingDevice
-//{
-//	obj: async::Object,
-//}
 
-static WATCH_LIST: ::kernel::sync::Mutex<Vec<Meta>> = ::kernel::sync::Mutex::new(Vec::new_const());
+static HOSTS: Mutex<Vec<Aref<Host>>> = Mutex::new(Vec::new_const());
+/// Sources for the event queue, indexed by the first value of each event (entries are `None` once removed)
+static WATCH_LIST: Mutex<Vec<Option<Meta>>> = Mutex::new(Vec::new_const());
 static EVENT_QUEUE: ::kernel::sync::Queue<(usize, usize)> = ::kernel::sync::Queue::new_const();
-//static ENUM_ENDPOINTS: ::kernel::sync::Mutex<Vec<Box<Endpoint>>> = ::kernel::sync::Mutex::new(Vec::new_const());
 
 pub fn register_host(mut h: Box<host::HostController>)
 {
+	let bus = ::kernel::device_manager::register_bus(&device::USB_BUS_MANAGER, vec![]);
 	let mut lh = WATCH_LIST.lock();
 	let idx = lh.len();
 	// Note: Setting the root waiter should trigger event pushes for any connected port
 	// - This doesn't race, because the list lock is still held.
 	h.set_root_waiter(&EVENT_QUEUE, idx);
-	lh.push(Meta::RootHub(Aref::new(Host {
+	let host = Aref::new(Host {
 		driver: h,
-		next_id: 1,
-		used_ids: [0; 128/8],
-		})));
+		bus: bus,
+		used_ids: Mutex::new([0; 128/8]),
+		devices: Mutex::new(Vec::new()),
+		});
+	lh.push(Some(Meta::RootHub(host.borrow())));
+	HOSTS.lock().push(host);
 }
 
 fn worker_thread()
@@ -74,95 +95,72 @@ fn worker_thread()
 		// This needs to check:
 		// - Root hub changes (when signalled by the HCD)
 		// - Interrupt reponses from hub devices
-		let lh = WATCH_LIST.lock();
-		match lh[idx]
+		// NOTE: The list lock isn't held while handling, as enumeration can add/remove entries
+		let ent = match WATCH_LIST.lock().get(idx)
+			{
+			Some(&Some(Meta::RootHub(ref h))) => Meta::RootHub(h.reborrow()),
+			Some(&Some(Meta::Hub(ref h))) => Meta::Hub(h.reborrow()),
+			_ => {
+				log_debug!("Event for removed source {} ({})", idx, data);
+				continue ;
+				},
+			};
+		match ent
 		{
-		Meta::RootHub(ref h) => h.handle_root_event(data),
+		Meta::RootHub(ref h) => handle_root_event(h, data),
 		Meta::Hub(ref h) => h.handle_int(data),
 		}
 	}
 }
-//fn enum_worker_thread()
-//{
-//	loop
-//	{
-//		// 1. Register sleeps on new endpoints
-//		// - Get device descriptor
-//		// - Enumerate available configurations
-//	}
-//}
+
+fn handle_root_event(h: &ArefBorrow<Host>, port_idx: usize)
+{
+	log_debug!("handle_root_event: ({})", port_idx);
+	device::handle_port_change(h, &**h, port_idx, PortId::Root(port_idx as u8), |speed| host::DevicePath {
+		root_port: port_idx as u8,
+		route: 0,
+		speed: speed,
+		parent: None,
+		tt: None,
+		});
+}
 
 impl Host
 {
-	fn handle_root_event(&self, port_idx: usize)
+	/// Allocate an unused device address (1-127)
+	fn allocate_address(&self) -> Option<u8>
 	{
-		log_debug!("handle_root_event: ({})", port_idx);
-		// TODO: Support timing port updates by using large values of `port_idx`
-		if self.driver.get_port_feature(port_idx, host::PortFeature::CConnection)
+		let mut lh = self.used_ids.lock();
+		for addr in 1 .. 128
 		{
-			self.driver.clear_port_feature(port_idx, host::PortFeature::CConnection);
-			if self.driver.get_port_feature(port_idx, host::PortFeature::Connection)
+			if lh[addr / 8] & (1 << (addr % 8)) == 0
 			{
-				// Connection detected, either:
-				// - Trigger a reset (and record this port as the next EP0)
-				// - OR, add to a list of to-be-enumerated ports (if EP0 is already in use)
-				if self.driver.get_port_feature(port_idx, host::PortFeature::Power)
-				{
-					//if self.endpoint_zero_in_use {
-					//}
-					//else {
-					//	self.endpoint_zero_in_use = true;
-						self.driver.set_port_feature(port_idx, host::PortFeature::Reset);
-					//}
-				}
-				else
-				{
-					todo!("Power on a newly connected port");
-				}
-			}
-			else
-			{
-				// Was disconnected, need to eliminate all downstream devices
-				// - Requires knowing what devices are on this port.
-				todo!("Handle port disconnection");
+				lh[addr / 8] |= 1 << (addr % 8);
+				return Some(addr as u8);
 			}
 		}
-		else if self.driver.get_port_feature(port_idx, host::PortFeature::CReset)
-		{
-			self.driver.clear_port_feature(port_idx, host::PortFeature::CReset);
-			if self.driver.get_port_feature(port_idx, host::PortFeature::Reset)
-			{
-			}
-			else if self.driver.get_port_feature(port_idx, host::PortFeature::Enable)
-			{
-				// Allocate an ID, allocate a , send the 'set device ID' request
-				todo!("Push new device to enumeration");
-			}
-			else
-			{
-				// Reset complete, but not enabled?
-				todo!("Handle port completing reset, but not being enabled?");
-			}
-		}
-		else if self.driver.get_port_feature(port_idx, host::PortFeature::CEnable)
-		{
-			self.driver.clear_port_feature(port_idx, host::PortFeature::CEnable);
-			log_debug!("Change in enable status...");
-		}
-		else
-		{
-		}
+		None
+	}
+	fn free_address(&self, addr: u8)
+	{
+		let addr = addr as usize;
+		let mut lh = self.used_ids.lock();
+		assert!(lh[addr / 8] & (1 << (addr % 8)) != 0, "Freeing unallocated USB address {}", addr);
+		lh[addr / 8] &= !(1 << (addr % 8));
 	}
 }
-
-impl HubDevice
+impl PortControl for Host
 {
-	fn handle_int(&self, _size: usize)
-	{
-		let data_handle = self.int_ep.get_data();
-		let data = data_handle.get();
-		todo!("Process interrupt bytes from host - {:?}", ::kernel::logging::HexDump(data));
+	fn set_port_feature(&self, port: usize, feature: host::PortFeature) {
+		self.driver.set_port_feature(port, feature)
+	}
+	fn clear_port_feature(&self, port: usize, feature: host::PortFeature) {
+		self.driver.clear_port_feature(port, feature)
+	}
+	fn get_port_feature(&self, port: usize, feature: host::PortFeature) -> bool {
+		self.driver.get_port_feature(port, feature)
+	}
+	fn get_port_speed(&self, port: usize) -> host::Speed {
+		self.driver.get_port_speed(port)
 	}
 }
-
-
@@ -0,0 +1,89 @@
+// "Tifflin" Kernel - USB HID Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_hid/keyboard.rs
+//! Boot protocol keyboards
+use gui::input::keyboard as gui_keyboard;
+use gui::input::keyboard::KeyCode;
+
+/// First modifier usage (LeftCtrl), the modifier byte has one bit per key from here
+const MODIFIER_BASE: u8 = 0xE0;
+
+pub struct Keyboard
+{
+	guidev: gui_keyboard::Instance,
+	modifiers: u8,
+	keys: [u8; 6],
+}
+
+impl Keyboard
+{
+	pub fn new() -> Keyboard {
+		Keyboard {
+			guidev: gui_keyboard::Instance::new(),
+			modifiers: 0,
+			keys: [0; 6],
+			}
+	}
+
+	/// Handle a boot protocol report - modifier bitmap, reserved byte, then up to six pressed key usages
+	pub fn handle_report(&mut self, data: &[u8])
+	{
+		if data.len() < 8 {
+			log_debug!("Short keyboard report ({} bytes)", data.len());
+			return ;
+		}
+		let modifiers = data[0];
+		let keys = &data[2..8];
+		// Too many keys pressed, the state is unknown (so leave it unchanged)
+		if keys.iter().any(|&k| k == KeyCode::ErrorRollover as u8) {
+			return ;
+		}
+
+		let changed = modifiers ^ self.modifiers;
+		for i in 0 .. 8
+		{
+			if changed & (1 << i) != 0
+			{
+				self.update(KeyCode::from(MODIFIER_BASE + i), modifiers & (1 << i) == 0);
+			}
+		}
+		for &k in self.keys.iter().filter(|&&k| k != 0 && !keys.contains(&k))
+		{
+			if let Some(key) = get_keycode(k) {
+				self.update(key, true);
+			}
+		}
+		for &k in keys.iter().filter(|&&k| k != 0 && !self.keys.contains(&k))
+		{
+			match get_keycode(k)
+			{
+			Some(key) => self.update(key, false),
+			None => log_debug!("Unknown key usage {:#x}", k),
+			}
+		}
+
+		self.modifiers = modifiers;
+		self.keys.copy_from_slice(keys);
+	}
+
+	fn update(&self, key: KeyCode, release: bool) {
+		if release {
+			self.guidev.release_key(key);
+		}
+		else {
+			self.guidev.press_key(key);
+		}
+	}
+}
+
+/// Convert a key usage into a `KeyCode` (which uses the same values), if it's in range
+fn get_keycode(usage: u8) -> Option<KeyCode>
+{
+	if usage <= KeyCode::Oper as u8 || (MODIFIER_BASE <= usage && usage <= KeyCode::RightGui as u8) {
+		Some(KeyCode::from(usage))
+	}
+	else {
+		None
+	}
+}
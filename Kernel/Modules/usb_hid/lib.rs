@@ -0,0 +1,243 @@
+// "Tifflin" Kernel - USB HID Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_hid/lib.rs
+//! USB Human Interface Devices (keyboards, mice and tablets)
+#![no_std]
+#![feature(linkage)]	// for module_define!
+use kernel::prelude::*;
+use kernel::lib::mem::aref::{Aref,ArefBorrow};
+use kernel::sync::Mutex;
+use kernel::device_manager;
+use usb_core::host::{self,SetupPacket,request_type};
+use usb_core::descriptors::{self,EndpointType};
+
+#[macro_use]
+extern crate kernel;
+extern crate usb_core;
+extern crate gui;
+
+module_define!{usb_hid, [usb_core, GUI], init}
+
+mod keyboard;
+mod mouse;
+mod report;
+
+fn init()
+{
+	// Start the worker, then forget the handle
+	::core::mem::forget( ::kernel::threads::WorkerThread::new("USB HID", worker_thread) );
+	::kernel::device_manager::register_driver(&HID_DRIVER);
+}
+
+const CLASS_HID: u32 = 3;
+const SUBCLASS_BOOT: u8 = 1;
+const PROTOCOL_KEYBOARD: u8 = 1;
+const PROTOCOL_MOUSE: u8 = 2;
+
+/// HID class requests
+mod request {
+	pub const SET_IDLE	: u8 = 0x0A;
+	pub const SET_PROTOCOL	: u8 = 0x0B;
+}
+
+struct HidDriver;
+static HID_DRIVER: HidDriver = HidDriver;
+
+/// Active devices, indexed by the first value of each event
+static DEVICES: Mutex<Vec<Option<ArefBorrow<HidDevice>>>> = Mutex::new(Vec::new_const());
+static EVENT_QUEUE: ::kernel::sync::Queue<(usize, usize)> = ::kernel::sync::Queue::new_const();
+
+struct HidDevice
+{
+	int_ep: host::Handle<host::InterruptEndpoint>,
+	handler: Mutex<Handler>,
+}
+enum Handler
+{
+	Keyboard(keyboard::Keyboard),
+	Pointer(mouse::Pointer),
+}
+struct Instance
+{
+	_dev: Aref<HidDevice>,
+	slot: usize,
+}
+struct NullInstance;
+
+impl device_manager::Driver for HidDriver
+{
+	fn name(&self) -> &str {
+		"usb-hid"
+	}
+	fn bus_type(&self) -> &str {
+		"usb"
+	}
+	fn handles(&self, bus_dev: &device_manager::BusDevice) -> u32
+	{
+		if bus_dev.get_attr("class").unwrap_u32() == CLASS_HID {
+			1
+		}
+		else {
+			0
+		}
+	}
+	fn bind(&self, bus_dev: &mut device_manager::BusDevice) -> Box<device_manager::DriverInstance+'static>
+	{
+		let iface = ::usb_core::get_interface(bus_dev).expect("HID driver bound to non-USB device");
+		let dev = iface.device();
+		let ep = match iface.endpoints().into_iter().find(|e| e.is_in() && e.transfer_type() == EndpointType::Interrupt)
+			{
+			Some(v) => v,
+			None => {
+				log_error!("HID {}/{}: No interrupt IN endpoint", dev.addr(), iface.number());
+				return Box::new(NullInstance);
+				},
+			};
+
+		let handler = match (iface.descriptor().subclass, iface.descriptor().protocol)
+			{
+			(SUBCLASS_BOOT, PROTOCOL_KEYBOARD) => {
+				log_notice!("HID {}/{}: Boot keyboard", dev.addr(), iface.number());
+				class_request(iface, request::SET_PROTOCOL, 0);
+				// Only report on change
+				class_request(iface, request::SET_IDLE, 0);
+				Handler::Keyboard(keyboard::Keyboard::new())
+				},
+			(SUBCLASS_BOOT, PROTOCOL_MOUSE) => {
+				log_notice!("HID {}/{}: Boot mouse", dev.addr(), iface.number());
+				class_request(iface, request::SET_PROTOCOL, 0);
+				Handler::Pointer(mouse::Pointer::new(report::PointerLayout::boot_mouse()))
+				},
+			_ => {
+				// Not a boot device, the report descriptor describes the format
+				let layout = match get_report_descriptor(iface)
+					{
+					Some(rd) => report::PointerLayout::parse(&rd),
+					None => None,
+					};
+				match layout
+				{
+				Some(l) => {
+					log_notice!("HID {}/{}: Pointer ({})", dev.addr(), iface.number(), if l.is_absolute() { "absolute" } else { "relative" });
+					Handler::Pointer(mouse::Pointer::new(l))
+					},
+				None => {
+					log_notice!("HID {}/{}: Unsupported device {}/{}", dev.addr(), iface.number(),
+						iface.descriptor().subclass, iface.descriptor().protocol);
+					return Box::new(NullInstance);
+					},
+				}
+				},
+			};
+
+		// Reserve the event slot, so the endpoint can be created before the device object
+		let slot = {
+			let mut lh = DEVICES.lock();
+			lh.push(None);
+			lh.len() - 1
+			};
+		let hid = Aref::new(HidDevice {
+			int_ep: dev.init_interrupt(&ep, &EVENT_QUEUE, slot),
+			handler: Mutex::new(handler),
+			});
+		DEVICES.lock()[slot] = Some(hid.borrow());
+		Box::new(Instance {
+			_dev: hid,
+			slot: slot,
+			})
+	}
+}
+
+impl device_manager::DriverInstance for Instance
+{
+}
+impl Drop for Instance
+{
+	fn drop(&mut self)
+	{
+		// NOTE: The worker holds the list lock while handling reports, so the borrow isn't in use after this
+		DEVICES.lock()[self.slot] = None;
+	}
+}
+impl device_manager::DriverInstance for NullInstance
+{
+}
+
+fn worker_thread()
+{
+	loop
+	{
+		let (idx, len) = EVENT_QUEUE.wait_pop();
+		// Handling is short (just passing events to the GUI), so is done with the list locked
+		let lh = DEVICES.lock();
+		match lh.get(idx)
+		{
+		Some(&Some(ref dev)) => dev.handle_report(len),
+		_ => log_debug!("Report for removed device {} ({} bytes)", idx, len),
+		}
+	}
+}
+
+impl HidDevice
+{
+	fn handle_report(&self, len: usize)
+	{
+		let data_handle = self.int_ep.get_data();
+		let data = data_handle.get();
+		let data = &data[.. ::core::cmp::min(len, data.len())];
+		log_trace!("handle_report: {:?}", ::kernel::logging::HexDump(data));
+		match *self.handler.lock()
+		{
+		Handler::Keyboard(ref mut k) => k.handle_report(data),
+		Handler::Pointer(ref mut p) => p.handle_report(data),
+		}
+	}
+}
+
+/// Send a class request (with no data stage) to an interface
+fn class_request(iface: &::usb_core::Interface, request: u8, value: u16)
+{
+	let rv = iface.device().control_write(SetupPacket {
+		req_type: request_type::TYPE_CLASS | request_type::RECIP_INTERFACE,
+		request: request,
+		value: value,
+		index: iface.number() as u16,
+		length: 0,
+		}, &[]);
+	if let Err(e) = rv {
+		// Optional requests (e.g. SET_IDLE) are allowed to stall
+		log_debug!("HID {}/{}: Request {:#x} failed - {:?}", iface.device().addr(), iface.number(), request, e);
+	}
+}
+
+/// Read the report descriptor (length is taken from the HID descriptor)
+fn get_report_descriptor(iface: &::usb_core::Interface) -> Option<Vec<u8>>
+{
+	let len = match iface.descriptors().find(|&(ty, _)| ty == descriptors::HID)
+		{
+		Some((_, d)) if d.len() >= 9 && d[6] == descriptors::HID_REPORT => d[7] as usize | (d[8] as usize) << 8,
+		_ => {
+			log_notice!("HID {}/{}: No report descriptor listed", iface.device().addr(), iface.number());
+			return None;
+			},
+		};
+	let mut buf = vec![0u8; len];
+	match iface.device().control_read(SetupPacket {
+			req_type: request_type::DIR_IN | request_type::TYPE_STANDARD | request_type::RECIP_INTERFACE,
+			request: host::request::GET_DESCRIPTOR,
+			value: (descriptors::HID_REPORT as u16) << 8,
+			index: iface.number() as u16,
+			length: len as u16,
+			}, &mut buf)
+	{
+	Ok(l) => {
+		buf.truncate(l);
+		Some(buf)
+		},
+	Err(e) => {
+		log_error!("HID {}/{}: Unable to read report descriptor - {:?}", iface.device().addr(), iface.number(), e);
+		None
+		},
+	}
+}
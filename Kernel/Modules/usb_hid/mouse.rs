@@ -0,0 +1,80 @@
+// "Tifflin" Kernel - USB HID Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_hid/mouse.rs
+//! Pointing devices (mice and tablets)
+use gui::input::mouse as gui_mouse;
+use report::PointerLayout;
+
+pub struct Pointer
+{
+	layout: PointerLayout,
+	guidev: gui_mouse::Instance,
+	btns: u32,
+}
+
+impl Pointer
+{
+	pub fn new(layout: PointerLayout) -> Pointer {
+		Pointer {
+			layout: layout,
+			guidev: gui_mouse::Instance::new(),
+			btns: 0,
+			}
+	}
+
+	pub fn handle_report(&mut self, data: &[u8])
+	{
+		let data = match self.layout.report_id
+			{
+			Some(id) if data.first() == Some(&id) => &data[1..],
+			// Not the pointer report
+			Some(_) => return,
+			None => data,
+			};
+
+		// Position
+		if let (&Some(ref xf), &Some(ref yf)) = (&self.layout.x, &self.layout.y)
+		{
+			if let (Some(x), Some(y)) = (xf.read(data), yf.read(data))
+			{
+				if xf.absolute
+				{
+					self.guidev.set_cursor_abs(xf.scale(x), yf.scale(y));
+				}
+				else if x != 0 || y != 0
+				{
+					self.guidev.move_cursor(clamp_i16(x), clamp_i16(y));
+				}
+			}
+		}
+		// TODO: Scroll wheel (`self.layout.wheel`), once the GUI supports it
+
+		// Buttons
+		let mut btns = 0;
+		for &(idx, ref f) in &self.layout.buttons
+		{
+			if f.read(data).map(|v| v != 0).unwrap_or(false) {
+				btns |= 1 << idx;
+			}
+		}
+		let changed = btns ^ self.btns;
+		for i in 0 .. 16
+		{
+			if changed & (1 << i) != 0
+			{
+				if btns & (1 << i) != 0 {
+					self.guidev.press_button(i);
+				}
+				else {
+					self.guidev.release_button(i);
+				}
+			}
+		}
+		self.btns = btns;
+	}
+}
+
+fn clamp_i16(v: i32) -> i16 {
+	::core::cmp::max(-0x8000, ::core::cmp::min(v, 0x7FFF)) as i16
+}
@@ -0,0 +1,265 @@
+// "Tifflin" Kernel - USB HID Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_hid/report.rs
+//! Report descriptor parsing (enough to locate pointer fields)
+use kernel::prelude::*;
+
+/// Usages (page in the high 16 bits)
+const USAGE_X: u32 = 0x0001_0030;
+const USAGE_Y: u32 = 0x0001_0031;
+const USAGE_WHEEL: u32 = 0x0001_0038;
+const USAGE_BUTTON_1: u32 = 0x0009_0001;
+const MAX_BUTTONS: u32 = 16;
+
+/// A value within a report
+#[derive(Clone,Debug)]
+pub struct Field
+{
+	/// Bit offset (after the report ID)
+	pub offset: usize,
+	/// Size in bits
+	pub size: usize,
+	pub absolute: bool,
+	pub min: i32,
+	pub max: i32,
+}
+impl Field
+{
+	fn new(offset: usize, size: usize, absolute: bool, min: i32, max: i32) -> Field {
+		Field { offset: offset, size: size, absolute: absolute, min: min, max: max }
+	}
+
+	/// Extract the value from a report (sign-extended if the logical range is signed)
+	pub fn read(&self, data: &[u8]) -> Option<i32> {
+		if self.size == 0 || self.size > 32 || (self.offset + self.size + 7) / 8 > data.len() {
+			return None;
+		}
+		let mut v: u32 = 0;
+		for i in 0 .. self.size
+		{
+			let bit = self.offset + i;
+			if data[bit / 8] & (1 << (bit % 8)) != 0 {
+				v |= 1 << i;
+			}
+		}
+		if self.min < 0 && self.size < 32 && v & (1 << (self.size - 1)) != 0 {
+			v |= !0u32 << self.size;
+		}
+		Some(v as i32)
+	}
+	/// Scale an absolute value to 0 - 0xFFFF
+	pub fn scale(&self, v: i32) -> u16 {
+		if self.max <= self.min {
+			0
+		}
+		else {
+			let v = ::core::cmp::max(self.min, ::core::cmp::min(v, self.max));
+			((v - self.min) as u64 * 0xFFFF / (self.max - self.min) as u64) as u16
+		}
+	}
+}
+
+/// Location of the pointer values within an input report
+#[derive(Debug)]
+pub struct PointerLayout
+{
+	/// Report ID of the pointer report (if the device uses report IDs)
+	pub report_id: Option<u8>,
+	pub x: Option<Field>,
+	pub y: Option<Field>,
+	pub wheel: Option<Field>,
+	/// Buttons (zero-based index, and field)
+	pub buttons: Vec<(u8, Field)>,
+}
+impl PointerLayout
+{
+	/// Fixed layout of the boot protocol mouse report (buttons, X, Y)
+	pub fn boot_mouse() -> PointerLayout {
+		PointerLayout {
+			report_id: None,
+			x: Some(Field::new(8, 8, false, -127, 127)),
+			y: Some(Field::new(16, 8, false, -127, 127)),
+			wheel: None,
+			buttons: (0 .. 3).map(|i| (i as u8, Field::new(i, 1, true, 0, 1))).collect(),
+			}
+	}
+
+	pub fn is_absolute(&self) -> bool {
+		self.x.as_ref().map(|f| f.absolute).unwrap_or(false)
+	}
+
+	/// Parse a report descriptor, returning the layout of the first report containing X and Y
+	pub fn parse(desc: &[u8]) -> Option<PointerLayout>
+	{
+		let mut parser = Parser::default();
+		let mut pos = 0;
+		while pos < desc.len()
+		{
+			let prefix = desc[pos];
+			// Long items (not used by any defined item tags) are skipped
+			if prefix == 0xFE {
+				if pos + 1 >= desc.len() {
+					break;
+				}
+				pos += 3 + desc[pos+1] as usize;
+				continue ;
+			}
+			let size = match prefix & 3 { 3 => 4, n => n as usize };
+			if pos + 1 + size > desc.len() {
+				log_warning!("Truncated report descriptor item at {}", pos);
+				break;
+			}
+			let data = &desc[pos+1 .. pos+1+size];
+			pos += 1 + size;
+			parser.item(prefix >> 4, (prefix >> 2) & 3, data);
+		}
+
+		// Only keep fields from the report containing X/Y
+		let report = match parser.fields.iter().find(|&&(_, usage, _)| usage == USAGE_X)
+			{
+			Some(&(r, _, _)) => r,
+			None => return None,
+			};
+		let mut rv = PointerLayout {
+			report_id: if parser.uses_ids { Some(report) } else { None },
+			x: None,
+			y: None,
+			wheel: None,
+			buttons: Vec::new(),
+			};
+		for (r, usage, f) in parser.fields
+		{
+			if r != report {
+				continue ;
+			}
+			match usage
+			{
+			USAGE_X => if rv.x.is_none() { rv.x = Some(f) },
+			USAGE_Y => if rv.y.is_none() { rv.y = Some(f) },
+			USAGE_WHEEL => if rv.wheel.is_none() { rv.wheel = Some(f) },
+			u if USAGE_BUTTON_1 <= u && u < USAGE_BUTTON_1 + MAX_BUTTONS => rv.buttons.push( ((u - USAGE_BUTTON_1) as u8, f) ),
+			_ => {},
+			}
+		}
+		if rv.y.is_none() {
+			return None;
+		}
+		Some(rv)
+	}
+}
+
+#[derive(Default)]
+struct Parser
+{
+	// Global state
+	usage_page: u32,
+	logical_min: i32,
+	logical_max: i32,
+	report_size: usize,
+	report_count: usize,
+	report_id: u8,
+	uses_ids: bool,
+	// Local state (cleared after each main item)
+	usages: Vec<u32>,
+	usage_min: Option<u32>,
+	usage_max: Option<u32>,
+	/// Current bit offset for each report ID
+	offsets: Vec<(u8, usize)>,
+	/// Input fields (report ID, usage, field)
+	fields: Vec<(u8, u32, Field)>,
+}
+impl Parser
+{
+	fn item(&mut self, tag: u8, ty: u8, data: &[u8])
+	{
+		let uval = data.iter().rev().fold(0u32, |a, &b| a << 8 | b as u32);
+		let sval = match data.len()
+			{
+			0 => 0,
+			1 => data[0] as i8 as i32,
+			2 => uval as u16 as i16 as i32,
+			_ => uval as i32,
+			};
+		// Usages without a page use the current page
+		let usage = if data.len() == 4 { uval } else { self.usage_page << 16 | uval };
+		match ty
+		{
+		// Main
+		0 => {
+			if tag == 0x8 {
+				self.input(uval);
+			}
+			// All main items (input, output, feature, collections) clear the local state
+			self.usages.clear();
+			self.usage_min = None;
+			self.usage_max = None;
+			},
+		// Global
+		1 => match tag
+			{
+			0x0 => self.usage_page = uval,
+			0x1 => self.logical_min = sval,
+			// Logical maximum is unsigned if the minimum is positive
+			0x2 => self.logical_max = if self.logical_min >= 0 { uval as i32 } else { sval },
+			0x7 => self.report_size = uval as usize,
+			0x8 => { self.report_id = uval as u8; self.uses_ids = true; },
+			0x9 => self.report_count = uval as usize,
+			0xA | 0xB => log_notice!("TODO: Report descriptor push/pop"),
+			_ => {},
+			},
+		// Local
+		2 => match tag
+			{
+			0x0 => self.usages.push(usage),
+			0x1 => self.usage_min = Some(usage),
+			0x2 => self.usage_max = Some(usage),
+			_ => {},
+			},
+		_ => {},
+		}
+	}
+
+	fn input(&mut self, flags: u32)
+	{
+		let report_id = self.report_id;
+		let base = match self.offsets.iter().position(|&(id, _)| id == report_id)
+			{
+			Some(i) => i,
+			None => { self.offsets.push( (report_id, 0) ); self.offsets.len() - 1 },
+			};
+		let ofs = self.offsets[base].1;
+		self.offsets[base].1 += self.report_size * self.report_count;
+
+		let is_const = flags & 1 != 0;
+		let is_variable = flags & 2 != 0;
+		let is_relative = flags & 4 != 0;
+		// Only variable (non-array) data fields are used
+		if is_const || !is_variable {
+			return ;
+		}
+		for i in 0 .. self.report_count
+		{
+			let usage = match self.usage_at(i)
+				{
+				Some(u) => u,
+				None => continue,
+				};
+			let f = Field::new(ofs + i * self.report_size, self.report_size, !is_relative, self.logical_min, self.logical_max);
+			self.fields.push( (report_id, usage, f) );
+		}
+	}
+	fn usage_at(&self, idx: usize) -> Option<u32>
+	{
+		if !self.usages.is_empty() {
+			// Extra values use the last usage
+			Some(self.usages[::core::cmp::min(idx, self.usages.len() - 1)])
+		}
+		else if let (Some(min), Some(max)) = (self.usage_min, self.usage_max) {
+			if min as usize + idx <= max as usize { Some(min + idx as u32) } else { None }
+		}
+		else {
+			None
+		}
+	}
+}
@@ -0,0 +1,166 @@
+// "Tifflin" Kernel - USB Mass Storage Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_msc/lib.rs
+//! USB Mass Storage (SCSI command set, bulk-only transport)
+#![no_std]
+#![feature(linkage)]	// for module_define!
+use kernel::prelude::*;
+use kernel::lib::mem::Arc;
+use kernel::device_manager;
+use kernel::metadevs::storage;
+use usb_core::host::{SetupPacket,request_type};
+use usb_core::descriptors::EndpointType;
+
+#[macro_use]
+extern crate kernel;
+extern crate usb_core;
+extern crate storage_scsi;
+
+module_define!{usb_msc, [usb_core, Storage], init}
+
+mod transport;
+
+fn init()
+{
+	::kernel::device_manager::register_driver(&MSC_DRIVER);
+}
+
+const CLASS_MASS_STORAGE: u32 = 8;
+/// SCSI transparent command set
+const SUBCLASS_SCSI: u32 = 6;
+/// Bulk-only transport
+const PROTOCOL_BBB: u32 = 0x50;
+
+/// Class request - Get Max LUN
+const REQ_GET_MAX_LUN: u8 = 0xFE;
+
+struct MscDriver;
+static MSC_DRIVER: MscDriver = MscDriver;
+
+struct Instance
+{
+	volumes: Vec<storage::PhysicalVolumeReg>,
+	transport: Arc<transport::Transport>,
+}
+struct NullInstance;
+
+/// SCSI interface for a single LUN
+struct LunInterface
+{
+	name: String,
+	transport: Arc<transport::Transport>,
+	lun: u8,
+}
+
+impl device_manager::Driver for MscDriver
+{
+	fn name(&self) -> &str {
+		"usb-msc"
+	}
+	fn bus_type(&self) -> &str {
+		"usb"
+	}
+	fn handles(&self, bus_dev: &device_manager::BusDevice) -> u32
+	{
+		if bus_dev.get_attr("class").unwrap_u32() != CLASS_MASS_STORAGE {
+			0
+		}
+		else if bus_dev.get_attr("subclass").unwrap_u32() == SUBCLASS_SCSI && bus_dev.get_attr("protocol").unwrap_u32() == PROTOCOL_BBB {
+			1
+		}
+		else {
+			log_notice!("USB mass storage with unsupported subclass/protocol {}/{:#x}",
+				bus_dev.get_attr("subclass").unwrap_u32(), bus_dev.get_attr("protocol").unwrap_u32());
+			0
+		}
+	}
+	fn bind(&self, bus_dev: &mut device_manager::BusDevice) -> Box<device_manager::DriverInstance+'static>
+	{
+		let iface = ::usb_core::get_interface(bus_dev).expect("MSC driver bound to non-USB device");
+		let dev = iface.device();
+		let name = format!("usb{}i{}", dev.addr(), iface.number());
+
+		let eps = iface.endpoints();
+		let (ep_in, ep_out) = match ( eps.iter().find(|e| e.is_in() && e.transfer_type() == EndpointType::Bulk), eps.iter().find(|e| !e.is_in() && e.transfer_type() == EndpointType::Bulk) )
+			{
+			(Some(i), Some(o)) => (i.clone(), o.clone()),
+			_ => {
+				log_error!("{}: Missing bulk endpoints", name);
+				return Box::new(NullInstance);
+				},
+			};
+
+		// Number of LUNs (devices with only one LUN may stall this request)
+		let mut max_lun = [0u8];
+		let max_lun = match dev.control_read(SetupPacket {
+				req_type: request_type::DIR_IN | request_type::TYPE_CLASS | request_type::RECIP_INTERFACE,
+				request: REQ_GET_MAX_LUN,
+				value: 0,
+				index: iface.number() as u16,
+				length: 1,
+				}, &mut max_lun)
+			{
+			Ok(1) => ::core::cmp::min(max_lun[0], 15),
+			_ => 0,
+			};
+		log_notice!("{}: Mass storage, {} LUN(s)", name, max_lun as usize + 1);
+
+		let transport = Arc::new(transport::Transport::new(iface, ep_in, ep_out));
+		let mut volumes = Vec::new();
+		for lun in 0 ..= max_lun
+		{
+			let int = LunInterface {
+				name: format!("{}l{}", name, lun),
+				transport: transport.clone(),
+				lun: lun,
+				};
+			match ::storage_scsi::Volume::new_boxed(int)
+			{
+			Ok(vol) => volumes.push( storage::register_pv(vol) ),
+			Err(e) => log_error!("{}: LUN {} failed to initialise - {:?}", name, lun, e),
+			}
+		}
+
+		Box::new(Instance {
+			volumes: volumes,
+			transport: transport,
+			})
+	}
+}
+
+impl device_manager::DriverInstance for Instance
+{
+}
+impl Drop for Instance
+{
+	fn drop(&mut self)
+	{
+		// Deregister the volumes, then detach the transport from the device (the volumes may still be referenced by
+		// in-flight requests, which will then fail)
+		self.volumes.clear();
+		self.transport.detach();
+	}
+}
+impl device_manager::DriverInstance for NullInstance
+{
+}
+
+impl ::storage_scsi::ScsiInterface for LunInterface
+{
+	fn name(&self) -> &str {
+		&self.name
+	}
+	fn send<'a>(&'a self, command: &[u8], data: &'a [u8]) -> storage::AsyncIoResult<'a,()>
+	{
+		use kernel::async::NullResultWaiter;
+		let rv = self.transport.command(self.lun, command, transport::Data::Out(data));
+		Box::new( NullResultWaiter::new(move || rv) )
+	}
+	fn recv<'a>(&'a self, command: &[u8], data: &'a mut [u8]) -> storage::AsyncIoResult<'a,()>
+	{
+		use kernel::async::NullResultWaiter;
+		let rv = self.transport.command(self.lun, command, transport::Data::In(data));
+		Box::new( NullResultWaiter::new(move || rv) )
+	}
+}
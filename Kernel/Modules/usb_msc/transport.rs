@@ -0,0 +1,272 @@
+// "Tifflin" Kernel - USB Mass Storage Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_msc/transport.rs
+//! Bulk-only transport (command/data/status over a pair of bulk endpoints)
+use kernel::prelude::*;
+use kernel::lib::mem::aref::ArefBorrow;
+use kernel::sync::Mutex;
+use kernel::metadevs::storage::IoError;
+use usb_core::host::{self,Handle,SetupPacket,request_type};
+use usb_core::descriptors::EndpointDescriptor;
+use storage_scsi::proto::SenseKey;
+
+const CBW_SIGNATURE: u32 = 0x43425355;	// "USBC"
+const CSW_SIGNATURE: u32 = 0x53425355;	// "USBS"
+const CBW_SIZE: usize = 31;
+const CSW_SIZE: usize = 13;
+const CBW_FLAG_IN: u8 = 0x80;
+
+/// Class request - Bulk-Only Mass Storage Reset
+const REQ_RESET: u8 = 0xFF;
+/// Standard feature selector - ENDPOINT_HALT
+const FEATURE_ENDPOINT_HALT: u16 = 0;
+
+/// Number of times a command is retried after a UNIT ATTENTION
+const UNIT_ATTENTION_RETRIES: usize = 3;
+
+/// Data stage of a command
+pub enum Data<'a>
+{
+	Out(&'a [u8]),
+	In(&'a mut [u8]),
+}
+
+pub struct Transport
+{
+	/// `None` once the device has been removed
+	inner: Mutex<Option<Inner>>,
+}
+struct Inner
+{
+	dev: ArefBorrow<::usb_core::Device>,
+	iface: u8,
+	ep_in_desc: EndpointDescriptor,
+	ep_out_desc: EndpointDescriptor,
+	ep_in: Handle<host::BulkEndpointIn>,
+	ep_out: Handle<host::BulkEndpointOut>,
+	tag: u32,
+}
+
+/// Result of a single command/data/status transaction
+enum Status
+{
+	Passed,
+	Failed,
+}
+
+impl Transport
+{
+	pub fn new(iface: &::usb_core::Interface, ep_in: EndpointDescriptor, ep_out: EndpointDescriptor) -> Transport
+	{
+		let dev = iface.device_ref();
+		Transport {
+			inner: Mutex::new(Some(Inner {
+				ep_in: dev.init_bulk_in(&ep_in),
+				ep_out: dev.init_bulk_out(&ep_out),
+				ep_in_desc: ep_in,
+				ep_out_desc: ep_out,
+				iface: iface.number(),
+				dev: dev,
+				tag: 1,
+				})),
+			}
+	}
+
+	/// Release the device (waits for any active command to complete, further commands fail)
+	pub fn detach(&self)
+	{
+		*self.inner.lock() = None;
+	}
+
+	/// Execute a SCSI command
+	pub fn command(&self, lun: u8, cmd: &[u8], mut data: Data) -> Result<(), IoError>
+	{
+		let mut lh = self.inner.lock();
+		let inner = match *lh
+			{
+			Some(ref mut v) => v,
+			None => return Err(IoError::Removed),
+			};
+		for _ in 0 .. UNIT_ATTENTION_RETRIES
+		{
+			match inner.transaction(lun, cmd, &mut data)
+			{
+			Ok(Status::Passed) => return Ok( () ),
+			Ok(Status::Failed) => {},
+			Err(e) => {
+				inner.reset_recovery();
+				return Err(e);
+				},
+			}
+
+			// Command failed, get the reason
+			let mut sense = [0u8; 18];
+			match inner.transaction(lun, &[0x03, 0, 0, 0, sense.len() as u8, 0], &mut Data::In(&mut sense))
+			{
+			Ok(Status::Passed) => {},
+			Ok(Status::Failed) => return Err(IoError::Unknown("REQUEST SENSE failed")),
+			Err(e) => {
+				inner.reset_recovery();
+				return Err(e);
+				},
+			}
+			let key = SenseKey::from(sense[2] & 0xF);
+			log_debug!("Command {:#x} failed: sense {:?} ASC={:#x} ASCQ={:#x}", cmd[0], key, sense[12], sense[13]);
+			return Err(match key
+				{
+				// Media change/reset, retry the command
+				SenseKey::UnitAttention => continue,
+				_ => ::storage_scsi::error_from_sense(key, sense[12], sense[13]).unwrap_or(IoError::Unknown("SCSI check condition")),
+				});
+		}
+		Err(IoError::Unknown("Repeated UNIT ATTENTION"))
+	}
+}
+
+impl Inner
+{
+	/// Perform a command/data/status transaction
+	///
+	/// Errors require reset recovery
+	fn transaction(&mut self, lun: u8, cmd: &[u8], data: &mut Data) -> Result<Status, IoError>
+	{
+		assert!(cmd.len() <= 16);
+		let tag = self.tag;
+		self.tag = self.tag.wrapping_add(1);
+
+		// Command
+		let (len, flags) = match *data
+			{
+			Data::Out(ref d) => (d.len(), 0),
+			Data::In(ref d) => (d.len(), CBW_FLAG_IN),
+			};
+		let mut cbw = [0u8; CBW_SIZE];
+		write_u32(&mut cbw[0..], CBW_SIGNATURE);
+		write_u32(&mut cbw[4..], tag);
+		write_u32(&mut cbw[8..], len as u32);
+		cbw[12] = flags;
+		cbw[13] = lun;
+		cbw[14] = cmd.len() as u8;
+		cbw[15 .. 15 + cmd.len()].copy_from_slice(cmd);
+		match host::bulk_send(&*self.ep_out, &cbw)
+		{
+		Ok(CBW_SIZE) => {},
+		Ok(n) => {
+			log_error!("Short CBW write ({} bytes)", n);
+			return Err(IoError::Unknown("USB transfer error"));
+			},
+		Err(e) => return Err(map_error(e)),
+		}
+
+		// Data (a stall here ends the data stage, the status is still read)
+		if len > 0
+		{
+			let rv = match *data
+				{
+				Data::Out(ref d) => host::bulk_send(&*self.ep_out, d),
+				Data::In(ref mut d) => host::bulk_recv(&*self.ep_in, d),
+				};
+			match rv
+			{
+			Ok(_) => {},
+			Err(host::Error::Stall) => {
+				let is_in = flags & CBW_FLAG_IN != 0;
+				log_debug!("Data stage stalled (in={})", is_in);
+				self.clear_halt(is_in);
+				},
+			Err(e) => return Err(map_error(e)),
+			}
+		}
+
+		// Status (retried once after clearing a stall)
+		let mut csw = [0u8; CSW_SIZE];
+		let csw_len = match host::bulk_recv(&*self.ep_in, &mut csw)
+			{
+			Err(host::Error::Stall) => {
+				self.clear_halt(true);
+				host::bulk_recv(&*self.ep_in, &mut csw).map_err(map_error)?
+				},
+			rv @ _ => rv.map_err(map_error)?,
+			};
+		if csw_len != CSW_SIZE || read_u32(&csw[0..]) != CSW_SIGNATURE || read_u32(&csw[4..]) != tag {
+			log_error!("Invalid CSW {:?} (tag {})", ::kernel::logging::HexDump(&csw[..csw_len]), tag);
+			return Err(IoError::Unknown("Invalid CSW"));
+		}
+		let residue = read_u32(&csw[8..]);
+		match csw[12]
+		{
+		0 => {
+			if residue != 0 {
+				log_debug!("Command {:#x} passed with residue {}", cmd[0], residue);
+			}
+			Ok(Status::Passed)
+			},
+		1 => Ok(Status::Failed),
+		_ => {
+			log_error!("Phase error on command {:#x}", cmd[0]);
+			Err(IoError::Unknown("Phase error"))
+			},
+		}
+	}
+
+	/// Bulk-only reset recovery (USB MSC BOT 5.3.4)
+	fn reset_recovery(&mut self)
+	{
+		log_notice!("Device {}: Reset recovery", self.dev.addr());
+		let rv = self.dev.control_write(SetupPacket {
+				req_type: request_type::TYPE_CLASS | request_type::RECIP_INTERFACE,
+				request: REQ_RESET,
+				value: 0,
+				index: self.iface as u16,
+				length: 0,
+				}, &[]);
+		if let Err(e) = rv {
+			log_error!("Device {}: Mass storage reset failed - {:?}", self.dev.addr(), e);
+		}
+		self.clear_halt(true);
+		self.clear_halt(false);
+	}
+
+	/// Clear a halt condition on one of the bulk endpoints
+	fn clear_halt(&mut self, is_in: bool)
+	{
+		let addr = if is_in { self.ep_in_desc.address } else { self.ep_out_desc.address };
+		let rv = self.dev.control_write(SetupPacket {
+				req_type: request_type::TYPE_STANDARD | request_type::RECIP_ENDPOINT,
+				request: host::request::CLEAR_FEATURE,
+				value: FEATURE_ENDPOINT_HALT,
+				index: addr as u16,
+				length: 0,
+				}, &[]);
+		if let Err(e) = rv {
+			log_error!("Device {}: CLEAR_FEATURE(ENDPOINT_HALT) on {:#x} failed - {:?}", self.dev.addr(), addr, e);
+		}
+		// Clearing the halt resets the data toggle, so the controller's endpoint state is re-created
+		if is_in {
+			self.ep_in = self.dev.init_bulk_in(&self.ep_in_desc);
+		}
+		else {
+			self.ep_out = self.dev.init_bulk_out(&self.ep_out_desc);
+		}
+	}
+}
+
+fn map_error(e: host::Error) -> IoError
+{
+	match e
+	{
+	host::Error::NoResponse => IoError::Timeout,
+	_ => IoError::Unknown("USB transfer error"),
+	}
+}
+
+fn write_u32(dst: &mut [u8], v: u32) {
+	dst[0] = v as u8;
+	dst[1] = (v >> 8) as u8;
+	dst[2] = (v >> 16) as u8;
+	dst[3] = (v >> 24) as u8;
+}
+fn read_u32(src: &[u8]) -> u32 {
+	src[0] as u32 | (src[1] as u32) << 8 | (src[2] as u32) << 16 | (src[3] as u32) << 24
+}
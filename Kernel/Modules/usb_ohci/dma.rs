@@ -0,0 +1,137 @@
+// "Tifflin" Kernel - OHCI USB driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_ohci/dma.rs
+//! DMA buffer preparation (32-bit addressing and bounce buffers)
+use kernel::prelude::*;
+use kernel::_async3 as async;
+use kernel::memory::virt::AllocHandle;
+use usb_core::host::Error;
+
+/// Region of a buffer covered by a single general TD (spans at most two pages)
+pub struct TdRange
+{
+	/// Physical address of the first byte
+	pub first: u32,
+	/// Physical address of the last byte
+	pub last: u32,
+	pub len: usize,
+}
+
+/// A buffer prepared for DMA - either the caller's memory, or a bounce buffer below 4GB
+pub struct DmaBuffer
+{
+	bounce: Option<AllocHandle>,
+	ptr: *const u8,
+	len: usize,
+}
+impl DmaBuffer
+{
+	/// Prepare a buffer to be sent to a device
+	pub fn new_write(buffer: async::WriteBufferHandle) -> Result<DmaBuffer, Error>
+	{
+		match buffer
+		{
+		async::WriteBufferHandle::Long(p) =>
+			if is_addressable(p) {
+				Ok(DmaBuffer { bounce: None, ptr: p.as_ptr(), len: p.len() })
+			}
+			else {
+				DmaBuffer::bounce_from(p)
+			},
+		// Short-lived buffers are only valid for the duration of the call, so must be copied
+		async::WriteBufferHandle::Short(p) => DmaBuffer::bounce_from(p),
+		}
+	}
+	/// Prepare a buffer to receive data from a device (`copy_to` must be called on completion)
+	pub fn new_read(buffer: &mut [u8]) -> Result<DmaBuffer, Error>
+	{
+		if is_addressable(buffer) {
+			Ok(DmaBuffer { bounce: None, ptr: buffer.as_ptr(), len: buffer.len() })
+		}
+		else {
+			let h = alloc_bounce(buffer.len())?;
+			Ok(DmaBuffer { ptr: h.as_ref::<u8>(0), bounce: Some(h), len: buffer.len() })
+		}
+	}
+	fn bounce_from(data: &[u8]) -> Result<DmaBuffer, Error>
+	{
+		let mut h = alloc_bounce(data.len())?;
+		h.as_mut_slice::<u8>(0, data.len()).copy_from_slice(data);
+		Ok(DmaBuffer { ptr: h.as_ref::<u8>(0), bounce: Some(h), len: data.len() })
+	}
+
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Split the buffer into TD regions
+	///
+	/// Each region spans at most two pages, and all but the last are a multiple of the packet size (so that a short
+	/// packet always ends the transfer).
+	pub fn td_ranges(&self, max_packet_size: usize) -> Vec<TdRange>
+	{
+		let mps = ::core::cmp::max(1, max_packet_size);
+		let mut rv = Vec::new();
+		let mut ofs = 0;
+		while ofs < self.len
+		{
+			let first = phys32(self.ptr as usize + ofs);
+			// - The rest of the first page, and all of the next
+			let mut len = ::core::cmp::min(self.len - ofs, 0x2000 - (first & 0xFFF) as usize);
+			if ofs + len < self.len {
+				len -= len % mps;
+			}
+			rv.push(TdRange {
+				first: first,
+				last: phys32(self.ptr as usize + ofs + len - 1),
+				len: len,
+				});
+			ofs += len;
+		}
+		rv
+	}
+
+	/// Copy received data out of the bounce buffer (if one was used)
+	///
+	/// UNSAFE: `dst` must be valid for `len` bytes
+	pub unsafe fn copy_to(&self, dst: *mut u8, len: usize)
+	{
+		if self.bounce.is_some() {
+			::core::ptr::copy_nonoverlapping(self.ptr, dst, ::core::cmp::min(len, self.len));
+		}
+	}
+}
+
+fn alloc_bounce(len: usize) -> Result<AllocHandle, Error>
+{
+	let pages = ::core::cmp::max(1, (len + ::kernel::PAGE_SIZE - 1) / ::kernel::PAGE_SIZE);
+	match ::kernel::memory::virt::alloc_dma(32, pages, "usb_ohci")
+	{
+	Ok(v) => Ok(v),
+	Err(e) => {
+		log_warning!("Unable to allocate {} byte bounce buffer: {:?}", len, e);
+		Err(Error::Controller)
+		},
+	}
+}
+
+/// Check that every page of a buffer is addressable by the controller (below 4GB)
+fn is_addressable(p: &[u8]) -> bool
+{
+	let mut ofs = 0;
+	while ofs < p.len()
+	{
+		let phys = ::kernel::memory::virt::get_phys(&p[ofs]) as u64;
+		if phys > 0xFFFF_FFFF {
+			return false;
+		}
+		ofs += 0x1000 - (phys & 0xFFF) as usize;
+	}
+	true
+}
+
+fn phys32(addr: usize) -> u32
+{
+	::kernel::memory::virt::get_phys(addr as *const u8) as u32
+}
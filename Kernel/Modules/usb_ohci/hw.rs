@@ -96,7 +96,6 @@ pub struct GeneralTD
 {
 	/// Flags
 	//  0:17 = AVAIL
-	//       > 0: Allocated bit (1 when allocated)
 	// 18    = Buffer Rounding (Allow an undersized packet)
 	// 19:20 = Direction (SETUP, OUT, IN, Resvd)
 	// 21:23 = Delay Interrupt (Frame count, 7 = no int)
@@ -114,14 +113,74 @@ pub struct GeneralTD
 	/// Address of final byte in buffer
 	// - Note, this can be in a different page to the base address to a maximum of two
 	pub buffer_end: u32,
+}
 
-	// -- Acess Information
-	pub meta_async_handle: u64,
-	_meta_unused: u64,
+/// An isochronous transfer descriptor (32-byte aligned)
+#[repr(C)]
+pub struct IsochronousTD
+{
+	//  0:15 = Starting Frame
+	// 21:23 = Delay Interrupt
+	// 24:26 = Frame Count (minus one)
+	// 28:31 = Condition Code
+	pub flags: u32,
+	/// Physical page of the first byte (low 12 bits ignored)
+	pub buffer_page0: u32,
+	pub next_td: u32,
+	pub buffer_end: u32,
+	/// Packet offsets (before completion) / Packet status words (after)
+	// - Offset:  0:11 = Offset,  12 = Page select (BufferPage0/BufferEnd), 13:15 = Condition code (NotAccessed)
+	// - Status:  0:10 = Size, 12:15 = Condition code
+	pub psw: [u16; 8],
+}
+
+/// Driver metadata stored alongside each transfer descriptor
+#[repr(C)]
+pub struct TdMeta
+{
+	/// Async handle (or interrupt endpoint index) signalled on completion
+	pub handle: u64,
+	/// Length of this TD's buffer
+	pub len: u32,
+	/// Bytes transferred by earlier TDs of the transfer (only used on the final TD)
+	pub accum: u32,
+	/// Non-zero when allocated
+	pub allocated: u32,
+	pub flags: u8,
+	/// Endpoint the TD is queued on (group, index)
+	pub ed: [u8; 2],
+	/// Final TD of the transfer (group, index)
+	pub final_td: [u8; 2],
+	/// Interrupt endpoint buffer slot
+	pub buf_slot: u8,
+	_pad: [u8; 6],
+}
+/// Size of a transfer descriptor slot (hardware descriptor, then metadata)
+pub const TD_SLOT_SIZE: usize = 64;
+/// Offset of the metadata within a TD slot
+pub const TD_META_OFS: usize = 32;
+
+/// Condition codes (completion status of a TD)
+#[allow(dead_code)]
+pub mod cc {
+	pub const NO_ERROR	: u8 = 0x0;
+	pub const CRC	: u8 = 0x1;
+	pub const BIT_STUFFING	: u8 = 0x2;
+	pub const DATA_TOGGLE_MISMATCH	: u8 = 0x3;
+	pub const STALL	: u8 = 0x4;
+	pub const DEVICE_NOT_RESPONDING	: u8 = 0x5;
+	pub const PID_CHECK_FAILURE	: u8 = 0x6;
+	pub const UNEXPECTED_PID	: u8 = 0x7;
+	pub const DATA_OVERRUN	: u8 = 0x8;
+	pub const DATA_UNDERRUN	: u8 = 0x9;
+	pub const BUFFER_OVERRUN	: u8 = 0xC;
+	pub const BUFFER_UNDERRUN	: u8 = 0xD;
+	pub const NOT_ACCESSED	: u8 = 0xF;
 }
 
 // 32 * 16  = 512 bytes long
 /// Structure of part of the HCCA (but NOT specified by the hardware, just suggested)
+#[repr(C)]
 pub struct IntLists
 {
 	/// 16ms polling periods
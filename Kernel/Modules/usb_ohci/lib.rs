@@ -6,7 +6,8 @@
 use kernel::prelude::*;
 use kernel::_async3 as async;
 use kernel::lib::mem::aref::{Aref,ArefBorrow};
-use core::sync::atomic::{AtomicPtr,AtomicUsize,Ordering};
+use kernel::memory::virt::AllocHandle;
+use core::sync::atomic::{AtomicPtr,AtomicUsize,AtomicU32,Ordering};
 
 #[macro_use]
 extern crate kernel;
@@ -14,6 +15,7 @@ extern crate usb_core;
 
 mod hw;
 mod pci;
+mod dma;
 
 module_define!{usb_ohci, [usb_core], init}
 
@@ -23,6 +25,50 @@ fn init()
 	::kernel::device_manager::register_driver(&PCI_DRIVER);
 }
 
+/// Number of additional pages that can be allocated for each descriptor type
+const MAX_POOLS: usize = 8;
+/// Endpoint descriptors in the HCCA page (after the HCCA and the interrupt lists)
+const HCCA_ED_FIRST: usize = 768 / 16;
+const HCCA_ED_END: usize = 2048 / 16;
+/// Transfer descriptor slots in the HCCA page
+const HCCA_TD_FIRST: usize = 2048 / hw::TD_SLOT_SIZE;
+const HCCA_TD_END: usize = 4096 / hw::TD_SLOT_SIZE;
+/// Index of the first interrupt list placeholder in the HCCA page (see `hw::IntLists`)
+const INT_LIST_FIRST: usize = 256 / 16;
+/// Index (relative to `INT_LIST_FIRST`) of the 1ms placeholder
+const INT_LIST_1MS: usize = 30;
+/// Maximum number of active interrupt endpoints on one controller
+const MAX_INTERRUPT_EPS: usize = 32;
+/// Number of packet buffers used by each interrupt endpoint
+const INT_BUFFER_SLOTS: usize = 4;
+
+// Software-defined bits in the endpoint descriptor flags
+/// Descriptor is allocated
+const ED_ALLOCATED: u32 = 1 << 31;
+/// List the endpoint is on
+const ED_LIST_MASK: u32 = 3 << 27;
+const ED_LIST_CONTROL: u32 = 0 << 27;
+const ED_LIST_BULK: u32 = 1 << 27;
+const ED_LIST_PERIODIC: u32 = 2 << 27;
+
+// Transfer descriptor flags
+const TD_SETUP: u32 = 0b00 << 19;
+const TD_OUT: u32 = 0b01 << 19;
+const TD_IN: u32 = 0b10 << 19;
+const TD_ROUNDING: u32 = 1 << 18;
+const TD_DATA0: u32 = 0b10 << 24;
+const TD_DATA1: u32 = 0b11 << 24;
+
+// Transfer descriptor metadata flags
+/// Last TD of a transfer, signals the async handle
+const TDF_FINAL: u8 = 1 << 0;
+/// Bytes transferred aren't included in the result (SETUP and status stages)
+const TDF_NOCOUNT: u8 = 1 << 1;
+/// Interrupt endpoint poll (handle is an index into `HostInner::interrupt_eps`)
+const TDF_INTERRUPT: u8 = 1 << 2;
+/// Isochronous TD
+const TDF_ISOCH: u8 = 1 << 3;
+
 struct BusDev
 {
 	host: Aref<HostInner>,
@@ -35,28 +81,106 @@ struct HostInner
 {
 	io: IoWrapper,
 	irq_handle: Option<::kernel::irqs::ObjectHandle>,
-	hcca_handle: ::kernel::memory::virt::AllocHandle,
+	hcca_handle: AllocHandle,
+	hcca_phys: u32,
 	nports: u8,
 	waiter_idx: AtomicUsize,
 	waiter_ptr: AtomicPtr<::kernel::sync::Queue<(usize,usize)>>,
+	/// Bitmap of device addresses that are low-speed (set in the endpoint descriptors)
+	low_speed: ::kernel::sync::Spinlock<[u8; 128/8]>,
+
+	/// Extra pages of endpoint descriptors (group N is `ed_pools[N-1]`)
+	ed_pools: [PoolPage; MAX_POOLS],
+	/// Extra pages of transfer descriptors
+	td_pools: [PoolPage; MAX_POOLS],
+	/// Owning handles for the pool pages (also serialises pool allocation)
+	pool_handles: ::kernel::sync::Mutex<Vec<AllocHandle>>,
+
+	/// Lock over all modifications to the endpoint lists and TD queues (also taken by the IRQ handler)
+	schedule: ::kernel::sync::Spinlock<Schedule>,
+	/// Active interrupt endpoints (indexed by the handle value in their TDs)
+	interrupt_eps: ::kernel::sync::Spinlock<[Option<InterruptState>; MAX_INTERRUPT_EPS]>,
 }
 struct IoWrapper(::kernel::device_manager::IOBinding);
 
+/// A page of descriptors allocated once the HCCA page is exhausted
+#[derive(Default)]
+struct PoolPage
+{
+	/// Virtual address (zero if not yet allocated)
+	virt: AtomicUsize,
+	phys: AtomicUsize,
+}
+/// Periodic schedule state
+struct Schedule
+{
+	/// Number of interrupt endpoints attached to each placeholder
+	int_load: [u8; 31],
+}
+/// State for a polled interrupt endpoint
+struct InterruptState
+{
+	ed: EndpointId,
+	/// Interrupt list placeholder this endpoint follows
+	placeholder: usize,
+	max_packet_size: usize,
+	/// Packet buffers (`INT_BUFFER_SLOTS` of `max_packet_size`)
+	buffer: AllocHandle,
+	waiter: &'static ::kernel::sync::Queue<(usize,usize)>,
+	waiter_idx: usize,
+	/// Most recently completed (slot, length)
+	last: (usize, usize),
+}
+
 /// Handle/index to an endpoint
+#[derive(Copy,Clone,Debug)]
 struct EndpointId {
 	// Group 0 is in the HCCA page (either in the interrupt graph or the buffers)
 	group: u8,
 	idx: u8
 }
 /// Index into a pool of transfer descriptors
+#[derive(Copy,Clone,Debug)]
 struct TransferDescriptorId {
 	// Group 0 is in the tail end of the HCCA
 	group: u8,
 	idx: u8,
 }
-struct EndpointMetadata {
-	spinlock: ::kernel::sync::Spinlock<()>,
-	tail: AtomicPtr<hw::GeneralTD>,
+/// Transfer descriptor to be queued
+struct TdSpec
+{
+	/// Hardware flags (direction, toggle, rounding)
+	flags: u32,
+	/// Physical addresses of the first and last byte of the buffer
+	first: u32,
+	last: u32,
+	len: usize,
+	/// Bytes transferred are included in the operation result
+	counted: bool,
+}
+impl TdSpec
+{
+	fn new(range: &dma::TdRange, flags: u32, counted: bool) -> TdSpec {
+		TdSpec { flags: flags, first: range.first, last: range.last, len: range.len, counted: counted }
+	}
+	fn empty(flags: u32) -> TdSpec {
+		TdSpec { flags: flags, first: 0, last: 0, len: 0, counted: false }
+	}
+}
+
+impl InterruptState
+{
+	/// Poll TD for a buffer slot
+	fn td_spec(&self, buf_slot: usize) -> TdSpec {
+		let first = ::kernel::memory::virt::get_phys(self.buffer.as_ref::<u8>(buf_slot * self.max_packet_size)) as u32;
+		TdSpec {
+			flags: TD_IN | TD_ROUNDING,
+			first: first,
+			last: first + self.max_packet_size as u32 - 1,
+			len: self.max_packet_size,
+			counted: true,
+			}
+	}
 }
 
 impl BusDev
@@ -87,7 +211,18 @@ impl HostInner
 		let io = IoWrapper(io);
 		
 		let fm_interval_val = io.read_reg(hw::Regs::HcFmInterval);
-		let frame_interval = fm_interval_val & 0x3FFF;
+		let frame_interval = match fm_interval_val & 0x3FFF
+			{
+			0 => 11999,	// Not programmed, use the nominal 1ms value
+			v => v,
+			};
+		// FSLargestDataPacket must be non-zero for the controller to schedule transfers
+		let fm_interval_val = if (fm_interval_val >> 16) & 0x7FFF == 0 {
+				frame_interval | ((frame_interval - 210) * 6 / 7) << 16
+			}
+			else {
+				fm_interval_val
+			};
 
 		
 		// Perform a hardware reset (and get controller from the firmware)
@@ -95,15 +230,25 @@ impl HostInner
 		let hc_control = io.read_reg(hw::Regs::HcControl);
 		if hc_control & 0x100 != 0
 		{
-			// SMM emulation
-			todo!("Request device from SMM");
+			// SMM emulation - Request an ownership change, and wait for the SMM driver to release the controller
+			log_notice!("Requesting controller from SMM");
+			// SAFE: No memory addresses
+			unsafe { io.write_reg(hw::Regs::HcCommandStatus, 1 << 3); }
+			let end = ::kernel::time::ticks() + 500;
+			while io.read_reg(hw::Regs::HcControl) & 0x100 != 0
+			{
+				if ::kernel::time::ticks() > end {
+					log_warning!("SMM didn't release the controller, resetting anyway");
+					break ;
+				}
+				::kernel::threads::yield_time();
+			}
 		}
 		else
 		{
 			if hc_control & 0xC0 == 0
 			{
-				// Bus is in UsbReset, wait a bit then switch to UsbOperational
-				// TODO: Wait for a period, or just assume that the wait has already happened.
+				// Bus is in UsbReset, the reset below puts it into a known state
 			}
 			else if hc_control & 0xC0 == 0x80
 			{
@@ -111,21 +256,23 @@ impl HostInner
 			}
 			else
 			{
-				// The bus is in UsbSuspend or UsbResume
-				todo!("Reset device, not ready yet");
+				// The bus is in UsbSuspend or UsbResume (firmware driver) - Resume it before the reset
+				// SAFE: No memory addresses
+				unsafe { io.write_reg(hw::Regs::HcControl, hc_control & !0xC0 | 0x40); }
+				::kernel::time::sleep_ms(20);
 			}
 		}
 
 		// Trigger a reset
 		// SAFE: No memory addresses in this one.
 		unsafe {
-			io.write_reg(hw::Regs::HcCommandStatus, io.read_reg(hw::Regs::HcCommandStatus) | (1 << 0));
-			// TODO: Wait for 10us
-			//::kernel::time::wait_busy_microseconds(10);
-			// - Restore the HcFmInterval value
-			io.write_reg(hw::Regs::HcFmInterval, fm_interval_val);
-			// - Set the bus back to UsbOperational
-			io.write_reg(hw::Regs::HcControl, io.read_reg(hw::Regs::HcControl) & !0xC0 | 0x40);
+			io.write_reg(hw::Regs::HcCommandStatus, 1 << 0);
+			// - Reset completes within 10us
+			while io.read_reg(hw::Regs::HcCommandStatus) & 1 != 0 {
+			}
+			// - Restore the HcFmInterval value (toggling FrameIntervalToggle)
+			io.write_reg(hw::Regs::HcFmInterval, fm_interval_val ^ (1 << 31));
+			// NOTE: The controller is now in UsbSuspend, it's moved to UsbOperational below (must be within 2ms)
 		}
 
 
@@ -135,8 +282,12 @@ impl HostInner
 		// -  256 byte HCCA
 		// -  512 bytes for interupt graph
 		// - 1280 bytes for 16+48 endpoints
-		// - 2048 bytes for 64 transfer descriptors (with 16 bytes of metadata)
+		// - 2048 bytes for 32 transfer descriptors (with 32 bytes of metadata each)
 		let mut handle_hcca = ::kernel::memory::virt::alloc_dma(32, 1, "usb_ohci")?;
+		for v in handle_hcca.as_mut_slice::<u8>(0, 4096) {
+			*v = 0;
+		}
+		let hcca_phys = ::kernel::memory::virt::get_phys(handle_hcca.as_ref::<u8>(0)) as u32;
 		let stop_endpoint_phys;
 		// - Fill the interrupt lists
 		{
@@ -195,7 +346,7 @@ impl HostInner
 		{
 			io.write_reg(hw::Regs::HcControlHeadED, stop_endpoint_phys);
 			io.write_reg(hw::Regs::HcBulkHeadED, stop_endpoint_phys);
-			io.write_reg(hw::Regs::HcHCCA, ::kernel::memory::virt::get_phys(handle_hcca.as_ref::<u8>(0)) as u32);
+			io.write_reg(hw::Regs::HcHCCA, hcca_phys);
 			// Enable almost all interrupts
 			// - 31: Global enable
 			// - 30: Ownership Change (disabled)
@@ -224,16 +375,40 @@ impl HostInner
 			io.write_reg(hw::Regs::HcPeriodicStart, frame_interval * 9 / 10);	// Program the periodic start point (maximum amount of time for non-int/isoch) to 90%
 		}
 		
-		let nports = (io.read_reg(hw::Regs::HcRhDescriptorA) & 0xFF) as u8;
+		let rh_desc_a = io.read_reg(hw::Regs::HcRhDescriptorA);
+		let nports = (rh_desc_a & 0xFF) as u8;
 		assert!(nports <= 15, "Too many ports in OHCI");
 
+		// Power the root hub ports (unless they're always powered)
+		if rh_desc_a & (1 << 9) == 0
+		{
+			// SAFE: No memory addresses
+			unsafe {
+				// - SetGlobalPower, then SetPortPower for individually switched ports
+				io.write_reg(hw::Regs::HcRhStatus, 1 << 16);
+				for i in 0 .. nports as usize
+				{
+					io.write_reg(::core::mem::transmute(hw::Regs::HcRhPortStatus0 as usize + i), 0x100);
+				}
+			}
+			// - Wait for PowerOnToPowerGoodTime (units of 2ms)
+			::kernel::time::sleep_ms( (rh_desc_a >> 24) as u64 * 2 );
+		}
+
 		let mut inner_aref = Aref::new(HostInner {
 			io: io,
 			hcca_handle: handle_hcca,
+			hcca_phys: hcca_phys,
 			nports: nports,
 			irq_handle: None,	// Filled below, once the allocation is made
 			waiter_idx: Default::default(),
 			waiter_ptr: Default::default(),
+			low_speed: ::kernel::sync::Spinlock::new([0; 128/8]),
+			ed_pools: Default::default(),
+			td_pools: Default::default(),
+			pool_handles: ::kernel::sync::Mutex::new(Vec::new()),
+			schedule: ::kernel::sync::Spinlock::new(Schedule { int_load: [0; 31] }),
+			interrupt_eps: ::kernel::sync::Spinlock::new(Default::default()),
 			});
 		
 		// Bind interrupt
@@ -264,8 +439,8 @@ impl HostInner
 			// WritebackDoneHead
 			if v & 0x02 != 0
 			{
-				log_debug!("WritebackDoneHead - ");
-				// TODO: Clear the contents of HccaDoneHead, releasing and completing those TDs
+				// NOTE: HccaDoneHead must be read before clearing the status bit
+				self.process_done_queue();
 			}
 			// StartofFrame (disabled)
 			if v & 0x04 != 0
@@ -315,127 +490,813 @@ impl HostInner
 		}
 	}
 
-	fn get_ed_pointer(&self, id: &EndpointId) -> *const hw::Endpoint {
+	fn get_ed_pointer(&self, id: &EndpointId) -> *mut hw::Endpoint {
+		let ofs = (id.idx as usize) * ::core::mem::size_of::<hw::Endpoint>();
 		if id.group == 0 {
-			let ofs = (id.idx as usize) * ::core::mem::size_of::<hw::Endpoint>();
 			assert!(ofs >= 256);
 			assert!(ofs < 2048);
-			self.hcca_handle.as_ref(ofs)
+			// SAFE: Returned as a raw pointer, accesses are done with the schedule lock held
+			unsafe { self.hcca_handle.as_int_mut::<hw::Endpoint>(ofs) }
+		}
+		else {
+			let base = self.ed_pools[id.group as usize - 1].virt.load(Ordering::Acquire);
+			assert!(base != 0, "get_ed_pointer: Unallocated pool {}", id.group);
+			(base + ofs) as *mut _
+		}
+	}
+	fn get_ed_phys(&self, id: &EndpointId) -> u32 {
+		let ofs = (id.idx as usize) * ::core::mem::size_of::<hw::Endpoint>();
+		if id.group == 0 {
+			self.hcca_phys + ofs as u32
+		}
+		else {
+			(self.ed_pools[id.group as usize - 1].phys.load(Ordering::Acquire) + ofs) as u32
+		}
+	}
+	fn ed_from_phys(&self, phys: u32) -> Option<EndpointId> {
+		const SIZE: u32 = 16;
+		if phys & !0xFFF == self.hcca_phys {
+			Some(EndpointId { group: 0, idx: ((phys & 0xFFF) / SIZE) as u8 })
 		}
 		else {
-			todo!("get_ed_pointer: Alternate pools for endpoint descriptors");
+			Self::pool_from_phys(&self.ed_pools, phys).map(|group| EndpointId { group: group, idx: ((phys & 0xFFF) / SIZE) as u8 })
 		}
 	}
-	fn get_general_td_pointer(&self, id: &TransferDescriptorId) -> *const hw::GeneralTD {
+	/// Get the base of a TD slot (the hardware descriptor is at the start, the metadata follows)
+	fn get_td_slot(&self, id: &TransferDescriptorId) -> *mut u8 {
+		let ofs = (id.idx as usize) * hw::TD_SLOT_SIZE;
 		if id.group == 0 {
-			let ofs = (id.idx as usize) * ::core::mem::size_of::<hw::GeneralTD>();
 			assert!(ofs >= 2048);
 			assert!(ofs < 4096);
-			self.hcca_handle.as_ref(ofs)
+			// SAFE: Returned as a raw pointer
+			unsafe { self.hcca_handle.as_int_mut::<u8>(ofs) }
+		}
+		else {
+			let base = self.td_pools[id.group as usize - 1].virt.load(Ordering::Acquire);
+			assert!(base != 0, "get_td_slot: Unallocated pool {}", id.group);
+			(base + ofs) as *mut _
+		}
+	}
+	fn get_general_td_pointer(&self, id: &TransferDescriptorId) -> *mut hw::GeneralTD {
+		self.get_td_slot(id) as *mut _
+	}
+	fn get_td_meta(&self, id: &TransferDescriptorId) -> *mut hw::TdMeta {
+		(self.get_td_slot(id) as usize + hw::TD_META_OFS) as *mut _
+	}
+	fn get_td_phys(&self, id: &TransferDescriptorId) -> u32 {
+		let ofs = (id.idx as usize) * hw::TD_SLOT_SIZE;
+		if id.group == 0 {
+			self.hcca_phys + ofs as u32
+		}
+		else {
+			(self.td_pools[id.group as usize - 1].phys.load(Ordering::Acquire) + ofs) as u32
+		}
+	}
+	fn td_from_phys(&self, phys: u32) -> Option<TransferDescriptorId> {
+		const SIZE: u32 = hw::TD_SLOT_SIZE as u32;
+		if phys & !0xFFF == self.hcca_phys {
+			Some(TransferDescriptorId { group: 0, idx: ((phys & 0xFFF) / SIZE) as u8 })
 		}
 		else {
-			todo!("get_general_td_pointer: Alternate pools for transfer descriptors");
+			Self::pool_from_phys(&self.td_pools, phys).map(|group| TransferDescriptorId { group: group, idx: ((phys & 0xFFF) / SIZE) as u8 })
+		}
+	}
+	/// Find the pool containing a descriptor (`None`, and an error logged, if the address is bogus)
+	fn pool_from_phys(pools: &[PoolPage], phys: u32) -> Option<u8> {
+		for (i,p) in pools.iter().enumerate()
+		{
+			if p.virt.load(Ordering::Acquire) != 0 && p.phys.load(Ordering::Acquire) as u32 == phys & !0xFFF {
+				return Some( (i + 1) as u8 );
+			}
+		}
+		log_error!("Descriptor address {:#x} not in any pool", phys);
+		None
+	}
+	/// Ensure that a pool page is allocated (returns false if allocation failed)
+	fn ensure_pool(&self, pools: &[PoolPage], group: usize) -> bool {
+		let pool = &pools[group - 1];
+		if pool.virt.load(Ordering::Acquire) != 0 {
+			return true;
+		}
+		let mut lh = self.pool_handles.lock();
+		if pool.virt.load(Ordering::Acquire) != 0 {
+			return true;
+		}
+		let mut handle = match ::kernel::memory::virt::alloc_dma(32, 1, "usb_ohci")
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("Unable to allocate descriptor pool: {:?}", e);
+				return false;
+				},
+			};
+		for v in handle.as_mut_slice::<u8>(0, 4096) {
+			*v = 0;
+		}
+		let base = handle.as_ref::<u8>(0);
+		pool.phys.store(::kernel::memory::virt::get_phys(base) as usize, Ordering::Release);
+		pool.virt.store(base as *const _ as usize, Ordering::Release);
+		lh.push(handle);
+		true
+	}
+
+	/// Allocate and initialise an endpoint descriptor (with a dummy TD)
+	fn allocate_ed(&self, flags: u32) -> Option<EndpointId>
+	{
+		let dummy = match self.allocate_td()
+			{
+			Some(v) => v,
+			None => return None,
+			};
+		let dummy_phys = self.get_td_phys(&dummy);
+		for group in 0 .. MAX_POOLS + 1
+		{
+			let range = if group == 0 {
+					HCCA_ED_FIRST .. HCCA_ED_END
+				}
+				else if self.ensure_pool(&self.ed_pools, group) {
+					0 .. 4096 / 16
+				}
+				else {
+					break
+				};
+			for idx in range
+			{
+				let id = EndpointId { group: group as u8, idx: idx as u8 };
+				let ed = self.get_ed_pointer(&id);
+				// SAFE: Descriptors are only claimed by this atomic exchange (free descriptors have zero flags)
+				unsafe {
+					let flags_ptr = &*(&(*ed).flags as *const u32 as *const AtomicU32);
+					if flags_ptr.compare_and_swap(0, flags | ED_ALLOCATED | (1 << 14), Ordering::SeqCst) == 0
+					{
+						// Initialise with skip set (cleared once the queue is valid)
+						::core::ptr::write_volatile(&mut (*ed).tail_ptr, dummy_phys);
+						::core::ptr::write_volatile(&mut (*ed).head_ptr, dummy_phys);
+						::core::ptr::write_volatile(&mut (*ed).next_ed, 0);
+						flags_ptr.store(flags | ED_ALLOCATED, Ordering::SeqCst);
+						return Some(id);
+					}
+				}
+			}
+		}
+		log_error!("Endpoint descriptors exhausted");
+		self.free_td(&dummy);
+		None
+	}
+	/// Allocate a new TD
+	fn allocate_td(&self) -> Option<TransferDescriptorId>
+	{
+		for group in 0 .. MAX_POOLS + 1
+		{
+			let range = if group == 0 {
+					HCCA_TD_FIRST .. HCCA_TD_END
+				}
+				else if self.ensure_pool(&self.td_pools, group) {
+					0 .. 4096 / hw::TD_SLOT_SIZE
+				}
+				else {
+					break
+				};
+			for idx in range
+			{
+				let id = TransferDescriptorId { group: group as u8, idx: idx as u8 };
+				// SAFE: Metadata is only claimed by this atomic exchange
+				unsafe {
+					let meta = self.get_td_meta(&id);
+					let alloc_ptr = &*(&(*meta).allocated as *const u32 as *const AtomicU32);
+					if alloc_ptr.compare_and_swap(0, 1, Ordering::SeqCst) == 0
+					{
+						::core::ptr::write_bytes(self.get_td_slot(&id), 0, hw::TD_META_OFS);
+						return Some(id);
+					}
+				}
+			}
 		}
+		log_error!("Transfer descriptors exhausted");
+		None
 	}
-	fn get_endpoint_meta(&self, id: &EndpointId) -> &EndpointMetadata {
-		todo!("get_endpoint_meta");
+	fn free_td(&self, id: &TransferDescriptorId)
+	{
+		// SAFE: Atomic access to the allocation flag
+		unsafe {
+			let meta = self.get_td_meta(id);
+			(*meta).flags = 0;
+			(*meta).handle = 0;
+			(&*(&(*meta).allocated as *const u32 as *const AtomicU32)).store(0, Ordering::SeqCst);
+		}
 	}
 
-	/// Register an interrupt endpoint
-	fn register_interrupt_ed(&self, period_pow_2: usize, flags: u32) -> EndpointId
+	/// Register an interrupt endpoint (returns the endpoint and the placeholder it follows)
+	fn register_interrupt_ed(&self, period_ms: usize, flags: u32) -> Option<(EndpointId, usize)>
+	{
+		// Placeholders (index and count) for each polling period
+		let (base, count) = if period_ms < 2 { (INT_LIST_1MS, 1) }
+			else if period_ms < 4 { (28, 2) }
+			else if period_ms < 8 { (24, 4) }
+			else if period_ms < 16 { (16, 8) }
+			else { (0, 16) };
+		let id = match self.allocate_ed(flags | ED_LIST_PERIODIC)
+			{
+			Some(v) => v,
+			None => return None,
+			};
+
+		let _irq = ::kernel::sync::hold_interrupts();
+		let mut lh = self.schedule.lock();
+		// Find the least loaded placeholder of this period, and put the endpoint after it
+		let slot = (base .. base + count).min_by_key(|&i| lh.int_load[i]).unwrap();
+		lh.int_load[slot] += 1;
+		let placeholder = EndpointId { group: 0, idx: (INT_LIST_FIRST + slot) as u8 };
+		// SAFE: Schedule lock held
+		unsafe { self.insert_ed_after(&placeholder, &id); }
+		Some( (id, slot) )
+	}
+	/// Remove an interrupt endpoint from the schedule, and release its placeholder slot
+	fn release_interrupt_ed(&self, id: &EndpointId, placeholder: usize)
 	{
-		// 1. Find a low-load slot of this period
-		// 2. Check if the placeholder is in use
-		// 3. If not, allocate a new endpoint descriptor and put it after the placeholder
-		todo!("register_interrupt_ed");
+		self.release_ed(id);
+		let _irq = ::kernel::sync::hold_interrupts();
+		self.schedule.lock().int_load[placeholder] -= 1;
+	}
+	/// Register an isochronous endpoint (placed after all interrupt endpoints in the 1ms list)
+	fn register_isoch_ed(&self, flags: u32) -> Option<EndpointId>
+	{
+		let id = match self.allocate_ed(flags | ED_LIST_PERIODIC)
+			{
+			Some(v) => v,
+			None => return None,
+			};
+		let stop_phys = self.get_ed_phys(&EndpointId { group: 0, idx: (INT_LIST_FIRST + 31) as u8 });
+
+		let _irq = ::kernel::sync::hold_interrupts();
+		let _lh = self.schedule.lock();
+		let mut prev = EndpointId { group: 0, idx: (INT_LIST_FIRST + INT_LIST_1MS) as u8 };
+		// SAFE: Schedule lock held
+		unsafe {
+			loop
+			{
+				let next = ::core::ptr::read_volatile(&(*self.get_ed_pointer(&prev)).next_ed) & !0xF;
+				if next == stop_phys || next == 0 {
+					break;
+				}
+				prev = match self.ed_from_phys(next)
+					{
+					Some(v) => v,
+					None => break,
+					};
+			}
+			self.insert_ed_after(&prev, &id);
+		}
+		Some(id)
 	}
 	/// Register a general-purpose endpoint descriptor and add it to the control queue
-	fn register_control_ed(&self, flags: u32) -> EndpointId
+	fn register_control_ed(&self, flags: u32) -> Option<EndpointId>
 	{
-		todo!("register_control_ed");
+		self.register_async_ed(hw::Regs::HcControlHeadED, flags | ED_LIST_CONTROL)
 	}
 	/// Register a general-purpose endpoint descriptor and add it to the bulk queue
-	fn register_bulk_ed(&self, flags: u32) -> EndpointId
+	fn register_bulk_ed(&self, flags: u32) -> Option<EndpointId>
 	{
-		todo!("register_bulk_ed");
+		self.register_async_ed(hw::Regs::HcBulkHeadED, flags | ED_LIST_BULK)
 	}
-	/// Allocate a new TD
-	fn allocate_td(&self, flags: u32) -> TransferDescriptorId
+	fn register_async_ed(&self, head_reg: hw::Regs, flags: u32) -> Option<EndpointId>
 	{
-		use core::sync::atomic::{AtomicU32, Ordering};
-		// Iterate over all avaliable pools
-		const SIZE: usize = ::core::mem::size_of::<hw::GeneralTD>();
-		for i in (2048 / SIZE .. 4096 / SIZE)
-		{
-			let ofs = i * SIZE;
-			log_debug!("allocate_td: i={} ofs={}", i, ofs);
-			// Do a compare+set of the flags field with the new value (with some masking)
-			// SAFE: I assume so? (TODO: Check)
-			let flags_ptr: &AtomicU32 = unsafe { &*(self.hcca_handle.as_ref(ofs) as *const u32 as *const AtomicU32) };
-			if flags_ptr.compare_and_swap(0, flags | 1, Ordering::SeqCst) == 0
+		let id = match self.allocate_ed(flags)
 			{
-				return TransferDescriptorId { group: 0, idx: i as u8 };
+			Some(v) => v,
+			None => return None,
+			};
+		let ed = self.get_ed_pointer(&id);
+
+		let _irq = ::kernel::sync::hold_interrupts();
+		let _lh = self.schedule.lock();
+		// SAFE: Schedule lock held, and the new endpoint is valid before it's linked
+		unsafe {
+			::core::ptr::write_volatile(&mut (*ed).next_ed, self.io.read_reg(head_reg));
+			self.io.write_reg(head_reg, self.get_ed_phys(&id));
+		}
+		Some(id)
+	}
+	/// Link an endpoint after another (caller must hold the schedule lock)
+	unsafe fn insert_ed_after(&self, prev: &EndpointId, id: &EndpointId)
+	{
+		let prev_ptr = self.get_ed_pointer(prev);
+		let ed = self.get_ed_pointer(id);
+		::core::ptr::write_volatile(&mut (*ed).next_ed, ::core::ptr::read_volatile(&(*prev_ptr).next_ed));
+		::core::ptr::write_volatile(&mut (*prev_ptr).next_ed, self.get_ed_phys(id));
+	}
+
+	/// Remove an endpoint from the schedule and free it (along with any queued TDs)
+	fn release_ed(&self, id: &EndpointId)
+	{
+		let ed = self.get_ed_pointer(id);
+		let phys = self.get_ed_phys(id);
+		// 1. Set skip, and wait for the controller to finish any in-progress transaction
+		{
+			let _irq = ::kernel::sync::hold_interrupts();
+			let _lh = self.schedule.lock();
+			// SAFE: Schedule lock held
+			unsafe { (*ed).flags |= 1 << 14; }
+		}
+		::kernel::time::sleep_ms(1);
+		// 2. Unlink from the list
+		{
+			let _irq = ::kernel::sync::hold_interrupts();
+			let _lh = self.schedule.lock();
+			// SAFE: Schedule lock held, all pointers come from the controller's lists
+			unsafe {
+				let flags = ::core::ptr::read_volatile(&(*ed).flags);
+				let next = ::core::ptr::read_volatile(&(*ed).next_ed);
+				let found = if flags & ED_LIST_MASK == ED_LIST_PERIODIC {
+						// Periodic endpoints can follow any placeholder
+						(0 .. 31).any(|p| self.unlink_after(EndpointId { group: 0, idx: (INT_LIST_FIRST + p) as u8 }, phys, next))
+					}
+					else {
+						let reg = if flags & ED_LIST_MASK == ED_LIST_BULK { hw::Regs::HcBulkHeadED } else { hw::Regs::HcControlHeadED };
+						let head = self.io.read_reg(reg) & !0xF;
+						if head == phys {
+							self.io.write_reg(reg, next);
+							true
+						}
+						else {
+							match self.ed_from_phys(head)
+							{
+							Some(e) => self.unlink_after(e, phys, next),
+							None => false,
+							}
+						}
+					};
+				if !found {
+					log_error!("release_ed: {:?} not found in its list", id);
+				}
+			}
+		}
+		// 3. The controller may still have been holding a pointer to the descriptor, wait for the next frame
+		::kernel::time::sleep_ms(2);
+		// 4. Free the queued TDs (including the dummy) and the endpoint
+		{
+			let _irq = ::kernel::sync::hold_interrupts();
+			let _lh = self.schedule.lock();
+			// SAFE: The controller no longer has access to this endpoint
+			unsafe {
+				let tail = ::core::ptr::read_volatile(&(*ed).tail_ptr) & !0xF;
+				let mut cur = ::core::ptr::read_volatile(&(*ed).head_ptr) & !0xF;
+				loop
+				{
+					let td = match self.td_from_phys(cur)
+						{
+						Some(v) => v,
+						None => break,
+						};
+					let next = ::core::ptr::read_volatile(&(*self.get_general_td_pointer(&td)).next_td) & !0xF;
+					self.free_td(&td);
+					if cur == tail || next == 0 {
+						break;
+					}
+					cur = next;
+				}
+				(&*(&(*ed).flags as *const u32 as *const AtomicU32)).store(0, Ordering::SeqCst);
 			}
 		}
-		todo!("allocate_td - alternate pools, main is exhausted");
 	}
-	unsafe fn push_td(&self, ep: &EndpointId, flags: u32, first_byte: u32, last_byte: u32, async_handle: async::ObjectHandle) -> TransferDescriptorId
+	/// Walk a list from `start` and unlink the endpoint at `phys` (caller must hold the schedule lock)
+	unsafe fn unlink_after(&self, start: EndpointId, phys: u32, next: u32) -> bool
 	{
-		// 1. Allocate a new transfer descriptor
-		let td_handle = self.allocate_td(flags);
-		let td_ptr: *mut hw::GeneralTD = self.get_general_td_pointer(&td_handle) as *mut _;
-		// - Fill it
-		//td_ptr.fill(first_byte, last_byte, ::core::mem::transmute::<_, usize>(async_handle) as u64);
+		let mut cur = start;
+		loop
 		{
-			::core::ptr::write(&mut (*td_ptr).cbp, first_byte);
-			::core::ptr::write(&mut (*td_ptr).buffer_end, last_byte);
-			// - Store the (single pointer) async handle in the 64-bit meta field
-			::core::ptr::write(&mut (*td_ptr).meta_async_handle, ::core::mem::transmute::<_, usize>(async_handle) as u64);
-		}
-		// 2. Add to the end of the endpoint's queue.
-		let ed = self.get_ed_pointer(ep) as *mut hw::Endpoint;
-		// - Set SKIP in flags
-		// - Get the metadata for this endpoint and lock it (spinlock)
-		let epm = self.get_endpoint_meta(ep);
-		let _lh = epm.spinlock.lock();
-		// - If the NextP is 0, set NextP = phys(td)
-		// > TODO: Atomic compare_and_swap
-		if (*ed).head_ptr == 0 {
-			(*ed).head_ptr = ::kernel::memory::virt::get_phys(td_ptr) as u32;
-			epm.tail.store(td_ptr, Ordering::SeqCst);
-		}
-		// - Else, set EP_Meta.tail.NextP = phys(td)
-		else {
-			// TODO: Possible race, if hardware is processing the TDs and is about to write back NextP between the above read and the write here.
-			let prev_tail = epm.tail.swap(td_ptr, Ordering::SeqCst);
-			(*prev_tail).next_td = ::kernel::memory::virt::get_phys(td_ptr) as u32;
+			let p = self.get_ed_pointer(&cur);
+			let n = ::core::ptr::read_volatile(&(*p).next_ed) & !0xF;
+			if n == phys {
+				::core::ptr::write_volatile(&mut (*p).next_ed, next);
+				return true;
+			}
+			if n == 0 {
+				return false;
+			}
+			cur = match self.ed_from_phys(n)
+				{
+				Some(v) => v,
+				None => return false,
+				};
 		}
+	}
 
-		td_handle
+	/// Abort all transfers queued on an endpoint, completing them with `Error::Timeout`
+	fn cancel_transfers(&self, id: &EndpointId)
+	{
+		let ed = self.get_ed_pointer(id);
+		// 1. Set skip, and wait for the controller to finish any in-progress transaction
+		{
+			let _irq = ::kernel::sync::hold_interrupts();
+			let _lh = self.schedule.lock();
+			// SAFE: Schedule lock held
+			unsafe { (*ed).flags |= 1 << 14; }
+		}
+		::kernel::time::sleep_ms(1);
+		// 2. Remove every TD before the dummy, signalling each transfer as its final TD is removed
+		{
+			let _irq = ::kernel::sync::hold_interrupts();
+			let _lh = self.schedule.lock();
+			// SAFE: Schedule lock held, and the controller is skipping this endpoint
+			unsafe {
+				let tail = ::core::ptr::read_volatile(&(*ed).tail_ptr) & !0xF;
+				let head_val = ::core::ptr::read_volatile(&(*ed).head_ptr);
+				let mut cur = head_val & !0xF;
+				while cur != tail
+				{
+					let td = match self.td_from_phys(cur)
+						{
+						Some(v) => v,
+						None => break,
+						};
+					let next = ::core::ptr::read_volatile(&(*self.get_general_td_pointer(&td)).next_td) & !0xF;
+					let meta = &*self.get_td_meta(&td);
+					if meta.flags & TDF_FINAL != 0 {
+						let handle: async::ObjectHandle = ::core::mem::transmute(meta.handle as usize);
+						handle.signal(::usb_core::host::Error::Timeout.to_result());
+					}
+					self.free_td(&td);
+					cur = next;
+				}
+				// - Keep the toggle carry, and clear the halted flag
+				::core::ptr::write_volatile(&mut (*ed).head_ptr, tail | (head_val & 0x2));
+				(*ed).flags &= !(1 << 14);
+			}
+		}
 	}
 
-	fn get_dma_write(&self, buffer: async::WriteBufferHandle) -> ((), u32, u32)
+	/// Queue a transfer on an endpoint, signalling `async` once the final TD completes
+	///
+	/// If the descriptors can't be allocated, `async` is signalled with `Error::Controller` instead.
+	fn queue_transfer(&self, ed_id: &EndpointId, tds: &[TdSpec], async: async::ObjectHandle)
 	{
-		match buffer
+		assert!(tds.len() > 0);
+		// Allocate before locking (allocation can require a new pool page)
+		let mut new_tds = Vec::with_capacity(tds.len());
+		for _ in tds
 		{
-		async::WriteBufferHandle::Long(p) => {
-			let start_phys = ::kernel::memory::virt::get_phys(p.as_ptr());
-			let last_phys = ::kernel::memory::virt::get_phys(&p[p.len()-1]);
-			if start_phys > 0xFFFF_FFFF || last_phys > 0xFFFF_FFFF {
-				// An address is more than 32-bits, bounce
+			match self.allocate_td()
+			{
+			Some(v) => new_tds.push(v),
+			None => {
+				for td in &new_tds {
+					self.free_td(td);
+				}
+				return async.signal(::usb_core::host::Error::Controller.to_result());
+				},
+			}
+		}
+		// SAFE: The handle is stored as an integer until completion, when it's signalled exactly once
+		let handle = unsafe { ::core::mem::transmute::<async::ObjectHandle, usize>(async) as u64 };
+		let list = {
+			let _irq = ::kernel::sync::hold_interrupts();
+			let _lh = self.schedule.lock();
+			// SAFE: Schedule lock held, and the TDs are owned
+			unsafe {
+				if !self.link_tds(ed_id, tds, handle, 0, &new_tds) {
+					for td in &new_tds {
+						self.free_td(td);
+					}
+					let async: async::ObjectHandle = ::core::mem::transmute(handle as usize);
+					return async.signal(::usb_core::host::Error::Controller.to_result());
+				}
+				::core::ptr::read_volatile(&(*self.get_ed_pointer(ed_id)).flags) & ED_LIST_MASK
+			}
+			};
+		// Tell the controller that the list has new work
+		// SAFE: Write-one-to-set bits, no memory addresses
+		unsafe {
+			match list
+			{
+			ED_LIST_CONTROL => self.io.write_reg(hw::Regs::HcCommandStatus, 1 << 1),
+			ED_LIST_BULK => self.io.write_reg(hw::Regs::HcCommandStatus, 1 << 2),
+			_ => {},
 			}
-			else if start_phys & !0xFFF != last_phys & !0xFFF && (0x1000 - (start_phys & 0xFFF) + last_phys & 0xFFF) as usize != p.len() {
-				// The buffer spans more than two pages, bounce
+		}
+	}
+	/// Fill the endpoint's dummy TD and append the new TDs (the last of which becomes the new dummy)
+	///
+	/// Caller must hold the schedule lock, and `new_tds` must be the same length as `tds`. Returns false
+	/// (with nothing linked) if the endpoint's tail pointer is invalid.
+	unsafe fn link_tds(&self, ed_id: &EndpointId, tds: &[TdSpec], handle: u64, meta_flags: u8, new_tds: &[TransferDescriptorId]) -> bool
+	{
+		let ed = self.get_ed_pointer(ed_id);
+		let n = tds.len();
+		let first = match self.td_from_phys(::core::ptr::read_volatile(&(*ed).tail_ptr) & !0xF)
+			{
+			Some(v) => v,
+			None => return false,
+			};
+		let td_at = |i: usize| if i == 0 { first } else { new_tds[i-1] };
+		let final_td = td_at(n-1);
+		let new_dummy = new_tds[n-1];
+		for (i,spec) in tds.iter().enumerate()
+		{
+			let id = td_at(i);
+			let next = if i + 1 < n { td_at(i+1) } else { new_dummy };
+			let is_final = i + 1 == n;
+			// Only the final TD interrupts (errors always cause a done queue writeback)
+			let delay = if is_final { 0 } else { 7 };
+			::core::ptr::write_volatile(self.get_general_td_pointer(&id), hw::GeneralTD {
+				flags: spec.flags | (delay << 21) | (hw::cc::NOT_ACCESSED as u32) << 28,
+				cbp: if spec.len > 0 { spec.first } else { 0 },
+				next_td: self.get_td_phys(&next),
+				buffer_end: if spec.len > 0 { spec.last } else { 0 },
+				});
+			let meta = &mut *self.get_td_meta(&id);
+			meta.handle = handle;
+			meta.len = spec.len as u32;
+			meta.accum = 0;
+			meta.flags = meta_flags | if is_final { TDF_FINAL } else { 0 } | if spec.counted { 0 } else { TDF_NOCOUNT };
+			meta.ed = [ed_id.group, ed_id.idx];
+			meta.final_td = [final_td.group, final_td.idx];
+		}
+		::core::ptr::write_volatile(&mut (*ed).tail_ptr, self.get_td_phys(&new_dummy));
+		true
+	}
+	/// Queue a single-packet isochronous TD
+	fn queue_isoch(&self, ed_id: &EndpointId, range: &dma::TdRange, frame: u32, async: async::ObjectHandle)
+	{
+		let new_dummy = match self.allocate_td()
+			{
+			Some(v) => v,
+			None => return async.signal(::usb_core::host::Error::Controller.to_result()),
+			};
+		let _irq = ::kernel::sync::hold_interrupts();
+		let _lh = self.schedule.lock();
+		// SAFE: Schedule lock held, and the TDs are owned
+		unsafe {
+			let ed = self.get_ed_pointer(ed_id);
+			let id = match self.td_from_phys(::core::ptr::read_volatile(&(*ed).tail_ptr) & !0xF)
+				{
+				Some(v) => v,
+				None => {
+					self.free_td(&new_dummy);
+					return async.signal(::usb_core::host::Error::Controller.to_result());
+					},
+				};
+			// - Stored as an integer until completion (see `queue_transfer`)
+			let handle = ::core::mem::transmute::<async::ObjectHandle, usize>(async) as u64;
+			let mut psw = [0u16; 8];
+			// - Offset within the first page, with the condition code set to NotAccessed
+			psw[0] = 0xE000 | (range.first & 0xFFF) as u16;
+			::core::ptr::write_volatile(self.get_td_slot(&id) as *mut hw::IsochronousTD, hw::IsochronousTD {
+				flags: (frame & 0xFFFF) | (0 << 21) | (0 << 24) | (hw::cc::NOT_ACCESSED as u32) << 28,
+				buffer_page0: range.first & !0xFFF,
+				next_td: self.get_td_phys(&new_dummy),
+				buffer_end: range.last,
+				psw: psw,
+				});
+			let meta = &mut *self.get_td_meta(&id);
+			meta.handle = handle;
+			meta.len = range.len as u32;
+			meta.accum = 0;
+			meta.flags = TDF_ISOCH | TDF_FINAL;
+			meta.ed = [ed_id.group, ed_id.idx];
+			meta.final_td = [id.group, id.idx];
+			::core::ptr::write_volatile(&mut (*ed).tail_ptr, self.get_td_phys(&new_dummy));
+		}
+	}
+
+	/// Process the done queue (called from the IRQ handler)
+	fn process_done_queue(&self)
+	{
+		let _lh = self.schedule.lock();
+		// SAFE: Schedule lock held, TDs in the done queue are owned by the driver
+		unsafe {
+			let hcca: &hw::Hcca = self.hcca_handle.as_ref(0);
+			let mut phys = ::core::ptr::read_volatile(&hcca.done_head) & !0xF;
+			// The done queue is in reverse completion order, reverse it first
+			let mut prev = 0;
+			while phys != 0
+			{
+				let td = match self.td_from_phys(phys)
+					{
+					Some(v) => self.get_general_td_pointer(&v),
+					None => break,
+					};
+				let next = ::core::ptr::read_volatile(&(*td).next_td) & !0xF;
+				::core::ptr::write_volatile(&mut (*td).next_td, prev);
+				prev = phys;
+				phys = next;
+			}
+			phys = prev;
+			while phys != 0
+			{
+				// NOTE: Every entry was validated by the reversal above
+				let id = match self.td_from_phys(phys)
+					{
+					Some(v) => v,
+					None => break,
+					};
+				let next = ::core::ptr::read_volatile(&(*self.get_general_td_pointer(&id)).next_td);
+				self.complete_td(id);
+				phys = next;
+			}
+		}
+	}
+	/// Handle a retired TD (caller must hold the schedule lock)
+	unsafe fn complete_td(&self, id: TransferDescriptorId)
+	{
+		let meta = &mut *self.get_td_meta(&id);
+		if meta.flags & TDF_ISOCH != 0 {
+			return self.complete_isoch(id);
+		}
+		let td = self.get_general_td_pointer(&id);
+		let cc = (::core::ptr::read_volatile(&(*td).flags) >> 28) as u8;
+		let count = Self::td_count(td, meta.len);
+		if meta.flags & TDF_INTERRUPT != 0 {
+			return self.complete_interrupt(id, cc, count);
+		}
+
+		let is_final = meta.flags & TDF_FINAL != 0;
+		let counted = if meta.flags & TDF_NOCOUNT != 0 { 0 } else { count };
+		let final_meta = if is_final { &mut *meta as *mut hw::TdMeta } else { self.get_td_meta(&TransferDescriptorId { group: meta.final_td[0], idx: meta.final_td[1] }) };
+		if !is_final && (*final_meta).handle != meta.handle {
+			// The transfer was cancelled while this TD was waiting in the done queue
+			self.free_td(&id);
+			return ;
+		}
+		if cc == hw::cc::NO_ERROR
+		{
+			if is_final {
+				let handle: async::ObjectHandle = ::core::mem::transmute(meta.handle as usize);
+				handle.signal(meta.accum as usize + counted);
 			}
 			else {
-				// Good
-				return ( (), start_phys as u32, last_phys as u32);
+				(*final_meta).accum += counted as u32;
+			}
+			self.free_td(&id);
+		}
+		else
+		{
+			let handle: async::ObjectHandle = ::core::mem::transmute(meta.handle as usize);
+			let ed_id = EndpointId { group: meta.ed[0], idx: meta.ed[1] };
+			// A short packet completes the transfer early
+			let res = if cc == hw::cc::DATA_UNDERRUN {
+					(*final_meta).accum as usize + counted
+				}
+				else {
+					log_debug!("TD {:?} on ED {:?} failed with CC={}", id, ed_id, cc);
+					Self::cc_to_error(cc).to_result()
+				};
+			// The endpoint is now halted, remove the rest of this transfer and restart it
+			let ed = self.get_ed_pointer(&ed_id);
+			if !is_final {
+				let tail = ::core::ptr::read_volatile(&(*ed).tail_ptr) & !0xF;
+				loop
+				{
+					let head_val = ::core::ptr::read_volatile(&(*ed).head_ptr);
+					let head = head_val & !0xF;
+					if head == tail {
+						break;
+					}
+					let td_id = match self.td_from_phys(head)
+						{
+						Some(v) => v,
+						None => break,
+						};
+					let td_meta = &*self.get_td_meta(&td_id);
+					if td_meta.handle != meta.handle {
+						break;
+					}
+					let done = td_meta.flags & TDF_FINAL != 0;
+					let next = ::core::ptr::read_volatile(&(*self.get_general_td_pointer(&td_id)).next_td) & !0xF;
+					::core::ptr::write_volatile(&mut (*ed).head_ptr, next | (head_val & 0x3));
+					self.free_td(&td_id);
+					if done {
+						break;
+					}
+				}
+			}
+			let head_val = ::core::ptr::read_volatile(&(*ed).head_ptr);
+			::core::ptr::write_volatile(&mut (*ed).head_ptr, head_val & !1);
+			self.free_td(&id);
+			handle.signal(res);
+		}
+	}
+	/// Handle a retired interrupt endpoint TD, and re-queue it
+	unsafe fn complete_interrupt(&self, id: TransferDescriptorId, cc: u8, count: usize)
+	{
+		let slot = (*self.get_td_meta(&id)).handle as usize;
+		let buf_slot = (*self.get_td_meta(&id)).buf_slot as usize;
+		let mut lh = self.interrupt_eps.lock();
+		let st = match lh[slot]
+			{
+			Some(ref mut v) => v,
+			// Endpoint has been closed
+			None => return self.free_td(&id),
+			};
+		let ed = self.get_ed_pointer(&st.ed);
+		if cc != hw::cc::NO_ERROR && cc != hw::cc::DATA_UNDERRUN
+		{
+			log_debug!("Interrupt ED {:?} failed with CC={}", st.ed, cc);
+			if cc == hw::cc::DEVICE_NOT_RESPONDING {
+				// Leave the endpoint halted, the device has most likely been removed
+				return self.free_td(&id);
 			}
-			todo!("Bounce buffer - long lifetime");
-			},
-		async::WriteBufferHandle::Short(p) => {
-			todo!("Bounce buffer - short lifetime");
-			},
+		}
+		else
+		{
+			st.last = (buf_slot, count);
+			st.waiter.push( (st.waiter_idx, count) );
+		}
+		// Re-arm using the next buffer, the completed TD becomes the new dummy
+		if !self.queue_interrupt_poll(st, slot, (buf_slot + 1) % INT_BUFFER_SLOTS, id) {
+			log_error!("Interrupt ED {:?} has an invalid queue, leaving halted", st.ed);
+			return self.free_td(&id);
+		}
+		let head_val = ::core::ptr::read_volatile(&(*ed).head_ptr);
+		::core::ptr::write_volatile(&mut (*ed).head_ptr, head_val & !1);
+	}
+	/// Queue a poll of an interrupt endpoint into a buffer slot (caller must hold the schedule lock)
+	///
+	/// Returns false if nothing could be queued (see `link_tds`)
+	unsafe fn queue_interrupt_poll(&self, st: &InterruptState, index: usize, buf_slot: usize, new_dummy: TransferDescriptorId) -> bool
+	{
+		let ed = self.get_ed_pointer(&st.ed);
+		let queued = match self.td_from_phys(::core::ptr::read_volatile(&(*ed).tail_ptr) & !0xF)
+			{
+			Some(v) => v,
+			None => return false,
+			};
+		if !self.link_tds(&st.ed, &[st.td_spec(buf_slot)], index as u64, TDF_INTERRUPT, &[new_dummy]) {
+			return false;
+		}
+		(*self.get_td_meta(&queued)).buf_slot = buf_slot as u8;
+		true
+	}
+	/// Handle a retired isochronous TD
+	unsafe fn complete_isoch(&self, id: TransferDescriptorId)
+	{
+		let meta = &*self.get_td_meta(&id);
+		let td = self.get_td_slot(&id) as *const hw::IsochronousTD;
+		let cc = (::core::ptr::read_volatile(&(*td).flags) >> 28) as u8;
+		let psw = ::core::ptr::read_volatile(&(*td).psw[0]);
+		let ed = self.get_ed_pointer(&EndpointId { group: meta.ed[0], idx: meta.ed[1] });
+		let is_in = (::core::ptr::read_volatile(&(*ed).flags) >> 11) & 3 == 0b10;
+		let pkt_cc = (psw >> 12) as u8;
+		let res = if cc != hw::cc::NO_ERROR {
+				Self::cc_to_error(cc).to_result()
+			}
+			else if pkt_cc != hw::cc::NO_ERROR && pkt_cc != hw::cc::DATA_UNDERRUN {
+				Self::cc_to_error(pkt_cc).to_result()
+			}
+			else if is_in {
+				(psw & 0x7FF) as usize
+			}
+			else {
+				meta.len as usize
+			};
+		let handle: async::ObjectHandle = ::core::mem::transmute(meta.handle as usize);
+		self.free_td(&id);
+		handle.signal(res);
+	}
+	/// Number of bytes transferred by a retired general TD
+	unsafe fn td_count(td: *const hw::GeneralTD, len: u32) -> usize
+	{
+		let cbp = ::core::ptr::read_volatile(&(*td).cbp);
+		if cbp == 0 {
+			// Completed (or zero-length)
+			len as usize
+		}
+		else {
+			let end = ::core::ptr::read_volatile(&(*td).buffer_end);
+			let remaining = if cbp & !0xFFF == end & !0xFFF {
+					end - cbp + 1
+				}
+				else {
+					(0x1000 - (cbp & 0xFFF)) + (end & 0xFFF) + 1
+				};
+			(len - remaining) as usize
+		}
+	}
+	fn cc_to_error(cc: u8) -> ::usb_core::host::Error
+	{
+		use usb_core::host::Error;
+		match cc
+		{
+		hw::cc::STALL => Error::Stall,
+		hw::cc::DEVICE_NOT_RESPONDING => Error::NoResponse,
+		hw::cc::CRC | hw::cc::BIT_STUFFING | hw::cc::DATA_TOGGLE_MISMATCH
+			| hw::cc::PID_CHECK_FAILURE | hw::cc::UNEXPECTED_PID | hw::cc::DATA_OVERRUN => Error::Data,
+		_ => Error::Controller,
+		}
+	}
+
+	fn is_low_speed(&self, addr: u8) -> bool
+	{
+		self.low_speed.lock()[addr as usize / 8] & (1 << (addr % 8)) != 0
+	}
+	fn set_low_speed(&self, addr: u8, is_low: bool)
+	{
+		let mut lh = self.low_speed.lock();
+		if is_low {
+			lh[addr as usize / 8] |= 1 << (addr % 8);
+		}
+		else {
+			lh[addr as usize / 8] &= !(1 << (addr % 8));
 		}
 	}
 
@@ -448,36 +1309,75 @@ impl HostInner
 	}
 }
 
-use ::usb_core::host::{EndpointAddr, PortFeature, Handle};
-use ::usb_core::host::{InterruptEndpoint, IsochEndpoint, ControlEndpoint, BulkEndpoint};
+use ::usb_core::host::{EndpointAddr, PortFeature, Handle, DevicePath, Speed};
+use ::usb_core::host::{InterruptEndpoint, IsochEndpoint, ControlEndpoint, BulkEndpointIn, BulkEndpointOut};
 impl ::usb_core::host::HostController for UsbHost
 {
 	/// Begin polling an endpoint at the given rate (buffer used is allocated by the driver to be the interrupt endpoint's size)
-	fn init_interrupt(&self, endpoint: EndpointAddr, period_ms: usize, waiter: async::ObjectHandle) -> Handle<InterruptEndpoint> {
-		todo!("init_interrupt");
+	fn init_interrupt(&self, endpoint: EndpointAddr, period_ms: usize, max_packet_size: usize, waiter: &'static ::kernel::sync::Queue<(usize,usize)>, waiter_idx: usize) -> Handle<InterruptEndpoint> {
+		let max_packet_size = ::core::cmp::max(1, ::core::cmp::min(max_packet_size, 4096 / INT_BUFFER_SLOTS));
+		// On failure the handle never reports any data
+		let index = self.open_interrupt(&endpoint, period_ms, max_packet_size, waiter, waiter_idx);
+		Handle::new(InterruptEndpointHandle {
+			controller: self.host.reborrow(),
+			index: index,
+			}).ok().unwrap()
 	}
 	fn init_isoch(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<IsochEndpoint> {
-		todo!("init_isoch");
+		// Format=1 (Isochronous), with an endpoint for each direction
+		let ed_out = self.host.register_isoch_ed(self.ed_flags(&endpoint, 0b01, max_packet_size) | (1 << 15));
+		let ed_in = self.host.register_isoch_ed(self.ed_flags(&endpoint, 0b10, max_packet_size) | (1 << 15));
+		let eds = match (ed_out, ed_in)
+			{
+			(Some(o), Some(i)) => Some( (o, i) ),
+			(o, i) => {
+				for ed in o.iter().chain(i.iter()) {
+					self.host.release_ed(ed);
+				}
+				None
+				},
+			};
+		Handle::new(IsochEndpointHandle {
+			controller: self.host.reborrow(),
+			eds: eds,
+			max_packet_size: max_packet_size as u16,
+			}).ok().unwrap()
 	}
 	fn init_control(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<ControlEndpoint> {
-		// Allocate an endpoint
-		let ptr = self.host.register_control_ed(
-			  (endpoint.dev_addr() & 0x7F) as u32
-			| ((endpoint.endpt() & 0xF) << 7) as u32
-			| (0b00 << 11)	// Direction - Use TD
-			| (0b0 << 13)	// Speed (TODO)
-			| (0b0 << 14)	// Skip - clear
-			| (0b0 << 15)	// Format - 0=control/bulk/int
-			| ((max_packet_size & 0xFFFF) << 16) as u32
-			);
+		// Allocate an endpoint (direction comes from the TD), if that fails all transfers fail
+		let ptr = self.host.register_control_ed( self.ed_flags(&endpoint, 0b00, max_packet_size) );
 
 		Handle::new(ControlEndpointHandle {
 			controller: self.host.reborrow(),
 			id: ptr,
+			max_packet_size: max_packet_size as u16,
+			}).ok().unwrap()
+	}
+	fn init_bulk_out(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<BulkEndpointOut> {
+		let ptr = self.host.register_bulk_ed( self.ed_flags(&endpoint, 0b01, max_packet_size) );
+		Handle::new(BulkEndpointHandle {
+			controller: self.host.reborrow(),
+			id: ptr,
+			max_packet_size: max_packet_size as u16,
 			}).ok().unwrap()
 	}
-	fn init_bulk(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<BulkEndpoint> {
-		todo!("init_bulk");
+	fn init_bulk_in(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<BulkEndpointIn> {
+		let ptr = self.host.register_bulk_ed( self.ed_flags(&endpoint, 0b10, max_packet_size) );
+		Handle::new(BulkEndpointHandle {
+			controller: self.host.reborrow(),
+			id: ptr,
+			max_packet_size: max_packet_size as u16,
+			}).ok().unwrap()
+	}
+
+	fn set_address(&self, path: &DevicePath, addr: u8) -> Result<(), ::usb_core::host::Error> {
+		// The device is still at address zero, so send using the speed of the new device
+		self.host.set_low_speed(0, path.speed == Speed::Low);
+		self.host.set_low_speed(addr, path.speed == Speed::Low);
+		::usb_core::host::send_set_address(self, addr)
+	}
+	fn release_address(&self, addr: u8) {
+		self.host.set_low_speed(addr, false);
 	}
 
 
@@ -536,6 +1436,7 @@ impl ::usb_core::host::HostController for UsbHost
 			PortFeature::Reset       => 0x0010,
 			PortFeature::Power       => 0x0100,
 			PortFeature::LowSpeed    => 0x0200,
+			PortFeature::HighSpeed   => return false,	// OHCI is full/low speed only
 			PortFeature::CConnection => 0x01_0000,
 			PortFeature::CEnable     => 0x02_0000,
 			PortFeature::CSuspend    => 0x04_0000,
@@ -562,30 +1463,376 @@ impl ::usb_core::host::HostController for UsbHost
 		}
 	}
 }
+impl UsbHost
+{
+	/// Allocate and start polling an interrupt endpoint, returning its index in `HostInner::interrupt_eps`
+	fn open_interrupt(&self, endpoint: &EndpointAddr, period_ms: usize, max_packet_size: usize, waiter: &'static ::kernel::sync::Queue<(usize,usize)>, waiter_idx: usize) -> Option<usize>
+	{
+		let buffer = match ::kernel::memory::virt::alloc_dma(32, 1, "usb_ohci")
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("Unable to allocate interrupt buffer: {:?}", e);
+				return None;
+				},
+			};
+		let first_td = match self.host.allocate_td()
+			{
+			Some(v) => v,
+			None => return None,
+			};
+		let (ed, placeholder) = match self.host.register_interrupt_ed(period_ms, self.ed_flags(endpoint, 0b10, max_packet_size))
+			{
+			Some(v) => v,
+			None => {
+				self.host.free_td(&first_td);
+				return None;
+				},
+			};
+
+		let index = {
+			let _irq = ::kernel::sync::hold_interrupts();
+			let _slh = self.host.schedule.lock();
+			let mut lh = self.host.interrupt_eps.lock();
+			match lh.iter().position(|v| v.is_none())
+			{
+			Some(index) => {
+				lh[index] = Some(InterruptState {
+					ed: ed,
+					placeholder: placeholder,
+					max_packet_size: max_packet_size,
+					buffer: buffer,
+					waiter: waiter,
+					waiter_idx: waiter_idx,
+					last: (0, 0),
+					});
+				// SAFE: Schedule lock held
+				if unsafe { self.host.queue_interrupt_poll(lh[index].as_ref().unwrap(), index, 0, first_td) } {
+					Some(index)
+				}
+				else {
+					lh[index] = None;
+					None
+				}
+				},
+			None => {
+				log_error!("Too many interrupt endpoints (max {})", MAX_INTERRUPT_EPS);
+				None
+				},
+			}
+			};
+		if index.is_none() {
+			self.host.free_td(&first_td);
+			self.host.release_interrupt_ed(&ed, placeholder);
+		}
+		index
+	}
+	/// Endpoint descriptor flags for an endpoint (`dir`: 0b00 = from TD, 0b01 = OUT, 0b10 = IN)
+	fn ed_flags(&self, endpoint: &EndpointAddr, dir: u32, max_packet_size: usize) -> u32
+	{
+		  (endpoint.dev_addr() & 0x7F) as u32
+		| ((endpoint.endpt() & 0xF) as u32) << 7
+		| dir << 11
+		| (self.host.is_low_speed(endpoint.dev_addr()) as u32) << 13	// Speed - 1=low
+		| (0b0 << 14)	// Skip - clear
+		| (0b0 << 15)	// Format - 0=control/bulk/int
+		| ((max_packet_size & 0x7FF) as u32) << 16
+	}
+}
+
+/// Complete an operation immediately with an error
+fn fail_op(async: async::ObjectHandle, mut stack: async::StackPush, err: ::usb_core::host::Error)
+{
+	// The pass-through layer isn't required, so a full stack is ignored
+	let _ = stack.push_closure(|_async, _stack, res| Some(res));
+	async.signal(err.to_result());
+}
+macro_rules! try_op {
+	($async:expr, $stack:expr, $e:expr) => {
+		match $e
+		{
+		Ok(v) => v,
+		Err(e) => return fail_op($async, $stack, e),
+		}
+	};
+}
+/// Push an operation's completion closure, failing the operation if the async stack is full
+macro_rules! try_push {
+	($async:expr, $stack:expr, $f:expr) => {
+		if $stack.push_closure($f).is_err() {
+			log_error!("Async stack full, failing transfer");
+			return $async.signal(::usb_core::host::Error::Controller.to_result());
+		}
+	};
+}
+
+/// Get an endpoint handle's descriptor (fails if it couldn't be allocated)
+fn get_ed(id: &Option<EndpointId>) -> Result<&EndpointId, ::usb_core::host::Error>
+{
+	id.as_ref().ok_or(::usb_core::host::Error::Controller)
+}
+
 struct ControlEndpointHandle {
 	controller: ArefBorrow<HostInner>,
-	id: EndpointId,
+	/// `None` if the endpoint descriptor couldn't be allocated
+	id: Option<EndpointId>,
+	max_packet_size: u16,
 }
 impl ControlEndpoint for ControlEndpointHandle
 {
 	fn out_only<'a, 's>(&'s self, async: async::ObjectHandle, mut stack: async::StackPush<'a, 's>, setup_data: async::WriteBufferHandle<'a, '_>, out_data: async::WriteBufferHandle<'a, '_>) {
-		// TODO: Figure out this stuff...
-		let (setup_buf, setup_first_phys, setup_last_phys) = self.controller.get_dma_write(setup_data);
-		let (out_buf, out_first_phys, out_last_phys) = self.controller.get_dma_write(out_data);
-		// SAFE: The buffers stay valid because they're moved into the async closure.
-		unsafe {
-			self.controller.push_td( &self.id, (0b00 << 19) /* setup */ | (7 << 21) /* no int */, setup_first_phys, setup_last_phys, async.clone() );
-			self.controller.push_td( &self.id, (0b01 << 19) /* out */ | (0 << 21) /* immediate int */, out_first_phys, out_last_phys, async.clone() );
+		let id = try_op!(async, stack, get_ed(&self.id));
+		let setup_buf = try_op!(async, stack, dma::DmaBuffer::new_write(setup_data));
+		let out_buf = try_op!(async, stack, dma::DmaBuffer::new_write(out_data));
+		let mut tds = Vec::new();
+		for r in setup_buf.td_ranges(8) {
+			tds.push(TdSpec::new(&r, TD_SETUP | TD_DATA0, false));
+		}
+		for (i,r) in out_buf.td_ranges(self.max_packet_size as usize).iter().enumerate() {
+			// Data stage starts with DATA1, then follows the toggle carry
+			tds.push(TdSpec::new(r, TD_OUT | if i == 0 { TD_DATA1 } else { 0 }, true));
 		}
-		stack.push_closure(move |async, _stack, out_bytes| {
+		// Status stage is an IN in the opposite direction
+		tds.push(TdSpec::empty(TD_IN | TD_DATA1));
+
+		try_push!(async, stack, move |_async, _stack, out_bytes| {
 			// - Capture buffer handles so they stay valid
-			let _ = setup_buf;
-			let _ = out_buf;
+			let _ = &setup_buf;
+			let _ = &out_buf;
 			// - Pass the result down the chain.
 			Some(out_bytes)
-			}).unwrap();
+			});
+		self.controller.queue_transfer(id, &tds, async);
+	}
+	fn in_only<'a, 's>(&'s self, async: async::ObjectHandle, mut stack: async::StackPush<'a, 's>, setup_data: async::WriteBufferHandle<'a, '_>, in_buf: &'a mut [u8]) {
+		let id = try_op!(async, stack, get_ed(&self.id));
+		let setup_buf = try_op!(async, stack, dma::DmaBuffer::new_write(setup_data));
+		let dma_buf = try_op!(async, stack, dma::DmaBuffer::new_read(in_buf));
+		let mut tds = Vec::new();
+		for r in setup_buf.td_ranges(8) {
+			tds.push(TdSpec::new(&r, TD_SETUP | TD_DATA0, false));
+		}
+		for (i,r) in dma_buf.td_ranges(self.max_packet_size as usize).iter().enumerate() {
+			tds.push(TdSpec::new(r, TD_IN | TD_ROUNDING | if i == 0 { TD_DATA1 } else { 0 }, true));
+		}
+		tds.push(TdSpec::empty(TD_OUT | TD_DATA1));
+
+		let dst = (in_buf.as_mut_ptr(), in_buf.len());
+		try_push!(async, stack, move |_async, _stack, res| {
+			let _ = &setup_buf;
+			if let Ok(len) = ::usb_core::host::Error::from_result(res) {
+				// SAFE: The destination buffer outlives the operation
+				unsafe { dma_buf.copy_to(dst.0, ::core::cmp::min(len, dst.1)); }
+			}
+			Some(res)
+			});
+		self.controller.queue_transfer(id, &tds, async);
+	}
+	fn cancel(&self) {
+		if let Some(ref id) = self.id {
+			self.controller.cancel_transfers(id);
+		}
+	}
+}
+impl ::core::ops::Drop for ControlEndpointHandle
+{
+	fn drop(&mut self) {
+		if let Some(ref id) = self.id {
+			self.controller.release_ed(id);
+		}
+	}
+}
+
+/// Bulk endpoint (direction set in the endpoint descriptor)
+struct BulkEndpointHandle {
+	controller: ArefBorrow<HostInner>,
+	/// `None` if the endpoint descriptor couldn't be allocated
+	id: Option<EndpointId>,
+	max_packet_size: u16,
+}
+impl BulkEndpointOut for BulkEndpointHandle
+{
+	fn send<'a, 's>(&'s self, async: async::ObjectHandle, mut stack: async::StackPush<'a, 's>, buffer: async::WriteBufferHandle<'a, '_>) {
+		let id = try_op!(async, stack, get_ed(&self.id));
+		let buf = try_op!(async, stack, dma::DmaBuffer::new_write(buffer));
+		let mut tds: Vec<_> = buf.td_ranges(self.max_packet_size as usize).iter().map(|r| TdSpec::new(r, TD_OUT, true)).collect();
+		if tds.is_empty() {
+			// Zero-length packet
+			tds.push(TdSpec::empty(TD_OUT));
+		}
+		try_push!(async, stack, move |_async, _stack, res| {
+			let _ = &buf;
+			Some(res)
+			});
+		self.controller.queue_transfer(id, &tds, async);
+	}
+	fn cancel(&self) {
+		if let Some(ref id) = self.id {
+			self.controller.cancel_transfers(id);
+		}
+	}
+}
+impl BulkEndpointIn for BulkEndpointHandle
+{
+	fn recv<'a, 's>(&'s self, async: async::ObjectHandle, mut stack: async::StackPush<'a, 's>, buffer: &'a mut [u8]) {
+		let id = try_op!(async, stack, get_ed(&self.id));
+		let buf = try_op!(async, stack, dma::DmaBuffer::new_read(buffer));
+		let ranges = buf.td_ranges(self.max_packet_size as usize);
+		// Only the last TD allows a short packet, a short packet earlier ends the transfer with DataUnderrun
+		let mut tds: Vec<_> = ranges.iter().enumerate().map(|(i,r)| TdSpec::new(r, TD_IN | if i + 1 == ranges.len() { TD_ROUNDING } else { 0 }, true)).collect();
+		if tds.is_empty() {
+			tds.push(TdSpec::empty(TD_IN | TD_ROUNDING));
+		}
+		let dst = (buffer.as_mut_ptr(), buffer.len());
+		try_push!(async, stack, move |_async, _stack, res| {
+			if let Ok(len) = ::usb_core::host::Error::from_result(res) {
+				// SAFE: The destination buffer outlives the operation
+				unsafe { buf.copy_to(dst.0, ::core::cmp::min(len, dst.1)); }
+			}
+			Some(res)
+			});
+		self.controller.queue_transfer(id, &tds, async);
+	}
+	fn cancel(&self) {
+		if let Some(ref id) = self.id {
+			self.controller.cancel_transfers(id);
+		}
+	}
+}
+impl ::core::ops::Drop for BulkEndpointHandle
+{
+	fn drop(&mut self) {
+		if let Some(ref id) = self.id {
+			self.controller.release_ed(id);
+		}
+	}
+}
+
+struct InterruptEndpointHandle {
+	controller: ArefBorrow<HostInner>,
+	/// Index into `HostInner::interrupt_eps` (`None` if the endpoint couldn't be opened)
+	index: Option<usize>,
+}
+impl InterruptEndpoint for InterruptEndpointHandle
+{
+	fn get_data(&self) -> Handle<::usb_core::handle::RemoteBuffer> {
+		let index = match self.index
+			{
+			Some(v) => v,
+			None => return Handle::new(InterruptBuffer { ptr: b"".as_ptr(), len: 0 }).ok().unwrap(),
+			};
+		let _irq = ::kernel::sync::hold_interrupts();
+		let lh = self.controller.interrupt_eps.lock();
+		let st = lh[index].as_ref().expect("Interrupt endpoint state missing");
+		let (slot, len) = st.last;
+		Handle::new(InterruptBuffer {
+			ptr: st.buffer.as_ref::<u8>(slot * st.max_packet_size),
+			len: len,
+			}).ok().unwrap()
+	}
+}
+impl ::core::ops::Drop for InterruptEndpointHandle
+{
+	fn drop(&mut self) {
+		let index = match self.index
+			{
+			Some(v) => v,
+			None => return,
+			};
+		// Remove the state first (so the IRQ handler stops re-queueing), then the endpoint, then free the buffer
+		let st = {
+			let _irq = ::kernel::sync::hold_interrupts();
+			self.controller.interrupt_eps.lock()[index].take().expect("Interrupt endpoint state missing")
+			};
+		self.controller.release_interrupt_ed(&st.ed, st.placeholder);
+		drop(st);
+	}
+}
+/// Most recently received data from an interrupt endpoint (valid until the ring of buffers wraps)
+struct InterruptBuffer
+{
+	ptr: *const u8,
+	len: usize,
+}
+impl ::usb_core::handle::RemoteFree for InterruptBuffer
+{
+	unsafe fn free_self(&mut self) {
+		// Buffer is owned by the endpoint
+	}
+}
+impl ::usb_core::handle::RemoteBuffer for InterruptBuffer
+{
+	fn get(&self) -> &[u8] {
+		// SAFE: Points into the endpoint's buffer, which outlives the handle (TODO: Could be overwritten once the ring wraps)
+		unsafe { ::core::slice::from_raw_parts(self.ptr, self.len) }
 	}
-	fn in_only<'a, 's>(&'s self, async: async::ObjectHandle, stack: async::StackPush<'a, 's>, setup_data: async::WriteBufferHandle<'a, '_>, in_buf: &'a mut [u8]) {
+}
+
+struct IsochEndpointHandle {
+	controller: ArefBorrow<HostInner>,
+	/// OUT and IN endpoint descriptors (`None` if they couldn't be allocated)
+	eds: Option<(EndpointId, EndpointId)>,
+	max_packet_size: u16,
+}
+impl IsochEndpoint for IsochEndpointHandle
+{
+	fn get_current_frame_and_time(&self) -> (u32, ::kernel::time::TickCount) {
+		(self.controller.io.read_reg(hw::Regs::HcFmNumber) & 0xFFFF, ::kernel::time::ticks())
+	}
+	fn send_at<'a, 's>(&'s self, async: async::ObjectHandle, mut stack: async::StackPush<'a, 's>, buffer: async::WriteBufferHandle<'a, '_>, abs_frame: u32) {
+		let &(ref ed_out, _) = try_op!(async, stack, self.eds.as_ref().ok_or(::usb_core::host::Error::Controller));
+		let buf = try_op!(async, stack, dma::DmaBuffer::new_write(buffer));
+		let range = match self.check_packet(&buf)
+			{
+			Ok(Some(r)) => r,
+			Ok(None) => { let _ = stack.push_closure(|_async, _stack, res| Some(res)); return async.signal(0); },
+			Err(e) => return fail_op(async, stack, e),
+			};
+		try_push!(async, stack, move |_async, _stack, res| {
+			let _ = &buf;
+			Some(res)
+			});
+		self.controller.queue_isoch(ed_out, &range, abs_frame, async);
+	}
+	fn recv_at<'a, 's>(&'s self, async: async::ObjectHandle, mut stack: async::StackPush<'a, 's>, buffer: &'a mut [u8], abs_frame: u32) {
+		let &(_, ref ed_in) = try_op!(async, stack, self.eds.as_ref().ok_or(::usb_core::host::Error::Controller));
+		let buf = try_op!(async, stack, dma::DmaBuffer::new_read(buffer));
+		let range = match self.check_packet(&buf)
+			{
+			Ok(Some(r)) => r,
+			Ok(None) => { let _ = stack.push_closure(|_async, _stack, res| Some(res)); return async.signal(0); },
+			Err(e) => return fail_op(async, stack, e),
+			};
+		let dst = (buffer.as_mut_ptr(), buffer.len());
+		try_push!(async, stack, move |_async, _stack, res| {
+			if let Ok(len) = ::usb_core::host::Error::from_result(res) {
+				// SAFE: The destination buffer outlives the operation
+				unsafe { buf.copy_to(dst.0, ::core::cmp::min(len, dst.1)); }
+			}
+			Some(res)
+			});
+		self.controller.queue_isoch(ed_in, &range, abs_frame, async);
+	}
+}
+impl IsochEndpointHandle
+{
+	/// Get the TD region for a single-packet buffer (`None` if empty)
+	fn check_packet(&self, buf: &dma::DmaBuffer) -> Result<Option<dma::TdRange>, ::usb_core::host::Error>
+	{
+		if buf.len() > self.max_packet_size as usize {
+			log_warning!("Isochronous packet too large ({} > {})", buf.len(), self.max_packet_size);
+			return Err(::usb_core::host::Error::Controller);
+		}
+		Ok(buf.td_ranges(self.max_packet_size as usize).pop())
+	}
+}
+impl ::core::ops::Drop for IsochEndpointHandle
+{
+	fn drop(&mut self) {
+		if let Some((ref ed_out, ref ed_in)) = self.eds {
+			self.controller.release_ed(ed_out);
+			self.controller.release_ed(ed_in);
+		}
 	}
 }
 
@@ -21,6 +21,8 @@ impl ::kernel::device_manager::Driver for PciDriver {
 	}
 	fn bind(&self, bus_dev: &mut ::kernel::device_manager::BusDevice) -> Box<::kernel::device_manager::DriverInstance+'static>
 	{
+		// Descriptors and buffers are accessed by the controller directly
+		bus_dev.set_attr("bus_master", ::kernel::device_manager::AttrValue::U32(1));
 		let irq = bus_dev.get_irq(0);
 		let base = bus_dev.bind_io(0);
 
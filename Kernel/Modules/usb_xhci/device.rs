@@ -0,0 +1,975 @@
+// "Tifflin" Kernel - xHCI USB driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_xhci/device.rs
+//! Device slots, transfer rings and endpoint handles
+use kernel::prelude::*;
+use kernel::_async3 as async;
+use kernel::lib::mem::aref::ArefBorrow;
+use kernel::lib::mem::Arc;
+use kernel::memory::virt::AllocHandle;
+use core::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+use usb_core::host::{EndpointAddr, Handle, DevicePath, Speed, Error};
+use usb_core::host::{InterruptEndpoint, IsochEndpoint, ControlEndpoint, BulkEndpointIn, BulkEndpointOut};
+use hw::{self, Trb};
+use ring::{Ring, TrbMeta, TRBF_COUNTED, TRBF_CONTROL};
+use dma::DmaBuffer;
+use HostInner;
+use RINGS_PER_SLOT;
+
+/// Number of packet buffers used by each interrupt endpoint
+const INT_BUFFER_SLOTS: usize = 4;
+
+/// An enabled device slot
+pub struct DeviceSlot
+{
+	slot_id: u8,
+	speed: Speed,
+	/// Slot context dwords 0-2 (route string, speed, root port and TT) as given to Address Device
+	slot_ctx: [u32; 3],
+	/// Output device context (written by the controller)
+	output: AllocHandle,
+	/// Input context, locked while building a command
+	input: ::kernel::sync::Mutex<InputContext>,
+	ep0: Arc<TransferRing>,
+	ep0_mps: AtomicUsize,
+	/// Slot has been disabled, closing endpoints doesn't need a command
+	disabled: AtomicBool,
+}
+struct InputContext
+{
+	handle: AllocHandle,
+	phys: u64,
+	/// Highest configured endpoint (slot context "Context Entries")
+	max_dci: u8,
+}
+impl InputContext
+{
+	fn clear(&mut self) {
+		for v in self.handle.as_mut_slice::<u8>(0, ::kernel::PAGE_SIZE) {
+			*v = 0;
+		}
+	}
+	/// Access a dword of a context (0 = input control, 1 = slot, `1 + dci` = endpoint)
+	fn dword(&mut self, context_size: usize, idx: usize, dword: usize) -> &mut u32 {
+		self.handle.as_mut::<u32>(idx * context_size + dword * 4)
+	}
+	/// Fill the slot context (the values from Address Device, with the current context entries)
+	fn set_slot(&mut self, context_size: usize, slot: &DeviceSlot) {
+		let max_dci = self.max_dci;
+		*self.dword(context_size, 1, 0) = slot.slot_ctx[0] | (max_dci as u32) << 27;
+		*self.dword(context_size, 1, 1) = slot.slot_ctx[1];
+		*self.dword(context_size, 1, 2) = slot.slot_ctx[2];
+	}
+	/// Fill an endpoint context
+	fn set_endpoint(&mut self, context_size: usize, dci: u8, ep_type: u32, max_packet_size: usize, interval: u8, ring_phys: u64) {
+		let idx = 1 + dci as usize;
+		let periodic = match ep_type
+			{
+			hw::ep_type::ISOCH_OUT | hw::ep_type::ISOCH_IN | hw::ep_type::INTERRUPT_OUT | hw::ep_type::INTERRUPT_IN => true,
+			_ => false,
+			};
+		*self.dword(context_size, idx, 0) = (interval as u32) << 16;
+		// - Error count of 3 (isochronous endpoints don't retry)
+		let cerr = if ep_type == hw::ep_type::ISOCH_OUT || ep_type == hw::ep_type::ISOCH_IN { 0 } else { 3 };
+		*self.dword(context_size, idx, 1) = cerr << 1 | ep_type << 3 | ((max_packet_size & 0xFFFF) as u32) << 16;
+		*self.dword(context_size, idx, 2) = ring_phys as u32 | 1;	// Dequeue Cycle State = 1
+		*self.dword(context_size, idx, 3) = (ring_phys >> 32) as u32;
+		// - Average TRB length (control endpoints use 8), and the max payload per service interval
+		let avg_len = if ep_type == hw::ep_type::CONTROL { 8 } else { max_packet_size as u32 };
+		let esit = if periodic { max_packet_size as u32 } else { 0 };
+		*self.dword(context_size, idx, 4) = (avg_len & 0xFFFF) | (esit & 0xFFFF) << 16;
+	}
+}
+
+/// An endpoint's transfer ring
+pub struct TransferRing
+{
+	slot_id: u8,
+	/// Device Context Index (endpoint number * 2, plus one for IN)
+	dci: u8,
+	ring: ::kernel::sync::Spinlock<Ring>,
+	/// Endpoint halted on an error (set by the IRQ handler, reset before the next transfer is queued)
+	halted: AtomicBool,
+	/// Polling state for interrupt IN endpoints
+	interrupt: Option<InterruptPoll>,
+}
+struct InterruptPoll
+{
+	max_packet_size: usize,
+	/// Packet buffers (`INT_BUFFER_SLOTS` of `max_packet_size`)
+	buffer: AllocHandle,
+	waiter: &'static ::kernel::sync::Queue<(usize,usize)>,
+	waiter_idx: usize,
+	/// Most recently filled buffer slot and its length
+	last: ::kernel::sync::Spinlock<(usize,usize)>,
+}
+impl TransferRing
+{
+	fn new(slot_id: u8, dci: u8, interrupt: Option<InterruptPoll>) -> Result<TransferRing, Error>
+	{
+		let ring = match Ring::new()
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_warning!("Unable to allocate transfer ring: {:?}", e);
+				return Err(Error::Controller);
+				},
+			};
+		Ok(TransferRing {
+			slot_id: slot_id,
+			dci: dci,
+			ring: ::kernel::sync::Spinlock::new(ring),
+			halted: AtomicBool::new(false),
+			interrupt: interrupt,
+			})
+	}
+	fn phys(&self) -> u64 {
+		let _irq = ::kernel::sync::hold_interrupts();
+		self.ring.lock().phys()
+	}
+}
+
+/// Build the TRBs for a data buffer (split at page boundaries, and chained)
+fn data_trbs(buf: &DmaBuffer, first_type: u32, flags: u32, meta_flags: u8, max_packet_size: usize, trbs: &mut Vec<Trb>, meta: &mut Vec<TrbMeta>)
+{
+	let mps = ::core::cmp::max(1, max_packet_size);
+	let chunks = buf.chunks();
+	let mut before = 0;
+	for (i, &(phys, len)) in chunks.iter().enumerate()
+	{
+		let is_last = i + 1 == chunks.len();
+		// TD Size: packets remaining after this TRB
+		let remaining = buf.len() - before - len;
+		let td_size = ::core::cmp::min((remaining + mps - 1) / mps, 31) as u32;
+		trbs.push(Trb {
+			param: phys,
+			status: len as u32 | td_size << 17,
+			control: (if i == 0 { first_type } else { hw::trb_type::NORMAL }) << 10
+				| hw::TRB_ISP
+				| if is_last { 0 } else { hw::TRB_CHAIN }
+				| if i == 0 { flags } else { 0 },
+			});
+		meta.push(TrbMeta {
+			len: len as u32,
+			before: before as u32,
+			flags: meta_flags | TRBF_COUNTED,
+			..Default::default()
+			});
+		before += len;
+	}
+}
+/// Build a zero-length TRB
+fn empty_trb(trb_type: u32, flags: u32, meta_flags: u8, before: usize, trbs: &mut Vec<Trb>, meta: &mut Vec<TrbMeta>)
+{
+	trbs.push(Trb { param: 0, status: 0, control: trb_type << 10 | flags });
+	meta.push(TrbMeta { before: before as u32, flags: meta_flags, ..Default::default() });
+}
+/// Read a setup packet into the immediate-data format
+fn setup_param(setup_data: async::WriteBufferHandle) -> Result<u64, Error>
+{
+	let p = match setup_data
+		{
+		async::WriteBufferHandle::Long(p) => p,
+		async::WriteBufferHandle::Short(p) => p,
+		};
+	if p.len() != 8 {
+		log_warning!("Setup packet must be 8 bytes (got {})", p.len());
+		return Err(Error::Controller);
+	}
+	Ok( p.iter().rev().fold(0, |acc, &b| acc << 8 | b as u64) )
+}
+
+/// Convert a transfer completion code into an error
+fn cc_to_error(cc: u8) -> Error
+{
+	match cc
+	{
+	hw::cc::STALL => Error::Stall,
+	hw::cc::USB_TRANSACTION => Error::NoResponse,
+	hw::cc::BABBLE | hw::cc::DATA_BUFFER => Error::Data,
+	_ => Error::Controller,
+	}
+}
+/// Endpoint interval (2^n microframes) for a polling period in milliseconds
+fn interval_for(period_ms: usize) -> u8
+{
+	let mut interval = 3;	// 8 microframes = 1ms
+	while interval < 10 && (1 << (interval - 3)) < period_ms {
+		interval += 1;
+	}
+	interval
+}
+
+impl HostInner
+{
+	/// Get the slot for a USB address
+	fn get_slot(&self, addr: u8) -> Option<Arc<DeviceSlot>>
+	{
+		let slot_id = self.addr_slots.lock()[addr as usize & 0x7F];
+		if slot_id == 0 {
+			None
+		}
+		else {
+			self.slots.lock()[slot_id as usize].clone()
+		}
+	}
+
+	/// Enable a slot for a new device, and address it
+	pub fn address_device(&self, path: &DevicePath, addr: u8) -> Result<(), Error>
+	{
+		let slot_id = match self.command(Trb { param: 0, status: 0, control: hw::trb_type::ENABLE_SLOT << 10 })
+			{
+			Ok(v) => v,
+			Err(hw::cc::NO_SLOTS) => return Err(Error::NoAddress),
+			Err(_) => return Err(Error::Controller),
+			};
+		log_debug!("address_device: Slot {} for address {} ({:?})", slot_id, addr, path);
+		let slot = match self.new_slot(slot_id, path)
+			{
+			Ok(v) => Arc::new(v),
+			Err(e) => {
+				let _ = self.command(Trb { param: 0, status: 0, control: hw::trb_type::DISABLE_SLOT << 10 | (slot_id as u32) << 24 });
+				return Err(e);
+				},
+			};
+		// SAFE: The controller doesn't access the entry until the slot is addressed
+		unsafe {
+			*self.dcbaa.as_int_mut::<u64>(slot_id as usize * 8) = ::kernel::memory::virt::get_phys(slot.output.as_ref::<u8>(0)) as u64;
+		}
+		{
+			let _irq = ::kernel::sync::hold_interrupts();
+			self.rings.lock()[slot_id as usize * RINGS_PER_SLOT + 1] = Some(slot.ep0.clone());
+		}
+
+		let input_phys = slot.input.lock().phys;
+		if let Err(cc) = self.command(Trb { param: input_phys, status: 0, control: hw::trb_type::ADDRESS_DEVICE << 10 | (slot_id as u32) << 24 })
+		{
+			log_warning!("Address Device failed for slot {}: CC={}", slot_id, cc);
+			self.disable_slot(slot_id);
+			return Err(if cc == hw::cc::USB_TRANSACTION { Error::NoResponse } else { Error::Controller });
+		}
+
+		self.slots.lock()[slot_id as usize] = Some(slot);
+		self.addr_slots.lock()[addr as usize & 0x7F] = slot_id;
+		Ok(())
+	}
+	/// Release a device's slot (device has been unplugged)
+	pub fn release_address(&self, addr: u8)
+	{
+		let slot_id = ::core::mem::replace(&mut self.addr_slots.lock()[addr as usize & 0x7F], 0);
+		if slot_id == 0 {
+			return ;
+		}
+		if let Some(slot) = self.slots.lock()[slot_id as usize].take() {
+			slot.disabled.store(true, Ordering::SeqCst);
+		}
+		self.disable_slot(slot_id);
+	}
+	fn disable_slot(&self, slot_id: u8)
+	{
+		// Remove the rings from the event lookup (dropped outside the lock)
+		let mut rings: [Option<Arc<TransferRing>>; RINGS_PER_SLOT] = Default::default();
+		{
+			let _irq = ::kernel::sync::hold_interrupts();
+			let mut lh = self.rings.lock();
+			for (dci, r) in rings.iter_mut().enumerate() {
+				*r = lh[slot_id as usize * RINGS_PER_SLOT + dci].take();
+			}
+		}
+		if let Err(cc) = self.command(Trb { param: 0, status: 0, control: hw::trb_type::DISABLE_SLOT << 10 | (slot_id as u32) << 24 }) {
+			log_warning!("Disable Slot {} failed: CC={}", slot_id, cc);
+		}
+		// SAFE: The slot is disabled, so the controller no longer uses this entry
+		unsafe {
+			*self.dcbaa.as_int_mut::<u64>(slot_id as usize * 8) = 0;
+		}
+		drop(rings);
+	}
+	fn new_slot(&self, slot_id: u8, path: &DevicePath) -> Result<DeviceSlot, Error>
+	{
+		let alloc = || ::kernel::memory::virt::alloc_dma(32, 1, "usb_xhci").map_err(|e| {
+			log_warning!("Unable to allocate device context: {:?}", e);
+			Error::Controller
+			});
+		let mut output = alloc()?;
+		for v in output.as_mut_slice::<u8>(0, ::kernel::PAGE_SIZE) {
+			*v = 0;
+		}
+		let input = alloc()?;
+		let input_phys = ::kernel::memory::virt::get_phys(input.as_ref::<u8>(0)) as u64;
+		let ep0 = Arc::new( TransferRing::new(slot_id, 1, None)? );
+
+		let speed_id = match path.speed
+			{
+			Speed::Full => 1,
+			Speed::Low => 2,
+			Speed::High => 3,
+			Speed::Super => 4,
+			};
+		// Low/full-speed devices behind a high-speed hub are reached through the hub's transaction translator
+		let tt = match path.tt
+			{
+			Some((hub_addr, hub_port)) => self.addr_slots.lock()[hub_addr as usize & 0x7F] as u32 | (hub_port as u32) << 8,
+			None => 0,
+			};
+		// TODO: Hub devices should also set the Hub flag and port count (needs a hook from usb_core's hub driver)
+		let slot = DeviceSlot {
+			slot_id: slot_id,
+			speed: path.speed,
+			slot_ctx: [
+				(path.route & 0xF_FFFF) | speed_id << 20,
+				(path.root_port as u32 + 1) << 16,
+				tt,
+				],
+			output: output,
+			input: ::kernel::sync::Mutex::new(InputContext { handle: input, phys: input_phys, max_dci: 1 }),
+			ep0: ep0,
+			ep0_mps: AtomicUsize::new(path.speed.default_mps0()),
+			disabled: AtomicBool::new(false),
+			};
+		{
+			let csz = self.context_size;
+			let ep0_phys = slot.ep0.phys();
+			let mps0 = path.speed.default_mps0();
+			let mut ic = slot.input.lock();
+			ic.clear();
+			*ic.dword(csz, 0, 1) = 0b11;	// Add slot and EP0
+			ic.set_slot(csz, &slot);
+			ic.set_endpoint(csz, 1, hw::ep_type::CONTROL, mps0, 0, ep0_phys);
+		}
+		Ok(slot)
+	}
+
+	/// Update EP0's maximum packet size (once the device descriptor has been read)
+	fn set_ep0_mps(&self, slot: &DeviceSlot, max_packet_size: usize)
+	{
+		let csz = self.context_size;
+		let mut ic = slot.input.lock();
+		ic.clear();
+		*ic.dword(csz, 0, 1) = 1 << 1;	// Evaluate EP0
+		ic.set_endpoint(csz, 1, hw::ep_type::CONTROL, max_packet_size, 0, 0);
+		match self.command(Trb { param: ic.phys, status: 0, control: hw::trb_type::EVALUATE_CONTEXT << 10 | (slot.slot_id as u32) << 24 })
+		{
+		Ok(_) => slot.ep0_mps.store(max_packet_size, Ordering::SeqCst),
+		Err(cc) => log_warning!("Evaluate Context failed for slot {}: CC={}", slot.slot_id, cc),
+		}
+	}
+	/// Add an endpoint to a slot
+	fn configure_endpoint(&self, slot: &DeviceSlot, dci: u8, ep_type: u32, max_packet_size: usize, interval: u8, ring_phys: u64) -> Result<(), u8>
+	{
+		let csz = self.context_size;
+		let mut ic = slot.input.lock();
+		ic.clear();
+		ic.max_dci = ::core::cmp::max(ic.max_dci, dci);
+		*ic.dword(csz, 0, 1) = 1 | 1 << dci;	// Add slot and the endpoint
+		ic.set_slot(csz, slot);
+		ic.set_endpoint(csz, dci, ep_type, max_packet_size, interval, ring_phys);
+		self.command(Trb { param: ic.phys, status: 0, control: hw::trb_type::CONFIGURE_ENDPOINT << 10 | (slot.slot_id as u32) << 24 }).map(|_| ())
+	}
+	/// Remove an endpoint from a slot, and from the event lookup
+	fn close_endpoint(&self, slot: &DeviceSlot, ep: &TransferRing)
+	{
+		if !slot.disabled.load(Ordering::SeqCst)
+		{
+			let csz = self.context_size;
+			let mut ic = slot.input.lock();
+			ic.clear();
+			*ic.dword(csz, 0, 0) = 1 << ep.dci;	// Drop the endpoint
+			*ic.dword(csz, 0, 1) = 1;	// (slot context is unchanged)
+			ic.set_slot(csz, slot);
+			if let Err(cc) = self.command(Trb { param: ic.phys, status: 0, control: hw::trb_type::CONFIGURE_ENDPOINT << 10 | (slot.slot_id as u32) << 24 }) {
+				log_warning!("Dropping endpoint {} of slot {} failed: CC={}", ep.dci, slot.slot_id, cc);
+			}
+		}
+		let r = {
+			let _irq = ::kernel::sync::hold_interrupts();
+			self.rings.lock()[ep.slot_id as usize * RINGS_PER_SLOT + ep.dci as usize].take()
+			};
+		drop(r);
+	}
+	/// Create and configure a transfer ring for an endpoint
+	fn open_ring(&self, endpoint: &EndpointAddr, is_in: bool, ep_type: u32, max_packet_size: usize, interval: u8, interrupt: Option<InterruptPoll>) -> Option<(Arc<DeviceSlot>, Arc<TransferRing>)>
+	{
+		let slot = match self.get_slot(endpoint.dev_addr())
+			{
+			Some(v) => v,
+			None => {
+				log_warning!("No slot for address {}", endpoint.dev_addr());
+				return None;
+				},
+			};
+		let dci = endpoint.endpt() * 2 + is_in as u8;
+		let ring = match TransferRing::new(slot.slot_id, dci, interrupt)
+			{
+			Ok(v) => Arc::new(v),
+			Err(_) => return None,
+			};
+		if let Err(cc) = self.configure_endpoint(&slot, dci, ep_type, max_packet_size, interval, ring.phys()) {
+			log_warning!("Configure Endpoint failed for slot {} DCI {}: CC={}", slot.slot_id, dci, cc);
+			return None;
+		}
+		{
+			let _irq = ::kernel::sync::hold_interrupts();
+			self.rings.lock()[slot.slot_id as usize * RINGS_PER_SLOT + dci as usize] = Some(ring.clone());
+		}
+		Some( (slot, ring) )
+	}
+
+	pub fn open_control(&self, host: ArefBorrow<HostInner>, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<ControlEndpoint>
+	{
+		let ep = if endpoint.endpt() == 0 {
+				self.get_slot(endpoint.dev_addr()).map(|slot| {
+					// EP0's packet size is only known after the first descriptor read (Super-speed uses a fixed size)
+					if slot.speed != Speed::Super && slot.ep0_mps.load(Ordering::SeqCst) != max_packet_size {
+						self.set_ep0_mps(&slot, max_packet_size);
+					}
+					let ring = slot.ep0.clone();
+					(slot, ring)
+					})
+			}
+			else {
+				self.open_ring(&endpoint, true, hw::ep_type::CONTROL, max_packet_size, 0, None)
+			};
+		Handle::new(EndpointHandle { host: host, ep: ep }).ok().unwrap()
+	}
+	pub fn open_bulk_out(&self, host: ArefBorrow<HostInner>, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<BulkEndpointOut>
+	{
+		let ep = self.open_ring(&endpoint, false, hw::ep_type::BULK_OUT, max_packet_size, 0, None);
+		Handle::new(EndpointHandle { host: host, ep: ep }).ok().unwrap()
+	}
+	pub fn open_bulk_in(&self, host: ArefBorrow<HostInner>, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<BulkEndpointIn>
+	{
+		let ep = self.open_ring(&endpoint, true, hw::ep_type::BULK_IN, max_packet_size, 0, None);
+		Handle::new(EndpointHandle { host: host, ep: ep }).ok().unwrap()
+	}
+	pub fn open_interrupt(&self, host: ArefBorrow<HostInner>, endpoint: EndpointAddr, period_ms: usize, max_packet_size: usize, waiter: &'static ::kernel::sync::Queue<(usize,usize)>, waiter_idx: usize) -> Handle<InterruptEndpoint>
+	{
+		let max_packet_size = ::core::cmp::max(1, ::core::cmp::min(max_packet_size, ::kernel::PAGE_SIZE / INT_BUFFER_SLOTS));
+		let ep = match ::kernel::memory::virt::alloc_dma(32, 1, "usb_xhci")
+			{
+			Ok(buffer) => {
+				let poll = InterruptPoll {
+					max_packet_size: max_packet_size,
+					buffer: buffer,
+					waiter: waiter,
+					waiter_idx: waiter_idx,
+					last: ::kernel::sync::Spinlock::new( (0, 0) ),
+					};
+				self.open_ring(&endpoint, true, hw::ep_type::INTERRUPT_IN, max_packet_size, interval_for(period_ms), Some(poll))
+				},
+			Err(e) => {
+				log_error!("Unable to allocate interrupt buffer: {:?}", e);
+				None
+				},
+			};
+		if let Some((_, ref ring)) = ep
+		{
+			if let Some(ref poll) = ring.interrupt
+			{
+				{
+					let _irq = ::kernel::sync::hold_interrupts();
+					let mut lh = ring.ring.lock();
+					queue_poll(&mut lh, poll, 0);
+				}
+				// SAFE: Doorbell for this endpoint
+				unsafe { self.regs.ring_doorbell(ring.slot_id, ring.dci); }
+			}
+		}
+		Handle::new(EndpointHandle { host: host, ep: ep }).ok().unwrap()
+	}
+	pub fn open_isoch(&self, host: ArefBorrow<HostInner>, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<IsochEndpoint>
+	{
+		// A ring for each direction, serviced every frame
+		let out_ep = self.open_ring(&endpoint, false, hw::ep_type::ISOCH_OUT, max_packet_size, interval_for(1), None);
+		let in_ep = self.open_ring(&endpoint, true, hw::ep_type::ISOCH_IN, max_packet_size, interval_for(1), None);
+		let state = match (out_ep, in_ep)
+			{
+			(Some((slot, out_ring)), Some((_, in_ring))) => Some(Box::new(IsochState {
+				slot: slot,
+				ring_out: out_ring,
+				ring_in: in_ring,
+				max_packet_size: max_packet_size,
+				})),
+			(out_ep, in_ep) => {
+				for (slot, ring) in out_ep.into_iter().chain(in_ep) {
+					self.close_endpoint(&slot, &ring);
+				}
+				None
+				},
+			};
+		Handle::new(IsochEndpointHandle { host: host, state: state }).ok().unwrap()
+	}
+
+	/// Queue a transfer on an endpoint's ring (signalling `async` on completion)
+	fn queue_transfer(&self, ep: &TransferRing, trbs: &[Trb], mut meta: Vec<TrbMeta>, async: async::ObjectHandle)
+	{
+		if ep.halted.load(Ordering::SeqCst) {
+			self.reset_endpoint(ep);
+		}
+		{
+			let _irq = ::kernel::sync::hold_interrupts();
+			let mut ring = ep.ring.lock();
+			if ring.free_space() < trbs.len() {
+				log_warning!("Transfer ring full (slot {}, DCI {})", ep.slot_id, ep.dci);
+				return async.signal(Error::Controller.to_result());
+			}
+			// SAFE: Stored as an integer until the completion event signals it
+			let handle = unsafe { ::core::mem::transmute::<async::ObjectHandle, usize>(async) } as u64;
+			for m in meta.iter_mut() {
+				m.handle = handle;
+			}
+			ring.push(trbs, &meta);
+		}
+		// SAFE: Doorbell for this endpoint
+		unsafe { self.regs.ring_doorbell(ep.slot_id, ep.dci); }
+	}
+	/// Recover a halted endpoint (clears the halt, and fails any transfers left on the ring)
+	fn reset_endpoint(&self, ep: &TransferRing)
+	{
+		log_debug!("Resetting halted endpoint (slot {}, DCI {})", ep.slot_id, ep.dci);
+		self.flush_endpoint(ep, Error::Controller);
+	}
+	/// Abort all transfers queued on an endpoint, completing them with `Error::Timeout`
+	fn cancel_transfers(&self, ep: &TransferRing)
+	{
+		log_debug!("Cancelling transfers (slot {}, DCI {})", ep.slot_id, ep.dci);
+		self.flush_endpoint(ep, Error::Timeout);
+	}
+	/// Stop (or reset, if halted) an endpoint and move its dequeue pointer past all queued TRBs, failing the
+	/// discarded transfers with `err`
+	fn flush_endpoint(&self, ep: &TransferRing, err: Error)
+	{
+		let target = (ep.dci as u32) << 16 | (ep.slot_id as u32) << 24;
+		let reset = Trb { param: 0, status: 0, control: hw::trb_type::RESET_ENDPOINT << 10 | target };
+		if ep.halted.load(Ordering::SeqCst) {
+			// - Fails with a context state error if the endpoint wasn't actually halted, which is harmless
+			let _ = self.command(reset);
+		}
+		else if let Err(_) = self.command(Trb { param: 0, status: 0, control: hw::trb_type::STOP_ENDPOINT << 10 | target }) {
+			// - Stopping fails if the endpoint halted in the meantime
+			if ep.halted.load(Ordering::SeqCst) {
+				let _ = self.command(reset);
+			}
+		}
+		let (deq, handles) = {
+			let _irq = ::kernel::sync::hold_interrupts();
+			let mut ring = ep.ring.lock();
+			let handles = ring.release_all();
+			(ring.enqueue_ptr(), handles)
+			};
+		if let Err(cc) = self.command(Trb { param: deq, status: 0, control: hw::trb_type::SET_TR_DEQUEUE << 10 | target }) {
+			log_warning!("Set TR Dequeue failed for slot {} DCI {}: CC={}", ep.slot_id, ep.dci, cc);
+		}
+		ep.halted.store(false, Ordering::SeqCst);
+		for handle in handles
+		{
+			// SAFE: Handle stored by `queue_transfer`, and the TRBs are now released (so it's only signalled once)
+			unsafe {
+				::core::mem::transmute::<usize, async::ObjectHandle>(handle as usize).signal(err.to_result());
+			}
+		}
+	}
+
+	/// Handle a transfer event (called by the IRQ handler)
+	pub fn transfer_event(&self, ep: &TransferRing, trb: &Trb)
+	{
+		let cc = (trb.status >> 24) as u8;
+		let residual = trb.status & 0xFF_FFFF;
+		let mut ring = ep.ring.lock();
+		let idx = match ring.index_of(trb.param)
+			{
+			Some(v) => v,
+			None => {
+				log_warning!("Transfer event for TRB {:#x} not on the ring (slot {}, DCI {})", trb.param, ep.slot_id, ep.dci);
+				return ;
+				},
+			};
+		if !ring.is_pending(idx) {
+			// Stale event (e.g. the final TRB's event after a short packet already completed the transfer)
+			return ;
+		}
+		if cc == hw::cc::STOPPED || cc == hw::cc::STOPPED_LENGTH_INVALID {
+			// Endpoint stopped by `cancel_transfers`, which fails the outstanding transfers
+			return ;
+		}
+		let m = ring.meta[idx];
+		let final_idx = m.final_idx as usize;
+		let here = if m.flags & TRBF_COUNTED != 0 { m.len - ::core::cmp::min(residual, m.len) } else { 0 };
+		let result = match cc
+			{
+			hw::cc::SUCCESS | hw::cc::SHORT_PACKET => {
+				let bytes = m.before + here;
+				if idx != final_idx && m.flags & TRBF_CONTROL != 0 {
+					// Short data stage of a control transfer, the status stage still runs
+					ring.meta[final_idx].short_count = Some(bytes);
+					return ;
+				}
+				Ok( ring.meta[final_idx].short_count.unwrap_or(bytes) as usize )
+				},
+			_ => {
+				log_debug!("Transfer failed (slot {}, DCI {}): CC={}", ep.slot_id, ep.dci, cc);
+				ep.halted.store(true, Ordering::SeqCst);
+				Err(cc_to_error(cc))
+				},
+			};
+		ring.release_to(final_idx);
+		let handle = ring.meta[final_idx].handle;
+
+		if let Some(ref poll) = ep.interrupt
+		{
+			// Interrupt poll: the handle is the buffer slot
+			let slot = handle as usize;
+			match result
+			{
+			Ok(len) => {
+				*poll.last.lock() = (slot, len);
+				poll.waiter.push( (poll.waiter_idx, len) );
+				},
+			Err(e) => log_debug!("Interrupt poll failed: {:?}", e),
+			}
+			// Re-arm (a halted endpoint needs commands, so is left stopped)
+			if !ep.halted.load(Ordering::SeqCst)
+			{
+				queue_poll(&mut ring, poll, (slot + 1) % INT_BUFFER_SLOTS);
+				// SAFE: Doorbell for this endpoint
+				unsafe { self.regs.ring_doorbell(ep.slot_id, ep.dci); }
+			}
+		}
+		else
+		{
+			// SAFE: Handle stored by `queue_transfer`, and the TRBs are now released (so it's only signalled once)
+			unsafe {
+				::core::mem::transmute::<usize, async::ObjectHandle>(handle as usize).signal(match result { Ok(v) => v, Err(e) => e.to_result() });
+			}
+		}
+	}
+}
+/// Queue a single-packet poll of an interrupt endpoint into a buffer slot
+fn queue_poll(ring: &mut Ring, poll: &InterruptPoll, slot: usize)
+{
+	let phys = ::kernel::memory::virt::get_phys(poll.buffer.as_ref::<u8>(slot * poll.max_packet_size)) as u64;
+	ring.push(
+		&[Trb { param: phys, status: poll.max_packet_size as u32, control: hw::trb_type::NORMAL << 10 | hw::TRB_ISP | hw::TRB_IOC }],
+		&[TrbMeta { handle: slot as u64, len: poll.max_packet_size as u32, flags: TRBF_COUNTED, ..Default::default() }]
+		);
+}
+
+/// Complete an operation immediately with an error
+fn fail_op(async: async::ObjectHandle, mut stack: async::StackPush, err: Error)
+{
+	// The pass-through layer isn't required, so a full stack is ignored
+	let _ = stack.push_closure(|_async, _stack, res| Some(res));
+	async.signal(err.to_result());
+}
+macro_rules! try_op {
+	($async:expr, $stack:expr, $e:expr) => {
+		match $e
+		{
+		Ok(v) => v,
+		Err(e) => return fail_op($async, $stack, e),
+		}
+	};
+}
+/// Push an operation's completion closure, failing the operation if the async stack is full
+macro_rules! try_push {
+	($async:expr, $stack:expr, $f:expr) => {
+		if $stack.push_closure($f).is_err() {
+			log_error!("Async stack full, failing transfer");
+			return $async.signal(Error::Controller.to_result());
+		}
+	};
+}
+macro_rules! get_ep {
+	($h:expr, $async:expr, $stack:expr) => {
+		match $h.ep
+		{
+		Some((_, ref ring)) => ring,
+		None => return fail_op($async, $stack, Error::NoResponse),
+		}
+	};
+}
+
+/// Handle to a control, bulk or interrupt endpoint
+struct EndpointHandle
+{
+	host: ArefBorrow<HostInner>,
+	/// `None` if the endpoint couldn't be configured (all transfers fail)
+	ep: Option<(Arc<DeviceSlot>, Arc<TransferRing>)>,
+}
+impl ControlEndpoint for EndpointHandle
+{
+	fn out_only<'a, 's>(&'s self, async: async::ObjectHandle, mut stack: async::StackPush<'a, 's>, setup_data: async::WriteBufferHandle<'a, '_>, out_data: async::WriteBufferHandle<'a, '_>) {
+		let ring = get_ep!(self, async, stack);
+		let setup = try_op!(async, stack, setup_param(setup_data));
+		let buf = try_op!(async, stack, DmaBuffer::new_write(out_data, self.host.ac64));
+		let mps = self.ep_mps();
+
+		let mut trbs = Vec::new();
+		let mut meta = Vec::new();
+		// Setup stage (Transfer Type: 0 = No data, 2 = OUT)
+		let trt = if buf.len() == 0 { 0 } else { 2 };
+		trbs.push(Trb { param: setup, status: 8, control: hw::trb_type::SETUP << 10 | hw::TRB_IDT | trt << 16 });
+		meta.push(TrbMeta { flags: TRBF_CONTROL, ..Default::default() });
+		data_trbs(&buf, hw::trb_type::DATA, 0, TRBF_CONTROL, mps, &mut trbs, &mut meta);
+		// Status stage is IN
+		empty_trb(hw::trb_type::STATUS, hw::TRB_DIR_IN | hw::TRB_IOC, TRBF_CONTROL, buf.len(), &mut trbs, &mut meta);
+
+		try_push!(async, stack, move |_async, _stack, res| {
+			// - Capture the buffer handle so it stays valid
+			let _ = &buf;
+			Some(res)
+			});
+		self.host.queue_transfer(ring, &trbs, meta, async);
+	}
+	fn in_only<'a, 's>(&'s self, async: async::ObjectHandle, mut stack: async::StackPush<'a, 's>, setup_data: async::WriteBufferHandle<'a, '_>, in_buf: &'a mut [u8]) {
+		let ring = get_ep!(self, async, stack);
+		let setup = try_op!(async, stack, setup_param(setup_data));
+		let buf = try_op!(async, stack, DmaBuffer::new_read(in_buf, self.host.ac64));
+		let mps = self.ep_mps();
+
+		let mut trbs = Vec::new();
+		let mut meta = Vec::new();
+		// Setup stage (Transfer Type: 0 = No data, 3 = IN)
+		let trt = if buf.len() == 0 { 0 } else { 3 };
+		trbs.push(Trb { param: setup, status: 8, control: hw::trb_type::SETUP << 10 | hw::TRB_IDT | trt << 16 });
+		meta.push(TrbMeta { flags: TRBF_CONTROL, ..Default::default() });
+		data_trbs(&buf, hw::trb_type::DATA, hw::TRB_DIR_IN, TRBF_CONTROL, mps, &mut trbs, &mut meta);
+		// Status stage is OUT (or IN if there was no data stage)
+		let status_dir = if buf.len() == 0 { hw::TRB_DIR_IN } else { 0 };
+		empty_trb(hw::trb_type::STATUS, status_dir | hw::TRB_IOC, TRBF_CONTROL, buf.len(), &mut trbs, &mut meta);
+
+		let dst = (in_buf.as_mut_ptr(), in_buf.len());
+		try_push!(async, stack, move |_async, _stack, res| {
+			if let Ok(len) = Error::from_result(res) {
+				// SAFE: The destination buffer outlives the operation
+				unsafe { buf.copy_to(dst.0, ::core::cmp::min(len, dst.1)); }
+			}
+			Some(res)
+			});
+		self.host.queue_transfer(ring, &trbs, meta, async);
+	}
+	fn cancel(&self) {
+		self.cancel_transfers();
+	}
+}
+impl BulkEndpointOut for EndpointHandle
+{
+	fn send<'a, 's>(&'s self, async: async::ObjectHandle, mut stack: async::StackPush<'a, 's>, buffer: async::WriteBufferHandle<'a, '_>) {
+		let ring = get_ep!(self, async, stack);
+		let buf = try_op!(async, stack, DmaBuffer::new_write(buffer, self.host.ac64));
+		let mut trbs = Vec::new();
+		let mut meta = Vec::new();
+		if buf.len() == 0 {
+			// Zero-length packet
+			empty_trb(hw::trb_type::NORMAL, hw::TRB_IOC, TRBF_COUNTED, 0, &mut trbs, &mut meta);
+		}
+		else {
+			data_trbs(&buf, hw::trb_type::NORMAL, 0, 0, self.ep_mps(), &mut trbs, &mut meta);
+			if let Some(t) = trbs.last_mut() {
+				t.control |= hw::TRB_IOC;
+			}
+		}
+		try_push!(async, stack, move |_async, _stack, res| {
+			let _ = &buf;
+			Some(res)
+			});
+		self.host.queue_transfer(ring, &trbs, meta, async);
+	}
+	fn cancel(&self) {
+		self.cancel_transfers();
+	}
+}
+impl BulkEndpointIn for EndpointHandle
+{
+	fn recv<'a, 's>(&'s self, async: async::ObjectHandle, mut stack: async::StackPush<'a, 's>, buffer: &'a mut [u8]) {
+		let ring = get_ep!(self, async, stack);
+		let buf = try_op!(async, stack, DmaBuffer::new_read(buffer, self.host.ac64));
+		let mut trbs = Vec::new();
+		let mut meta = Vec::new();
+		if buf.len() == 0 {
+			empty_trb(hw::trb_type::NORMAL, hw::TRB_IOC, TRBF_COUNTED, 0, &mut trbs, &mut meta);
+		}
+		else {
+			data_trbs(&buf, hw::trb_type::NORMAL, 0, 0, self.ep_mps(), &mut trbs, &mut meta);
+			if let Some(t) = trbs.last_mut() {
+				t.control |= hw::TRB_IOC;
+			}
+		}
+		let dst = (buffer.as_mut_ptr(), buffer.len());
+		try_push!(async, stack, move |_async, _stack, res| {
+			if let Ok(len) = Error::from_result(res) {
+				// SAFE: The destination buffer outlives the operation
+				unsafe { buf.copy_to(dst.0, ::core::cmp::min(len, dst.1)); }
+			}
+			Some(res)
+			});
+		self.host.queue_transfer(ring, &trbs, meta, async);
+	}
+	fn cancel(&self) {
+		self.cancel_transfers();
+	}
+}
+impl InterruptEndpoint for EndpointHandle
+{
+	fn get_data(&self) -> Handle<::usb_core::handle::RemoteBuffer> {
+		let (ptr, len) = match self.ep
+			{
+			Some((_, ref ring)) => match ring.interrupt
+				{
+				Some(ref poll) => {
+					let _irq = ::kernel::sync::hold_interrupts();
+					let (slot, len) = *poll.last.lock();
+					(poll.buffer.as_ref::<u8>(slot * poll.max_packet_size) as *const u8, len)
+					},
+				None => {
+					log_warning!("get_data on a non-interrupt endpoint");
+					(::core::ptr::null(), 0)
+					},
+				},
+			None => (::core::ptr::null(), 0),
+			};
+		Handle::new(InterruptBuffer {
+			ptr: ptr,
+			len: len,
+			}).ok().unwrap()
+	}
+}
+impl EndpointHandle
+{
+	fn cancel_transfers(&self)
+	{
+		if let Some((_, ref ring)) = self.ep {
+			self.host.cancel_transfers(ring);
+		}
+	}
+	/// Maximum packet size (read back from the output context)
+	fn ep_mps(&self) -> usize
+	{
+		match self.ep
+		{
+		Some((ref slot, ref ring)) =>
+			if ring.dci == 1 {
+				slot.ep0_mps.load(Ordering::SeqCst)
+			}
+			else {
+				let ofs = ring.dci as usize * self.host.context_size + 4;
+				// SAFE: Read-only access to the controller-owned output context
+				(unsafe { ::core::ptr::read_volatile(slot.output.as_ref::<u32>(ofs)) } >> 16) as usize
+			},
+		None => 0,
+		}
+	}
+}
+impl ::core::ops::Drop for EndpointHandle
+{
+	fn drop(&mut self) {
+		if let Some((ref slot, ref ring)) = self.ep
+		{
+			// EP0 belongs to the slot
+			if ring.dci != 1 {
+				self.host.close_endpoint(slot, ring);
+			}
+		}
+	}
+}
+/// Most recently received data from an interrupt endpoint (valid until the ring of buffers wraps)
+struct InterruptBuffer
+{
+	ptr: *const u8,
+	len: usize,
+}
+impl ::usb_core::handle::RemoteFree for InterruptBuffer
+{
+	unsafe fn free_self(&mut self) {
+		// Buffer is owned by the endpoint
+	}
+}
+impl ::usb_core::handle::RemoteBuffer for InterruptBuffer
+{
+	fn get(&self) -> &[u8] {
+		if self.len == 0 {
+			return &[];
+		}
+		// SAFE: Points into the endpoint's buffer, which outlives the handle (TODO: Could be overwritten once the ring wraps)
+		unsafe { ::core::slice::from_raw_parts(self.ptr, self.len) }
+	}
+}
+
+struct IsochEndpointHandle
+{
+	host: ArefBorrow<HostInner>,
+	state: Option<Box<IsochState>>,
+}
+struct IsochState
+{
+	slot: Arc<DeviceSlot>,
+	ring_out: Arc<TransferRing>,
+	ring_in: Arc<TransferRing>,
+	max_packet_size: usize,
+}
+impl IsochEndpoint for IsochEndpointHandle
+{
+	fn get_current_frame_and_time(&self) -> (u32, ::kernel::time::TickCount) {
+		// MFINDEX counts microframes, the frame ID is in 1ms frames
+		((self.host.regs.rt32(hw::intr::MFINDEX) >> 3) & 0x7FF, ::kernel::time::ticks())
+	}
+	fn send_at<'a, 's>(&'s self, async: async::ObjectHandle, mut stack: async::StackPush<'a, 's>, buffer: async::WriteBufferHandle<'a, '_>, abs_frame: u32) {
+		let st = match self.state { Some(ref v) => v, None => return fail_op(async, stack, Error::NoResponse) };
+		let buf = try_op!(async, stack, DmaBuffer::new_write(buffer, self.host.ac64));
+		let (trbs, meta) = try_op!(async, stack, st.packet(&buf, abs_frame));
+		try_push!(async, stack, move |_async, _stack, res| {
+			let _ = &buf;
+			Some(res)
+			});
+		self.host.queue_transfer(&st.ring_out, &trbs, meta, async);
+	}
+	fn recv_at<'a, 's>(&'s self, async: async::ObjectHandle, mut stack: async::StackPush<'a, 's>, buffer: &'a mut [u8], abs_frame: u32) {
+		let st = match self.state { Some(ref v) => v, None => return fail_op(async, stack, Error::NoResponse) };
+		let buf = try_op!(async, stack, DmaBuffer::new_read(buffer, self.host.ac64));
+		let (trbs, meta) = try_op!(async, stack, st.packet(&buf, abs_frame));
+		let dst = (buffer.as_mut_ptr(), buffer.len());
+		try_push!(async, stack, move |_async, _stack, res| {
+			if let Ok(len) = Error::from_result(res) {
+				// SAFE: The destination buffer outlives the operation
+				unsafe { buf.copy_to(dst.0, ::core::cmp::min(len, dst.1)); }
+			}
+			Some(res)
+			});
+		self.host.queue_transfer(&st.ring_in, &trbs, meta, async);
+	}
+}
+impl IsochState
+{
+	/// Build the TRBs for a single-packet isochronous transfer
+	fn packet(&self, buf: &DmaBuffer, abs_frame: u32) -> Result<(Vec<Trb>, Vec<TrbMeta>), Error>
+	{
+		if buf.len() > self.max_packet_size {
+			log_warning!("Isochronous packet too large ({} > {})", buf.len(), self.max_packet_size);
+			return Err(Error::Controller);
+		}
+		let mut trbs = Vec::new();
+		let mut meta = Vec::new();
+		let frame = (abs_frame & 0x7FF) << 20;
+		if buf.len() == 0 {
+			empty_trb(hw::trb_type::ISOCH, frame | hw::TRB_IOC, TRBF_COUNTED, 0, &mut trbs, &mut meta);
+		}
+		else {
+			data_trbs(buf, hw::trb_type::ISOCH, frame, 0, self.max_packet_size, &mut trbs, &mut meta);
+			if let Some(t) = trbs.last_mut() {
+				t.control |= hw::TRB_IOC;
+			}
+		}
+		Ok( (trbs, meta) )
+	}
+}
+impl ::core::ops::Drop for IsochEndpointHandle
+{
+	fn drop(&mut self) {
+		if let Some(ref st) = self.state
+		{
+			self.host.close_endpoint(&st.slot, &st.ring_out);
+			self.host.close_endpoint(&st.slot, &st.ring_in);
+		}
+	}
+}
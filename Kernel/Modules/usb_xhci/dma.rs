@@ -0,0 +1,113 @@
+// "Tifflin" Kernel - xHCI USB driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_xhci/dma.rs
+//! DMA buffer preparation (page splitting and bounce buffers)
+use kernel::prelude::*;
+use kernel::_async3 as async;
+use kernel::memory::virt::AllocHandle;
+use usb_core::host::Error;
+
+/// A buffer prepared for DMA - either the caller's memory, or a bounce buffer
+pub struct DmaBuffer
+{
+	bounce: Option<AllocHandle>,
+	ptr: *const u8,
+	len: usize,
+}
+impl DmaBuffer
+{
+	/// Prepare a buffer to be sent to a device
+	pub fn new_write(buffer: async::WriteBufferHandle, ac64: bool) -> Result<DmaBuffer, Error>
+	{
+		match buffer
+		{
+		async::WriteBufferHandle::Long(p) =>
+			if is_addressable(p, ac64) {
+				Ok(DmaBuffer { bounce: None, ptr: p.as_ptr(), len: p.len() })
+			}
+			else {
+				DmaBuffer::bounce_from(p)
+			},
+		// Short-lived buffers are only valid for the duration of the call, so must be copied
+		async::WriteBufferHandle::Short(p) => DmaBuffer::bounce_from(p),
+		}
+	}
+	/// Prepare a buffer to receive data from a device (`copy_to` must be called on completion)
+	pub fn new_read(buffer: &mut [u8], ac64: bool) -> Result<DmaBuffer, Error>
+	{
+		if is_addressable(buffer, ac64) {
+			Ok(DmaBuffer { bounce: None, ptr: buffer.as_ptr(), len: buffer.len() })
+		}
+		else {
+			let h = alloc_bounce(buffer.len())?;
+			Ok(DmaBuffer { ptr: h.as_ref::<u8>(0), bounce: Some(h), len: buffer.len() })
+		}
+	}
+	fn bounce_from(data: &[u8]) -> Result<DmaBuffer, Error>
+	{
+		let mut h = alloc_bounce(data.len())?;
+		h.as_mut_slice::<u8>(0, data.len()).copy_from_slice(data);
+		Ok(DmaBuffer { ptr: h.as_ref::<u8>(0), bounce: Some(h), len: data.len() })
+	}
+
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Split the buffer into physically contiguous (address, length) chunks, none crossing a page boundary
+	pub fn chunks(&self) -> Vec<(u64, usize)>
+	{
+		let mut rv = Vec::new();
+		let mut ofs = 0;
+		while ofs < self.len
+		{
+			let phys = ::kernel::memory::virt::get_phys((self.ptr as usize + ofs) as *const u8) as u64;
+			let len = ::core::cmp::min(self.len - ofs, 0x1000 - (phys & 0xFFF) as usize);
+			rv.push( (phys, len) );
+			ofs += len;
+		}
+		rv
+	}
+
+	/// Copy received data out of the bounce buffer (if one was used)
+	///
+	/// UNSAFE: `dst` must be valid for `len` bytes
+	pub unsafe fn copy_to(&self, dst: *mut u8, len: usize)
+	{
+		if self.bounce.is_some() {
+			::core::ptr::copy_nonoverlapping(self.ptr, dst, ::core::cmp::min(len, self.len));
+		}
+	}
+}
+
+fn alloc_bounce(len: usize) -> Result<AllocHandle, Error>
+{
+	let pages = ::core::cmp::max(1, (len + ::kernel::PAGE_SIZE - 1) / ::kernel::PAGE_SIZE);
+	match ::kernel::memory::virt::alloc_dma(32, pages, "usb_xhci")
+	{
+	Ok(v) => Ok(v),
+	Err(e) => {
+		log_warning!("Unable to allocate {} byte bounce buffer: {:?}", len, e);
+		Err(Error::Controller)
+		},
+	}
+}
+
+/// Check that every page of a buffer is addressable by the controller
+fn is_addressable(p: &[u8], ac64: bool) -> bool
+{
+	if ac64 {
+		return true;
+	}
+	let mut ofs = 0;
+	while ofs < p.len()
+	{
+		let phys = ::kernel::memory::virt::get_phys(&p[ofs]) as u64;
+		if phys > 0xFFFF_FFFF {
+			return false;
+		}
+		ofs += 0x1000 - (phys & 0xFFF) as usize;
+	}
+	true
+}
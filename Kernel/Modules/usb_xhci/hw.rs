@@ -0,0 +1,196 @@
+// "Tifflin" Kernel - xHCI USB driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_xhci/hw.rs
+//! Hardware definitions (registers, TRBs and contexts)
+
+/// Capability registers (offsets from BAR0)
+pub mod cap {
+	/// Length of the capability registers (8 bits), followed by the interface version (16 bits at +2)
+	pub const CAPLENGTH	: usize = 0x00;
+	//  0: 7 = MaxSlots
+	//  8:18 = MaxIntrs
+	// 24:31 = MaxPorts
+	pub const HCSPARAMS1	: usize = 0x04;
+	// 21:25 = Max Scratchpad Buffers (high)
+	// 27:31 = Max Scratchpad Buffers (low)
+	pub const HCSPARAMS2	: usize = 0x08;
+	//  0    = 64-bit addressing
+	//  2    = Context Size (64 bytes)
+	// 16:31 = Extended capabilities pointer (dwords)
+	pub const HCCPARAMS1	: usize = 0x10;
+	/// Doorbell array offset
+	pub const DBOFF	: usize = 0x14;
+	/// Runtime registers offset
+	pub const RTSOFF	: usize = 0x18;
+}
+
+/// Operational registers (offsets from CAPLENGTH)
+pub mod op {
+	//  0 = Run/Stop
+	//  1 = Host Controller Reset
+	//  2 = Interrupter Enable
+	pub const USBCMD	: usize = 0x00;
+	//  0 = HCHalted
+	//  3 = Event Interrupt (RW1C)
+	//  4 = Port Change Detect (RW1C)
+	// 11 = Controller Not Ready
+	pub const USBSTS	: usize = 0x04;
+	pub const PAGESIZE	: usize = 0x08;
+	/// Command Ring Control (64-bit)
+	pub const CRCR	: usize = 0x18;
+	/// Device Context Base Address Array Pointer (64-bit)
+	pub const DCBAAP	: usize = 0x30;
+	//  0: 7 = Max Device Slots Enabled
+	pub const CONFIG	: usize = 0x38;
+	/// Port register sets (PORTSC is the first register of each 16 byte set)
+	pub const PORTSC_BASE	: usize = 0x400;
+}
+
+pub const USBCMD_RS: u32 = 1 << 0;
+pub const USBCMD_HCRST: u32 = 1 << 1;
+pub const USBCMD_INTE: u32 = 1 << 2;
+pub const USBSTS_HCH: u32 = 1 << 0;
+pub const USBSTS_EINT: u32 = 1 << 3;
+pub const USBSTS_PCD: u32 = 1 << 4;
+pub const USBSTS_CNR: u32 = 1 << 11;
+
+/// Interrupter register set zero (offsets from RTSOFF)
+pub mod intr {
+	/// Microframe index (relative to RTSOFF, not the interrupter)
+	pub const MFINDEX	: usize = 0x00;
+	//  0 = Interrupt Pending (RW1C)
+	//  1 = Interrupt Enable
+	pub const IMAN	: usize = 0x20;
+	pub const IMOD	: usize = 0x24;
+	pub const ERSTSZ	: usize = 0x28;
+	/// Event Ring Segment Table Base Address (64-bit)
+	pub const ERSTBA	: usize = 0x30;
+	/// Event Ring Dequeue Pointer (64-bit), bit 3 = Event Handler Busy (RW1C)
+	pub const ERDP	: usize = 0x38;
+}
+
+// PORTSC bits
+pub const PORTSC_CCS: u32 = 1 << 0;
+pub const PORTSC_PED: u32 = 1 << 1;
+pub const PORTSC_OCA: u32 = 1 << 3;
+pub const PORTSC_PR: u32 = 1 << 4;
+pub const PORTSC_PLS_SHIFT: u32 = 5;
+pub const PORTSC_PP: u32 = 1 << 9;
+pub const PORTSC_SPEED_SHIFT: u32 = 10;
+pub const PORTSC_LWS: u32 = 1 << 16;
+pub const PORTSC_CSC: u32 = 1 << 17;
+pub const PORTSC_PEC: u32 = 1 << 18;
+pub const PORTSC_WRC: u32 = 1 << 19;
+pub const PORTSC_OCC: u32 = 1 << 20;
+pub const PORTSC_PRC: u32 = 1 << 21;
+pub const PORTSC_PLC: u32 = 1 << 22;
+/// Bits that can be written back unchanged (read-only and read-write-sticky, excludes RW1C/RW1S bits)
+pub const PORTSC_PRESERVE: u32 = 0x4F00_FFE9;
+
+/// Extended capability: USB Legacy Support
+pub const XCAP_LEGACY: u8 = 1;
+pub const LEGSUP_BIOS_OWNED: u32 = 1 << 16;
+pub const LEGSUP_OS_OWNED: u32 = 1 << 24;
+
+/// Transfer Request Block
+#[repr(C)]
+#[derive(Copy,Clone,Default)]
+pub struct Trb
+{
+	pub param: u64,
+	pub status: u32,
+	//  0    = Cycle
+	//  1    = Evaluate Next TRB / Toggle Cycle (Link)
+	//  2    = Interrupt on Short Packet / Event Data (event)
+	//  4    = Chain
+	//  5    = Interrupt On Completion
+	//  6    = Immediate Data
+	// 10:15 = TRB Type
+	// 16:17 = Transfer Type (Setup)
+	// 16    = Direction (Data/Status)
+	// 24:31 = Slot ID (command/event)
+	pub control: u32,
+}
+pub const TRB_SIZE: usize = 16;
+
+pub const TRB_CYCLE: u32 = 1 << 0;
+pub const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+pub const TRB_ISP: u32 = 1 << 2;
+pub const TRB_CHAIN: u32 = 1 << 4;
+pub const TRB_IOC: u32 = 1 << 5;
+pub const TRB_IDT: u32 = 1 << 6;
+pub const TRB_DIR_IN: u32 = 1 << 16;
+
+/// TRB types
+pub mod trb_type {
+	pub const NORMAL	: u32 = 1;
+	pub const SETUP	: u32 = 2;
+	pub const DATA	: u32 = 3;
+	pub const STATUS	: u32 = 4;
+	pub const ISOCH	: u32 = 5;
+	pub const LINK	: u32 = 6;
+	pub const ENABLE_SLOT	: u32 = 9;
+	pub const DISABLE_SLOT	: u32 = 10;
+	pub const ADDRESS_DEVICE	: u32 = 11;
+	pub const CONFIGURE_ENDPOINT	: u32 = 12;
+	pub const EVALUATE_CONTEXT	: u32 = 13;
+	pub const RESET_ENDPOINT	: u32 = 14;
+	pub const STOP_ENDPOINT	: u32 = 15;
+	pub const SET_TR_DEQUEUE	: u32 = 16;
+	pub const TRANSFER_EVENT	: u32 = 32;
+	pub const COMMAND_COMPLETION	: u32 = 33;
+	pub const PORT_STATUS_CHANGE	: u32 = 34;
+}
+pub fn trb_type(control: u32) -> u32 {
+	(control >> 10) & 0x3F
+}
+
+/// Completion codes
+#[allow(dead_code)]
+pub mod cc {
+	pub const SUCCESS	: u8 = 1;
+	pub const DATA_BUFFER	: u8 = 2;
+	pub const BABBLE	: u8 = 3;
+	pub const USB_TRANSACTION	: u8 = 4;
+	pub const TRB	: u8 = 5;
+	pub const STALL	: u8 = 6;
+	pub const NO_SLOTS	: u8 = 9;
+	pub const SHORT_PACKET	: u8 = 13;
+	pub const RING_UNDERRUN	: u8 = 14;
+	pub const RING_OVERRUN	: u8 = 15;
+	pub const MISSED_SERVICE	: u8 = 23;
+	pub const STOPPED	: u8 = 26;
+	pub const STOPPED_LENGTH_INVALID	: u8 = 27;
+}
+
+/// Event Ring Segment Table entry
+#[repr(C)]
+pub struct ErstEntry
+{
+	pub base: u64,
+	pub size: u32,
+	_rsvd: u32,
+}
+
+// Slot context (dword offsets)
+//  0: 0:19 = Route String, 20:23 = Speed, 25 = MTT, 26 = Hub, 27:31 = Context Entries
+//  1: 16:23 = Root Hub Port Number, 24:31 = Number of Ports
+//  2: 0:7 = TT Hub Slot ID, 8:15 = TT Port Number
+//  3: 0:7 = USB Device Address, 27:31 = Slot State
+// Endpoint context (dword offsets)
+//  0: 16:23 = Interval
+//  1: 1:2 = Error Count, 3:5 = Endpoint Type, 16:31 = Max Packet Size
+//  2/3: TR Dequeue Pointer (bit 0 = Dequeue Cycle State)
+//  4: 0:15 = Average TRB Length, 16:31 = Max ESIT Payload (low)
+
+/// Endpoint types (endpoint context)
+pub mod ep_type {
+	pub const ISOCH_OUT	: u32 = 1;
+	pub const BULK_OUT	: u32 = 2;
+	pub const INTERRUPT_OUT	: u32 = 3;
+	pub const CONTROL	: u32 = 4;
+	pub const ISOCH_IN	: u32 = 5;
+	pub const BULK_IN	: u32 = 6;
+	pub const INTERRUPT_IN	: u32 = 7;
+}
@@ -0,0 +1,538 @@
+// "Tifflin" Kernel - xHCI USB driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_xhci/lib.rs
+//! eXtensible Host Controller Interface (USB 3) driver
+#![no_std]
+#![feature(linkage)]	// for module_define!
+use kernel::prelude::*;
+use kernel::_async3 as async;
+use kernel::lib::mem::aref::{Aref,ArefBorrow};
+use kernel::lib::mem::Arc;
+use kernel::memory::virt::AllocHandle;
+use core::sync::atomic::{AtomicPtr,AtomicUsize,Ordering};
+
+#[macro_use]
+extern crate kernel;
+extern crate usb_core;
+
+mod hw;
+mod pci;
+mod ring;
+mod dma;
+mod device;
+
+module_define!{usb_xhci, [usb_core], init}
+
+fn init()
+{
+	static PCI_DRIVER: pci::PciDriver = pci::PciDriver;
+	::kernel::device_manager::register_driver(&PCI_DRIVER);
+}
+
+/// Offset of the event ring segment table within the DCBAA page
+const ERST_OFS: usize = 2048;
+/// Number of endpoint slots per device in `HostInner::rings` (DCI 1-31, zero unused)
+const RINGS_PER_SLOT: usize = 32;
+
+struct BusDev
+{
+	host: Aref<HostInner>,
+}
+struct UsbHost
+{
+	host: ArefBorrow<HostInner>,
+}
+struct HostInner
+{
+	regs: Regs,
+	irq_handle: Option<::kernel::irqs::ObjectHandle>,
+	nports: u8,
+	/// Size of a context structure in bytes (32 or 64)
+	context_size: usize,
+	/// Controller can address memory above 4GB
+	ac64: bool,
+	/// Device Context Base Address Array (the event ring segment table is in the second half)
+	dcbaa: AllocHandle,
+	/// Scratchpad pages (and the array pointing to them), owned by the controller
+	_scratchpad: Vec<AllocHandle>,
+	command_ring: ::kernel::sync::Spinlock<ring::Ring>,
+	event_ring: ::kernel::sync::Spinlock<ring::EventRing>,
+	waiter_idx: AtomicUsize,
+	waiter_ptr: AtomicPtr<::kernel::sync::Queue<(usize,usize)>>,
+
+	/// Transfer rings, indexed by `slot_id * RINGS_PER_SLOT + dci` (used to route transfer events)
+	rings: ::kernel::sync::Spinlock<Vec<Option<Arc<device::TransferRing>>>>,
+	/// Enabled device slots, indexed by slot ID
+	slots: ::kernel::sync::Mutex<Vec<Option<Arc<device::DeviceSlot>>>>,
+	/// Slot ID for each USB address (addresses are assigned by usb_core, slots by the controller)
+	addr_slots: ::kernel::sync::Mutex<[u8; 128]>,
+}
+/// Register access (capability, operational, runtime and doorbell registers)
+struct Regs
+{
+	io: ::kernel::device_manager::IOBinding,
+	op: usize,
+	rt: usize,
+	db: usize,
+}
+
+impl BusDev
+{
+	fn new_boxed(irq: u32, io: ::kernel::device_manager::IOBinding) -> Result<Box<BusDev>, &'static str>
+	{
+		Ok(Box::new(BusDev {
+			host: HostInner::new_aref(irq, io)?
+			}))
+	}
+}
+impl ::kernel::device_manager::DriverInstance for BusDev
+{
+}
+
+impl Regs
+{
+	fn cap32(&self, ofs: usize) -> u32 {
+		// SAFE: Register reads have no side-effects
+		unsafe { self.io.read_32(ofs) }
+	}
+	unsafe fn cap_write32(&self, ofs: usize, v: u32) {
+		self.io.write_32(ofs, v);
+	}
+	fn op32(&self, ofs: usize) -> u32 {
+		// SAFE: Register reads have no side-effects
+		unsafe { self.io.read_32(self.op + ofs) }
+	}
+	unsafe fn op_write32(&self, ofs: usize, v: u32) {
+		self.io.write_32(self.op + ofs, v);
+	}
+	unsafe fn op_write64(&self, ofs: usize, v: u64) {
+		self.io.write_32(self.op + ofs, v as u32);
+		self.io.write_32(self.op + ofs + 4, (v >> 32) as u32);
+	}
+	fn rt32(&self, ofs: usize) -> u32 {
+		// SAFE: Register reads have no side-effects
+		unsafe { self.io.read_32(self.rt + ofs) }
+	}
+	unsafe fn rt_write32(&self, ofs: usize, v: u32) {
+		self.io.write_32(self.rt + ofs, v);
+	}
+	unsafe fn rt_write64(&self, ofs: usize, v: u64) {
+		self.io.write_32(self.rt + ofs, v as u32);
+		self.io.write_32(self.rt + ofs + 4, (v >> 32) as u32);
+	}
+	/// Ring a doorbell (slot zero is the command ring)
+	unsafe fn ring_doorbell(&self, slot: u8, target: u8) {
+		self.io.write_32(self.db + slot as usize * 4, target as u32);
+	}
+
+	fn portsc(&self, port: usize) -> u32 {
+		self.op32(op_portsc(port))
+	}
+	/// Write to a port's status register, keeping the read-write bits and clearing no change bits except `v`'s
+	unsafe fn update_portsc(&self, port: usize, v: u32) {
+		let cur = self.portsc(port);
+		self.op_write32(op_portsc(port), (cur & hw::PORTSC_PRESERVE) | v);
+	}
+}
+fn op_portsc(port: usize) -> usize {
+	hw::op::PORTSC_BASE + port * 0x10
+}
+
+/// Poll a condition for up to `ms` milliseconds
+fn wait_for<F: Fn()->bool>(ms: u64, cond: F) -> bool
+{
+	::kernel::time::wait_for(ms, 1, cond)
+}
+
+impl HostInner
+{
+	fn new_aref(irq: u32, io: ::kernel::device_manager::IOBinding) -> Result<Aref<HostInner>, &'static str>
+	{
+		// SAFE: No side-effects
+		let (caplength, version) = unsafe { (io.read_8(hw::cap::CAPLENGTH) as usize, io.read_32(hw::cap::CAPLENGTH) >> 16) };
+		let regs = Regs {
+			op: caplength,
+			rt: (io_read(&io, hw::cap::RTSOFF) & !0x1F) as usize,
+			db: (io_read(&io, hw::cap::DBOFF) & !0x3) as usize,
+			io: io,
+			};
+		let hcs1 = regs.cap32(hw::cap::HCSPARAMS1);
+		let hcs2 = regs.cap32(hw::cap::HCSPARAMS2);
+		let hcc1 = regs.cap32(hw::cap::HCCPARAMS1);
+		let max_slots = (hcs1 & 0xFF) as u8;
+		let nports = (hcs1 >> 24) as u8;
+		log_notice!("Card {:?} version {:#x}, {} slots, {} ports", regs.io, version, max_slots, nports);
+
+		// - Take ownership from the firmware
+		Self::bios_handoff(&regs, (hcc1 >> 16) as usize * 4);
+
+		// Stop and reset the controller
+		// SAFE: No memory addresses (controller isn't using any yet)
+		unsafe {
+			regs.op_write32(hw::op::USBCMD, regs.op32(hw::op::USBCMD) & !hw::USBCMD_RS);
+			if !wait_for(20, || regs.op32(hw::op::USBSTS) & hw::USBSTS_HCH != 0) {
+				log_warning!("Controller didn't halt, resetting anyway");
+			}
+			regs.op_write32(hw::op::USBCMD, hw::USBCMD_HCRST);
+		}
+		if !wait_for(1000, || regs.op32(hw::op::USBCMD) & hw::USBCMD_HCRST == 0 && regs.op32(hw::op::USBSTS) & hw::USBSTS_CNR == 0) {
+			return Err("Controller reset timed out");
+		}
+
+		let context_size = if hcc1 & (1 << 2) != 0 { 64 } else { 32 };
+		let ac64 = hcc1 & (1 << 0) != 0;
+		if regs.op32(hw::op::PAGESIZE) & 1 == 0 {
+			return Err("Controller doesn't support 4KB pages");
+		}
+
+		// Device context array (and the event ring segment table)
+		let mut dcbaa = ::kernel::memory::virt::alloc_dma(32, 1, "usb_xhci")?;
+		for v in dcbaa.as_mut_slice::<u8>(0, ::kernel::PAGE_SIZE) {
+			*v = 0;
+		}
+		let dcbaa_phys = ::kernel::memory::virt::get_phys(dcbaa.as_ref::<u8>(0)) as u64;
+
+		// Scratchpad buffers (memory the controller uses internally)
+		let n_scratch = ((hcs2 >> 27) & 0x1F | ((hcs2 >> 21) & 0x1F) << 5) as usize;
+		let mut scratchpad = Vec::new();
+		if n_scratch > 0
+		{
+			log_debug!("{} scratchpad pages", n_scratch);
+			let mut array = ::kernel::memory::virt::alloc_dma(32, 1, "usb_xhci")?;
+			for i in 0 .. n_scratch
+			{
+				let mut page = ::kernel::memory::virt::alloc_dma(32, 1, "usb_xhci")?;
+				for v in page.as_mut_slice::<u8>(0, ::kernel::PAGE_SIZE) {
+					*v = 0;
+				}
+				*array.as_mut::<u64>(i * 8) = ::kernel::memory::virt::get_phys(page.as_ref::<u8>(0)) as u64;
+				scratchpad.push(page);
+			}
+			*dcbaa.as_mut::<u64>(0) = ::kernel::memory::virt::get_phys(array.as_ref::<u8>(0)) as u64;
+			scratchpad.push(array);
+		}
+
+		let command_ring = ring::Ring::new()?;
+		let event_ring = ring::EventRing::new()?;
+		{
+			let erst: &mut hw::ErstEntry = dcbaa.as_mut(ERST_OFS);
+			erst.base = event_ring.phys();
+			erst.size = event_ring.size() as u32;
+		}
+
+		// SAFE: All addresses are of memory owned by this driver (and outlive the controller's use of them)
+		unsafe {
+			regs.op_write32(hw::op::CONFIG, max_slots as u32);
+			regs.op_write64(hw::op::DCBAAP, dcbaa_phys);
+			regs.op_write64(hw::op::CRCR, command_ring.phys() | 1);	// Ring Cycle State = 1
+			regs.rt_write32(hw::intr::ERSTSZ, 1);
+			regs.rt_write64(hw::intr::ERDP, event_ring.dequeue_phys());
+			regs.rt_write64(hw::intr::ERSTBA, dcbaa_phys + ERST_OFS as u64);
+			regs.rt_write32(hw::intr::IMOD, 0);
+			regs.rt_write32(hw::intr::IMAN, 0b11);	// Clear pending, enable
+		}
+
+		let mut inner_aref = Aref::new(HostInner {
+			regs: regs,
+			irq_handle: None,	// Filled below, once the allocation is made
+			nports: nports,
+			context_size: context_size,
+			ac64: ac64,
+			dcbaa: dcbaa,
+			_scratchpad: scratchpad,
+			command_ring: ::kernel::sync::Spinlock::new(command_ring),
+			event_ring: ::kernel::sync::Spinlock::new(event_ring),
+			waiter_idx: Default::default(),
+			waiter_ptr: Default::default(),
+			rings: ::kernel::sync::Spinlock::new( (0 .. (max_slots as usize + 1) * RINGS_PER_SLOT).map(|_| None).collect() ),
+			slots: ::kernel::sync::Mutex::new( (0 .. max_slots as usize + 1).map(|_| None).collect() ),
+			addr_slots: ::kernel::sync::Mutex::new([0; 128]),
+			});
+
+		// Bind interrupt
+		{
+			struct RawSend<T: Send>(*const T);
+			unsafe impl<T: Send> Send for RawSend<T> {}
+			let ret_raw = RawSend(&*inner_aref);
+			// SAFE: Pointer _should_ be valid as long as this IRQ binding exists
+			Aref::get_mut(&mut inner_aref).unwrap().irq_handle = Some(::kernel::irqs::bind_object(irq, Box::new(move || unsafe { (*ret_raw.0).handle_irq() } )));
+		}
+
+		// Start the controller
+		{
+			let regs = &inner_aref.regs;
+			// SAFE: Controller structures are all initialised
+			unsafe { regs.op_write32(hw::op::USBCMD, hw::USBCMD_RS | hw::USBCMD_INTE); }
+			if !wait_for(20, || regs.op32(hw::op::USBSTS) & hw::USBSTS_HCH == 0) {
+				return Err("Controller didn't start");
+			}
+			// - Ensure all ports are powered
+			let mut powered = false;
+			for i in 0 .. nports as usize
+			{
+				if regs.portsc(i) & hw::PORTSC_PP == 0 {
+					// SAFE: No memory addresses
+					unsafe { regs.update_portsc(i, hw::PORTSC_PP); }
+					powered = true;
+				}
+			}
+			if powered {
+				::kernel::time::sleep_ms(20);
+			}
+		}
+
+		::usb_core::register_host(Box::new(UsbHost { host: inner_aref.borrow() }));
+		Ok(inner_aref)
+	}
+
+	/// Request ownership of the controller from the firmware (via the USB Legacy Support extended capability)
+	fn bios_handoff(regs: &Regs, mut ofs: usize)
+	{
+		while ofs != 0
+		{
+			let v = regs.cap32(ofs);
+			if v as u8 == hw::XCAP_LEGACY
+			{
+				if v & hw::LEGSUP_BIOS_OWNED != 0
+				{
+					log_notice!("Requesting controller from BIOS");
+					// SAFE: No memory addresses
+					unsafe { regs.cap_write32(ofs, v | hw::LEGSUP_OS_OWNED); }
+					if !wait_for(1000, || regs.cap32(ofs) & hw::LEGSUP_BIOS_OWNED == 0) {
+						log_warning!("BIOS didn't release the controller, continuing anyway");
+					}
+				}
+				// Disable SMIs (clearing any pending events)
+				// SAFE: No memory addresses
+				unsafe { regs.cap_write32(ofs + 4, 0xE000_0000); }
+				return ;
+			}
+			let next = (v >> 8) & 0xFF;
+			ofs = if next == 0 { 0 } else { ofs + next as usize * 4 };
+		}
+	}
+
+	fn handle_irq(&self) -> bool
+	{
+		let sts = self.regs.op32(hw::op::USBSTS);
+		if sts & (hw::USBSTS_EINT | hw::USBSTS_PCD) == 0 {
+			return false;
+		}
+		log_trace!("handle_irq: {:#x}", sts);
+		// SAFE: Write-1-to-clear status bits
+		unsafe {
+			self.regs.op_write32(hw::op::USBSTS, sts & (hw::USBSTS_EINT | hw::USBSTS_PCD));
+			self.regs.rt_write32(hw::intr::IMAN, 0b11);
+		}
+
+		let mut ev = self.event_ring.lock();
+		while let Some(trb) = ev.pop()
+		{
+			self.handle_event(&trb);
+		}
+		// SAFE: Address is within the event ring, and clears Event Handler Busy
+		unsafe { self.regs.rt_write64(hw::intr::ERDP, ev.dequeue_phys() | (1 << 3)); }
+		true
+	}
+	fn handle_event(&self, trb: &hw::Trb)
+	{
+		match hw::trb_type(trb.control)
+		{
+		hw::trb_type::TRANSFER_EVENT => {
+			let slot = (trb.control >> 24) as usize;
+			let dci = ((trb.control >> 16) & 0x1F) as usize;
+			let lh = self.rings.lock();
+			match lh.get(slot * RINGS_PER_SLOT + dci)
+			{
+			Some(&Some(ref r)) => self.transfer_event(r, trb),
+			_ => log_notice!("Transfer event for unknown endpoint (slot {}, DCI {})", slot, dci),
+			}
+			},
+		hw::trb_type::COMMAND_COMPLETION => {
+			let mut ring = self.command_ring.lock();
+			match ring.index_of(trb.param)
+			{
+			Some(idx) if ring.is_pending(idx) => {
+				let handle = ring.meta[idx].handle;
+				ring.release_to(idx);
+				let res = (trb.status >> 24) as usize | ((trb.control >> 24) as usize) << 8;
+				// SAFE: Handle was stored by `command`, and is only signalled once (the TRB is now released)
+				unsafe { ::core::mem::transmute::<usize, async::ObjectHandle>(handle as usize).signal(res); }
+				},
+			_ => log_warning!("Completion for unknown command TRB {:#x}", trb.param),
+			}
+			},
+		hw::trb_type::PORT_STATUS_CHANGE => {
+			let port = ((trb.param >> 24) & 0xFF) as usize;
+			log_debug!("Port status change: port {}", port);
+			let waiter = self.waiter_ptr.load(Ordering::SeqCst);
+			if port > 0 && !waiter.is_null()
+			{
+				// SAFE: Pointer is to a `&'static` set by `set_root_waiter`
+				unsafe { (*waiter).push( (self.waiter_idx.load(Ordering::SeqCst), port - 1) ); }
+			}
+			},
+		t => log_debug!("Unhandled event type {}", t),
+		}
+	}
+
+	/// Issue a command and wait for it to complete, returning the slot ID (or the failing completion code)
+	fn command(&self, trb: hw::Trb) -> Result<u8, u8>
+	{
+		let mut obj = async::Object::default();
+		{
+			let handle = obj.get_handle();
+			let mut stack = obj.get_stack();
+			stack.push_closure(|_async, _stack, res| Some(res)).unwrap();
+			let meta = ring::TrbMeta {
+				// SAFE: Stored as an integer until the completion event signals it
+				handle: unsafe { ::core::mem::transmute::<async::ObjectHandle, usize>(handle) } as u64,
+				..Default::default()
+				};
+			let _irq = ::kernel::sync::hold_interrupts();
+			self.command_ring.lock().push(&[trb], &[meta]);
+		}
+		// SAFE: Doorbell zero target zero is the command ring
+		unsafe { self.regs.ring_doorbell(0, 0); }
+
+		// TODO: Time out (and abort the command ring) if the controller doesn't respond
+		let res = match async::Waiter::new(&[&obj]).wait_one()
+			{
+			Some(r) => r.result,
+			None => return Err(0),
+			};
+		let cc = res as u8;
+		if cc == hw::cc::SUCCESS {
+			Ok( (res >> 8) as u8 )
+		}
+		else {
+			log_debug!("Command type {} failed: CC={}", hw::trb_type(trb.control), cc);
+			Err(cc)
+		}
+	}
+}
+fn io_read(io: &::kernel::device_manager::IOBinding, ofs: usize) -> u32 {
+	// SAFE: Register reads have no side-effects
+	unsafe { io.read_32(ofs) }
+}
+
+use ::usb_core::host::{EndpointAddr, PortFeature, Handle, DevicePath, Speed};
+use ::usb_core::host::{InterruptEndpoint, IsochEndpoint, ControlEndpoint, BulkEndpointIn, BulkEndpointOut};
+impl ::usb_core::host::HostController for UsbHost
+{
+	/// Begin polling an endpoint at the given rate (buffer used is allocated by the driver to be the interrupt endpoint's size)
+	fn init_interrupt(&self, endpoint: EndpointAddr, period_ms: usize, max_packet_size: usize, waiter: &'static ::kernel::sync::Queue<(usize,usize)>, waiter_idx: usize) -> Handle<InterruptEndpoint> {
+		self.host.open_interrupt(self.host.reborrow(), endpoint, period_ms, max_packet_size, waiter, waiter_idx)
+	}
+	fn init_isoch(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<IsochEndpoint> {
+		self.host.open_isoch(self.host.reborrow(), endpoint, max_packet_size)
+	}
+	fn init_control(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<ControlEndpoint> {
+		self.host.open_control(self.host.reborrow(), endpoint, max_packet_size)
+	}
+	fn init_bulk_out(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<BulkEndpointOut> {
+		self.host.open_bulk_out(self.host.reborrow(), endpoint, max_packet_size)
+	}
+	fn init_bulk_in(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<BulkEndpointIn> {
+		self.host.open_bulk_in(self.host.reborrow(), endpoint, max_packet_size)
+	}
+
+	fn set_address(&self, path: &DevicePath, addr: u8) -> Result<(), ::usb_core::host::Error> {
+		// The controller picks the address itself (Address Device command), usb_core's address maps to the slot
+		self.host.address_device(path, addr)
+	}
+	fn release_address(&self, addr: u8) {
+		self.host.release_address(addr);
+	}
+
+
+	// Root hub maintainence
+	fn set_port_feature(&self, port: usize, feature: PortFeature) {
+		log_trace!("set_port_feature({}, {:?})", port, feature);
+		let v = match feature
+			{
+			PortFeature::Suspend => hw::PORTSC_LWS | 3 << hw::PORTSC_PLS_SHIFT,	// U3
+			PortFeature::Reset   => hw::PORTSC_PR,
+			PortFeature::Power   => hw::PORTSC_PP,
+			PortFeature::Enable  => return,	// Ports are enabled by a reset
+			_ => return,
+			};
+		// SAFE: Can't cause memory unsafety
+		unsafe {
+			self.host.regs.update_portsc(port, v);
+		}
+	}
+	fn clear_port_feature(&self, port: usize, feature: PortFeature) {
+		log_trace!("clear_port_feature({}, {:?})", port, feature);
+		let portsc = self.host.regs.portsc(port);
+		let v = match feature
+			{
+			PortFeature::Enable  => hw::PORTSC_PED,	// Write 1 to disable
+			PortFeature::Suspend => hw::PORTSC_LWS | 0 << hw::PORTSC_PLS_SHIFT,	// U0
+			PortFeature::Power   => {
+				// SAFE: Can't cause memory unsafety
+				unsafe { self.host.regs.op_write32(op_portsc(port), portsc & hw::PORTSC_PRESERVE & !hw::PORTSC_PP); }
+				return ;
+				},
+			PortFeature::CConnection => hw::PORTSC_CSC,
+			PortFeature::CEnable     => hw::PORTSC_PEC,
+			PortFeature::CSuspend    => hw::PORTSC_PLC,
+			PortFeature::COverCurrent=> hw::PORTSC_OCC,
+			PortFeature::CReset      => hw::PORTSC_PRC | hw::PORTSC_WRC,
+			_ => return,
+			};
+		// SAFE: Can't cause memory unsafety
+		unsafe {
+			self.host.regs.update_portsc(port, v);
+		}
+	}
+	fn get_port_feature(&self, port: usize, feature: PortFeature) -> bool {
+		log_trace!("get_port_feature({}, {:?})", port, feature);
+		let v = self.host.regs.portsc(port);
+		let speed = (v >> hw::PORTSC_SPEED_SHIFT) & 0xF;
+		match feature
+		{
+		PortFeature::Connection  => v & hw::PORTSC_CCS != 0,
+		PortFeature::Enable      => v & hw::PORTSC_PED != 0,
+		PortFeature::Suspend     => (v >> hw::PORTSC_PLS_SHIFT) & 0xF == 3,
+		PortFeature::OverCurrent => v & hw::PORTSC_OCA != 0,
+		PortFeature::Reset       => v & hw::PORTSC_PR != 0,
+		PortFeature::Power       => v & hw::PORTSC_PP != 0,
+		PortFeature::LowSpeed    => speed == 2,
+		PortFeature::HighSpeed   => speed == 3,
+		PortFeature::CConnection => v & hw::PORTSC_CSC != 0,
+		PortFeature::CEnable     => v & hw::PORTSC_PEC != 0,
+		PortFeature::CSuspend    => v & hw::PORTSC_PLC != 0,
+		PortFeature::COverCurrent=> v & hw::PORTSC_OCC != 0,
+		PortFeature::CReset      => v & (hw::PORTSC_PRC | hw::PORTSC_WRC) != 0,
+		PortFeature::Test        => false,
+		PortFeature::Indicator   => false,
+		}
+	}
+	fn get_port_speed(&self, port: usize) -> Speed {
+		// Protocol speed ID (default mapping, the controller doesn't define custom IDs for USB2/3 ports)
+		match (self.host.regs.portsc(port) >> hw::PORTSC_SPEED_SHIFT) & 0xF
+		{
+		1 => Speed::Full,
+		2 => Speed::Low,
+		3 => Speed::High,
+		0 => Speed::Full,
+		_ => Speed::Super,
+		}
+	}
+	fn set_root_waiter(&mut self, waiter: &'static ::kernel::sync::Queue<(usize,usize)>, my_idx: usize) {
+		// 1. Store the waiter pointer
+		self.host.waiter_idx.store(my_idx, Ordering::SeqCst);
+		self.host.waiter_ptr.store(waiter as *const _ as *mut _, Ordering::SeqCst);
+		// 2. For each connected port, push an event/item
+		for i in 0 .. self.host.nports as usize
+		{
+			let v = self.host.regs.portsc(i);
+			log_debug!("set_root_waiter: Port {} - v={:#x}", i, v);
+			if v & hw::PORTSC_CCS != 0 {
+				waiter.push( (my_idx, i) );
+			}
+		}
+	}
+}
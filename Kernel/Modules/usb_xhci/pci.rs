@@ -0,0 +1,47 @@
+// "Tifflin" Kernel - xHCI USB driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_xhci/pci.rs
+//! PCI binding
+use kernel::prelude::*;
+
+pub struct PciDriver;
+
+impl ::kernel::device_manager::Driver for PciDriver {
+	fn name(&self) -> &str {
+		"xhci-pci"
+	}
+	fn bus_type(&self) -> &str {
+		"pci"
+	}
+	fn handles(&self, bus_dev: &::kernel::device_manager::BusDevice) -> u32
+	{
+		let class = bus_dev.get_attr("class").unwrap_u32();
+		if class & 0xFF_FF_FF_00 == 0x0C0330_00 {
+			1
+		}
+		else {
+			0
+		}
+	}
+	fn bind(&self, bus_dev: &mut ::kernel::device_manager::BusDevice) -> Box<::kernel::device_manager::DriverInstance+'static>
+	{
+		bus_dev.set_attr("bus_master", ::kernel::device_manager::AttrValue::U32(1));
+		let irq = bus_dev.get_irq(0);
+		let base = bus_dev.bind_io(0);
+
+		match ::BusDev::new_boxed(irq, base)
+		{
+		Ok(v) => v,
+		Err(e) => {
+			log_error!("Failed to initialise xHCI controller: {}", e);
+			Box::new(NullInstance)
+			},
+		}
+	}
+}
+
+/// Placeholder instance for a controller that failed to initialise
+struct NullInstance;
+impl ::kernel::device_manager::DriverInstance for NullInstance {
+}
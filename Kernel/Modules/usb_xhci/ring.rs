@@ -0,0 +1,230 @@
+// "Tifflin" Kernel - xHCI USB driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_xhci/ring.rs
+//! TRB rings (command/transfer producer rings, and the event ring)
+use kernel::prelude::*;
+use kernel::memory::virt::AllocHandle;
+use hw::{self, Trb};
+
+/// Number of TRBs in a ring (a single page, the last entry of producer rings is a link TRB)
+pub const RING_SIZE: usize = 4096 / hw::TRB_SIZE;
+/// Usable entries in a producer ring
+const RING_USABLE: usize = RING_SIZE - 1;
+
+/// TRB bytes count towards the transfer result
+pub const TRBF_COUNTED: u8 = 1 << 0;
+/// Part of a control transfer (a short packet doesn't end the transfer, the status stage still runs)
+pub const TRBF_CONTROL: u8 = 1 << 1;
+
+/// Driver metadata for a queued TRB
+#[derive(Copy,Clone,Default)]
+pub struct TrbMeta
+{
+	/// Async handle to signal on completion (or buffer slot for interrupt polls)
+	pub handle: u64,
+	/// Data length of this TRB
+	pub len: u32,
+	/// Counted bytes in earlier TRBs of the same transfer
+	pub before: u32,
+	/// Bytes transferred, recorded on the final TRB when a control transfer's data stage is short
+	pub short_count: Option<u32>,
+	/// Index of the transfer's final TRB
+	pub final_idx: u16,
+	pub flags: u8,
+}
+
+/// A producer ring (command ring, or an endpoint's transfer ring)
+pub struct Ring
+{
+	handle: AllocHandle,
+	phys: u64,
+	enqueue: usize,
+	dequeue: usize,
+	cycle: bool,
+	/// Number of TRBs owned by the controller
+	in_use: usize,
+	pub meta: Vec<TrbMeta>,
+}
+impl Ring
+{
+	pub fn new() -> Result<Ring, ::kernel::memory::virt::MapError>
+	{
+		let mut handle = ::kernel::memory::virt::alloc_dma(64, 1, "usb_xhci")?;
+		for v in handle.as_mut_slice::<u8>(0, 4096) {
+			*v = 0;
+		}
+		let phys = ::kernel::memory::virt::get_phys(handle.as_ref::<u8>(0)) as u64;
+		// Final entry links back to the start, toggling the cycle state
+		*handle.as_mut::<Trb>((RING_SIZE - 1) * hw::TRB_SIZE) = Trb {
+			param: phys,
+			status: 0,
+			control: hw::trb_type::LINK << 10 | hw::TRB_TOGGLE_CYCLE,
+			};
+		Ok(Ring {
+			handle: handle,
+			phys: phys,
+			enqueue: 0,
+			dequeue: 0,
+			cycle: true,
+			in_use: 0,
+			meta: vec![Default::default(); RING_SIZE],
+			})
+	}
+	pub fn phys(&self) -> u64 {
+		self.phys
+	}
+	/// Physical address of the next TRB to be written, with the cycle state in bit 0 (for Set TR Dequeue Pointer)
+	pub fn enqueue_ptr(&self) -> u64 {
+		self.phys + (self.enqueue * hw::TRB_SIZE) as u64 | self.cycle as u64
+	}
+	pub fn free_space(&self) -> usize {
+		RING_USABLE - self.in_use
+	}
+	/// Convert a TRB address (from an event) into an index
+	pub fn index_of(&self, phys: u64) -> Option<usize> {
+		if phys >= self.phys && phys < self.phys + (RING_USABLE * hw::TRB_SIZE) as u64 {
+			Some( ((phys - self.phys) / hw::TRB_SIZE as u64) as usize )
+		}
+		else {
+			None
+		}
+	}
+	/// Check if a TRB is still owned by the controller (events for released TRBs are stale)
+	pub fn is_pending(&self, idx: usize) -> bool {
+		(idx + RING_USABLE - self.dequeue) % RING_USABLE < self.in_use
+	}
+
+	/// Add a set of TRBs (a transfer, or a command), returning the index of the last
+	///
+	/// The first TRB's cycle bit is written last, so the controller never sees a partial transfer.
+	pub fn push(&mut self, trbs: &[Trb], meta: &[TrbMeta]) -> usize
+	{
+		assert!(trbs.len() > 0 && trbs.len() <= self.free_space());
+		assert!(trbs.len() == meta.len());
+		let first_idx = self.enqueue;
+		let first_cycle = self.cycle;
+		let mut last = 0;
+		for (i,(trb,m)) in Iterator::zip(trbs.iter(), meta.iter()).enumerate()
+		{
+			let idx = self.enqueue;
+			let cycle = if i == 0 { !self.cycle } else { self.cycle };
+			let mut v = *trb;
+			v.control = (v.control & !hw::TRB_CYCLE) | cycle as u32;
+			// SAFE: Index is within the ring page, and the entry is owned by software
+			unsafe { ::core::ptr::write_volatile(self.trb_ptr(idx), v); }
+			self.meta[idx] = *m;
+			last = idx;
+			self.advance(trb.control & hw::TRB_CHAIN != 0);
+		}
+		self.meta[last].final_idx = last as u16;
+		for i in 0 .. trbs.len() - 1
+		{
+			let idx = (first_idx + i) % RING_USABLE;
+			self.meta[idx].final_idx = last as u16;
+		}
+		// Hand the TRBs to the controller
+		// SAFE: Index is within the ring
+		unsafe {
+			let p = self.trb_ptr(first_idx);
+			let ctrl = ::core::ptr::read_volatile(&(*p).control);
+			::core::ptr::write_volatile(&mut (*p).control, (ctrl & !hw::TRB_CYCLE) | first_cycle as u32);
+		}
+		self.in_use += trbs.len();
+		last
+	}
+	fn advance(&mut self, chained: bool)
+	{
+		self.enqueue += 1;
+		if self.enqueue == RING_USABLE
+		{
+			// Pass the link TRB to the controller (chained if it's within a transfer) and wrap
+			// SAFE: Link TRB is within the ring
+			unsafe {
+				let p = self.trb_ptr(RING_USABLE);
+				let ctrl = hw::trb_type::LINK << 10 | hw::TRB_TOGGLE_CYCLE | if chained { hw::TRB_CHAIN } else { 0 };
+				::core::ptr::write_volatile(&mut (*p).control, ctrl | self.cycle as u32);
+			}
+			self.enqueue = 0;
+			self.cycle = !self.cycle;
+		}
+	}
+	/// Release TRBs up to (and including) `idx` once their transfer has completed
+	pub fn release_to(&mut self, idx: usize)
+	{
+		let count = (idx + RING_USABLE - self.dequeue) % RING_USABLE + 1;
+		assert!(count <= self.in_use, "Releasing {} TRBs with only {} in use", count, self.in_use);
+		self.in_use -= count;
+		self.dequeue = (idx + 1) % RING_USABLE;
+	}
+	/// Release all TRBs (after the dequeue pointer has been moved to the enqueue position)
+	///
+	/// Returns the handles of the transfers that were still queued
+	pub fn release_all(&mut self) -> Vec<u64>
+	{
+		let handles = (0 .. self.in_use)
+			.map(|i| (self.dequeue + i) % RING_USABLE)
+			.filter(|&idx| self.meta[idx].final_idx as usize == idx)
+			.map(|idx| self.meta[idx].handle)
+			.collect();
+		self.in_use = 0;
+		self.dequeue = self.enqueue;
+		handles
+	}
+
+	fn trb_ptr(&self, idx: usize) -> *mut Trb {
+		// SAFE: Returned as a raw pointer
+		unsafe { self.handle.as_int_mut::<Trb>(idx * hw::TRB_SIZE) }
+	}
+}
+
+/// The event ring (a single segment)
+pub struct EventRing
+{
+	handle: AllocHandle,
+	phys: u64,
+	dequeue: usize,
+	cycle: bool,
+}
+impl EventRing
+{
+	pub fn new() -> Result<EventRing, ::kernel::memory::virt::MapError>
+	{
+		let mut handle = ::kernel::memory::virt::alloc_dma(64, 1, "usb_xhci")?;
+		for v in handle.as_mut_slice::<u8>(0, 4096) {
+			*v = 0;
+		}
+		let phys = ::kernel::memory::virt::get_phys(handle.as_ref::<u8>(0)) as u64;
+		Ok(EventRing {
+			handle: handle,
+			phys: phys,
+			dequeue: 0,
+			cycle: true,
+			})
+	}
+	pub fn phys(&self) -> u64 {
+		self.phys
+	}
+	pub fn size(&self) -> usize {
+		RING_SIZE
+	}
+	/// Address to write to ERDP
+	pub fn dequeue_phys(&self) -> u64 {
+		self.phys + (self.dequeue * hw::TRB_SIZE) as u64
+	}
+	/// Obtain the next event written by the controller
+	pub fn pop(&mut self) -> Option<Trb>
+	{
+		// SAFE: Index is within the page, and the controller only writes whole entries before setting the cycle bit
+		let trb = unsafe { ::core::ptr::read_volatile(self.handle.as_int_mut::<Trb>(self.dequeue * hw::TRB_SIZE)) };
+		if (trb.control & hw::TRB_CYCLE != 0) != self.cycle {
+			return None;
+		}
+		self.dequeue += 1;
+		if self.dequeue == RING_SIZE {
+			self.dequeue = 0;
+			self.cycle = !self.cycle;
+		}
+		Some(trb)
+	}
+}
@@ -34,17 +34,17 @@ struct Volume<I: Interface>
 
 impl BlockDevice
 {
-	pub fn new<T: Interface+Send+'static>(mut int: T) -> Self {
+	pub fn new<T: Interface+Send+Sync+'static>(mut int: T) -> Self {
 		// SAFE: Readable registers
 		let capacity = unsafe { int.cfg_read_32(0) as u64 | ((int.cfg_read_32(4) as u64) << 32) };
 		log_debug!("Block Device: {}", storage::SizePrinter(capacity * 512));
 
-		let requestq = int.get_queue(0, 0).expect("Queue #0 'requestq' missing on virtio block device");
-	
+		// NOTE: Features must be negotiated before queues are set up (required for 1.0 devices)
 		let features = int.negotiate_features( VIRTIO_BLK_F_RO );
 		if features & VIRTIO_BLK_F_RO != 0 {
 			// TODO: Need a way of indicating to the upper layers that a volume is read-only
 		}
+		let requestq = int.get_queue(0, 0).expect("Queue #0 'requestq' missing on virtio block device");
 		int.set_driver_ok();
 
 		let mut vol = Box::new(Volume {
@@ -77,7 +77,7 @@ struct VirtioBlockReq
 unsafe impl ::kernel::lib::POD for VirtioBlockReq {}
 
 const BLOCK_SIZE: usize = 512;
-impl<I: Interface+Send+'static> storage::PhysicalVolume for Volume<I>
+impl<I: Interface+Send+Sync+'static> storage::PhysicalVolume for Volume<I>
 {
 	fn name(&self) -> &str { "virtio0" }
 	fn blocksize(&self) -> usize { BLOCK_SIZE }
@@ -127,9 +127,10 @@ impl<I: Interface+Send+'static> storage::PhysicalVolume for Volume<I>
 			Buffer::Read( src ),
 			Buffer::Write( ::kernel::lib::as_byte_slice_mut(&mut status) )
 			]);
+		// NOTE: The used length only counts bytes written by the device (the status byte), so report the requested count
 		let rv = match h.wait_for_completion()
 			{
-			Ok(bytes) => Ok( bytes / BLOCK_SIZE ),
+			Ok(_) => Ok( num ),
 			Err( () ) => Err( storage::IoError::Unknown("VirtIO") ),
 			};
 
@@ -0,0 +1,285 @@
+// "Tifflin" Kernel - VirtIO Driver
+// - By John Hodge (thePowersGang)
+//
+// virtio/devices/console.rs
+//! VirtIO console device
+//!
+//! The first port is used as both a kernel log sink and a character device (`console<N>`)
+use kernel::prelude::*;
+use kernel::sync::{Mutex,Spinlock};
+use kernel::lib::mem::aref::Aref;
+use kernel::lib::ring_buffer::RingBuf;
+use kernel::memory::virt::AllocHandle;
+use kernel::metadevs::character;
+use kernel::logging;
+use core::sync::atomic::{AtomicBool,Ordering};
+use interface::Interface;
+use queue::{Queue,Buffer,Request};
+
+#[allow(dead_code)]
+mod defs {
+pub const VIRTIO_CONSOLE_F_SIZE       	: u32 = 1 << 0;
+pub const VIRTIO_CONSOLE_F_MULTIPORT  	: u32 = 1 << 1;
+pub const VIRTIO_CONSOLE_F_EMERG_WRITE	: u32 = 1 << 2;
+}
+
+const RECEIVEQ: usize = 0;
+const TRANSMITQ: usize = 1;
+
+const RX_BUF_SIZE: usize = 512;
+const N_RX_BUFS: usize = ::kernel::PAGE_SIZE / RX_BUF_SIZE;
+/// Size of the buffered (not yet sent/read) data in each direction
+const RING_SIZE: usize = 4096;
+
+pub struct ConsoleDevice<I: Interface+Send+Sync+'static>
+{
+	// NOTE: These borrow `dev`, and are released in Drop
+	log_sink: Option<logging::ExternalSinkHandle>,
+	chardev: Option<character::Registration>,
+	_worker: ::kernel::threads::WorkerThread,
+	dev: Aref<Console<I>>,
+}
+
+struct Console<I: Interface+Send+Sync+'static>
+{
+	// NOTE: Holds requests that borrow the queue and buffers below, so must be dropped first
+	rx_active: Mutex<Vec<Option<Request<'static>>>>,
+
+	/// Received data waiting to be read
+	rx_data: Spinlock<RingBuf<u8>>,
+	/// Data waiting to be sent (written by the log sink, so must only be locked with interrupts held)
+	tx_data: Spinlock<RingBuf<u8>>,
+	/// Sleep object for the transmit worker
+	tx_waiter: Spinlock<Option<::kernel::threads::SleepObjectRef>>,
+	tx_complete: AtomicBool,
+	stop: AtomicBool,
+
+	interface: I,
+	receiveq: Queue,
+	transmitq: Queue,
+	rx_buffers: AllocHandle,
+	tx_buffer: AllocHandle,
+}
+
+impl<I: Interface+Send+Sync+'static> ConsoleDevice<I>
+{
+	pub fn new(mut int: I) -> Self {
+		// NOTE: Only the first port is used, so multiport isn't negotiated
+		let _features = int.negotiate_features(0);
+		let receiveq = int.get_queue(RECEIVEQ, 0).expect("Queue #0 'receiveq' missing on virtio console device");
+		let transmitq = int.get_queue(TRANSMITQ, 0).expect("Queue #1 'transmitq' missing on virtio console device");
+
+		let mut dev = Aref::new(Console {
+			rx_active: Mutex::new( (0 .. N_RX_BUFS).map(|_| None).collect() ),
+			rx_data: Spinlock::new(RingBuf::new(RING_SIZE)),
+			tx_data: Spinlock::new(RingBuf::new(RING_SIZE)),
+			tx_waiter: Spinlock::new(None),
+			tx_complete: AtomicBool::new(false),
+			stop: AtomicBool::new(false),
+			interface: int,
+			receiveq: receiveq,
+			transmitq: transmitq,
+			rx_buffers: ::kernel::memory::virt::alloc_dma(64, 1, "VirtIO-Console").expect("TODO: Handle alloc failure VirtIO console buffers"),
+			tx_buffer: ::kernel::memory::virt::alloc_dma(64, 1, "VirtIO-Console").expect("TODO: Handle alloc failure VirtIO console buffers"),
+			});
+
+		struct SPtr<T>(*const T);
+		unsafe impl<T> Send for SPtr<T> {}
+		let sp = SPtr(&*dev);
+		{
+			let d = Aref::get_mut(&mut dev).unwrap();
+			// SAFE: Aref contents have a stable address, and won't be invalidated until after Drop is called
+			d.interface.bind_interrupt( Box::new(move || unsafe { (*sp.0).handle_irq() }) );
+			d.interface.set_driver_ok();
+		}
+		for i in 0 .. N_RX_BUFS {
+			dev.post_rx(&mut dev.rx_active.lock(), i);
+		}
+
+		let worker_dev = dev.borrow();
+		ConsoleDevice {
+			log_sink: logging::register_external_sink(dev.borrow()),
+			chardev: Some( character::register("console", dev.borrow()) ),
+			_worker: ::kernel::threads::WorkerThread::new("VirtIO Console", move || worker_dev.tx_worker()),
+			dev: dev,
+			}
+	}
+}
+impl<I: Interface+Send+Sync+'static> ::kernel::device_manager::DriverInstance for ConsoleDevice<I> {
+}
+impl<I: Interface+Send+Sync+'static> ::core::ops::Drop for ConsoleDevice<I>
+{
+	fn drop(&mut self) {
+		self.log_sink = None;
+		self.chardev = None;
+		// Stop the worker, and wait for it to release its borrow
+		self.dev.stop.store(true, Ordering::Release);
+		self.dev.signal_worker();
+		while Aref::get_mut(&mut self.dev).is_none() {
+			::kernel::threads::yield_time();
+		}
+	}
+}
+
+impl<I: Interface+Send+Sync+'static> Console<I>
+{
+	/// Hand a receive buffer to the device
+	fn post_rx(&self, active: &mut [Option<Request<'static>>], idx: usize) {
+		assert!(active[idx].is_none());
+		// SAFE: The buffer isn't in use by the device, and `rx_active` is dropped before the buffers and queue
+		let req = unsafe {
+			let buf: &'static mut [u8] = &mut *(self.rx_buffers.as_int_mut_slice(idx * RX_BUF_SIZE, RX_BUF_SIZE) as *mut [u8]);
+			let queue: &'static Queue = &*(&self.receiveq as *const _);
+			queue.send_buffers(&self.interface, &mut [ Buffer::Write(buf) ])
+			};
+		active[idx] = Some(req);
+	}
+
+	fn handle_irq(&self) -> bool {
+		// Transmit: At most one request is active
+		let mut tx_done = false;
+		self.transmitq.collect_used(|_id, _len| tx_done = true);
+		if tx_done {
+			self.tx_complete.store(true, Ordering::Release);
+			self.signal_worker();
+		}
+
+		// Receive: Copy into the ring buffer and hand the buffer back
+		let mut active = self.rx_active.lock();
+		self.receiveq.collect_used(|id, len| {
+			match active.iter().position(|r| r.as_ref().map(|r| r.id()) == Some(id))
+			{
+			Some(idx) => {
+				active[idx] = None;
+				let data = self.rx_buffers.as_slice::<u8>(idx * RX_BUF_SIZE, ::core::cmp::min(len, RX_BUF_SIZE));
+				{
+					let _irq = ::kernel::sync::hold_interrupts();
+					let mut lh = self.rx_data.lock();
+					for &b in data {
+						if lh.push_back(b).is_err() {
+							log_notice!("Console receive buffer full, dropping data");
+							break;
+						}
+					}
+				}
+				self.post_rx(&mut active, idx);
+				},
+			None => log_warning!("RX completion for unknown descriptor {}", id),
+			}
+			});
+		true
+	}
+
+	fn signal_worker(&self) {
+		let _irq = ::kernel::sync::hold_interrupts();
+		if let Some(ref v) = *self.tx_waiter.lock() {
+			v.signal();
+		}
+	}
+	fn push_tx(&self, data: &[u8]) -> usize {
+		let _irq = ::kernel::sync::hold_interrupts();
+		let mut lh = self.tx_data.lock();
+		for (i, &b) in data.iter().enumerate() {
+			if lh.push_back(b).is_err() {
+				return i;
+			}
+		}
+		data.len()
+	}
+
+	fn tx_worker(&self) {
+		let so = ::kernel::threads::SleepObject::new("VirtIO Console TX");
+		{
+			let _irq = ::kernel::sync::hold_interrupts();
+			*self.tx_waiter.lock() = Some(so.get_ref());
+		}
+		while ! self.stop.load(Ordering::Acquire)
+		{
+			self.flush_tx(&so);
+			so.wait();
+		}
+		let _irq = ::kernel::sync::hold_interrupts();
+		*self.tx_waiter.lock() = None;
+	}
+	/// Send all buffered data
+	fn flush_tx(&self, so: &::kernel::threads::SleepObject) {
+		loop
+		{
+			// SAFE: Only the worker accesses the transmit buffer, and it's not in use by the device
+			let buf: &mut [u8] = unsafe { self.tx_buffer.as_int_mut_slice(0, ::kernel::PAGE_SIZE) };
+			let len = {
+				let _irq = ::kernel::sync::hold_interrupts();
+				let mut lh = self.tx_data.lock();
+				let mut len = 0;
+				while len < buf.len() {
+					match lh.pop_front()
+					{
+					Some(b) => { buf[len] = b; len += 1; },
+					None => break,
+					}
+				}
+				len
+				};
+			if len == 0 {
+				break;
+			}
+
+			self.tx_complete.store(false, Ordering::Release);
+			let _req = self.transmitq.send_buffers(&self.interface, &mut [ Buffer::Read(&buf[..len]) ]);
+			// NOTE: The sleep object is also signalled when new data is queued, so loop until complete
+			while ! self.tx_complete.load(Ordering::Acquire) {
+				if self.stop.load(Ordering::Acquire) {
+					// TODO: The request is still held by the device, reset it?
+					return ;
+				}
+				so.wait();
+			}
+		}
+	}
+}
+
+impl<I: Interface+Send+Sync+'static> logging::ExternalSink for Console<I>
+{
+	fn start(&self, timestamp: ::kernel::time::TickCount, level: logging::Level, source: &'static str) {
+		use core::fmt::Write;
+		struct RingWriter<'a, I: 'a + Interface+Send+Sync+'static>(&'a Console<I>);
+		impl<'a, I: Interface+Send+Sync+'static> ::core::fmt::Write for RingWriter<'a, I> {
+			fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
+				self.0.push_tx(s.as_bytes());
+				Ok( () )
+			}
+		}
+		let _ = write!(RingWriter(self), "{:6}{} [{}] - ", timestamp, level, source);
+	}
+	fn write(&self, data: &str) {
+		self.push_tx(data.as_bytes());
+	}
+	fn end(&self) {
+		self.push_tx(b"\n");
+	}
+	fn flush(&self) {
+		// Logging lock is released, safe to wake the worker
+		self.signal_worker();
+	}
+}
+
+impl<I: Interface+Send+Sync+'static> character::CharDevice for Console<I>
+{
+	fn read(&self, dst: &mut [u8]) -> usize {
+		let _irq = ::kernel::sync::hold_interrupts();
+		let mut lh = self.rx_data.lock();
+		for i in 0 .. dst.len() {
+			match lh.pop_front()
+			{
+			Some(b) => dst[i] = b,
+			None => return i,
+			}
+		}
+		dst.len()
+	}
+	fn write(&self, src: &[u8]) -> usize {
+		let rv = self.push_tx(src);
+		self.signal_worker();
+		rv
+	}
+}
@@ -0,0 +1,236 @@
+// "Tifflin" Kernel - VirtIO Driver
+// - By John Hodge (thePowersGang)
+//
+// virtio/devices/input.rs
+//! VirtIO input device (keyboard/mouse, using evdev event codes)
+use kernel::prelude::*;
+use kernel::sync::Mutex;
+use kernel::memory::virt::AllocHandle;
+use gui::input::keyboard as gui_keyboard;
+use gui::input::keyboard::KeyCode;
+use gui::input::mouse as gui_mouse;
+use interface::Interface;
+use queue::{Queue,Buffer,Request};
+
+#[allow(dead_code)]
+mod defs {
+// Event types
+pub const EV_SYN	: u16 = 0x00;
+pub const EV_KEY	: u16 = 0x01;
+pub const EV_REL	: u16 = 0x02;
+pub const EV_ABS	: u16 = 0x03;
+// Relative axes
+pub const REL_X	: u16 = 0x00;
+pub const REL_Y	: u16 = 0x01;
+pub const REL_WHEEL	: u16 = 0x08;
+// Buttons
+pub const BTN_MOUSE	: u16 = 0x110;	// BTN_LEFT
+pub const BTN_TASK	: u16 = 0x117;
+pub const BTN_TOUCH	: u16 = 0x14A;
+}
+use self::defs::*;
+
+const EVENTQ: usize = 0;
+const N_EVENT_BUFS: usize = 64;
+
+pub struct InputDevice<I: Interface+Send+Sync+'static>
+{
+	_dev: Box<Input<I>>,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct VirtioInputEvent
+{
+	type_: u16,
+	code: u16,
+	value: u32,
+}
+unsafe impl ::kernel::lib::POD for VirtioInputEvent {}
+const EVENT_SIZE: usize = 8;
+
+struct Input<I: Interface>
+{
+	// NOTE: Holds requests that borrow the queue and buffers below, so must be dropped first
+	state: Mutex<State>,
+
+	interface: I,
+	eventq: Queue,
+	event_buffers: AllocHandle,
+}
+struct State
+{
+	active: Vec<Option<Request<'static>>>,
+	/// Relative motion accumulated until the next EV_SYN
+	rel: (i16, i16),
+	abs_warned: bool,
+	keyboard: gui_keyboard::Instance,
+	mouse: gui_mouse::Instance,
+}
+impl<I: Interface+Send+Sync+'static> InputDevice<I>
+{
+	pub fn new(mut int: I) -> Self {
+		let _features = int.negotiate_features(0);
+		let eventq = int.get_queue(EVENTQ, 0).expect("Queue #0 'eventq' missing on virtio input device");
+		// NOTE: The status queue (LED state) is unused
+
+		let mut dev = Box::new(Input {
+			state: Mutex::new(State {
+				active: (0 .. N_EVENT_BUFS).map(|_| None).collect(),
+				rel: (0, 0),
+				abs_warned: false,
+				keyboard: gui_keyboard::Instance::new(),
+				mouse: gui_mouse::Instance::new(),
+				}),
+			interface: int,
+			eventq: eventq,
+			event_buffers: ::kernel::memory::virt::alloc_dma(64, 1, "VirtIO-Input").expect("TODO: Handle alloc failure VirtIO input buffers"),
+			});
+
+		struct SPtr<T>(*const T);
+		unsafe impl<T> Send for SPtr<T> {}
+		let sp = SPtr(&*dev);
+		// SAFE: Now boxed, won't be invalidated until after Drop is called
+		dev.interface.bind_interrupt( Box::new(move || unsafe { (*sp.0).handle_irq() }) );
+		dev.interface.set_driver_ok();
+
+		{
+			let mut lh = dev.state.lock();
+			for i in 0 .. N_EVENT_BUFS {
+				dev.post_event(&mut lh.active, i);
+			}
+		}
+
+		InputDevice {
+			_dev: dev,
+			}
+	}
+}
+impl<I: Interface+Send+Sync+'static> ::kernel::device_manager::DriverInstance for InputDevice<I> {
+}
+
+impl<I: Interface+Send+Sync+'static> Input<I>
+{
+	/// Hand an event buffer to the device
+	fn post_event(&self, active: &mut [Option<Request<'static>>], idx: usize) {
+		assert!(active[idx].is_none());
+		// SAFE: The buffer isn't in use by the device, and `state` is dropped before the buffers and queue
+		let req = unsafe {
+			let buf: &'static mut [u8] = &mut *(self.event_buffers.as_int_mut_slice(idx * EVENT_SIZE, EVENT_SIZE) as *mut [u8]);
+			let queue: &'static Queue = &*(&self.eventq as *const _);
+			queue.send_buffers(&self.interface, &mut [ Buffer::Write(buf) ])
+			};
+		active[idx] = Some(req);
+	}
+
+	fn handle_irq(&self) -> bool {
+		let mut lh = self.state.lock();
+		let state = &mut *lh;
+		self.eventq.collect_used(|id, _len| {
+			match state.active.iter().position(|r| r.as_ref().map(|r| r.id()) == Some(id))
+			{
+			Some(idx) => {
+				state.active[idx] = None;
+				let ev: &VirtioInputEvent = self.event_buffers.as_ref(idx * EVENT_SIZE);
+				state.handle_event(ev);
+				self.post_event(&mut state.active, idx);
+				},
+			None => log_warning!("Event for unknown descriptor {}", id),
+			}
+			});
+		true
+	}
+}
+
+impl State
+{
+	fn handle_event(&mut self, ev: &VirtioInputEvent) {
+		log_trace!("handle_event: {:?}", ev);
+		match ev.type_
+		{
+		EV_SYN => {
+			let (dx, dy) = self.rel;
+			if dx != 0 || dy != 0 {
+				self.mouse.move_cursor(dx, dy);
+			}
+			self.rel = (0, 0);
+			},
+		EV_KEY => {
+			// 0 = release, 1 = press, 2 = autorepeat (ignored)
+			let release = match ev.value
+				{
+				0 => true,
+				1 => false,
+				_ => return,
+				};
+			match ev.code
+			{
+			c @ BTN_MOUSE ... BTN_TASK => self.mouse_button((c - BTN_MOUSE) as u8, release),
+			BTN_TOUCH => self.mouse_button(0, release),
+			c @ _ => {
+				let key = keymap::EVDEV.get(c as usize).cloned().unwrap_or(KeyCode::None);
+				if key == KeyCode::None {
+					log_debug!("Unknown key code {:#x}", c);
+				}
+				else if release {
+					self.keyboard.release_key(key);
+				}
+				else {
+					self.keyboard.press_key(key);
+				}
+				},
+			}
+			},
+		EV_REL => {
+			let v = ::core::cmp::max(-0x8000, ::core::cmp::min(ev.value as i32, 0x7FFF)) as i16;
+			match ev.code
+			{
+			REL_X => self.rel.0 = self.rel.0.saturating_add(v),
+			REL_Y => self.rel.1 = self.rel.1.saturating_add(v),
+			_ => {},	// TODO: Scroll wheel
+			}
+			},
+		EV_ABS => {
+			// TODO: Absolute pointers (tablets) need the screen dimensions
+			if !self.abs_warned {
+				log_notice!("TODO: Support absolute pointer events");
+				self.abs_warned = true;
+			}
+			},
+		_ => {},
+		}
+	}
+
+	fn mouse_button(&self, btn: u8, release: bool) {
+		if release {
+			self.mouse.release_button(btn);
+		}
+		else {
+			self.mouse.press_button(btn);
+		}
+	}
+}
+
+mod keymap {
+	use gui::input::keyboard::KeyCode;
+	use gui::input::keyboard::KeyCode::*;
+	/// Linux evdev key codes to HID codes
+	pub static EVDEV: [KeyCode; 0x80] = [
+		None, Esc, Kb1, Kb2, Kb3, Kb4, Kb5, Kb6,
+		Kb7, Kb8, Kb9, Kb0, Minus, Equals, Backsp, Tab,
+		Q, W, E, R, T, Y, U, I,
+		O, P, SquareOpen, SquareClose, Return, LeftCtrl, A, S,
+		D, F, G, H, J, K, L, Semicolon,
+		Quote, GraveTilde, LeftShift, Backslash, Z, X, C, V,
+		B, N, M, Comma, Period, Slash, RightShift, KpStar,
+		LeftAlt, Space, Caps, F1, F2, F3, F4, F5,
+		F6, F7, F8, F9, F10, Numlock, ScrollLock, Kp7,
+		Kp8, Kp9, KpMinus, Kp4, Kp5, Kp6, KpPlus, Kp1,
+		Kp2, Kp3, Kp0, KpPeriod, None, None, NonUSBackslash, F11,
+		F12, None, None, None, None, None, None, None,
+		KpEnter, RightCtrl, KpSlash, PrintScreen, RightAlt, None, Home, UpArrow,
+		PgUp, LeftArrow, RightArrow, End, DownArrow, PgDn, Insert, Delete,
+		None, Mute, VolDn, VolUp, Power, KpEquals, None, Pause,
+		None, KpComma, None, None, None, LeftGui, RightGui, Application,
+		];
+}
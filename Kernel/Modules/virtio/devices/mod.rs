@@ -7,20 +7,36 @@ use kernel::device_manager;
 use interface::Interface;
 
 mod block;
-//mod network;
+mod network;
+mod console;
+mod rng;
+mod input;
+mod scsi;
 
-pub fn new_boxed<T: Interface+Send+'static>(dev: u32, io: device_manager::IOBinding, irq: u32) -> Box<device_manager::DriverInstance>
+pub fn new_boxed<T: Interface+Send+Sync+'static>(dev: u32, int: T) -> Box<device_manager::DriverInstance>
 {
 	match dev
 	{
 	// 0: Reserved/invalid
 	0 => Box::new( NullDevice ),
-	//1 => Box::new( network::NetDevice::new(T::new(io, irq)) ),
-	1 => {
-		log_notice!("TODO: Support VirtIO network devices (type = 1)");
+	// Block and SCSI devices can't be used without their config space
+	2 | 8 if !int.has_device_config() => {
+		log_error!("VirtIO device type {} has no device config region", dev);
 		Box::new(NullDevice)
-		}
-	2 => Box::new( block::BlockDevice::new(T::new(io, irq)) ),
+		},
+	1 => Box::new( network::NetDevice::new(int) ),
+	2 => Box::new( block::BlockDevice::new(int) ),
+	3 => Box::new( console::ConsoleDevice::new(int) ),
+	4 => Box::new( rng::RngDevice::new(int) ),
+	8 => match scsi::ScsiDevice::new(int)
+		{
+		Ok(v) => Box::new(v),
+		Err(e) => {
+			log_error!("VirtIO SCSI device failed to initialise: {}", e);
+			Box::new(NullDevice)
+			},
+		},
+	18 => Box::new( input::InputDevice::new(int) ),
 	dev @ _ => {
 		log_error!("VirtIO device has unknown device ID {:#x}", dev);
 		Box::new(NullDevice)
@@ -0,0 +1,402 @@
+// "Tifflin" Kernel - VirtIO Driver
+// - By John Hodge (thePowersGang)
+//
+// virtio/devices/network.rs
+//! VirtIO network device
+use kernel::prelude::*;
+use kernel::sync::Mutex;
+use kernel::lib::collections::VecDeque;
+use kernel::memory::virt::AllocHandle;
+use kernel::_async3 as async;
+use network::nic;
+use interface::Interface;
+use queue::{Queue,Buffer,Request};
+
+#[allow(dead_code)]
+mod defs {
+pub const VIRTIO_NET_F_CSUM      	: u32 = 1 << 0;
+pub const VIRTIO_NET_F_GUEST_CSUM	: u32 = 1 << 1;
+pub const VIRTIO_NET_F_MAC       	: u32 = 1 << 5;
+pub const VIRTIO_NET_F_MRG_RXBUF 	: u32 = 1 << 15;
+pub const VIRTIO_NET_F_STATUS    	: u32 = 1 << 16;
+// TODO: Other feature flags (GSO/TSO, control queue)
+
+pub const VIRTIO_NET_HDR_F_NEEDS_CSUM	: u8 = 1;
+pub const VIRTIO_NET_HDR_F_DATA_VALID	: u8 = 2;
+
+pub const VIRTIO_NET_HDR_GSO_NONE	: u8 = 0;
+}
+use self::defs::*;
+
+const RECEIVEQ: usize = 0;
+const TRANSMITQ: usize = 1;
+
+/// Size of a packet buffer (header and frame)
+const SLOT_SIZE: usize = 2048;
+const SLOTS_PER_PAGE: usize = ::kernel::PAGE_SIZE / SLOT_SIZE;
+const N_RX_SLOTS: usize = 16;
+const N_TX_SLOTS: usize = 16;
+/// Largest frame that can be transmitted (Ethernet II header and payload, no FCS)
+const MAX_FRAME_SIZE: usize = 1514;
+
+pub struct NetDevice<I: Interface+Send+Sync+'static>
+{
+	_nic_reg: nic::Registration<Card<I>>,
+}
+
+/// Handle registered with the network stack
+///
+/// The device state is boxed so the interrupt handler can hold a pointer to it before registration.
+struct Card<I: Interface+Send+Sync+'static>(Box<Device<I>>);
+
+struct Device<I: Interface+Send+Sync+'static>
+{
+	// NOTE: These hold requests that borrow the queues and buffers below, so must be dropped first
+	rx: Mutex<RxState>,
+	tx: Mutex<TxState>,
+	waiter_handle: Mutex<Option<::kernel::threads::SleepObjectRef>>,
+
+	interface: I,
+	/// Size of `virtio_net_hdr` (10 bytes for legacy devices, 12 with `num_buffers`)
+	hdr_len: usize,
+	receiveq: Queue,
+	transmitq: Queue,
+	rx_buffers: AllocHandle,
+	tx_buffers: AllocHandle,
+}
+
+struct RxState
+{
+	/// Buffers handed to the device (`None` if the slot is ready or held by the network stack)
+	active: Vec<Option<Request<'static>>>,
+	/// Received frames (slot, frame length) in arrival order
+	ready: VecDeque<(usize, usize)>,
+}
+struct TxState
+{
+	active: Vec<Option<TxSlot>>,
+	/// Frames waiting for a free slot
+	pending: VecDeque<(Box<[u8]>, Option<async::ObjectHandle>)>,
+}
+struct TxSlot
+{
+	req: Request<'static>,
+	async: Option<async::ObjectHandle>,
+}
+
+impl<I: Interface+Send+Sync+'static> NetDevice<I>
+{
+	pub fn new(mut int: I) -> Self {
+		// NOTE: Features must be negotiated before queues are set up (required for 1.0 devices)
+		// - CSUM is accepted so the stack can later request offload, all frames are currently sent with complete checksums
+		let features = int.negotiate_features( VIRTIO_NET_F_MAC | VIRTIO_NET_F_CSUM | VIRTIO_NET_F_GUEST_CSUM | VIRTIO_NET_F_MRG_RXBUF );
+		let mac = if features & VIRTIO_NET_F_MAC != 0 && int.has_device_config() {
+				// SAFE: Readable registers
+				let (w0, w1) = unsafe { (int.cfg_read_32(0), int.cfg_read_32(4)) };
+				[w0 as u8, (w0 >> 8) as u8, (w0 >> 16) as u8, (w0 >> 24) as u8, w1 as u8, (w1 >> 8) as u8]
+			}
+			else {
+				log_warning!("VirtIO network device doesn't provide a MAC address, using a fixed locally-administered address");
+				[0x02, 0x00, 0x00, 0x00, 0x00, 0x01]
+			};
+		// - `num_buffers` is present if mergeable buffers are negotiated, or always on 1.0 devices
+		let hdr_len = if features & VIRTIO_NET_F_MRG_RXBUF != 0 || int.is_modern() { 12 } else { 10 };
+		log_notice!("VirtIO Network MAC={:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x} features={:#x}",
+			mac[0], mac[1], mac[2], mac[3], mac[4], mac[5], features);
+
+		let receiveq = int.get_queue(RECEIVEQ, 0).expect("Queue #0 'receiveq' missing on virtio network device");
+		let transmitq = int.get_queue(TRANSMITQ, 0).expect("Queue #1 'transmitq' missing on virtio network device");
+
+		let mut dev = Box::new(Device {
+			rx: Mutex::new(RxState {
+				active: (0 .. N_RX_SLOTS).map(|_| None).collect(),
+				ready: VecDeque::new(),
+				}),
+			tx: Mutex::new(TxState {
+				active: (0 .. N_TX_SLOTS).map(|_| None).collect(),
+				pending: VecDeque::new(),
+				}),
+			waiter_handle: Default::default(),
+			interface: int,
+			hdr_len: hdr_len,
+			receiveq: receiveq,
+			transmitq: transmitq,
+			rx_buffers: ::kernel::memory::virt::alloc_dma(64, N_RX_SLOTS / SLOTS_PER_PAGE, "VirtIO-Net").expect("TODO: Handle alloc failure VirtIO network buffers"),
+			tx_buffers: ::kernel::memory::virt::alloc_dma(64, N_TX_SLOTS / SLOTS_PER_PAGE, "VirtIO-Net").expect("TODO: Handle alloc failure VirtIO network buffers"),
+			});
+
+		struct SPtr<T>(*const T);
+		unsafe impl<T> Send for SPtr<T> {}
+		let sp = SPtr(&*dev);
+		// SAFE: Now boxed, won't be invalidated until after Drop is called
+		dev.interface.bind_interrupt( Box::new(move || unsafe { (*sp.0).handle_irq() }) );
+		dev.interface.set_driver_ok();
+
+		// Hand all receive buffers to the device (only allowed after DRIVER_OK)
+		{
+			let mut rx = dev.rx.lock();
+			for slot in 0 .. N_RX_SLOTS {
+				dev.post_rx(&mut rx, slot);
+			}
+		}
+
+		NetDevice {
+			_nic_reg: nic::register(mac, Card(dev)),
+			}
+	}
+}
+impl<I: Interface+Send+Sync+'static> ::kernel::device_manager::DriverInstance for NetDevice<I> {
+}
+
+impl<I: Interface+Send+Sync+'static> Device<I>
+{
+	/// Obtain the header and data portions of a buffer slot
+	///
+	/// UNSAFE: Caller must ensure that the slot isn't otherwise borrowed (by the device or the network stack), and that
+	/// the returned slices don't outlive `buffers`
+	unsafe fn slot_buffers(&self, buffers: &AllocHandle, slot: usize) -> (&'static mut [u8], &'static mut [u8]) {
+		let s: *mut [u8] = buffers.as_int_mut_slice(slot * SLOT_SIZE, SLOT_SIZE);
+		(&mut *s).split_at_mut(self.hdr_len)
+	}
+
+	/// Hand a receive slot to the device
+	fn post_rx(&self, rx: &mut RxState, slot: usize) {
+		assert!(rx.active[slot].is_none());
+		// SAFE: The slot is owned by the driver (not active, not held by the stack), and the `rx` field is dropped
+		//       before the buffers and queue.
+		let req = unsafe {
+			let (hdr, data) = self.slot_buffers(&self.rx_buffers, slot);
+			let queue: &'static Queue = &*(&self.receiveq as *const _);
+			queue.send_buffers(&self.interface, &mut [ Buffer::Write(hdr), Buffer::Write(data) ])
+			};
+		rx.active[slot] = Some(req);
+	}
+
+	/// Copy a frame into a transmit slot and hand it to the device
+	fn start_tx<'a, It: IntoIterator<Item=&'a [u8]>>(&self, tx: &mut TxState, slot: usize, pkt: It, async: Option<async::ObjectHandle>) {
+		assert!(tx.active[slot].is_none());
+		// SAFE: The slot isn't active, and the `tx` field is dropped before the buffers and queue.
+		let req = unsafe {
+			let (hdr, data) = self.slot_buffers(&self.tx_buffers, slot);
+			// - No offload requested, the frame is complete
+			for b in hdr.iter_mut() {
+				*b = 0;
+			}
+			hdr[1] = VIRTIO_NET_HDR_GSO_NONE;
+			let mut len = 0;
+			for span in pkt {
+				data[len ..][.. span.len()].copy_from_slice(span);
+				len += span.len();
+			}
+			log_trace!("start_tx: slot={}, len={}", slot, len);
+			let queue: &'static Queue = &*(&self.transmitq as *const _);
+			queue.send_buffers(&self.interface, &mut [ Buffer::Read(hdr), Buffer::Read(&data[..len]) ])
+			};
+		tx.active[slot] = Some(TxSlot { req: req, async: async });
+	}
+
+	/// Send a frame, or queue it if all slots are in use
+	fn tx_frame(&self, pkt: &nic::SparsePacket, async: Option<async::ObjectHandle>) -> Result<(), nic::Error> {
+		let len = pkt.into_iter().fold(0, |s, span| s + span.len());
+		if len > MAX_FRAME_SIZE {
+			return Err( nic::Error::MtuExceeded );
+		}
+
+		let mut tx = self.tx.lock();
+		match tx.active.iter().position(|s| s.is_none())
+		{
+		Some(slot) => self.start_tx(&mut tx, slot, pkt, async),
+		None => {
+			// Take a copy of the packet, it's sent once a slot is released
+			let mut buf = Vec::new();
+			for span in pkt {
+				buf.extend_from_slice(span);
+			}
+			tx.pending.push_back( (buf.into_boxed_slice(), async) );
+			},
+		}
+		Ok( () )
+	}
+
+	fn handle_irq(&self) -> bool
+	{
+		// ---
+		// Transmit complete - Release slots and start any pending frames
+		// ---
+		{
+			let mut tx = self.tx.lock();
+			let tx = &mut *tx;
+			self.transmitq.collect_used(|id, _len| {
+				match tx.active.iter().position(|s| s.as_ref().map(|s| s.req.id()) == Some(id))
+				{
+				Some(slot) => {
+					// NOTE: Dropping the slot releases the descriptors
+					let TxSlot { req, async } = tx.active[slot].take().unwrap();
+					drop(req);
+					if let Some(a) = async {
+						a.signal(0);
+					}
+					if let Some( (buf, async) ) = tx.pending.pop_front() {
+						self.start_tx(tx, slot, Some(&buf[..]), async);
+					}
+					},
+				None => log_warning!("TX completion for unknown descriptor {}", id),
+				}
+				});
+		}
+
+		// ---
+		// Receive complete - Queue frames for the network stack
+		// ---
+		let mut num_packets = 0;
+		{
+			let mut rx = self.rx.lock();
+			let rx = &mut *rx;
+			self.receiveq.collect_used(|id, len| {
+				match rx.active.iter().position(|r| r.as_ref().map(|r| r.id()) == Some(id))
+				{
+				Some(slot) => {
+					rx.active[slot] = None;
+					match self.complete_rx(slot, len)
+					{
+					Some(frame_len) => {
+						rx.ready.push_back( (slot, frame_len) );
+						num_packets += 1;
+						},
+					None => self.post_rx(rx, slot),
+					}
+					},
+				None => log_warning!("RX completion for unknown descriptor {}", id),
+				}
+				});
+		}
+		if num_packets > 0
+		{
+			if let Some(ref v) = *self.waiter_handle.lock()
+			{
+				v.signal();
+			}
+		}
+
+		true
+	}
+
+	/// Check the header of a received frame (finishing the checksum if required), returning the frame length
+	fn complete_rx(&self, slot: usize, len: usize) -> Option<usize> {
+		if len < self.hdr_len {
+			log_warning!("RX slot {}: Short write from device ({} < {})", slot, len, self.hdr_len);
+			return None;
+		}
+		// SAFE: The slot has just been returned by the device
+		let (hdr, data) = unsafe { self.slot_buffers(&self.rx_buffers, slot) };
+		let frame_len = len - self.hdr_len;
+		if frame_len > data.len() {
+			log_warning!("RX slot {}: Oversized frame ({} > {})", slot, frame_len, data.len());
+			return None;
+		}
+		if self.hdr_len >= 12 {
+			let num_buffers = hdr[10] as u16 | (hdr[11] as u16) << 8;
+			if num_buffers > 1 {
+				// Buffers are sized for a full frame, so this shouldn't happen without GSO
+				log_warning!("RX slot {}: Frame split across {} buffers, dropping", slot, num_buffers);
+				return None;
+			}
+		}
+		if hdr[0] & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+			let csum_start  = hdr[6] as usize | (hdr[7] as usize) << 8;
+			let csum_offset = hdr[8] as usize | (hdr[9] as usize) << 8;
+			finish_checksum(&mut data[..frame_len], csum_start, csum_offset);
+		}
+		Some(frame_len)
+	}
+}
+
+/// Complete a partial checksum (the device has already summed the pseudo-header into the checksum field)
+fn finish_checksum(data: &mut [u8], start: usize, offset: usize) {
+	if start + offset + 2 > data.len() {
+		log_warning!("Partial checksum outside frame ({}+{} > {})", start, offset, data.len());
+		return ;
+	}
+	let mut sum: u32 = 0;
+	for c in data[start..].chunks(2) {
+		sum += (c[0] as u32) << 8 | (if c.len() > 1 { c[1] as u32 } else { 0 });
+	}
+	while sum > 0xFFFF {
+		sum = (sum & 0xFFFF) + (sum >> 16);
+	}
+	let v = !(sum as u16);
+	data[start + offset + 0] = (v >> 8) as u8;
+	data[start + offset + 1] = v as u8;
+}
+
+impl<I: Interface+Send+Sync+'static> nic::Interface for Card<I>
+{
+	fn tx_raw(&self, pkt: nic::SparsePacket) {
+		log_trace!("tx_raw()");
+		// NOTE: Doesn't wait for a slot, the frame is queued if none are free
+		if let Err(e) = self.0.tx_frame(&pkt, None) {
+			log_error!("tx_raw: Unable to send frame - {:?}", e);
+		}
+	}
+
+	fn tx_async<'a, 's>(&'s self, async: async::ObjectHandle, _stack: async::StackPush<'a, 's>, pkt: nic::SparsePacket) -> Result<(), nic::Error> {
+		log_trace!("tx_async()");
+		// The frame is always copied into driver memory, so the stack isn't needed.
+		self.0.tx_frame(&pkt, Some(async))
+	}
+
+	fn rx_wait_register(&self, channel: &::kernel::threads::SleepObject) {
+		*self.0.waiter_handle.lock() = Some(channel.get_ref());
+	}
+	fn rx_packet(&self) -> Result<nic::PacketHandle, nic::Error> {
+		struct RxPacketHandle<'a, I: 'a + Interface+Send+Sync+'static> {
+			dev: &'a Device<I>,
+			slot: usize,
+			len: usize,
+		}
+		impl<'a, I: Interface+Send+Sync+'static> nic::RxPacket for RxPacketHandle<'a, I> {
+			fn len(&self) -> usize {
+				self.len
+			}
+			fn num_regions(&self) -> usize {
+				1
+			}
+			fn get_region(&self, idx: usize) -> &[u8] {
+				assert!(idx == 0);
+				self.dev.rx_buffers.as_slice(self.slot * SLOT_SIZE + self.dev.hdr_len, self.len)
+			}
+			fn get_slice(&self, range: ::core::ops::Range<usize>) -> Option<&[u8]> {
+				let b = self.get_region(0);
+				b.get(range)
+			}
+		}
+		impl<'a, I: Interface+Send+Sync+'static> ::core::ops::Drop for RxPacketHandle<'a, I> {
+			fn drop(&mut self) {
+				log_trace!("Release RX slot {} to device", self.slot);
+				let more = {
+					let mut rx = self.dev.rx.lock();
+					self.dev.post_rx(&mut rx, self.slot);
+					!rx.ready.is_empty()
+					};
+				// The stack only takes one packet per wakeup, so wake it again if there's more waiting
+				if more {
+					if let Some(ref v) = *self.dev.waiter_handle.lock() {
+						v.signal();
+					}
+				}
+			}
+		}
+
+		let (slot, len) = match self.0.rx.lock().ready.pop_front()
+			{
+			Some(v) => v,
+			None => return Err( nic::Error::NoPacket ),
+			};
+		let rv = RxPacketHandle {
+			dev: &*self.0,
+			slot: slot,
+			len: len,
+			};
+		Ok( nic::PacketHandle::new(rv).ok().unwrap() )
+	}
+}
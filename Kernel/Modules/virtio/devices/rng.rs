@@ -0,0 +1,75 @@
+// "Tifflin" Kernel - VirtIO Driver
+// - By John Hodge (thePowersGang)
+//
+// virtio/devices/rng.rs
+//! VirtIO entropy source
+use kernel::prelude::*;
+use kernel::metadevs::entropy;
+use interface::Interface;
+use queue::{Queue,Buffer};
+
+/// Number of bytes requested from the device each time the pool asks for more
+const REQUEST_SIZE: usize = 64;
+
+pub struct RngDevice
+{
+	// TODO: The worker isn't stopped if the device is removed (VirtIO devices currently never are)
+	_worker: ::kernel::threads::WorkerThread,
+}
+
+struct Rng<I: Interface>
+{
+	interface: I,
+	requestq: Queue,
+}
+
+impl RngDevice
+{
+	pub fn new<T: Interface+Send+Sync+'static>(mut int: T) -> Self {
+		let _features = int.negotiate_features(0);
+		let requestq = int.get_queue(0, 0).expect("Queue #0 'requestq' missing on virtio entropy device");
+		int.set_driver_ok();
+
+		let mut dev = Box::new(Rng {
+			interface: int,
+			requestq: requestq,
+			});
+
+		struct SPtr<T>(*const T);
+		unsafe impl<T> Send for SPtr<T> {}
+		let sp = SPtr(&*dev);
+		// SAFE: Now boxed, won't be invalidated until after Drop is called
+		dev.interface.bind_interrupt( Box::new(move || unsafe { (*sp.0).requestq.check_interrupt(); true }) );
+
+		RngDevice {
+			_worker: ::kernel::threads::WorkerThread::new("VirtIO RNG", move || dev.worker()),
+			}
+	}
+}
+impl ::kernel::device_manager::DriverInstance for RngDevice {
+}
+
+impl<I: Interface> Rng<I>
+{
+	fn worker(&self) {
+		loop
+		{
+			let mut buf = [0u8; REQUEST_SIZE];
+			let rv = {
+				let h = self.requestq.send_buffers(&self.interface, &mut [ Buffer::Write(&mut buf) ]);
+				h.wait_for_completion()
+				};
+			match rv
+			{
+			Ok(len) => {
+				let len = ::core::cmp::min(len, REQUEST_SIZE);
+				log_debug!("Adding {} bytes to the entropy pool", len);
+				entropy::add_entropy(&buf[..len], len * 8);
+				},
+			Err( () ) => log_error!("VirtIO entropy request failed"),
+			}
+			// Sleep until the pool needs more
+			entropy::wait_for_demand();
+		}
+	}
+}
@@ -0,0 +1,516 @@
+// "Tifflin" Kernel - VirtIO Driver
+// - By John Hodge (thePowersGang)
+//
+// virtio/devices/scsi.rs
+//! VirtIO SCSI host adapter (each LUN exposed as a SCSI volume)
+use kernel::prelude::*;
+use kernel::lib::mem::Arc;
+use kernel::sync::{Mutex,Semaphore};
+use kernel::memory::virt::AllocHandle;
+use kernel::metadevs::storage;
+use core::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+use interface::Interface;
+use queue::{Queue,Buffer,Request};
+
+#[allow(dead_code)]
+mod defs {
+pub const VIRTIO_SCSI_F_INOUT	: u32 = 1 << 0;
+pub const VIRTIO_SCSI_F_HOTPLUG	: u32 = 1 << 1;
+pub const VIRTIO_SCSI_F_CHANGE	: u32 = 1 << 2;
+
+// Request/response `response` values
+pub const VIRTIO_SCSI_S_OK	: u8 = 0;
+pub const VIRTIO_SCSI_S_OVERRUN	: u8 = 1;
+pub const VIRTIO_SCSI_S_ABORTED	: u8 = 2;
+pub const VIRTIO_SCSI_S_BAD_TARGET	: u8 = 3;
+pub const VIRTIO_SCSI_S_RESET	: u8 = 4;
+pub const VIRTIO_SCSI_S_BUSY	: u8 = 5;
+pub const VIRTIO_SCSI_S_TRANSPORT_FAILURE	: u8 = 6;
+pub const VIRTIO_SCSI_S_TARGET_FAILURE	: u8 = 7;
+pub const VIRTIO_SCSI_S_NEXUS_FAILURE	: u8 = 8;
+pub const VIRTIO_SCSI_S_FAILURE	: u8 = 9;
+
+// Task attributes
+pub const VIRTIO_SCSI_S_SIMPLE	: u8 = 0;
+
+// SCSI status codes
+pub const SCSI_STATUS_GOOD	: u8 = 0x00;
+pub const SCSI_STATUS_CHECK_CONDITION	: u8 = 0x02;
+pub const SCSI_STATUS_BUSY	: u8 = 0x08;
+
+// Event types
+pub const VIRTIO_SCSI_T_NO_EVENT	: u32 = 0;
+pub const VIRTIO_SCSI_T_TRANSPORT_RESET	: u32 = 1;
+pub const VIRTIO_SCSI_T_ASYNC_NOTIFY	: u32 = 2;
+pub const VIRTIO_SCSI_T_PARAM_CHANGE	: u32 = 3;
+pub const VIRTIO_SCSI_T_EVENTS_MISSED	: u32 = 0x8000_0000;
+
+// Transport reset reasons
+pub const VIRTIO_SCSI_EVT_RESET_HARD	: u32 = 0;
+pub const VIRTIO_SCSI_EVT_RESET_RESCAN	: u32 = 1;
+pub const VIRTIO_SCSI_EVT_RESET_REMOVED	: u32 = 2;
+
+// Configuration space offsets
+pub const CFG_NUM_QUEUES	: usize = 0;
+pub const CFG_SEG_MAX	: usize = 4;
+pub const CFG_MAX_SECTORS	: usize = 8;
+pub const CFG_CMD_PER_LUN	: usize = 12;
+pub const CFG_EVENT_INFO_SIZE	: usize = 16;
+pub const CFG_SENSE_SIZE	: usize = 20;
+pub const CFG_CDB_SIZE	: usize = 24;
+pub const CFG_MAX_CHANNEL_TARGET	: usize = 28;	// max_channel: u16, max_target: u16
+pub const CFG_MAX_LUN	: usize = 32;
+}
+use self::defs::*;
+
+const CONTROLQ: usize = 0;
+const EVENTQ: usize = 1;
+/// First (and only used) request queue
+const REQUESTQ: usize = 2;
+
+const CDB_SIZE: usize = 32;
+const SENSE_SIZE: usize = 96;
+/// Size of the request header (excluding the trailing structure padding)
+const REQ_SIZE: usize = 8 + 8 + 3 + CDB_SIZE;
+
+/// Number of event buffers kept posted (each is one bit in `Host::events_ready`)
+const N_EVENT_BUFS: usize = 16;
+const EVENT_SIZE: usize = 16;
+
+/// Highest LUN number representable using flat addressing
+const MAX_LUN: u32 = 0x3FFF;
+/// Maximum number of LUNs read from REPORT LUNS
+const REPORT_LUNS_MAX: usize = 64;
+
+/// Index used to name volumes on each adapter
+static S_NEXT_ADAPTER: AtomicUsize = AtomicUsize::new(0);
+
+pub struct ScsiDevice<I: Interface+Send+Sync+'static>
+{
+	host: Arc<Host<I>>,
+	worker: Option<::kernel::threads::WorkerThread>,
+}
+
+#[repr(C)]
+struct VirtioScsiReq
+{
+	lun: [u8; 8],
+	id: u64,
+	task_attr: u8,
+	prio: u8,
+	crn: u8,
+	cdb: [u8; CDB_SIZE],
+}
+unsafe impl ::kernel::lib::POD for VirtioScsiReq {}
+
+#[repr(C)]
+struct VirtioScsiResp
+{
+	sense_len: u32,
+	resid: u32,
+	status_qualifier: u16,
+	status: u8,
+	response: u8,
+	sense: [u8; SENSE_SIZE],
+}
+unsafe impl ::kernel::lib::POD for VirtioScsiResp {}
+
+#[repr(C)]
+struct VirtioScsiEvent
+{
+	event: u32,
+	lun: [u8; 8],
+	reason: u32,
+}
+unsafe impl ::kernel::lib::POD for VirtioScsiEvent {}
+
+/// Data stage of a command
+enum Data<'a>
+{
+	None,
+	Out(&'a [u8]),
+	In(&'a mut [u8]),
+}
+
+struct Host<I: Interface>
+{
+	// NOTE: Holds requests that borrow the queue and buffers below, so must be dropped first
+	events: Mutex<Vec<Option<Request<'static>>>>,
+	/// Bitmask of event buffers completed by the device, waiting for the worker
+	events_ready: AtomicUsize,
+	events_sem: Semaphore,
+	/// Set (and `events_sem` released) to stop the worker
+	worker_shutdown: AtomicBool,
+	/// Set by the worker once it has released the LUNs
+	worker_done: AtomicBool,
+
+	interface: I,
+	_controlq: Queue,
+	eventq: Queue,
+	requestq: Queue,
+	event_buffers: AllocHandle,
+
+	index: usize,
+	max_target: u8,
+	max_lun: u16,
+	next_tag: AtomicUsize,
+}
+
+/// SCSI interface for a single LUN
+struct LunInterface<I: Interface+Send+Sync+'static>
+{
+	name: String,
+	host: Arc<Host<I>>,
+	target: u8,
+	lun: u16,
+}
+
+/// A registered LUN
+struct Lun
+{
+	target: u8,
+	lun: u16,
+	_reg: storage::PhysicalVolumeReg,
+}
+
+impl<I: Interface+Send+Sync+'static> ScsiDevice<I>
+{
+	pub fn new(mut int: I) -> Result<Self, &'static str> {
+		let features = int.negotiate_features( VIRTIO_SCSI_F_HOTPLUG );
+		// SAFE: Readable registers, and the sizes are writable by the driver
+		let (max_target, max_lun) = unsafe {
+			int.cfg_write_32(CFG_CDB_SIZE, CDB_SIZE as u32);
+			int.cfg_write_32(CFG_SENSE_SIZE, SENSE_SIZE as u32);
+			(int.cfg_read_32(CFG_MAX_CHANNEL_TARGET) >> 16, int.cfg_read_32(CFG_MAX_LUN))
+			};
+		// - Targets are addressed using a single byte
+		let max_target = ::core::cmp::min(max_target, 255) as u8;
+		let max_lun = ::core::cmp::min(max_lun, MAX_LUN) as u16;
+		log_debug!("SCSI Host: max_target={}, max_lun={}, hotplug={}", max_target, max_lun, features & VIRTIO_SCSI_F_HOTPLUG != 0);
+
+		let controlq = try!(int.get_queue(CONTROLQ, 0).ok_or("Queue #0 'controlq' missing"));
+		let eventq = try!(int.get_queue(EVENTQ, 0).ok_or("Queue #1 'eventq' missing"));
+		let requestq = try!(int.get_queue(REQUESTQ, 0).ok_or("Queue #2 'requestq' missing"));
+		let event_buffers = try!(::kernel::memory::virt::alloc_dma(64, 1, "VirtIO-SCSI").map_err(|_| "Unable to allocate event buffers"));
+
+		let mut host = Arc::new(Host {
+			events: Mutex::new( (0 .. N_EVENT_BUFS).map(|_| None).collect() ),
+			events_ready: AtomicUsize::new(0),
+			// - One per event buffer, plus shutdown
+			events_sem: Semaphore::new(0, N_EVENT_BUFS as isize + 1),
+			worker_shutdown: AtomicBool::new(false),
+			worker_done: AtomicBool::new(false),
+			interface: int,
+			_controlq: controlq,
+			eventq: eventq,
+			requestq: requestq,
+			event_buffers: event_buffers,
+			index: S_NEXT_ADAPTER.fetch_add(1, Ordering::Relaxed),
+			max_target: max_target,
+			max_lun: max_lun,
+			next_tag: AtomicUsize::new(0),
+			});
+
+		{
+			struct SPtr<T>(*const T);
+			unsafe impl<T> Send for SPtr<T> {}
+			let sp = SPtr(&*host);
+			let h = Arc::get_mut(&mut host).expect("Newly created Arc not unique");
+			// SAFE: The binding is owned by the host, so is released before the pointed-to data is invalidated
+			h.interface.bind_interrupt( Box::new(move || unsafe { (*sp.0).handle_irq() }) );
+			h.interface.set_driver_ok();
+		}
+
+		{
+			let mut lh = host.events.lock();
+			for i in 0 .. N_EVENT_BUFS {
+				host.post_event(&mut lh, i);
+			}
+		}
+
+		let mut luns = Vec::new();
+		Host::scan(&host, &mut luns);
+
+		let worker_host = host.clone();
+		Ok(ScsiDevice {
+			host: host,
+			worker: Some(::kernel::threads::WorkerThread::new("VirtIO SCSI", move || Host::worker(worker_host, luns))),
+			})
+	}
+}
+impl<I: Interface+Send+Sync+'static> ::kernel::device_manager::DriverInstance for ScsiDevice<I> {
+}
+impl<I: Interface+Send+Sync+'static> ::core::ops::Drop for ScsiDevice<I>
+{
+	fn drop(&mut self)
+	{
+		// Stop the worker (it owns the LUN registrations, which reference the host)
+		if self.worker.is_some()
+		{
+			self.host.worker_shutdown.store(true, Ordering::Release);
+			self.host.events_sem.release();
+			while !self.host.worker_done.load(Ordering::Acquire)
+			{
+				::kernel::threads::yield_time();
+			}
+		}
+	}
+}
+
+/// Encode a LUN address (single level, flat addressing)
+fn lun_address(target: u8, lun: u16) -> [u8; 8] {
+	[1, target, 0x40 | (lun >> 8) as u8, lun as u8, 0,0,0,0]
+}
+/// Decode a LUN address into (target, lun)
+fn decode_lun_address(addr: &[u8; 8]) -> (u8, u16) {
+	(addr[1], (((addr[2] & 0x3F) as u16) << 8) | addr[3] as u16)
+}
+
+impl<I: Interface+Send+Sync+'static> Host<I>
+{
+	/// Hand an event buffer to the device
+	fn post_event(&self, active: &mut [Option<Request<'static>>], idx: usize) {
+		assert!(active[idx].is_none());
+		// SAFE: The buffer isn't in use by the device, and `events` is dropped before the buffers and queue
+		let req = unsafe {
+			let buf: &'static mut [u8] = &mut *(self.event_buffers.as_int_mut_slice(idx * EVENT_SIZE, EVENT_SIZE) as *mut [u8]);
+			let queue: &'static Queue = &*(&self.eventq as *const _);
+			queue.send_buffers(&self.interface, &mut [ Buffer::Write(buf) ])
+			};
+		active[idx] = Some(req);
+	}
+
+	fn handle_irq(&self) -> bool {
+		self.requestq.check_interrupt();
+
+		// Events are handled (and the buffers re-posted) by the worker, as adding a LUN requires sending commands
+		let mut lh = self.events.lock();
+		self.eventq.collect_used(|id, _len| {
+			match lh.iter().position(|r| r.as_ref().map(|r| r.id()) == Some(id))
+			{
+			Some(idx) => {
+				lh[idx] = None;
+				self.events_ready.fetch_or(1 << idx, Ordering::Release);
+				self.events_sem.release();
+				},
+			None => log_warning!("Event for unknown descriptor {}", id),
+			}
+			});
+		true
+	}
+
+	/// Hotplug worker, owns the registered LUNs
+	fn worker(this: Arc<Self>, mut luns: Vec<Lun>) {
+		loop
+		{
+			this.events_sem.acquire();
+			if this.worker_shutdown.load(Ordering::Acquire) {
+				break ;
+			}
+			let ready = this.events_ready.swap(0, Ordering::Acquire);
+			for idx in 0 .. N_EVENT_BUFS
+			{
+				if ready & (1 << idx) == 0 {
+					continue ;
+				}
+				let (event, lun, reason) = {
+					let ev: &VirtioScsiEvent = this.event_buffers.as_ref(idx * EVENT_SIZE);
+					(ev.event, ev.lun, ev.reason)
+					};
+				this.post_event(&mut this.events.lock(), idx);
+				Host::handle_event(&this, &mut luns, event, &lun, reason);
+			}
+		}
+		drop(luns);
+		this.worker_done.store(true, Ordering::Release);
+	}
+
+	fn handle_event(this: &Arc<Self>, luns: &mut Vec<Lun>, event: u32, lun: &[u8; 8], reason: u32) {
+		log_trace!("handle_event: event={:#x}, lun={:?}, reason={}", event, lun, reason);
+		if event & VIRTIO_SCSI_T_EVENTS_MISSED != 0 {
+			log_notice!("vscsi{}: Events missed, rescanning", this.index);
+			Host::scan(this, luns);
+		}
+		match event & !VIRTIO_SCSI_T_EVENTS_MISSED
+		{
+		VIRTIO_SCSI_T_NO_EVENT => {},
+		VIRTIO_SCSI_T_TRANSPORT_RESET => {
+			let (target, lun) = decode_lun_address(lun);
+			match reason
+			{
+			VIRTIO_SCSI_EVT_RESET_RESCAN => {
+				log_notice!("vscsi{}: LUN {}:{} added", this.index, target, lun);
+				if ! luns.iter().any(|l| l.target == target && l.lun == lun) {
+					Host::probe_lun(this, luns, target, lun);
+				}
+				},
+			VIRTIO_SCSI_EVT_RESET_REMOVED => {
+				log_notice!("vscsi{}: LUN {}:{} removed", this.index, target, lun);
+				luns.retain(|l| !(l.target == target && l.lun == lun));
+				},
+			VIRTIO_SCSI_EVT_RESET_HARD => log_notice!("vscsi{}: LUN {}:{} reset", this.index, target, lun),
+			_ => log_notice!("vscsi{}: Unknown reset reason {} for LUN {}:{}", this.index, reason, target, lun),
+			}
+			},
+		ev @ _ => log_notice!("vscsi{}: Unhandled event {:#x}", this.index, ev),
+		}
+	}
+
+	/// Scan all targets for LUNs, registering any that aren't already known
+	fn scan(this: &Arc<Self>, luns: &mut Vec<Lun>) {
+		for target in 0 ..= this.max_target
+		{
+			let mut rsp = vec![0u8; 8 + 8 * REPORT_LUNS_MAX];
+			let cmd = {
+				let len = rsp.len() as u32;
+				[0xA0, 0, 0,0,0,0, (len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8, 0, 0]
+				};
+			let found: Vec<u16> = match this.command(target, 0, &cmd, Data::In(&mut rsp))
+				{
+				Ok(_) => {
+					let list_len = (rsp[0] as usize) << 24 | (rsp[1] as usize) << 16 | (rsp[2] as usize) << 8 | rsp[3] as usize;
+					let count = ::core::cmp::min(list_len / 8, REPORT_LUNS_MAX);
+					rsp[8 ..][.. count * 8].chunks(8)
+						.filter_map(|e| match e[0] >> 6
+							{
+							// Peripheral device addressing
+							0 => Some(e[1] as u16),
+							// Flat space addressing
+							1 => Some((((e[0] & 0x3F) as u16) << 8) | e[1] as u16),
+							_ => None,
+							})
+						.filter(|&l| l <= this.max_lun)
+						.collect()
+					},
+				// No target at this address
+				Err(storage::IoError::Removed) => continue,
+				// - REPORT LUNS is optional for old devices, fall back to just LUN 0
+				Err(e) => {
+					log_debug!("vscsi{}: REPORT LUNS failed on target {} - {:?}", this.index, target, e);
+					vec![0]
+					},
+				};
+
+			for lun in found
+			{
+				if ! luns.iter().any(|l| l.target == target && l.lun == lun) {
+					Host::probe_lun(this, luns, target, lun);
+				}
+			}
+		}
+	}
+
+	/// Register a volume for the specified LUN
+	fn probe_lun(this: &Arc<Self>, luns: &mut Vec<Lun>, target: u8, lun: u16) {
+		let int = LunInterface {
+			name: format!("vscsi{}t{}l{}", this.index, target, lun),
+			host: this.clone(),
+			target: target,
+			lun: lun,
+			};
+		match ::storage_scsi::Volume::new_boxed(int)
+		{
+		Ok(vol) => luns.push(Lun {
+			target: target,
+			lun: lun,
+			_reg: storage::register_pv(vol),
+			}),
+		Err(e) => log_error!("vscsi{}: LUN {}:{} failed to initialise - {:?}", this.index, target, lun, e),
+		}
+	}
+
+	/// Synchronously execute a SCSI command
+	fn command(&self, target: u8, lun: u16, cdb: &[u8], data: Data) -> Result<(), storage::IoError> {
+		assert!(cdb.len() <= CDB_SIZE);
+		let mut req = VirtioScsiReq {
+			lun: lun_address(target, lun),
+			id: self.next_tag.fetch_add(1, Ordering::Relaxed) as u64,
+			task_attr: VIRTIO_SCSI_S_SIMPLE,
+			prio: 0,
+			crn: 0,
+			cdb: [0; CDB_SIZE],
+			};
+		req.cdb[.. cdb.len()].copy_from_slice(cdb);
+		let mut rsp = VirtioScsiResp {
+			sense_len: 0,
+			resid: 0,
+			status_qualifier: 0,
+			status: 0,
+			response: 0,
+			sense: [0; SENSE_SIZE],
+			};
+
+		let rv = {
+			let hdr = &::kernel::lib::as_byte_slice(&req)[.. REQ_SIZE];
+			let rsp_buf = ::kernel::lib::as_byte_slice_mut(&mut rsp);
+			// Device-readable buffers must come before device-writable ones
+			let h = match data
+				{
+				Data::None => self.requestq.send_buffers(&self.interface, &mut [ Buffer::Read(hdr), Buffer::Write(rsp_buf) ]),
+				Data::Out(d) => self.requestq.send_buffers(&self.interface, &mut [ Buffer::Read(hdr), Buffer::Read(d), Buffer::Write(rsp_buf) ]),
+				Data::In(d) => self.requestq.send_buffers(&self.interface, &mut [ Buffer::Read(hdr), Buffer::Write(rsp_buf), Buffer::Write(d) ]),
+				};
+			h.wait_for_completion()
+			};
+		if let Err( () ) = rv {
+			return Err( storage::IoError::Unknown("VirtIO") );
+		}
+
+		match rsp.response
+		{
+		VIRTIO_SCSI_S_OK => {},
+		VIRTIO_SCSI_S_BAD_TARGET => return Err( storage::IoError::Removed ),
+		VIRTIO_SCSI_S_OVERRUN => return Err( storage::IoError::Unknown("Data overrun") ),
+		VIRTIO_SCSI_S_ABORTED | VIRTIO_SCSI_S_RESET => return Err( storage::IoError::Unknown("Command aborted") ),
+		VIRTIO_SCSI_S_BUSY => return Err( storage::IoError::Unknown("Adapter busy") ),
+		v @ _ => {
+			log_notice!("vscsi{}: Command {:#x} to {}:{} failed - response {}", self.index, cdb[0], target, lun, v);
+			return Err( storage::IoError::Unknown("VirtIO SCSI failure") );
+			},
+		}
+
+		match rsp.status
+		{
+		SCSI_STATUS_GOOD => Ok( () ),
+		SCSI_STATUS_CHECK_CONDITION if rsp.sense_len > 0 => {
+			// Autosense data is returned with the response, so doesn't need a REQUEST SENSE
+			let mut sense = ::storage_scsi::proto::RequestSenseRsp::new();
+			let len = ::core::cmp::min( ::core::cmp::min(rsp.sense_len as usize, SENSE_SIZE), sense.len() );
+			sense.as_mut()[.. len].copy_from_slice(&rsp.sense[.. len]);
+			log_debug!("vscsi{}: Sense {:?} ASC={:#x} ASCQ={:#x}", self.index, sense.key(), sense.asc(), sense.ascq());
+			match ::storage_scsi::error_from_sense(sense.key(), sense.asc(), sense.ascq())
+			{
+			Some(e) => Err(e),
+			None => Ok( () ),
+			}
+			},
+		SCSI_STATUS_CHECK_CONDITION => Err( storage::IoError::Unknown("SCSI check condition") ),
+		SCSI_STATUS_BUSY => Err( storage::IoError::Unknown("Device busy") ),
+		v @ _ => {
+			log_notice!("vscsi{}: Command {:#x} to {}:{} returned status {:#x}", self.index, cdb[0], target, lun, v);
+			Err( storage::IoError::Unknown("SCSI command failed") )
+			},
+		}
+	}
+}
+
+impl<I: Interface+Send+Sync+'static> ::storage_scsi::ScsiInterface for LunInterface<I>
+{
+	fn name(&self) -> &str {
+		&self.name
+	}
+	fn send<'a>(&'a self, command: &[u8], data: &'a [u8]) -> storage::AsyncIoResult<'a,()>
+	{
+		use kernel::async::NullResultWaiter;
+		let data = if data.len() > 0 { Data::Out(data) } else { Data::None };
+		let rv = self.host.command(self.target, self.lun, command, data);
+		Box::new( NullResultWaiter::new(move || rv) )
+	}
+	fn recv<'a>(&'a self, command: &[u8], data: &'a mut [u8]) -> storage::AsyncIoResult<'a,()>
+	{
+		use kernel::async::NullResultWaiter;
+		let data = if data.len() > 0 { Data::In(data) } else { Data::None };
+		let rv = self.host.command(self.target, self.lun, command, data);
+		Box::new( NullResultWaiter::new(move || rv) )
+	}
+}
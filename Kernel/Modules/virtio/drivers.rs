@@ -8,10 +8,12 @@ use devices::NullDevice;
 
 
 static S_FDT_MMIO_DRIVER: FdtMmioDriver = FdtMmioDriver;
+static S_PCI_DRIVER: PciDriver = PciDriver;
 
 pub fn register()
 {
 	device_manager::register_driver(&S_FDT_MMIO_DRIVER);
+	device_manager::register_driver(&S_PCI_DRIVER);
 }
 
 
@@ -47,7 +49,56 @@ impl device_manager::Driver for FdtMmioDriver
 			return Box::new( NullDevice );
 		}
 
-		::devices::new_boxed::<::interface::Mmio>(dev, io, bus_dev.get_irq(0))
+		let irq = bus_dev.get_irq(0);
+		::devices::new_boxed(dev, ::interface::Mmio::new(io, irq))
+	}
+}
+
+const PCI_VENDOR_REDHAT: u32 = 0x1AF4;
+/// First PCI device ID for virtio 1.0 (non-transitional) devices, device type is added to this
+const PCI_DEVICE_MODERN_BASE: u32 = 0x1040;
+
+struct PciDriver;
+impl device_manager::Driver for PciDriver
+{
+	fn name(&self) -> &str {
+		"virtio-pci"
+	}
+	fn bus_type(&self) -> &str {
+		"pci"
+	}
+	fn handles(&self, bus_dev: &device_manager::BusDevice) -> u32
+	{
+		let vendor = bus_dev.get_attr("vendor").unwrap_u32();
+		let device = bus_dev.get_attr("device").unwrap_u32();
+		// 0x1000-0x103F are transitional (legacy) devices, 0x1040-0x107F are 1.0 devices
+		if vendor == PCI_VENDOR_REDHAT && 0x1000 <= device && device <= 0x107F {
+			1
+		}
+		else {
+			0
+		}
+	}
+	fn bind(&self, bus_dev: &mut device_manager::BusDevice) -> Box<device_manager::DriverInstance+'static>
+	{
+		let device = bus_dev.get_attr("device").unwrap_u32();
+		let dev = if device >= PCI_DEVICE_MODERN_BASE {
+				device - PCI_DEVICE_MODERN_BASE
+			}
+			else {
+				// Transitional devices report the device type in the subsystem ID
+				bus_dev.config_read(0x2C) >> 16
+			};
+		log_debug!("VirtIO PCI {:#x}: Device type {}", device, dev);
+
+		match ::interface::Pci::new(bus_dev)
+		{
+		Ok(int) => ::devices::new_boxed(dev, int),
+		Err(e) => {
+			log_error!("VirtIO PCI device {:#x} can't be bound: {}", device, e);
+			Box::new( NullDevice )
+			},
+		}
 	}
 }
 
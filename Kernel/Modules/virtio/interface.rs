@@ -4,21 +4,29 @@
 // virtio/interface.rs
 //! VirtualIO Interface (bus binding)
 use kernel::prelude::*;
-use kernel::device_manager::IOBinding;
-use queue::Queue;
+use kernel::device_manager::{IOBinding,BusDevice,AttrValue};
+use kernel::lib::mem::Arc;
+use kernel::sync::Mutex;
+use queue::{self,Queue};
 
 pub trait Interface
 {
-	fn new(io: IOBinding, irq: u32) -> Self;
-
 	fn bind_interrupt(&mut self, cb: Box<FnMut()->bool + Send + 'static>);
 
+	/// Negotiate device features, returns the common subset of `supported`
+	///
+	/// NOTE: Ring features (e.g. indirect descriptors) are negotiated internally and passed to the queues
 	fn negotiate_features(&mut self, supported: u32) -> u32;
 	fn get_queue(&mut self, idx: usize, size: usize) -> Option<Queue>;
 	fn set_driver_ok(&mut self);
 
 	fn notify_queue(&self, idx: usize);
 
+	/// Returns true if the device is operating as a VirtIO 1.0 ("modern") device
+	fn is_modern(&self) -> bool { false }
+	/// Returns true if the device-specific config space is accessible (it's optional for 1.0 PCI devices)
+	fn has_device_config(&self) -> bool { true }
+
 	//fn cfg_read_8(&self, ofs: usize) -> u8;
 	//fn cfg_read_16(&self, ofs: usize) -> u16;
 	unsafe fn cfg_read_32(&self, ofs: usize) -> u32;
@@ -33,24 +41,11 @@ pub struct Mmio {
 	io: IOBinding,
 	irq_gsi: u32,
 	irq_handle: Option<::kernel::irqs::ObjectHandle>,
+	/// Negotiated ring features (passed to queues)
+	ring_features: u32,
 }
 impl Interface for Mmio
 {
-	fn new(io: IOBinding, irq_gsi: u32) -> Self {
-		let mut rv = Mmio {
-			io: io,
-			irq_gsi: irq_gsi,
-			irq_handle: None,
-			};
-		// SAFE: Unique access
-		unsafe {
-			rv.set_device_status(0x0);	// Reset
-			rv.set_device_status(0x1);	// Acknowledge
-			rv.io.write_32(0x28, ::kernel::PAGE_SIZE as u32);	// "GuestPageSize"
-		}
-		rv
-	}
-
 	fn bind_interrupt(&mut self, cb: Box<FnMut()->bool + Send + 'static>) {
 		self.irq_handle = Some( ::kernel::irqs::bind_object(self.irq_gsi, cb) );
 	}
@@ -59,9 +54,10 @@ impl Interface for Mmio
 		// SAFE: Unique access
 		unsafe {
 			let dev_supported = self.io.read_32(0x10);
-			let common = dev_supported & supported;
+			let common = dev_supported & (supported | queue::RING_FEATURES);
 			self.io.write_32(0x20, common);
-			common
+			self.ring_features = common & queue::RING_FEATURES;
+			common & supported
 		}
 	}
 
@@ -77,7 +73,7 @@ impl Interface for Mmio
 		}
 		else {
 			let size = if size == 0 || size > max_size { max_size } else { size };
-			let queue = Queue::new(idx, size);
+			let queue = Queue::new(idx, size, self.ring_features);
 
 			// SAFE: Unique access, so no race possible
 			unsafe {
@@ -116,7 +112,529 @@ impl Interface for Mmio
 	}
 }
 impl Mmio {
+	pub fn new(io: IOBinding, irq_gsi: u32) -> Self {
+		let mut rv = Mmio {
+			io: io,
+			irq_gsi: irq_gsi,
+			irq_handle: None,
+			ring_features: 0,
+			};
+		// SAFE: Unique access
+		unsafe {
+			rv.set_device_status(0x0);	// Reset
+			rv.set_device_status(0x1);	// Acknowledge
+			rv.io.write_32(0x28, ::kernel::PAGE_SIZE as u32);	// "GuestPageSize"
+		}
+		rv
+	}
 	unsafe fn set_device_status(&mut self, val: u32) {
 			self.io.write_32(0x70, val);
 	}
 }
+
+/// Device status bits (common to all transports)
+#[allow(dead_code)]
+mod status {
+	pub const ACKNOWLEDGE	: u8 = 1;
+	pub const DRIVER     	: u8 = 2;
+	pub const DRIVER_OK  	: u8 = 4;
+	pub const FEATURES_OK	: u8 = 8;
+	pub const FAILED     	: u8 = 128;
+}
+
+/// Register offsets for legacy (pre-1.0) PCI devices, all within BAR0
+mod pci_legacy {
+	pub const DEVICE_FEATURES   	: usize = 0x00;
+	pub const GUEST_FEATURES    	: usize = 0x04;
+	pub const QUEUE_ADDRESS     	: usize = 0x08;
+	pub const QUEUE_SIZE        	: usize = 0x0C;
+	pub const QUEUE_SELECT      	: usize = 0x0E;
+	pub const QUEUE_NOTIFY      	: usize = 0x10;
+	pub const DEVICE_STATUS     	: usize = 0x12;
+	pub const ISR_STATUS        	: usize = 0x13;
+	pub const CONFIG_MSIX_VECTOR	: usize = 0x14;
+	pub const QUEUE_MSIX_VECTOR 	: usize = 0x16;
+	/// Offset of the device-specific config without MSI-X enabled
+	pub const DEVICE_CFG        	: usize = 0x14;
+	/// Offset of the device-specific config with MSI-X enabled
+	pub const DEVICE_CFG_MSIX   	: usize = 0x18;
+}
+/// Offsets in the virtio 1.0 "common configuration" structure
+#[allow(dead_code)]
+mod pci_common {
+	pub const DEVICE_FEATURE_SELECT	: usize = 0x00;
+	pub const DEVICE_FEATURE   	: usize = 0x04;
+	pub const DRIVER_FEATURE_SELECT	: usize = 0x08;
+	pub const DRIVER_FEATURE   	: usize = 0x0C;
+	pub const MSIX_CONFIG      	: usize = 0x10;
+	pub const NUM_QUEUES       	: usize = 0x12;
+	pub const DEVICE_STATUS    	: usize = 0x14;
+	pub const CONFIG_GENERATION	: usize = 0x15;
+	pub const QUEUE_SELECT     	: usize = 0x16;
+	pub const QUEUE_SIZE       	: usize = 0x18;
+	pub const QUEUE_MSIX_VECTOR	: usize = 0x1A;
+	pub const QUEUE_ENABLE     	: usize = 0x1C;
+	pub const QUEUE_NOTIFY_OFF 	: usize = 0x1E;
+	pub const QUEUE_DESC       	: usize = 0x20;
+	pub const QUEUE_AVAIL      	: usize = 0x28;
+	pub const QUEUE_USED       	: usize = 0x30;
+}
+/// `cfg_type` values for virtio vendor-specific PCI capabilities
+mod pci_cap {
+	pub const COMMON_CFG	: u8 = 1;
+	pub const NOTIFY_CFG	: u8 = 2;
+	pub const ISR_CFG   	: u8 = 3;
+	pub const DEVICE_CFG	: u8 = 4;
+}
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+const PCI_CAP_ID_MSIX: u8 = 0x11;
+/// Bit 32 of the feature set, indicates a virtio 1.0 compliant device/driver
+const VIRTIO_F_VERSION_1_HI: u32 = 1 << 0;
+/// "No vector" value for MSI-X vector registers
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xFFFF;
+/// Maximum time for a 1.0 device to complete a reset
+const RESET_TIMEOUT_MS: u64 = 1000;
+
+type IrqCallback = Box<FnMut()->bool + Send + 'static>;
+
+/// PCI binding, supporting both legacy (IO BAR) and virtio 1.0 (capability-based) devices
+pub struct Pci
+{
+	regs: Arc<PciRegs>,
+	irq_gsi: u32,
+	irq_handle: Option<::kernel::irqs::ObjectHandle>,
+	/// MSI-X vector 0, shared by the config change and all queues
+	msix: Option<::kernel::irqs::MsiHandle>,
+	/// Handler set by `bind_interrupt`, invoked by the MSI-X binding
+	msix_cb: Arc<Mutex<Option<IrqCallback>>>,
+	/// Per-queue offset into the notify region (only used by 1.0 devices)
+	queue_notify: Vec<usize>,
+	/// Negotiated ring features (passed to queues)
+	ring_features: u32,
+}
+enum PciRegs
+{
+	Legacy {
+		io: IOBinding,
+		/// Offset of the device-specific config (depends on MSI-X state)
+		cfg_ofs: usize,
+	},
+	Modern {
+		common: PciRegion,
+		notify: PciRegion,
+		notify_mult: u32,
+		isr: PciRegion,
+		device: Option<PciRegion>,
+	},
+}
+/// A register region within a BAR, as described by a virtio capability
+struct PciRegion
+{
+	io: Arc<IOBinding>,
+	ofs: usize,
+	len: usize,
+}
+
+impl Pci
+{
+	/// Bind to a PCI device, using the 1.0 interface if avaliable
+	pub fn new(bus_dev: &mut BusDevice) -> Result<Pci, &'static str>
+	{
+		bus_dev.set_attr("bus_master", AttrValue::U32(1));
+		let irq_gsi = bus_dev.get_irq(0);
+		let caps = get_pci_caps(bus_dev);
+
+		let mut bars: [Option<Arc<IOBinding>>; 6] = [None, None, None, None, None, None];
+		let mut common = None;
+		let mut notify = None;
+		let mut isr = None;
+		let mut device = None;
+		for &(id, ofs) in caps.iter().filter(|&&(id,_)| id == PCI_CAP_ID_VNDR)
+		{
+			let w0 = bus_dev.config_read(ofs);
+			let bar = (bus_dev.config_read(ofs + 4) & 0xFF) as usize;
+			let reg_ofs = bus_dev.config_read(ofs + 8) as usize;
+			let reg_len = bus_dev.config_read(ofs + 12) as usize;
+			let cfg_type = (w0 >> 24) as u8;
+			log_trace!("Cap {:#x} @{:#x}: type={} BAR{} {:#x}+{:#x}", id, ofs, cfg_type, bar, reg_ofs, reg_len);
+			if bar >= 6 {
+				continue ;
+			}
+			let slot = match cfg_type
+				{
+				pci_cap::COMMON_CFG => &mut common,
+				pci_cap::NOTIFY_CFG => &mut notify,
+				pci_cap::ISR_CFG    => &mut isr,
+				pci_cap::DEVICE_CFG => &mut device,
+				_ => continue,
+				};
+			// Only the first capability of each type is used
+			if slot.is_none() {
+				let notify_mult = if cfg_type == pci_cap::NOTIFY_CFG { bus_dev.config_read(ofs + 16) } else { 0 };
+				*slot = Some( (PciRegion { io: get_bar(&mut bars, bus_dev, bar), ofs: reg_ofs, len: reg_len }, notify_mult) );
+			}
+		}
+
+		let msix_cb = Arc::new(Mutex::new(None));
+		let msix = match caps.iter().find(|&&(id,_)| id == PCI_CAP_ID_MSIX)
+			{
+			Some(&(_, ofs)) => enable_msix(bus_dev, ofs, &mut bars, &msix_cb),
+			None => None,
+			};
+		
+		let regs = match (common, notify, isr)
+			{
+			(Some((common,_)), Some((notify,notify_mult)), Some((isr,_))) => {
+				log_debug!("VirtIO PCI 1.0 device (msix={})", msix.is_some());
+				PciRegs::Modern {
+					common: common,
+					notify: notify,
+					notify_mult: notify_mult,
+					isr: isr,
+					device: device.map(|(r,_)| r),
+					}
+				},
+			_ => {
+				if bus_dev.get_attr("device").unwrap_u32() >= 0x1040 {
+					return Err("Non-transitional device missing required capabilities");
+				}
+				log_debug!("VirtIO PCI legacy device (msix={})", msix.is_some());
+				PciRegs::Legacy {
+					io: bus_dev.bind_io(0),
+					cfg_ofs: if msix.is_some() { pci_legacy::DEVICE_CFG_MSIX } else { pci_legacy::DEVICE_CFG },
+					}
+				},
+			};
+
+		let rv = Pci {
+			regs: Arc::new(regs),
+			irq_gsi: irq_gsi,
+			irq_handle: None,
+			msix: msix,
+			msix_cb: msix_cb,
+			queue_notify: Vec::new(),
+			ring_features: 0,
+			};
+		// SAFE: Unique access
+		unsafe {
+			try!(rv.regs.reset());
+			rv.regs.set_status(status::ACKNOWLEDGE);
+			rv.regs.set_status(status::ACKNOWLEDGE | status::DRIVER);
+			if rv.msix.is_some() {
+				rv.regs.set_config_vector(0);
+			}
+		}
+		Ok(rv)
+	}
+}
+impl Interface for Pci
+{
+	fn bind_interrupt(&mut self, cb: Box<FnMut()->bool + Send + 'static>) {
+		if self.msix.is_some() {
+			*self.msix_cb.lock() = Some(cb);
+		}
+		else {
+			let regs = self.regs.clone();
+			let mut cb = cb;
+			self.irq_handle = Some( ::kernel::irqs::bind_object(self.irq_gsi, Box::new(move || {
+				// SAFE: Reading the ISR clears it, which is the desired effect (INTx is level-triggered)
+				if unsafe { regs.read_isr() } & 3 != 0 {
+					cb()
+				}
+				else {
+					false
+				}
+				})) );
+		}
+	}
+
+	fn negotiate_features(&mut self, supported: u32) -> u32 {
+		// SAFE: Unique access
+		unsafe {
+			match *self.regs
+			{
+			PciRegs::Legacy { ref io, .. } => {
+				let dev_supported = io.read_32(pci_legacy::DEVICE_FEATURES);
+				let common = dev_supported & (supported | queue::RING_FEATURES);
+				io.write_32(pci_legacy::GUEST_FEATURES, common);
+				self.ring_features = common & queue::RING_FEATURES;
+				common & supported
+				},
+			PciRegs::Modern { ref common, .. } => {
+				common.write_32(pci_common::DEVICE_FEATURE_SELECT, 0);
+				let dev_supported = common.read_32(pci_common::DEVICE_FEATURE);
+				common.write_32(pci_common::DEVICE_FEATURE_SELECT, 1);
+				let dev_supported_hi = common.read_32(pci_common::DEVICE_FEATURE);
+				if dev_supported_hi & VIRTIO_F_VERSION_1_HI == 0 {
+					log_warning!("VirtIO 1.0 device doesn't offer VIRTIO_F_VERSION_1");
+				}
+				let rv = dev_supported & (supported | queue::RING_FEATURES);
+				common.write_32(pci_common::DRIVER_FEATURE_SELECT, 0);
+				common.write_32(pci_common::DRIVER_FEATURE, rv);
+				common.write_32(pci_common::DRIVER_FEATURE_SELECT, 1);
+				common.write_32(pci_common::DRIVER_FEATURE, dev_supported_hi & VIRTIO_F_VERSION_1_HI);
+
+				let s = self.regs.get_status();
+				self.regs.set_status(s | status::FEATURES_OK);
+				if self.regs.get_status() & status::FEATURES_OK == 0 {
+					log_error!("VirtIO device rejected feature set {:#x}", rv);
+					self.regs.set_status(s | status::FAILED);
+				}
+				self.ring_features = rv & queue::RING_FEATURES;
+				rv & supported
+				},
+			}
+		}
+	}
+
+	fn get_queue(&mut self, idx: usize, size: usize) -> Option<Queue> {
+		let use_msix = self.msix.is_some();
+		// SAFE: Unique access, so no race possible
+		unsafe {
+			match *self.regs
+			{
+			PciRegs::Legacy { ref io, .. } => {
+				io.write_16(pci_legacy::QUEUE_SELECT, idx as u16);
+				let max_size = io.read_16(pci_legacy::QUEUE_SIZE) as usize;
+				if max_size == 0 {
+					return None;
+				}
+				// Legacy devices have a fixed queue size
+				if size != 0 && size != max_size {
+					log_debug!("Queue {}: Requested size {} ignored, legacy device uses {}", idx, size, max_size);
+				}
+				let queue = Queue::new(idx, max_size, self.ring_features);
+				if use_msix {
+					io.write_16(pci_legacy::QUEUE_MSIX_VECTOR, 0);
+					if io.read_16(pci_legacy::QUEUE_MSIX_VECTOR) == VIRTIO_MSI_NO_VECTOR {
+						log_error!("Queue {}: Unable to assign MSI-X vector", idx);
+					}
+				}
+				let page = queue.phys_addr() / ::kernel::PAGE_SIZE as u64;
+				io.write_32(pci_legacy::QUEUE_ADDRESS, page as u32);
+				Some(queue)
+				},
+			PciRegs::Modern { ref common, notify_mult, .. } => {
+				common.write_16(pci_common::QUEUE_SELECT, idx as u16);
+				let max_size = common.read_16(pci_common::QUEUE_SIZE) as usize;
+				if max_size == 0 {
+					return None;
+				}
+				let size = if size == 0 || size > max_size { max_size } else { size };
+				let queue = Queue::new(idx, size, self.ring_features);
+				common.write_16(pci_common::QUEUE_SIZE, size as u16);
+				if use_msix {
+					common.write_16(pci_common::QUEUE_MSIX_VECTOR, 0);
+					if common.read_16(pci_common::QUEUE_MSIX_VECTOR) == VIRTIO_MSI_NO_VECTOR {
+						log_error!("Queue {}: Unable to assign MSI-X vector", idx);
+					}
+				}
+				common.write_64(pci_common::QUEUE_DESC, queue.phys_addr_desc());
+				common.write_64(pci_common::QUEUE_AVAIL, queue.phys_addr_avail());
+				common.write_64(pci_common::QUEUE_USED, queue.phys_addr_used());
+				let notify_ofs = common.read_16(pci_common::QUEUE_NOTIFY_OFF) as usize * notify_mult as usize;
+				common.write_16(pci_common::QUEUE_ENABLE, 1);
+
+				while self.queue_notify.len() <= idx {
+					self.queue_notify.push(0);
+				}
+				self.queue_notify[idx] = notify_ofs;
+				Some(queue)
+				},
+			}
+		}
+	}
+
+	fn set_driver_ok(&mut self) {
+		// SAFE: Unique access
+		unsafe {
+			let s = self.regs.get_status();
+			self.regs.set_status(s | status::DRIVER_OK);
+		}
+	}
+	
+	fn notify_queue(&self, idx: usize) {
+		// SAFE: Atomic write
+		unsafe {
+			match *self.regs
+			{
+			PciRegs::Legacy { ref io, .. } => io.write_16(pci_legacy::QUEUE_NOTIFY, idx as u16),
+			PciRegs::Modern { ref notify, .. } => notify.write_16(self.queue_notify[idx], idx as u16),
+			}
+		}
+	}
+
+	fn is_modern(&self) -> bool {
+		match *self.regs
+		{
+		PciRegs::Legacy { .. } => false,
+		PciRegs::Modern { .. } => true,
+		}
+	}
+	fn has_device_config(&self) -> bool {
+		match *self.regs
+		{
+		PciRegs::Legacy { .. } => true,
+		PciRegs::Modern { ref device, .. } => device.is_some(),
+		}
+	}
+
+	unsafe fn cfg_read_32(&self, ofs: usize) -> u32 {
+		match *self.regs
+		{
+		PciRegs::Legacy { ref io, cfg_ofs } => io.read_32(cfg_ofs + ofs),
+		PciRegs::Modern { device: Some(ref r), .. } => r.read_32(ofs),
+		// NOTE: Devices using the config space aren't bound without it (see `devices::new_boxed`)
+		PciRegs::Modern { device: None, .. } => panic!("cfg_read_32 - No device config region"),
+		}
+	}
+	unsafe fn cfg_write_32(&self, ofs: usize, v: u32) {
+		match *self.regs
+		{
+		PciRegs::Legacy { ref io, cfg_ofs } => io.write_32(cfg_ofs + ofs, v),
+		PciRegs::Modern { device: Some(ref r), .. } => r.write_32(ofs, v),
+		PciRegs::Modern { device: None, .. } => panic!("cfg_write_32 - No device config region"),
+		}
+	}
+}
+
+impl PciRegs
+{
+	unsafe fn reset(&self) -> Result<(), &'static str> {
+		self.set_status(0);
+		// 1.0 devices indicate reset completion by reading back zero
+		if let PciRegs::Modern { .. } = *self {
+			if ! ::kernel::time::wait_for(RESET_TIMEOUT_MS, 1, || self.get_status() == 0) {
+				return Err("Device didn't complete reset");
+			}
+		}
+		Ok( () )
+	}
+	fn get_status(&self) -> u8 {
+		// SAFE: Read has no side-effects
+		unsafe {
+			match *self
+			{
+			PciRegs::Legacy { ref io, .. } => io.read_8(pci_legacy::DEVICE_STATUS),
+			PciRegs::Modern { ref common, .. } => common.read_8(pci_common::DEVICE_STATUS),
+			}
+		}
+	}
+	unsafe fn set_status(&self, val: u8) {
+		match *self
+		{
+		PciRegs::Legacy { ref io, .. } => io.write_8(pci_legacy::DEVICE_STATUS, val),
+		PciRegs::Modern { ref common, .. } => common.write_8(pci_common::DEVICE_STATUS, val),
+		}
+	}
+	unsafe fn set_config_vector(&self, vector: u16) {
+		match *self
+		{
+		PciRegs::Legacy { ref io, .. } => io.write_16(pci_legacy::CONFIG_MSIX_VECTOR, vector),
+		PciRegs::Modern { ref common, .. } => common.write_16(pci_common::MSIX_CONFIG, vector),
+		}
+	}
+	/// Read (and hence clear) the ISR status
+	unsafe fn read_isr(&self) -> u8 {
+		match *self
+		{
+		PciRegs::Legacy { ref io, .. } => io.read_8(pci_legacy::ISR_STATUS),
+		PciRegs::Modern { ref isr, .. } => isr.read_8(0),
+		}
+	}
+}
+
+impl PciRegion
+{
+	unsafe fn read_8(&self, ofs: usize) -> u8 {
+		assert!(ofs + 1 <= self.len);
+		self.io.read_8(self.ofs + ofs)
+	}
+	unsafe fn read_16(&self, ofs: usize) -> u16 {
+		assert!(ofs + 2 <= self.len);
+		self.io.read_16(self.ofs + ofs)
+	}
+	unsafe fn read_32(&self, ofs: usize) -> u32 {
+		assert!(ofs + 4 <= self.len);
+		self.io.read_32(self.ofs + ofs)
+	}
+	unsafe fn write_8(&self, ofs: usize, v: u8) {
+		assert!(ofs + 1 <= self.len);
+		self.io.write_8(self.ofs + ofs, v)
+	}
+	unsafe fn write_16(&self, ofs: usize, v: u16) {
+		assert!(ofs + 2 <= self.len);
+		self.io.write_16(self.ofs + ofs, v)
+	}
+	unsafe fn write_32(&self, ofs: usize, v: u32) {
+		assert!(ofs + 4 <= self.len);
+		self.io.write_32(self.ofs + ofs, v)
+	}
+	/// 64-bit registers are written as two 32-bit halves (low first)
+	unsafe fn write_64(&self, ofs: usize, v: u64) {
+		self.write_32(ofs + 0, v as u32);
+		self.write_32(ofs + 4, (v >> 32) as u32);
+	}
+}
+
+/// Enumerate the PCI capability list, returning (id, config offset) pairs
+fn get_pci_caps(bus_dev: &BusDevice) -> Vec<(u8, usize)>
+{
+	let mut rv = Vec::new();
+	// Status register bit 4 indicates the presence of a capabilities list
+	if bus_dev.config_read(0x04) & (1 << (16+4)) == 0 {
+		return rv;
+	}
+	let mut ptr = (bus_dev.config_read(0x34) & 0xFC) as usize;
+	// Limit the walk, in case of a malformed (looping) list
+	let mut limit = 48;
+	while ptr != 0 && limit > 0
+	{
+		let w = bus_dev.config_read(ptr);
+		rv.push( ((w & 0xFF) as u8, ptr) );
+		ptr = ((w >> 8) & 0xFC) as usize;
+		limit -= 1;
+	}
+	rv
+}
+
+/// Obtain a (shared) binding to the specified BAR
+fn get_bar(bars: &mut [Option<Arc<IOBinding>>; 6], bus_dev: &mut BusDevice, idx: usize) -> Arc<IOBinding>
+{
+	if bars[idx].is_none() {
+		bars[idx] = Some( Arc::new(bus_dev.bind_io(idx)) );
+	}
+	bars[idx].as_ref().unwrap().clone()
+}
+
+/// Allocate an MSI and point MSI-X table entry 0 at it, then enable MSI-X
+fn enable_msix(bus_dev: &mut BusDevice, cap_ofs: usize, bars: &mut [Option<Arc<IOBinding>>; 6], cb: &Arc<Mutex<Option<IrqCallback>>>) -> Option<::kernel::irqs::MsiHandle>
+{
+	let ctrl = bus_dev.config_read(cap_ofs);
+	let table = bus_dev.config_read(cap_ofs + 4);
+	let table_size = ((ctrl >> 16) & 0x7FF) + 1;
+	let table_bar = (table & 7) as usize;
+	let table_ofs = (table & !7) as usize;
+	log_debug!("MSI-X: {} vectors, table BAR{}+{:#x}", table_size, table_bar, table_ofs);
+	if table_bar >= 6 {
+		return None;
+	}
+
+	let cb = cb.clone();
+	let msi = match ::kernel::irqs::bind_msi_object(Box::new(move || match *cb.lock() { Some(ref mut f) => f(), None => false }))
+		{
+		Some(v) => v,
+		None => return None,
+		};
+	let io = get_bar(bars, bus_dev, table_bar);
+	// SAFE: Entry 0 of the MSI-X table is owned by this driver
+	unsafe {
+		io.write_32(table_ofs + 0, msi.addr() as u32);
+		io.write_32(table_ofs + 4, (msi.addr() >> 32) as u32);
+		io.write_32(table_ofs + 8, msi.data());
+		io.write_32(table_ofs + 12, 0);	// Vector control: Unmasked
+	}
+	// Set "MSI-X Enable", clear "Function Mask"
+	bus_dev.config_write(cap_ofs, (ctrl & !(1 << 30)) | (1 << 31));
+	Some(msi)
+}
@@ -7,8 +7,11 @@
 #![feature(linkage)]
 
 #[macro_use] extern crate kernel;
+extern crate network;
+extern crate gui;
+extern crate storage_scsi;
 
-module_define!{VirtIO, [DeviceManager, Storage], init}
+module_define!{VirtIO, [DeviceManager, Storage, Network, GUI], init}
 
 mod drivers;
 mod interface;
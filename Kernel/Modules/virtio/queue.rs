@@ -1,22 +1,64 @@
+// "Tifflin" Kernel - VirtIO Driver
+// - By John Hodge (thePowersGang)
 //
-//
-//
+// virtio/queue.rs
+//! VirtIO split virtqueues
 //!
+//! Free descriptors are kept in a chain (linked through the `next` field), with senders blocking until enough are
+//! released. Requests with multiple buffer regions use an indirect table (if negotiated) so they only consume one
+//! descriptor from the main table.
 use kernel::prelude::*;
+use kernel::sync::{Spinlock,Semaphore};
+use kernel::memory::virt::AllocHandle;
+use kernel::memory::helpers::DMABuffer;
 use interface::Interface;
-use core::sync::atomic::{AtomicUsize,Ordering};
+use core::sync::atomic::{AtomicUsize,Ordering,fence};
+use core::ptr::{read_volatile,write_volatile};
+
+/// Ring feature: Indirect descriptor tables
+pub const VIRTIO_F_INDIRECT_DESC: u32 = 1 << 28;
+/// Ring feature: Notification suppression using `used_event`/`avail_event`
+pub const VIRTIO_F_EVENT_IDX	: u32 = 1 << 29;
+/// Ring features handled by the queue (negotiated by the interface, independent of the device type)
+pub const RING_FEATURES: u32 = VIRTIO_F_INDIRECT_DESC | VIRTIO_F_EVENT_IDX;
+
+pub const VRING_DESC_F_NEXT 	: u16 = 1;
+pub const VRING_DESC_F_WRITE	: u16 = 2;
+pub const VRING_DESC_F_INDIRECT	: u16 = 4;
+
+/// Set by the device in the used ring flags to suppress notifications (when EVENT_IDX isn't negotiated)
+pub const VRING_USED_F_NO_NOTIFY: u16 = 1;
+
+/// Maximum length of an indirect table (longer requests use a chain in the main table)
+const INDIRECT_MAX: usize = 16;
+const INDIRECT_TABLE_SIZE: usize = INDIRECT_MAX * 16;
+/// Flag set in the result slot when a request completes (as the written length can be zero)
+const RES_COMPLETE: usize = 1 << (::core::mem::size_of::<usize>() * 8 - 1);
 
 pub struct Queue {
 	idx: usize,
 	size: usize,
-	buffer: ::kernel::memory::virt::AllocHandle,
-	descriptors_lock: ::kernel::sync::Mutex<()>,
-	avail_ring_lock: ::kernel::sync::Mutex<()>,
+	features: u32,
+	buffer: AllocHandle,
+	/// One indirect table per descriptor, used when that descriptor is the head of a request
+	indirect_tables: Option<AllocHandle>,
+
+	/// Free descriptor chain (also protects the `next` links of free descriptors)
+	free_list: Spinlock<FreeList>,
+	/// Released (once per waiter) when descriptors are returned to the free chain
+	free_wait: Semaphore,
+	avail_ring_lock: Spinlock<()>,
 
 	last_seen_used: AtomicUsize,
-	interrupt_flag: ::kernel::sync::Semaphore,
+	interrupt_flag: Semaphore,
 	avail_ring_res: Vec<AtomicUsize>,
 }
+struct FreeList {
+	head: u16,
+	count: usize,
+	/// Number of senders waiting on `free_wait`
+	waiters: usize,
+}
 
 pub enum Buffer<'a> {
 	Read(&'a [u8]),
@@ -39,11 +81,6 @@ impl<'a> Buffer<'a> {
 	}
 }
 
-pub const VRING_DESC_F_NEXT 	: u16 = 1;
-pub const VRING_DESC_F_WRITE	: u16 = 2;
-#[allow(dead_code)]
-pub const VRING_DESC_F_INDIRECT	: u16 = 4;
-
 #[repr(C)]
 pub struct VRingDesc {
 	addr: u64,
@@ -53,27 +90,16 @@ pub struct VRingDesc {
 }
 #[repr(C)]
 #[derive(Debug)]
-struct AvailRing {
-	flags: u16,
-	idx: u16,
-	ents: [u16],
-	//used_event: u16,
-}
-#[repr(C)]
-#[derive(Debug)]
-struct UsedRing {
-	flags: u16,
-	idx: u16,
-	ents: [UsedElem],
-	//avail_event: u16,
-}
-#[repr(C)]
-#[derive(Debug)]
 struct UsedElem {
 	id: u32,
 	len: u32,
 }
 
+/// Returns true if the index moving from `old` to `new` passes `event` (virtio spec `vring_need_event`)
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+	new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
 impl Queue
 {
 	fn get_first_size(count: usize) -> usize {
@@ -85,169 +111,283 @@ impl Queue
 		Self::get_first_size(count) + ((second + 0xFFF) & !0xFFF)
 	}
 
-	pub fn new(idx: usize, count: usize) -> Queue
+	/// Allocate a queue with `count` entries, `features` is the negotiated subset of `RING_FEATURES`
+	pub fn new(idx: usize, count: usize, features: u32) -> Queue
 	{
+		assert!(count > 0 && count <= 0x8000, "Invalid queue size {}", count);
 		let n_pages = Self::get_alloc_size(count) / ::kernel::PAGE_SIZE;
-		assert!(n_pages > 0);
+		let buffer = ::kernel::memory::virt::alloc_dma(32+12, n_pages, "VirtIO").expect("TODO: Handle alloc failure VirtIO queue");
+		// Chain all descriptors together to form the initial free list
+		{
+			// SAFE: Newly allocated, so uniquely owned
+			let descs: &mut [VRingDesc] = unsafe { buffer.as_int_mut_slice(0, count) };
+			for (i, d) in descs.iter_mut().enumerate() {
+				d.length = 0;
+				d.flags = 0;
+				d.next = (i + 1) as u16;
+			}
+		}
+		let indirect_tables = if features & VIRTIO_F_INDIRECT_DESC != 0 {
+				let per_page = ::kernel::PAGE_SIZE / INDIRECT_TABLE_SIZE;
+				Some( ::kernel::memory::virt::alloc_dma(64, (count + per_page - 1) / per_page, "VirtIO").expect("TODO: Handle alloc failure VirtIO indirect tables") )
+			}
+			else {
+				None
+			};
+		log_debug!("Queue {}: {} entries, features={:#x}", idx, count, features);
 		Queue {
 			idx: idx,
 			size: count,
-			buffer: ::kernel::memory::virt::alloc_dma(32+12, n_pages, "VirtIO").expect("TODO: Handle alloc failure VirtIO queue"),
-			descriptors_lock: Default::default(),
-			avail_ring_lock: Default::default(),
+			features: features,
+			buffer: buffer,
+			indirect_tables: indirect_tables,
+
+			free_list: Spinlock::new(FreeList { head: 0, count: count, waiters: 0 }),
+			free_wait: Semaphore::new(0, ::core::isize::MAX),
+			avail_ring_lock: Spinlock::new( () ),
 
 			last_seen_used: AtomicUsize::new(0),
-			interrupt_flag: ::kernel::sync::Semaphore::new(0, count as isize),
+			interrupt_flag: Semaphore::new(0, count as isize),
 			avail_ring_res: (0..count).map(|_| AtomicUsize::new(0)).collect(),
 			}
 	}
 
+	/// Handle completions for requests that use `Request::wait_for_completion`
 	pub fn check_interrupt(&self) {
-		while self.last_seen_used.load(Ordering::Relaxed) as u16 != self.used_ring().idx {
-			let idx = self.last_seen_used.fetch_add(1, Ordering::Relaxed) & 0xFFFF;
-			log_debug!("idx={}, desc={:?}", idx, self.used_ring().ents[idx]);
-			let UsedElem { id, len } = self.used_ring().ents[idx];
-
-			assert!(len > 0);
-			self.avail_ring_res[id as usize].store(len as usize, Ordering::Release);
+		self.collect_used(|id, len| {
+			self.avail_ring_res[id as usize].store(len | RES_COMPLETE, Ordering::Release);
 			self.interrupt_flag.release();
+			});
+	}
+
+	/// Pop completed entries from the used ring without waking waiters, passing (head descriptor, written length) to `f`
+	///
+	/// For queues where the driver tracks outstanding requests itself (e.g. network queues), instead of waiting on them.
+	pub fn collect_used<F: FnMut(u16, usize)>(&self, mut f: F) {
+		loop
+		{
+			while self.last_seen_used.load(Ordering::Relaxed) as u16 != self.used_idx() {
+				// Entry contents must only be read after observing the index update
+				fence(Ordering::Acquire);
+				let idx = self.last_seen_used.fetch_add(1, Ordering::Relaxed) % self.size;
+				let UsedElem { id, len } = self.used_elem(idx);
+				log_trace!("collect_used: idx={}, id={}, len={}", idx, id, len);
+				f(id as u16, len as usize);
+			}
+
+			if self.features & VIRTIO_F_EVENT_IDX == 0 {
+				break;
+			}
+			// Ask for an interrupt on the next used entry, then check again in case one was added before the device saw it
+			let seen = self.last_seen_used.load(Ordering::Relaxed) as u16;
+			// SAFE: Only written by the driver, read by the device
+			unsafe { write_volatile(self.avail_ptr().offset(2 + self.size as isize), seen); }
+			fence(Ordering::SeqCst);
+			if self.used_idx() == seen {
+				break;
+			}
 		}
 	}
 
 	pub fn phys_addr(&self) -> u64 {
 		::kernel::memory::virt::get_phys(self.buffer.as_ref::<u8>(0)) as u64
 	}
+	/// Physical address of the descriptor table (for transports that take separate addresses)
+	pub fn phys_addr_desc(&self) -> u64 {
+		self.phys_addr()
+	}
+	/// Physical address of the available (driver) ring
+	pub fn phys_addr_avail(&self) -> u64 {
+		self.phys_addr() + 16 * self.size as u64
+	}
+	/// Physical address of the used (device) ring
+	pub fn phys_addr_used(&self) -> u64 {
+		self.phys_addr() + Self::get_first_size(self.size) as u64
+	}
 
+	/// Hand a set of buffers to the device
+	///
+	/// Blocks until enough descriptors are free, so should only be called from interrupt context when the driver
+	/// bounds its outstanding requests (e.g. re-posting a receive buffer after its completion).
 	pub fn send_buffers<'a, I: Interface>(&'a self, interface: &I, buffers: &mut [Buffer<'a>]) -> Request<'a> {
 		assert!(buffers.len() > 0);
 
-		// Allocate a descriptor for each buffer (backwards to build up linked list)
-		let mut it = buffers.iter_mut().rev();
-		let mut descriptor = self.allocate_descriptor(None, it.next().unwrap());
-		for buf in it
-		{
-			descriptor = self.allocate_descriptor(Some(descriptor), buf);
+		let n_ranges: usize = buffers.iter().map(|b| DMABuffer::new(b.as_slice(), 64).phys_ranges().count()).sum();
+		let use_indirect = self.indirect_tables.is_some() && n_ranges > 1 && n_ranges <= INDIRECT_MAX;
+		let n_desc = if use_indirect { 1 } else { n_ranges };
+		assert!(n_desc <= self.size, "Request needs {} descriptors, queue only has {}", n_desc, self.size);
+
+		let head = self.allocate_descriptors(n_desc);
+		// SAFE: The allocated descriptors (and the indirect table for `head`) are owned by this request
+		unsafe {
+			let descs = self.descriptors();
+			if use_indirect {
+				let table = self.indirect_table(head);
+				let n = Self::fill_indirect(table, buffers);
+				let d = &mut descs[head as usize];
+				d.addr = ::kernel::memory::virt::get_phys(table.as_ptr()) as u64;
+				d.length = (n * 16) as u32;
+				d.flags = VRING_DESC_F_INDIRECT;
+				log_trace!("Desc {}: Indirect {} entries", head, n);
+			}
+			else {
+				// The allocated descriptors are already chained through `next`
+				let mut idx = head;
+				let mut n = 0;
+				for buf in buffers.iter()
+				{
+					let write = buf.is_write();
+					for (phys, len) in DMABuffer::new(buf.as_slice(), 64).phys_ranges()
+					{
+						n += 1;
+						let d = &mut descs[idx as usize];
+						d.addr = phys as u64;
+						d.length = len as u32;
+						d.flags = (if n < n_desc { VRING_DESC_F_NEXT } else { 0 }) | (if write { VRING_DESC_F_WRITE } else { 0 });
+						log_trace!("Desc {}: {:#x}+{}", idx, phys, len);
+						idx = d.next;
+					}
+				}
+			}
 		}
 
 		// Add to the active queue
-		self.dispatch_descriptor(interface, descriptor)
+		self.dispatch_descriptor(interface, head)
 	}
 
-	fn allocate_descriptor<'a>(&self, mut next: Option<DescriptorHandle<'a>>, buffer: &mut Buffer<'a>) -> DescriptorHandle<'a> {
-		let write = buffer.is_write();
-		for (phys, len) in ::kernel::memory::helpers::DMABuffer::new(buffer.as_slice(), 64).phys_ranges().rev()
+	/// Populate an indirect table with the buffers, returning the number of entries used
+	fn fill_indirect(table: &mut [VRingDesc], buffers: &[Buffer]) -> usize {
+		let mut n = 0;
+		for buf in buffers
 		{
-			next = Some( self.allocate_descriptor_raw(next, write, phys as u64, len as u32) );
+			let write = buf.is_write();
+			for (phys, len) in DMABuffer::new(buf.as_slice(), 64).phys_ranges()
+			{
+				table[n].addr = phys as u64;
+				table[n].length = len as u32;
+				table[n].flags = VRING_DESC_F_NEXT | (if write { VRING_DESC_F_WRITE } else { 0 });
+				table[n].next = (n + 1) as u16;
+				n += 1;
+			}
 		}
-		next.unwrap()
+		table[n-1].flags &= !VRING_DESC_F_NEXT;
+		n
 	}
-	fn allocate_descriptor_raw<'a>(&self, next: Option<DescriptorHandle<'a>>, write: bool, phys: u64, len: u32) -> DescriptorHandle<'a> {
-		// TODO: Semaphore to ensure sufficient quantity
-		// TODO: Use a "free chain" instead
-		for (idx, desc) in self.descriptors().iter_mut().enumerate()
+
+	/// Take a chain of `count` descriptors from the free list (blocking until enough are avaliable)
+	fn allocate_descriptors(&self, count: usize) -> u16 {
+		loop
 		{
-			if desc.length == 0 {
-				desc.addr = phys;
-				desc.length = len;
-				desc.flags = (if next.is_some() { VRING_DESC_F_NEXT } else { 0 }) | (if write { VRING_DESC_F_WRITE } else { 0 });
-				desc.next = match next { Some(v) => v.idx, None => 0 };
-				log_trace!("Desc {}: {:#x}+{}", idx, phys, len);
-				return DescriptorHandle { pd: ::core::marker::PhantomData, idx: idx as u16 };
+			{
+				let _irq = ::kernel::sync::hold_interrupts();
+				let mut lh = self.free_list.lock();
+				if lh.count >= count {
+					// SAFE: Free list lock held
+					let descs = unsafe { self.descriptors() };
+					let head = lh.head;
+					let mut last = head;
+					for _ in 1 .. count {
+						last = descs[last as usize].next;
+					}
+					lh.head = descs[last as usize].next;
+					lh.count -= count;
+					return head;
+				}
+				lh.waiters += 1;
 			}
+			log_trace!("Queue {}: Waiting for {} descriptors", self.idx, count);
+			self.free_wait.acquire();
 		}
-		todo!("allocate_descriptor - out of descriptors");
 	}
-	fn dispatch_descriptor<'a, I: Interface>(&'a self, interface: &I, handle: DescriptorHandle<'a>) -> Request<'a> {
-		
-		self.avail_ring().push( handle.idx );
-		// TODO: Memory barrier
+	/// Return a request's descriptors to the free list
+	fn release_descriptors(&self, head: u16) {
+		let wake = {
+			let _irq = ::kernel::sync::hold_interrupts();
+			let mut lh = self.free_list.lock();
+			// SAFE: Descriptors are owned by the request being released, and the free list lock is held
+			let descs = unsafe { self.descriptors() };
+			let mut count = 1;
+			let mut last = head;
+			while descs[last as usize].flags & VRING_DESC_F_NEXT != 0 {
+				last = descs[last as usize].next;
+				count += 1;
+			}
+			log_trace!("- Desc {}: Release {}", head, count);
+			descs[last as usize].next = lh.head;
+			lh.head = head;
+			lh.count += count;
+			::core::mem::replace(&mut lh.waiters, 0)
+			};
+		for _ in 0 .. wake {
+			self.free_wait.release();
+		}
+	}
+
+	fn dispatch_descriptor<'a, I: Interface>(&'a self, interface: &I, head: u16) -> Request<'a> {
+		let notify = {
+			let _irq = ::kernel::sync::hold_interrupts();
+			let _lh = self.avail_ring_lock.lock();
+			let avail = self.avail_ptr();
+			// SAFE: Avaliable ring lock held, and `avail_ptr` is valid
+			unsafe {
+				let old = read_volatile(avail.offset(1));
+				write_volatile(avail.offset(2 + (old as usize % self.size) as isize), head);
+				// Descriptor and ring contents must be visible before the index update
+				fence(Ordering::Release);
+				let new = old.wrapping_add(1);
+				write_volatile(avail.offset(1), new);
+				// Index update must be visible before checking if the device wants to be notified
+				fence(Ordering::SeqCst);
 
-		interface.notify_queue(self.idx);
+				if self.features & VIRTIO_F_EVENT_IDX != 0 {
+					need_event(read_volatile(self.used_ptr().offset(2 + 4 * self.size as isize)), new, old)
+				}
+				else {
+					read_volatile(self.used_ptr()) & VRING_USED_F_NO_NOTIFY == 0
+				}
+			}
+			};
+
+		if notify {
+			interface.notify_queue(self.idx);
+		}
 		Request {
 			queue: self,
-			first_desc: handle.idx
+			first_desc: head,
 			}
 	}
 
-	/// Return a lock handle to the "avaliable" ring buffer (the list of descriptors handed to the device)
-	fn avail_ring(&self) -> LockedAvailRing {
-		LockedAvailRing {
-			_lh: self.avail_ring_lock.lock(),
-			// SAFE: Locked
-			ptr: unsafe { 
-				let base_ptr: *const u16 = self.buffer.as_int_mut(16 * self.size);
-				// NOTE: Constructing an unsized struct pointer
-				let ptr: &mut AvailRing = ::core::mem::transmute( (base_ptr, self.size) );
-				assert_eq!(&ptr.flags as *const _, base_ptr);
-				ptr
-				},
-		}
+	/// Pointer to the avaliable ring (`flags`, `idx`, `ring[size]`, `used_event`)
+	fn avail_ptr(&self) -> *mut u16 {
+		// SAFE: Only used as a raw pointer
+		unsafe { self.buffer.as_int_mut::<u16>(16 * self.size) as *mut u16 }
 	}
-	fn used_ring(&self) -> &UsedRing {
-		// SAFE: Unaliased memory
-		unsafe {
-			let ptr: *const () = self.buffer.as_ref( Self::get_first_size(self.size) );
-			let rv: &UsedRing = ::core::mem::transmute(::core::slice::from_raw_parts(ptr, self.size));
-			assert_eq!(&rv.flags as *const _, ptr as *const u16);
-			rv
-		}
+	/// Pointer to the used ring (`flags`, `idx`, `ring[size]` as 32-bit pairs, `avail_event`)
+	fn used_ptr(&self) -> *const u16 {
+		self.buffer.as_ref::<u16>( Self::get_first_size(self.size) ) as *const u16
 	}
-
-	/// Return a lock handle to the descriptor table
-	fn descriptors(&self) -> LockedDescriptors {
-		LockedDescriptors {
-			_lh: self.descriptors_lock.lock(),
-			// SAFE: Locked access
-			slice: unsafe { self.buffer.as_int_mut_slice(0, self.size) },
-		}
+	fn used_idx(&self) -> u16 {
+		// SAFE: Valid pointer, device-written so volatile
+		unsafe { read_volatile(self.used_ptr().offset(1)) }
 	}
-}
-
-struct LockedAvailRing<'a> {
-	_lh: ::kernel::sync::mutex::HeldMutex<'a, ()>,
-	ptr: *mut AvailRing,
-}
-impl<'a> ::core::ops::Deref for LockedAvailRing<'a> {
-	type Target = AvailRing;
-	// SAFE: Locked access
-	fn deref(&self) -> &AvailRing { unsafe { &*self.ptr }}
-}
-impl<'a> ::core::ops::DerefMut for LockedAvailRing<'a> {
-	// SAFE: Locked access
-	fn deref_mut(&mut self) -> &mut AvailRing { unsafe { &mut *self.ptr }}
-}
-impl AvailRing {
-	fn push(&mut self, val: u16) {
-		let count = self.ents.len();
-		self.ents[self.idx as usize % count] = val;
-		self.idx += 1;
-		//log_debug!("AvailRing = {:?}", self);
+	fn used_elem(&self, idx: usize) -> UsedElem {
+		assert!(idx < self.size);
+		// SAFE: Index checked, device-written so volatile
+		unsafe { read_volatile( (self.used_ptr().offset(2) as *const UsedElem).offset(idx as isize) ) }
 	}
-}
-
-impl UsedRing {
-	//pub fn used_event(&self) -> &u16 {
-	//}
-}
 
-struct LockedDescriptors<'a> {
-	_lh: ::kernel::sync::mutex::HeldMutex<'a, ()>,
-	slice: *mut [VRingDesc],
-}
-impl<'a> ::core::ops::Deref for LockedDescriptors<'a> {
-	type Target = [VRingDesc];
-	// SAFE: Uniquely controlled at this point
-	fn deref(&self) -> &[VRingDesc] { unsafe { &*self.slice } }
-}
-impl<'a> ::core::ops::DerefMut for LockedDescriptors<'a> {
-	// SAFE: Uniquely controlled at this point
-	fn deref_mut(&mut self) -> &mut [VRingDesc] { unsafe { &mut *self.slice } }
-}
-
-struct DescriptorHandle<'a>
-{
-	pd: ::core::marker::PhantomData<&'a [u8]>,
-	idx: u16,
+	/// Descriptor table
+	///
+	/// UNSAFE: Caller must only touch descriptors it owns (allocated, or free with the free list lock held)
+	unsafe fn descriptors(&self) -> &mut [VRingDesc] {
+		self.buffer.as_int_mut_slice(0, self.size)
+	}
+	/// Indirect table associated with the head descriptor `head`
+	///
+	/// UNSAFE: Caller must own `head`
+	unsafe fn indirect_table(&self, head: u16) -> &mut [VRingDesc] {
+		self.indirect_tables.as_ref().unwrap().as_int_mut_slice(head as usize * INDIRECT_TABLE_SIZE, INDIRECT_MAX)
+	}
 }
 
 pub struct Request<'a>
@@ -257,17 +397,19 @@ pub struct Request<'a>
 }
 impl<'a> Request<'a>
 {
+	/// Index of the first descriptor in this request (the `id` reported in the used ring)
+	pub fn id(&self) -> u16 {
+		self.first_desc
+	}
+
+	/// Wait for the device to complete this request (requires the driver to call `Queue::check_interrupt` in its interrupt handler)
 	pub fn wait_for_completion(&self) -> Result<usize,()> {
-		// XXX: HACK! No interrupts... yet
-		while self.queue.avail_ring_res[self.first_desc as usize].load(Ordering::Relaxed) == 0 {
-			self.queue.check_interrupt();
-		}
 		self.queue.interrupt_flag.acquire();
 		loop
 		{
 			let v = self.queue.avail_ring_res[self.first_desc as usize].swap(0, Ordering::Acquire);
 			if v != 0 {
-				return Ok(v);
+				return Ok(v & !RES_COMPLETE);
 			}
 			self.queue.interrupt_flag.release();
 			// HACK: Yield here to prevent this wait from instantly waking
@@ -279,17 +421,7 @@ impl<'a> Request<'a>
 impl<'a> ::core::ops::Drop for Request<'a>
 {
 	fn drop(&mut self) {
-		let mut d = self.queue.descriptors();
-		let mut idx = self.first_desc as usize;
-		loop
-		{
-			log_trace!("- Desc {}: Release", idx);
-			d[idx].length = 0;
-			if d[idx].flags & VRING_DESC_F_NEXT == 0 {
-				break ;
-			}
-			idx = d[idx].next as usize;
-		}
+		self.queue.avail_ring_res[self.first_desc as usize].store(0, Ordering::Relaxed);
+		self.queue.release_descriptors(self.first_desc);
 	}
 }
-
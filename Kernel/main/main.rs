@@ -42,6 +42,8 @@ pub extern "C" fn kmain()
 	
 	// Intialise the IRQ worker
 	::kernel::irqs::init();
+	// - and the timer wheel
+	::kernel::time::init();
 	
 	// Modules (dependency tree included)
 	// - Requests that the GUI be started as soon as possible
@@ -36,22 +36,26 @@ fn main()
 	loop
 	{
 		::syscalls::threads::wait(&mut waits, !0);
-		let mut idx = !0;
+		let mut idx = 0;
 		while idx < handles.len()
 		{
-			idx += 1;
 			let (buffer, _obj) = match handles[idx].channel.try_receive()
 				{
 				Ok(v) => v,
-				Err(::syscalls::ipc::RxError::NoMessage) => continue,
-				Err(::syscalls::ipc::RxError::ConnectionClosed) => {
+				Err(::syscalls::ipc::Error::NoMessage) => { idx += 1; continue },
+				Err(::syscalls::ipc::Error::ConnectionClosed) => {
 					kernel_log!("Connection '{}' dropped", handles[idx].name);
 					handles.swap_remove(idx);
-					idx -= 1;
+					continue
+					},
+				Err(e) => {
+					kernel_log!("Error receiving from '{}' - {:?}", handles[idx].name, e);
+					idx += 1;
 					continue
 					},
 				};
 			let conn = &handles[idx];
+			idx += 1;
 			match protocol::Request::try_from(buffer)
 			{
 			Ok(protocol::Request::CreateChild(req)) => {
@@ -64,17 +68,19 @@ fn main()
 					{
 					b"fileviewer" => b"/system/bin/fileviewer",
 					_ => {
-						conn.channel.send( protocol::RspError::new(0, "Unknown name").into() );
+						conn.send( protocol::RspError::new(0, "Unknown name").into() );
 						continue
 						},
 					};
 				match filesystem_root.open_child_path(path).and_then(|x| x.into_file(::syscalls::vfs::FileOpenMode::Execute))
 				{
 				Ok(fh) => {
-					conn.channel.send_obj( protocol::RspOpenedFile::new(path).into(), fh );
+					if let Err(e) = conn.channel.send_obj( protocol::RspOpenedFile::new(path).into(), fh ) {
+						kernel_log!("Failed to send response to '{}' - {:?}", conn.name, e);
+					}
 					},
 				Err(_) => {
-					conn.channel.send( protocol::RspError::new(0, "Could not open executable file").into() );
+					conn.send( protocol::RspError::new(0, "Could not open executable file").into() );
 					continue
 					},
 				}
@@ -86,13 +92,24 @@ fn main()
 				},
 			Err(protocol::UnmarshalError::BadValue) => {
 				kernel_log!("NOTICE: Malformed request from '{}' - {}", conn.name, buffer[0]);
-				conn.channel.send( protocol::RspError::new(0, "Bad request").into() );
+				conn.send( protocol::RspError::new(0, "Bad request").into() );
 				},
 			Err(protocol::UnmarshalError::UnknownRequest) => {
 				kernel_log!("NOTICE: Unknown request from '{}' - {}", conn.name, buffer[0]);
-				conn.channel.send( protocol::RspError::new(0, "Unknown request").into() );
+				conn.send( protocol::RspError::new(0, "Unknown request").into() );
 				},
 			}
 		}
+		// Connection list may have changed
+		waits = handles.iter().map(|x| x.channel.wait_rx()).collect();
+	}
+}
+
+impl Connection
+{
+	fn send(&self, msg: ::syscalls::ipc::RpcMessage) {
+		if let Err(e) = self.channel.send(msg) {
+			kernel_log!("Failed to send response to '{}' - {:?}", self.name, e);
+		}
 	}
 }
@@ -56,9 +56,9 @@ impl Connection
 {
 	/// Open a named executable
 	pub fn open_executable(&self, name: &str) -> Result< ::syscalls::vfs::File, OpenError > {
-		self.channel.send( protocol::ReqOpenExecutable::new(name).into() );
+		self.channel.send( protocol::ReqOpenExecutable::new(name).into() ).expect("Failed to send request to handle server");
 		::syscalls::threads::wait(&mut [ self.channel.wait_rx() ], !0);
-		let (rsp, obj) = self.channel.try_receive().unwrap();
+		let (rsp, obj) = self.channel.try_receive().expect("Failed to receive response from handle server");
 		match protocol::Response::try_from(rsp)
 		{
 		Ok(protocol::Response::OpenedFile(_v)) => {
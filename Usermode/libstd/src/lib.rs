@@ -16,6 +16,7 @@
 #![feature(alloc,allocator_api)]
 #![feature(allocator_internals)]
 #![feature(core_panic_info)]	// Needed because of import of `panic` macro bringin in the module too
+#![feature(asm)]	// Used to read the thread pointer
 #![default_lib_allocator]
 #![no_std]
 
@@ -123,3 +124,5 @@ pub mod os;
 
 pub mod heap;
 
+pub mod thread;
+
@@ -0,0 +1,142 @@
+//
+//
+//
+//! Native threads
+use core::cell::UnsafeCell;
+use boxed::Box;
+use vec::Vec;
+
+/// Size of the stack allocated for new threads
+const DEFAULT_STACK_SIZE: usize = 256 * 1024;
+/// Offset of the initial stack pointer from an aligned address (x86-64 enters as if a return address was pushed)
+#[cfg(arch="amd64")] const ENTRY_STACK_OFS: usize = 8;
+#[cfg(not(arch="amd64"))] const ENTRY_STACK_OFS: usize = 0;
+
+/// Handle used to wait for a thread to finish (the thread is detached if this is dropped)
+pub struct JoinHandle<T>
+{
+	thread: ::syscalls::threads::Thread,
+	/// Memory used by the running thread (leaked if the handle is dropped before the thread terminates)
+	resources: Option<ThreadResources<T>>,
+}
+struct ThreadResources<T>
+{
+	_stack: Vec<u8>,
+	_info: Box<ThreadData>,
+	packet: Box<Packet<T>>,
+}
+/// Storage for the thread's return value
+struct Packet<T>(UnsafeCell<Option<T>>);
+
+/// Per-thread data, pointed to by the thread pointer
+#[repr(C)]
+struct ThreadInfo<F>
+{
+	/// MUST be first (the x86-64 TLS ABI requires the thread pointer to point to itself)
+	self_ptr: *const ThreadInfo<F>,
+	fcn: Option<F>,
+}
+/// Allows the thread's data to be freed without knowing its type
+trait ThreadData {}
+impl<F> ThreadData for ThreadInfo<F> {}
+
+/// Spawn a new thread, returning a handle to wait for its result
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+	F: FnOnce() -> T + Send + 'static,
+	T: Send + 'static,
+{
+	let packet = Box::new( Packet(UnsafeCell::new(None)) );
+	// NOTE: Passed as usize so the closure is Send
+	let packet_ptr = &*packet as *const Packet<T> as usize;
+	let main = move || {
+		let rv = f();
+		// SAFE: The packet is not accessed by the handle until this thread has terminated
+		unsafe { *(*(packet_ptr as *const Packet<T>)).0.get() = Some(rv); }
+		};
+
+	let mut info = Box::new(ThreadInfo { self_ptr: 0 as *const _, fcn: Some(main) });
+	info.self_ptr = &*info;
+
+	let mut stack: Vec<u8> = Vec::with_capacity(DEFAULT_STACK_SIZE);
+	stack.resize(DEFAULT_STACK_SIZE, 0);
+	let stack_top = ((stack.as_ptr() as usize + stack.len()) & !15) - ENTRY_STACK_OFS;
+
+	// SAFE: The stack and thread info are kept until the thread terminates
+	let thread = match unsafe { start_native(&info, stack_top) }
+		{
+		Ok(v) => v,
+		Err(e) => panic!("Failed to start thread - {:#x}", e),
+		};
+
+	JoinHandle {
+		thread: thread,
+		resources: Some(ThreadResources { _stack: stack, _info: info, packet: packet }),
+	}
+}
+
+/// Start a thread running `info`'s closure
+///
+/// UNSAFE: Both `info` and the stack must outlive the thread
+unsafe fn start_native<F: FnOnce()>(info: &ThreadInfo<F>, stack_top: usize) -> Result<::syscalls::threads::Thread, u32>
+{
+	::syscalls::threads::start_thread(thread_start::<F> as usize, stack_top, info as *const _ as usize)
+}
+
+/// Entrypoint for new threads
+extern "C" fn thread_start<F: FnOnce()>() -> !
+{
+	// SAFE: The thread pointer was set to this thread's info by `spawn`, and only this thread accesses it
+	let info = unsafe { &mut *(get_thread_pointer() as *mut ThreadInfo<F>) };
+	let fcn = info.fcn.take().expect("thread_start - Closure already taken");
+	fcn();
+	::syscalls::threads::exit_thread();
+}
+
+#[cfg(arch="amd64")]
+fn get_thread_pointer() -> usize {
+	let rv: usize;
+	// SAFE: Reads the self-pointer at the start of the TLS block
+	unsafe { asm!("mov %fs:0, $0" : "=r" (rv)); }
+	rv
+}
+#[cfg(arch="armv7")]
+fn get_thread_pointer() -> usize {
+	let rv: usize;
+	// SAFE: Reads the user read-only thread ID register
+	unsafe { asm!("mrc p15,0, $0, c13,c0,3" : "=r" (rv)); }
+	rv
+}
+#[cfg(arch="armv8")]
+fn get_thread_pointer() -> usize {
+	let rv: usize;
+	// SAFE: Reads the user thread pointer register
+	unsafe { asm!("mrs $0, TPIDR_EL0" : "=r" (rv)); }
+	rv
+}
+
+impl<T> JoinHandle<T>
+{
+	/// Wait for the thread to terminate, returning the closure's result
+	pub fn join(mut self) -> Result<T, ()>
+	{
+		let mut waits = [self.thread.wait_terminate()];
+		while ::syscalls::threads::wait(&mut waits, !0) == 0 {
+		}
+		let res = self.resources.take().expect("JoinHandle::join - No resources");
+		// SAFE: Thread has terminated, so nothing else accesses the packet
+		let rv = unsafe { (*res.packet.0.get()).take() };
+		// `None` indicates that the thread didn't return (e.g. it called `exit_thread`)
+		rv.ok_or( () )
+	}
+}
+impl<T> Drop for JoinHandle<T>
+{
+	fn drop(&mut self)
+	{
+		// The thread may still be running, so its stack and data can't be freed
+		if let Some(res) = self.resources.take() {
+			::core::mem::forget(res);
+		}
+	}
+}
@@ -37,12 +37,66 @@ enum ErrorInner
 	VFS(::syscalls::vfs::Error),
 }
 
+/// General categories of IO error
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum ErrorKind
+{
+	NotFound,
+	PermissionDenied,
+	AlreadyExists,
+	WouldBlock,
+	InvalidInput,
+	InvalidData,
+	WriteZero,
+	Interrupted,
+	NotADirectory,
+	DirectoryNotEmpty,
+	ReadOnlyFilesystem,
+	FilesystemLoop,
+	StorageFull,
+	OutOfMemory,
+	Other,
+}
+
 impl_conv! {
 	From<::syscalls::vfs::Error>(v) for Error {
 		Error( ErrorInner::VFS(v) )
 	}
 }
 
+impl Error
+{
+	/// Obtain the category of this error
+	pub fn kind(&self) -> ErrorKind {
+		use syscalls::vfs::Error as VfsError;
+		match self.0
+		{
+		ErrorInner::Misc => ErrorKind::Other,
+		ErrorInner::VFS(ref e) =>
+			match *e
+			{
+			VfsError::FileNotFound => ErrorKind::NotFound,
+			VfsError::TypeError => ErrorKind::InvalidInput,
+			VfsError::PermissionDenied => ErrorKind::PermissionDenied,
+			VfsError::FileLocked => ErrorKind::WouldBlock,
+			VfsError::MalformedPath => ErrorKind::InvalidInput,
+			VfsError::AlreadyExists => ErrorKind::AlreadyExists,
+			VfsError::NotEmpty => ErrorKind::DirectoryNotEmpty,
+			VfsError::InvalidParameter => ErrorKind::InvalidInput,
+			VfsError::NotADirectory => ErrorKind::NotADirectory,
+			VfsError::RecursionLimit => ErrorKind::FilesystemLoop,
+			VfsError::IoError => ErrorKind::Other,
+			VfsError::ReadOnly => ErrorKind::ReadOnlyFilesystem,
+			VfsError::Inconsistent => ErrorKind::InvalidData,
+			VfsError::OutOfSpace => ErrorKind::StorageFull,
+			VfsError::OutOfMemory => ErrorKind::OutOfMemory,
+			VfsError::Transient => ErrorKind::Interrupted,
+			VfsError::Unknown => ErrorKind::Other,
+			},
+		}
+	}
+}
+
 pub trait Read
 {
 	fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
@@ -65,7 +119,7 @@ pub trait Write
 			match self.write(buf) {
 			Ok(0) => return Err(Error(ErrorInner::Misc)/*::new(ErrorKind::WriteZero, "failed to write whole buffer")*/),
 			Ok(n) => buf = &buf[n..],
-			//Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+			Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
 			Err(e) => return Err(e),
 			}
 		}
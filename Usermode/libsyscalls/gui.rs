@@ -42,9 +42,8 @@ impl Group
 		match super::ObjectHandle::new( unsafe { syscall!(GUI_NEWGROUP, name.as_ptr() as usize, name.len()) } as usize )
 		{
 		Ok(rv) => Ok( Group(rv) ),
-		Err(code) => {
-			panic!("TODO: Error code {}", code);
-			},
+		// Process lacks the session capability
+		Err(_) => Err( () ),
 		}
 	}
 	
@@ -3,6 +3,7 @@
 //
 //! Inter-process communication
 pub use values::RpcMessage;
+pub use values::RpcError as Error;
 
 pub struct RpcChannel(::ObjectHandle);
 
@@ -22,7 +23,7 @@ impl ::Object for RpcChannel
 
 	type Waits = RpcChannelWaits;
 	fn get_wait(&self, waits: Self::Waits) -> ::values::WaitItem {
-		::values::WaitItem { object: 0, flags: waits.0 }
+		::values::WaitItem { object: self.0 .0, flags: waits.0 }
 	}
 	fn check_wait(&self, wi: &::values::WaitItem) -> Self::Waits {
 		RpcChannelWaits(wi.flags)
@@ -30,7 +31,12 @@ impl ::Object for RpcChannel
 }
 define_waits!{ RpcChannelWaits => (
 	rx:has_rx = ::values::EV_IPC_RPC_RECV,
+	closed:is_closed = ::values::EV_IPC_RPC_CLOSED,
 )}
+
+fn to_result(val: usize) -> Result<u32, Error> {
+	::to_result(val).map_err(|e| if e < 0xFF { Error::try_from(e as u8).unwrap_or(Error::Unknown) } else { Error::Unknown })
+}
 impl RpcChannel
 {
 	pub fn new_pair() -> Result< (RpcChannel, RpcChannel), NewError > {
@@ -47,36 +53,39 @@ impl RpcChannel
 		}
 	}
 
-	pub fn send(&self, message: RpcMessage) {
+	/// Send a message to the other end (fails if the other end's queue is full, or it has been closed)
+	pub fn send(&self, message: RpcMessage) -> Result<(), Error> {
 		// SAFE: Syscall
-		unsafe { self.0.call_2(::values::IPC_RPC_SEND, &message as *const _ as usize, 0); }
+		to_result( unsafe { self.0.call_2(::values::IPC_RPC_SEND, &message as *const _ as usize, 0) as usize } )
+			.map(|_| ())
 	}
-	pub fn send_obj<T: ::Object>(&self, message: RpcMessage, object: T) {
+	/// Send a message along with an object (the object is dropped if the send fails)
+	pub fn send_obj<T: ::Object>(&self, message: RpcMessage, object: T) -> Result<(), Error> {
+		let handle = object.into_handle().into_raw();
 		// SAFE: Syscall
-		unsafe { self.0.call_2(::values::IPC_RPC_SEND, &message as *const _ as usize, object.into_handle().into_raw() as usize); }
+		let rv = to_result( unsafe { self.0.call_2(::values::IPC_RPC_SEND, &message as *const _ as usize, handle as usize) as usize } );
+		if rv.is_err() {
+			// The kernel only takes the object on success
+			drop( ::ObjectHandle(handle) );
+		}
+		rv.map(|_| ())
 	}
-	pub fn try_receive(&self) -> Result< (RpcMessage, Option<::AnyObject>), RxError> {
+	/// Receive a message (and any attached object) without blocking
+	pub fn try_receive(&self) -> Result< (RpcMessage, Option<::AnyObject>), Error> {
 		let mut msg: RpcMessage = Default::default();
 		// SAFE: Syscall
-		let rv = unsafe { self.0.call_1(::values::IPC_RPC_RECV, &mut msg as *mut _ as usize) };
-		if rv < 0x1000 {
-			Ok( (msg, if rv > 0 { Some(::AnyObject(::ObjectHandle(rv as u32))) } else { None }) )
-		}
-		else {
-			unimplemented!()
-		}
+		let rv = try!(to_result( unsafe { self.0.call_1(::values::IPC_RPC_RECV, &mut msg as *mut _ as usize) as usize } ));
+		Ok( (msg, if rv > 0 { Some(::AnyObject(::ObjectHandle(rv))) } else { None }) )
 	}
 
+	/// Wait item for a message being available (also fires if the other end is closed)
 	pub fn wait_rx(&self) -> ::WaitItem {
 		::values::WaitItem { object: self.0 .0, flags: ::values::EV_IPC_RPC_RECV }
 	}
-}
-
-#[derive(Debug)]
-pub enum RxError
-{
-	NoMessage,
-	ConnectionClosed,
+	/// Wait item for the other end being closed
+	pub fn wait_closed(&self) -> ::WaitItem {
+		::values::WaitItem { object: self.0 .0, flags: ::values::EV_IPC_RPC_CLOSED }
+	}
 }
 
 #[derive(Debug)]
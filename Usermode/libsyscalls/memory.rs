@@ -34,3 +34,47 @@ pub unsafe fn deallocate(addr: usize) -> Result<(), Error> {
 		.map_err(|_| Error)
 }
 
+
+pub use values::MemoryError as MapError;
+
+/// Shared memory region, clone the handle (and send the clone) to share it with another process
+pub struct SharedMemory(::ObjectHandle);
+impl ::Object for SharedMemory {
+	const CLASS: u16 = ::values::CLASS_MEM_SHARED;
+	fn class() -> u16 { Self::CLASS }
+	fn from_handle(handle: ::ObjectHandle) -> Self {
+		SharedMemory(handle)
+	}
+	fn into_handle(self) -> ::ObjectHandle { self.0 }
+	fn handle(&self) -> &::ObjectHandle { &self.0 }
+
+	type Waits = ();
+}
+impl SharedMemory
+{
+	/// Create a new zero-filled region of at least `size` bytes
+	pub fn new(size: usize) -> Result<SharedMemory, Error> {
+		// SAFE: Syscall
+		::ObjectHandle::new( unsafe { syscall!(MEM_SHM_CREATE, size) } as usize )
+			.map(|h| SharedMemory(h))
+			.map_err(|_| Error)
+	}
+	/// Obtain a second handle to the same region
+	pub fn try_clone(&self) -> Result<SharedMemory, Error> {
+		self.0.try_clone()
+			.map(|h| SharedMemory(h))
+			.map_err(|_| Error)
+	}
+
+	/// Size of the region in bytes
+	pub fn size(&self) -> usize {
+		// SAFE: Syscall
+		unsafe { self.0.call_0(::values::MEM_SHM_GETSIZE) as usize }
+	}
+	/// Map the region at `addr` (page aligned), unmap each page using `deallocate`
+	pub unsafe fn map(&self, addr: usize, protection: ProtectionMode) -> Result<(), MapError> {
+		super::to_result( self.0.call_2(::values::MEM_SHM_MAP, addr, protection as u8 as usize) as usize )
+			.map(|_| ())
+			.map_err(|e| MapError::try_from(e as u8).unwrap())
+	}
+}
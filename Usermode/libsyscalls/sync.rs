@@ -66,6 +66,7 @@ impl<'a, T: 'a> ops::Drop for HeldMutex<'a, T>
 	}
 }
 
+/// Sleep until woken by `futex_wake`, if `addr` still holds `sleep_if_val`
 pub fn futex_wait(addr: &AtomicUsize, sleep_if_val: usize)
 {
 	// SAFE: Assumed
@@ -73,6 +74,7 @@ pub fn futex_wait(addr: &AtomicUsize, sleep_if_val: usize)
 		syscall!(CORE_FUTEX_SLEEP, addr as *const _ as usize, sleep_if_val);
 	}
 }
+/// Wake up to `num_to_wake` threads sleeping on `addr`
 pub fn futex_wake(addr: &AtomicUsize, num_to_wake: usize)
 {
 	// SAFE: Assumed
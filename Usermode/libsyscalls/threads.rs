@@ -3,6 +3,10 @@
 //
 //! Thread management system calls
 
+pub use values::{CAP_GUI_SESSION, CAP_NET_RAW, CAP_SET_CREDENTIALS, CAP_ALL};
+pub use values::{FS_RIGHT_READ, FS_RIGHT_WRITE};
+pub use values::ProcessError;
+
 #[derive(Debug)]
 pub enum RecvObjectError
 {
@@ -19,9 +23,12 @@ impl ::core::fmt::Display for RecvObjectError {
 	}
 }
 
+/// Start a new thread in this process
+///
+/// UNSAFE: `sp` and `tlsbase` must point to memory that stays valid for the life of the thread
 #[inline]
-pub unsafe fn start_thread(ip: usize, sp: usize, tlsbase: usize) -> Result<u32, u32> {
-	::to_result( syscall!(CORE_STARTTHREAD, ip, sp, tlsbase) as usize )
+pub unsafe fn start_thread(ip: usize, sp: usize, tlsbase: usize) -> Result<Thread, u32> {
+	::ObjectHandle::new( syscall!(CORE_STARTTHREAD, ip, sp, tlsbase) as usize ).map( |h| Thread(h) )
 }
 #[inline]
 pub fn exit_thread() -> ! {
@@ -82,6 +89,14 @@ impl ::Object for ThisProcess {
 }
 
 
+/// Obtain the user and group IDs of the current process
+#[inline]
+pub fn get_credentials() -> (u32, u32) {
+	// SAFE: Syscall with no side-effects
+	let v = unsafe { syscall!(CORE_GETCREDENTIALS) };
+	(v as u32, (v >> 32) as u32)
+}
+
 #[inline]
 pub fn start_process(name: &str,  clone_start: usize, clone_end: usize) -> Result<ProtoProcess,()> {
 	// SAFE: Syscall
@@ -119,6 +134,37 @@ impl ProtoProcess
 		// SAFE: Syscall
 		unsafe { self.0.call_2l(::values::CORE_PROTOPROCESS_SENDOBJ, ::values::FixedStr8::from(tag).into(), oh as usize); }
 	}
+
+	/// Remove capabilities (`CAP_*` flags) from the child process
+	#[inline]
+	pub fn drop_capabilities(&self, flags: u32) {
+		// SAFE: Syscall
+		unsafe { self.0.call_1(::values::CORE_PROTOPROCESS_DROPCAPS, flags as usize); }
+	}
+	/// Limit the network ports the child process can use to `first` ..= `last`
+	#[inline]
+	pub fn limit_ports(&self, first: u16, last: u16) {
+		// SAFE: Syscall
+		unsafe { self.0.call_2(::values::CORE_PROTOPROCESS_LIMITPORTS, first as usize, last as usize); }
+	}
+	/// Set the user and group IDs of the child process (requires the `CAP_SET_CREDENTIALS` capability)
+	#[inline]
+	pub fn set_credentials(&self, uid: u32, gid: u32) -> Result<(),ProcessError> {
+		// SAFE: Syscall
+		match ::to_result( unsafe { self.0.call_2(::values::CORE_PROTOPROCESS_SETCREDS, uid as usize, gid as usize) } as usize )
+		{
+		Ok(_) => Ok( () ),
+		Err(e) => Err( ProcessError::try_from(e as u8).unwrap_or(ProcessError::PermissionDenied) ),
+		}
+	}
+	/// Limit the child's filesystem access to the passed (absolute) subtree, with at most the passed `FS_RIGHT_*` rights
+	///
+	/// The first call replaces the inherited set of subtrees, later calls add to it.
+	#[inline]
+	pub fn limit_path(&self, path: &[u8], rights: u8) {
+		// SAFE: Syscall
+		unsafe { self.0.call_3(::values::CORE_PROTOPROCESS_LIMITPATH, path.as_ptr() as usize, path.len(), rights as usize); }
+	}
  
  	#[inline]
 	pub fn start(self, entry: usize, stack: usize) -> Process {
@@ -143,6 +189,17 @@ impl Process {
 	pub fn wait_terminate(&self) -> ::values::WaitItem {
 		self.0.get_wait(::values::EV_PROCESS_TERMINATED)
 	}
+
+	/// Exit status of the process, `None` if it's still running
+	#[inline]
+	pub fn exit_status(&self) -> Option<u32> {
+		// SAFE: Syscall
+		match unsafe { self.0.call_0(::values::CORE_PROCESS_GETEXIT) }
+		{
+		0xFFFF_FFFF_FFFF_FFFF => None,
+		v => Some(v as u32),
+		}
+	}
 }
 impl ::Object for Process {
 	const CLASS: u16 = ::values::CLASS_CORE_PROCESS;
@@ -156,6 +213,29 @@ impl ::Object for Process {
 	type Waits = ProcessWaits;
 }
 
+define_waits!{ ThreadWaits => (
+	terminate:get_terminate = ::values::EV_THREAD_TERMINATED,
+)}
+/// Handle to a thread in this process
+pub struct Thread(::ObjectHandle);
+impl Thread {
+	#[inline]
+	pub fn wait_terminate(&self) -> ::values::WaitItem {
+		self.0.get_wait(::values::EV_THREAD_TERMINATED)
+	}
+}
+impl ::Object for Thread {
+	const CLASS: u16 = ::values::CLASS_CORE_THREAD;
+	fn class() -> u16 { Self::CLASS }
+	fn from_handle(handle: ::ObjectHandle) -> Self {
+		Thread(handle)
+	}
+	fn into_handle(self) -> ::ObjectHandle { self.0 }
+	fn handle(&self) -> &::ObjectHandle { &self.0 }
+	
+	type Waits = ThreadWaits;
+}
+
 #[inline]
 pub fn exit(code: u32) -> ! {
 	// SAFE: Syscall
@@ -132,7 +132,6 @@ fn load_binary(path: &::std::ffi::OsStr, fh: ::syscalls::vfs::File) -> usize
 		while let Some(segment) = segments_it.next()
 		{
 			use syscalls::vfs::MemoryMapMode;
-			use syscalls::memory::ProtectionMode;
 			kernel_log!("segment = {:?}", segment);
 			
 			if segment.load_addr <= entrypoint && entrypoint < segment.load_addr + segment.mem_size {
@@ -140,49 +139,25 @@ fn load_binary(path: &::std::ffi::OsStr, fh: ::syscalls::vfs::File) -> usize
 			}
 			
 			assert!(segment.file_size <= segment.mem_size);
-			// Split the segment into three regions: (reverse)
-			// - Page-aligned resident data
-			// - Non-resident data
-			// - Tailing resident data
-			let extra = segment.mem_size - segment.file_size;
-			let tail    = segment.file_size % PAGE_SIZE;
-			let aligned = segment.file_size - tail;
+			// Split the segment into two regions:
+			// - File data, mapped directly (pages are read on first access, and the kernel zero-fills the rest of the last page)
+			// - Non-resident data, allocated as zero-filled memory
+			let file_pages = (segment.file_size + PAGE_SIZE-1) / PAGE_SIZE;
+			let mem_pages = (segment.mem_size + PAGE_SIZE-1) / PAGE_SIZE;
 			let map_mode = match segment.protection
 				{
 				::load::SegmentProt::Execute   => MemoryMapMode::Execute,
 				::load::SegmentProt::ReadWrite => MemoryMapMode::COW,
 				::load::SegmentProt::ReadOnly  => MemoryMapMode::ReadOnly,
 				};
-			let alloc_mode = match segment.protection
-				{
-				::load::SegmentProt::Execute   => ProtectionMode::Executable,
-				::load::SegmentProt::ReadWrite => ProtectionMode::ReadWrite,	// Allocates as read-write
-				::load::SegmentProt::ReadOnly  => ProtectionMode::ReadOnly,
-				};
 			let fp = segments_it.get_file();
-			if aligned > 0 {
-				let mm = fp.memory_map(segment.file_addr, aligned, segment.load_addr as *mut _, map_mode);
-				::std::mem::forget(mm);
-			}
-			if tail > 0 {
-				// SAFE: Trusing addresses to be valid
-				unsafe {
-					let destslice = ::std::slice::from_raw_parts_mut((segment.load_addr + aligned) as *mut u8, tail);
-					// - Allocate space
-					::syscalls::memory::allocate(destslice.as_ptr() as usize, 1).expect("tail alloc");
-					// - Read data
-					fp.read_at(segment.file_addr + aligned as u64, destslice).expect("Failure reading file data for end of .segment");
-					// - Reprotect to the real mode, not bothering if the desired is Read-Write
-					if alloc_mode != ProtectionMode::ReadWrite {
-						::syscalls::memory::reprotect(destslice.as_ptr() as usize, alloc_mode).expect("reprotect");
-					}
-				}
+			if segment.file_size > 0 {
+				fp.memory_map(segment.file_addr, segment.file_size, segment.load_addr as *mut _, map_mode).expect("Failure mapping segment");
 			}
-			if extra > PAGE_SIZE - tail {
-				let addr = segment.load_addr + aligned + PAGE_SIZE;
-				let pages = (extra - (PAGE_SIZE - tail) + PAGE_SIZE-1) / PAGE_SIZE;
+			if mem_pages > file_pages {
+				let addr = segment.load_addr + file_pages * PAGE_SIZE;
 				// SAFE: Just allocating at a known free place
-				unsafe { ::syscalls::memory::allocate(addr, pages).expect("extra alloc"); }
+				unsafe { ::syscalls::memory::allocate(addr, mem_pages - file_pages).expect("extra alloc"); }
 			}
 		}
 	}
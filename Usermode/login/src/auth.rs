@@ -1,4 +1,22 @@
+// Tifflin OS - login
+// - By John Hodge (thePowersGang)
+//
+// auth.rs
+//! User authentication against the passwd and shadow files
+//!
+//! `/sysroot/etc/passwd` - One account per line: `name:x:uid:gid:comment:home:shell`
+//! `/sysroot/etc/shadow` - One account per line: `name:$pbkdf2-sha256$<rounds>$<salt>$<hash>`
+//!  - `salt` and `hash` are hex-encoded, `hash` is PBKDF2-HMAC-SHA-256 of the password with `rounds` iterations
+//!  - A hash field starting with `!` or `*` marks a disabled account
+//!  - Only readable by the login process (session processes have it removed from their path rights)
 
+const PASSWD_PATH: &'static [u8] = b"/sysroot/etc/passwd";
+pub const SHADOW_PATH: &'static [u8] = b"/sysroot/etc/shadow";
+
+/// Fewest PBKDF2 rounds accepted for a stored hash
+const MIN_ROUNDS: u32 = 1000;
+
+#[derive(Debug)]
 pub enum Error
 {
 	InvalidAuthentication,
@@ -7,18 +25,63 @@ pub enum Error
 
 pub struct UserInfo
 {
+	pub uid: u32,
+	pub gid: u32,
+	home: String,
+	shell: String,
 }
 
 pub fn try_login(username: &str, password: &str) -> Result<UserInfo, Error>
 {
 	// TODO: Use a proper auth infrastructure, something PAM-esque
-	if username == "root" && password == "password"
-	{
-		Ok(UserInfo {})
+	let shadow = match read_file(SHADOW_PATH)
+		{
+		Ok(v) => v,
+		Err(e) => {
+			kernel_log!("Unable to read shadow file - {:?}", e);
+			return Err(Error::InvalidAuthentication);
+			},
+		};
+	let hash_field = match find_entry(&shadow, username).and_then(|f| f.get(1).cloned())
+		{
+		Some(v) => v,
+		None => return Err(Error::InvalidAuthentication),
+		};
+	if hash_field.starts_with(b"!") || hash_field.starts_with(b"*") {
+		return Err(Error::Disabled);
+	}
+	if !check_password(hash_field, password) {
+		return Err(Error::InvalidAuthentication);
 	}
-	else
+
+	let passwd = match read_file(PASSWD_PATH)
+		{
+		Ok(v) => v,
+		Err(e) => {
+			kernel_log!("Unable to read passwd file - {:?}", e);
+			return Err(Error::InvalidAuthentication);
+			},
+		};
+	let fields = match find_entry(&passwd, username)
+		{
+		Some(ref f) if f.len() == 7 => f.clone(),
+		_ => {
+			kernel_log!("No valid passwd entry for '{}'", username);
+			return Err(Error::InvalidAuthentication);
+			},
+		};
+	match ( parse_int(fields[2]), parse_int(fields[3]), ::std::str::from_utf8(fields[5]), ::std::str::from_utf8(fields[6]) )
 	{
+	(Some(uid), Some(gid), Ok(home), Ok(shell)) => Ok(UserInfo {
+		uid: uid,
+		gid: gid,
+		home: String::from(home),
+		shell: String::from(shell),
+		}),
+	_ => {
+		kernel_log!("Malformed passwd entry for '{}'", username);
 		Err(Error::InvalidAuthentication)
+		},
 	}
 }
 
@@ -27,7 +90,97 @@ impl UserInfo
 {
 	pub fn get_shell(&self) -> &str
 	{
-		"/sysroot/bin/shell"
+		&self.shell
+	}
+	pub fn get_home(&self) -> &str
+	{
+		&self.home
+	}
+}
+
+fn read_file(path: &[u8]) -> Result<Vec<u8>, ::syscalls::vfs::Error>
+{
+	let file = try!( try!(::syscalls::vfs::ROOT.open_child_path(path)).into_file(::syscalls::vfs::FileOpenMode::ReadOnly) );
+	let mut data: Vec<u8> = (0 .. file.get_size()).map(|_| 0u8).collect();
+	let len = try!(file.read_at(0, &mut data));
+	data.truncate(len);
+	Ok(data)
+}
+
+/// Locate the line for the named user, returning its `:` separated fields
+fn find_entry<'a>(data: &'a [u8], username: &str) -> Option<Vec<&'a [u8]>>
+{
+	for line in data.split(|&b| b == b'\n')
+	{
+		let line = if line.ends_with(b"\r") { &line[..line.len()-1] } else { line };
+		if line.is_empty() || line[0] == b'#' {
+			continue ;
+		}
+		let fields: Vec<&[u8]> = line.split(|&b| b == b':').collect();
+		if fields[0] == username.as_bytes() {
+			return Some(fields);
+		}
+	}
+	None
+}
+
+/// Check a password against a `$pbkdf2-sha256$<rounds>$<salt>$<hash>` field
+fn check_password(hash_field: &[u8], password: &str) -> bool
+{
+	let parts: Vec<&[u8]> = hash_field.split(|&b| b == b'$').collect();
+	if parts.len() != 5 || !parts[0].is_empty() || parts[1] != &b"pbkdf2-sha256"[..] {
+		kernel_log!("Unsupported password hash format");
+		return false;
+	}
+	let (rounds, salt, expected) = match (parse_int(parts[2]), decode_hex(parts[3]), decode_hex(parts[4]))
+		{
+		(Some(r), Some(s), Some(h)) => (r, s, h),
+		_ => return false,
+		};
+	if rounds < MIN_ROUNDS {
+		kernel_log!("Password hash has too few rounds ({})", rounds);
+		return false;
+	}
+
+	let digest = ::sha256::pbkdf2(password.as_bytes(), &salt, rounds);
+
+	// Compare without an early exit
+	expected.len() == digest.len() && expected.iter().zip(digest.iter()).fold(0, |acc, (a,b)| acc | (a ^ b)) == 0
+}
+
+fn decode_hex(s: &[u8]) -> Option<Vec<u8>>
+{
+	fn nibble(c: u8) -> Option<u8> {
+		match c
+		{
+		b'0' ... b'9' => Some(c - b'0'),
+		b'a' ... b'f' => Some(c - b'a' + 10),
+		b'A' ... b'F' => Some(c - b'A' + 10),
+		_ => None,
+		}
 	}
+	if s.len() % 2 != 0 {
+		return None;
+	}
+	s.chunks(2).map(|p| match (nibble(p[0]), nibble(p[1])) { (Some(h), Some(l)) => Some(h << 4 | l), _ => None }).collect()
 }
 
+fn parse_int(s: &[u8]) -> Option<u32>
+{
+	if s.is_empty() {
+		return None;
+	}
+	let mut rv: u32 = 0;
+	for &c in s
+	{
+		if c < b'0' || c > b'9' {
+			return None;
+		}
+		rv = match rv.checked_mul(10).and_then(|v| v.checked_add((c - b'0') as u32))
+			{
+			Some(v) => v,
+			None => return None,
+			};
+	}
+	Some(rv)
+}
@@ -18,6 +18,7 @@ macro_rules! imgpath {
 }
 
 mod auth;
+mod sha256;
 
 static VFS_ROOT: LazyStatic< ::syscalls::vfs::Dir > = LazyStatic::new();
 
@@ -123,13 +124,13 @@ fn main()
 
 fn try_login(username: &str, password: &str) -> Result<(), &'static str>
 {
-	kernel_log!("username = \"{}\", password = \"{}\"", username, password);
+	kernel_log!("username = \"{}\"", username);
 	match auth::try_login(username, password)
 	{
 	Ok(i) => {
 		// Spawn console, and wait for it to terminate
 		// - This also spawns the handle server for the session
-		spawn_console_and_wait( i.get_shell() );
+		spawn_console_and_wait( &i );
 		Ok( () )
 		},
 	Err(auth::Error::InvalidAuthentication) => Err("Invalid username or password"),
@@ -145,8 +146,22 @@ fn open_exe(path: &str) -> Result<::syscalls::vfs::File, ::syscalls::vfs::Error>
 	}
 }
 
-fn spawn_console_and_wait(path: &str)
+/// Capabilities that the user's processes don't get
+const SESSION_DROPPED_CAPS: u32 = ::syscalls::threads::CAP_SET_CREDENTIALS | ::syscalls::threads::CAP_GUI_SESSION;
+
+/// Apply the user's identity and the session restrictions to a new process
+fn restrict_session_process(pp: &::syscalls::threads::ProtoProcess, user: &auth::UserInfo)
+{
+	pp.set_credentials(user.uid, user.gid).expect("Could not set session process credentials");
+	pp.drop_capabilities(SESSION_DROPPED_CAPS);
+	// Password hashes are only for the login process
+	pp.limit_path(b"/", ::syscalls::threads::FS_RIGHT_READ | ::syscalls::threads::FS_RIGHT_WRITE);
+	pp.limit_path(auth::SHADOW_PATH, 0);
+}
+
+fn spawn_console_and_wait(user: &auth::UserInfo)
 {
+	let path = user.get_shell();
 	let (hs_svr_chan, hs_clt_chan) = ::syscalls::ipc::RpcChannel::new_pair().expect("Coudn't create new RPC Channel");
 
 	// Spawn a session leader handled server
@@ -154,6 +169,7 @@ fn spawn_console_and_wait(path: &str)
 		let path = "/sysroot/bin/handle_server";
 		let fh = open_exe(path).unwrap_or_else(|e| panic!("Couldn't open handle server - {:?}", e));
 		let pp = loader::new_process(fh, path.as_bytes(), &[]).expect("Could not spawn handle server");
+		restrict_session_process(&pp, user);
 		pp.send_obj( "RwRoot", VFS_ROOT.clone() );
 		pp.send_obj( "HsChan", hs_svr_chan );
 		pp.start()
@@ -166,6 +182,7 @@ fn spawn_console_and_wait(path: &str)
 			Err(e) => panic!("Couldn't open executable '{}' - {:?}", path, e),
 			};
 		let pp = loader::new_process(fh, path.as_bytes(), &[]).expect("Could not spawn shell");
+		restrict_session_process(&pp, user);
 		pp.send_obj( "guigrp", ::syscalls::gui::clone_group_handle() );
 		pp.send_obj( "HsChan", hs_clt_chan );
 		pp.start()
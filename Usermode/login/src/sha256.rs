@@ -0,0 +1,186 @@
+// Tifflin OS - login
+// - By John Hodge (thePowersGang)
+//
+// sha256.rs
+//! SHA-256 hash, and PBKDF2-HMAC-SHA-256 (used for password hashes)
+
+const K: [u32; 64] = [
+	0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+	0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+	0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+	0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+	0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+	0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+	0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+	0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+	];
+
+/// Incremental SHA-256 state
+#[derive(Clone)]
+pub struct Sha256
+{
+	state: [u32; 8],
+	block: [u8; 64],
+	block_len: usize,
+	total_len: u64,
+}
+
+impl Sha256
+{
+	pub fn new() -> Sha256 {
+		Sha256 {
+			state: [
+				0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+				0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+				],
+			block: [0; 64],
+			block_len: 0,
+			total_len: 0,
+		}
+	}
+
+	/// Add data to the hash
+	pub fn update(&mut self, mut data: &[u8]) {
+		self.total_len += data.len() as u64;
+		while data.len() > 0
+		{
+			let len = ::std::cmp::min(64 - self.block_len, data.len());
+			self.block[self.block_len ..][..len].copy_from_slice(&data[..len]);
+			self.block_len += len;
+			data = &data[len..];
+			if self.block_len == 64 {
+				self.process_block();
+			}
+		}
+	}
+
+	/// Complete the hash and return the digest
+	pub fn finish(mut self) -> [u8; 32] {
+		let bit_len = self.total_len * 8;
+		// Padding: a single 1 bit, zeroes, then the 64-bit big-endian message length
+		self.block[self.block_len] = 0x80;
+		self.block_len += 1;
+		if self.block_len > 56 {
+			for b in self.block[self.block_len..].iter_mut() {
+				*b = 0;
+			}
+			self.process_block();
+		}
+		for b in self.block[self.block_len .. 56].iter_mut() {
+			*b = 0;
+		}
+		for i in 0 .. 8 {
+			self.block[56 + i] = (bit_len >> (56 - i*8)) as u8;
+		}
+		self.process_block();
+
+		let mut rv = [0; 32];
+		for (i, w) in self.state.iter().enumerate() {
+			rv[i*4 + 0] = (w >> 24) as u8;
+			rv[i*4 + 1] = (w >> 16) as u8;
+			rv[i*4 + 2] = (w >>  8) as u8;
+			rv[i*4 + 3] = (w >>  0) as u8;
+		}
+		rv
+	}
+
+	fn process_block(&mut self) {
+		let mut w = [0u32; 64];
+		for i in 0 .. 16 {
+			let b = &self.block[i*4 ..][..4];
+			w[i] = (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | (b[3] as u32);
+		}
+		for i in 16 .. 64 {
+			let s0 = w[i-15].rotate_right(7) ^ w[i-15].rotate_right(18) ^ (w[i-15] >> 3);
+			let s1 = w[i-2].rotate_right(17) ^ w[i-2].rotate_right(19) ^ (w[i-2] >> 10);
+			w[i] = w[i-16].wrapping_add(s0).wrapping_add(w[i-7]).wrapping_add(s1);
+		}
+
+		let mut v = self.state;
+		for i in 0 .. 64 {
+			let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+			let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+			let t1 = v[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+			let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+			let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+			let t2 = s0.wrapping_add(maj);
+			v[7] = v[6];
+			v[6] = v[5];
+			v[5] = v[4];
+			v[4] = v[3].wrapping_add(t1);
+			v[3] = v[2];
+			v[2] = v[1];
+			v[1] = v[0];
+			v[0] = t1.wrapping_add(t2);
+		}
+		for (s, v) in self.state.iter_mut().zip(v.iter()) {
+			*s = s.wrapping_add(*v);
+		}
+		self.block_len = 0;
+	}
+}
+
+/// HMAC-SHA-256 keyed state (the key-derived first blocks are hashed once)
+struct Hmac
+{
+	inner: Sha256,
+	outer: Sha256,
+}
+impl Hmac
+{
+	fn new(key: &[u8]) -> Hmac {
+		// Keys longer than a block are hashed first
+		let mut key_block = [0u8; 64];
+		if key.len() > 64 {
+			let mut h = Sha256::new();
+			h.update(key);
+			key_block[..32].copy_from_slice(&h.finish());
+		}
+		else {
+			key_block[..key.len()].copy_from_slice(key);
+		}
+
+		let mut pad = [0u8; 64];
+		let mut inner = Sha256::new();
+		for (p, k) in pad.iter_mut().zip(key_block.iter()) {
+			*p = k ^ 0x36;
+		}
+		inner.update(&pad);
+		let mut outer = Sha256::new();
+		for (p, k) in pad.iter_mut().zip(key_block.iter()) {
+			*p = k ^ 0x5C;
+		}
+		outer.update(&pad);
+		Hmac {
+			inner: inner,
+			outer: outer,
+		}
+	}
+	/// Compute the MAC of the concatenation of `data`
+	fn mac(&self, data: &[&[u8]]) -> [u8; 32] {
+		let mut inner = self.inner.clone();
+		for d in data {
+			inner.update(d);
+		}
+		let mut outer = self.outer.clone();
+		outer.update(&inner.finish());
+		outer.finish()
+	}
+}
+
+/// PBKDF2 using HMAC-SHA-256, returning the first (32-byte) block of derived key
+pub fn pbkdf2(password: &[u8], salt: &[u8], rounds: u32) -> [u8; 32]
+{
+	let prf = Hmac::new(password);
+	// U_1 = PRF(password, salt || INT(1)), later U_i = PRF(password, U_{i-1})
+	let mut u = prf.mac(&[salt, &[0u8, 0, 0, 1][..]]);
+	let mut rv = u;
+	for _ in 1 .. rounds
+	{
+		u = prf.mac(&[&u[..]]);
+		for (r, b) in rv.iter_mut().zip(u.iter()) {
+			*r ^= *b;
+		}
+	}
+	rv
+}
@@ -40,19 +40,38 @@ def_grp!( 0: GROUP_CORE = {
 	=4: CORE_EXITTHREAD,
 	/// Start a new process (loader only, use loader API instead)
 	=5: CORE_STARTPROCESS,
-	/// Start a new thread in the current process
+	/// Start a new thread in the current process (returns a thread handle)
 	=6: CORE_STARTTHREAD,
 	/// Wait for any of a set of events
 	=7: CORE_WAIT,
-	/// Wait on a futex
+	/// Wait on a futex (if it holds the passed value)
 	=8: CORE_FUTEX_SLEEP,
-	/// Wake a number of sleepers on a futex
+	/// Wake a number of sleepers on a futex (returns the number woken)
 	=9: CORE_FUTEX_WAKE,
+	/// Get the user and group IDs of the current process (uid in the low 32 bits, gid in the high)
+	=10: CORE_GETCREDENTIALS,
 });
 
 /// Value for `get_text_info`'s `unit` argument, indicating kernel core
 pub const TEXTINFO_KERNEL: u32 = 0;
 
+/// Exit status of a process terminated by `CORE_PROCESS_KILL`
+pub const EXIT_STATUS_KILLED: u32 = 0x8000_0001;
+
+/// Capability: Create new GUI sessions (window groups)
+pub const CAP_GUI_SESSION: u32 = 1 << 0;
+/// Capability: Raw network access (raw frames and MAC-level addressing)
+pub const CAP_NET_RAW: u32 = 1 << 1;
+/// Capability: Set the user/group identity of new processes
+pub const CAP_SET_CREDENTIALS: u32 = 1 << 2;
+/// All capability flags
+pub const CAP_ALL: u32 = CAP_GUI_SESSION | CAP_NET_RAW | CAP_SET_CREDENTIALS;
+
+/// Filesystem right: Read files and enumerate directories
+pub const FS_RIGHT_READ: u8 = 1 << 0;
+/// Filesystem right: Open files for writing
+pub const FS_RIGHT_WRITE: u8 = 1 << 1;
+
 #[repr(C)]
 #[derive(Debug)]
 /// Object reference used by the CORE_WAIT system call
@@ -80,6 +99,8 @@ def_grp!( 2: GROUP_MEM = {
 	=0: MEM_ALLOCATE,
 	=1: MEM_REPROTECT,
 	=2: MEM_DEALLOCATE,
+	/// Create a shared memory object of the given size in bytes (rounded up to pages, returns !0 on failure)
+	=3: MEM_SHM_CREATE,
 });
 
 /// Process memory management
@@ -155,6 +176,14 @@ def_classes! {
 		/// Give the process one of this process's objects
 		/// This method blocks if the child process hasn't popped the previous object
 		=0: CORE_PROTOPROCESS_SENDOBJ,
+		/// Remove capability flags (`CAP_*`) from the process
+		=1: CORE_PROTOPROCESS_DROPCAPS,
+		/// Limit the network ports usable by the process to an inclusive range
+		=2: CORE_PROTOPROCESS_LIMITPORTS,
+		/// Limit filesystem access to a subtree (first call replaces the inherited set, later calls add to it)
+		=3: CORE_PROTOPROCESS_LIMITPATH,
+		/// Set the user and group IDs of the process (requires `CAP_SET_CREDENTIALS`)
+		=4: CORE_PROTOPROCESS_SETCREDS,
 		--
 		/// Start the process executing
 		=0: CORE_PROTOPROCESS_START,
@@ -163,7 +192,10 @@ def_classes! {
 	/// Handle to a spawned process, used to communicate with it
 	=1: CLASS_CORE_PROCESS = {
 		/// Request that the process be terminated
+		/// Threads exit at their next system call, EV_PROCESS_TERMINATED fires once all have exited
 		=0: CORE_PROCESS_KILL,
+		/// Get the exit status of the process (!0 if it hasn't yet terminated)
+		=1: CORE_PROCESS_GETEXIT,
 		--
 	}|{
 		/// Wakes if the child process terminates
@@ -262,14 +294,17 @@ def_classes! {
 
 	/// Remote procedure call channel
 	=10: CLASS_IPC_RPC = {
-		/// Send a message over the channel (RpcMessage, limited size)
+		/// Send a message over the channel (RpcMessage, limited size), optionally moving an object handle with it
+		/// Returns an RpcError if the remote's queue is full or it has been closed
 		=0: IPC_RPC_SEND,
-		/// Receive a message
+		/// Receive a message (returns the handle of the attached object, or 0 if there was none)
 		=1: IPC_RPC_RECV,
 	--
 	}|{
-		/// Fires when the channel has a message waiting
+		/// Fires when the channel has a message waiting (or the other end has been closed)
 		=0: EV_IPC_RPC_RECV,
+		/// Fires when the other end of the channel has been closed
+		=1: EV_IPC_RPC_CLOSED,
 	},
 
 	/// Socket server
@@ -299,6 +334,23 @@ def_classes! {
 	--
 	}|{
 	},
+	/// Handle to a thread in the current process
+	=14: CLASS_CORE_THREAD = {
+	--
+	}|{
+		/// Wakes when the thread terminates
+		=0: EV_THREAD_TERMINATED,
+	},
+	/// Shared memory region (clone the handle to share it with another process)
+	=15: CLASS_MEM_SHARED = {
+		/// Get the size of the region (in bytes)
+		=0: MEM_SHM_GETSIZE,
+		/// Map the region at the given address (protection mode as for MEM_REPROTECT), returns a MemoryError on failure
+		/// Unmap using MEM_DEALLOCATE
+		=1: MEM_SHM_MAP,
+		--
+	}|{
+	},
 /*
 	/// A registered read/write buffer
 	=12: CLASS_BUFFER = {
@@ -352,11 +404,40 @@ macro_rules! enum_to_from {
 }
 
 enum_to_from!{ VFSError => u32:
+	/// File (or a path component) not found
 	FileNotFound = 0,
+	/// Node was not the requested type
 	TypeError = 1,
+	/// Permission denied
 	PermissionDenied = 2,
+	/// File is exclusively locked
 	FileLocked = 3,
+	/// Path was malformed
 	MalformedPath = 4,
+	/// The item already exists
+	AlreadyExists = 5,
+	/// Directory is not empty
+	NotEmpty = 6,
+	/// A parameter was invalid (empty name, offset out of range, ...)
+	InvalidParameter = 7,
+	/// A component of the path was not a directory
+	NotADirectory = 8,
+	/// Symbolic link recursion limit reached
+	RecursionLimit = 9,
+	/// Underlying device reported an IO error
+	IoError = 10,
+	/// Filesystem is read-only
+	ReadOnly = 11,
+	/// Filesystem is corrupted
+	Inconsistent = 12,
+	/// Volume is out of space
+	OutOfSpace = 13,
+	/// Kernel is out of memory
+	OutOfMemory = 14,
+	/// Transient failure, operation can be retried
+	Transient = 15,
+	/// Unknown/miscellaneous error
+	Unknown = 16,
 }
 enum_to_from!{ VFSNodeType => u32:
 	File = 0,
@@ -493,7 +574,37 @@ pub enum GuiEvent
 	MouseTriClick(u32,u32, u8),
 }
 
+// --------------------------------------------------------------------
+// Processes
+// --------------------------------------------------------------------
+enum_to_from!{ ProcessError => u8:
+	/// The calling process doesn't hold the capability required for the operation
+	PermissionDenied = 0,
+}
+
+// --------------------------------------------------------------------
+// Memory
+// --------------------------------------------------------------------
+enum_to_from!{ MemoryError => u8:
+	/// Part of the requested range is already mapped
+	RangeInUse = 0,
+	/// Not enough memory to complete the request
+	OutOfMemory = 1,
+}
+
 pub type RpcMessage = [u8; 32];
+enum_to_from!{ RpcError => u8:
+	/// No message waiting
+	NoMessage = 0,
+	/// The other end of the channel has been closed
+	ConnectionClosed = 1,
+	/// The other end's message queue is full
+	QueueFull = 2,
+	/// No free object slots for the attached object (the message is left queued)
+	TooManyObjects = 3,
+	/// Error code not known to this version of the library
+	Unknown = 0xFF,
+}
 
 // --------------------------------------------------------------------
 // Network
@@ -505,6 +616,8 @@ enum_to_from!{ SocketError => u8:
 	InvalidValue = 1,
 	/// The specified address was already in use
 	AlreadyInUse = 2,
+	/// The process isn't allowed to use the requested port/address type
+	PermissionDenied = 3,
 }
 enum_to_from!{ SocketShutdownSide => u8:
 	Transmit = 0,
@@ -532,15 +645,15 @@ enum_to_from!{ SocketPortType => u8:
 #[derive(Default,Copy,Clone)]
 pub struct SocketAddress
 {
-	port_ty: u8,
-	port: u16,
-	addr_ty: u8,
-	addr: [u8; 16],
+	pub port_ty: u8,
+	pub port: u16,
+	pub addr_ty: u8,
+	pub addr: [u8; 16],
 }
 #[derive(Default,Copy,Clone)]
 pub struct MaskedSocketAddress
 {
-	addr: SocketAddress,
-	mask: u8,
+	pub addr: SocketAddress,
+	pub mask: u8,
 }
 